@@ -0,0 +1,269 @@
+//! Device logic behind the `Accessory` trait, with the HAP side owned by a
+//! generic `Runner`.
+
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use crate::builder::{AccessoryBuilder, BuildError, Registered, ServiceBuilder, ServiceHandle};
+use crate::read::ReadHandler;
+use crate::sys::{Char, HapSys};
+use crate::value::Value;
+use crate::write::{Status, Write, WriteHandler};
+use crate::Hap;
+
+/// What a device does, without the HAP plumbing.
+pub trait Accessory: Send + 'static {
+    /// The services to register, the runner installs their callbacks.
+    fn services(&self) -> Vec<ServiceBuilder>;
+
+    /// The registered services, in the order of `services`, to take the
+    /// handles `poll` reports under.
+    fn attach(&mut self, _services: &[ServiceHandle]) {}
+
+    /// A controller wrote `value` to the characteristic of type `uuid`.
+    fn handle_write(&mut self, uuid: &str, value: &Value) -> Result<(), Status>;
+
+    /// The characteristics whose value changed since the last poll.
+    fn poll(&mut self) -> Vec<(Char, Value)>;
+}
+
+/// Owns an accessory: registers its services, routes the controller's
+/// writes and reads to it and notifies whatever `poll` reports.
+pub struct Runner<S: 'static, A> {
+    hap: &'static Hap<S>,
+    accessory: Mutex<A>,
+}
+
+impl<S: HapSys, A: Accessory> Runner<S, A> {
+    /// Hands out the runner for the lifetime of the firmware, as the HAP
+    /// services keep a pointer to it.
+    pub fn new(hap: &'static Hap<S>, accessory: A) -> &'static Self {
+        Box::leak(Box::new(Self {
+            hap,
+            accessory: Mutex::new(accessory),
+        }))
+    }
+
+    /// Registers the accessory's services ahead of the ones already on
+    /// `builder`, so the first of them is primary unless another is marked.
+    pub fn register(&'static self, builder: AccessoryBuilder) -> Result<Registered, BuildError> {
+        let services: Vec<_> = self
+            .lock()
+            .services()
+            .into_iter()
+            .map(|service| service.on_write(self).on_read(self))
+            .collect();
+        let count = services.len();
+
+        let registered = builder.prepend(services).register(self.hap)?;
+        self.with(|accessory| accessory.attach(&registered.services[..count]));
+
+        Ok(registered)
+    }
+
+    /// Runs `f` on the accessory, for local control, then notifies what changed.
+    pub fn with<R>(&self, f: impl FnOnce(&mut A) -> R) -> R {
+        let (result, changes) = {
+            let mut accessory = self.lock();
+            let result = f(&mut accessory);
+            (result, accessory.poll())
+        };
+        self.notify(changes);
+
+        result
+    }
+
+    /// One round of the periodic poll.
+    pub fn poll(&self) {
+        self.with(|_| ());
+    }
+
+    fn lock(&self) -> MutexGuard<'_, A> {
+        // A panicked handler leaves the device state as it was, still usable
+        self.accessory
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // Unlocked, so a slow SDK never holds up local control
+    fn notify(&self, changes: Vec<(Char, Value)>) {
+        for (hc, value) in changes {
+            self.hap.update(hc, &value);
+        }
+    }
+}
+
+impl<S: HapSys, A: Accessory> WriteHandler for Runner<S, A> {
+    fn write(&self, write: &Write) -> Result<(), Status> {
+        let uuid = self
+            .hap
+            .sys()
+            .char_type_uuid(write.hc)
+            .ok_or(Status::ResourceAbsent)?;
+        self.with(|accessory| accessory.handle_write(&uuid, &write.value))?;
+
+        // Accepted, the controller reads back what it wrote
+        self.hap.update(write.hc, &write.value);
+
+        Ok(())
+    }
+}
+
+impl<S: HapSys, A: Accessory> ReadHandler for Runner<S, A> {
+    fn read(&self, _hc: Char) -> Result<(), Status> {
+        self.poll();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::c_void;
+
+    use super::*;
+    use crate::mock::{Call, MockSys};
+    use crate::sys::{uuid, AccessoryInfo, HAP_SUCCESS};
+
+    unsafe extern "C" fn identify(_: *mut c_void) -> i32 {
+        HAP_SUCCESS
+    }
+
+    fn builder() -> AccessoryBuilder<'static> {
+        let text = c"Test";
+        AccessoryBuilder::new(AccessoryInfo {
+            name: text,
+            model: text,
+            manufacturer: text,
+            serial_num: text,
+            fw_rev: text,
+            hw_rev: text,
+            pv: text,
+            cid: 5,
+            identify,
+        })
+    }
+
+    /// A lamp whose switch can also flip on its own, like a wall switch.
+    #[derive(Default)]
+    struct Lamp {
+        on: bool,
+        reported: Option<bool>,
+        on_char: Option<Char>,
+        broken: bool,
+    }
+
+    impl Accessory for Lamp {
+        fn services(&self) -> Vec<ServiceBuilder> {
+            vec![ServiceBuilder::switch().name("Lamp")]
+        }
+
+        fn attach(&mut self, services: &[ServiceHandle]) {
+            self.on_char = services[0].char(uuid::ON);
+        }
+
+        fn handle_write(&mut self, uuid: &str, value: &Value) -> Result<(), Status> {
+            if !uuid::eq(uuid, uuid::ON) {
+                return Err(Status::WriteOnReadOnly);
+            }
+            if self.broken {
+                return Err(Status::CommunicationError);
+            }
+
+            self.on = value.as_bool().ok_or(Status::InvalidValue)?;
+            self.reported = Some(self.on);
+            Ok(())
+        }
+
+        fn poll(&mut self) -> Vec<(Char, Value)> {
+            let (Some(hc), false) = (self.on_char, self.reported == Some(self.on)) else {
+                return Vec::new();
+            };
+
+            self.reported = Some(self.on);
+            vec![(hc, Value::Bool(self.on))]
+        }
+    }
+
+    fn lamp(hap: &'static Hap<MockSys>) -> (&'static Runner<MockSys, Lamp>, ServiceHandle) {
+        let runner = Runner::new(hap, Lamp::default());
+        let registered = runner.register(builder()).unwrap();
+
+        (runner, registered.services[0].clone())
+    }
+
+    fn hap() -> &'static Hap<MockSys> {
+        Box::leak(Box::new(Hap::new(MockSys::new())))
+    }
+
+    #[test]
+    fn writes_reach_the_device() {
+        let hap = hap();
+        let (runner, service) = lamp(hap);
+        let on = service.char(uuid::ON).unwrap();
+
+        let (result, statuses) = hap.sys().write(service.serv, &[(on, Value::Bool(true))]);
+        assert_eq!((result, statuses), (HAP_SUCCESS, vec![0]));
+        assert!(runner.with(|lamp| lamp.on));
+        assert_eq!(hap.sys().value(on), Some(Value::Bool(true)));
+    }
+
+    #[test]
+    fn rejected_writes_leave_the_value() {
+        let hap = hap();
+        let (runner, service) = lamp(hap);
+        let on = service.char(uuid::ON).unwrap();
+        runner.with(|lamp| lamp.broken = true);
+
+        let (_, statuses) = hap.sys().write(service.serv, &[(on, Value::Bool(true))]);
+        assert_eq!(statuses, vec![Status::CommunicationError.code()]);
+        assert_eq!(hap.sys().value(on), Some(Value::Bool(false)));
+    }
+
+    #[test]
+    fn local_changes_are_notified() {
+        let hap = hap();
+        let (runner, service) = lamp(hap);
+        let on = service.char(uuid::ON).unwrap();
+
+        runner.with(|lamp| lamp.on = true);
+        assert_eq!(hap.sys().value(on), Some(Value::Bool(true)));
+
+        // Unchanged, so the next poll stays quiet
+        let updates = |calls: Vec<Call>| {
+            calls
+                .into_iter()
+                .filter(|c| matches!(c, Call::UpdateVal(..)))
+                .count()
+        };
+        let before = updates(hap.sys().calls());
+        runner.poll();
+        assert_eq!(updates(hap.sys().calls()), before);
+    }
+
+    #[test]
+    fn reads_poll_the_device() {
+        let hap = hap();
+        let (runner, service) = lamp(hap);
+        let on = service.char(uuid::ON).unwrap();
+
+        // Flipped behind the runner's back, as a wall switch would
+        runner.lock().on = true;
+        assert_eq!(hap.sys().read(service.serv, on), (HAP_SUCCESS, 0));
+        assert_eq!(hap.sys().value(on), Some(Value::Bool(true)));
+    }
+
+    #[test]
+    fn the_device_services_come_first() {
+        let hap = hap();
+        let runner = Runner::new(hap, Lamp::default());
+        let registered = runner
+            .register(builder().service(ServiceBuilder::custom(b"A0\0").name("Extra")))
+            .unwrap();
+
+        assert_eq!(registered.services.len(), 2);
+        assert!(hap
+            .sys()
+            .calls()
+            .contains(&Call::ServMarkPrimary(registered.services[0].serv)));
+        assert!(runner.with(|lamp| lamp.on_char).is_some());
+    }
+}
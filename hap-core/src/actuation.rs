@@ -0,0 +1,271 @@
+//! How a relay channel's coil is driven to switch it: held at a level like
+//! the usual relay modules, or pulsed, on one pin for impulse relays that
+//! toggle at every pulse, or on a set and a reset pin for dual-coil latching
+//! relays.
+//!
+//! The pins of a pulsed relay say nothing about its contacts, so the state
+//! is the actuator's own record, or what a sense of the contacts told it.
+//! An impulse relay is taken to be open while that is unknown, after a boot
+//! or a failed pulse; a dual-coil relay gets the pulse either way, which
+//! makes it known.
+
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::{Mutex, PoisonError};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    /// Held at the active level while closed
+    Level,
+    /// A pulse on the one pin toggles the relay
+    Pulse { width_ms: u32 },
+    /// A pulse on the set pin closes the relay, one on the reset pin opens it
+    DualCoil { width_ms: u32 },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Coil {
+    Set,
+    /// Only dual-coil relays have one
+    Reset,
+}
+
+/// The pins of a channel.
+pub trait Coils {
+    type Error;
+
+    /// Drives the pin of `coil` to its active level, or back to idle.
+    fn drive(&mut self, coil: Coil, active: bool) -> Result<(), Self::Error>;
+
+    fn wait(&mut self, ms: u32);
+}
+
+const UNKNOWN: u8 = 2;
+
+/// One channel, switched one command at a time: a command arriving during
+/// a pulse waits for it and starts from the state it left.
+pub struct Actuator<C> {
+    mode: Mode,
+    coils: Mutex<C>,
+    state: AtomicU8,
+}
+
+impl<C: Coils> Actuator<C> {
+    pub fn new(mode: Mode, coils: C) -> Self {
+        Self {
+            mode,
+            coils: Mutex::new(coils),
+            state: AtomicU8::new(UNKNOWN),
+        }
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    /// Without waiting for a pulse under way, `None` until the first switch.
+    pub fn state(&self) -> Option<bool> {
+        match self.state.load(Ordering::Relaxed) {
+            UNKNOWN => None,
+            state => Some(state == 1),
+        }
+    }
+
+    /// Takes the state of the contacts as read elsewhere, a feedback input
+    /// across them, for the next switch to start from.
+    pub fn sense(&self, on: bool) {
+        let _coils = self.coils.lock().unwrap_or_else(PoisonError::into_inner);
+        self.state.store(on as u8, Ordering::Relaxed);
+    }
+
+    pub fn set(&self, on: bool) -> Result<(), C::Error> {
+        let mut coils = self.coils.lock().unwrap_or_else(PoisonError::into_inner);
+        let state = self.state();
+        let result = match self.mode {
+            Mode::Level => coils.drive(Coil::Set, on),
+            Mode::Pulse { width_ms } if state.unwrap_or(false) != on => {
+                pulse(&mut *coils, Coil::Set, width_ms)
+            }
+            Mode::DualCoil { width_ms } if state != Some(on) => {
+                let coil = if on { Coil::Set } else { Coil::Reset };
+                pulse(&mut *coils, coil, width_ms)
+            }
+            _ => Ok(()),
+        };
+
+        match (&result, self.mode) {
+            (Ok(()), _) => self.state.store(on as u8, Ordering::Relaxed),
+            // Whether the pulse got through is anyone's guess
+            (Err(_), Mode::Pulse { .. } | Mode::DualCoil { .. }) => {
+                self.state.store(UNKNOWN, Ordering::Relaxed)
+            }
+            (Err(_), Mode::Level) => {}
+        }
+
+        result
+    }
+}
+
+/// Releases the coil even when energizing it failed; a coil left energized
+/// overheats.
+fn pulse<C: Coils>(coils: &mut C, coil: Coil, width_ms: u32) -> Result<(), C::Error> {
+    let energized = coils.drive(coil, true);
+    if energized.is_ok() {
+        coils.wait(width_ms);
+    }
+    let released = coils.drive(coil, false);
+
+    energized.and(released)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    enum Step {
+        Drive(Coil, bool),
+        Wait(u32),
+    }
+
+    #[derive(Clone, Default)]
+    struct Recorder {
+        steps: Arc<Mutex<Vec<Step>>>,
+        fail_release: bool,
+    }
+
+    impl Coils for Recorder {
+        type Error = ();
+
+        fn drive(&mut self, coil: Coil, active: bool) -> Result<(), ()> {
+            self.steps.lock().unwrap().push(Step::Drive(coil, active));
+            if self.fail_release && !active {
+                return Err(());
+            }
+            Ok(())
+        }
+
+        fn wait(&mut self, ms: u32) {
+            self.steps.lock().unwrap().push(Step::Wait(ms));
+            thread::sleep(Duration::from_millis(ms as u64));
+        }
+    }
+
+    fn steps(recorder: &Recorder) -> Vec<Step> {
+        std::mem::take(&mut *recorder.steps.lock().unwrap())
+    }
+
+    #[test]
+    fn drives_each_mode() {
+        let recorder = Recorder::default();
+        let level = Actuator::new(Mode::Level, recorder.clone());
+        level.set(true).unwrap();
+        level.set(true).unwrap();
+        assert_eq!(
+            steps(&recorder),
+            [Step::Drive(Coil::Set, true), Step::Drive(Coil::Set, true)]
+        );
+
+        let pulse = Actuator::new(Mode::Pulse { width_ms: 1 }, recorder.clone());
+        // Taken to be open at first
+        pulse.set(false).unwrap();
+        assert_eq!(steps(&recorder), []);
+        pulse.set(true).unwrap();
+        pulse.set(true).unwrap();
+        pulse.set(false).unwrap();
+        let toggle = [
+            Step::Drive(Coil::Set, true),
+            Step::Wait(1),
+            Step::Drive(Coil::Set, false),
+        ];
+        assert_eq!(steps(&recorder), [toggle, toggle].concat());
+
+        let dual = Actuator::new(Mode::DualCoil { width_ms: 1 }, recorder.clone());
+        dual.set(false).unwrap();
+        dual.set(false).unwrap();
+        dual.set(true).unwrap();
+        assert_eq!(
+            steps(&recorder),
+            [
+                Step::Drive(Coil::Reset, true),
+                Step::Wait(1),
+                Step::Drive(Coil::Reset, false),
+                Step::Drive(Coil::Set, true),
+                Step::Wait(1),
+                Step::Drive(Coil::Set, false),
+            ]
+        );
+        assert_eq!(dual.state(), Some(true));
+    }
+
+    #[test]
+    fn starts_from_the_sensed_state() {
+        let recorder = Recorder::default();
+        let pulse = Actuator::new(Mode::Pulse { width_ms: 1 }, recorder.clone());
+        // Latched on before the boot
+        pulse.sense(true);
+        assert_eq!(pulse.state(), Some(true));
+        pulse.set(true).unwrap();
+        assert_eq!(steps(&recorder), []);
+        pulse.set(false).unwrap();
+        assert_eq!(steps(&recorder).len(), 3);
+        assert_eq!(pulse.state(), Some(false));
+    }
+
+    #[test]
+    fn forgets_the_state_of_a_failed_pulse() {
+        let recorder = Recorder {
+            fail_release: true,
+            ..Recorder::default()
+        };
+        let dual = Actuator::new(Mode::DualCoil { width_ms: 1 }, recorder.clone());
+        assert!(dual.set(true).is_err());
+        assert_eq!(dual.state(), None);
+
+        // Pulsed again whatever the state was
+        assert!(dual.set(true).is_err());
+        assert_eq!(steps(&recorder).len(), 6);
+    }
+
+    #[test]
+    fn queues_overlapping_commands() {
+        let recorder = Recorder::default();
+        let dual = Arc::new(Actuator::new(
+            Mode::DualCoil { width_ms: 5 },
+            recorder.clone(),
+        ));
+        dual.set(false).unwrap();
+        steps(&recorder);
+
+        let writers: Vec<_> = (0..8)
+            .map(|i| {
+                let dual = dual.clone();
+                thread::spawn(move || dual.set(i % 2 == 0).unwrap())
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+
+        // Whole pulses only, each switching to the other state
+        let steps = steps(&recorder);
+        assert!(!steps.is_empty());
+        let mut on = false;
+        for pulse in steps.chunks(3) {
+            let coil = if on { Coil::Reset } else { Coil::Set };
+            assert_eq!(
+                pulse,
+                [
+                    Step::Drive(coil, true),
+                    Step::Wait(5),
+                    Step::Drive(coil, false)
+                ]
+            );
+            on = !on;
+        }
+        assert_eq!(dual.state(), Some(on));
+    }
+}
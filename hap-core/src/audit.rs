@@ -0,0 +1,294 @@
+//! The audit trail of characteristic changes: what changed, from what to
+//! what, when and through which path, and the compact form the latest
+//! entries are stored in.
+//!
+//! Stored form: a format version byte, then per entry the time (u32 seconds
+//! since the epoch, little endian, 0 before the clock synced), the origin
+//! and three length-prefixed strings: UUID, old and new value.
+
+use std::collections::VecDeque;
+use std::fmt;
+
+use crate::value::Value;
+
+pub const CAPACITY: usize = 64;
+pub const FORMAT_VERSION: u8 = 1;
+/// Longer UUIDs and values are cut, so an entry never exceeds
+/// `MAX_ENTRY_LEN` bytes stored.
+pub const MAX_FIELD_LEN: usize = 36;
+pub const MAX_ENTRY_LEN: usize = 4 + 1 + 3 * (1 + MAX_FIELD_LEN);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Origin {
+    /// A write by a paired controller
+    HomeKit,
+    /// The button, touch pad or encoder
+    Local,
+    Schedule,
+    Console,
+    /// The firmware itself: the state restored at boot, the safe state at
+    /// shutdown, a guardrail switching off
+    System,
+}
+
+impl Origin {
+    const ALL: [Origin; 5] = [
+        Origin::HomeKit,
+        Origin::Local,
+        Origin::Schedule,
+        Origin::Console,
+        Origin::System,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Origin::HomeKit => "homekit",
+            Origin::Local => "local",
+            Origin::Schedule => "schedule",
+            Origin::Console => "console",
+            Origin::System => "system",
+        }
+    }
+
+    fn from_u8(value: u8) -> Option<Self> {
+        Self::ALL.get(value as usize).copied()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Entry {
+    /// Seconds since the epoch, 0 before the clock synced
+    pub at: u32,
+    pub uuid: String,
+    pub old: String,
+    pub new: String,
+    pub origin: Origin,
+}
+
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {} -> {} ({})",
+            self.uuid,
+            self.old,
+            self.new,
+            self.origin.name()
+        )
+    }
+}
+
+/// A value as the audit trail shows it; strings are cut, TLV8 and data only
+/// show their length.
+pub fn show(value: &Value) -> String {
+    let shown = match value {
+        Value::Bool(b) => b.to_string(),
+        Value::Uint8(u) => u.to_string(),
+        Value::Uint16(u) => u.to_string(),
+        Value::Uint32(u) => u.to_string(),
+        Value::Uint64(u) => u.to_string(),
+        Value::Int(i) => i.to_string(),
+        Value::Float(f) => format!("{:.2}", f),
+        Value::String(s) => s.clone(),
+        Value::Tlv8(bytes) | Value::Data(bytes) => format!("<{} bytes>", bytes.len()),
+    };
+
+    cut(shown)
+}
+
+fn cut(mut text: String) -> String {
+    if text.len() > MAX_FIELD_LEN {
+        let mut end = MAX_FIELD_LEN;
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        text.truncate(end);
+    }
+
+    text
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DecodeError;
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("malformed audit log")
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// The latest `CAPACITY` entries, the oldest dropped first.
+#[derive(Debug, Default)]
+pub struct Log {
+    entries: VecDeque<Entry>,
+    /// Entries recorded since boot, to tell whether the stored copy is behind
+    recorded: u32,
+}
+
+impl Log {
+    pub const fn new() -> Self {
+        Self {
+            entries: VecDeque::new(),
+            recorded: 0,
+        }
+    }
+
+    pub fn record(&mut self, mut entry: Entry) {
+        entry.uuid = cut(entry.uuid);
+        entry.old = cut(entry.old);
+        entry.new = cut(entry.new);
+        if self.entries.len() == CAPACITY {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(entry);
+        self.recorded = self.recorded.wrapping_add(1);
+    }
+
+    /// Oldest first.
+    pub fn entries(&self) -> impl DoubleEndedIterator<Item = &Entry> {
+        self.entries.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn recorded(&self) -> u32 {
+        self.recorded
+    }
+
+    /// Puts the stored entries before the ones recorded since boot.
+    pub fn restore(&mut self, stored: Vec<Entry>) {
+        let recent = std::mem::take(&mut self.entries);
+        self.entries = stored.into_iter().chain(recent).collect();
+        while self.entries.len() > CAPACITY {
+            self.entries.pop_front();
+        }
+    }
+
+    /// The latest `count` entries in the stored form, at most
+    /// `1 + count * MAX_ENTRY_LEN` bytes.
+    pub fn encode(&self, count: usize) -> Vec<u8> {
+        let skip = self.entries.len().saturating_sub(count);
+        let mut out = vec![FORMAT_VERSION];
+        for entry in self.entries.iter().skip(skip) {
+            out.extend_from_slice(&entry.at.to_le_bytes());
+            out.push(entry.origin as u8);
+            for field in [&entry.uuid, &entry.old, &entry.new] {
+                out.push(field.len() as u8);
+                out.extend_from_slice(field.as_bytes());
+            }
+        }
+
+        out
+    }
+}
+
+fn take<'a>(rest: &mut &'a [u8], len: usize) -> Result<&'a [u8], DecodeError> {
+    if rest.len() < len {
+        return Err(DecodeError);
+    }
+    let (taken, remaining) = rest.split_at(len);
+    *rest = remaining;
+
+    Ok(taken)
+}
+
+/// Reads what `Log::encode` stored, oldest first.
+pub fn decode(bytes: &[u8]) -> Result<Vec<Entry>, DecodeError> {
+    let (&version, mut rest) = bytes.split_first().ok_or(DecodeError)?;
+    if version != FORMAT_VERSION {
+        return Err(DecodeError);
+    }
+
+    let mut entries = Vec::new();
+    while !rest.is_empty() {
+        let at = take(&mut rest, 4)?;
+        let at = u32::from_le_bytes([at[0], at[1], at[2], at[3]]);
+        let origin = Origin::from_u8(take(&mut rest, 1)?[0]).ok_or(DecodeError)?;
+        let mut fields = [String::new(), String::new(), String::new()];
+        for field in &mut fields {
+            let len = take(&mut rest, 1)?[0] as usize;
+            *field = String::from_utf8(take(&mut rest, len)?.to_vec()).map_err(|_| DecodeError)?;
+        }
+        let [uuid, old, new] = fields;
+        entries.push(Entry {
+            at,
+            uuid,
+            old,
+            new,
+            origin,
+        });
+    }
+
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(at: u32, new: bool, origin: Origin) -> Entry {
+        Entry {
+            at,
+            uuid: "25".into(),
+            old: show(&Value::Bool(!new)),
+            new: show(&Value::Bool(new)),
+            origin,
+        }
+    }
+
+    #[test]
+    fn keeps_the_latest_entries() {
+        let mut log = Log::new();
+        for at in 0..CAPACITY as u32 + 3 {
+            log.record(entry(at, at % 2 == 0, Origin::Local));
+        }
+        assert_eq!(log.len(), CAPACITY);
+        assert_eq!(log.recorded(), CAPACITY as u32 + 3);
+        assert_eq!(log.entries().next().unwrap().at, 3);
+        assert_eq!(log.entries().next_back().unwrap().at, CAPACITY as u32 + 2);
+    }
+
+    #[test]
+    fn round_trips_the_latest_entries() {
+        let mut log = Log::new();
+        log.record(entry(100, true, Origin::HomeKit));
+        log.record(entry(200, false, Origin::Schedule));
+        log.record(Entry {
+            at: 300,
+            uuid: "0000D6A1-28E5-4C3F-9B6E-5A1D7E3C9000".into(),
+            old: "x".repeat(50),
+            new: "ü".repeat(30),
+            origin: Origin::Console,
+        });
+
+        let bytes = log.encode(2);
+        assert!(bytes.len() <= 1 + 2 * MAX_ENTRY_LEN);
+        let stored = decode(&bytes).unwrap();
+        assert_eq!(stored.len(), 2);
+        assert_eq!(stored[0], entry(200, false, Origin::Schedule));
+        assert_eq!(stored[1].old.len(), MAX_FIELD_LEN);
+        assert_eq!(stored[1].new, "ü".repeat(18));
+        assert_eq!(stored[0].to_string(), "25 true -> false (schedule)");
+
+        assert!(decode(&bytes[..bytes.len() - 1]).is_err());
+        assert!(decode(&[2]).is_err());
+        assert_eq!(decode(&[FORMAT_VERSION]).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn restored_entries_go_before_new_ones() {
+        let mut log = Log::new();
+        log.record(entry(500, true, Origin::System));
+        log.restore(vec![entry(100, true, Origin::HomeKit)]);
+        let times: Vec<u32> = log.entries().map(|entry| entry.at).collect();
+        assert_eq!(times, vec![100, 500]);
+    }
+}
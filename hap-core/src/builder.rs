@@ -0,0 +1,673 @@
+//! Accessories described declaratively and registered with the SDK calls in
+//! the order it expects.
+
+use std::error::Error;
+use std::ffi::CString;
+use std::fmt;
+use std::sync::OnceLock;
+
+use crate::iid::{self, Assigned, IidMap};
+use crate::read::ReadHandler;
+use crate::sys::{perm, uuid, Acc, AccessoryInfo, Char, HapSys, Serv, HAP_SUCCESS};
+use crate::value::{Format, Value};
+use crate::write::{self, Bounds, WriteHandler};
+use crate::Hap;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BuildError {
+    NoServices,
+    /// Index of a service without a name
+    Unnamed(usize),
+    InvalidName(String),
+    /// More than one service was marked primary
+    Primaries(usize),
+    /// The database is fixed once HAP started
+    Started,
+    MissingChar {
+        service: usize,
+        uuid: String,
+    },
+    /// The SDK refused the named call
+    Sdk(&'static str),
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BuildError::NoServices => f.write_str("the accessory has no services"),
+            BuildError::Unnamed(index) => write!(f, "service {} has no name", index),
+            BuildError::InvalidName(name) => write!(f, "service name '{}' is not valid", name),
+            BuildError::Primaries(count) => {
+                write!(f, "{} services are primary, at most one can be", count)
+            }
+            BuildError::Started => f.write_str("HAP already started"),
+            BuildError::MissingChar { service, uuid } => {
+                write!(f, "service {} has no characteristic {}", service, uuid)
+            }
+            BuildError::Sdk(call) => write!(f, "{} failed", call),
+        }
+    }
+}
+
+impl Error for BuildError {}
+
+/// Receives a characteristic handle when the accessory is registered, so a
+/// module can keep it in a static for later updates.
+pub struct CharSlot(OnceLock<Char>);
+
+impl CharSlot {
+    pub const fn new() -> Self {
+        Self(OnceLock::new())
+    }
+
+    pub fn get(&self) -> Option<Char> {
+        self.0.get().copied()
+    }
+}
+
+impl Default for CharSlot {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+const ON_PERMS: u16 = perm::PR | perm::PW | perm::EV;
+
+enum Kind {
+    Outlet,
+    Switch,
+    Custom(&'static [u8]),
+}
+
+impl Kind {
+    fn uuid(&self) -> &'static [u8] {
+        match self {
+            Kind::Outlet => b"47\0",
+            Kind::Switch => b"49\0",
+            Kind::Custom(uuid) => uuid,
+        }
+    }
+}
+
+fn uuid_name(uuid: &[u8]) -> String {
+    String::from_utf8_lossy(uuid.strip_suffix(b"\0").unwrap_or(uuid)).into()
+}
+
+pub struct ServiceBuilder {
+    kind: Kind,
+    name: Option<String>,
+    primary: bool,
+    hidden: bool,
+    status_fault: bool,
+    chars: Vec<(&'static [u8], u16, Value)>,
+    bindings: Vec<(&'static [u8], &'static CharSlot)>,
+    bounds: Vec<(&'static [u8], Bounds)>,
+    valid_values: Vec<(&'static [u8], &'static [u8])>,
+    write: Option<&'static dyn WriteHandler>,
+    read: Option<&'static dyn ReadHandler>,
+}
+
+impl ServiceBuilder {
+    fn new(kind: Kind) -> Self {
+        Self {
+            kind,
+            name: None,
+            primary: false,
+            hidden: false,
+            status_fault: false,
+            chars: Vec::new(),
+            bindings: Vec::new(),
+            bounds: Vec::new(),
+            valid_values: Vec::new(),
+            write: None,
+            read: None,
+        }
+    }
+
+    /// An Outlet with its On and Outlet In Use characteristics, both false.
+    pub fn outlet() -> Self {
+        Self::new(Kind::Outlet)
+    }
+
+    /// A Switch with its On characteristic, false.
+    pub fn switch() -> Self {
+        Self::new(Kind::Switch)
+    }
+
+    /// A service of any type, its characteristics all added with `char`.
+    pub fn custom(uuid: &'static [u8]) -> Self {
+        Self::new(Kind::Custom(uuid))
+    }
+
+    pub fn name(mut self, name: &str) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Without one marked, the first service of the accessory is primary.
+    pub fn primary(mut self) -> Self {
+        self.primary = true;
+        self
+    }
+
+    /// Keeps the service out of the controllers' user interface; apps
+    /// showing every service still list it.
+    pub fn hidden(mut self) -> Self {
+        self.hidden = true;
+        self
+    }
+
+    /// Adds a Status Fault characteristic, initially no fault.
+    pub fn status_fault(mut self) -> Self {
+        self.status_fault = true;
+        self
+    }
+
+    /// Adds a characteristic of the format of its initial value.
+    pub fn char(mut self, uuid: &'static [u8], perms: u16, value: Value) -> Self {
+        self.chars.push((uuid, perms, value));
+        self
+    }
+
+    /// Hands the characteristic `uuid` to `slot` on registration.
+    pub fn bind(mut self, uuid: &'static [u8], slot: &'static CharSlot) -> Self {
+        self.bindings.push((uuid, slot));
+        self
+    }
+
+    /// Limits the numeric characteristic `uuid`; writes outside are refused.
+    pub fn bounds(mut self, uuid: &'static [u8], bounds: Bounds) -> Self {
+        self.bounds.push((uuid, bounds));
+        self
+    }
+
+    /// Limits the integer characteristic `uuid` to the listed values.
+    pub fn valid_values(mut self, uuid: &'static [u8], values: &'static [u8]) -> Self {
+        self.valid_values.push((uuid, values));
+        self
+    }
+
+    pub fn on_write(mut self, handler: &'static dyn WriteHandler) -> Self {
+        self.write = Some(handler);
+        self
+    }
+
+    pub fn on_read(mut self, handler: &'static dyn ReadHandler) -> Self {
+        self.read = Some(handler);
+        self
+    }
+
+    fn register<S: HapSys>(
+        &self,
+        hap: &Hap<S>,
+        index: usize,
+        primary: bool,
+    ) -> Result<ServiceHandle, BuildError> {
+        let sys = hap.sys();
+        let (serv, builtin): (_, &[_]) = match self.kind {
+            Kind::Outlet => (
+                sys.serv_outlet_create(false, false),
+                &[
+                    (uuid::ON, Format::Bool, ON_PERMS),
+                    (uuid::OUTLET_IN_USE, Format::Bool, perm::PR | perm::EV),
+                ],
+            ),
+            Kind::Switch => (
+                sys.serv_switch_create(false),
+                &[(uuid::ON, Format::Bool, ON_PERMS)],
+            ),
+            Kind::Custom(uuid) => (sys.serv_create(uuid), &[]),
+        };
+        let serv = serv.ok_or(BuildError::Sdk("creating a service"))?;
+        let mut handle = ServiceHandle {
+            serv,
+            chars: Vec::new(),
+        };
+
+        // The SDK checks for a name when the service is added
+        let name = self.name.as_deref().ok_or(BuildError::Unnamed(index))?;
+        let name = CString::new(name).map_err(|_| BuildError::InvalidName(name.into()))?;
+        let hc = sys
+            .char_name_create(&name)
+            .ok_or(BuildError::Sdk("creating the name"))?;
+        write::register(hc, Format::String, perm::PR);
+        sys.serv_add_char(serv, hc);
+        handle.chars.push((uuid::NAME, hc));
+
+        for &(uuid, format, perms) in builtin {
+            let hc = hap
+                .char_by_uuid(serv, uuid, format, perms)
+                .ok_or(BuildError::Sdk("looking up a characteristic"))?;
+            handle.chars.push((uuid, hc));
+        }
+        if self.status_fault {
+            let hc = sys
+                .char_status_fault_create(0)
+                .ok_or(BuildError::Sdk("creating the status fault"))?;
+            write::register(hc, Format::Uint8, perm::PR | perm::EV);
+            sys.serv_add_char(serv, hc);
+            handle.chars.push((uuid::STATUS_FAULT, hc));
+        }
+        for &(uuid, perms, ref value) in &self.chars {
+            let hc = hap
+                .add_char(serv, uuid, perms, value)
+                .ok_or(BuildError::Sdk("creating a characteristic"))?;
+            handle.chars.push((uuid, hc));
+        }
+
+        if primary {
+            sys.serv_mark_primary(serv);
+        }
+        if self.hidden {
+            sys.serv_mark_hidden(serv);
+        }
+        hap.set_handlers(serv, self.write, self.read);
+
+        let find = |uuid: &[u8]| {
+            handle.char(uuid).ok_or_else(|| BuildError::MissingChar {
+                service: index,
+                uuid: uuid_name(uuid),
+            })
+        };
+        for &(uuid, bounds) in &self.bounds {
+            hap.set_bounds(find(uuid)?, bounds);
+        }
+        for &(uuid, values) in &self.valid_values {
+            hap.set_valid_values(find(uuid)?, values);
+        }
+        for (uuid, slot) in &self.bindings {
+            let _ = slot.0.set(find(uuid)?);
+        }
+
+        Ok(handle)
+    }
+}
+
+/// A registered service and the characteristics it was built with.
+#[derive(Clone, Debug)]
+pub struct ServiceHandle {
+    pub serv: Serv,
+    chars: Vec<(&'static [u8], Char)>,
+}
+
+impl ServiceHandle {
+    pub fn char(&self, uuid: &[u8]) -> Option<Char> {
+        self.chars
+            .iter()
+            .find(|(known, _)| *known == uuid)
+            .map(|(_, hc)| *hc)
+    }
+}
+
+/// A registered accessory, services in the order they were added.
+#[derive(Clone, Debug)]
+pub struct Registered {
+    pub acc: Acc,
+    pub services: Vec<ServiceHandle>,
+}
+
+pub struct AccessoryBuilder<'a> {
+    info: AccessoryInfo<'a>,
+    services: Vec<ServiceBuilder>,
+    bridged: bool,
+    iids: Option<&'a mut IidMap>,
+}
+
+impl<'a> AccessoryBuilder<'a> {
+    pub fn new(info: AccessoryInfo<'a>) -> Self {
+        Self {
+            info,
+            services: Vec::new(),
+            bridged: false,
+            iids: None,
+        }
+    }
+
+    pub fn service(mut self, service: ServiceBuilder) -> Self {
+        self.services.push(service);
+        self
+    }
+
+    /// Puts services ahead of the ones added so far.
+    pub(crate) fn prepend(mut self, services: Vec<ServiceBuilder>) -> Self {
+        self.services.splice(0..0, services);
+        self
+    }
+
+    /// Registers the accessory behind a bridge, under an aid kept for its
+    /// serial number.
+    pub fn bridged(mut self, bridged: bool) -> Self {
+        self.bridged = bridged;
+        self
+    }
+
+    /// Keeps the iids of the services and characteristics in `map`, the
+    /// caller stores it when it changed.
+    pub fn iids(mut self, map: &'a mut IidMap) -> Self {
+        self.iids = Some(map);
+        self
+    }
+
+    // Before the accessory is added, while the SDK still takes new iids
+    fn assign_iids<S: HapSys>(&self, sys: &S, map: &mut IidMap, services: &[ServiceHandle]) {
+        let prefix = format!("{}/", self.info.serial_num.to_string_lossy());
+        let names: Vec<_> = self
+            .services
+            .iter()
+            .map(|service| uuid_name(service.kind.uuid()))
+            .collect();
+        let service_keys = iid::indexed(&names);
+
+        let mut items = Vec::new();
+        for (key, handle) in service_keys.iter().zip(services) {
+            let key = format!("{}{}", prefix, key);
+            items.push((key.clone(), sys.serv_get_iid(handle.serv)));
+
+            let names: Vec<_> = handle
+                .chars
+                .iter()
+                .map(|(uuid, _)| uuid_name(uuid))
+                .collect();
+            let char_keys = iid::indexed(&names);
+            for (char_key, (_, hc)) in char_keys.iter().zip(&handle.chars) {
+                items.push((format!("{}/{}", key, char_key), sys.char_get_iid(*hc)));
+            }
+        }
+
+        let (iids, assigned) = map.assign(&prefix, &items);
+        let mut iids = iids.into_iter();
+        for handle in services {
+            sys.serv_set_iid(handle.serv, iids.next().unwrap_or_default());
+            for (_, hc) in &handle.chars {
+                sys.char_set_iid(*hc, iids.next().unwrap_or_default());
+            }
+        }
+
+        // Controllers that know the database only look at what is new if told
+        if let Assigned::Added(_) = assigned {
+            sys.update_config_number();
+        }
+    }
+
+    /// Creates the accessory and its services and adds it to the database.
+    /// Nothing reaches the SDK unless the description is complete.
+    pub fn register<S: HapSys>(mut self, hap: &Hap<S>) -> Result<Registered, BuildError> {
+        if hap.is_started() {
+            return Err(BuildError::Started);
+        }
+        if self.services.is_empty() {
+            return Err(BuildError::NoServices);
+        }
+        if let Some(index) = self.services.iter().position(|s| s.name.is_none()) {
+            return Err(BuildError::Unnamed(index));
+        }
+        let primaries = self.services.iter().filter(|s| s.primary).count();
+        if primaries > 1 {
+            return Err(BuildError::Primaries(primaries));
+        }
+
+        let sys = hap.sys();
+        let acc = sys
+            .acc_create(&self.info)
+            .ok_or(BuildError::Sdk("creating the accessory"))?;
+
+        let mut services = Vec::new();
+        for (index, service) in self.services.iter().enumerate() {
+            let primary = service.primary || (primaries == 0 && index == 0);
+            let handle = service.register(hap, index, primary)?;
+            if sys.acc_add_serv(acc, handle.serv) != HAP_SUCCESS {
+                return Err(BuildError::Sdk("adding a service"));
+            }
+            services.push(handle);
+        }
+        if let Some(map) = self.iids.take() {
+            self.assign_iids(sys, map, &services);
+        }
+
+        if self.bridged {
+            let aid = sys.unique_aid(self.info.serial_num);
+            if sys.add_bridged_accessory(acc, aid) != HAP_SUCCESS {
+                return Err(BuildError::Sdk("adding the bridged accessory"));
+            }
+        } else {
+            sys.add_accessory(acc);
+        }
+
+        Ok(Registered { acc, services })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::c_void;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use super::*;
+    use crate::mock::{Call, MockSys};
+    use crate::sys::HAP_FAIL;
+    use crate::write::{Status, Write};
+
+    const LEVEL_UUID: &[u8] = b"0000D2A0-28E5-4C3F-9B6E-5A1D7E3C9000\0";
+
+    unsafe extern "C" fn identify(_: *mut c_void) -> i32 {
+        HAP_SUCCESS
+    }
+
+    fn info() -> AccessoryInfo<'static> {
+        let text = c"Test";
+        AccessoryInfo {
+            name: text,
+            model: text,
+            manufacturer: text,
+            serial_num: text,
+            fw_rev: text,
+            hw_rev: text,
+            pv: text,
+            cid: 7,
+            identify,
+        }
+    }
+
+    fn accept(_: &Write) -> Result<(), Status> {
+        Ok(())
+    }
+
+    #[test]
+    fn registers_in_sdk_order() {
+        let hap = Hap::new(MockSys::new());
+        let registered = AccessoryBuilder::new(info())
+            .service(ServiceBuilder::outlet().name("Outlet").on_write(&accept))
+            .register(&hap)
+            .unwrap();
+
+        let serv = registered.services[0].serv;
+        let calls = hap.sys().calls();
+        let position = |call: &Call| calls.iter().position(|c| c == call).unwrap();
+        assert!(matches!(calls[0], Call::AccCreate { .. }));
+        assert!(position(&Call::ServOutletCreate) < position(&Call::ServMarkPrimary(serv)));
+        assert!(
+            position(&Call::ServSetWriteCb(serv))
+                < position(&Call::AccAddServ(registered.acc, serv))
+        );
+        assert_eq!(calls.last(), Some(&Call::AddAccessory(registered.acc)));
+        assert!(registered.services[0].char(uuid::ON).is_some());
+        assert!(registered.services[0].char(uuid::NAME).is_some());
+    }
+
+    #[test]
+    fn incomplete_descriptions_reach_no_sdk_call() {
+        let hap = Hap::new(MockSys::new());
+        let unnamed = AccessoryBuilder::new(info())
+            .service(ServiceBuilder::outlet().name("Outlet"))
+            .service(ServiceBuilder::switch())
+            .register(&hap);
+        assert_eq!(unnamed.unwrap_err(), BuildError::Unnamed(1));
+
+        let primaries = AccessoryBuilder::new(info())
+            .service(ServiceBuilder::outlet().name("Outlet").primary())
+            .service(ServiceBuilder::switch().name("Switch").primary())
+            .register(&hap);
+        assert_eq!(primaries.unwrap_err(), BuildError::Primaries(2));
+
+        let empty = AccessoryBuilder::new(info()).register(&hap);
+        assert_eq!(empty.unwrap_err(), BuildError::NoServices);
+        assert!(hap.sys().calls().is_empty());
+    }
+
+    #[test]
+    fn only_the_marked_service_is_primary() {
+        let hap = Hap::new(MockSys::new());
+        let registered = AccessoryBuilder::new(info())
+            .service(ServiceBuilder::outlet().name("Outlet"))
+            .service(ServiceBuilder::switch().name("Switch").primary())
+            .register(&hap)
+            .unwrap();
+
+        let primaries: Vec<_> = hap
+            .sys()
+            .calls()
+            .into_iter()
+            .filter(|c| matches!(c, Call::ServMarkPrimary(_)))
+            .collect();
+        assert_eq!(
+            primaries,
+            vec![Call::ServMarkPrimary(registered.services[1].serv)]
+        );
+    }
+
+    #[test]
+    fn nothing_registers_after_start() {
+        let hap = Hap::new(MockSys::new());
+        assert_eq!(hap.start(), HAP_SUCCESS);
+
+        let late = AccessoryBuilder::new(info())
+            .service(ServiceBuilder::outlet().name("Outlet"))
+            .register(&hap);
+        assert_eq!(late.unwrap_err(), BuildError::Started);
+    }
+
+    static LEVEL: CharSlot = CharSlot::new();
+    static MISSING: CharSlot = CharSlot::new();
+
+    #[test]
+    fn slots_receive_their_characteristics() {
+        let hap = Hap::new(MockSys::new());
+        let registered = AccessoryBuilder::new(info())
+            .service(
+                ServiceBuilder::custom(b"A0\0")
+                    .name("Level")
+                    .char(LEVEL_UUID, 0, Value::Uint8(3))
+                    .bind(LEVEL_UUID, &LEVEL),
+            )
+            .register(&hap)
+            .unwrap();
+
+        let level = LEVEL.get().unwrap();
+        assert_eq!(registered.services[0].char(LEVEL_UUID), Some(level));
+        assert_eq!(hap.sys().value(level), Some(Value::Uint8(3)));
+
+        let missing = AccessoryBuilder::new(info())
+            .service(
+                ServiceBuilder::switch()
+                    .name("Switch")
+                    .bind(LEVEL_UUID, &MISSING),
+            )
+            .register(&hap);
+        assert!(matches!(
+            missing,
+            Err(BuildError::MissingChar { service: 0, .. })
+        ));
+        assert_eq!(MISSING.get(), None);
+    }
+
+    #[test]
+    fn bridged_accessories_keep_their_aid() {
+        let hap = Hap::new(MockSys::new());
+        let registered = AccessoryBuilder::new(info())
+            .service(ServiceBuilder::switch().name("Switch"))
+            .bridged(true)
+            .register(&hap)
+            .unwrap();
+
+        let aid = hap.sys().unique_aid(info().serial_num);
+        assert_eq!(
+            hap.sys().calls().last(),
+            Some(&Call::AddBridgedAccessory(registered.acc, aid))
+        );
+    }
+
+    static READ: AtomicBool = AtomicBool::new(false);
+
+    fn refresh(_: Char) -> Result<(), Status> {
+        READ.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    #[test]
+    fn reads_and_writes_share_the_service() {
+        let hap = Hap::new(MockSys::new());
+        let registered = AccessoryBuilder::new(info())
+            .service(
+                ServiceBuilder::switch()
+                    .name("Switch")
+                    .on_write(&accept)
+                    .on_read(&refresh),
+            )
+            .register(&hap)
+            .unwrap();
+
+        let service = &registered.services[0];
+        let on = service.char(uuid::ON).unwrap();
+        assert_eq!(hap.sys().read(service.serv, on), (HAP_SUCCESS, 0));
+        assert!(READ.load(Ordering::Relaxed));
+        assert_eq!(
+            hap.sys().write(service.serv, &[(on, Value::Bool(true))]),
+            (HAP_SUCCESS, vec![0])
+        );
+
+        let unread = AccessoryBuilder::new(info())
+            .service(ServiceBuilder::switch().name("Switch").on_write(&accept))
+            .register(&hap)
+            .unwrap();
+        assert_eq!(hap.sys().read(unread.services[0].serv, on).0, HAP_FAIL);
+    }
+
+    #[test]
+    fn iids_survive_a_reorganized_database() {
+        let mut map = IidMap::new();
+        let hap = Hap::new(MockSys::new());
+        let before = AccessoryBuilder::new(info())
+            .service(ServiceBuilder::outlet().name("Outlet"))
+            .service(ServiceBuilder::switch().name("Switch"))
+            .iids(&mut map)
+            .register(&hap)
+            .unwrap();
+        let switch_iid = hap.sys().serv_get_iid(before.services[1].serv);
+        let on_iid = hap
+            .sys()
+            .char_get_iid(before.services[1].char(uuid::ON).unwrap());
+        assert!(map.is_changed());
+        assert_eq!(hap.sys().config_updates(), 0);
+
+        // After an update that puts a new service first
+        let mut map = IidMap::decode(&map.encode());
+        let hap = Hap::new(MockSys::new());
+        let after = AccessoryBuilder::new(info())
+            .service(ServiceBuilder::custom(LEVEL_UUID).name("Level"))
+            .service(ServiceBuilder::switch().name("Switch"))
+            .service(ServiceBuilder::outlet().name("Outlet"))
+            .iids(&mut map)
+            .register(&hap)
+            .unwrap();
+
+        assert_eq!(hap.sys().serv_get_iid(after.services[1].serv), switch_iid);
+        assert_eq!(
+            hap.sys()
+                .char_get_iid(after.services[1].char(uuid::ON).unwrap()),
+            on_iid
+        );
+        let level_iid = hap.sys().serv_get_iid(after.services[0].serv);
+        assert!(level_iid > on_iid);
+        assert_eq!(hap.sys().config_updates(), 1);
+    }
+}
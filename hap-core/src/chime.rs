@@ -0,0 +1,384 @@
+//! Tone patterns for a piezo buzzer, played one at a time by priority.
+
+use std::borrow::Cow;
+use std::fmt;
+
+/// One tone and the silence after it; 0 Hz is a rest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Note {
+    pub hz: u16,
+    pub ms: u16,
+    pub gap_ms: u16,
+}
+
+const fn note(hz: u16, ms: u16, gap_ms: u16) -> Note {
+    Note { hz, ms, gap_ms }
+}
+
+/// What a pattern is for, in rising priority: a higher class preempts a
+/// lower one, which is dropped rather than queued.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Class {
+    /// Feedback for the user at the device, such as pairing
+    Courtesy,
+    Doorbell,
+    /// The security system
+    Alarm,
+    /// Smoke and leaks, played even when muted
+    Hazard,
+}
+
+impl Class {
+    pub fn is_mutable(self) -> bool {
+        self != Class::Hazard
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Pattern {
+    pub name: Cow<'static, str>,
+    pub class: Class,
+    pub notes: Cow<'static, [Note]>,
+    /// Plays until stopped
+    pub repeat: bool,
+}
+
+const fn builtin(
+    name: &'static str,
+    class: Class,
+    notes: &'static [Note],
+    repeat: bool,
+) -> Pattern {
+    Pattern {
+        name: Cow::Borrowed(name),
+        class,
+        notes: Cow::Borrowed(notes),
+        repeat,
+    }
+}
+
+pub const PAIRING_STARTED: Pattern = builtin(
+    "pairing",
+    Class::Courtesy,
+    &[note(2000, 60, 60), note(2000, 60, 0)],
+    false,
+);
+pub const PAIRED: Pattern = builtin(
+    "paired",
+    Class::Courtesy,
+    &[note(1500, 80, 20), note(2000, 80, 20), note(2500, 120, 0)],
+    false,
+);
+pub const DOORBELL: Pattern = builtin(
+    "doorbell",
+    Class::Doorbell,
+    &[note(659, 400, 50), note(523, 600, 0)],
+    false,
+);
+pub const LEAK: Pattern = builtin(
+    "leak",
+    Class::Hazard,
+    &[
+        note(3000, 150, 100),
+        note(3000, 150, 100),
+        note(3000, 150, 1000),
+    ],
+    true,
+);
+pub const ALARM: Pattern = builtin(
+    "alarm",
+    Class::Alarm,
+    &[note(2400, 250, 0), note(1800, 250, 0)],
+    true,
+);
+
+pub const TAG_ACCEPTED: Pattern = builtin("accepted", Class::Courtesy, &[note(2500, 80, 0)], false);
+pub const TAG_REJECTED: Pattern = builtin(
+    "rejected",
+    Class::Courtesy,
+    &[note(400, 150, 80), note(400, 150, 0)],
+    false,
+);
+
+pub const BUILTIN: &[Pattern] = &[
+    PAIRING_STARTED,
+    PAIRED,
+    DOORBELL,
+    LEAK,
+    ALARM,
+    TAG_ACCEPTED,
+    TAG_REJECTED,
+];
+
+pub fn builtin_pattern(name: &str) -> Option<&'static Pattern> {
+    BUILTIN.iter().find(|pattern| pattern.name == name)
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseError(pub String);
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Parses `hz/ms/gap` triples separated by spaces or commas, such as
+/// `880/200/50 660/400/0`.
+pub fn parse(text: &str) -> Result<Vec<Note>, ParseError> {
+    let notes = text
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|triple| !triple.is_empty())
+        .map(|triple| {
+            let fields: Vec<&str> = triple.split('/').collect();
+            let [hz, ms, gap_ms] = fields[..] else {
+                return Err(ParseError(format!("'{}' is not hz/ms/gap", triple)));
+            };
+            let number = |field: &str| {
+                field
+                    .parse::<u16>()
+                    .map_err(|_| ParseError(format!("'{}' in '{}' is not a number", field, triple)))
+            };
+            Ok(note(number(hz)?, number(ms)?, number(gap_ms)?))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    if notes.is_empty() {
+        return Err(ParseError("no notes".into()));
+    }
+
+    Ok(notes)
+}
+
+/// Plays patterns note by note, ticked with a monotonic clock in
+/// milliseconds; the caller drives the buzzer with what the ticks return.
+#[derive(Debug, Default)]
+pub struct Player {
+    playing: Option<Pattern>,
+    note: usize,
+    /// Sounding the note, or in the gap after it
+    sounding: bool,
+    until: u64,
+    muted: bool,
+}
+
+impl Player {
+    pub const fn new() -> Self {
+        Self {
+            playing: None,
+            note: 0,
+            sounding: false,
+            until: 0,
+            muted: false,
+        }
+    }
+
+    pub fn playing(&self) -> Option<&Pattern> {
+        self.playing.as_ref()
+    }
+
+    pub fn is_muted(&self) -> bool {
+        self.muted
+    }
+
+    /// Muting stops what is playing unless it is a hazard.
+    pub fn set_muted(&mut self, muted: bool) {
+        self.muted = muted;
+        if muted {
+            if let Some(class) = self.playing.as_ref().map(|pattern| pattern.class) {
+                if class.is_mutable() {
+                    self.playing = None;
+                }
+            }
+        }
+    }
+
+    /// Starts `pattern` unless muted or something more important plays;
+    /// tells whether it started. A pattern without any length never does.
+    pub fn play(&mut self, pattern: Pattern, now: u64) -> bool {
+        if pattern
+            .notes
+            .iter()
+            .all(|note| note.ms == 0 && note.gap_ms == 0)
+        {
+            return false;
+        }
+        if self.muted && pattern.class.is_mutable() {
+            return false;
+        }
+        if let Some(playing) = &self.playing {
+            if playing.class > pattern.class {
+                return false;
+            }
+        }
+
+        self.playing = Some(pattern);
+        self.note = 0;
+        self.sounding = false;
+        self.until = now;
+        true
+    }
+
+    /// Stops what is playing if it is of `class`, or anything for none.
+    pub fn stop(&mut self, class: Option<Class>) {
+        let stops = match (&self.playing, class) {
+            (Some(playing), Some(class)) => playing.class == class,
+            (Some(_), None) => true,
+            (None, _) => false,
+        };
+        if stops {
+            self.playing = None;
+        }
+    }
+
+    /// The tone to sound from `now`, 0 for silence; unchanged between the
+    /// steps of a pattern.
+    pub fn tick(&mut self, now: u64) -> u16 {
+        loop {
+            let Some(pattern) = &self.playing else {
+                return 0;
+            };
+            // `note` is the next one, the one sounding or in its gap is before it
+            if now < self.until {
+                return if self.sounding {
+                    pattern.notes[self.note - 1].hz
+                } else {
+                    0
+                };
+            }
+
+            if self.sounding {
+                self.sounding = false;
+                self.until += pattern.notes[self.note - 1].gap_ms as u64;
+                continue;
+            }
+            if self.note == pattern.notes.len() {
+                if !pattern.repeat {
+                    self.playing = None;
+                    return 0;
+                }
+                self.note = 0;
+            }
+            self.sounding = true;
+            self.until += pattern.notes[self.note].ms as u64;
+            self.note += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn custom(class: Class) -> Pattern {
+        Pattern {
+            name: "custom".into(),
+            class,
+            notes: vec![note(1000, 100, 50), note(0, 20, 0), note(500, 100, 0)].into(),
+            repeat: false,
+        }
+    }
+
+    #[test]
+    fn parses_triples() {
+        assert_eq!(
+            parse("880/200/50, 660/400/0").unwrap(),
+            vec![note(880, 200, 50), note(660, 400, 0)]
+        );
+        assert_eq!(parse(" 0/10/0 ").unwrap(), vec![note(0, 10, 0)]);
+        assert!(parse("").is_err());
+        assert!(parse("880/200").is_err());
+        assert!(parse("880/200/x").is_err());
+        assert!(parse("70000/1/1").is_err());
+    }
+
+    #[test]
+    fn plays_notes_and_gaps() {
+        let mut player = Player::new();
+        assert!(player.play(custom(Class::Courtesy), 1000));
+        assert_eq!(player.tick(1000), 1000);
+        assert_eq!(player.tick(1099), 1000);
+        assert_eq!(player.tick(1100), 0);
+        // The rest, then the last note
+        assert_eq!(player.tick(1150), 0);
+        assert_eq!(player.tick(1170), 500);
+        assert_eq!(player.tick(1270), 0);
+        assert!(player.playing().is_none());
+
+        // Ticked in the gap before the end
+        player.play(PAIRING_STARTED, 0);
+        assert_eq!(player.tick(0), 2000);
+        assert_eq!(player.tick(70), 0);
+        assert_eq!(player.tick(125), 2000);
+        assert_eq!(player.tick(180), 0);
+        assert!(player.playing().is_none());
+    }
+
+    #[test]
+    fn catches_up_on_late_ticks() {
+        let mut player = Player::new();
+        player.play(custom(Class::Courtesy), 0);
+        assert_eq!(player.tick(180), 500);
+    }
+
+    #[test]
+    fn repeats_until_stopped() {
+        let mut player = Player::new();
+        player.play(ALARM, 0);
+        assert_eq!(player.tick(0), 2400);
+        assert_eq!(player.tick(250), 1800);
+        assert_eq!(player.tick(500), 2400);
+        player.stop(Some(Class::Doorbell));
+        assert_eq!(player.tick(600), 2400);
+        player.stop(Some(Class::Alarm));
+        assert_eq!(player.tick(600), 0);
+    }
+
+    #[test]
+    fn higher_classes_preempt() {
+        let mut player = Player::new();
+        player.play(PAIRED, 0);
+        assert!(player.play(ALARM, 10));
+        assert!(!player.play(DOORBELL, 20));
+        assert!(player.play(LEAK, 30));
+        assert_eq!(player.playing().unwrap().name, "leak");
+        assert!(!player.play(ALARM, 40));
+    }
+
+    #[test]
+    fn refuses_patterns_without_length() {
+        let mut player = Player::new();
+        let silent = Pattern {
+            notes: vec![note(1000, 0, 0)].into(),
+            repeat: true,
+            ..custom(Class::Courtesy)
+        };
+        assert!(!player.play(silent, 0));
+        assert_eq!(player.tick(0), 0);
+    }
+
+    #[test]
+    fn mute_spares_hazards() {
+        let mut player = Player::new();
+        player.play(DOORBELL, 0);
+        player.set_muted(true);
+        assert!(player.playing().is_none());
+        assert!(!player.play(ALARM, 10));
+
+        assert!(player.play(LEAK, 10));
+        player.set_muted(true);
+        assert_eq!(player.tick(10), 3000);
+    }
+
+    #[test]
+    fn finds_the_builtin_patterns() {
+        for name in [
+            "pairing", "paired", "doorbell", "leak", "alarm", "accepted", "rejected",
+        ] {
+            assert!(builtin_pattern(name).is_some(), "{}", name);
+        }
+        assert!(builtin_pattern("siren").is_none());
+    }
+}
@@ -0,0 +1,247 @@
+use std::time::Duration;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Event {
+    Pressed,
+    Released,
+    Click,
+    DoubleClick,
+    TripleClick,
+    /// Released after being held for at least the long-press time
+    LongPress(Duration),
+}
+
+#[derive(Clone, Copy)]
+pub struct Timing {
+    /// How long a level has to be stable before it counts
+    pub debounce_ms: u64,
+    pub long_press_ms: u64,
+    /// Window after a click in which another click makes a double or a
+    /// triple click
+    pub double_click_ms: u64,
+}
+
+/// Turns the raw, bouncing edges of one button into events. Pure state, fed
+/// with timestamps, so it behaves the same for recorded edge sequences.
+pub struct Classifier {
+    timing: Timing,
+    pressed: bool,
+    /// Level seen last and since when, not yet stable for the debounce time
+    candidate: Option<(bool, u64)>,
+    pressed_at: u64,
+    clicks: u8,
+    click_deadline: u64,
+}
+
+impl Classifier {
+    pub const fn new(timing: Timing) -> Self {
+        Self {
+            timing,
+            pressed: false,
+            candidate: None,
+            pressed_at: 0,
+            clicks: 0,
+            click_deadline: 0,
+        }
+    }
+
+    /// Records a raw edge; bouncing back to the stable level cancels it.
+    pub fn edge(&mut self, pressed: bool, at_ms: u64) {
+        let latest = self.candidate.map_or(self.pressed, |(level, _)| level);
+        if pressed == latest {
+            return;
+        }
+
+        self.candidate = if pressed == self.pressed {
+            None
+        } else {
+            Some((pressed, at_ms))
+        };
+    }
+
+    /// Advances time to `now_ms`, emitting the events that became certain.
+    pub fn poll(&mut self, now_ms: u64, emit: &mut impl FnMut(Event)) {
+        if let Some((pressed, since)) = self.candidate {
+            if now_ms.saturating_sub(since) >= self.timing.debounce_ms {
+                self.candidate = None;
+                self.commit(pressed, since, emit);
+            }
+        }
+
+        // A press that started within the window may still become a double click
+        let pending_press = self
+            .candidate
+            .is_some_and(|(pressed, since)| pressed && since < self.click_deadline);
+        if !self.pressed && self.clicks > 0 && now_ms >= self.click_deadline && !pending_press {
+            self.flush(emit);
+        }
+    }
+
+    /// Emits the clicks counted so far, once no more can join them.
+    fn flush(&mut self, emit: &mut impl FnMut(Event)) {
+        match self.clicks {
+            0 => {}
+            1 => emit(Event::Click),
+            _ => emit(Event::DoubleClick),
+        }
+        self.clicks = 0;
+    }
+
+    fn commit(&mut self, pressed: bool, at_ms: u64, emit: &mut impl FnMut(Event)) {
+        self.pressed = pressed;
+
+        if pressed {
+            if at_ms >= self.click_deadline {
+                self.flush(emit);
+            }
+            self.pressed_at = at_ms;
+            emit(Event::Pressed);
+            return;
+        }
+
+        emit(Event::Released);
+
+        // Edges can arrive late, after a level sample took their place
+        let held = at_ms.saturating_sub(self.pressed_at);
+        if held >= self.timing.long_press_ms {
+            self.flush(emit);
+            emit(Event::LongPress(Duration::from_millis(held)));
+        } else if self.clicks == 2 {
+            self.clicks = 0;
+            emit(Event::TripleClick);
+        } else {
+            self.clicks += 1;
+            self.click_deadline = at_ms + self.timing.double_click_ms;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMING: Timing = Timing {
+        debounce_ms: 30,
+        long_press_ms: 1000,
+        double_click_ms: 300,
+    };
+
+    /// Feeds (level, at) edges, then polls in 10 ms steps up to `until`.
+    fn run(edges: &[(bool, u64)], until: u64) -> Vec<Event> {
+        let mut classifier = Classifier::new(TIMING);
+        let mut events = Vec::new();
+        let mut edges = edges.iter().peekable();
+
+        for now in (0..=until).step_by(10) {
+            while let Some(&&(pressed, at)) = edges.peek() {
+                if at > now {
+                    break;
+                }
+                classifier.edge(pressed, at);
+                edges.next();
+            }
+            classifier.poll(now, &mut |event| events.push(event));
+        }
+
+        events
+    }
+
+    #[test]
+    fn click_waits_for_the_double_click_window() {
+        let events = run(&[(true, 100), (false, 200)], 400);
+        assert_eq!(events, vec![Event::Pressed, Event::Released]);
+
+        let events = run(&[(true, 100), (false, 200)], 600);
+        assert_eq!(events, vec![Event::Pressed, Event::Released, Event::Click]);
+    }
+
+    #[test]
+    fn second_press_in_the_window_is_a_double_click() {
+        let events = run(
+            &[(true, 100), (false, 200), (true, 350), (false, 450)],
+            1000,
+        );
+        assert_eq!(
+            events,
+            vec![
+                Event::Pressed,
+                Event::Released,
+                Event::Pressed,
+                Event::Released,
+                Event::DoubleClick
+            ]
+        );
+    }
+
+    #[test]
+    fn third_press_in_the_window_is_a_triple_click() {
+        let edges = [
+            (true, 100),
+            (false, 200),
+            (true, 350),
+            (false, 450),
+            (true, 600),
+            (false, 700),
+        ];
+        let events = run(&edges, 1200);
+        assert_eq!(
+            events
+                .iter()
+                .filter(|event| **event != Event::Pressed && **event != Event::Released)
+                .collect::<Vec<_>>(),
+            vec![&Event::TripleClick]
+        );
+    }
+
+    #[test]
+    fn second_press_after_the_window_is_two_clicks() {
+        let events = run(
+            &[(true, 100), (false, 200), (true, 700), (false, 800)],
+            1500,
+        );
+        assert_eq!(
+            events,
+            vec![
+                Event::Pressed,
+                Event::Released,
+                Event::Click,
+                Event::Pressed,
+                Event::Released,
+                Event::Click
+            ]
+        );
+    }
+
+    #[test]
+    fn holding_is_a_long_press() {
+        let events = run(&[(true, 100), (false, 1600)], 2000);
+        assert_eq!(
+            events,
+            vec![
+                Event::Pressed,
+                Event::Released,
+                Event::LongPress(Duration::from_millis(1500))
+            ]
+        );
+    }
+
+    #[test]
+    fn bounces_shorter_than_the_debounce_time_are_ignored() {
+        let events = run(&[(true, 100), (false, 105), (true, 110), (false, 115)], 500);
+        assert!(events.is_empty(), "{:?}", events);
+    }
+
+    #[test]
+    fn bouncing_press_counts_once() {
+        let edges = [
+            (true, 100),
+            (false, 103),
+            (true, 106),
+            (false, 250),
+            (true, 253),
+            (false, 256),
+        ];
+        let events = run(&edges, 800);
+        assert_eq!(events, vec![Event::Pressed, Event::Released, Event::Click]);
+    }
+}
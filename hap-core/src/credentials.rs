@@ -0,0 +1,134 @@
+//! Wi-Fi credentials as the provisioning portal's form posts them.
+
+use std::fmt;
+
+// 802.11 limits, the SSID in bytes and a WPA2 passphrase in characters
+const MAX_SSID_LEN: usize = 32;
+const MIN_PASSWORD_LEN: usize = 8;
+const MAX_PASSWORD_LEN: usize = 63;
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct Credentials {
+    pub ssid: String,
+    /// Empty for an open network
+    pub password: String,
+}
+
+// Never the password, credentials end up in logs
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Credentials")
+            .field("ssid", &self.ssid)
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FormError(pub &'static str);
+
+impl fmt::Display for FormError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.0)
+    }
+}
+
+impl std::error::Error for FormError {}
+
+impl Credentials {
+    pub fn validate(&self) -> Result<(), FormError> {
+        if self.ssid.is_empty() {
+            return Err(FormError("the SSID is empty"));
+        }
+        if self.ssid.len() > MAX_SSID_LEN {
+            return Err(FormError("the SSID is longer than 32 bytes"));
+        }
+        let password_len = self.password.chars().count();
+        if password_len > 0 && !(MIN_PASSWORD_LEN..=MAX_PASSWORD_LEN).contains(&password_len) {
+            return Err(FormError("the password needs 8 to 63 characters"));
+        }
+
+        Ok(())
+    }
+}
+
+fn hex(digit: u8) -> Option<u8> {
+    (digit as char).to_digit(16).map(|value| value as u8)
+}
+
+/// Decodes one `application/x-www-form-urlencoded` name or value.
+fn decode(text: &str) -> Result<String, FormError> {
+    let mut bytes = Vec::with_capacity(text.len());
+    let mut rest = text.bytes();
+    while let Some(byte) = rest.next() {
+        match byte {
+            b'+' => bytes.push(b' '),
+            b'%' => {
+                let high = rest.next().and_then(hex);
+                let low = rest.next().and_then(hex);
+                let (Some(high), Some(low)) = (high, low) else {
+                    return Err(FormError("broken percent escape"));
+                };
+                bytes.push(high << 4 | low);
+            }
+            byte => bytes.push(byte),
+        }
+    }
+
+    String::from_utf8(bytes).map_err(|_| FormError("not UTF-8"))
+}
+
+/// Parses the `ssid` and `password` fields of a posted form, and checks them.
+pub fn parse_form(body: &str) -> Result<Credentials, FormError> {
+    let mut ssid = None;
+    let mut password = String::new();
+    for pair in body.trim_end().split('&').filter(|pair| !pair.is_empty()) {
+        let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+        match &*decode(name)? {
+            "ssid" => ssid = Some(decode(value)?),
+            "password" => password = decode(value)?,
+            _ => {}
+        }
+    }
+
+    let credentials = Credentials {
+        ssid: ssid.ok_or(FormError("no SSID"))?,
+        password,
+    };
+    credentials.validate()?;
+
+    Ok(credentials)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_forms() {
+        let credentials = parse_form("ssid=My+Home%21&password=s3cret%26pass").unwrap();
+        assert_eq!(credentials.ssid, "My Home!");
+        assert_eq!(credentials.password, "s3cret&pass");
+
+        // Open networks, unknown fields and a trailing newline
+        let credentials = parse_form("submit=Save&ssid=Caf%C3%A9&password=\r\n").unwrap();
+        assert_eq!(credentials.ssid, "Café");
+        assert_eq!(credentials.password, "");
+    }
+
+    #[test]
+    fn refuses_bad_forms() {
+        assert!(parse_form("password=12345678").is_err());
+        assert!(parse_form("ssid=&password=12345678").is_err());
+        assert!(parse_form("ssid=home&password=short").is_err());
+        assert!(parse_form(&format!("ssid={}", "x".repeat(33))).is_err());
+        assert!(parse_form(&format!("ssid=home&password={}", "x".repeat(64))).is_err());
+        assert!(parse_form("ssid=home%2").is_err());
+        assert!(parse_form("ssid=%ff").is_err());
+    }
+
+    #[test]
+    fn keeps_the_password_out_of_logs() {
+        let credentials = parse_form("ssid=home&password=hunter2hunter2").unwrap();
+        assert!(!format!("{:?}", credentials).contains("hunter2"));
+    }
+}
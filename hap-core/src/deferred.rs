@@ -0,0 +1,56 @@
+//! Writes applied by a worker task rather than the HAP server task: the
+//! handler records the target and succeeds at once, the worker does the slow
+//! part and confirms through the characteristic's update.
+
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+/// The latest target not yet applied; a newer one replaces it, only the
+/// last of a burst of writes is worth driving to.
+#[derive(Debug, Default)]
+pub struct Deferred<T> {
+    pending: Mutex<Option<T>>,
+}
+
+impl<T> Deferred<T> {
+    pub const fn new() -> Self {
+        Self {
+            pending: Mutex::new(None),
+        }
+    }
+
+    pub fn submit(&self, value: T) {
+        *self.lock() = Some(value);
+    }
+
+    /// The target to apply, if one was submitted since the last call.
+    pub fn take(&self) -> Option<T> {
+        self.lock().take()
+    }
+
+    pub fn is_pending(&self) -> bool {
+        self.lock().is_some()
+    }
+
+    // A panicking worker leaves the option whole
+    fn lock(&self) -> MutexGuard<'_, Option<T>> {
+        self.pending.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_the_latest_target() {
+        let target = Deferred::new();
+        assert_eq!(target.take(), None);
+
+        target.submit(30u8);
+        target.submit(70);
+        assert!(target.is_pending());
+        assert_eq!(target.take(), Some(70));
+        assert!(!target.is_pending());
+        assert_eq!(target.take(), None);
+    }
+}
@@ -0,0 +1,220 @@
+//! Ultrasonic ranging (HC-SR04, JSN-SR04T) and what the distance says about
+//! a garage door and the car under the sensor.
+
+/// The result of one ping.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Ping {
+    /// Centimetres to the nearest target
+    Echo(f32),
+    /// The sensor answered that nothing was within its range
+    OutOfRange,
+    /// No echo pulse at all, the sensor is missing or dead
+    NoEcho,
+}
+
+/// Speed of sound in m/s at `celsius`.
+pub fn speed_of_sound(celsius: f32) -> f32 {
+    331.3 + 0.606 * celsius
+}
+
+/// Converts the width of the echo pulse, there and back, to centimetres.
+/// Pulses of `max_us` or longer are the sensors' "nothing in range".
+pub fn ping(echo_us: Option<u32>, celsius: f32, max_us: u32) -> Ping {
+    match echo_us {
+        None => Ping::NoEcho,
+        Some(us) if us >= max_us => Ping::OutOfRange,
+        Some(us) => Ping::Echo(us as f32 * speed_of_sound(celsius) / 2.0 / 10_000.0),
+    }
+}
+
+/// The median echo if most pings got one, else whatever most of them were,
+/// so a single spurious echo or dropout does not count.
+pub fn median(pings: &[Ping]) -> Ping {
+    let mut echoes: Vec<f32> = pings
+        .iter()
+        .filter_map(|ping| match ping {
+            Ping::Echo(cm) => Some(*cm),
+            _ => None,
+        })
+        .collect();
+    let majority = pings.len() / 2 + 1;
+    if echoes.len() >= majority {
+        echoes.sort_by(f32::total_cmp);
+        return Ping::Echo(echoes[echoes.len() / 2]);
+    }
+
+    let out_of_range = pings.iter().filter(|p| **p == Ping::OutOfRange).count();
+    if out_of_range > 0 && out_of_range + echoes.len() >= majority {
+        Ping::OutOfRange
+    } else {
+        Ping::NoEcho
+    }
+}
+
+/// HAP's Current Door State.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DoorState {
+    Open = 0,
+    Closed = 1,
+    Opening = 2,
+    Closing = 3,
+    Stopped = 4,
+}
+
+/// Distance bands of a sensor on the ceiling above a tilting or sectional
+/// door: the open door is close, the closed one leaves the floor or the car.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DoorBands {
+    pub open_within: f32,
+    pub closed_beyond: f32,
+    pub hysteresis: f32,
+}
+
+/// Follows the door through the bands; in between it is stopped part-way.
+#[derive(Clone, Debug)]
+pub struct DoorTracker {
+    bands: DoorBands,
+    state: DoorState,
+}
+
+impl DoorTracker {
+    pub const fn new(bands: DoorBands) -> Self {
+        Self {
+            bands,
+            state: DoorState::Closed,
+        }
+    }
+
+    pub fn state(&self) -> DoorState {
+        self.state
+    }
+
+    /// A band is left only once the distance is the hysteresis past its edge.
+    pub fn update(&mut self, cm: f32) -> DoorState {
+        let DoorBands {
+            open_within,
+            closed_beyond,
+            hysteresis,
+        } = self.bands;
+        let hold = |state| if self.state == state { hysteresis } else { 0.0 };
+
+        self.state = if cm <= open_within + hold(DoorState::Open) {
+            DoorState::Open
+        } else if cm >= closed_beyond - hold(DoorState::Closed) {
+            DoorState::Closed
+        } else {
+            DoorState::Stopped
+        };
+
+        self.state
+    }
+}
+
+/// Whether a car is parked under the sensor, with hysteresis so a car at the
+/// edge does not flap.
+#[derive(Clone, Debug)]
+pub struct Presence {
+    within: f32,
+    hysteresis: f32,
+    present: bool,
+}
+
+impl Presence {
+    pub const fn new(within: f32, hysteresis: f32) -> Self {
+        Self {
+            within,
+            hysteresis,
+            present: false,
+        }
+    }
+
+    pub fn is_present(&self) -> bool {
+        self.present
+    }
+
+    pub fn update(&mut self, cm: f32) -> bool {
+        let limit = if self.present {
+            self.within + self.hysteresis
+        } else {
+            self.within
+        };
+        self.present = cm <= limit;
+
+        self.present
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compensates_the_speed_of_sound() {
+        // 1 m there and back at 20 °C
+        let Ping::Echo(cm) = ping(Some(5829), 20.0, 30_000) else {
+            panic!("no echo");
+        };
+        assert!((cm - 100.0).abs() < 0.1, "{}", cm);
+
+        let Ping::Echo(cold) = ping(Some(5829), -10.0, 30_000) else {
+            panic!("no echo");
+        };
+        assert!(cold < 95.0, "{}", cold);
+
+        assert_eq!(ping(Some(30_000), 20.0, 30_000), Ping::OutOfRange);
+        assert_eq!(ping(None, 20.0, 30_000), Ping::NoEcho);
+    }
+
+    #[test]
+    fn takes_the_median_of_the_echoes() {
+        let pings = [
+            Ping::Echo(101.0),
+            Ping::Echo(99.0),
+            Ping::Echo(450.0),
+            Ping::NoEcho,
+            Ping::Echo(100.0),
+        ];
+        assert_eq!(median(&pings), Ping::Echo(101.0));
+    }
+
+    #[test]
+    fn tells_out_of_range_from_a_dead_sensor() {
+        let far = [
+            Ping::OutOfRange,
+            Ping::OutOfRange,
+            Ping::Echo(300.0),
+            Ping::OutOfRange,
+            Ping::NoEcho,
+        ];
+        assert_eq!(median(&far), Ping::OutOfRange);
+
+        let dead = [Ping::NoEcho; 5];
+        assert_eq!(median(&dead), Ping::NoEcho);
+    }
+
+    #[test]
+    fn maps_the_distance_to_the_door_state() {
+        let mut door = DoorTracker::new(DoorBands {
+            open_within: 40.0,
+            closed_beyond: 100.0,
+            hysteresis: 10.0,
+        });
+        assert_eq!(door.update(30.0), DoorState::Open);
+        // Within the hysteresis of the open band
+        assert_eq!(door.update(45.0), DoorState::Open);
+        assert_eq!(door.update(60.0), DoorState::Stopped);
+        assert_eq!(door.update(95.0), DoorState::Stopped);
+        assert_eq!(door.update(100.0), DoorState::Closed);
+        assert_eq!(door.update(92.0), DoorState::Closed);
+        assert_eq!(door.update(89.0), DoorState::Stopped);
+    }
+
+    #[test]
+    fn detects_a_parked_car() {
+        let mut car = Presence::new(150.0, 20.0);
+        assert!(!car.update(160.0));
+        assert!(car.update(140.0));
+        assert!(car.update(165.0));
+        assert!(!car.update(175.0));
+    }
+}
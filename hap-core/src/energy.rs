@@ -0,0 +1,316 @@
+//! Energy accumulation: active power integrated into Wh, in daily buckets
+//! by local day and a lifetime total.
+
+pub const DAYS: usize = 7;
+
+const MWH_PER_WH: u64 = 1000;
+
+/// The day number of a local date, days since 1970-01-01, from the year and
+/// the zero-based day of the year as `localtime` gives them.
+pub fn day_number(year: i32, yday: u16) -> u32 {
+    let previous = year as i64 - 1;
+    let leap_days = |y: i64| y / 4 - y / 100 + y / 400;
+    let days = (year as i64 - 1970) * 365 + leap_days(previous) - leap_days(1969) + yday as i64;
+
+    days.max(0) as u32
+}
+
+/// What is persisted, and restored after a reboot.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Totals {
+    /// The local day of `days[0]`, none until the clock was first synced
+    pub day: Option<u32>,
+    /// Wh per day, today first
+    pub days: [u32; DAYS],
+    /// Wh, wrapping; `wraps` counts the times it did
+    pub lifetime: u32,
+    pub wraps: u32,
+}
+
+impl Totals {
+    pub fn today(&self) -> u32 {
+        self.days[0]
+    }
+
+    pub fn lifetime_wh(&self) -> u64 {
+        (self.wraps as u64) << 32 | self.lifetime as u64
+    }
+
+    fn add_lifetime(&mut self, wh: u32) {
+        let (lifetime, wrapped) = self.lifetime.overflowing_add(wh);
+        self.lifetime = lifetime;
+        if wrapped {
+            self.wraps += 1;
+        }
+    }
+}
+
+/// What a sample changed, for the caller to store.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Changed {
+    /// Whole Wh were added
+    pub energy: bool,
+    /// A new day started, or the pending energy went to one
+    pub day: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct Accumulator {
+    totals: Totals,
+    /// A day was given since the start, until then `totals.day` may be stale
+    synced: bool,
+    /// Whole Wh taken before the clock was synced, for the day it syncs on
+    pending: u32,
+    /// Below a Wh, carried to the next sample
+    residual_mwh: u64,
+    /// The previous sample, milliseconds on a monotonic clock and watts
+    last: Option<(u64, f32)>,
+    max_gap_ms: u64,
+}
+
+impl Accumulator {
+    /// Samples further apart than `max_gap_ms`, a meter gone offline, count
+    /// nothing for the time between them.
+    pub const fn new(max_gap_ms: u64) -> Self {
+        Self {
+            totals: Totals {
+                day: None,
+                days: [0; DAYS],
+                lifetime: 0,
+                wraps: 0,
+            },
+            synced: false,
+            pending: 0,
+            residual_mwh: 0,
+            last: None,
+            max_gap_ms,
+        }
+    }
+
+    /// Continues from what was stored before a reboot.
+    pub fn restore(&mut self, totals: Totals) {
+        self.totals = totals;
+    }
+
+    pub fn totals(&self) -> &Totals {
+        &self.totals
+    }
+
+    pub fn pending(&self) -> u32 {
+        self.pending
+    }
+
+    /// Takes the active power measured at `now`, and the local day if the
+    /// clock is synced; the energy since the previous sample counts at the
+    /// mean of both powers.
+    pub fn sample(&mut self, watts: f32, now: u64, day: Option<u32>) -> Changed {
+        let watts = watts.max(0.0);
+        let mut changed = Changed {
+            energy: false,
+            day: self.roll(day),
+        };
+
+        let Some((last, last_watts)) = self.last.replace((now, watts)) else {
+            return changed;
+        };
+        let elapsed = now.saturating_sub(last);
+        if elapsed > self.max_gap_ms {
+            return changed;
+        }
+
+        // W * ms / 3600 = mWh
+        let mwh = (watts + last_watts) as f64 / 2.0 * elapsed as f64 / 3600.0;
+        self.residual_mwh += mwh.round() as u64;
+        let wh = (self.residual_mwh / MWH_PER_WH) as u32;
+        if wh == 0 {
+            return changed;
+        }
+        self.residual_mwh %= MWH_PER_WH;
+
+        self.totals.add_lifetime(wh);
+        if self.synced {
+            self.totals.days[0] = self.totals.days[0].saturating_add(wh);
+        } else {
+            self.pending = self.pending.saturating_add(wh);
+        }
+        changed.energy = true;
+
+        changed
+    }
+
+    /// Moves the buckets on to `day`, and hands the pending energy to the
+    /// first day known.
+    fn roll(&mut self, day: Option<u32>) -> bool {
+        let Some(day) = day else {
+            return false;
+        };
+
+        let rolled = match self.totals.day {
+            // A clock stepping back stays on the later day
+            Some(current) if day <= current => false,
+            Some(current) => {
+                let shift = ((day - current) as usize).min(DAYS);
+                self.totals.days.rotate_right(shift);
+                self.totals.days[..shift].fill(0);
+                self.totals.day = Some(day);
+                true
+            }
+            // Restored from a blank store, or never synced before
+            None => {
+                self.totals.days = [0; DAYS];
+                self.totals.day = Some(day);
+                true
+            }
+        };
+
+        if self.synced {
+            return rolled;
+        }
+        self.synced = true;
+        let pending = std::mem::take(&mut self.pending);
+        self.totals.days[0] = self.totals.days[0].saturating_add(pending);
+
+        rolled || pending > 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOUR: u64 = 3_600_000;
+
+    #[test]
+    fn numbers_days() {
+        assert_eq!(day_number(1970, 0), 0);
+        assert_eq!(day_number(1971, 0), 365);
+        // 2024-01-01 and 2024-12-31, a leap year
+        assert_eq!(day_number(2024, 0), 19723);
+        assert_eq!(day_number(2024, 365), 20088);
+        assert_eq!(day_number(2025, 0), 20089);
+        assert_eq!(day_number(2000, 59), 11016);
+    }
+
+    #[test]
+    fn integrates_power() {
+        let mut energy = Accumulator::new(HOUR);
+        energy.sample(100.0, 0, Some(10));
+        let changed = energy.sample(100.0, HOUR / 2, Some(10));
+        assert!(changed.energy);
+        assert_eq!(energy.totals().today(), 50);
+
+        // The mean of both ends
+        energy.sample(300.0, HOUR, Some(10));
+        assert_eq!(energy.totals().today(), 150);
+        assert_eq!(energy.totals().lifetime_wh(), 150);
+    }
+
+    #[test]
+    fn carries_fractions_of_a_wh() {
+        let mut energy = Accumulator::new(HOUR);
+        energy.sample(1.0, 0, Some(1));
+        for i in 1..=36 {
+            energy.sample(1.0, i * 100_000, Some(1));
+        }
+        assert_eq!(energy.totals().today(), 1);
+    }
+
+    #[test]
+    fn skips_gaps() {
+        let mut energy = Accumulator::new(60_000);
+        energy.sample(1000.0, 0, Some(1));
+        energy.sample(1000.0, HOUR, Some(1));
+        assert_eq!(energy.totals().today(), 0);
+        energy.sample(1000.0, HOUR + 36_000, Some(1));
+        assert_eq!(energy.totals().today(), 10);
+    }
+
+    #[test]
+    fn rolls_over_at_midnight() {
+        let mut energy = Accumulator::new(HOUR);
+        energy.sample(1000.0, 0, Some(100));
+        energy.sample(1000.0, HOUR, Some(100));
+        let changed = energy.sample(1000.0, 2 * HOUR, Some(101));
+        assert!(changed.day);
+        assert_eq!(energy.totals().days[..3], [1000, 1000, 0]);
+
+        // Two days later, the power falling to nothing on the way
+        energy.sample(0.0, 3 * HOUR, Some(103));
+        assert_eq!(energy.totals().days[..4], [500, 0, 1000, 1000]);
+        assert_eq!(energy.totals().day, Some(103));
+
+        // A week later nothing is left but the lifetime
+        energy.sample(0.0, 4 * HOUR, Some(120));
+        assert_eq!(energy.totals().days, [0; DAYS]);
+        assert_eq!(energy.totals().lifetime_wh(), 2500);
+    }
+
+    #[test]
+    fn assigns_pending_energy_once_synced() {
+        let mut energy = Accumulator::new(HOUR);
+        energy.restore(Totals {
+            day: Some(50),
+            days: [5, 6, 0, 0, 0, 0, 0],
+            lifetime: 11,
+            wraps: 0,
+        });
+        energy.sample(120.0, 0, None);
+        energy.sample(120.0, HOUR, None);
+        assert_eq!(energy.pending(), 120);
+        assert_eq!(energy.totals().today(), 5);
+        assert_eq!(energy.totals().lifetime_wh(), 131);
+
+        // Synced on the next day
+        assert!(energy.sample(0.0, HOUR + 1, Some(51)).day);
+        assert_eq!(energy.pending(), 0);
+        assert_eq!(energy.totals().days[..3], [120, 5, 6]);
+    }
+
+    #[test]
+    fn resumes_the_same_day() {
+        let mut energy = Accumulator::new(HOUR);
+        energy.restore(Totals {
+            day: Some(50),
+            days: [40, 0, 0, 0, 0, 0, 0],
+            lifetime: 40,
+            wraps: 0,
+        });
+        assert!(!energy.sample(100.0, 0, Some(50)).day);
+        energy.sample(100.0, HOUR, Some(50));
+        assert_eq!(energy.totals().today(), 140);
+        // A clock stepping back does not roll
+        assert!(!energy.sample(100.0, HOUR, Some(49)).day);
+        assert_eq!(energy.totals().day, Some(50));
+    }
+
+    #[test]
+    fn resumes_the_same_day_after_syncing() {
+        let mut energy = Accumulator::new(HOUR);
+        energy.restore(Totals {
+            day: Some(50),
+            days: [40, 0, 0, 0, 0, 0, 0],
+            lifetime: 40,
+            wraps: 0,
+        });
+        energy.sample(60.0, 0, None);
+        energy.sample(60.0, HOUR, None);
+        assert!(energy.sample(60.0, HOUR, Some(50)).day);
+        assert_eq!(energy.totals().days[..2], [100, 0]);
+    }
+
+    #[test]
+    fn wraps_the_lifetime() {
+        let mut energy = Accumulator::new(HOUR);
+        energy.restore(Totals {
+            day: Some(1),
+            days: [0; DAYS],
+            lifetime: u32::MAX - 10,
+            wraps: 0,
+        });
+        energy.sample(100.0, 0, Some(1));
+        energy.sample(100.0, HOUR, Some(1));
+        assert_eq!(energy.totals().lifetime, 89);
+        assert_eq!(energy.totals().wraps, 1);
+        assert_eq!(energy.totals().lifetime_wh(), u32::MAX as u64 + 90);
+    }
+}
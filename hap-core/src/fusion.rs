@@ -0,0 +1,354 @@
+//! One temperature from several probes: each reading corrected by its
+//! probe's offset, probes that stopped reading left out, and the rest
+//! combined as the mean, the minimum or a weighted mean.
+//!
+//! Stored form: a format version byte, the aggregation, then per probe its
+//! ROM code, offset in hundredths of a degree (i16, little endian), weight
+//! and whether it contributes.
+
+use std::fmt;
+
+use crate::onewire::Rom;
+
+pub const MAX_PROBES: usize = 8;
+pub const FORMAT_VERSION: u8 = 1;
+
+const PROBE_LEN: usize = 8 + 2 + 1 + 1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Aggregation {
+    Mean = 0,
+    Min = 1,
+    /// By the probes' weights, those of weight 0 left out
+    Weighted = 2,
+}
+
+impl Aggregation {
+    const ALL: [Aggregation; 3] = [Aggregation::Mean, Aggregation::Min, Aggregation::Weighted];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Aggregation::Mean => "mean",
+            Aggregation::Min => "min",
+            Aggregation::Weighted => "weighted",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|aggregation| aggregation.name() == name)
+    }
+
+    fn from_u8(value: u8) -> Option<Self> {
+        Self::ALL.get(value as usize).copied()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Probe {
+    pub rom: Rom,
+    /// Added to every reading, in °C
+    pub offset: f32,
+    pub weight: u8,
+    /// Whether it counts towards the aggregate, or is only reported
+    pub contributes: bool,
+}
+
+impl Probe {
+    pub fn new(rom: Rom) -> Self {
+        Self {
+            rom,
+            offset: 0.0,
+            weight: 1,
+            contributes: true,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FusionError {
+    Full,
+    NoSuchProbe,
+    Malformed,
+}
+
+impl fmt::Display for FusionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FusionError::Full => write!(f, "at most {} probes", MAX_PROBES),
+            FusionError::NoSuchProbe => f.write_str("no such probe"),
+            FusionError::Malformed => f.write_str("malformed probe list"),
+        }
+    }
+}
+
+impl std::error::Error for FusionError {}
+
+/// The aggregate at one point in time.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Output {
+    /// The last aggregate while no probe contributes, none before the first
+    pub celsius: Option<f32>,
+    pub contributors: usize,
+}
+
+impl Output {
+    /// No probe contributes, the value is held.
+    pub fn is_fault(&self) -> bool {
+        self.contributors == 0
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Fusion {
+    pub aggregation: Aggregation,
+    stale_secs: u32,
+    probes: Vec<Probe>,
+    /// The corrected reading of each probe and when it was taken
+    readings: Vec<Option<(f32, u32)>>,
+    held: Option<f32>,
+}
+
+impl Fusion {
+    pub const fn new(stale_secs: u32) -> Self {
+        Self {
+            aggregation: Aggregation::Mean,
+            stale_secs,
+            probes: Vec::new(),
+            readings: Vec::new(),
+            held: None,
+        }
+    }
+
+    pub fn probes(&self) -> &[Probe] {
+        &self.probes
+    }
+
+    /// False if the probe was registered already.
+    pub fn add(&mut self, probe: Probe) -> Result<bool, FusionError> {
+        if self.index(&probe.rom).is_some() {
+            return Ok(false);
+        }
+        if self.probes.len() == MAX_PROBES {
+            return Err(FusionError::Full);
+        }
+        self.probes.push(probe);
+        self.readings.push(None);
+
+        Ok(true)
+    }
+
+    /// False if the probe was not registered.
+    pub fn remove(&mut self, rom: &Rom) -> bool {
+        let Some(index) = self.index(rom) else {
+            return false;
+        };
+        self.probes.remove(index);
+        self.readings.remove(index);
+
+        true
+    }
+
+    /// Changes a probe's settings; readings taken before a new offset are
+    /// only corrected by it from the next one on.
+    pub fn update(&mut self, rom: &Rom, f: impl FnOnce(&mut Probe)) -> Result<(), FusionError> {
+        let index = self.index(rom).ok_or(FusionError::NoSuchProbe)?;
+        f(&mut self.probes[index]);
+
+        Ok(())
+    }
+
+    fn index(&self, rom: &Rom) -> Option<usize> {
+        self.probes.iter().position(|probe| probe.rom == *rom)
+    }
+
+    /// Records a probe's raw reading at `now`, seconds on any monotonic
+    /// clock; false for a probe that is not registered.
+    pub fn record(&mut self, rom: &Rom, raw: f32, now: u32) -> bool {
+        let Some(index) = self.index(rom) else {
+            return false;
+        };
+        self.readings[index] = Some((raw + self.probes[index].offset, now));
+
+        true
+    }
+
+    /// A probe's corrected reading, none once it is stale.
+    pub fn reading(&self, rom: &Rom, now: u32) -> Option<f32> {
+        let (celsius, at) = self.readings[self.index(rom)?]?;
+        (now.saturating_sub(at) < self.stale_secs).then_some(celsius)
+    }
+
+    fn contributes(&self, probe: &Probe) -> bool {
+        probe.contributes && (self.aggregation != Aggregation::Weighted || probe.weight > 0)
+    }
+
+    /// Combines the fresh readings of the contributing probes; with none
+    /// the last aggregate is held.
+    pub fn aggregate(&mut self, now: u32) -> Output {
+        let fresh: Vec<(f32, f32)> = self
+            .probes
+            .iter()
+            .filter(|probe| self.contributes(probe))
+            .filter_map(|probe| Some((self.reading(&probe.rom, now)?, probe.weight as f32)))
+            .collect();
+        if fresh.is_empty() {
+            return Output {
+                celsius: self.held,
+                contributors: 0,
+            };
+        }
+
+        let celsius = match self.aggregation {
+            Aggregation::Mean => fresh.iter().map(|(c, _)| c).sum::<f32>() / fresh.len() as f32,
+            Aggregation::Min => fresh.iter().map(|(c, _)| *c).fold(f32::INFINITY, f32::min),
+            Aggregation::Weighted => {
+                let total: f32 = fresh.iter().map(|(_, weight)| weight).sum();
+                fresh.iter().map(|(c, weight)| c * weight).sum::<f32>() / total
+            }
+        };
+        self.held = Some(celsius);
+
+        Output {
+            celsius: Some(celsius),
+            contributors: fresh.len(),
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = vec![FORMAT_VERSION, self.aggregation as u8];
+        for probe in &self.probes {
+            out.extend_from_slice(&probe.rom.0);
+            let offset = (probe.offset * 100.0).round() as i16;
+            out.extend_from_slice(&offset.to_le_bytes());
+            out.push(probe.weight);
+            out.push(probe.contributes as u8);
+        }
+
+        out
+    }
+
+    /// The probes and aggregation stored, without readings.
+    pub fn decode(bytes: &[u8], stale_secs: u32) -> Result<Self, FusionError> {
+        let [FORMAT_VERSION, aggregation, ref probes @ ..] = *bytes else {
+            return Err(FusionError::Malformed);
+        };
+        let probes = probes.chunks_exact(PROBE_LEN);
+        if !probes.remainder().is_empty() {
+            return Err(FusionError::Malformed);
+        }
+
+        let mut fusion = Self::new(stale_secs);
+        fusion.aggregation = Aggregation::from_u8(aggregation).ok_or(FusionError::Malformed)?;
+        for probe in probes {
+            let rom = Rom(probe[..8].try_into().unwrap());
+            if !rom.is_valid() {
+                return Err(FusionError::Malformed);
+            }
+            fusion.add(Probe {
+                rom,
+                offset: i16::from_le_bytes([probe[8], probe[9]]) as f32 / 100.0,
+                weight: probe[10],
+                contributes: probe[11] != 0,
+            })?;
+        }
+
+        Ok(fusion)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::onewire::crc8;
+
+    fn rom(serial: u8) -> Rom {
+        let mut rom = [0x28, serial, 0, 0, 0, 0, 0, 0];
+        rom[7] = crc8(&rom[..7]);
+        Rom(rom)
+    }
+
+    fn fusion() -> Fusion {
+        let mut fusion = Fusion::new(300);
+        fusion.add(Probe::new(rom(1))).unwrap();
+        fusion
+            .add(Probe {
+                offset: -0.5,
+                weight: 3,
+                ..Probe::new(rom(2))
+            })
+            .unwrap();
+        fusion
+    }
+
+    #[test]
+    fn aggregates_corrected_readings() {
+        let mut fusion = fusion();
+        fusion.record(&rom(1), 20.0, 0);
+        fusion.record(&rom(2), 24.5, 0);
+        assert_eq!(fusion.reading(&rom(2), 0), Some(24.0));
+
+        assert_eq!(fusion.aggregate(0).celsius, Some(22.0));
+        fusion.aggregation = Aggregation::Min;
+        assert_eq!(fusion.aggregate(0).celsius, Some(20.0));
+        fusion.aggregation = Aggregation::Weighted;
+        assert_eq!(fusion.aggregate(0).celsius, Some(23.0));
+
+        fusion.update(&rom(1), |probe| probe.weight = 0).unwrap();
+        assert_eq!(fusion.aggregate(0).contributors, 1);
+        fusion
+            .update(&rom(2), |probe| probe.contributes = false)
+            .unwrap();
+        assert!(fusion.aggregate(0).is_fault());
+        assert_eq!(
+            fusion.update(&rom(3), |_| {}),
+            Err(FusionError::NoSuchProbe)
+        );
+    }
+
+    #[test]
+    fn leaves_out_stale_probes_and_holds_the_last_value() {
+        let mut fusion = fusion();
+        assert_eq!(
+            fusion.aggregate(0),
+            Output {
+                celsius: None,
+                contributors: 0
+            }
+        );
+
+        fusion.record(&rom(1), 20.0, 0);
+        fusion.record(&rom(2), 24.5, 200);
+        assert_eq!(fusion.aggregate(200).celsius, Some(22.0));
+        // The first probe went quiet
+        assert_eq!(fusion.reading(&rom(1), 300), None);
+        assert_eq!(fusion.aggregate(300).celsius, Some(24.0));
+
+        let held = fusion.aggregate(500);
+        assert!(held.is_fault());
+        assert_eq!(held.celsius, Some(24.0));
+
+        fusion.record(&rom(1), 21.0, 510);
+        assert_eq!(fusion.aggregate(510).contributors, 1);
+    }
+
+    #[test]
+    fn round_trips_the_probes() {
+        let mut fusion = fusion();
+        fusion.aggregation = Aggregation::Weighted;
+        let decoded = Fusion::decode(&fusion.encode(), 300).unwrap();
+        assert_eq!(decoded.probes(), fusion.probes());
+        assert_eq!(decoded.aggregation, Aggregation::Weighted);
+
+        let bytes = fusion.encode();
+        assert!(Fusion::decode(&bytes[..bytes.len() - 1], 300).is_err());
+        assert!(fusion.remove(&rom(1)));
+        assert!(!fusion.remove(&rom(1)));
+        assert_eq!(fusion.add(Probe::new(rom(2))), Ok(false));
+        for serial in 3..10 {
+            fusion.add(Probe::new(rom(serial))).unwrap();
+        }
+        assert_eq!(fusion.add(Probe::new(rom(10))), Err(FusionError::Full));
+    }
+}
@@ -0,0 +1,167 @@
+//! Button gestures as a Stateless Programmable Switch reports them, and the
+//! local actions a table maps them to.
+
+use std::time::Duration;
+
+use crate::classifier::Event;
+
+/// The values of ProgrammableSwitchEvent.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Gesture {
+    Single = 0,
+    Double = 1,
+    Long = 2,
+}
+
+impl Gesture {
+    pub const ALL: [Gesture; 3] = [Gesture::Single, Gesture::Double, Gesture::Long];
+    pub const VALUES: &'static [u8] = &[0, 1, 2];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Gesture::Single => "single",
+            Gesture::Double => "double",
+            Gesture::Long => "long",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|gesture| gesture.name() == name)
+    }
+
+    /// The gesture of a classified button event. Holds of `max_long` and
+    /// more are not gestures but the button's reset functions.
+    pub fn of(event: Event, max_long: Duration) -> Option<Self> {
+        match event {
+            Event::Click => Some(Gesture::Single),
+            Event::DoubleClick => Some(Gesture::Double),
+            Event::LongPress(held) if held < max_long => Some(Gesture::Long),
+            _ => None,
+        }
+    }
+}
+
+/// What a gesture does to the local target before it is reported.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    None = 0,
+    Toggle = 1,
+    On = 2,
+    Off = 3,
+}
+
+impl Action {
+    const ALL: [Action; 4] = [Action::None, Action::Toggle, Action::On, Action::Off];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Action::None => "none",
+            Action::Toggle => "toggle",
+            Action::On => "on",
+            Action::Off => "off",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|action| action.name() == name)
+    }
+
+    fn from_u8(value: u8) -> Option<Self> {
+        Self::ALL.get(value as usize).copied()
+    }
+
+    /// The state the target switches to from `on`, `None` to leave it.
+    pub fn apply(self, on: bool) -> Option<bool> {
+        match self {
+            Action::None => None,
+            Action::Toggle => Some(!on),
+            Action::On => Some(true),
+            Action::Off => Some(false),
+        }
+    }
+}
+
+/// The action of every gesture; stored as one byte per gesture.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Mapping([Action; 3]);
+
+impl Default for Mapping {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Mapping {
+    /// A click toggles, as the button always did.
+    pub const fn new() -> Self {
+        Self([Action::Toggle, Action::None, Action::None])
+    }
+
+    pub fn action(&self, gesture: Gesture) -> Action {
+        self.0[gesture as usize]
+    }
+
+    pub fn set(&mut self, gesture: Gesture, action: Action) {
+        self.0[gesture as usize] = action;
+    }
+
+    pub fn encode(&self) -> [u8; 3] {
+        self.0.map(|action| action as u8)
+    }
+
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        let [single, double, long] = *bytes else {
+            return None;
+        };
+
+        Some(Self([
+            Action::from_u8(single)?,
+            Action::from_u8(double)?,
+            Action::from_u8(long)?,
+        ]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RESET_HOLD: Duration = Duration::from_secs(5);
+
+    #[test]
+    fn classifies_gestures() {
+        assert_eq!(Gesture::of(Event::Click, RESET_HOLD), Some(Gesture::Single));
+        assert_eq!(
+            Gesture::of(Event::DoubleClick, RESET_HOLD),
+            Some(Gesture::Double)
+        );
+        assert_eq!(
+            Gesture::of(Event::LongPress(Duration::from_millis(1500)), RESET_HOLD),
+            Some(Gesture::Long)
+        );
+        assert_eq!(Gesture::of(Event::LongPress(RESET_HOLD), RESET_HOLD), None);
+        assert_eq!(Gesture::of(Event::TripleClick, RESET_HOLD), None);
+        assert_eq!(Gesture::of(Event::Pressed, RESET_HOLD), None);
+    }
+
+    #[test]
+    fn applies_actions() {
+        assert_eq!(Action::None.apply(true), None);
+        assert_eq!(Action::Toggle.apply(true), Some(false));
+        assert_eq!(Action::On.apply(true), Some(true));
+        assert_eq!(Action::Off.apply(false), Some(false));
+    }
+
+    #[test]
+    fn round_trips_the_mapping() {
+        let mut mapping = Mapping::default();
+        assert_eq!(mapping.action(Gesture::Single), Action::Toggle);
+
+        mapping.set(Gesture::Long, Action::Off);
+        assert_eq!(Mapping::decode(&mapping.encode()), Some(mapping));
+        assert_eq!(Mapping::decode(&[1, 0]), None);
+        assert_eq!(Mapping::decode(&[1, 0, 7]), None);
+        assert_eq!(Action::from_name("off"), Some(Action::Off));
+        assert_eq!(Gesture::from_name("double"), Some(Gesture::Double));
+    }
+}
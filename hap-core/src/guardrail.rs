@@ -0,0 +1,387 @@
+//! Guardrails on an outlet: at most so long on within a rolling window,
+//! quiet hours during which it stays off, and a cooldown once either made
+//! it switch off.
+//!
+//! Times are uptime seconds, which keep counting while the clock is not
+//! synced. Quiet hours need the local time of day; without it the clock
+//! policy says whether switching on is allowed.
+//!
+//! Stored form: format version, max on, window and cooldown seconds (u32,
+//! little endian), quiet start and end as minutes of the day (u16, little
+//! endian, equal for none) and the clock policy.
+
+use std::collections::VecDeque;
+use std::fmt;
+
+pub const FORMAT_VERSION: u8 = 1;
+/// On periods kept within the window; the oldest are merged beyond that.
+pub const MAX_PERIODS: usize = 16;
+
+const DAY_MINUTES: u16 = 24 * 60;
+const ENCODED_LEN: usize = 1 + 3 * 4 + 2 * 2 + 1;
+
+/// Whether quiet hours allow switching on while the time of day is unknown.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ClockPolicy {
+    FailOpen = 0,
+    FailClosed = 1,
+}
+
+impl ClockPolicy {
+    pub fn name(self) -> &'static str {
+        match self {
+            ClockPolicy::FailOpen => "open",
+            ClockPolicy::FailClosed => "closed",
+        }
+    }
+
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(ClockPolicy::FailOpen),
+            1 => Some(ClockPolicy::FailClosed),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Limits {
+    /// 0 for no duty limit
+    pub max_on_secs: u32,
+    pub window_secs: u32,
+    /// From the first minute of the day up to the second, across midnight
+    /// if the second is earlier
+    pub quiet: Option<(u16, u16)>,
+    pub cooldown_secs: u32,
+    pub clock: ClockPolicy,
+}
+
+impl Limits {
+    pub const NONE: Limits = Limits {
+        max_on_secs: 0,
+        window_secs: 60 * 60,
+        quiet: None,
+        cooldown_secs: 0,
+        clock: ClockPolicy::FailClosed,
+    };
+
+    pub fn is_valid(&self) -> bool {
+        let duty = self.max_on_secs == 0 || self.max_on_secs < self.window_secs;
+        let quiet = self
+            .quiet
+            .is_none_or(|(start, end)| start != end && start.max(end) < DAY_MINUTES);
+        duty && quiet
+    }
+
+    fn is_quiet(&self, minute: u16) -> bool {
+        match self.quiet {
+            None => false,
+            Some((start, end)) if start < end => (start..end).contains(&minute),
+            Some((start, end)) => minute >= start || minute < end,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let (start, end) = self.quiet.unwrap_or((0, 0));
+        let mut out = vec![FORMAT_VERSION];
+        out.extend_from_slice(&self.max_on_secs.to_le_bytes());
+        out.extend_from_slice(&self.window_secs.to_le_bytes());
+        out.extend_from_slice(&self.cooldown_secs.to_le_bytes());
+        out.extend_from_slice(&start.to_le_bytes());
+        out.extend_from_slice(&end.to_le_bytes());
+        out.push(self.clock as u8);
+
+        out
+    }
+
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != ENCODED_LEN || bytes[0] != FORMAT_VERSION {
+            return None;
+        }
+        let u32_at = |at: usize| u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap());
+        let u16_at = |at: usize| u16::from_le_bytes(bytes[at..at + 2].try_into().unwrap());
+        let (start, end) = (u16_at(13), u16_at(15));
+
+        let limits = Limits {
+            max_on_secs: u32_at(1),
+            window_secs: u32_at(5),
+            cooldown_secs: u32_at(9),
+            quiet: (start != end).then_some((start, end)),
+            clock: ClockPolicy::from_u8(bytes[17])?,
+        };
+        limits.is_valid().then_some(limits)
+    }
+}
+
+/// Why the outlet may not be on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Refusal {
+    QuietHours,
+    /// The time of day is unknown and quiet hours fail closed
+    ClockUnknown,
+    DutyCycle,
+    Cooldown,
+}
+
+impl fmt::Display for Refusal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Refusal::QuietHours => "quiet hours",
+            Refusal::ClockUnknown => "time of day unknown during possible quiet hours",
+            Refusal::DutyCycle => "on time for the window used up",
+            Refusal::Cooldown => "cooling down after a limit tripped",
+        })
+    }
+}
+
+/// The values of the state characteristic.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum State {
+    Ok = 0,
+    Limited = 1,
+    Cooldown = 2,
+}
+
+impl State {
+    pub const VALUES: &'static [u8] = &[0, 1, 2];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            State::Ok => "ok",
+            State::Limited => "limited",
+            State::Cooldown => "cooldown",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Guardrail {
+    limits: Limits,
+    /// Finished on periods as start and end, oldest first
+    periods: VecDeque<(u32, u32)>,
+    on_since: Option<u32>,
+    cooldown_until: Option<u32>,
+}
+
+impl Guardrail {
+    pub const fn new(limits: Limits) -> Self {
+        Self {
+            limits,
+            periods: VecDeque::new(),
+            on_since: None,
+            cooldown_until: None,
+        }
+    }
+
+    pub fn limits(&self) -> &Limits {
+        &self.limits
+    }
+
+    /// Takes effect at once; the on time so far counts against new limits.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    /// Tells the guardrail the outlet is now on or off.
+    pub fn switched(&mut self, on: bool, now: u32) {
+        match (on, self.on_since) {
+            (true, None) => self.on_since = Some(now),
+            (false, Some(since)) => {
+                self.on_since = None;
+                self.periods.push_back((since, now));
+                if self.periods.len() > MAX_PERIODS {
+                    // Counting the gap between them as on errs on the safe side
+                    let (start, _) = self.periods.pop_front().unwrap();
+                    self.periods[0].0 = start;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Seconds on within the window ending at `now`.
+    pub fn on_secs(&self, now: u32) -> u32 {
+        let from = now.saturating_sub(self.limits.window_secs);
+        self.periods
+            .iter()
+            .copied()
+            .chain(self.on_since.map(|since| (since, now)))
+            .map(|(start, end)| end.min(now).saturating_sub(start.max(from)))
+            .sum()
+    }
+
+    fn cooling(&self, now: u32) -> Option<u32> {
+        self.cooldown_until
+            .filter(|&until| until > now)
+            .map(|until| until - now)
+    }
+
+    /// Whether the outlet may be on at `now`, `minute` being the minute of
+    /// the local day if the clock is synced.
+    pub fn check(&self, now: u32, minute: Option<u16>) -> Result<(), Refusal> {
+        if self.cooling(now).is_some() {
+            return Err(Refusal::Cooldown);
+        }
+        match minute {
+            None if self.limits.quiet.is_some() && self.limits.clock == ClockPolicy::FailClosed => {
+                return Err(Refusal::ClockUnknown)
+            }
+            Some(minute) if self.limits.is_quiet(minute) => return Err(Refusal::QuietHours),
+            _ => {}
+        }
+        if self.limits.max_on_secs > 0 && self.on_secs(now) >= self.limits.max_on_secs {
+            return Err(Refusal::DutyCycle);
+        }
+
+        Ok(())
+    }
+
+    /// Checks an outlet that is on; when a limit trips it has to switch
+    /// off, and the cooldown starts.
+    pub fn tick(&mut self, now: u32, minute: Option<u16>) -> Option<Refusal> {
+        self.on_since?;
+        let refusal = self.check(now, minute).err()?;
+        if self.limits.cooldown_secs > 0 {
+            self.cooldown_until = Some(now + self.limits.cooldown_secs);
+        }
+
+        Some(refusal)
+    }
+
+    /// Ends a cooldown early; true if there was one.
+    pub fn clear_cooldown(&mut self, now: u32) -> bool {
+        self.cooldown_until.take().is_some_and(|until| until > now)
+    }
+
+    /// The state with its seconds remaining: of the cooldown, until the
+    /// outlet may switch on again, or the on time left in the window when
+    /// it may (0 without a duty limit).
+    pub fn state(&self, now: u32, minute: Option<u16>) -> (State, u32) {
+        if let Some(secs) = self.cooling(now) {
+            return (State::Cooldown, secs);
+        }
+
+        match self.check(now, minute) {
+            Ok(()) if self.limits.max_on_secs > 0 => {
+                (State::Ok, self.limits.max_on_secs - self.on_secs(now))
+            }
+            Ok(()) => (State::Ok, 0),
+            Err(Refusal::QuietHours) => {
+                let (_, end) = self.limits.quiet.unwrap();
+                let minute = minute.unwrap();
+                let minutes = (end + DAY_MINUTES - minute) % DAY_MINUTES;
+                (State::Limited, minutes as u32 * 60)
+            }
+            Err(Refusal::DutyCycle) => (State::Limited, self.duty_frees(now)),
+            Err(_) => (State::Limited, 0),
+        }
+    }
+
+    /// Seconds until enough on time leaves the window to switch on again,
+    /// staying off meanwhile.
+    fn duty_frees(&self, now: u32) -> u32 {
+        let mut off = self.clone();
+        off.switched(false, now);
+        let (mut low, mut high) = (0, self.limits.window_secs);
+        while low < high {
+            let mid = low + (high - low) / 2;
+            if off.on_secs(now + mid) < self.limits.max_on_secs {
+                high = mid;
+            } else {
+                low = mid + 1;
+            }
+        }
+
+        low
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 30 minutes per hour, quiet from 23:00 to 06:00, 10 minutes to cool down
+    const PUMP: Limits = Limits {
+        max_on_secs: 30 * 60,
+        window_secs: 60 * 60,
+        quiet: Some((23 * 60, 6 * 60)),
+        cooldown_secs: 10 * 60,
+        clock: ClockPolicy::FailClosed,
+    };
+    const NOON: Option<u16> = Some(12 * 60);
+
+    #[test]
+    fn limits_the_on_time_per_window() {
+        let mut guard = Guardrail::new(PUMP);
+        guard.switched(true, 0);
+        assert_eq!(guard.tick(1000, NOON), None);
+        assert_eq!(guard.state(1000, NOON), (State::Ok, 800));
+        guard.switched(false, 1000);
+        guard.switched(true, 1200);
+        assert_eq!(guard.tick(2000, NOON), Some(Refusal::DutyCycle));
+        guard.switched(false, 2000);
+
+        assert_eq!(guard.check(2000, NOON), Err(Refusal::Cooldown));
+        assert_eq!(guard.state(2000, NOON), (State::Cooldown, 600));
+        // Still over the limit once cooled down, until the first period
+        // starts leaving the window
+        assert_eq!(guard.state(2600, NOON), (State::Limited, 1001));
+        assert_eq!(guard.check(3601, NOON), Ok(()));
+        assert_eq!(guard.check(3600, NOON), Err(Refusal::DutyCycle));
+    }
+
+    #[test]
+    fn keeps_quiet_hours_across_midnight() {
+        let mut guard = Guardrail::new(PUMP);
+        assert_eq!(guard.check(0, Some(23 * 60 + 30)), Err(Refusal::QuietHours));
+        assert_eq!(guard.check(0, Some(5 * 60)), Err(Refusal::QuietHours));
+        assert_eq!(guard.state(0, Some(5 * 60)), (State::Limited, 3600));
+        assert_eq!(guard.check(0, Some(6 * 60)), Ok(()));
+
+        guard.switched(true, 0);
+        assert_eq!(guard.tick(60, Some(23 * 60)), Some(Refusal::QuietHours));
+        assert!(guard.clear_cooldown(60));
+        assert!(!guard.clear_cooldown(60));
+
+        // Without the time of day
+        assert_eq!(guard.check(0, None), Err(Refusal::ClockUnknown));
+        guard.set_limits(Limits {
+            clock: ClockPolicy::FailOpen,
+            ..PUMP
+        });
+        assert_eq!(guard.check(0, None), Ok(()));
+        guard.set_limits(Limits::NONE);
+        assert_eq!(guard.check(0, None), Ok(()));
+    }
+
+    #[test]
+    fn round_trips_the_limits() {
+        assert_eq!(Limits::decode(&PUMP.encode()), Some(PUMP));
+        assert_eq!(Limits::decode(&Limits::NONE.encode()), Some(Limits::NONE));
+        assert_eq!(Limits::decode(&PUMP.encode()[1..]), None);
+
+        let invalid = Limits {
+            max_on_secs: 60 * 60,
+            ..PUMP
+        };
+        assert!(!invalid.is_valid());
+        assert_eq!(Limits::decode(&invalid.encode()), None);
+        assert!(!Limits {
+            quiet: Some((0, DAY_MINUTES)),
+            ..PUMP
+        }
+        .is_valid());
+    }
+
+    #[test]
+    fn merges_periods_beyond_the_cap() {
+        let mut guard = Guardrail::new(Limits::NONE);
+        for i in 0..MAX_PERIODS as u32 + 1 {
+            guard.switched(true, i * 10);
+            guard.switched(false, i * 10 + 1);
+        }
+        assert_eq!(guard.periods.len(), MAX_PERIODS);
+        // The gap between the first two counts as on
+        assert_eq!(guard.on_secs(200), MAX_PERIODS as u32 + 1 + 9);
+    }
+}
@@ -0,0 +1,199 @@
+//! Instance IDs kept across firmware updates, so reordering or adding
+//! services does not make controllers forget the existing ones.
+
+/// The iids handed out so far, by a key naming each service and
+/// characteristic by its UUID and its position among equal UUIDs.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct IidMap {
+    entries: Vec<(String, i32)>,
+    changed: bool,
+}
+
+/// What `assign` did to the database.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Assigned {
+    /// Everything had an iid already
+    Unchanged,
+    /// Nothing was stored for the accessory, the SDK's iids were kept
+    Migrated,
+    /// New items got fresh iids, the configuration number has to change
+    Added(usize),
+}
+
+impl IidMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reads the `key=iid` lines of `encode`; malformed lines are skipped.
+    pub fn decode(bytes: &[u8]) -> Self {
+        let entries = String::from_utf8_lossy(bytes)
+            .lines()
+            .filter_map(|line| {
+                let (key, iid) = line.rsplit_once('=')?;
+                Some((key.to_string(), iid.parse().ok()?))
+            })
+            .collect();
+
+        Self {
+            entries,
+            changed: false,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = String::new();
+        for (key, iid) in &self.entries {
+            out.push_str(key);
+            out.push('=');
+            out.push_str(&iid.to_string());
+            out.push('\n');
+        }
+
+        out.into_bytes()
+    }
+
+    /// Whether `assign` added entries that still have to be stored.
+    pub fn is_changed(&self) -> bool {
+        self.changed
+    }
+
+    pub fn get(&self, key: &str) -> Option<i32> {
+        self.entries
+            .iter()
+            .find(|(known, _)| known == key)
+            .map(|(_, iid)| *iid)
+    }
+
+    /// Decides the iid of every item of one accessory, its keys all starting
+    /// with `prefix`, from the iids the SDK just assigned. Entries of removed
+    /// items stay, so their iids are never handed out again.
+    pub fn assign(&mut self, prefix: &str, items: &[(String, i32)]) -> (Vec<i32>, Assigned) {
+        let known = |key: &str| key.starts_with(prefix);
+        if !self.entries.iter().any(|(key, _)| known(key)) {
+            // Devices updating from before the map: the SDK assigned the same
+            // iids to the same database, so they are what controllers know
+            self.entries.extend(items.iter().cloned());
+            self.changed = !items.is_empty();
+            return (
+                items.iter().map(|(_, iid)| *iid).collect(),
+                Assigned::Migrated,
+            );
+        }
+
+        // Above everything stored and everything the SDK handed out, which
+        // includes the accessory information service
+        let mut next = self
+            .entries
+            .iter()
+            .filter(|(key, _)| known(key))
+            .map(|(_, iid)| *iid)
+            .chain(items.iter().map(|(_, iid)| *iid))
+            .max()
+            .unwrap_or(0)
+            + 1;
+
+        let mut added = 0;
+        let iids = items
+            .iter()
+            .map(|(key, _)| match self.get(key) {
+                Some(iid) => iid,
+                None => {
+                    let iid = next;
+                    next += 1;
+                    added += 1;
+                    self.entries.push((key.clone(), iid));
+                    iid
+                }
+            })
+            .collect();
+
+        if added == 0 {
+            return (iids, Assigned::Unchanged);
+        }
+        self.changed = true;
+        (iids, Assigned::Added(added))
+    }
+}
+
+/// Numbers repeated names, `uuid#0`, `uuid#1`, in the order they come.
+pub(crate) fn indexed(names: &[String]) -> Vec<String> {
+    names
+        .iter()
+        .enumerate()
+        .map(|(i, name)| {
+            let index = names[..i].iter().filter(|known| *known == name).count();
+            format!("{}#{}", name, index)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn items(keys: &[&str], first: i32) -> Vec<(String, i32)> {
+        keys.iter()
+            .zip(first..)
+            .map(|(key, iid)| (key.to_string(), iid))
+            .collect()
+    }
+
+    #[test]
+    fn a_first_boot_keeps_the_sdk_iids() {
+        let mut map = IidMap::new();
+        let (iids, assigned) = map.assign("acc/", &items(&["acc/a", "acc/b"], 10));
+
+        assert_eq!(iids, vec![10, 11]);
+        assert_eq!(assigned, Assigned::Migrated);
+        assert!(map.is_changed());
+    }
+
+    #[test]
+    fn reordered_items_keep_their_iids() {
+        let mut map = IidMap::new();
+        map.assign("acc/", &items(&["acc/a", "acc/b", "acc/c"], 10));
+
+        let mut map = IidMap::decode(&map.encode());
+        let (iids, assigned) = map.assign("acc/", &items(&["acc/c", "acc/a"], 10));
+        assert_eq!(iids, vec![12, 10]);
+        assert_eq!(assigned, Assigned::Unchanged);
+        assert!(!map.is_changed());
+    }
+
+    #[test]
+    fn new_items_never_reuse_an_iid() {
+        let mut map = IidMap::new();
+        map.assign("acc/", &items(&["acc/a", "acc/b"], 10));
+
+        // b was removed, d is new; the SDK gave it b's old iid
+        let (iids, assigned) = map.assign("acc/", &items(&["acc/a", "acc/d"], 10));
+        assert_eq!(iids, vec![10, 12]);
+        assert_eq!(assigned, Assigned::Added(1));
+        assert_eq!(map.get("acc/b"), Some(11));
+    }
+
+    #[test]
+    fn accessories_are_kept_apart() {
+        let mut map = IidMap::new();
+        map.assign("one/", &items(&["one/a"], 10));
+        let (iids, assigned) = map.assign("two/", &items(&["two/a"], 10));
+
+        assert_eq!(iids, vec![10]);
+        assert_eq!(assigned, Assigned::Migrated);
+    }
+
+    #[test]
+    fn malformed_lines_are_skipped() {
+        let map = IidMap::decode(b"acc/a=10\ngarbage\nacc/b=x\nacc/c=12\n");
+        assert_eq!(map.get("acc/a"), Some(10));
+        assert_eq!(map.get("acc/b"), None);
+        assert_eq!(map.get("acc/c"), Some(12));
+    }
+
+    #[test]
+    fn repeated_names_are_numbered() {
+        let names = ["49", "47", "49"].map(String::from);
+        assert_eq!(indexed(&names), vec!["49#0", "47#0", "49#1"]);
+    }
+}
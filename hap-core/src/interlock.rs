@@ -0,0 +1,334 @@
+//! Rules between the channels of a relay backend: groups of which at most
+//! one channel is closed, and channels following another one, straight or
+//! inverted, for pilot lights.
+//!
+//! Written as text: interlock groups `0+1,2+3`, each a `+`-separated list of
+//! channels; follows `3=1,4=!0`, the follower before the channel it follows,
+//! `!` inverting it. States are bit masks of the channels.
+
+use std::fmt;
+
+/// At most this many channels, the width of the state masks.
+pub const MAX_CHANNELS: u8 = 32;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Follow {
+    pub channel: u8,
+    pub leader: u8,
+    pub inverted: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RuleError {
+    Syntax(String),
+    NoSuchChannel(u8),
+    /// A group of a single channel interlocks nothing
+    SmallGroup(u8),
+    InTwoGroups(u8),
+    FollowsItself(u8),
+    FollowedTwice(u8),
+    /// The leader follows another channel in turn
+    Chained(u8),
+    /// Following would close it against its group
+    FollowerInterlocked(u8),
+    /// Writes to an exposed channel would be overridden
+    FollowerExposed(u8),
+    /// Neither HomeKit nor a rule switches the leader
+    LeaderHidden(u8),
+}
+
+impl fmt::Display for RuleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RuleError::Syntax(text) => write!(f, "cannot parse '{}'", text),
+            RuleError::NoSuchChannel(channel) => write!(f, "no relay channel {}", channel),
+            RuleError::SmallGroup(channel) => {
+                write!(f, "the group of channel {} has no other channel", channel)
+            }
+            RuleError::InTwoGroups(channel) => {
+                write!(f, "channel {} is in two interlock groups", channel)
+            }
+            RuleError::FollowsItself(channel) => write!(f, "channel {} follows itself", channel),
+            RuleError::FollowedTwice(channel) => {
+                write!(f, "channel {} follows two channels", channel)
+            }
+            RuleError::Chained(channel) => {
+                write!(f, "channel {} is followed but follows another", channel)
+            }
+            RuleError::FollowerInterlocked(channel) => {
+                write!(f, "channel {} follows another but is interlocked", channel)
+            }
+            RuleError::FollowerExposed(channel) => {
+                write!(
+                    f,
+                    "channel {} follows another but is switched from HomeKit",
+                    channel
+                )
+            }
+            RuleError::LeaderHidden(channel) => {
+                write!(
+                    f,
+                    "channel {} is followed but not switched from HomeKit",
+                    channel
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for RuleError {}
+
+fn channel(text: &str, channels: u8) -> Result<u8, RuleError> {
+    let channel = text
+        .trim()
+        .parse()
+        .map_err(|_| RuleError::Syntax(text.into()))?;
+    if channel >= channels {
+        return Err(RuleError::NoSuchChannel(channel));
+    }
+
+    Ok(channel)
+}
+
+/// A comma-separated list of channels, empty for none.
+pub fn parse_channels(text: &str, channels: u8) -> Result<Vec<u8>, RuleError> {
+    list(text).map(|item| channel(item, channels)).collect()
+}
+
+fn list(text: &str) -> impl Iterator<Item = &str> {
+    text.split(',').filter(|item| !item.trim().is_empty())
+}
+
+/// The steps to a new state, opening before closing.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Plan {
+    pub opens: Vec<u8>,
+    pub closes: Vec<u8>,
+    /// A channel opens for another of its group, the closes wait for the
+    /// break-before-make delay
+    pub break_first: bool,
+    pub target: u32,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Rules {
+    groups: Vec<u32>,
+    follows: Vec<Follow>,
+}
+
+impl Rules {
+    /// Parses and checks the rules against the backend's `channels` and the
+    /// `exposed` ones, switched from HomeKit.
+    pub fn parse(
+        interlock: &str,
+        follow: &str,
+        channels: u8,
+        exposed: u32,
+    ) -> Result<Self, RuleError> {
+        let channels = channels.min(MAX_CHANNELS);
+        let mut rules = Rules::default();
+
+        for group in list(interlock) {
+            let mut mask = 0u32;
+            for item in group.split('+') {
+                let channel = channel(item, channels)?;
+                if rules.group(channel).is_some() || mask & 1 << channel != 0 {
+                    return Err(RuleError::InTwoGroups(channel));
+                }
+                mask |= 1 << channel;
+            }
+            if mask.count_ones() < 2 {
+                return Err(RuleError::SmallGroup(mask.trailing_zeros() as u8));
+            }
+            rules.groups.push(mask);
+        }
+
+        for item in list(follow) {
+            let (follower, leader) = item
+                .split_once('=')
+                .ok_or_else(|| RuleError::Syntax(item.into()))?;
+            let (leader, inverted) = match leader.trim().strip_prefix('!') {
+                Some(leader) => (leader, true),
+                None => (leader, false),
+            };
+            let follow = Follow {
+                channel: channel(follower, channels)?,
+                leader: channel(leader, channels)?,
+                inverted,
+            };
+            rules.follows.push(follow);
+        }
+
+        rules.check(exposed)?;
+
+        Ok(rules)
+    }
+
+    fn check(&self, exposed: u32) -> Result<(), RuleError> {
+        for (i, follow) in self.follows.iter().enumerate() {
+            let channel = follow.channel;
+            if follow.leader == channel {
+                return Err(RuleError::FollowsItself(channel));
+            }
+            if self.follows[..i]
+                .iter()
+                .any(|other| other.channel == channel)
+            {
+                return Err(RuleError::FollowedTwice(channel));
+            }
+            if self.follower(follow.leader).is_some() {
+                return Err(RuleError::Chained(follow.leader));
+            }
+            if self.group(channel).is_some() {
+                return Err(RuleError::FollowerInterlocked(channel));
+            }
+            if exposed & 1 << channel != 0 {
+                return Err(RuleError::FollowerExposed(channel));
+            }
+            if exposed & 1 << follow.leader == 0 {
+                return Err(RuleError::LeaderHidden(follow.leader));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.groups.is_empty() && self.follows.is_empty()
+    }
+
+    pub fn groups(&self) -> &[u32] {
+        &self.groups
+    }
+
+    pub fn follows(&self) -> &[Follow] {
+        &self.follows
+    }
+
+    fn group(&self, channel: u8) -> Option<u32> {
+        self.groups
+            .iter()
+            .copied()
+            .find(|group| group & 1 << channel != 0)
+    }
+
+    fn follower(&self, channel: u8) -> Option<&Follow> {
+        self.follows.iter().find(|follow| follow.channel == channel)
+    }
+
+    /// `state` with every follower set from its leader.
+    pub fn settle(&self, mut state: u32) -> u32 {
+        for follow in &self.follows {
+            let on = (state & 1 << follow.leader != 0) != follow.inverted;
+            if on {
+                state |= 1 << follow.channel;
+            } else {
+                state &= !(1 << follow.channel);
+            }
+        }
+
+        state
+    }
+
+    /// How to get from `state` to `channel` switched `on`: the others of its
+    /// group open first, and followers go along. A write to a follower only
+    /// settles it back to its leader.
+    pub fn plan(&self, state: u32, channel: u8, on: bool) -> Plan {
+        let mut target = if on {
+            state | 1 << channel
+        } else {
+            state & !(1 << channel)
+        };
+        let mut break_first = false;
+        if let (true, Some(group)) = (on, self.group(channel)) {
+            let others = group & !(1 << channel);
+            break_first = state & others != 0;
+            target &= !others;
+        }
+        let target = self.settle(target);
+
+        let channels = |mask: u32| (0..MAX_CHANNELS).filter(move |c| mask & 1 << c != 0);
+        Plan {
+            opens: channels(state & !target).collect(),
+            closes: channels(target & !state).collect(),
+            break_first,
+            target,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EXPOSED: u32 = 0b0111;
+
+    #[test]
+    fn validates_the_rules() {
+        let rules = Rules::parse("0+1", "3=!0", 4, EXPOSED).unwrap();
+        assert_eq!(rules.groups(), &[0b0011]);
+        assert_eq!(
+            rules.follows(),
+            &[Follow {
+                channel: 3,
+                leader: 0,
+                inverted: true
+            }]
+        );
+        assert!(Rules::parse("", "", 1, 1).unwrap().is_empty());
+        assert_eq!(parse_channels("1, 2", 4), Ok(vec![1, 2]));
+
+        let error = |interlock, follow| Rules::parse(interlock, follow, 4, EXPOSED).unwrap_err();
+        assert_eq!(error("0+4", ""), RuleError::NoSuchChannel(4));
+        assert_eq!(error("0+x", ""), RuleError::Syntax("x".into()));
+        assert_eq!(error("2", ""), RuleError::SmallGroup(2));
+        assert_eq!(error("0+1,1+2", ""), RuleError::InTwoGroups(1));
+        assert_eq!(error("", "3=3"), RuleError::FollowsItself(3));
+        assert_eq!(error("", "3=0,3=1"), RuleError::FollowedTwice(3));
+        assert_eq!(error("0+3", "3=1"), RuleError::FollowerInterlocked(3));
+        assert_eq!(error("", "2=0"), RuleError::FollowerExposed(2));
+        assert_eq!(
+            Rules::parse("", "3=1", 5, 0b10101).unwrap_err(),
+            RuleError::LeaderHidden(1)
+        );
+        assert_eq!(
+            Rules::parse("", "3=0,4=3", 5, EXPOSED).unwrap_err(),
+            RuleError::Chained(3)
+        );
+    }
+
+    #[test]
+    fn breaks_before_making() {
+        let rules = Rules::parse("0+1", "", 4, EXPOSED).unwrap();
+        let plan = rules.plan(0b0001, 1, true);
+        assert_eq!(plan.opens, vec![0]);
+        assert_eq!(plan.closes, vec![1]);
+        assert!(plan.break_first);
+        assert_eq!(plan.target, 0b0010);
+
+        // Nothing to break, or not interlocked
+        assert!(!rules.plan(0b0000, 1, true).break_first);
+        assert_eq!(rules.plan(0b0001, 2, true).target, 0b0101);
+        assert_eq!(rules.plan(0b0011, 0, false).target, 0b0010);
+    }
+
+    #[test]
+    fn followers_go_along() {
+        let rules = Rules::parse("0+1", "2=0,3=!0", 4, 0b0011).unwrap();
+        assert_eq!(rules.settle(0), 0b1000);
+
+        let plan = rules.plan(0b1010, 0, true);
+        assert_eq!(plan.opens, vec![1, 3]);
+        assert_eq!(plan.closes, vec![0, 2]);
+        assert_eq!(plan.target, 0b0101);
+
+        // Writes to a follower are settled back
+        assert_eq!(
+            rules.plan(0b0101, 3, true),
+            Plan {
+                target: 0b0101,
+                ..Plan::default()
+            }
+        );
+    }
+}
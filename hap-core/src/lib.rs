@@ -0,0 +1,197 @@
+//! The accessory logic between the firmware and the esp-homekit-sdk, written
+//! against the `HapSys` trait so it builds and tests on the host.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+pub mod accessory;
+pub mod actuation;
+pub mod audit;
+pub mod builder;
+pub mod chime;
+pub mod classifier;
+pub mod credentials;
+pub mod deferred;
+pub mod distance;
+pub mod energy;
+pub mod fusion;
+pub mod gesture;
+pub mod guardrail;
+pub mod iid;
+pub mod interlock;
+pub mod mdns;
+#[cfg(any(test, feature = "mock"))]
+pub mod mock;
+pub mod onewire;
+pub mod pairing_window;
+pub mod pn532;
+pub mod position;
+pub mod read;
+pub mod rfid;
+pub mod schedule;
+pub mod schema;
+pub mod selftest;
+pub mod sessions;
+pub mod setup;
+pub mod soil;
+pub mod sys;
+pub mod trace;
+pub mod value;
+pub mod write;
+
+pub use accessory::{Accessory, Runner};
+pub use builder::{
+    AccessoryBuilder, BuildError, CharSlot, Registered, ServiceBuilder, ServiceHandle,
+};
+pub use iid::IidMap;
+pub use mdns::MdnsInfo;
+pub use read::ReadHandler;
+pub use schedule::Schedule;
+pub use sys::{Acc, Char, ControllerId, Event, HapSys, Serv};
+pub use value::{Format, Value};
+pub use write::{Bounds, Status, Write, WriteHandler};
+
+/// The private data of a service with handlers, shared by both callbacks.
+pub(crate) struct Handlers {
+    pub write: Option<&'static dyn WriteHandler>,
+    pub read: Option<&'static dyn ReadHandler>,
+}
+
+/// The safe layer over a `HapSys`; the firmware has one for the real SDK.
+pub struct Hap<S> {
+    sys: S,
+    started: AtomicBool,
+}
+
+impl<S: HapSys> Hap<S> {
+    pub const fn new(sys: S) -> Self {
+        Self {
+            sys,
+            started: AtomicBool::new(false),
+        }
+    }
+
+    pub fn sys(&self) -> &S {
+        &self.sys
+    }
+
+    /// Starts HAP. The accessory database is fixed from then on.
+    pub fn start(&self) -> i32 {
+        let err = self.sys.start();
+        if err == sys::HAP_SUCCESS {
+            self.started.store(true, Ordering::Relaxed);
+        }
+
+        err
+    }
+
+    pub fn is_started(&self) -> bool {
+        self.started.load(Ordering::Relaxed)
+    }
+
+    /// Creates a characteristic of the format of its initial value.
+    pub fn create_char(&self, uuid: &'static [u8], perms: u16, value: &Value) -> Option<Char> {
+        let hc = self.sys.char_create(uuid, perms, value)?;
+        write::register(hc, value.format(), perms);
+
+        Some(hc)
+    }
+
+    /// Looks up a characteristic the SDK created along with the service,
+    /// noting the format and permissions the SDK gave it.
+    pub fn char_by_uuid(
+        &self,
+        serv: Serv,
+        uuid: &'static [u8],
+        format: Format,
+        perms: u16,
+    ) -> Option<Char> {
+        let hc = self.sys.serv_char_by_uuid(serv, uuid)?;
+        write::register(hc, format, perms);
+
+        Some(hc)
+    }
+
+    /// Limits the values of a numeric characteristic, for controllers and
+    /// the write path alike.
+    pub fn set_bounds(&self, hc: Char, bounds: Bounds) {
+        match write::format_of(hc) {
+            Some(Format::Float) => self.sys.char_float_set_constraints(
+                hc,
+                bounds.min as f32,
+                bounds.max as f32,
+                bounds.step as f32,
+            ),
+            Some(_) => self.sys.char_int_set_constraints(
+                hc,
+                bounds.min as i32,
+                bounds.max as i32,
+                bounds.step as i32,
+            ),
+            None => return,
+        }
+        write::set_bounds(hc, bounds);
+    }
+
+    /// Limits an integer characteristic to the listed values.
+    pub fn set_valid_values(&self, hc: Char, values: &'static [u8]) {
+        self.sys.char_add_valid_vals(hc, values);
+        write::set_valid_values(hc, values);
+    }
+
+    /// Adds a new characteristic to a service, returning it for updates.
+    pub fn add_char(
+        &self,
+        serv: Serv,
+        uuid: &'static [u8],
+        perms: u16,
+        value: &Value,
+    ) -> Option<Char> {
+        let hc = self.create_char(uuid, perms, value)?;
+        self.sys.serv_add_char(serv, hc);
+
+        Some(hc)
+    }
+
+    /// The value controllers read, of a characteristic with a known format.
+    pub fn value(&self, hc: Char) -> Option<Value> {
+        self.sys.char_get_val(hc, write::format_of(hc)?)
+    }
+
+    pub fn update(&self, hc: Char, value: &Value) -> i32 {
+        if trace::enabled() {
+            let kind = trace::Kind::Notify {
+                hc,
+                value: value.clone(),
+            };
+            trace::record(None, kind);
+        }
+        let raw = value.to_raw();
+        self.sys.char_update_val(hc, raw.get())
+    }
+
+    /// Routes the writes to the service through `handler`, entry by entry.
+    pub fn set_write_handler(&self, serv: Serv, handler: &'static dyn WriteHandler) {
+        self.set_handlers(serv, Some(handler), None);
+    }
+
+    /// Installs the callbacks of a service; both share its private data.
+    pub fn set_handlers(
+        &self,
+        serv: Serv,
+        write: Option<&'static dyn WriteHandler>,
+        read: Option<&'static dyn ReadHandler>,
+    ) {
+        if write.is_none() && read.is_none() {
+            return;
+        }
+
+        let handlers = Box::into_raw(Box::new(Handlers { write, read }));
+        self.sys.serv_set_priv(serv, handlers as *mut _);
+        if write.is_some() {
+            self.sys.serv_set_write_cb(serv, write::on_write);
+        }
+        if read.is_some() {
+            self.sys.serv_set_read_cb(serv, read::on_read);
+        }
+    }
+}
@@ -0,0 +1,187 @@
+//! The `_hap._tcp` record controllers discover the accessory by, as read
+//! back from mDNS, and the checks of it against the accessory's own state.
+
+use std::fmt;
+
+/// The TXT keys a controller needs to find and pair the accessory.
+pub const REQUIRED_KEYS: [&str; 5] = ["c#", "s#", "ci", "sf", "id"];
+
+/// Status flag bit set while the accessory is not paired.
+pub const SF_UNPAIRED: u8 = 0x01;
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MdnsInfo {
+    pub instance: String,
+    pub hostname: String,
+    pub port: u16,
+    pub txt: Vec<(String, String)>,
+}
+
+/// Where the advertised record disagrees with the accessory; a
+/// discoverable accessory is unpaired and takes pairings.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Mismatch {
+    Missing(&'static str),
+    Port { advertised: u16, expected: u16 },
+    StatusFlags { sf: u8, discoverable: bool },
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Mismatch::Missing(key) => write!(f, "TXT record has no {}", key),
+            Mismatch::Port {
+                advertised,
+                expected,
+            } => write!(
+                f,
+                "port {} advertised, HAP listens on {}",
+                advertised, expected
+            ),
+            Mismatch::StatusFlags {
+                sf,
+                discoverable: false,
+            } => {
+                write!(f, "sf={} offers pair setup the accessory does not take", sf)
+            }
+            Mismatch::StatusFlags {
+                sf,
+                discoverable: true,
+            } => {
+                write!(f, "sf={} hides the unpaired accessory from pair setup", sf)
+            }
+        }
+    }
+}
+
+impl MdnsInfo {
+    pub fn txt(&self, key: &str) -> Option<&str> {
+        self.txt
+            .iter()
+            .find(|(known, _)| known == key)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// The configuration number, `c#`.
+    pub fn config_number(&self) -> Option<u32> {
+        self.txt("c#")?.parse().ok()
+    }
+
+    /// The status flags, `sf`.
+    pub fn status_flags(&self) -> Option<u8> {
+        self.txt("sf")?.parse().ok()
+    }
+
+    /// Compares the record with the port HAP listens on and whether the
+    /// accessory takes pairings: it is unpaired, and its pairing window, if
+    /// any, is open.
+    pub fn check(&self, port: u16, discoverable: bool) -> Vec<Mismatch> {
+        let mut mismatches: Vec<_> = REQUIRED_KEYS
+            .iter()
+            .filter(|key| self.txt(key).is_none())
+            .map(|key| Mismatch::Missing(key))
+            .collect();
+
+        if self.port != port {
+            mismatches.push(Mismatch::Port {
+                advertised: self.port,
+                expected: port,
+            });
+        }
+        if let Some(sf) = self.status_flags() {
+            if (sf & SF_UNPAIRED != 0) != discoverable {
+                mismatches.push(Mismatch::StatusFlags { sf, discoverable });
+            }
+        }
+
+        mismatches
+    }
+}
+
+impl fmt::Display for MdnsInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "'{}' on {}:{}", self.instance, self.hostname, self.port)?;
+        for (key, value) in &self.txt {
+            write!(f, " {}={}", key, value)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(sf: &str) -> MdnsInfo {
+        let txt = [
+            ("c#", "3"),
+            ("s#", "1"),
+            ("ci", "7"),
+            ("sf", sf),
+            ("id", "AA:BB"),
+        ];
+        MdnsInfo {
+            instance: "Smart-Outlet".into(),
+            hostname: "outlet.local".into(),
+            port: 8080,
+            txt: txt.map(|(k, v)| (k.into(), v.into())).to_vec(),
+        }
+    }
+
+    #[test]
+    fn reads_the_txt_record() {
+        let info = record("1");
+        assert_eq!(info.config_number(), Some(3));
+        assert_eq!(info.status_flags(), Some(1));
+        assert_eq!(info.txt("ci"), Some("7"));
+        assert_eq!(info.txt("pv"), None);
+    }
+
+    #[test]
+    fn a_matching_record_passes() {
+        assert_eq!(record("1").check(8080, true), vec![]);
+        assert_eq!(record("0").check(8080, false), vec![]);
+    }
+
+    #[test]
+    fn the_status_flag_has_to_follow_the_pairing() {
+        assert_eq!(
+            record("1").check(8080, false),
+            vec![Mismatch::StatusFlags {
+                sf: 1,
+                discoverable: false
+            }]
+        );
+        assert_eq!(
+            record("0").check(8080, true),
+            vec![Mismatch::StatusFlags {
+                sf: 0,
+                discoverable: true
+            }]
+        );
+    }
+
+    #[test]
+    fn reports_missing_keys_and_a_wrong_port() {
+        let mut info = record("1");
+        info.txt.retain(|(key, _)| key != "id");
+        info.port = 80;
+
+        let mismatches = info.check(8080, true);
+        assert_eq!(
+            mismatches,
+            vec![
+                Mismatch::Missing("id"),
+                Mismatch::Port {
+                    advertised: 80,
+                    expected: 8080
+                }
+            ]
+        );
+        assert_eq!(
+            mismatches[1].to_string(),
+            "port 80 advertised, HAP listens on 8080"
+        );
+    }
+}
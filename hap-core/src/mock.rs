@@ -0,0 +1,588 @@
+//! A `HapSys` that records the calls and keeps the database in memory, with
+//! controller writes and events driven from the test.
+
+use std::ffi::{c_void, CStr};
+use std::ptr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use crate::sys::{
+    uuid, Acc, AccessoryInfo, Char, Event, HapSys, RawWrite, ReadCb, Serv, WriteCb, HAP_FAIL,
+    HAP_SUCCESS,
+};
+use crate::value::{Format, RawBuf, RawVal, Value};
+
+// Unique across mocks, the format registry is process-wide
+static NEXT_HANDLE: AtomicUsize = AtomicUsize::new(0x1000);
+
+// The accessory information service comes first, its iids are the SDK's
+const INFO_IIDS: i32 = 8;
+
+fn next_handle() -> usize {
+    NEXT_HANDLE.fetch_add(0x10, Ordering::Relaxed)
+}
+
+fn uuid_str(uuid: &[u8]) -> String {
+    String::from_utf8_lossy(uuid.strip_suffix(b"\0").unwrap_or(uuid)).into_owned()
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Call {
+    Init,
+    Start,
+    SetSetup {
+        code: String,
+        id: String,
+    },
+    SetSetupInfo {
+        id: String,
+    },
+    ResetToFactory,
+    ResetNetwork,
+    ResetPairings,
+    OnEvent,
+    CloseSession(i32),
+    AccCreate {
+        name: String,
+        cid: u32,
+    },
+    AccAddServ(Acc, Serv),
+    AddAccessory(Acc),
+    AddBridgedAccessory(Acc, i32),
+    ServCreate(String),
+    ServOutletCreate,
+    ServSwitchCreate,
+    ServAddChar(Serv, Char),
+    ServSetPriv(Serv),
+    ServSetWriteCb(Serv),
+    ServSetReadCb(Serv),
+    ServMarkPrimary(Serv),
+    ServMarkHidden(Serv),
+    CharCreate {
+        uuid: String,
+        perms: u16,
+        value: Value,
+    },
+    UpdateVal(Char, Value),
+    IntConstraints(Char, i32, i32, i32),
+    FloatConstraints(Char, f32, f32, f32),
+    ValidVals(Char, Vec<u8>),
+}
+
+struct MockChar {
+    hc: Char,
+    uuid: String,
+    format: Format,
+    value: Value,
+    serv: Option<Serv>,
+    iid: i32,
+}
+
+struct MockServ {
+    serv: Serv,
+    iid: i32,
+    priv_data: usize,
+    write_cb: Option<WriteCb>,
+    read_cb: Option<ReadCb>,
+}
+
+#[derive(Default)]
+struct State {
+    calls: Vec<Call>,
+    chars: Vec<MockChar>,
+    services: Vec<MockServ>,
+    paired: i32,
+    handlers: Vec<fn(Event)>,
+    aids: Vec<String>,
+    // Next iid per accessory, after those of its information service
+    next_iids: Vec<(Acc, i32)>,
+    config_updates: u32,
+}
+
+#[derive(Default)]
+pub struct MockSys {
+    state: Mutex<State>,
+}
+
+impl MockSys {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn calls(&self) -> Vec<Call> {
+        self.state.lock().unwrap().calls.clone()
+    }
+
+    /// The current value of a characteristic, as a controller would read it.
+    pub fn value(&self, hc: Char) -> Option<Value> {
+        let state = self.state.lock().unwrap();
+        state
+            .chars
+            .iter()
+            .find(|c| c.hc == hc)
+            .map(|c| c.value.clone())
+    }
+
+    pub fn service_of(&self, hc: Char) -> Option<Serv> {
+        let state = self.state.lock().unwrap();
+        state.chars.iter().find(|c| c.hc == hc).and_then(|c| c.serv)
+    }
+
+    /// How often the configuration number was bumped.
+    pub fn config_updates(&self) -> u32 {
+        self.state.lock().unwrap().config_updates
+    }
+
+    pub fn set_paired(&self, count: i32) {
+        self.state.lock().unwrap().paired = count;
+    }
+
+    /// Sends a controller's write batch to the service, through the write
+    /// callback like the SDK does. Returns the result and the entry statuses.
+    pub fn write(&self, serv: Serv, entries: &[(Char, Value)]) -> (i32, Vec<i32>) {
+        let (cb, priv_data) = {
+            let state = self.state.lock().unwrap();
+            let Some(service) = state.services.iter().find(|s| s.serv == serv) else {
+                return (HAP_FAIL, Vec::new());
+            };
+            let Some(cb) = service.write_cb else {
+                return (HAP_FAIL, Vec::new());
+            };
+            (cb, service.priv_data)
+        };
+
+        let raws: Vec<_> = entries.iter().map(|(_, value)| value.to_raw()).collect();
+        let mut statuses = vec![i32::MIN; entries.len()];
+        let mut batch: Vec<RawWrite> = entries
+            .iter()
+            .zip(&raws)
+            .zip(statuses.iter_mut())
+            .map(|(((hc, _), raw), status)| RawWrite {
+                hc: hc.as_ptr(),
+                val: *raw.get(),
+                auth_data: RawBuf {
+                    buf: ptr::null_mut(),
+                    buflen: 0,
+                },
+                remote: true,
+                status,
+            })
+            .collect();
+
+        // Unlocked, the handler is free to update values
+        let result = unsafe {
+            cb(
+                batch.as_mut_ptr(),
+                batch.len() as i32,
+                priv_data as *mut c_void,
+                ptr::null_mut(),
+            )
+        };
+
+        (result, statuses)
+    }
+
+    /// Reads a characteristic through the read callback of its service, like
+    /// a controller would. Returns the result and the status.
+    pub fn read(&self, serv: Serv, hc: Char) -> (i32, i32) {
+        let (cb, priv_data) = {
+            let state = self.state.lock().unwrap();
+            let Some(service) = state.services.iter().find(|s| s.serv == serv) else {
+                return (HAP_FAIL, 0);
+            };
+            let Some(cb) = service.read_cb else {
+                return (HAP_FAIL, 0);
+            };
+            (cb, service.priv_data)
+        };
+
+        let mut status = i32::MIN;
+        let result = unsafe {
+            cb(
+                hc.as_ptr(),
+                &mut status,
+                priv_data as *mut c_void,
+                ptr::null_mut(),
+            )
+        };
+
+        (result, status)
+    }
+
+    /// Delivers an event to the registered handlers, like the event loop does.
+    pub fn deliver(&self, event: Event) {
+        let handlers = self.state.lock().unwrap().handlers.clone();
+        for handler in handlers {
+            handler(event);
+        }
+    }
+
+    fn record(&self, call: Call) {
+        self.state.lock().unwrap().calls.push(call);
+    }
+
+    fn new_char(&self, uuid: &[u8], value: Value, serv: Option<Serv>) -> Char {
+        let hc = Char(next_handle());
+        self.state.lock().unwrap().chars.push(MockChar {
+            hc,
+            uuid: uuid_str(uuid),
+            format: value.format(),
+            value,
+            serv,
+            iid: 0,
+        });
+
+        hc
+    }
+
+    fn new_serv(&self) -> Serv {
+        let serv = Serv(next_handle());
+        self.state.lock().unwrap().services.push(MockServ {
+            serv,
+            iid: 0,
+            priv_data: 0,
+            write_cb: None,
+            read_cb: None,
+        });
+
+        serv
+    }
+}
+
+impl HapSys for MockSys {
+    fn init(&self) -> i32 {
+        self.record(Call::Init);
+        HAP_SUCCESS
+    }
+
+    fn start(&self) -> i32 {
+        self.record(Call::Start);
+        HAP_SUCCESS
+    }
+
+    fn set_setup(&self, code: &CStr, id: &CStr) {
+        self.record(Call::SetSetup {
+            code: code.to_string_lossy().into_owned(),
+            id: id.to_string_lossy().into_owned(),
+        });
+    }
+
+    fn set_setup_info(&self, _salt: &[u8; 16], _verifier: &[u8; 384], id: &CStr) {
+        self.record(Call::SetSetupInfo {
+            id: id.to_string_lossy().into_owned(),
+        });
+    }
+
+    fn paired_controller_count(&self) -> i32 {
+        self.state.lock().unwrap().paired
+    }
+
+    fn reset_to_factory(&self) -> i32 {
+        self.record(Call::ResetToFactory);
+        HAP_SUCCESS
+    }
+
+    fn reset_network(&self) -> i32 {
+        self.record(Call::ResetNetwork);
+        HAP_SUCCESS
+    }
+
+    fn reset_pairings(&self) -> i32 {
+        self.record(Call::ResetPairings);
+        HAP_SUCCESS
+    }
+
+    fn on_event(&self, handler: fn(Event)) -> i32 {
+        self.record(Call::OnEvent);
+        self.state.lock().unwrap().handlers.push(handler);
+        HAP_SUCCESS
+    }
+
+    fn close_session(&self, socket: i32) -> i32 {
+        self.record(Call::CloseSession(socket));
+        HAP_SUCCESS
+    }
+
+    fn acc_create(&self, info: &AccessoryInfo) -> Option<Acc> {
+        self.record(Call::AccCreate {
+            name: info.name.to_string_lossy().into_owned(),
+            cid: info.cid,
+        });
+        let acc = Acc(next_handle());
+        self.state
+            .lock()
+            .unwrap()
+            .next_iids
+            .push((acc, INFO_IIDS + 1));
+
+        Some(acc)
+    }
+
+    fn acc_add_serv(&self, acc: Acc, serv: Serv) -> i32 {
+        self.record(Call::AccAddServ(acc, serv));
+
+        // Numbered in the order added, like the SDK does
+        let mut guard = self.state.lock().unwrap();
+        let state = &mut *guard;
+        let Some((_, next)) = state.next_iids.iter_mut().find(|(a, _)| *a == acc) else {
+            return HAP_FAIL;
+        };
+        if let Some(service) = state.services.iter_mut().find(|s| s.serv == serv) {
+            service.iid = *next;
+            *next += 1;
+        }
+        for c in state.chars.iter_mut().filter(|c| c.serv == Some(serv)) {
+            c.iid = *next;
+            *next += 1;
+        }
+
+        HAP_SUCCESS
+    }
+
+    fn add_accessory(&self, acc: Acc) {
+        self.record(Call::AddAccessory(acc));
+    }
+
+    fn add_bridged_accessory(&self, acc: Acc, aid: i32) -> i32 {
+        self.record(Call::AddBridgedAccessory(acc, aid));
+        HAP_SUCCESS
+    }
+
+    fn unique_aid(&self, id: &CStr) -> i32 {
+        let id = id.to_string_lossy().into_owned();
+        let mut state = self.state.lock().unwrap();
+        let index = match state.aids.iter().position(|known| *known == id) {
+            Some(index) => index,
+            None => {
+                state.aids.push(id);
+                state.aids.len() - 1
+            }
+        };
+
+        // aid 1 is the bridge itself
+        index as i32 + 2
+    }
+
+    fn serv_create(&self, uuid: &'static [u8]) -> Option<Serv> {
+        self.record(Call::ServCreate(uuid_str(uuid)));
+        Some(self.new_serv())
+    }
+
+    fn serv_outlet_create(&self, on: bool, in_use: bool) -> Option<Serv> {
+        self.record(Call::ServOutletCreate);
+        let serv = self.new_serv();
+        self.new_char(uuid::ON, Value::Bool(on), Some(serv));
+        self.new_char(uuid::OUTLET_IN_USE, Value::Bool(in_use), Some(serv));
+
+        Some(serv)
+    }
+
+    fn serv_switch_create(&self, on: bool) -> Option<Serv> {
+        self.record(Call::ServSwitchCreate);
+        let serv = self.new_serv();
+        self.new_char(uuid::ON, Value::Bool(on), Some(serv));
+
+        Some(serv)
+    }
+
+    fn serv_add_char(&self, serv: Serv, hc: Char) -> i32 {
+        self.record(Call::ServAddChar(serv, hc));
+        let mut state = self.state.lock().unwrap();
+        match state.chars.iter_mut().find(|c| c.hc == hc) {
+            Some(c) if c.serv.is_none() => {
+                c.serv = Some(serv);
+                HAP_SUCCESS
+            }
+            _ => HAP_FAIL,
+        }
+    }
+
+    fn serv_char_by_uuid(&self, serv: Serv, uuid: &'static [u8]) -> Option<Char> {
+        let uuid = uuid_str(uuid);
+        let state = self.state.lock().unwrap();
+        state
+            .chars
+            .iter()
+            .find(|c| c.serv == Some(serv) && c.uuid == uuid)
+            .map(|c| c.hc)
+    }
+
+    fn serv_set_priv(&self, serv: Serv, priv_data: *mut c_void) {
+        self.record(Call::ServSetPriv(serv));
+        let mut state = self.state.lock().unwrap();
+        if let Some(service) = state.services.iter_mut().find(|s| s.serv == serv) {
+            service.priv_data = priv_data as usize;
+        }
+    }
+
+    fn serv_set_write_cb(&self, serv: Serv, cb: WriteCb) {
+        self.record(Call::ServSetWriteCb(serv));
+        let mut state = self.state.lock().unwrap();
+        if let Some(service) = state.services.iter_mut().find(|s| s.serv == serv) {
+            service.write_cb = Some(cb);
+        }
+    }
+
+    fn serv_set_read_cb(&self, serv: Serv, cb: ReadCb) {
+        self.record(Call::ServSetReadCb(serv));
+        let mut state = self.state.lock().unwrap();
+        if let Some(service) = state.services.iter_mut().find(|s| s.serv == serv) {
+            service.read_cb = Some(cb);
+        }
+    }
+
+    fn serv_mark_primary(&self, serv: Serv) {
+        self.record(Call::ServMarkPrimary(serv));
+    }
+
+    fn serv_mark_hidden(&self, serv: Serv) {
+        self.record(Call::ServMarkHidden(serv));
+    }
+
+    fn char_create(&self, uuid: &'static [u8], perms: u16, value: &Value) -> Option<Char> {
+        self.record(Call::CharCreate {
+            uuid: uuid_str(uuid),
+            perms,
+            value: value.clone(),
+        });
+        Some(self.new_char(uuid, value.clone(), None))
+    }
+
+    fn char_name_create(&self, name: &CStr) -> Option<Char> {
+        let value = Value::String(name.to_string_lossy().into_owned());
+        Some(self.new_char(uuid::NAME, value, None))
+    }
+
+    fn char_status_fault_create(&self, fault: u8) -> Option<Char> {
+        Some(self.new_char(uuid::STATUS_FAULT, Value::Uint8(fault), None))
+    }
+
+    fn char_type_uuid(&self, hc: Char) -> Option<String> {
+        let state = self.state.lock().unwrap();
+        state
+            .chars
+            .iter()
+            .find(|c| c.hc == hc)
+            .map(|c| c.uuid.clone())
+    }
+
+    fn char_get_val(&self, hc: Char, _: Format) -> Option<Value> {
+        let state = self.state.lock().unwrap();
+        state
+            .chars
+            .iter()
+            .find(|c| c.hc == hc)
+            .map(|c| c.value.clone())
+    }
+
+    fn char_update_val(&self, hc: Char, val: &RawVal) -> i32 {
+        let mut state = self.state.lock().unwrap();
+        let Some(c) = state.chars.iter_mut().find(|c| c.hc == hc) else {
+            return HAP_FAIL;
+        };
+
+        // Decoded like the SDK would, by the format the characteristic has
+        c.value = unsafe { Value::from_raw(c.format, val) };
+        let value = c.value.clone();
+        state.calls.push(Call::UpdateVal(hc, value));
+
+        HAP_SUCCESS
+    }
+
+    fn update_config_number(&self) {
+        self.state.lock().unwrap().config_updates += 1;
+    }
+
+    fn serv_get_iid(&self, serv: Serv) -> i32 {
+        let state = self.state.lock().unwrap();
+        state
+            .services
+            .iter()
+            .find(|s| s.serv == serv)
+            .map_or(0, |s| s.iid)
+    }
+
+    fn serv_set_iid(&self, serv: Serv, iid: i32) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(service) = state.services.iter_mut().find(|s| s.serv == serv) {
+            service.iid = iid;
+        }
+    }
+
+    fn char_get_iid(&self, hc: Char) -> i32 {
+        let state = self.state.lock().unwrap();
+        state.chars.iter().find(|c| c.hc == hc).map_or(0, |c| c.iid)
+    }
+
+    fn char_set_iid(&self, hc: Char, iid: i32) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(c) = state.chars.iter_mut().find(|c| c.hc == hc) {
+            c.iid = iid;
+        }
+    }
+
+    fn char_int_set_constraints(&self, hc: Char, min: i32, max: i32, step: i32) {
+        self.record(Call::IntConstraints(hc, min, max, step));
+    }
+
+    fn char_float_set_constraints(&self, hc: Char, min: f32, max: f32, step: f32) {
+        self.record(Call::FloatConstraints(hc, min, max, step));
+    }
+
+    fn char_add_valid_vals(&self, hc: Char, values: &'static [u8]) {
+        self.record(Call::ValidVals(hc, values.to_vec()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use super::*;
+    use crate::Hap;
+
+    #[test]
+    fn updates_are_decoded_by_the_characteristic_format() {
+        let hap = Hap::new(MockSys::new());
+        let serv = hap.sys().serv_create(b"A0\0").unwrap();
+        let power = hap.add_char(serv, b"A1\0", 0, &Value::Float(0.0)).unwrap();
+
+        assert_eq!(hap.update(power, &Value::Float(230.5)), HAP_SUCCESS);
+        assert_eq!(hap.sys().value(power), Some(Value::Float(230.5)));
+        assert_eq!(
+            hap.sys().calls().last(),
+            Some(&Call::UpdateVal(power, Value::Float(230.5)))
+        );
+    }
+
+    #[test]
+    fn characteristics_join_one_service() {
+        let hap = Hap::new(MockSys::new());
+        let first = hap.sys().serv_create(b"A0\0").unwrap();
+        let second = hap.sys().serv_create(b"A0\0").unwrap();
+        let hc = hap
+            .add_char(first, b"A1\0", 0, &Value::Bool(false))
+            .unwrap();
+
+        assert_eq!(hap.sys().serv_add_char(second, hc), HAP_FAIL);
+        assert_eq!(hap.sys().service_of(hc), Some(first));
+    }
+
+    static EVENTS: AtomicU32 = AtomicU32::new(0);
+
+    fn count_paired(event: Event) {
+        if event == Event::ControllerPaired {
+            EVENTS.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn events_reach_the_handlers() {
+        let sys = MockSys::new();
+        sys.on_event(count_paired);
+
+        sys.deliver(Event::PairingStarted);
+        sys.deliver(Event::ControllerPaired);
+        assert_eq!(EVENTS.load(Ordering::Relaxed), 1);
+    }
+}
@@ -0,0 +1,312 @@
+//! The 1-Wire bus as the DS18B20 probes use it: ROM codes, the search that
+//! finds every device on the bus, and the probes' scratchpad.
+//!
+//! A ROM code is the family byte, a 48-bit serial number and a CRC-8 over
+//! both, sent least significant bit first.
+
+use std::fmt;
+use std::str::FromStr;
+
+pub const SEARCH_ROM: u8 = 0xF0;
+pub const MATCH_ROM: u8 = 0x55;
+pub const SKIP_ROM: u8 = 0xCC;
+
+pub const FAMILY_DS18B20: u8 = 0x28;
+pub const CONVERT_T: u8 = 0x44;
+pub const READ_SCRATCHPAD: u8 = 0xBE;
+/// A 12-bit conversion takes up to 750 ms.
+pub const CONVERSION_MS: u64 = 750;
+
+/// At most this many devices are searched for.
+pub const MAX_DEVICES: usize = 16;
+
+// The temperature register's value after power-on, before any conversion
+const POWER_ON_RAW: i16 = 0x0550;
+
+/// The Dallas/Maxim CRC-8 (x^8 + x^5 + x^4 + 1, reflected).
+pub fn crc8(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0, |mut crc, &byte| {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0x8C
+            } else {
+                crc >> 1
+            };
+        }
+        crc
+    })
+}
+
+/// A device's ROM code.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Rom(pub [u8; 8]);
+
+impl Rom {
+    pub fn family(&self) -> u8 {
+        self.0[0]
+    }
+
+    pub fn is_valid(&self) -> bool {
+        crc8(&self.0[..7]) == self.0[7]
+    }
+
+    fn bit(&self, index: usize) -> bool {
+        self.0[index / 8] & (1 << (index % 8)) != 0
+    }
+
+    fn set_bit(&mut self, index: usize, value: bool) {
+        if value {
+            self.0[index / 8] |= 1 << (index % 8);
+        } else {
+            self.0[index / 8] &= !(1 << (index % 8));
+        }
+    }
+}
+
+/// Family byte first, as the probes' labels and most tools print them.
+impl fmt::Display for Rom {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for byte in self.0 {
+            write!(f, "{:02X}", byte)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Rom {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ParseRomError;
+
+impl fmt::Display for ParseRomError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("expected 16 hex digits with a valid CRC")
+    }
+}
+
+impl std::error::Error for ParseRomError {}
+
+impl FromStr for Rom {
+    type Err = ParseRomError;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        if text.len() != 16 || !text.is_ascii() {
+            return Err(ParseRomError);
+        }
+        let mut rom = Rom([0; 8]);
+        for (byte, pair) in rom.0.iter_mut().zip(text.as_bytes().chunks(2)) {
+            let pair = std::str::from_utf8(pair).map_err(|_| ParseRomError)?;
+            *byte = u8::from_str_radix(pair, 16).map_err(|_| ParseRomError)?;
+        }
+
+        rom.is_valid().then_some(rom).ok_or(ParseRomError)
+    }
+}
+
+/// The bit-level operations of a bus master.
+pub trait Bus {
+    /// True when a device answered the reset with a presence pulse.
+    fn reset(&mut self) -> bool;
+    fn write_bit(&mut self, bit: bool);
+    fn read_bit(&mut self) -> bool;
+
+    fn write_byte(&mut self, byte: u8) {
+        for i in 0..8 {
+            self.write_bit(byte & (1 << i) != 0);
+        }
+    }
+
+    fn read_byte(&mut self) -> u8 {
+        (0..8).fold(0, |byte, i| byte | (self.read_bit() as u8) << i)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BusError {
+    /// No presence pulse
+    NoDevice,
+    /// Both a bit and its complement read 1 during the search: noise, or
+    /// a device left the bus
+    Search,
+    Crc,
+}
+
+impl fmt::Display for BusError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            BusError::NoDevice => "no device on the 1-Wire bus",
+            BusError::Search => "1-Wire search failed",
+            BusError::Crc => "1-Wire CRC mismatch",
+        })
+    }
+}
+
+impl std::error::Error for BusError {}
+
+/// Every device on the bus, in the order of their ROM codes read least
+/// significant bit first.
+pub fn search(bus: &mut impl Bus) -> Result<Vec<Rom>, BusError> {
+    let mut found = Vec::new();
+    let mut rom = Rom([0; 8]);
+    // The highest bit at which the last pass took the 0 branch of two
+    let mut last_discrepancy = None;
+
+    while found.len() < MAX_DEVICES {
+        if !bus.reset() {
+            return Err(BusError::NoDevice);
+        }
+        bus.write_byte(SEARCH_ROM);
+
+        let mut last_zero = None;
+        for index in 0..64 {
+            let (bit, complement) = (bus.read_bit(), bus.read_bit());
+            let direction = match (bit, complement) {
+                (true, true) => return Err(BusError::Search),
+                (bit, complement) if bit != complement => bit,
+                // Devices differ here: take the 0 branch first, then the
+                // 1 branch once everything below it was found
+                _ => {
+                    let direction = match last_discrepancy {
+                        Some(last) if index < last => rom.bit(index),
+                        Some(last) => index == last,
+                        None => false,
+                    };
+                    if !direction {
+                        last_zero = Some(index);
+                    }
+                    direction
+                }
+            };
+            rom.set_bit(index, direction);
+            bus.write_bit(direction);
+        }
+
+        if !rom.is_valid() {
+            return Err(BusError::Crc);
+        }
+        found.push(rom);
+        last_discrepancy = last_zero;
+        if last_discrepancy.is_none() {
+            break;
+        }
+    }
+
+    Ok(found)
+}
+
+/// The temperature in °C from a DS18B20's scratchpad.
+pub fn celsius(scratchpad: &[u8; 9]) -> Result<f32, BusError> {
+    if crc8(&scratchpad[..8]) != scratchpad[8] || scratchpad.iter().all(|&byte| byte == 0) {
+        return Err(BusError::Crc);
+    }
+    let raw = i16::from_le_bytes([scratchpad[0], scratchpad[1]]);
+    if raw == POWER_ON_RAW {
+        // The probe reset since the conversion was started
+        return Err(BusError::NoDevice);
+    }
+
+    Ok(raw as f32 / 16.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rom(serial: u8) -> Rom {
+        let mut rom = [FAMILY_DS18B20, serial, 0x4A, 0x1C, 0x05, 0x00, 0x00, 0];
+        rom[7] = crc8(&rom[..7]);
+        Rom(rom)
+    }
+
+    /// Devices answering the search on an open-drain bus, where any device
+    /// pulling low wins.
+    struct Devices {
+        roms: Vec<Rom>,
+        active: Vec<bool>,
+        /// Bits of the command written so far
+        written: usize,
+        /// The ROM bit the search is at
+        index: usize,
+        complement: bool,
+    }
+
+    impl Bus for Devices {
+        fn reset(&mut self) -> bool {
+            self.active = vec![true; self.roms.len()];
+            self.written = 0;
+            self.index = 0;
+            self.complement = false;
+            !self.roms.is_empty()
+        }
+
+        fn write_bit(&mut self, bit: bool) {
+            if self.written < 8 {
+                self.written += 1;
+                return;
+            }
+            for (rom, active) in self.roms.iter().zip(&mut self.active) {
+                *active &= rom.bit(self.index) == bit;
+            }
+            self.index += 1;
+        }
+
+        fn read_bit(&mut self) -> bool {
+            let complement = self.complement;
+            self.complement = !complement;
+            self.roms
+                .iter()
+                .zip(&self.active)
+                .filter(|(_, active)| **active)
+                .all(|(rom, _)| rom.bit(self.index) != complement)
+        }
+    }
+
+    #[test]
+    fn finds_every_device() {
+        let roms = vec![rom(0x01), rom(0x02), rom(0x03), rom(0x80)];
+        let mut bus = Devices {
+            roms: roms.clone(),
+            active: Vec::new(),
+            written: 0,
+            index: 0,
+            complement: false,
+        };
+        let mut found = search(&mut bus).unwrap();
+        found.sort_by_key(|rom| rom.0);
+        assert_eq!(found, roms);
+
+        bus.roms.clear();
+        assert_eq!(search(&mut bus), Err(BusError::NoDevice));
+    }
+
+    #[test]
+    fn parses_rom_codes() {
+        let rom = rom(0x42);
+        assert!(rom.is_valid());
+        assert_eq!(rom.to_string().parse::<Rom>(), Ok(rom));
+        assert!(rom.to_string().starts_with("2842"));
+        assert_eq!("2842".parse::<Rom>(), Err(ParseRomError));
+        assert_eq!("28420000000000FF".parse::<Rom>(), Err(ParseRomError));
+    }
+
+    #[test]
+    fn converts_the_scratchpad() {
+        let mut scratchpad = [0x91, 0x01, 0x4B, 0x46, 0x7F, 0xFF, 0x0F, 0x10, 0];
+        scratchpad[8] = crc8(&scratchpad[..8]);
+        assert_eq!(celsius(&scratchpad), Ok(25.0625));
+
+        let mut negative = [0x5E, 0xFF, 0x4B, 0x46, 0x7F, 0xFF, 0x0F, 0x10, 0];
+        negative[8] = crc8(&negative[..8]);
+        assert_eq!(celsius(&negative), Ok(-10.125));
+
+        scratchpad[0] ^= 1;
+        assert_eq!(celsius(&scratchpad), Err(BusError::Crc));
+        assert_eq!(celsius(&[0; 9]), Err(BusError::Crc));
+    }
+}
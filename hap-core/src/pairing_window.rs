@@ -0,0 +1,111 @@
+//! The time an unpaired accessory takes pairings for: opened at the first
+//! boot, after a pairing reset or by a button press, closed once it ran
+//! out. A closed window stays closed across restarts until opened again.
+//!
+//! Times are uptime seconds.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum State {
+    /// A controller is paired, the window does not apply
+    Paired = 0,
+    Open = 1,
+    Closed = 2,
+}
+
+impl State {
+    pub const VALUES: &'static [u8] = &[0, 1, 2];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            State::Paired => "paired",
+            State::Open => "open",
+            State::Closed => "closed",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct PairingWindow {
+    length_secs: u32,
+    /// None while closed
+    open_until: Option<u32>,
+}
+
+impl PairingWindow {
+    /// A closed window, opened for `length_secs` at a time.
+    pub const fn new(length_secs: u32) -> Self {
+        Self {
+            length_secs,
+            open_until: None,
+        }
+    }
+
+    /// Opens the window for its full length from `now`, again if it is open.
+    pub fn open(&mut self, now: u32) {
+        self.open_until = Some(now.saturating_add(self.length_secs));
+    }
+
+    pub fn close(&mut self) {
+        self.open_until = None;
+    }
+
+    pub fn is_open(&self, now: u32) -> bool {
+        self.open_until.is_some_and(|until| now < until)
+    }
+
+    /// Seconds until the window closes, 0 while it is closed.
+    pub fn remaining(&self, now: u32) -> u32 {
+        self.open_until.map_or(0, |until| until.saturating_sub(now))
+    }
+
+    /// Closes a window that ran out; true only at that transition.
+    pub fn expire(&mut self, now: u32) -> bool {
+        let expired = self.open_until.is_some_and(|until| now >= until);
+        if expired {
+            self.open_until = None;
+        }
+
+        expired
+    }
+
+    pub fn state(&self, now: u32, paired: bool) -> State {
+        match (paired, self.is_open(now)) {
+            (true, _) => State::Paired,
+            (false, true) => State::Open,
+            (false, false) => State::Closed,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn closes_once_the_window_ran_out() {
+        let mut window = PairingWindow::new(900);
+        assert_eq!(window.state(0, false), State::Closed);
+
+        window.open(100);
+        assert_eq!(window.state(500, false), State::Open);
+        assert_eq!(window.remaining(500), 500);
+        assert!(!window.expire(999));
+        assert!(window.expire(1000));
+        assert!(!window.expire(1001));
+        assert_eq!(window.state(1001, false), State::Closed);
+        assert_eq!(window.remaining(1001), 0);
+    }
+
+    #[test]
+    fn reopening_restarts_the_window() {
+        let mut window = PairingWindow::new(900);
+        window.open(0);
+        window.open(600);
+        assert_eq!(window.remaining(900), 600);
+        assert_eq!(window.state(900, true), State::Paired);
+
+        window.close();
+        assert!(!window.is_open(900));
+        assert!(!window.expire(2000));
+    }
+}
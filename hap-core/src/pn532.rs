@@ -0,0 +1,238 @@
+//! The PN532's host protocol, whatever the bus: commands go out in normal
+//! information frames, the chip acknowledges each and then answers with a
+//! frame of its own.
+//!
+//! Frame: 00 00 FF, LEN, LCS, TFI, data, DCS, 00. LEN counts TFI and the
+//! data, LCS makes LEN + LCS zero and DCS makes TFI + data + DCS zero.
+
+use std::fmt;
+
+use crate::rfid::Uid;
+
+pub const HOST_TO_PN532: u8 = 0xD4;
+pub const PN532_TO_HOST: u8 = 0xD5;
+pub const ACK: [u8; 6] = [0x00, 0x00, 0xFF, 0x00, 0xFF, 0x00];
+
+pub const GET_FIRMWARE_VERSION: u8 = 0x02;
+pub const SAM_CONFIGURATION: u8 = 0x14;
+pub const RF_CONFIGURATION: u8 = 0x32;
+pub const IN_LIST_PASSIVE_TARGET: u8 = 0x4A;
+
+/// SAMConfiguration: normal mode, no SAM, 50 ms × 20 for virtual card mode,
+/// and the IRQ pin driven.
+pub const SAM_NORMAL: [u8; 3] = [0x01, 0x14, 0x01];
+/// RFConfiguration: retry activating a passive target 16 times rather than
+/// forever, so InListPassiveTarget answers when no tag is in the field.
+pub const PASSIVE_RETRIES: [u8; 4] = [0x05, 0xFF, 0x01, 0x10];
+/// InListPassiveTarget: one target, ISO14443A at 106 kbps.
+pub const LIST_ONE_14443A: [u8; 2] = [0x01, 0x00];
+
+/// What a normal frame carries at most, TFI and command included.
+pub const MAX_LEN: usize = 255;
+
+const START: [u8; 2] = [0x00, 0xFF];
+// The chip's answer to a frame it could not take
+const SYNTAX_ERROR: u8 = 0x7F;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrameError {
+    /// No start code, or cut short
+    Truncated,
+    Checksum,
+    /// Not an answer to the command sent
+    Unexpected,
+    /// The chip refused the frame
+    Syntax,
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            FrameError::Truncated => "truncated PN532 frame",
+            FrameError::Checksum => "PN532 frame checksum mismatch",
+            FrameError::Unexpected => "unexpected PN532 response",
+            FrameError::Syntax => "the PN532 refused the frame",
+        })
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+fn checksum(bytes: &[u8]) -> u8 {
+    bytes
+        .iter()
+        .fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+        .wrapping_neg()
+}
+
+/// The frame for `command` with its parameters.
+pub fn encode(command: u8, params: &[u8]) -> Vec<u8> {
+    assert!(params.len() + 2 <= MAX_LEN, "PN532 command too long");
+
+    let len = (params.len() + 2) as u8;
+    let mut out = vec![0x00, START[0], START[1], len, len.wrapping_neg()];
+    let body = out.len();
+    out.push(HOST_TO_PN532);
+    out.push(command);
+    out.extend_from_slice(params);
+    out.push(checksum(&out[body..]));
+    out.push(0x00);
+
+    out
+}
+
+/// What follows the start code; the preamble's length varies.
+fn after_start(bytes: &[u8]) -> Result<&[u8], FrameError> {
+    let start = bytes
+        .windows(2)
+        .position(|pair| pair == START)
+        .ok_or(FrameError::Truncated)?;
+
+    Ok(&bytes[start + 2..])
+}
+
+pub fn is_ack(bytes: &[u8]) -> bool {
+    after_start(bytes).is_ok_and(|rest| rest.starts_with(&[0x00, 0xFF]))
+}
+
+/// The data of the chip's answer to `command`, read with whatever follows
+/// it on the bus.
+pub fn decode(bytes: &[u8], command: u8) -> Result<&[u8], FrameError> {
+    let &[len, lcs, ref rest @ ..] = after_start(bytes)? else {
+        return Err(FrameError::Truncated);
+    };
+    if len.wrapping_add(lcs) != 0 {
+        return Err(FrameError::Checksum);
+    }
+    let len = len as usize;
+    if rest.len() < len + 1 {
+        return Err(FrameError::Truncated);
+    }
+    let (body, dcs) = (&rest[..len], rest[len]);
+    if checksum(body) != dcs {
+        return Err(FrameError::Checksum);
+    }
+
+    match body {
+        [SYNTAX_ERROR] => Err(FrameError::Syntax),
+        [PN532_TO_HOST, response, data @ ..] if *response == command.wrapping_add(1) => Ok(data),
+        _ => Err(FrameError::Unexpected),
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Firmware {
+    /// 0x32 for a PN532
+    pub ic: u8,
+    pub version: u8,
+    pub revision: u8,
+}
+
+pub fn parse_firmware(data: &[u8]) -> Result<Firmware, FrameError> {
+    match *data {
+        [ic, version, revision, _support] => Ok(Firmware {
+            ic,
+            version,
+            revision,
+        }),
+        _ => Err(FrameError::Unexpected),
+    }
+}
+
+/// A tag in the field.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Target {
+    pub sens_res: u16,
+    pub sel_res: u8,
+    pub uid: Uid,
+}
+
+/// The answer to InListPassiveTarget for ISO14443A: none if no tag came
+/// into the field before the chip gave up.
+pub fn parse_target(data: &[u8]) -> Result<Option<Target>, FrameError> {
+    match *data {
+        [0, ..] => Ok(None),
+        [_count, _tg, sens_hi, sens_lo, sel_res, len, ref rest @ ..]
+            if rest.len() >= len as usize =>
+        {
+            let uid = Uid::new(&rest[..len as usize]).ok_or(FrameError::Unexpected)?;
+            Ok(Some(Target {
+                sens_res: u16::from_be_bytes([sens_hi, sens_lo]),
+                sel_res,
+                uid,
+            }))
+        }
+        _ => Err(FrameError::Unexpected),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_commands() {
+        assert_eq!(
+            encode(GET_FIRMWARE_VERSION, &[]),
+            [0x00, 0x00, 0xFF, 0x02, 0xFE, 0xD4, 0x02, 0x2A, 0x00]
+        );
+        assert_eq!(
+            encode(SAM_CONFIGURATION, &SAM_NORMAL),
+            [0x00, 0x00, 0xFF, 0x05, 0xFB, 0xD4, 0x14, 0x01, 0x14, 0x01, 0x02, 0x00]
+        );
+    }
+
+    #[test]
+    fn decodes_responses() {
+        let firmware = [
+            0x00, 0x00, 0xFF, 0x06, 0xFA, 0xD5, 0x03, 0x32, 0x01, 0x06, 0x07, 0xE8, 0x00,
+        ];
+        let data = decode(&firmware, GET_FIRMWARE_VERSION).unwrap();
+        assert_eq!(
+            parse_firmware(data),
+            Ok(Firmware {
+                ic: 0x32,
+                version: 1,
+                revision: 6
+            })
+        );
+        assert_eq!(
+            decode(&firmware, SAM_CONFIGURATION),
+            Err(FrameError::Unexpected)
+        );
+
+        let mut corrupt = firmware;
+        corrupt[8] ^= 1;
+        assert_eq!(
+            decode(&corrupt, GET_FIRMWARE_VERSION),
+            Err(FrameError::Checksum)
+        );
+        assert_eq!(
+            decode(&firmware[..9], GET_FIRMWARE_VERSION),
+            Err(FrameError::Truncated)
+        );
+        assert_eq!(
+            decode(
+                &[0x00, 0x00, 0xFF, 0x01, 0xFF, 0x7F, 0x81, 0x00],
+                SAM_CONFIGURATION
+            ),
+            Err(FrameError::Syntax)
+        );
+
+        assert!(is_ack(&ACK));
+        assert!(is_ack(&[0x01, 0x00, 0x00, 0xFF, 0x00, 0xFF, 0x00]));
+        assert!(!is_ack(&firmware));
+    }
+
+    #[test]
+    fn parses_targets() {
+        let data = [0x01, 0x01, 0x00, 0x04, 0x08, 0x04, 0xDE, 0xAD, 0xBE, 0xEF];
+        let target = parse_target(&data).unwrap().unwrap();
+        assert_eq!(target.sens_res, 0x0004);
+        assert_eq!(target.sel_res, 0x08);
+        assert_eq!(target.uid.to_string(), "DE:AD:BE:EF");
+
+        assert_eq!(parse_target(&[0x00]), Ok(None));
+        assert_eq!(parse_target(&data[..8]), Err(FrameError::Unexpected));
+    }
+}
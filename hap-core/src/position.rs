@@ -0,0 +1,407 @@
+//! Motorised doors, windows and coverings with position feedback: HomeKit
+//! moves them from their current to their target position in percent, and
+//! tilts the slats of blinds in degrees.
+
+/// HAP's Position State.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PositionState {
+    Decreasing = 0,
+    Increasing = 1,
+    Stopped = 2,
+}
+
+/// How to drive the motor.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Drive {
+    Stop,
+    Open,
+    Close,
+}
+
+impl Drive {
+    pub fn reverse(self) -> Self {
+        match self {
+            Drive::Stop => Drive::Stop,
+            Drive::Open => Drive::Close,
+            Drive::Close => Drive::Open,
+        }
+    }
+
+    pub fn state(self) -> PositionState {
+        match self {
+            Drive::Stop => PositionState::Stopped,
+            Drive::Open => PositionState::Increasing,
+            Drive::Close => PositionState::Decreasing,
+        }
+    }
+}
+
+/// The raw readings of the position sensor, ADC or encoder counts, at 0 %
+/// and at 100 %; either order works.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Calibration {
+    pub closed: i32,
+    pub open: i32,
+}
+
+impl Calibration {
+    /// Position in percent, clamped to 0-100.
+    pub fn percent(&self, raw: i32) -> u8 {
+        if self.closed == self.open {
+            return 0;
+        }
+
+        let span = self.open as f32 - self.closed as f32;
+        ((raw as f32 - self.closed as f32) / span * 100.0)
+            .round()
+            .clamp(0.0, 100.0) as u8
+    }
+}
+
+/// Why the motor stopped short of the target.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fault {
+    /// The position did not change for the stall time
+    Stalled,
+    /// The move took longer than the travel time
+    TimedOut,
+}
+
+/// Drives towards the target, mid-move retargets included, and stops on a
+/// stall, a timeout or an obstruction, holding the position it stopped at.
+#[derive(Clone, Debug)]
+pub struct Mover {
+    tolerance: u8,
+    stall_ms: u64,
+    travel_ms: u64,
+    measured: u8,
+    target: u8,
+    drive: Drive,
+    started: u64,
+    /// When the position last changed, and to what
+    progress: (u64, u8),
+    obstructed: bool,
+}
+
+impl Mover {
+    /// Positions within `tolerance` of the target count as there.
+    pub const fn new(tolerance: u8, stall_ms: u64, travel_ms: u64) -> Self {
+        Self {
+            tolerance,
+            stall_ms,
+            travel_ms,
+            measured: 0,
+            target: 0,
+            drive: Drive::Stop,
+            started: 0,
+            progress: (0, 0),
+            obstructed: false,
+        }
+    }
+
+    /// Starts out holding `position`, as after a boot.
+    pub fn hold(&mut self, position: u8) {
+        self.measured = position;
+        self.target = position;
+        self.drive = Drive::Stop;
+    }
+
+    /// The current position for HomeKit: the target once it is there, so
+    /// the Home app does not show a door stopped within the tolerance as
+    /// still moving.
+    pub fn position(&self) -> u8 {
+        if self.drive == Drive::Stop && self.measured.abs_diff(self.target) <= self.tolerance {
+            self.target
+        } else {
+            self.measured
+        }
+    }
+
+    pub fn target(&self) -> u8 {
+        self.target
+    }
+
+    pub fn drive(&self) -> Drive {
+        self.drive
+    }
+
+    pub fn is_obstructed(&self) -> bool {
+        self.obstructed
+    }
+
+    /// Moves to `target` from `now`, milliseconds on any monotonic clock;
+    /// a new target clears an obstruction.
+    pub fn set_target(&mut self, target: u8, now: u64) {
+        self.target = target.min(100);
+        self.obstructed = false;
+        self.started = now;
+        self.progress = (now, self.measured);
+    }
+
+    /// Takes the position measured at `now` and tells how to drive the
+    /// motor, and why it stopped if it stopped short.
+    pub fn update(&mut self, position: u8, now: u64) -> (Drive, Option<Fault>) {
+        if position != self.progress.1 {
+            self.progress = (now, position);
+        }
+        self.measured = position;
+
+        if self.obstructed {
+            self.target = position;
+            return (Drive::Stop, None);
+        }
+
+        let wanted = if position as u16 + (self.tolerance as u16) < self.target as u16 {
+            Drive::Open
+        } else if position as u16 > self.target as u16 + self.tolerance as u16 {
+            Drive::Close
+        } else {
+            Drive::Stop
+        };
+
+        let fault = if wanted == Drive::Stop || self.drive == Drive::Stop {
+            None
+        } else if now.saturating_sub(self.progress.0) >= self.stall_ms {
+            Some(Fault::Stalled)
+        } else if now.saturating_sub(self.started) >= self.travel_ms {
+            Some(Fault::TimedOut)
+        } else {
+            None
+        };
+
+        if fault.is_some() {
+            self.hold(position);
+        } else {
+            self.drive = wanted;
+        }
+
+        (self.drive, fault)
+    }
+
+    /// Takes the position something else drove the motor to, the slats of
+    /// blinds turning: the target follows unless it is still within the
+    /// tolerance, so the lift does not undo it.
+    pub fn settle(&mut self, position: u8) {
+        self.measured = position;
+        self.drive = Drive::Stop;
+        if position.abs_diff(self.target) > self.tolerance {
+            self.target = position;
+        }
+    }
+
+    /// The safety edge tripped: holds where the door stopped until the next
+    /// target, and tells which way to back off if it was moving.
+    pub fn obstruct(&mut self) -> Drive {
+        let drive = self.drive;
+        self.hold(self.measured);
+        self.obstructed = true;
+
+        drive.reverse()
+    }
+}
+
+/// The slats of venetian blinds, in degrees from -90 to 90, turned over by
+/// short runs of the lift motor: a full turn takes `full_ms`, and any lift
+/// move leaves them at the end of its direction.
+#[derive(Clone, Debug)]
+pub struct Tilt {
+    full_ms: u64,
+    current: i8,
+    target: i8,
+}
+
+impl Tilt {
+    pub const fn new(full_ms: u64) -> Self {
+        Self {
+            full_ms,
+            current: 0,
+            target: 0,
+        }
+    }
+
+    pub fn current(&self) -> i8 {
+        self.current
+    }
+
+    pub fn target(&self) -> i8 {
+        self.target
+    }
+
+    pub fn set_target(&mut self, angle: i8) {
+        self.target = angle.clamp(-90, 90);
+    }
+
+    /// Starts out with the slats at `angle`, as after a boot.
+    pub fn hold(&mut self, angle: i8) {
+        self.current = angle.clamp(-90, 90);
+        self.target = self.current;
+    }
+
+    /// A lift move in `drive`'s direction turned the slats all the way over.
+    pub fn lifted(&mut self, drive: Drive) {
+        match drive {
+            Drive::Open => self.current = 90,
+            Drive::Close => self.current = -90,
+            Drive::Stop => {}
+        }
+    }
+
+    /// How long to run the motor which way to bring the slats to the target,
+    /// none when they are there; they count as there from then on.
+    pub fn pulse(&mut self) -> Option<(Drive, u64)> {
+        let delta = self.target as i32 - self.current as i32;
+        if delta == 0 {
+            return None;
+        }
+
+        self.current = self.target;
+        let drive = if delta > 0 { Drive::Open } else { Drive::Close };
+        Some((drive, delta.unsigned_abs() as u64 * self.full_ms / 180))
+    }
+}
+
+/// The pulse width for a hobby servo at `angle`, -90 to 90 degrees, between
+/// the pulse widths of its two ends.
+pub fn servo_pulse_us(angle: i8, min_us: u32, max_us: u32) -> u32 {
+    let angle = angle.clamp(-90, 90) as i32 + 90;
+    (min_us as i32 + (max_us as i32 - min_us as i32) * angle / 180) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mover() -> Mover {
+        let mut mover = Mover::new(2, 3000, 60_000);
+        mover.hold(0);
+        mover
+    }
+
+    #[test]
+    fn maps_the_calibration_to_percent() {
+        let pot = Calibration {
+            closed: 400,
+            open: 3600,
+        };
+        assert_eq!(pot.percent(400), 0);
+        assert_eq!(pot.percent(2000), 50);
+        assert_eq!(pot.percent(3900), 100);
+
+        let encoder = Calibration {
+            closed: 0,
+            open: -1200,
+        };
+        assert_eq!(encoder.percent(-300), 25);
+        assert_eq!(encoder.percent(100), 0);
+        assert_eq!(Calibration { closed: 7, open: 7 }.percent(7), 0);
+    }
+
+    #[test]
+    fn drives_to_the_target() {
+        let mut door = mover();
+        door.set_target(50, 0);
+        assert_eq!(door.update(0, 0), (Drive::Open, None));
+        assert_eq!(door.update(30, 1000), (Drive::Open, None));
+        assert_eq!(door.position(), 30);
+        assert_eq!(door.update(49, 2000), (Drive::Stop, None));
+        // Within the tolerance, reported as there
+        assert_eq!(door.position(), 50);
+    }
+
+    #[test]
+    fn retargets_mid_move() {
+        let mut door = mover();
+        door.set_target(100, 0);
+        assert_eq!(door.update(40, 1000), (Drive::Open, None));
+        door.set_target(10, 1500);
+        assert_eq!(door.update(45, 2000), (Drive::Close, None));
+        assert_eq!(door.update(10, 4000), (Drive::Stop, None));
+    }
+
+    #[test]
+    fn stops_a_stalled_or_slow_move() {
+        let mut door = mover();
+        door.set_target(100, 0);
+        assert_eq!(door.update(20, 1000), (Drive::Open, None));
+        assert_eq!(door.update(20, 3999), (Drive::Open, None));
+        assert_eq!(door.update(20, 4000), (Drive::Stop, Some(Fault::Stalled)));
+        assert_eq!(door.target(), 20);
+
+        let mut slow = Mover::new(2, 3000, 5000);
+        slow.hold(0);
+        slow.set_target(100, 0);
+        for (i, position) in [10, 20, 30, 40].into_iter().enumerate() {
+            slow.update(position, i as u64 * 1000);
+        }
+        assert_eq!(slow.update(50, 5000), (Drive::Stop, Some(Fault::TimedOut)));
+    }
+
+    #[test]
+    fn backs_off_an_obstruction_and_holds() {
+        let mut door = mover();
+        door.hold(80);
+        door.set_target(0, 0);
+        assert_eq!(door.update(60, 1000), (Drive::Close, None));
+
+        assert_eq!(door.obstruct(), Drive::Open);
+        assert!(door.is_obstructed());
+        // Backing off moves the door, it still does not head for the target
+        assert_eq!(door.update(63, 1200), (Drive::Stop, None));
+        assert_eq!(door.target(), 63);
+
+        door.set_target(0, 2000);
+        assert!(!door.is_obstructed());
+        assert_eq!(door.update(63, 2000), (Drive::Close, None));
+    }
+
+    #[test]
+    fn settles_where_the_tilt_left_the_lift() {
+        let mut door = mover();
+        door.hold(50);
+        door.settle(52);
+        assert_eq!(door.target(), 50);
+        assert_eq!(door.update(52, 0), (Drive::Stop, None));
+
+        door.settle(55);
+        assert_eq!(door.target(), 55);
+        assert_eq!(door.position(), 55);
+    }
+
+    #[test]
+    fn pulses_the_slats_to_the_target() {
+        let mut tilt = Tilt::new(1800);
+        tilt.hold(0);
+        assert_eq!(tilt.pulse(), None);
+
+        tilt.set_target(45);
+        assert_eq!(tilt.pulse(), Some((Drive::Open, 450)));
+        assert_eq!(tilt.current(), 45);
+        assert_eq!(tilt.pulse(), None);
+
+        tilt.set_target(-120);
+        assert_eq!(tilt.target(), -90);
+        assert_eq!(tilt.pulse(), Some((Drive::Close, 1350)));
+    }
+
+    #[test]
+    fn reapplies_the_tilt_after_a_lift_move() {
+        let mut tilt = Tilt::new(1800);
+        tilt.hold(30);
+        tilt.lifted(Drive::Close);
+        assert_eq!(tilt.current(), -90);
+        assert_eq!(tilt.pulse(), Some((Drive::Open, 1200)));
+
+        tilt.lifted(Drive::Open);
+        assert_eq!(tilt.pulse(), Some((Drive::Close, 600)));
+    }
+
+    #[test]
+    fn maps_angles_to_servo_pulses() {
+        assert_eq!(servo_pulse_us(-90, 500, 2500), 500);
+        assert_eq!(servo_pulse_us(0, 500, 2500), 1500);
+        assert_eq!(servo_pulse_us(90, 500, 2500), 2500);
+        assert_eq!(servo_pulse_us(127, 1000, 2000), 2000);
+        // Servos mounted the other way round
+        assert_eq!(servo_pulse_us(-90, 2000, 1000), 2000);
+    }
+}
@@ -0,0 +1,54 @@
+use std::ffi::c_void;
+
+use crate::sys::{Char, HAP_FAIL, HAP_SUCCESS};
+use crate::trace::{self, Kind};
+use crate::write::Status;
+use crate::{sessions, Handlers};
+
+/// Refreshes a characteristic when a controller reads it. The SDK replies
+/// with the stored value, so the handler updates it before returning.
+pub trait ReadHandler: Sync {
+    fn read(&self, hc: Char) -> Result<(), Status>;
+}
+
+impl<F> ReadHandler for F
+where
+    F: Fn(Char) -> Result<(), Status> + Sync,
+{
+    fn read(&self, hc: Char) -> Result<(), Status> {
+        self(hc)
+    }
+}
+
+/// The read callback of services with a handler, which is the private data.
+///
+/// # Safety
+///
+/// `serv_priv` has to point to the `Handlers` of the service.
+pub unsafe extern "C" fn on_read(
+    hc: *mut c_void,
+    status: *mut i32,
+    serv_priv: *mut c_void,
+    read_priv: *mut c_void,
+) -> i32 {
+    sessions::request(read_priv);
+    let handlers = (serv_priv as *const Handlers).as_ref();
+    let result = match (Char::from_ptr(hc), handlers.and_then(|h| h.read)) {
+        (Some(hc), Some(handler)) => handler.read(hc),
+        _ => Err(Status::ResourceAbsent),
+    };
+
+    let code = result.err().unwrap_or(Status::Success);
+    if let (true, Some(hc)) = (trace::enabled(), Char::from_ptr(hc)) {
+        let kind = Kind::Read { hc, status: code };
+        trace::record(trace::controller(read_priv), kind);
+    }
+    if !status.is_null() {
+        *status = code.code();
+    }
+    if code == Status::Success {
+        HAP_SUCCESS
+    } else {
+        HAP_FAIL
+    }
+}
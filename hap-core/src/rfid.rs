@@ -0,0 +1,290 @@
+//! RFID tags for local unlock: their UIDs, the whitelist of the ones that
+//! open, and the rejected tags that make up a tamper attempt.
+//!
+//! Stored form of the whitelist: a format version byte, then per tag its
+//! UID length and bytes.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::str::FromStr;
+
+pub const MAX_TAGS: usize = 32;
+pub const FORMAT_VERSION: u8 = 1;
+
+/// An ISO14443A UID: single, double or triple size.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Uid {
+    len: u8,
+    bytes: [u8; 10],
+}
+
+impl Uid {
+    pub fn new(uid: &[u8]) -> Option<Self> {
+        if !matches!(uid.len(), 4 | 7 | 10) {
+            return None;
+        }
+        let mut bytes = [0; 10];
+        bytes[..uid.len()].copy_from_slice(uid);
+
+        Some(Self {
+            len: uid.len() as u8,
+            bytes,
+        })
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len as usize]
+    }
+}
+
+/// Hex bytes separated by colons, as the readers' apps show them.
+impl fmt::Display for Uid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, byte) in self.as_bytes().iter().enumerate() {
+            if i > 0 {
+                f.write_str(":")?;
+            }
+            write!(f, "{:02X}", byte)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Uid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ParseUidError;
+
+impl fmt::Display for ParseUidError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("expected 4, 7 or 10 hex bytes")
+    }
+}
+
+impl std::error::Error for ParseUidError {}
+
+/// With or without colons between the bytes.
+impl FromStr for Uid {
+    type Err = ParseUidError;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let digits: String = text.chars().filter(|c| *c != ':').collect();
+        let bytes = digits
+            .as_bytes()
+            .chunks(2)
+            .map(|pair| {
+                let pair = std::str::from_utf8(pair)
+                    .ok()
+                    .filter(|pair| pair.len() == 2)?;
+                u8::from_str_radix(pair, 16).ok()
+            })
+            .collect::<Option<Vec<u8>>>()
+            .ok_or(ParseUidError)?;
+
+        Uid::new(&bytes).ok_or(ParseUidError)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WhitelistError {
+    Full,
+    Malformed,
+}
+
+impl fmt::Display for WhitelistError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WhitelistError::Full => write!(f, "the whitelist holds {} tags", MAX_TAGS),
+            WhitelistError::Malformed => f.write_str("malformed tag whitelist"),
+        }
+    }
+}
+
+impl std::error::Error for WhitelistError {}
+
+/// The tags that unlock, in the order they were enrolled.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Whitelist {
+    tags: Vec<Uid>,
+}
+
+impl Whitelist {
+    pub const fn new() -> Self {
+        Self { tags: Vec::new() }
+    }
+
+    pub fn contains(&self, uid: &Uid) -> bool {
+        self.tags.contains(uid)
+    }
+
+    /// False if the tag was on the list already.
+    pub fn add(&mut self, uid: Uid) -> Result<bool, WhitelistError> {
+        if self.contains(&uid) {
+            return Ok(false);
+        }
+        if self.tags.len() == MAX_TAGS {
+            return Err(WhitelistError::Full);
+        }
+        self.tags.push(uid);
+
+        Ok(true)
+    }
+
+    /// False if the tag was not on the list.
+    pub fn remove(&mut self, uid: &Uid) -> bool {
+        let len = self.tags.len();
+        self.tags.retain(|tag| tag != uid);
+        self.tags.len() != len
+    }
+
+    pub fn tags(&self) -> &[Uid] {
+        &self.tags
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = vec![FORMAT_VERSION];
+        for tag in &self.tags {
+            out.push(tag.len);
+            out.extend_from_slice(tag.as_bytes());
+        }
+
+        out
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, WhitelistError> {
+        let (&version, mut rest) = bytes.split_first().ok_or(WhitelistError::Malformed)?;
+        if version != FORMAT_VERSION {
+            return Err(WhitelistError::Malformed);
+        }
+
+        let mut whitelist = Self::new();
+        while let Some((&len, tail)) = rest.split_first() {
+            let len = len as usize;
+            if tail.len() < len {
+                return Err(WhitelistError::Malformed);
+            }
+            let uid = Uid::new(&tail[..len]).ok_or(WhitelistError::Malformed)?;
+            whitelist.add(uid)?;
+            rest = &tail[len..];
+        }
+
+        Ok(whitelist)
+    }
+}
+
+/// Rejected tags within a sliding window; `limit` of them is a tamper
+/// attempt, which stands until cleared.
+#[derive(Clone, Debug)]
+pub struct Tamper {
+    limit: usize,
+    window_secs: u32,
+    /// Uptime seconds of the recent rejections, oldest first
+    rejections: VecDeque<u32>,
+    tampered: bool,
+}
+
+impl Tamper {
+    pub const fn new(limit: usize, window_secs: u32) -> Self {
+        Self {
+            limit,
+            window_secs,
+            rejections: VecDeque::new(),
+            tampered: false,
+        }
+    }
+
+    /// Counts a rejected tag at `now`; true when it makes a tamper attempt
+    /// that was not one before.
+    pub fn reject(&mut self, now: u32) -> bool {
+        while self
+            .rejections
+            .front()
+            .is_some_and(|&at| now.saturating_sub(at) >= self.window_secs)
+        {
+            self.rejections.pop_front();
+        }
+        self.rejections.push_back(now);
+        if self.rejections.len() < self.limit || self.tampered {
+            return false;
+        }
+        self.tampered = true;
+
+        true
+    }
+
+    /// True if there was a tamper attempt to clear.
+    pub fn clear(&mut self) -> bool {
+        self.rejections.clear();
+        std::mem::replace(&mut self.tampered, false)
+    }
+
+    pub fn is_tampered(&self) -> bool {
+        self.tampered
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_and_shows_uids() {
+        let uid: Uid = "04:a1:2b:3c".parse().unwrap();
+        assert_eq!(uid.as_bytes(), &[0x04, 0xa1, 0x2b, 0x3c]);
+        assert_eq!(uid.to_string(), "04:A1:2B:3C");
+        assert_eq!("04A12B3C5D6E7F".parse::<Uid>().unwrap().as_bytes().len(), 7);
+        assert_eq!("04:A1:2B".parse::<Uid>(), Err(ParseUidError));
+        assert_eq!("04:A1:2B:3".parse::<Uid>(), Err(ParseUidError));
+        assert_eq!("zz:A1:2B:3C".parse::<Uid>(), Err(ParseUidError));
+    }
+
+    #[test]
+    fn round_trips_the_whitelist() {
+        let mut whitelist = Whitelist::new();
+        let short = Uid::new(&[1, 2, 3, 4]).unwrap();
+        let long = Uid::new(&[1, 2, 3, 4, 5, 6, 7]).unwrap();
+        assert_eq!(whitelist.add(short), Ok(true));
+        assert_eq!(whitelist.add(long), Ok(true));
+        assert_eq!(whitelist.add(short), Ok(false));
+
+        let bytes = whitelist.encode();
+        assert_eq!(Whitelist::decode(&bytes), Ok(whitelist.clone()));
+        assert!(Whitelist::decode(&bytes[..bytes.len() - 1]).is_err());
+        assert!(Whitelist::decode(&[FORMAT_VERSION, 3, 1, 2, 3]).is_err());
+
+        assert!(whitelist.remove(&short));
+        assert!(!whitelist.remove(&short));
+        assert!(!whitelist.contains(&short));
+        assert_eq!(whitelist.tags(), &[long]);
+
+        for i in 0..MAX_TAGS as u8 - 1 {
+            whitelist.add(Uid::new(&[i, 0, 0, 0]).unwrap()).unwrap();
+        }
+        assert_eq!(
+            whitelist.add(Uid::new(&[9, 9, 9, 9]).unwrap()),
+            Err(WhitelistError::Full)
+        );
+    }
+
+    #[test]
+    fn rejections_within_the_window_are_tampering() {
+        let mut tamper = Tamper::new(3, 60);
+        assert!(!tamper.reject(0));
+        assert!(!tamper.reject(30));
+        // The first one has left the window
+        assert!(!tamper.reject(60));
+        assert!(!tamper.is_tampered());
+        assert!(tamper.reject(70));
+        assert!(tamper.is_tampered());
+        assert!(!tamper.reject(80));
+
+        assert!(tamper.clear());
+        assert!(!tamper.clear());
+        assert!(!tamper.reject(90));
+    }
+}
@@ -0,0 +1,386 @@
+//! A weekly on/off table the accessory follows without a home hub, and its
+//! TLV8 form, used for the custom characteristic and for storage alike.
+//!
+//! TLV8 items: `1` format version (one byte), `2` revision (u32, little
+//! endian), `3` one entry per item: days bitmask (bit 0 Sunday), hour,
+//! minute and action (0 off, 1 on).
+
+use std::error::Error;
+use std::fmt;
+
+pub const MAX_ENTRIES: usize = 16;
+pub const FORMAT_VERSION: u8 = 1;
+
+const TYPE_VERSION: u8 = 1;
+const TYPE_REVISION: u8 = 2;
+const TYPE_ENTRY: u8 = 3;
+
+const ALL_DAYS: u8 = 0x7f;
+const DAY_MINUTES: u32 = 24 * 60;
+pub const WEEK_MINUTES: u32 = 7 * DAY_MINUTES;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Entry {
+    /// Bit 0 is Sunday, as in `tm_wday`
+    pub days: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub on: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ScheduleError {
+    Malformed,
+    Version(u8),
+    /// A write based on another revision than the stored one
+    Revision {
+        stored: u32,
+        written: u32,
+    },
+    TooManyEntries(usize),
+    InvalidEntry(Entry),
+    NoSuchEntry(usize),
+}
+
+impl fmt::Display for ScheduleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ScheduleError::Malformed => f.write_str("malformed schedule TLV"),
+            ScheduleError::Version(version) => {
+                write!(f, "schedule format {} is not supported", version)
+            }
+            ScheduleError::Revision { stored, written } => write!(
+                f,
+                "schedule changed meanwhile, revision {} is stored, the write is based on {}",
+                stored, written
+            ),
+            ScheduleError::TooManyEntries(count) => {
+                write!(f, "{} entries, at most {} fit", count, MAX_ENTRIES)
+            }
+            ScheduleError::InvalidEntry(entry) => write!(f, "invalid entry {}", entry),
+            ScheduleError::NoSuchEntry(index) => write!(f, "no entry {}", index),
+        }
+    }
+}
+
+impl Error for ScheduleError {}
+
+impl Entry {
+    pub fn is_valid(&self) -> bool {
+        self.days != 0 && self.days & !ALL_DAYS == 0 && self.hour < 24 && self.minute < 60
+    }
+
+    /// Minutes from `at` back to the latest time the entry fired, at most a week.
+    fn since(&self, at: u32) -> Option<u32> {
+        let time = self.hour as u32 * 60 + self.minute as u32;
+        (0..7)
+            .filter(|day| self.days & (1 << day) != 0)
+            .map(|day| (at + WEEK_MINUTES - (day * DAY_MINUTES + time)) % WEEK_MINUTES)
+            .min()
+    }
+}
+
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        const DAYS: [&str; 7] = ["Su", "Mo", "Tu", "We", "Th", "Fr", "Sa"];
+        let days: Vec<_> = (0..7)
+            .filter(|day| self.days & (1 << day) != 0)
+            .map(|day| DAYS[day])
+            .collect();
+        write!(
+            f,
+            "{} {:02}:{:02} {}",
+            days.join(","),
+            self.hour,
+            self.minute,
+            if self.on { "on" } else { "off" }
+        )
+    }
+}
+
+/// The minute of the week, from Sunday 00:00.
+pub fn minute_of_week(weekday: u8, hour: u8, minute: u8) -> u32 {
+    weekday as u32 * DAY_MINUTES + hour as u32 * 60 + minute as u32
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Schedule {
+    revision: u32,
+    entries: Vec<Entry>,
+}
+
+impl Schedule {
+    pub const fn new() -> Self {
+        Self {
+            revision: 0,
+            entries: Vec::new(),
+        }
+    }
+
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    /// Counts every change, so a controller can tell its copy is outdated.
+    pub fn revision(&self) -> u32 {
+        self.revision
+    }
+
+    /// Replaces the table; nothing changes if any entry is invalid.
+    pub fn replace(&mut self, entries: Vec<Entry>) -> Result<(), ScheduleError> {
+        if entries.len() > MAX_ENTRIES {
+            return Err(ScheduleError::TooManyEntries(entries.len()));
+        }
+        if let Some(entry) = entries.iter().find(|entry| !entry.is_valid()) {
+            return Err(ScheduleError::InvalidEntry(*entry));
+        }
+
+        self.entries = entries;
+        self.revision = self.revision.wrapping_add(1);
+
+        Ok(())
+    }
+
+    pub fn add(&mut self, entry: Entry) -> Result<(), ScheduleError> {
+        let mut entries = self.entries.clone();
+        entries.push(entry);
+        self.replace(entries)
+    }
+
+    pub fn remove(&mut self, index: usize) -> Result<Entry, ScheduleError> {
+        if index >= self.entries.len() {
+            return Err(ScheduleError::NoSuchEntry(index));
+        }
+
+        let entry = self.entries.remove(index);
+        self.revision = self.revision.wrapping_add(1);
+
+        Ok(entry)
+    }
+
+    /// The action of the latest entry within the `span` minutes up to and
+    /// including `at`, a minute of the week; entries later in the table win
+    /// a tie. A span of a week finds the entry in force.
+    pub fn latest(&self, at: u32, span: u32) -> Option<bool> {
+        let mut latest: Option<(u32, bool)> = None;
+        for entry in &self.entries {
+            let Some(since) = entry.since(at % WEEK_MINUTES) else {
+                continue;
+            };
+            if since < span && latest.is_none_or(|(best, _)| since <= best) {
+                latest = Some((since, entry.on));
+            }
+        }
+
+        latest.map(|(_, on)| on)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = vec![TYPE_VERSION, 1, FORMAT_VERSION, TYPE_REVISION, 4];
+        out.extend_from_slice(&self.revision.to_le_bytes());
+        for entry in &self.entries {
+            out.extend_from_slice(&[
+                TYPE_ENTRY,
+                4,
+                entry.days,
+                entry.hour,
+                entry.minute,
+                entry.on as u8,
+            ]);
+        }
+
+        out
+    }
+
+    /// Reads what `encode` stored, keeping its revision.
+    pub fn decode(bytes: &[u8]) -> Result<Self, ScheduleError> {
+        let (revision, entries) = parse(bytes)?;
+        let mut schedule = Self::new();
+        schedule.replace(entries)?;
+        schedule.revision = revision.unwrap_or(0);
+
+        Ok(schedule)
+    }
+
+    /// Applies a controller's write. A revision in it has to be the stored
+    /// one, so two controllers editing at once cannot overwrite each other.
+    pub fn apply_write(&mut self, bytes: &[u8]) -> Result<(), ScheduleError> {
+        let (revision, entries) = parse(bytes)?;
+        if let Some(written) = revision.filter(|written| *written != self.revision) {
+            return Err(ScheduleError::Revision {
+                stored: self.revision,
+                written,
+            });
+        }
+
+        self.replace(entries)
+    }
+}
+
+fn parse(bytes: &[u8]) -> Result<(Option<u32>, Vec<Entry>), ScheduleError> {
+    let mut version = None;
+    let mut revision = None;
+    let mut entries = Vec::new();
+
+    let mut rest = bytes;
+    while let [kind, len, tail @ ..] = rest {
+        let len = *len as usize;
+        if tail.len() < len {
+            return Err(ScheduleError::Malformed);
+        }
+        let (value, tail) = tail.split_at(len);
+        match (*kind, value) {
+            (TYPE_VERSION, [v]) => version = Some(*v),
+            (TYPE_REVISION, [a, b, c, d]) => revision = Some(u32::from_le_bytes([*a, *b, *c, *d])),
+            (TYPE_ENTRY, [days, hour, minute, on @ (0 | 1)]) => entries.push(Entry {
+                days: *days,
+                hour: *hour,
+                minute: *minute,
+                on: *on == 1,
+            }),
+            (TYPE_VERSION | TYPE_REVISION | TYPE_ENTRY, _) => return Err(ScheduleError::Malformed),
+            // Unknown items are left for newer formats
+            _ => {}
+        }
+        rest = tail;
+    }
+    if !rest.is_empty() {
+        return Err(ScheduleError::Malformed);
+    }
+
+    match version {
+        Some(FORMAT_VERSION) => Ok((revision, entries)),
+        Some(version) => Err(ScheduleError::Version(version)),
+        None => Err(ScheduleError::Malformed),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WEEKDAYS: u8 = 0b0111110;
+
+    fn entry(days: u8, hour: u8, minute: u8, on: bool) -> Entry {
+        Entry {
+            days,
+            hour,
+            minute,
+            on,
+        }
+    }
+
+    fn schedule(entries: &[Entry]) -> Schedule {
+        let mut schedule = Schedule::new();
+        schedule.replace(entries.to_vec()).unwrap();
+        schedule
+    }
+
+    #[test]
+    fn round_trips_through_tlv() {
+        let schedule = schedule(&[entry(WEEKDAYS, 7, 30, true), entry(ALL_DAYS, 23, 0, false)]);
+        assert_eq!(Schedule::decode(&schedule.encode()), Ok(schedule));
+    }
+
+    #[test]
+    fn fires_in_the_minute_of_an_entry() {
+        let schedule = schedule(&[entry(WEEKDAYS, 7, 30, true)]);
+        // Monday
+        assert_eq!(schedule.latest(minute_of_week(1, 7, 30), 1), Some(true));
+        assert_eq!(schedule.latest(minute_of_week(1, 7, 31), 1), None);
+        // Sunday is not a weekday
+        assert_eq!(schedule.latest(minute_of_week(0, 7, 30), 1), None);
+    }
+
+    #[test]
+    fn catches_up_with_the_entry_in_force() {
+        let schedule = schedule(&[entry(ALL_DAYS, 7, 0, true), entry(ALL_DAYS, 22, 0, false)]);
+        assert_eq!(
+            schedule.latest(minute_of_week(3, 12, 0), WEEK_MINUTES),
+            Some(true)
+        );
+        assert_eq!(
+            schedule.latest(minute_of_week(3, 23, 0), WEEK_MINUTES),
+            Some(false)
+        );
+        // Before the first entry of Sunday, Saturday night still holds
+        assert_eq!(
+            schedule.latest(minute_of_week(0, 1, 0), WEEK_MINUTES),
+            Some(false)
+        );
+        assert_eq!(Schedule::new().latest(0, WEEK_MINUTES), None);
+    }
+
+    #[test]
+    fn later_entries_win_a_tie() {
+        let schedule = schedule(&[entry(ALL_DAYS, 8, 0, true), entry(ALL_DAYS, 8, 0, false)]);
+        assert_eq!(schedule.latest(minute_of_week(2, 8, 0), 1), Some(false));
+    }
+
+    #[test]
+    fn rejects_invalid_tables() {
+        let mut schedule = Schedule::new();
+        assert_eq!(
+            schedule.replace(vec![entry(0, 7, 0, true)]),
+            Err(ScheduleError::InvalidEntry(entry(0, 7, 0, true)))
+        );
+        assert_eq!(
+            schedule.replace(vec![entry(ALL_DAYS, 24, 0, true)]),
+            Err(ScheduleError::InvalidEntry(entry(ALL_DAYS, 24, 0, true)))
+        );
+        assert_eq!(
+            schedule.replace(vec![entry(ALL_DAYS, 7, 0, true); MAX_ENTRIES + 1]),
+            Err(ScheduleError::TooManyEntries(MAX_ENTRIES + 1))
+        );
+        assert_eq!(schedule.revision(), 0);
+    }
+
+    #[test]
+    fn writes_are_checked_against_the_revision() {
+        let mut schedule = schedule(&[entry(ALL_DAYS, 7, 0, true)]);
+        let stale = schedule.encode();
+        schedule.add(entry(ALL_DAYS, 8, 0, false)).unwrap();
+
+        assert_eq!(
+            schedule.apply_write(&stale),
+            Err(ScheduleError::Revision {
+                stored: 2,
+                written: 1
+            })
+        );
+
+        // Without a revision the write replaces whatever is stored
+        let unversioned = [TYPE_VERSION, 1, FORMAT_VERSION, TYPE_ENTRY, 4, 1, 9, 0, 1];
+        assert_eq!(schedule.apply_write(&unversioned), Ok(()));
+        assert_eq!(schedule.entries(), &[entry(1, 9, 0, true)]);
+        assert_eq!(schedule.revision(), 3);
+    }
+
+    #[test]
+    fn rejects_malformed_tlv() {
+        let mut schedule = Schedule::new();
+        assert_eq!(schedule.apply_write(&[]), Err(ScheduleError::Malformed));
+        assert_eq!(
+            schedule.apply_write(&[TYPE_VERSION, 1, 2]),
+            Err(ScheduleError::Version(2))
+        );
+        assert_eq!(
+            schedule.apply_write(&[TYPE_VERSION, 1, 1, TYPE_ENTRY, 4, 1, 2]),
+            Err(ScheduleError::Malformed)
+        );
+        assert_eq!(
+            schedule.apply_write(&[TYPE_VERSION, 1, 1, TYPE_ENTRY, 4, 1, 2, 3, 7]),
+            Err(ScheduleError::Malformed)
+        );
+        // Unknown types are skipped
+        assert_eq!(schedule.apply_write(&[TYPE_VERSION, 1, 1, 9, 1, 0]), Ok(()));
+    }
+
+    #[test]
+    fn entries_print_readably() {
+        assert_eq!(
+            entry(WEEKDAYS, 7, 5, true).to_string(),
+            "Mo,Tu,We,Th,Fr 07:05 on"
+        );
+    }
+}
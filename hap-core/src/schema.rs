@@ -0,0 +1,388 @@
+//! The versioned layout of the `app_state` namespace, and the migrations
+//! from every older one run at boot.
+//!
+//! Version 1 is everything stored before the version key: the outlet's last
+//! state as `on`. Version 2 keeps the last state of every relay channel in
+//! the `relays` blob, a byte per channel. Version 3 stores the restore
+//! policy, rather than falling back to the compiled-in one, so a firmware
+//! built with another default leaves a device's policy alone.
+//!
+//! Every migration can run again over its own output, the firmware writes
+//! the version last and a power cut in between only repeats them.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::gesture::Mapping;
+use crate::guardrail::Limits;
+use crate::interlock::MAX_CHANNELS;
+use crate::rfid::Whitelist;
+
+/// The version this firmware writes.
+pub const VERSION: u8 = 3;
+
+pub const VERSION_KEY: &str = "schema_version";
+pub const RELAYS_KEY: &str = "relays";
+pub const POLICY_KEY: &str = "policy";
+
+/// The outlet's last state in version 1.
+const ON_KEY: &str = "on";
+// Restore policies are 0 to 2, see `RestorePolicy`
+const MAX_POLICY: u8 = 2;
+
+const FLAGS: &[&str] = &["dirty", "childlock", "mute", "pair_closed"];
+const COUNTERS: &[&str] = &["outages", "brownouts", "outage_at", "outage_secs", "beat"];
+type Check = fn(&[u8]) -> bool;
+
+/// Blobs of other modules with the check of their own format, a blob a
+/// module cannot load fails here rather than at its first use.
+const BLOBS: &[(&str, Check)] = &[
+    ("rfid_tags", |bytes| Whitelist::decode(bytes).is_ok()),
+    ("gestures", |bytes| Mapping::decode(bytes).is_some()),
+    ("guardrail", |bytes| Limits::decode(bytes).is_some()),
+];
+
+/// A stored value, of the NVS types the namespace uses.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Stored {
+    U8(u8),
+    U32(u32),
+    Blob(Vec<u8>),
+    Str(String),
+}
+
+/// The entries of the namespace by key.
+pub type Entries = BTreeMap<String, Stored>;
+
+/// What the migrations fill in that older versions did not store.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Defaults {
+    pub outlet_channel: u8,
+    pub policy: u8,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SchemaError {
+    WrongType(String),
+    BadValue(String),
+    Missing(&'static str),
+    /// A key of an older version left over
+    Leftover(&'static str),
+}
+
+impl fmt::Display for SchemaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SchemaError::WrongType(key) => write!(f, "'{}' is stored as another type", key),
+            SchemaError::BadValue(key) => write!(f, "'{}' holds an invalid value", key),
+            SchemaError::Missing(key) => write!(f, "'{}' is missing", key),
+            SchemaError::Leftover(key) => write!(f, "'{}' is left from an older version", key),
+        }
+    }
+}
+
+impl std::error::Error for SchemaError {}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    /// At this version and valid
+    Current,
+    /// The entries to store instead
+    Migrated { from: u8, entries: Entries },
+    /// Written by a newer firmware, left alone
+    Newer(u8),
+    /// Neither valid nor migratable
+    Failed { from: u8, error: SchemaError },
+}
+
+/// The stored version; a namespace without one, even an empty one, is at
+/// version 1.
+pub fn version(entries: &Entries) -> Result<u8, SchemaError> {
+    match entries.get(VERSION_KEY) {
+        None => Ok(1),
+        Some(Stored::U8(0)) => Err(SchemaError::BadValue(VERSION_KEY.into())),
+        Some(Stored::U8(version)) => Ok(*version),
+        Some(_) => Err(SchemaError::WrongType(VERSION_KEY.into())),
+    }
+}
+
+/// Brings `entries` to `VERSION` and checks the result.
+pub fn migrate(entries: &Entries, defaults: &Defaults) -> Outcome {
+    let from = match version(entries) {
+        Ok(version) => version,
+        Err(error) => return Outcome::Failed { from: 0, error },
+    };
+    if from > VERSION {
+        return Outcome::Newer(from);
+    }
+    if from == VERSION {
+        return match validate(entries) {
+            Ok(()) => Outcome::Current,
+            Err(error) => Outcome::Failed { from, error },
+        };
+    }
+
+    let mut migrated = entries.clone();
+    let steps = MIGRATIONS[from as usize - 1..]
+        .iter()
+        .try_for_each(|step| step(&mut migrated, defaults))
+        .and_then(|()| {
+            migrated.insert(VERSION_KEY.into(), Stored::U8(VERSION));
+            validate(&migrated)
+        });
+    match steps {
+        Ok(()) => Outcome::Migrated {
+            from,
+            entries: migrated,
+        },
+        Err(error) => Outcome::Failed { from, error },
+    }
+}
+
+type Migration = fn(&mut Entries, &Defaults) -> Result<(), SchemaError>;
+
+/// The step from version `i + 1` to `i + 2` at index `i`.
+const MIGRATIONS: [Migration; VERSION as usize - 1] = [relays_array, stored_policy];
+
+/// 1 to 2: the outlet's state moves into the array at its channel.
+fn relays_array(entries: &mut Entries, defaults: &Defaults) -> Result<(), SchemaError> {
+    let on = match entries.remove(ON_KEY) {
+        None => return Ok(()),
+        Some(Stored::U8(on @ (0 | 1))) => on == 1,
+        Some(Stored::U8(_)) => return Err(SchemaError::BadValue(ON_KEY.into())),
+        Some(_) => return Err(SchemaError::WrongType(ON_KEY.into())),
+    };
+    let mut relays = Vec::new();
+    set_relay_state(&mut relays, defaults.outlet_channel, on);
+    entries.insert(RELAYS_KEY.into(), Stored::Blob(relays));
+
+    Ok(())
+}
+
+/// 2 to 3: the policy a device ran with so far, the compiled-in one unless
+/// set, is stored.
+fn stored_policy(entries: &mut Entries, defaults: &Defaults) -> Result<(), SchemaError> {
+    entries
+        .entry(POLICY_KEY.into())
+        .or_insert(Stored::U8(defaults.policy));
+
+    Ok(())
+}
+
+/// Checks every key this version knows; others are left to their modules.
+pub fn validate(entries: &Entries) -> Result<(), SchemaError> {
+    if entries.contains_key(ON_KEY) {
+        return Err(SchemaError::Leftover(ON_KEY));
+    }
+    match entries.get(VERSION_KEY) {
+        Some(Stored::U8(VERSION)) => {}
+        Some(Stored::U8(_)) => return Err(SchemaError::BadValue(VERSION_KEY.into())),
+        Some(_) => return Err(SchemaError::WrongType(VERSION_KEY.into())),
+        None => return Err(SchemaError::Missing(VERSION_KEY)),
+    }
+    match entries.get(POLICY_KEY) {
+        Some(Stored::U8(policy)) if *policy <= MAX_POLICY => {}
+        Some(Stored::U8(_)) => return Err(SchemaError::BadValue(POLICY_KEY.into())),
+        Some(_) => return Err(SchemaError::WrongType(POLICY_KEY.into())),
+        None => return Err(SchemaError::Missing(POLICY_KEY)),
+    }
+
+    for (key, value) in entries {
+        let check = BLOBS
+            .iter()
+            .find(|(name, _)| name == key)
+            .map(|(_, check)| check);
+        let valid = match (key.as_str(), value, check) {
+            (RELAYS_KEY, Stored::Blob(relays), _) => {
+                relays.len() <= MAX_CHANNELS as usize && relays.iter().all(|&on| on <= 1)
+            }
+            (_, Stored::Blob(bytes), Some(check)) => check(bytes),
+            (name, Stored::U8(flag), _) if FLAGS.contains(&name) => *flag <= 1,
+            (name, Stored::U32(_), _) if COUNTERS.contains(&name) => true,
+            (name, _, check)
+                if name == RELAYS_KEY
+                    || check.is_some()
+                    || FLAGS.contains(&name)
+                    || COUNTERS.contains(&name) =>
+            {
+                return Err(SchemaError::WrongType(key.clone()))
+            }
+            _ => true,
+        };
+        if !valid {
+            return Err(SchemaError::BadValue(key.clone()));
+        }
+    }
+
+    Ok(())
+}
+
+/// The last state of `channel` in the `relays` blob, off if not stored.
+pub fn relay_state(relays: &[u8], channel: u8) -> bool {
+    relays.get(channel as usize) == Some(&1)
+}
+
+/// Sets the last state of `channel`, growing the blob up to it.
+pub fn set_relay_state(relays: &mut Vec<u8>, channel: u8, on: bool) {
+    let index = channel as usize;
+    if relays.len() <= index {
+        relays.resize(index + 1, 0);
+    }
+    relays[index] = on as u8;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEFAULTS: Defaults = Defaults {
+        outlet_channel: 2,
+        policy: 0,
+    };
+
+    // Captured from devices: two 4-byte tags, at most 30 min on within 1 h
+    // without quiet hours, and a double click switching off
+    const RFID_TAGS: &[u8] = &[1, 4, 0xa3, 0x1f, 0x72, 0x04, 4, 0x5c, 0x90, 0x2e, 0x11];
+    const GUARDRAIL: &[u8] = &[
+        1, 0x08, 0x07, 0, 0, 0x10, 0x0e, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    ];
+    const GESTURES: &[u8] = &[1, 3, 0];
+
+    fn entries(items: &[(&str, Stored)]) -> Entries {
+        items
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.clone()))
+            .collect()
+    }
+
+    fn v1() -> Entries {
+        entries(&[
+            ("on", Stored::U8(1)),
+            ("dirty", Stored::U8(1)),
+            ("outages", Stored::U32(3)),
+            ("beat", Stored::U32(1_760_000_000)),
+            ("rfid_tags", Stored::Blob(RFID_TAGS.to_vec())),
+            ("guardrail", Stored::Blob(GUARDRAIL.to_vec())),
+        ])
+    }
+
+    fn v2() -> Entries {
+        entries(&[
+            ("schema_version", Stored::U8(2)),
+            ("relays", Stored::Blob(vec![0, 1, 1])),
+            ("policy", Stored::U8(2)),
+            ("mute", Stored::U8(1)),
+            ("gestures", Stored::Blob(GESTURES.to_vec())),
+        ])
+    }
+
+    #[test]
+    fn migrates_the_single_outlet() {
+        let Outcome::Migrated { from, entries } = migrate(&v1(), &DEFAULTS) else {
+            panic!("not migrated");
+        };
+        assert_eq!(from, 1);
+        assert_eq!(entries.get("on"), None);
+        assert_eq!(entries["relays"], Stored::Blob(vec![0, 0, 1]));
+        assert_eq!(entries["policy"], Stored::U8(DEFAULTS.policy));
+        assert_eq!(entries["schema_version"], Stored::U8(VERSION));
+        assert_eq!(entries["rfid_tags"], Stored::Blob(RFID_TAGS.to_vec()));
+        assert_eq!(migrate(&entries, &DEFAULTS), Outcome::Current);
+
+        // A fresh namespace only gets the version and policy
+        let Outcome::Migrated { entries, .. } = migrate(&Entries::new(), &DEFAULTS) else {
+            panic!("not migrated");
+        };
+        assert_eq!(entries.len(), 2);
+
+        // Interrupted after the relays were written
+        let mut again = v1();
+        again.insert("relays".into(), Stored::Blob(vec![0, 0, 1]));
+        let Outcome::Migrated { entries, .. } = migrate(&again, &DEFAULTS) else {
+            panic!("not migrated");
+        };
+        assert_eq!(entries["relays"], Stored::Blob(vec![0, 0, 1]));
+    }
+
+    #[test]
+    fn keeps_a_set_policy() {
+        let Outcome::Migrated { from, entries } = migrate(&v2(), &DEFAULTS) else {
+            panic!("not migrated");
+        };
+        assert_eq!(from, 2);
+        assert_eq!(entries["policy"], Stored::U8(2));
+        assert_eq!(entries["relays"], Stored::Blob(vec![0, 1, 1]));
+
+        let mut unset = v2();
+        unset.remove("policy");
+        let Outcome::Migrated { entries, .. } = migrate(&unset, &DEFAULTS) else {
+            panic!("not migrated");
+        };
+        assert_eq!(entries["policy"], Stored::U8(DEFAULTS.policy));
+    }
+
+    #[test]
+    fn refuses_newer_and_broken_state() {
+        let mut newer = v2();
+        newer.insert("schema_version".into(), Stored::U8(VERSION + 1));
+        assert_eq!(migrate(&newer, &DEFAULTS), Outcome::Newer(VERSION + 1));
+
+        let mut v1 = v1();
+        v1.insert("on".into(), Stored::Blob(vec![1]));
+        assert_eq!(
+            migrate(&v1, &DEFAULTS),
+            Outcome::Failed {
+                from: 1,
+                error: SchemaError::WrongType("on".into())
+            }
+        );
+
+        let mut v2 = v2();
+        v2.insert("outages".into(), Stored::U8(3));
+        assert_eq!(
+            migrate(&v2, &DEFAULTS),
+            Outcome::Failed {
+                from: 2,
+                error: SchemaError::WrongType("outages".into())
+            }
+        );
+
+        let mut current = entries(&[
+            ("schema_version", Stored::U8(VERSION)),
+            ("policy", Stored::U8(1)),
+            ("relays", Stored::Blob(vec![2])),
+        ]);
+        assert_eq!(
+            migrate(&current, &DEFAULTS),
+            Outcome::Failed {
+                from: VERSION,
+                error: SchemaError::BadValue("relays".into())
+            }
+        );
+        // A guardrail blob cut short
+        current.insert("relays".into(), Stored::Blob(vec![1]));
+        current.insert("guardrail".into(), Stored::Blob(GUARDRAIL[..13].to_vec()));
+        assert_eq!(
+            migrate(&current, &DEFAULTS),
+            Outcome::Failed {
+                from: VERSION,
+                error: SchemaError::BadValue("guardrail".into())
+            }
+        );
+        assert_eq!(
+            version(&entries(&[("schema_version", Stored::U32(3))])),
+            Err(SchemaError::WrongType("schema_version".into()))
+        );
+    }
+
+    #[test]
+    fn stores_relay_states() {
+        let mut relays = Vec::new();
+        set_relay_state(&mut relays, 3, true);
+        assert_eq!(relays, vec![0, 0, 0, 1]);
+        set_relay_state(&mut relays, 0, true);
+        assert!(relay_state(&relays, 0));
+        assert!(!relay_state(&relays, 1));
+        assert!(!relay_state(&relays, 7));
+    }
+}
@@ -0,0 +1,298 @@
+//! The results of the boot-time self-test, per subsystem.
+
+use std::fmt::Write;
+
+/// HAP strings are limited to 64 characters unless told otherwise.
+pub const MAX_SUMMARY_LEN: usize = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Subsystem {
+    Relay,
+    Storage,
+    SoilProbe,
+    DoorSensor,
+}
+
+impl Subsystem {
+    pub fn name(self) -> &'static str {
+        match self {
+            Subsystem::Relay => "relay",
+            Subsystem::Storage => "nvs",
+            Subsystem::SoilProbe => "soil probe",
+            Subsystem::DoorSensor => "door sensor",
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Report {
+    /// In the order the checks ran; a subsystem checked again replaces its entry
+    results: Vec<(Subsystem, Result<(), String>)>,
+}
+
+impl Report {
+    pub const fn new() -> Self {
+        Self {
+            results: Vec::new(),
+        }
+    }
+
+    pub fn record(&mut self, subsystem: Subsystem, result: Result<(), String>) {
+        match self.results.iter_mut().find(|(s, _)| *s == subsystem) {
+            Some(entry) => entry.1 = result,
+            None => self.results.push((subsystem, result)),
+        }
+    }
+
+    pub fn results(&self) -> &[(Subsystem, Result<(), String>)] {
+        &self.results
+    }
+
+    pub fn failed(&self, subsystem: Subsystem) -> bool {
+        self.results
+            .iter()
+            .any(|(s, result)| *s == subsystem && result.is_err())
+    }
+
+    pub fn failures(&self) -> usize {
+        self.results
+            .iter()
+            .filter(|(_, result)| result.is_err())
+            .count()
+    }
+
+    /// One line for the diagnostics characteristic: the failures with their
+    /// reasons, cut to fit, or how many checks passed.
+    pub fn summary(&self) -> String {
+        if self.results.is_empty() {
+            return "not run".into();
+        }
+        if self.failures() == 0 {
+            return format!("passed ({} checks)", self.results.len());
+        }
+
+        let mut summary = String::new();
+        for (subsystem, result) in &self.results {
+            let Err(reason) = result else {
+                continue;
+            };
+            if !summary.is_empty() {
+                summary.push_str("; ");
+            }
+            let _ = write!(summary, "{}: {}", subsystem.name(), reason);
+        }
+
+        truncate(summary, MAX_SUMMARY_LEN)
+    }
+}
+
+/// Cuts `text` to at most `max` bytes on a character boundary, marking the cut.
+fn truncate(mut text: String, max: usize) -> String {
+    if text.len() <= max {
+        return text;
+    }
+
+    let mut end = max - '…'.len_utf8();
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    text.truncate(end);
+    text.push('…');
+
+    text
+}
+
+/// The rail every sample sits at, if they all do: an open or shorted input
+/// rather than a signal.
+pub fn stuck_at_rail(samples: &[u16], max: u16, margin: u16) -> Option<u16> {
+    let (&first, _) = samples.split_first()?;
+    let rail = if first <= margin {
+        0
+    } else if first >= max - margin {
+        max
+    } else {
+        return None;
+    };
+
+    samples
+        .iter()
+        .all(|&sample| sample.abs_diff(rail) <= margin)
+        .then_some(rail)
+}
+
+/// The checks of the smoke test, which runs the whole accessory on a
+/// flashed board, for hardware-in-the-loop CI.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Check {
+    /// The server started
+    Hap,
+    /// The accessory answers its own `_hap._tcp` query as advertised
+    Mdns,
+    /// The server refuses unverified requests
+    Http,
+    /// The relay follows the outlet, the characteristic and feedback too
+    Relay,
+    Sensors,
+    /// The relay and the pairing window are as they were
+    Restored,
+}
+
+impl Check {
+    pub fn name(self) -> &'static str {
+        match self {
+            Check::Hap => "hap",
+            Check::Mdns => "mdns",
+            Check::Http => "http",
+            Check::Relay => "relay",
+            Check::Sensors => "sensors",
+            Check::Restored => "restored",
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Smoke {
+    results: Vec<(Check, Result<(), String>)>,
+}
+
+impl Smoke {
+    pub const fn new() -> Self {
+        Self {
+            results: Vec::new(),
+        }
+    }
+
+    pub fn record(&mut self, check: Check, result: Result<(), String>) {
+        self.results.push((check, result));
+    }
+
+    pub fn results(&self) -> &[(Check, Result<(), String>)] {
+        &self.results
+    }
+
+    pub fn passed(&self) -> bool {
+        self.results.iter().all(|(_, result)| result.is_ok())
+    }
+
+    /// The one line CI looks for, every check with its result.
+    pub fn line(&self) -> String {
+        let mut line = String::from(if self.passed() {
+            "SMOKE PASS"
+        } else {
+            "SMOKE FAIL"
+        });
+        for (check, result) in &self.results {
+            let _ = match result {
+                Ok(()) => write!(line, " {}=ok", check.name()),
+                Err(reason) => write!(line, " {}=FAIL({})", check.name(), reason),
+            };
+        }
+
+        line
+    }
+}
+
+/// HAP answers requests of a connection without pair-verify with 470,
+/// whether paired or not; anything else means the accessory database is
+/// out in the open or the server is not HAP's.
+pub fn check_unverified_response(response: &[u8]) -> Result<(), String> {
+    let line = response.split(|&b| b == b'\n').next().unwrap_or_default();
+    let line = String::from_utf8_lossy(line);
+    let mut parts = line.trim_end().splitn(3, ' ');
+    let status = match (parts.next(), parts.next()) {
+        (Some(version), Some(status)) if version.starts_with("HTTP/1.") => status,
+        _ if response.is_empty() => return Err("no response".into()),
+        _ => return Err("not an HTTP response".into()),
+    };
+
+    match status.parse::<u16>() {
+        Ok(470) => Ok(()),
+        Ok(status) if (200..300).contains(&status) => {
+            Err(format!("answered {} without pair-verify", status))
+        }
+        Ok(status) => Err(format!("answered {}, not 470", status)),
+        Err(_) => Err(format!("bad status '{}'", status)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summarises_results() {
+        let mut report = Report::new();
+        assert_eq!(report.summary(), "not run");
+
+        report.record(Subsystem::Storage, Ok(()));
+        report.record(Subsystem::Relay, Ok(()));
+        assert_eq!(report.summary(), "passed (2 checks)");
+        assert!(!report.failed(Subsystem::Relay));
+
+        report.record(Subsystem::Relay, Err("GPIO5 reads low".into()));
+        report.record(Subsystem::SoilProbe, Err("stuck at 4095".into()));
+        assert!(report.failed(Subsystem::Relay));
+        assert!(!report.failed(Subsystem::Storage));
+        assert_eq!(report.failures(), 2);
+        assert_eq!(report.results().len(), 3);
+        assert_eq!(
+            report.summary(),
+            "relay: GPIO5 reads low; soil probe: stuck at 4095"
+        );
+    }
+
+    #[test]
+    fn fits_the_summary_into_a_hap_string() {
+        let mut report = Report::new();
+        report.record(Subsystem::Storage, Err("ä".repeat(40)));
+        let summary = report.summary();
+        assert!(summary.len() <= MAX_SUMMARY_LEN);
+        assert!(summary.starts_with("nvs: ää"));
+        assert!(summary.ends_with('…'));
+    }
+
+    #[test]
+    fn detects_stuck_inputs() {
+        assert_eq!(stuck_at_rail(&[0, 3, 1], 4095, 16), Some(0));
+        assert_eq!(stuck_at_rail(&[4095, 4090], 4095, 16), Some(4095));
+        assert_eq!(stuck_at_rail(&[0, 2000], 4095, 16), None);
+        assert_eq!(stuck_at_rail(&[1800, 1810], 4095, 16), None);
+        assert_eq!(stuck_at_rail(&[], 4095, 16), None);
+    }
+
+    #[test]
+    fn sums_up_the_smoke_test() {
+        let mut smoke = Smoke::new();
+        smoke.record(Check::Hap, Ok(()));
+        smoke.record(Check::Mdns, Ok(()));
+        assert!(smoke.passed());
+        assert_eq!(smoke.line(), "SMOKE PASS hap=ok mdns=ok");
+
+        smoke.record(Check::Relay, Err("feedback says off".into()));
+        smoke.record(Check::Restored, Ok(()));
+        assert!(!smoke.passed());
+        assert_eq!(
+            smoke.line(),
+            "SMOKE FAIL hap=ok mdns=ok relay=FAIL(feedback says off) restored=ok"
+        );
+    }
+
+    #[test]
+    fn wants_unverified_requests_refused() {
+        let refused = b"HTTP/1.1 470 Connection Authorization Required\r\n\r\n";
+        assert_eq!(check_unverified_response(refused), Ok(()));
+        assert_eq!(
+            check_unverified_response(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\n{}"),
+            Err("answered 200 without pair-verify".into())
+        );
+        assert_eq!(
+            check_unverified_response(b"HTTP/1.1 404 Not Found\r\n"),
+            Err("answered 404, not 470".into())
+        );
+        assert_eq!(check_unverified_response(b""), Err("no response".into()));
+        assert_eq!(
+            check_unverified_response(b"SSH-2.0-dropbear\r\n"),
+            Err("not an HTTP response".into())
+        );
+    }
+}
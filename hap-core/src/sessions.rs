@@ -0,0 +1,371 @@
+//! The controllers' HAP sessions: how many are open, the heap each one took,
+//! and which to shed once the heap runs low, so the next pair-verify finds
+//! room rather than failing.
+//!
+//! The SDK reports connects and disconnects by controller, not by socket; a
+//! session learns its socket from its first read or write, and only sessions
+//! with a known socket can be shed, by closing that socket. Heap is measured
+//! against the last sample before a connect or disconnect, whatever else
+//! allocated meanwhile counts along. Times are uptime seconds.
+
+use std::ffi::c_void;
+use std::sync::{Mutex, PoisonError};
+
+use crate::sys::{ControllerId, HapSys, HAP_SUCCESS};
+
+static REQUEST_HOOK: Mutex<Option<fn(*mut c_void)>> = Mutex::new(None);
+
+/// Has `hook` called for every read and write of a controller, with the
+/// request the SDK tells the controller from (`read_priv`, `write_priv`).
+pub fn on_request(hook: fn(*mut c_void)) {
+    *REQUEST_HOOK.lock().unwrap_or_else(PoisonError::into_inner) = Some(hook);
+}
+
+pub(crate) fn request(request: *mut c_void) {
+    let hook = *REQUEST_HOOK.lock().unwrap_or_else(PoisonError::into_inner);
+    if let (false, Some(hook)) = (request.is_null(), hook) {
+        hook(request);
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Session {
+    pub controller: ControllerId,
+    /// Unknown until the session's first read or write
+    pub socket: Option<i32>,
+    pub opened: u32,
+    pub last_active: u32,
+    /// Heap gone between the last sample and the connect
+    pub heap_bytes: u32,
+    /// Asked to close, not shed again
+    shedding: bool,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub open: u32,
+    pub opened: u32,
+    pub shed: u32,
+    /// Mean heap a session took at its connect
+    pub heap_per_session: u32,
+    /// Mean heap given back at a disconnect
+    pub heap_returned: u32,
+}
+
+pub struct Sessions {
+    sessions: Vec<Session>,
+    /// Free heap at the last sample, connect or disconnect
+    free: Option<u32>,
+    opened: u32,
+    closed: u32,
+    shed: u32,
+    heap_taken: u64,
+    heap_returned: u64,
+}
+
+impl Sessions {
+    pub const fn new() -> Self {
+        Self {
+            sessions: Vec::new(),
+            free: None,
+            opened: 0,
+            closed: 0,
+            shed: 0,
+            heap_taken: 0,
+            heap_returned: 0,
+        }
+    }
+
+    /// Notes the free heap, what the next session is measured against.
+    pub fn sample(&mut self, free: u32) {
+        self.free = Some(free);
+    }
+
+    pub fn connected(&mut self, controller: ControllerId, now: u32, free: u32) {
+        let heap_bytes = self.free.map_or(0, |before| before.saturating_sub(free));
+        self.sessions.push(Session {
+            controller,
+            socket: None,
+            opened: now,
+            last_active: now,
+            heap_bytes,
+            shedding: false,
+        });
+        self.opened += 1;
+        self.heap_taken += heap_bytes as u64;
+        self.free = Some(free);
+    }
+
+    /// Ends a session of the controller, one being shed or else its oldest.
+    pub fn disconnected(&mut self, controller: ControllerId, free: u32) -> Option<Session> {
+        let of_controller = |session: &Session| session.controller == controller;
+        let index = self
+            .sessions
+            .iter()
+            .position(|session| of_controller(session) && session.shedding)
+            .or_else(|| self.sessions.iter().position(of_controller))?;
+        let session = self.sessions.remove(index);
+
+        self.closed += 1;
+        if let Some(before) = self.free {
+            self.heap_returned += free.saturating_sub(before) as u64;
+        }
+        self.free = Some(free);
+
+        Some(session)
+    }
+
+    /// A read or write of the controller, on `socket` where known: the
+    /// session on it, or the controller's oldest without one yet, which
+    /// takes it. Without a socket, every session of the controller.
+    pub fn active(&mut self, controller: ControllerId, socket: Option<i32>, now: u32) {
+        let of_controller = |session: &Session| session.controller == controller;
+        let index = socket.and_then(|socket| {
+            self.sessions
+                .iter()
+                .position(|session| of_controller(session) && session.socket == Some(socket))
+                .or_else(|| {
+                    self.sessions
+                        .iter()
+                        .position(|session| of_controller(session) && session.socket.is_none())
+                })
+        });
+
+        match index {
+            Some(index) => {
+                let session = &mut self.sessions[index];
+                session.socket = socket;
+                session.last_active = now;
+            }
+            None => {
+                for session in self.sessions.iter_mut().filter(|s| of_controller(s)) {
+                    session.last_active = now;
+                }
+            }
+        }
+    }
+
+    /// With less than `floor` heap free, closes the socket of the session
+    /// idle the longest, for at least `min_idle` seconds, through `sys`; the
+    /// session and what closing it returned. The disconnect event ends it.
+    pub fn shed<S: HapSys>(
+        &mut self,
+        sys: &S,
+        free: u32,
+        floor: u32,
+        min_idle: u32,
+        now: u32,
+    ) -> Option<(Session, i32)> {
+        if free >= floor {
+            return None;
+        }
+        let idlest = self
+            .sessions
+            .iter_mut()
+            .filter(|session| !session.shedding && session.socket.is_some())
+            .filter(|session| now.saturating_sub(session.last_active) >= min_idle)
+            .min_by_key(|session| session.last_active)?;
+
+        let err = sys.close_session(idlest.socket?);
+        if err == HAP_SUCCESS {
+            idlest.shedding = true;
+            self.shed += 1;
+        }
+
+        Some((*idlest, err))
+    }
+
+    pub fn sessions(&self) -> &[Session] {
+        &self.sessions
+    }
+
+    pub fn stats(&self) -> Stats {
+        let mean = |total: u64, count: u32| (total / count.max(1) as u64) as u32;
+        Stats {
+            open: self.sessions.len() as u32,
+            opened: self.opened,
+            shed: self.shed,
+            heap_per_session: mean(self.heap_taken, self.opened),
+            heap_returned: mean(self.heap_returned, self.closed),
+        }
+    }
+}
+
+impl Default for Sessions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use super::*;
+    use crate::mock::{Call, MockSys};
+    use crate::sys::Event;
+    use crate::Hap;
+
+    fn closed(hap: &Hap<MockSys>) -> Vec<i32> {
+        hap.sys()
+            .calls()
+            .into_iter()
+            .filter_map(|call| match call {
+                Call::CloseSession(socket) => Some(socket),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn sheds_the_idlest_session() {
+        let hap = Hap::new(MockSys::new());
+        let (phone, pod, tv) = (
+            ControllerId::new("phone"),
+            ControllerId::new("pod"),
+            ControllerId::new("tv"),
+        );
+        let mut sessions = Sessions::new();
+        sessions.sample(60_000);
+        sessions.connected(phone, 0, 50_000);
+        sessions.connected(pod, 10, 40_000);
+        sessions.connected(pod, 15, 35_000);
+        sessions.connected(tv, 20, 30_000);
+        sessions.active(phone, Some(54), 100);
+        sessions.active(pod, Some(55), 20);
+        sessions.active(pod, Some(56), 25);
+
+        // Heap enough, or no session idle long enough
+        assert_eq!(sessions.shed(hap.sys(), 40_000, 32_000, 60, 100), None);
+        assert_eq!(sessions.shed(hap.sys(), 30_000, 32_000, 90, 100), None);
+
+        // The tv's socket is unknown, so the pod's older session goes
+        let (shed, err) = sessions.shed(hap.sys(), 30_000, 32_000, 60, 100).unwrap();
+        assert_eq!(
+            (shed.controller, shed.socket, err),
+            (pod, Some(55), HAP_SUCCESS)
+        );
+        assert_eq!(shed.heap_bytes, 10_000);
+        // Not again while it closes
+        let (shed, _) = sessions.shed(hap.sys(), 30_000, 32_000, 60, 100).unwrap();
+        assert_eq!(shed.socket, Some(56));
+        assert_eq!(sessions.shed(hap.sys(), 30_000, 32_000, 60, 100), None);
+        assert_eq!(closed(&hap), [55, 56]);
+
+        // The session being shed ends first
+        assert_eq!(sessions.disconnected(pod, 35_000).unwrap().socket, Some(55));
+        assert_eq!(sessions.disconnected(pod, 40_000).unwrap().socket, Some(56));
+        assert_eq!(sessions.disconnected(pod, 40_000), None);
+        let stats = sessions.stats();
+        assert_eq!((stats.open, stats.opened, stats.shed), (2, 4, 2));
+        assert_eq!(stats.heap_per_session, 7_500);
+        assert_eq!(stats.heap_returned, 5_000);
+    }
+
+    #[test]
+    fn keeps_a_socket_to_its_session() {
+        let phone = ControllerId::new("phone");
+        let mut sessions = Sessions::new();
+        sessions.connected(phone, 0, 0);
+        sessions.connected(phone, 5, 0);
+        sessions.active(phone, Some(60), 10);
+        sessions.active(phone, Some(61), 20);
+        sessions.active(phone, Some(60), 30);
+        // Unknown socket of a controller, it stays with the sessions it has
+        sessions.active(phone, Some(62), 40);
+
+        let seen: Vec<_> = sessions
+            .sessions()
+            .iter()
+            .map(|session| (session.socket, session.last_active))
+            .collect();
+        assert_eq!(seen, [(Some(60), 40), (Some(61), 40)]);
+    }
+
+    static TRACKED: Mutex<Sessions> = Mutex::new(Sessions::new());
+    static FREE: AtomicU32 = AtomicU32::new(0);
+    static NOW: AtomicU32 = AtomicU32::new(0);
+
+    fn track(event: Event) {
+        let free = FREE.load(Ordering::Relaxed);
+        let mut sessions = TRACKED.lock().unwrap();
+        match event {
+            Event::ControllerConnected(controller) => {
+                sessions.connected(controller, NOW.load(Ordering::Relaxed), free)
+            }
+            Event::ControllerDisconnected(controller) => {
+                sessions.disconnected(controller, free);
+            }
+            _ => {}
+        }
+    }
+
+    #[test]
+    fn connect_loops_stay_above_the_floor() {
+        const SESSION: u32 = 12_000;
+        const FLOOR: u32 = 40_000;
+        let hap = Hap::new(MockSys::new());
+        hap.sys().on_event(track);
+        let controllers: Vec<_> = (0..6)
+            .map(|i| ControllerId::new(&format!("controller-{}", i)))
+            .collect();
+        FREE.store(100_000, Ordering::Relaxed);
+        TRACKED.lock().unwrap().sample(100_000);
+
+        // The server's side, the sockets open with their controller, oldest
+        // first
+        let mut open: Vec<(i32, ControllerId)> = Vec::new();
+        let mut handled = 0;
+        let disconnect = |open: &mut Vec<(i32, ControllerId)>, index: usize| {
+            let (_, controller) = open.remove(index);
+            FREE.fetch_add(SESSION, Ordering::Relaxed);
+            hap.sys().deliver(Event::ControllerDisconnected(controller));
+        };
+        for round in 0..1000u32 {
+            let now = round * 10;
+            NOW.store(now, Ordering::Relaxed);
+            let controller = controllers[round as usize % controllers.len()];
+            let socket = 54 + round as i32;
+            FREE.fetch_sub(SESSION, Ordering::Relaxed);
+            hap.sys().deliver(Event::ControllerConnected(controller));
+            open.push((socket, controller));
+            TRACKED
+                .lock()
+                .unwrap()
+                .active(controller, Some(socket), now);
+
+            // The firmware's policy after a connect
+            let free = FREE.load(Ordering::Relaxed);
+            TRACKED
+                .lock()
+                .unwrap()
+                .shed(hap.sys(), free, FLOOR, 30, now);
+            let requested = closed(&hap);
+            for &socket in &requested[handled..] {
+                let index = open.iter().position(|&(s, _)| s == socket).unwrap();
+                disconnect(&mut open, index);
+            }
+            handled = requested.len();
+            assert!(FREE.load(Ordering::Relaxed) >= FLOOR);
+
+            // Every third controller leaves on its own, from its oldest
+            // session as the tracker takes it
+            if round % 3 == 0 {
+                let index = open.iter().position(|&(_, c)| c == controller).unwrap();
+                disconnect(&mut open, index);
+            }
+        }
+        while !open.is_empty() {
+            disconnect(&mut open, 0);
+        }
+
+        let stats = TRACKED.lock().unwrap().stats();
+        assert_eq!(stats.open, 0);
+        assert_eq!(stats.opened, 1000);
+        assert_eq!(stats.heap_per_session, SESSION);
+        assert_eq!(stats.heap_returned, SESSION);
+        assert_eq!(FREE.load(Ordering::Relaxed), 100_000);
+        assert_eq!(handled as u32, stats.shed);
+        assert!(stats.shed > 0);
+    }
+}
@@ -0,0 +1,94 @@
+use std::error::Error;
+use std::fmt;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SetupError {
+    Code(String),
+    Id(String),
+}
+
+impl fmt::Display for SetupError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SetupError::Code(code) => {
+                write!(f, "setup code '{}' is not of the form XXX-XX-XXX", code)
+            }
+            SetupError::Id(id) => write!(f, "setup id '{}' must be 4 alphanumeric characters", id),
+        }
+    }
+}
+
+impl Error for SetupError {}
+
+/// Checks the pairing code and setup id before they reach the SDK, which
+/// would only reject them when a controller tries to pair.
+pub fn validate(code: &str, id: &str) -> Result<(), SetupError> {
+    let digits = code.bytes().filter(u8::is_ascii_digit).count();
+    let shape = code.len() == 10 && code.as_bytes()[3] == b'-' && code.as_bytes()[6] == b'-';
+    if digits != 8 || !shape {
+        return Err(SetupError::Code(code.into()));
+    }
+
+    validate_id(id)
+}
+
+/// Checks the setup id alone, for setups provisioned as a verifier.
+pub fn validate_id(id: &str) -> Result<(), SetupError> {
+    if id.len() != 4 || !id.bytes().all(|b| b.is_ascii_alphanumeric()) {
+        return Err(SetupError::Id(id.into()));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_the_default_setup() {
+        assert_eq!(validate("111-22-333", "ES32"), Ok(()));
+    }
+
+    #[test]
+    fn rejects_malformed_codes() {
+        for code in [
+            "11122333",
+            "111-22-33",
+            "111-22-3334",
+            "1112-2-333",
+            "abc-de-fgh",
+            "",
+        ] {
+            assert_eq!(
+                validate(code, "ES32"),
+                Err(SetupError::Code(code.into())),
+                "{}",
+                code
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_ids() {
+        for id in ["ES3", "ES321", "ES-2", ""] {
+            assert_eq!(
+                validate("111-22-333", id),
+                Err(SetupError::Id(id.into())),
+                "{}",
+                id
+            );
+            assert_eq!(validate_id(id), Err(SetupError::Id(id.into())));
+        }
+        assert_eq!(validate_id("ES32"), Ok(()));
+    }
+
+    #[test]
+    fn names_the_offending_value() {
+        let err = validate("1-2-3", "ES32").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "setup code '1-2-3' is not of the form XXX-XX-XXX"
+        );
+    }
+}
@@ -0,0 +1,135 @@
+//! Capacitive soil moisture probes on a 12-bit ADC, and when the irrigation
+//! automation opens the valve.
+
+/// Full scale of the ADC.
+pub const ADC_MAX: u16 = 4095;
+
+/// Readings this close to either rail come from a disconnected or shorted
+/// probe rather than from soil.
+pub const RAIL_MARGIN: u16 = 16;
+
+/// The raw readings of the probe in dry air and in water. Capacitive probes
+/// read lower the wetter they are, either order works.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Calibration {
+    pub dry: u16,
+    pub wet: u16,
+}
+
+impl Calibration {
+    /// The common v1.2 probes on 3.3 V at 11 dB attenuation.
+    pub const DEFAULT: Self = Self {
+        dry: 2800,
+        wet: 1200,
+    };
+
+    /// Moisture in percent, clamped to 0-100.
+    pub fn percent(&self, raw: u16) -> f32 {
+        if self.dry == self.wet {
+            return 0.0;
+        }
+
+        let span = self.wet as f32 - self.dry as f32;
+        ((raw as f32 - self.dry as f32) / span * 100.0).clamp(0.0, 100.0)
+    }
+}
+
+pub fn at_rail(raw: u16) -> bool {
+    raw <= RAIL_MARGIN || raw >= ADC_MAX - RAIL_MARGIN
+}
+
+/// Waters when the soil is drier than the threshold. Every opening of the
+/// valve, by the automation or not, starts a lockout, so a probe failing dry
+/// waters once per lockout at most.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Automation {
+    pub enabled: bool,
+    /// Moisture in percent
+    pub threshold: u8,
+    lockout_secs: u32,
+    last_opened: Option<u32>,
+}
+
+impl Automation {
+    pub const fn new(lockout_secs: u32) -> Self {
+        Self {
+            enabled: false,
+            threshold: 30,
+            lockout_secs,
+            last_opened: None,
+        }
+    }
+
+    /// The valve opened at `now`, seconds on any monotonic clock.
+    pub fn opened(&mut self, now: u32) {
+        self.last_opened = Some(now);
+    }
+
+    /// When the lockout ends, if one is running at `now`.
+    pub fn locked_until(&self, now: u32) -> Option<u32> {
+        let until = self.last_opened?.saturating_add(self.lockout_secs);
+        (now < until).then_some(until)
+    }
+
+    /// Whether to open the closed valve for `moisture` at `now`.
+    pub fn due(&self, now: u32, moisture: f32, open: bool) -> bool {
+        self.enabled
+            && !open
+            && moisture < self.threshold as f32
+            && self.locked_until(now).is_none()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_the_calibration_to_percent() {
+        let calibration = Calibration::DEFAULT;
+        assert_eq!(calibration.percent(2800), 0.0);
+        assert_eq!(calibration.percent(1200), 100.0);
+        assert_eq!(calibration.percent(2000), 50.0);
+        // Beyond the calibration points
+        assert_eq!(calibration.percent(3500), 0.0);
+        assert_eq!(calibration.percent(900), 100.0);
+
+        let rising = Calibration {
+            dry: 1000,
+            wet: 3000,
+        };
+        assert_eq!(rising.percent(1500), 25.0);
+        assert_eq!(Calibration { dry: 5, wet: 5 }.percent(5), 0.0);
+    }
+
+    #[test]
+    fn detects_readings_at_the_rails() {
+        assert!(at_rail(0));
+        assert!(at_rail(ADC_MAX));
+        assert!(at_rail(ADC_MAX - 3));
+        assert!(!at_rail(2000));
+    }
+
+    #[test]
+    fn waters_dry_soil_when_enabled() {
+        let mut automation = Automation::new(3600);
+        assert!(!automation.due(0, 10.0, false));
+
+        automation.enabled = true;
+        assert!(automation.due(0, 10.0, false));
+        assert!(!automation.due(0, 10.0, true));
+        assert!(!automation.due(0, 30.0, false));
+    }
+
+    #[test]
+    fn locks_out_after_every_opening() {
+        let mut automation = Automation::new(3600);
+        automation.enabled = true;
+        automation.opened(100);
+
+        assert_eq!(automation.locked_until(100), Some(3700));
+        assert!(!automation.due(3699, 0.0, false));
+        assert!(automation.due(3700, 0.0, false));
+        assert_eq!(automation.locked_until(3700), None);
+    }
+}
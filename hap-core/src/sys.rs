@@ -0,0 +1,209 @@
+use std::ffi::{c_void, CStr};
+use std::fmt;
+
+use crate::value::{Format, RawBuf, RawVal, Value};
+
+macro_rules! handle {
+    ($(#[$doc:meta])* $name:ident) => {
+        $(#[$doc])*
+        #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+        pub struct $name(pub usize);
+
+        impl $name {
+            pub fn from_ptr<T>(ptr: *mut T) -> Option<Self> {
+                (!ptr.is_null()).then(|| Self(ptr as usize))
+            }
+
+            pub fn as_ptr<T>(self) -> *mut T {
+                self.0 as *mut T
+            }
+        }
+    };
+}
+
+handle!(
+    /// An accessory in the SDK's database
+    Acc
+);
+handle!(
+    /// A service in the SDK's database
+    Serv
+);
+handle!(
+    /// A characteristic in the SDK's database
+    Char
+);
+
+/// Mirror of the SDK's `hap_write_data_t`, one entry of a write batch.
+#[repr(C)]
+pub struct RawWrite {
+    pub hc: *mut c_void,
+    pub val: RawVal,
+    pub auth_data: RawBuf,
+    pub remote: bool,
+    pub status: *mut i32,
+}
+
+pub type WriteCb = unsafe extern "C" fn(*mut RawWrite, i32, *mut c_void, *mut c_void) -> i32;
+pub type ReadCb = unsafe extern "C" fn(*mut c_void, *mut i32, *mut c_void, *mut c_void) -> i32;
+pub type IdentifyCb = unsafe extern "C" fn(*mut c_void) -> i32;
+
+pub const HAP_SUCCESS: i32 = 0;
+pub const HAP_FAIL: i32 = -1;
+
+pub struct AccessoryInfo<'a> {
+    pub name: &'a CStr,
+    pub model: &'a CStr,
+    pub manufacturer: &'a CStr,
+    pub serial_num: &'a CStr,
+    pub fw_rev: &'a CStr,
+    pub hw_rev: &'a CStr,
+    /// HAP protocol version
+    pub pv: &'a CStr,
+    /// Accessory category, `hap_cid_t`
+    pub cid: u32,
+    pub identify: IdentifyCb,
+}
+
+/// A controller's pairing identifier, which the SDK passes along with
+/// connects and disconnects; longer ones are cut to `ControllerId::LEN`.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct ControllerId {
+    len: u8,
+    bytes: [u8; ControllerId::LEN],
+}
+
+impl ControllerId {
+    /// The SDK's `HAP_CTRL_ID_LEN`
+    pub const LEN: usize = 64;
+
+    pub fn new(id: &str) -> Self {
+        let mut len = id.len().min(Self::LEN);
+        while !id.is_char_boundary(len) {
+            len -= 1;
+        }
+        let mut bytes = [0; Self::LEN];
+        bytes[..len].copy_from_slice(&id.as_bytes()[..len]);
+
+        Self {
+            len: len as u8,
+            bytes,
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        std::str::from_utf8(&self.bytes[..self.len as usize]).unwrap_or("")
+    }
+}
+
+impl fmt::Debug for ControllerId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+impl fmt::Display for ControllerId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Event {
+    PairingStarted,
+    PairingAborted,
+    ControllerPaired,
+    ControllerUnpaired,
+    /// A session opened, after pair-verify
+    ControllerConnected(ControllerId),
+    ControllerDisconnected(ControllerId),
+    Other(i32),
+}
+
+/// The part of the esp-homekit-sdk the firmware calls. UUIDs are
+/// NUL-terminated and `'static`, the SDK keeps the pointers.
+pub trait HapSys: Sync {
+    fn init(&self) -> i32;
+    fn start(&self) -> i32;
+    fn set_setup(&self, code: &CStr, id: &CStr);
+    /// Sets up pairing from an SRP salt and verifier instead of the code.
+    fn set_setup_info(&self, salt: &[u8; 16], verifier: &[u8; 384], id: &CStr);
+    fn paired_controller_count(&self) -> i32;
+    fn reset_to_factory(&self) -> i32;
+    /// Erases the Wi-Fi configuration and restarts, from the SDK's task.
+    fn reset_network(&self) -> i32;
+    /// Erases the controller pairings and restarts, from the SDK's task.
+    fn reset_pairings(&self) -> i32;
+    /// Calls `handler` from the event loop for every HAP event.
+    fn on_event(&self, handler: fn(Event)) -> i32;
+    /// Closes the HAP session on `socket` from the server's own task; the
+    /// controller's disconnect event follows.
+    fn close_session(&self, socket: i32) -> i32;
+
+    fn acc_create(&self, info: &AccessoryInfo) -> Option<Acc>;
+    fn acc_add_serv(&self, acc: Acc, serv: Serv) -> i32;
+    fn add_accessory(&self, acc: Acc);
+    fn add_bridged_accessory(&self, acc: Acc, aid: i32) -> i32;
+    /// The accessory id kept for `id` across restarts, for bridged accessories.
+    fn unique_aid(&self, id: &CStr) -> i32;
+    /// Tells controllers the database changed, so they fetch it again.
+    fn update_config_number(&self);
+
+    fn serv_create(&self, uuid: &'static [u8]) -> Option<Serv>;
+    fn serv_outlet_create(&self, on: bool, in_use: bool) -> Option<Serv>;
+    fn serv_switch_create(&self, on: bool) -> Option<Serv>;
+    fn serv_add_char(&self, serv: Serv, hc: Char) -> i32;
+    fn serv_char_by_uuid(&self, serv: Serv, uuid: &'static [u8]) -> Option<Char>;
+    fn serv_set_priv(&self, serv: Serv, priv_data: *mut c_void);
+    fn serv_set_write_cb(&self, serv: Serv, cb: WriteCb);
+    fn serv_set_read_cb(&self, serv: Serv, cb: ReadCb);
+    fn serv_mark_primary(&self, serv: Serv);
+    /// Kept out of the controllers' user interface.
+    fn serv_mark_hidden(&self, serv: Serv);
+    /// Assigned when the service is added to an accessory, like the iids
+    /// of its characteristics, and replaceable until HAP starts.
+    fn serv_get_iid(&self, serv: Serv) -> i32;
+    fn serv_set_iid(&self, serv: Serv, iid: i32);
+
+    fn char_create(&self, uuid: &'static [u8], perms: u16, value: &Value) -> Option<Char>;
+    fn char_name_create(&self, name: &CStr) -> Option<Char>;
+    fn char_status_fault_create(&self, fault: u8) -> Option<Char>;
+    fn char_type_uuid(&self, hc: Char) -> Option<String>;
+    fn char_update_val(&self, hc: Char, val: &RawVal) -> i32;
+    /// The value the SDK holds, what controllers read; `format` is the one
+    /// the characteristic was created with.
+    fn char_get_val(&self, hc: Char, format: Format) -> Option<Value>;
+    fn char_get_iid(&self, hc: Char) -> i32;
+    fn char_set_iid(&self, hc: Char, iid: i32);
+    /// Advertised to controllers; for every integer format alike.
+    fn char_int_set_constraints(&self, hc: Char, min: i32, max: i32, step: i32);
+    fn char_float_set_constraints(&self, hc: Char, min: f32, max: f32, step: f32);
+    /// The SDK keeps the pointer to `values`.
+    fn char_add_valid_vals(&self, hc: Char, values: &'static [u8]);
+}
+
+/// Characteristic permissions, the SDK's `HAP_CHAR_PERM_*`. The firmware
+/// asserts that they match.
+pub mod perm {
+    /// Paired read
+    pub const PR: u16 = 1 << 0;
+    /// Paired write
+    pub const PW: u16 = 1 << 1;
+    /// Events
+    pub const EV: u16 = 1 << 2;
+}
+
+/// Apple's short UUIDs of the characteristics the firmware looks up.
+pub mod uuid {
+    pub const NAME: &[u8] = b"23\0";
+    pub const ON: &[u8] = b"25\0";
+    pub const OUTLET_IN_USE: &[u8] = b"26\0";
+    pub const STATUS_FAULT: &[u8] = b"77\0";
+    pub const STATUS_TAMPERED: &[u8] = b"7A\0";
+
+    /// Compares a UUID the SDK reports with one of these, or any other
+    /// NUL-terminated UUID.
+    pub fn eq(reported: &str, uuid: &[u8]) -> bool {
+        reported.as_bytes() == uuid.strip_suffix(b"\0").unwrap_or(uuid)
+    }
+}
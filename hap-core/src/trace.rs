@@ -0,0 +1,371 @@
+//! An opt-in trace of the HAP traffic: what the controllers read and write,
+//! with the status they got, the values pushed to them and the pairing and
+//! session events. Off by default, when the traced paths pay one atomic
+//! load for it.
+//!
+//! Records go to the sink the firmware sets, which knows the time and the
+//! characteristic types; the lines it renders may be kept in a `Ring`.
+
+use std::collections::VecDeque;
+use std::ffi::c_void;
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, PoisonError};
+
+use crate::sys::{Char, ControllerId, Event};
+use crate::value::Value;
+use crate::write::Status;
+
+/// Bytes of a string, TLV8 or data value shown, the rest is only counted.
+pub const VALUE_BYTES: usize = 32;
+
+/// Tells the controller from the request the SDK passes along with a read or
+/// write (`read_priv`, `write_priv`).
+pub type Resolver = fn(*mut c_void) -> Option<ControllerId>;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static SINK: Mutex<Option<fn(&Record)>> = Mutex::new(None);
+static RESOLVER: Mutex<Option<Resolver>> = Mutex::new(None);
+
+#[inline]
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+pub fn set_sink(sink: fn(&Record)) {
+    *SINK.lock().unwrap_or_else(PoisonError::into_inner) = Some(sink);
+}
+
+/// Has the reads and writes traced with their controller.
+pub fn on_controller(resolver: Resolver) {
+    *RESOLVER.lock().unwrap_or_else(PoisonError::into_inner) = Some(resolver);
+}
+
+pub(crate) fn controller(request: *mut c_void) -> Option<ControllerId> {
+    if !enabled() || request.is_null() {
+        return None;
+    }
+    let resolver = *RESOLVER.lock().unwrap_or_else(PoisonError::into_inner);
+
+    resolver.and_then(|resolver| resolver(request))
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Kind {
+    Read {
+        hc: Char,
+        status: Status,
+    },
+    /// `hc` and `value` are missing where the entry had none the accessory
+    /// knows or could decode
+    Write {
+        hc: Option<Char>,
+        value: Option<Value>,
+        status: Status,
+    },
+    Notify {
+        hc: Char,
+        value: Value,
+    },
+    Event(Event),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Record {
+    pub controller: Option<ControllerId>,
+    pub kind: Kind,
+}
+
+impl Record {
+    pub fn hc(&self) -> Option<Char> {
+        match self.kind {
+            Kind::Read { hc, .. } | Kind::Notify { hc, .. } => Some(hc),
+            Kind::Write { hc, .. } => hc,
+            Kind::Event(_) => None,
+        }
+    }
+
+    /// One line without the time, `uuid` being the characteristic's type.
+    pub fn render(&self, uuid: Option<&str>) -> String {
+        let controller = self.controller.as_ref().map_or("-", |c| c.as_str());
+        let uuid = uuid.unwrap_or("?");
+        match &self.kind {
+            Kind::Read { status, .. } => format!("{} read {} -> {:?}", controller, uuid, status),
+            Kind::Write { value, status, .. } => format!(
+                "{} write {} = {} -> {:?}",
+                controller,
+                uuid,
+                value.as_ref().map_or("?".into(), format_value),
+                status
+            ),
+            Kind::Notify { value, .. } => {
+                format!("{} notify {} = {}", controller, uuid, format_value(value))
+            }
+            Kind::Event(event) => format!("{} event {:?}", controller, event),
+        }
+    }
+}
+
+/// Hands the record to the sink; callers check `enabled()` first, so the
+/// record is not even built while tracing is off.
+pub fn record(controller: Option<ControllerId>, kind: Kind) {
+    let sink = *SINK.lock().unwrap_or_else(PoisonError::into_inner);
+    if let Some(sink) = sink {
+        sink(&Record { controller, kind });
+    }
+}
+
+/// Records an event, of the controller it names if any.
+pub fn event(event: Event) {
+    if !enabled() {
+        return;
+    }
+    let controller = match event {
+        Event::ControllerConnected(id) | Event::ControllerDisconnected(id) => Some(id),
+        _ => None,
+    };
+    record(controller, Kind::Event(event));
+}
+
+/// Numbers as they are, strings quoted and TLV8 and data in hex, the long
+/// ones cut at `VALUE_BYTES` with their length.
+pub fn format_value(value: &Value) -> String {
+    match value {
+        Value::Bool(b) => b.to_string(),
+        Value::Uint8(u) => u.to_string(),
+        Value::Uint16(u) => u.to_string(),
+        Value::Uint32(u) => u.to_string(),
+        Value::Uint64(u) => u.to_string(),
+        Value::Int(i) => i.to_string(),
+        Value::Float(f) => f.to_string(),
+        Value::String(s) if s.len() <= VALUE_BYTES => format!("{:?}", s),
+        Value::String(s) => {
+            let mut end = VALUE_BYTES;
+            while !s.is_char_boundary(end) {
+                end -= 1;
+            }
+            format!("{:?}... ({} B)", &s[..end], s.len())
+        }
+        Value::Tlv8(bytes) => format!("tlv8 {}", hex(bytes)),
+        Value::Data(bytes) => format!("data {}", hex(bytes)),
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(3 * VALUE_BYTES.min(bytes.len()) + 12);
+    for (i, byte) in bytes.iter().take(VALUE_BYTES).enumerate() {
+        if i > 0 {
+            out.push(' ');
+        }
+        let _ = write!(out, "{:02x}", byte);
+    }
+    if bytes.len() > VALUE_BYTES {
+        let _ = write!(out, " ... ({} B)", bytes.len());
+    }
+    if bytes.is_empty() {
+        out.push_str("(empty)");
+    }
+
+    out
+}
+
+/// The last lines of the trace, for the console to show after the fact.
+pub struct Ring {
+    lines: VecDeque<String>,
+    capacity: usize,
+    dropped: u32,
+}
+
+impl Ring {
+    /// Keeps nothing until given a capacity.
+    pub const fn new() -> Self {
+        Self {
+            lines: VecDeque::new(),
+            capacity: 0,
+            dropped: 0,
+        }
+    }
+
+    /// 0 stops keeping lines and frees the kept ones.
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        while self.lines.len() > capacity {
+            self.lines.pop_front();
+            self.dropped += 1;
+        }
+        if capacity == 0 {
+            self.lines = VecDeque::new();
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn push(&mut self, line: String) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.lines.len() == self.capacity {
+            self.lines.pop_front();
+            self.dropped += 1;
+        }
+        self.lines.push_back(line);
+    }
+
+    /// The oldest first.
+    pub fn lines(&self) -> impl Iterator<Item = &str> {
+        self.lines.iter().map(String::as_str)
+    }
+
+    /// Lines pushed out by newer ones since the last clear.
+    pub fn dropped(&self) -> u32 {
+        self.dropped
+    }
+
+    pub fn clear(&mut self) {
+        self.lines.clear();
+        self.dropped = 0;
+    }
+}
+
+impl Default for Ring {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockSys;
+    use crate::sys::{perm, uuid, HapSys};
+    use crate::value::Format;
+    use crate::write::Write;
+    use crate::Hap;
+
+    #[test]
+    fn cuts_long_values() {
+        assert_eq!(format_value(&Value::Float(21.5)), "21.5");
+        assert_eq!(format_value(&Value::String("Hall".into())), "\"Hall\"");
+
+        // Not in the middle of a character
+        let long = format!("{}é{}", "a".repeat(VALUE_BYTES - 1), "b".repeat(10));
+        assert_eq!(
+            format_value(&Value::String(long)),
+            format!("\"{}\"... (43 B)", "a".repeat(VALUE_BYTES - 1))
+        );
+
+        assert_eq!(
+            format_value(&Value::Tlv8(vec![0x01, 0x02, 0xab])),
+            "tlv8 01 02 ab"
+        );
+        let data = format_value(&Value::Data(vec![0xff; 100]));
+        assert!(data.starts_with("data ff ff"));
+        assert!(data.ends_with("ff ... (100 B)"));
+        assert_eq!(data.matches("ff").count(), VALUE_BYTES);
+        assert_eq!(format_value(&Value::Data(Vec::new())), "data (empty)");
+    }
+
+    #[test]
+    fn keeps_the_last_lines() {
+        let mut ring = Ring::new();
+        ring.push("lost".into());
+        ring.set_capacity(2);
+        for line in ["a", "b", "c"] {
+            ring.push(line.into());
+        }
+        assert_eq!(ring.lines().collect::<Vec<_>>(), ["b", "c"]);
+        assert_eq!(ring.dropped(), 1);
+
+        ring.set_capacity(1);
+        assert_eq!(ring.lines().collect::<Vec<_>>(), ["c"]);
+        ring.clear();
+        assert_eq!((ring.lines().count(), ring.dropped()), (0, 0));
+    }
+
+    static RECORDS: Mutex<Vec<Record>> = Mutex::new(Vec::new());
+
+    fn collect(record: &Record) {
+        RECORDS.lock().unwrap().push(record.clone());
+    }
+
+    #[test]
+    fn traces_writes_and_notifications() {
+        let hap = Hap::new(MockSys::new());
+        let serv = hap.sys().serv_outlet_create(false, false).unwrap();
+        let on = hap
+            .char_by_uuid(serv, uuid::ON, Format::Bool, perm::PR | perm::PW)
+            .unwrap();
+        let name = hap
+            .add_char(serv, uuid::NAME, perm::PR, &Value::String("Outlet".into()))
+            .unwrap();
+        static HANDLER: fn(&Write) -> Result<(), Status> = |_| Ok(());
+        hap.set_write_handler(serv, &HANDLER);
+        set_sink(collect);
+
+        let batch = [
+            (on, Value::Bool(true)),
+            (name, Value::String("Hall".into())),
+        ];
+        let of_test = |record: &Record| record.hc() == Some(on) || record.hc() == Some(name);
+        // Nothing while off
+        hap.sys().write(serv, &batch);
+        hap.update(on, &Value::Bool(false));
+        assert!(!RECORDS.lock().unwrap().iter().any(of_test));
+
+        set_enabled(true);
+        hap.sys().write(serv, &batch);
+        hap.update(on, &Value::Bool(false));
+        set_enabled(false);
+
+        let records: Vec<_> = RECORDS
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|record| of_test(record))
+            .cloned()
+            .collect();
+        assert_eq!(
+            records,
+            [
+                Record {
+                    controller: None,
+                    kind: Kind::Write {
+                        hc: Some(on),
+                        value: Some(Value::Bool(true)),
+                        status: Status::Success,
+                    },
+                },
+                Record {
+                    controller: None,
+                    kind: Kind::Write {
+                        hc: Some(name),
+                        value: Some(Value::String("Hall".into())),
+                        status: Status::WriteOnReadOnly,
+                    },
+                },
+                Record {
+                    controller: None,
+                    kind: Kind::Notify {
+                        hc: on,
+                        value: Value::Bool(false),
+                    },
+                },
+            ]
+        );
+        let from_phone = Record {
+            controller: Some(ControllerId::new("phone")),
+            ..records[1].clone()
+        };
+        assert_eq!(
+            from_phone.render(Some("23")),
+            "phone write 23 = \"Hall\" -> WriteOnReadOnly"
+        );
+        assert_eq!(records[2].render(None), "- notify ? = false");
+    }
+}
@@ -0,0 +1,329 @@
+use std::ffi::{c_char, CStr, CString};
+use std::marker::PhantomData;
+use std::ptr;
+use std::slice;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Bool,
+    Uint8,
+    Uint16,
+    Uint32,
+    Uint64,
+    Int,
+    Float,
+    String,
+    Tlv8,
+    Data,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Bool(bool),
+    Uint8(u8),
+    Uint16(u16),
+    Uint32(u32),
+    Uint64(u64),
+    Int(i32),
+    Float(f32),
+    String(String),
+    Tlv8(Vec<u8>),
+    Data(Vec<u8>),
+}
+
+/// Mirror of the SDK's `hap_data_val_t` and `hap_tlv8_val_t`.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct RawBuf {
+    pub buf: *mut u8,
+    pub buflen: u32,
+}
+
+impl RawBuf {
+    fn of(bytes: &[u8]) -> Self {
+        Self {
+            buf: bytes.as_ptr() as *mut u8,
+            buflen: bytes.len() as u32,
+        }
+    }
+
+    unsafe fn to_vec(self) -> Vec<u8> {
+        if self.buf.is_null() {
+            Vec::new()
+        } else {
+            slice::from_raw_parts(self.buf, self.buflen as usize).to_vec()
+        }
+    }
+}
+
+/// Mirror of the SDK's `hap_val_t`; the firmware asserts that the layouts match.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub union RawVal {
+    pub b: bool,
+    /// uint8, uint16 and uint32 alike
+    pub u: u32,
+    pub i: i32,
+    pub i64: u64,
+    pub f: f32,
+    pub s: *mut c_char,
+    pub d: RawBuf,
+    pub t: RawBuf,
+}
+
+/// A value in the SDK representation, borrowing the buffers of the `Value`.
+pub struct RawValue<'a> {
+    val: RawVal,
+    _string: Option<CString>,
+    _value: PhantomData<&'a Value>,
+}
+
+impl RawValue<'_> {
+    pub fn get(&self) -> &RawVal {
+        &self.val
+    }
+}
+
+impl Value {
+    pub fn format(&self) -> Format {
+        match self {
+            Value::Bool(_) => Format::Bool,
+            Value::Uint8(_) => Format::Uint8,
+            Value::Uint16(_) => Format::Uint16,
+            Value::Uint32(_) => Format::Uint32,
+            Value::Uint64(_) => Format::Uint64,
+            Value::Int(_) => Format::Int,
+            Value::Float(_) => Format::Float,
+            Value::String(_) => Format::String,
+            Value::Tlv8(_) => Format::Tlv8,
+            Value::Data(_) => Format::Data,
+        }
+    }
+
+    pub fn to_raw(&self) -> RawValue<'_> {
+        let mut string = None;
+        let val = match self {
+            Value::Bool(b) => RawVal { b: *b },
+            Value::Uint8(u) => RawVal { u: *u as u32 },
+            Value::Uint16(u) => RawVal { u: *u as u32 },
+            Value::Uint32(u) => RawVal { u: *u },
+            Value::Uint64(u) => RawVal { i64: *u },
+            Value::Int(i) => RawVal { i: *i },
+            Value::Float(f) => RawVal { f: *f },
+            Value::String(s) => {
+                // An interior NUL would cut the string short in the SDK anyway
+                let s = CString::new(s.as_str()).unwrap_or_default();
+                let val = RawVal {
+                    s: s.as_ptr() as *mut c_char,
+                };
+                string = Some(s);
+                val
+            }
+            Value::Tlv8(bytes) => RawVal {
+                t: RawBuf::of(bytes),
+            },
+            Value::Data(bytes) => RawVal {
+                d: RawBuf::of(bytes),
+            },
+        };
+
+        RawValue {
+            val,
+            _string: string,
+            _value: PhantomData,
+        }
+    }
+
+    /// Reads a value the SDK handed over, e.g. in a write batch.
+    ///
+    /// # Safety
+    ///
+    /// `raw` has to hold a value of `format`, with valid string and buffer
+    /// pointers for the formats that have them.
+    pub unsafe fn from_raw(format: Format, raw: &RawVal) -> Self {
+        match format {
+            Format::Bool => Value::Bool(raw.b),
+            Format::Uint8 => Value::Uint8(raw.u as u8),
+            Format::Uint16 => Value::Uint16(raw.u as u16),
+            Format::Uint32 => Value::Uint32(raw.u),
+            Format::Uint64 => Value::Uint64(raw.i64),
+            Format::Int => Value::Int(raw.i),
+            Format::Float => Value::Float(raw.f),
+            Format::String => Value::String(if raw.s.is_null() {
+                String::new()
+            } else {
+                CStr::from_ptr(raw.s).to_string_lossy().into_owned()
+            }),
+            Format::Tlv8 => Value::Tlv8(raw.t.to_vec()),
+            Format::Data => Value::Data(raw.d.to_vec()),
+        }
+    }
+
+    /// Like `from_raw`, but rejects what is no valid value of `format`: a
+    /// bool other than 0 or 1, an integer too wide for its format, a float
+    /// that is not finite or a string that is not UTF-8.
+    ///
+    /// # Safety
+    ///
+    /// As for `from_raw`.
+    pub unsafe fn decode(format: Format, raw: &RawVal) -> Option<Self> {
+        let valid = match format {
+            // The SDK only sets the first byte, the others are arbitrary
+            Format::Bool => *(raw as *const RawVal as *const u8) <= 1,
+            Format::Uint8 => raw.u <= u8::MAX as u32,
+            Format::Uint16 => raw.u <= u16::MAX as u32,
+            Format::Float => raw.f.is_finite(),
+            Format::String => raw.s.is_null() || CStr::from_ptr(raw.s).to_str().is_ok(),
+            _ => true,
+        };
+
+        valid.then(|| Self::from_raw(format, raw))
+    }
+
+    /// Any numeric value, for range checks.
+    pub fn as_f64(&self) -> Option<f64> {
+        match *self {
+            Value::Uint8(u) => Some(u as f64),
+            Value::Uint16(u) => Some(u as f64),
+            Value::Uint32(u) => Some(u as f64),
+            Value::Uint64(u) => Some(u as f64),
+            Value::Int(i) => Some(i as f64),
+            Value::Float(f) => Some(f as f64),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Value::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    pub fn as_u32(&self) -> Option<u32> {
+        match self {
+            Value::Uint8(u) => Some(*u as u32),
+            Value::Uint16(u) => Some(*u as u32),
+            Value::Uint32(u) => Some(*u),
+            _ => None,
+        }
+    }
+
+    pub fn as_f32(&self) -> Option<f32> {
+        match self {
+            Value::Float(f) => Some(*f),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+}
+
+impl Default for RawVal {
+    fn default() -> Self {
+        RawVal { s: ptr::null_mut() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(value: Value) {
+        let raw = value.to_raw();
+        let back = unsafe { Value::from_raw(value.format(), raw.get()) };
+        assert_eq!(back, value);
+    }
+
+    #[test]
+    fn every_format_round_trips() {
+        round_trip(Value::Bool(true));
+        round_trip(Value::Bool(false));
+        round_trip(Value::Uint8(u8::MAX));
+        round_trip(Value::Uint16(u16::MAX));
+        round_trip(Value::Uint32(u32::MAX));
+        round_trip(Value::Uint64(u64::MAX));
+        round_trip(Value::Int(i32::MIN));
+        round_trip(Value::Float(-12.5));
+        round_trip(Value::String("Smart-Outlet".into()));
+        round_trip(Value::String(String::new()));
+        round_trip(Value::Tlv8(vec![0x01, 0x02, 0xaa, 0xbb]));
+        round_trip(Value::Data(vec![0; 300]));
+        round_trip(Value::Data(Vec::new()));
+    }
+
+    #[test]
+    fn small_unsigned_formats_share_the_u_member() {
+        let raw = Value::Uint8(200).to_raw();
+        assert_eq!(unsafe { raw.get().u }, 200);
+
+        let raw = Value::Uint16(60_000).to_raw();
+        assert_eq!(unsafe { raw.get().u }, 60_000);
+    }
+
+    #[test]
+    fn string_with_interior_nul_becomes_empty() {
+        let value = Value::String("a\0b".into());
+        let raw = value.to_raw();
+        let back = unsafe { Value::from_raw(Format::String, raw.get()) };
+        assert_eq!(back, Value::String(String::new()));
+    }
+
+    #[test]
+    fn null_pointers_read_as_empty() {
+        let raw = RawVal::default();
+        assert_eq!(
+            unsafe { Value::from_raw(Format::String, &raw) },
+            Value::String(String::new())
+        );
+
+        let raw = RawVal {
+            d: RawBuf {
+                buf: ptr::null_mut(),
+                buflen: 4,
+            },
+        };
+        assert_eq!(
+            unsafe { Value::from_raw(Format::Data, &raw) },
+            Value::Data(Vec::new())
+        );
+    }
+
+    #[test]
+    fn decoding_rejects_what_the_format_cannot_hold() {
+        let decode = |format, value: Value| unsafe { Value::decode(format, value.to_raw().get()) };
+
+        assert_eq!(
+            decode(Format::Uint8, Value::Uint8(7)),
+            Some(Value::Uint8(7))
+        );
+        assert_eq!(decode(Format::Uint8, Value::Uint16(300)), None);
+        assert_eq!(decode(Format::Uint16, Value::Uint32(70_000)), None);
+        assert_eq!(decode(Format::Float, Value::Float(f32::NAN)), None);
+        assert_eq!(decode(Format::Bool, Value::Uint8(2)), None);
+        assert_eq!(
+            decode(Format::Bool, Value::Bool(true)),
+            Some(Value::Bool(true))
+        );
+
+        let invalid = RawVal {
+            s: c"\xff".as_ptr() as *mut c_char,
+        };
+        assert_eq!(unsafe { Value::decode(Format::String, &invalid) }, None);
+    }
+
+    #[test]
+    fn accessors_check_the_format() {
+        assert_eq!(Value::Bool(true).as_bool(), Some(true));
+        assert_eq!(Value::Uint8(3).as_bool(), None);
+        assert_eq!(Value::Uint16(7).as_u32(), Some(7));
+        assert_eq!(Value::Int(7).as_u32(), None);
+        assert_eq!(Value::Float(1.5).as_f32(), Some(1.5));
+        assert_eq!(Value::String("x".into()).as_str(), Some("x"));
+    }
+}
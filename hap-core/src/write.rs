@@ -0,0 +1,630 @@
+use std::any::Any;
+use std::cell::Cell;
+use std::ffi::c_void;
+use std::panic::{self, AssertUnwindSafe};
+use std::slice;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::sys::{perm, Char, ControllerId, RawWrite, HAP_FAIL, HAP_SUCCESS};
+use crate::trace::{self, Kind};
+use crate::value::{Format, Value};
+use crate::{sessions, Handlers};
+
+/// HAP status codes of a single write, `hap_status_t`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Status {
+    Success,
+    NoPrivilege,
+    CommunicationError,
+    ResourceBusy,
+    WriteOnReadOnly,
+    NoResource,
+    Timeout,
+    ResourceAbsent,
+    InvalidValue,
+    InsufficientAuthorization,
+}
+
+impl Status {
+    pub fn code(self) -> i32 {
+        match self {
+            Status::Success => 0,
+            Status::NoPrivilege => -70401,
+            Status::CommunicationError => -70402,
+            Status::ResourceBusy => -70403,
+            Status::WriteOnReadOnly => -70404,
+            Status::NoResource => -70407,
+            Status::Timeout => -70408,
+            Status::ResourceAbsent => -70409,
+            Status::InvalidValue => -70410,
+            Status::InsufficientAuthorization => -70411,
+        }
+    }
+}
+
+/// One decoded entry of a write batch.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Write {
+    pub hc: Char,
+    pub value: Value,
+    /// From a controller rather than a local source
+    pub remote: bool,
+}
+
+pub trait WriteHandler: Sync {
+    fn write(&self, write: &Write) -> Result<(), Status>;
+}
+
+impl<F> WriteHandler for F
+where
+    F: Fn(&Write) -> Result<(), Status> + Sync,
+{
+    fn write(&self, write: &Write) -> Result<(), Status> {
+        self(write)
+    }
+}
+
+/// Numeric range of a characteristic; `step` of 0 allows any value.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Bounds {
+    pub min: f64,
+    pub max: f64,
+    pub step: f64,
+}
+
+impl Bounds {
+    pub fn contains(&self, value: f64) -> bool {
+        if !(self.min..=self.max).contains(&value) {
+            return false;
+        }
+        if self.step <= 0.0 {
+            return true;
+        }
+
+        let steps = (value - self.min) / self.step;
+        (steps - steps.round()).abs() < 1e-6
+    }
+}
+
+/// What the write path checks a characteristic's writes against.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Meta {
+    pub format: Format,
+    pub perms: u16,
+    pub bounds: Option<Bounds>,
+    pub valid_values: Option<&'static [u8]>,
+}
+
+// The SDK hands out untyped values, so the format is noted at creation
+static META: Mutex<Vec<(Char, Meta)>> = Mutex::new(Vec::new());
+
+/// Runs before every handler and can refuse a write the accessory cannot
+/// take right now, e.g. with `ResourceBusy` during an update.
+pub type Gate = &'static (dyn Fn(&Write) -> Result<(), Status> + Send + Sync);
+
+static GATES: Mutex<Vec<Gate>> = Mutex::new(Vec::new());
+
+pub fn add_gate(gate: impl Fn(&Write) -> Result<(), Status> + Send + Sync + 'static) {
+    GATES.lock().unwrap().push(Box::leak(Box::new(gate)));
+}
+
+/// Handlers run on the HAP server task; longer ones hold up every other
+/// controller and eventually the task watchdog.
+pub const SLOW_HANDLER: Duration = Duration::from_millis(50);
+
+/// Something wrong with a handler, as the monitors are told.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Incident {
+    /// Fails the batch with `HAP_FAIL`, the entry's own status being
+    /// `CommunicationError` as the SDK has none for a failed handler; the
+    /// rest of the batch goes on
+    Panicked(String),
+    /// Took longer than `SLOW_HANDLER`, and how long
+    Slow(Duration),
+}
+
+/// Told of the incidents of every handler, to log them.
+pub type Monitor = &'static (dyn Fn(Char, &Incident) + Send + Sync);
+
+static MONITORS: Mutex<Vec<Monitor>> = Mutex::new(Vec::new());
+
+pub fn add_monitor(monitor: impl Fn(Char, &Incident) + Send + Sync + 'static) {
+    MONITORS.lock().unwrap().push(Box::leak(Box::new(monitor)));
+}
+
+fn report(hc: Char, incident: Incident) {
+    let monitors = MONITORS.lock().unwrap().clone();
+    for monitor in monitors {
+        monitor(hc, &incident);
+    }
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        return (*message).into();
+    }
+    if let Some(message) = payload.downcast_ref::<String>() {
+        return message.clone();
+    }
+
+    "unknown panic".into()
+}
+
+thread_local! {
+    static CONTAINING: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Whether a panic under way is a write handler's, which unwinds to the
+/// dispatch and stays there; for a panic hook to leave the others to abort.
+pub fn containing_panic() -> bool {
+    CONTAINING.with(Cell::get)
+}
+
+/// Runs the handler on one entry, a panic staying inside it; the panic hook
+/// has logged the backtrace by the time it is caught.
+fn invoke(handler: &dyn WriteHandler, write: &Write) -> Status {
+    let started = Instant::now();
+    CONTAINING.with(|containing| containing.set(true));
+    let result = panic::catch_unwind(AssertUnwindSafe(|| handler.write(write)));
+    CONTAINING.with(|containing| containing.set(false));
+    let elapsed = started.elapsed();
+    if elapsed > SLOW_HANDLER {
+        report(write.hc, Incident::Slow(elapsed));
+    }
+
+    match result {
+        Ok(result) => result.err().unwrap_or(Status::Success),
+        Err(payload) => {
+            report(write.hc, Incident::Panicked(panic_message(&*payload)));
+            Status::CommunicationError
+        }
+    }
+}
+
+pub fn register(hc: Char, format: Format, perms: u16) {
+    let meta = Meta {
+        format,
+        perms,
+        bounds: None,
+        valid_values: None,
+    };
+
+    let mut known = META.lock().unwrap();
+    match known.iter_mut().find(|(known, _)| *known == hc) {
+        Some(entry) => entry.1 = meta,
+        None => known.push((hc, meta)),
+    }
+}
+
+fn update_meta(hc: Char, f: impl FnOnce(&mut Meta)) {
+    let mut known = META.lock().unwrap();
+    if let Some((_, meta)) = known.iter_mut().find(|(known, _)| *known == hc) {
+        f(meta);
+    }
+}
+
+pub fn set_bounds(hc: Char, bounds: Bounds) {
+    update_meta(hc, |meta| meta.bounds = Some(bounds));
+}
+
+pub fn set_valid_values(hc: Char, values: &'static [u8]) {
+    update_meta(hc, |meta| meta.valid_values = Some(values));
+}
+
+pub fn meta(hc: Char) -> Option<Meta> {
+    let known = META.lock().unwrap();
+    known
+        .iter()
+        .find(|(known, _)| *known == hc)
+        .map(|(_, meta)| meta.clone())
+}
+
+pub fn format_of(hc: Char) -> Option<Format> {
+    meta(hc).map(|meta| meta.format)
+}
+
+/// Checks an entry against what is known of its characteristic and the
+/// gates, so handlers only see writes they can act on.
+unsafe fn validate(entry: &RawWrite) -> Result<Write, Status> {
+    let hc = Char::from_ptr(entry.hc).ok_or(Status::ResourceAbsent)?;
+    let meta = meta(hc).ok_or(Status::ResourceAbsent)?;
+    if meta.perms & perm::PW == 0 {
+        return Err(Status::WriteOnReadOnly);
+    }
+
+    let value = Value::decode(meta.format, &entry.val).ok_or(Status::InvalidValue)?;
+    if let (Some(bounds), Some(number)) = (meta.bounds, value.as_f64()) {
+        if !bounds.contains(number) {
+            return Err(Status::InvalidValue);
+        }
+    }
+    if let (Some(valid), Some(number)) = (meta.valid_values, value.as_u32()) {
+        if !valid.iter().any(|&v| v as u32 == number) {
+            return Err(Status::InvalidValue);
+        }
+    }
+
+    let write = Write {
+        hc,
+        value,
+        remote: entry.remote,
+    };
+    let gates = GATES.lock().unwrap().clone();
+    for gate in gates {
+        gate(&write)?;
+    }
+
+    Ok(write)
+}
+
+/// Validates every entry of a batch, hands the valid ones to the handler and
+/// records each status. The batch fails as a whole if any entry did, as the
+/// SDK expects.
+///
+/// # Safety
+///
+/// The entries have to come from the SDK, or be built like it does.
+pub unsafe fn dispatch(batch: &mut [RawWrite], handler: &dyn WriteHandler) -> i32 {
+    dispatch_of(batch, handler, None)
+}
+
+unsafe fn dispatch_of(
+    batch: &mut [RawWrite],
+    handler: &dyn WriteHandler,
+    controller: Option<ControllerId>,
+) -> i32 {
+    let mut result = HAP_SUCCESS;
+
+    for entry in batch {
+        let status = match validate(entry) {
+            Ok(write) => invoke(handler, &write),
+            Err(status) => status,
+        };
+        if trace::enabled() {
+            trace_write(entry, status, controller);
+        }
+
+        if !entry.status.is_null() {
+            *entry.status = status.code();
+        }
+        if status != Status::Success {
+            result = HAP_FAIL;
+        }
+    }
+
+    result
+}
+
+/// Decodes the entry again rather than slowing down every write to keep the
+/// value, rejected entries included.
+unsafe fn trace_write(entry: &RawWrite, status: Status, controller: Option<ControllerId>) {
+    let hc = Char::from_ptr(entry.hc);
+    let value = hc
+        .and_then(format_of)
+        .and_then(|format| Value::decode(format, &entry.val));
+    trace::record(controller, Kind::Write { hc, value, status });
+}
+
+/// The write callback of services with a handler, which is the private data.
+///
+/// # Safety
+///
+/// `serv_priv` has to point to the `Handlers` of the service.
+pub unsafe extern "C" fn on_write(
+    write_data: *mut RawWrite,
+    count: i32,
+    serv_priv: *mut c_void,
+    write_priv: *mut c_void,
+) -> i32 {
+    sessions::request(write_priv);
+    let handlers = (serv_priv as *const Handlers).as_ref();
+    let Some(handler) = handlers.and_then(|h| h.write) else {
+        return HAP_FAIL;
+    };
+    if write_data.is_null() || count <= 0 {
+        return HAP_FAIL;
+    }
+
+    dispatch_of(
+        slice::from_raw_parts_mut(write_data, count as usize),
+        handler,
+        trace::controller(write_priv),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::mock::{Call, MockSys};
+    use crate::sys::{uuid, HapSys};
+    use crate::Hap;
+
+    const WRITABLE: u16 = perm::PR | perm::PW | perm::EV;
+
+    const LEVEL_UUID: &[u8] = b"0000D2A0-28E5-4C3F-9B6E-5A1D7E3C9000\0";
+
+    struct Recorder {
+        writes: Mutex<Vec<Write>>,
+        reject: Option<Char>,
+    }
+
+    impl WriteHandler for Recorder {
+        fn write(&self, write: &Write) -> Result<(), Status> {
+            self.writes.lock().unwrap().push(write.clone());
+            match self.reject {
+                Some(hc) if hc == write.hc => Err(Status::InvalidValue),
+                _ => Ok(()),
+            }
+        }
+    }
+
+    fn service(hap: &Hap<MockSys>, reject_level: bool) -> (Char, Char, &'static Recorder) {
+        let serv = hap.sys().serv_outlet_create(false, false).unwrap();
+        let on = hap
+            .char_by_uuid(serv, uuid::ON, Format::Bool, WRITABLE)
+            .unwrap();
+        let level = hap
+            .create_char(LEVEL_UUID, WRITABLE, &Value::Uint8(0))
+            .unwrap();
+        hap.sys().serv_add_char(serv, level);
+
+        let recorder: &'static Recorder = Box::leak(Box::new(Recorder {
+            writes: Mutex::new(Vec::new()),
+            reject: reject_level.then_some(level),
+        }));
+        hap.set_write_handler(serv, recorder);
+
+        (on, level, recorder)
+    }
+
+    #[test]
+    fn every_entry_of_a_batch_is_dispatched() {
+        let hap = Hap::new(MockSys::new());
+        let (on, level, recorder) = service(&hap, false);
+
+        let serv = hap.sys().service_of(on).unwrap();
+        let (result, statuses) = hap
+            .sys()
+            .write(serv, &[(on, Value::Bool(true)), (level, Value::Uint8(42))]);
+
+        assert_eq!(result, HAP_SUCCESS);
+        assert_eq!(statuses, vec![0, 0]);
+        let writes = recorder.writes.lock().unwrap();
+        assert_eq!(writes.len(), 2);
+        assert_eq!(writes[0].hc, on);
+        assert_eq!(writes[0].value, Value::Bool(true));
+        assert!(writes[0].remote);
+        assert_eq!(writes[1].value, Value::Uint8(42));
+    }
+
+    #[test]
+    fn a_rejected_entry_fails_only_itself() {
+        let hap = Hap::new(MockSys::new());
+        let (on, level, recorder) = service(&hap, true);
+
+        let serv = hap.sys().service_of(on).unwrap();
+        let (result, statuses) = hap
+            .sys()
+            .write(serv, &[(level, Value::Uint8(1)), (on, Value::Bool(false))]);
+
+        assert_eq!(result, HAP_FAIL);
+        assert_eq!(statuses, vec![Status::InvalidValue.code(), 0]);
+        assert_eq!(recorder.writes.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn unknown_characteristics_are_absent() {
+        let hap = Hap::new(MockSys::new());
+        let (on, _, recorder) = service(&hap, false);
+
+        let serv = hap.sys().service_of(on).unwrap();
+        let stray = hap.sys().serv_outlet_create(false, false).unwrap();
+        // Created by the SDK, never looked up with a format
+        let in_use = hap
+            .sys()
+            .serv_char_by_uuid(stray, uuid::OUTLET_IN_USE)
+            .unwrap();
+        let (result, statuses) = hap.sys().write(serv, &[(in_use, Value::Bool(true))]);
+
+        assert_eq!(result, HAP_FAIL);
+        assert_eq!(statuses, vec![Status::ResourceAbsent.code()]);
+        assert!(recorder.writes.lock().unwrap().is_empty());
+    }
+
+    fn rejected(hap: &Hap<MockSys>, hc: Char, value: Value, recorder: &Recorder) -> i32 {
+        let serv = hap.sys().service_of(hc).unwrap();
+        let (result, statuses) = hap.sys().write(serv, &[(hc, value)]);
+
+        assert_eq!(result, HAP_FAIL);
+        assert!(recorder.writes.lock().unwrap().is_empty());
+        statuses[0]
+    }
+
+    #[test]
+    fn read_only_characteristics_refuse_writes() {
+        let hap = Hap::new(MockSys::new());
+        let (on, _, recorder) = service(&hap, false);
+
+        let serv = hap.sys().service_of(on).unwrap();
+        let power = hap
+            .add_char(serv, LEVEL_UUID, perm::PR | perm::EV, &Value::Float(0.0))
+            .unwrap();
+        assert_eq!(
+            rejected(&hap, power, Value::Float(1.0), recorder),
+            Status::WriteOnReadOnly.code()
+        );
+    }
+
+    #[test]
+    fn values_the_format_cannot_hold_are_invalid() {
+        let hap = Hap::new(MockSys::new());
+        let (on, level, recorder) = service(&hap, false);
+
+        assert_eq!(
+            rejected(&hap, level, Value::Uint16(256), recorder),
+            Status::InvalidValue.code()
+        );
+        assert_eq!(
+            rejected(&hap, on, Value::Uint8(2), recorder),
+            Status::InvalidValue.code()
+        );
+    }
+
+    #[test]
+    fn values_out_of_bounds_are_invalid() {
+        let hap = Hap::new(MockSys::new());
+        let (_, level, recorder) = service(&hap, false);
+        hap.set_bounds(
+            level,
+            Bounds {
+                min: 10.0,
+                max: 50.0,
+                step: 5.0,
+            },
+        );
+
+        for value in [5, 55, 12] {
+            assert_eq!(
+                rejected(&hap, level, Value::Uint8(value), recorder),
+                Status::InvalidValue.code()
+            );
+        }
+
+        let serv = hap.sys().service_of(level).unwrap();
+        let (result, _) = hap.sys().write(serv, &[(level, Value::Uint8(15))]);
+        assert_eq!(result, HAP_SUCCESS);
+    }
+
+    #[test]
+    fn values_outside_the_valid_ones_are_invalid() {
+        let hap = Hap::new(MockSys::new());
+        let (_, level, recorder) = service(&hap, false);
+        hap.set_valid_values(level, &[0, 2]);
+
+        assert_eq!(
+            rejected(&hap, level, Value::Uint8(1), recorder),
+            Status::InvalidValue.code()
+        );
+        assert!(hap
+            .sys()
+            .calls()
+            .contains(&Call::ValidVals(level, vec![0, 2])));
+    }
+
+    #[test]
+    fn gates_refuse_writes_with_their_status() {
+        let hap = Hap::new(MockSys::new());
+        let (on, level, recorder) = service(&hap, false);
+
+        // Gates are process-wide, so this one only minds its own test
+        add_gate(move |write: &Write| {
+            if write.hc == level {
+                return Err(Status::ResourceBusy);
+            }
+            Ok(())
+        });
+
+        assert_eq!(
+            rejected(&hap, level, Value::Uint8(1), recorder),
+            Status::ResourceBusy.code()
+        );
+        let serv = hap.sys().service_of(on).unwrap();
+        assert_eq!(
+            hap.sys().write(serv, &[(on, Value::Bool(true))]).0,
+            HAP_SUCCESS
+        );
+    }
+
+    #[test]
+    fn closures_are_handlers() {
+        let hap = Hap::new(MockSys::new());
+        let serv = hap.sys().serv_switch_create(false).unwrap();
+        let on = hap
+            .char_by_uuid(serv, uuid::ON, Format::Bool, WRITABLE)
+            .unwrap();
+        hap.set_write_handler(serv, &|write: &Write| match write.value.as_bool() {
+            Some(_) => Ok(()),
+            None => Err(Status::InvalidValue),
+        });
+
+        assert_eq!(
+            hap.sys().write(serv, &[(on, Value::Bool(true))]).0,
+            HAP_SUCCESS
+        );
+    }
+
+    #[test]
+    fn panicking_handlers_fail_their_entry() {
+        let hap = Hap::new(MockSys::new());
+        let serv = hap.sys().serv_switch_create(false).unwrap();
+        let on = hap
+            .char_by_uuid(serv, uuid::ON, Format::Bool, WRITABLE)
+            .unwrap();
+        let level = hap
+            .add_char(serv, LEVEL_UUID, WRITABLE, &Value::Uint8(0))
+            .unwrap();
+        hap.set_write_handler(serv, &|write: &Write| match write.value {
+            Value::Bool(_) => panic!("relay driver gone"),
+            _ if containing_panic() => Ok(()),
+            _ => Err(Status::ResourceBusy),
+        });
+        let incidents: &'static Mutex<Vec<Incident>> = Box::leak(Box::new(Mutex::new(Vec::new())));
+        add_monitor(move |hc, incident| {
+            if hc == on {
+                incidents.lock().unwrap().push(incident.clone());
+            }
+        });
+
+        let (result, statuses) = hap
+            .sys()
+            .write(serv, &[(on, Value::Bool(true)), (level, Value::Uint8(3))]);
+        assert_eq!(result, HAP_FAIL);
+        assert_eq!(statuses, vec![Status::CommunicationError.code(), 0]);
+        assert_eq!(
+            *incidents.lock().unwrap(),
+            vec![Incident::Panicked("relay driver gone".into())]
+        );
+        assert!(!containing_panic());
+    }
+
+    #[test]
+    fn slow_handlers_are_reported() {
+        let hap = Hap::new(MockSys::new());
+        let serv = hap.sys().serv_switch_create(false).unwrap();
+        let on = hap
+            .char_by_uuid(serv, uuid::ON, Format::Bool, WRITABLE)
+            .unwrap();
+        hap.set_write_handler(serv, &|_: &Write| {
+            std::thread::sleep(SLOW_HANDLER + Duration::from_millis(10));
+            Ok(())
+        });
+        let slow: &'static Mutex<Option<Duration>> = Box::leak(Box::new(Mutex::new(None)));
+        add_monitor(move |hc, incident| {
+            if let (true, Incident::Slow(elapsed)) = (hc == on, incident) {
+                *slow.lock().unwrap() = Some(*elapsed);
+            }
+        });
+
+        assert_eq!(
+            hap.sys().write(serv, &[(on, Value::Bool(true))]).0,
+            HAP_SUCCESS
+        );
+        assert!(slow.lock().unwrap().unwrap() > SLOW_HANDLER);
+    }
+
+    #[test]
+    fn empty_batches_fail() {
+        let result = unsafe {
+            on_write(
+                std::ptr::null_mut(),
+                0,
+                std::ptr::null_mut(),
+                std::ptr::null_mut(),
+            )
+        };
+        assert_eq!(result, HAP_FAIL);
+    }
+}
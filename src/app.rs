@@ -0,0 +1,65 @@
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use esp_idf_svc::netif::EspNetifStack;
+use esp_idf_svc::nvs::EspDefaultNvs;
+use esp_idf_svc::sysloop::EspSysLoopStack;
+use esp_idf_svc::wifi::EspWifi;
+use hap_core::{Acc, Serv};
+use once_cell::sync::OnceCell;
+
+use crate::outlet::OutletRunner;
+
+/// Everything that has to stay alive for the lifetime of the firmware.
+pub struct AppContext {
+    pub netif: Arc<EspNetifStack>,
+    pub sysloop: Arc<EspSysLoopStack>,
+    pub nvs: Arc<EspDefaultNvs>,
+    pub wifi: Mutex<Box<EspWifi>>,
+    /// Set by the HAP task once the accessory database is built
+    pub accessory: OnceCell<Accessory>,
+}
+
+pub struct Accessory {
+    pub accessory: Acc,
+    pub outlet_service: Serv,
+    pub diag_service: Serv,
+    pub outlet: &'static OutletRunner,
+}
+
+/// Class of a fatal startup failure, attached to errors as anyhow context.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Failure {
+    Wifi,
+    Gpio,
+    Config,
+    HapInit,
+    HapStart,
+    FactoryData,
+}
+
+impl Failure {
+    pub fn blink_count(self) -> u32 {
+        match self {
+            Failure::Wifi => 2,
+            Failure::Gpio | Failure::Config | Failure::HapInit => 3,
+            Failure::HapStart => 4,
+            Failure::FactoryData => 5,
+        }
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let description = match self {
+            Failure::Wifi => "Wi-Fi bring-up failed",
+            Failure::Gpio => "configuring the relay backend failed",
+            Failure::Config => "invalid accessory configuration",
+            Failure::HapInit => "HAP initialization failed",
+            Failure::HapStart => "HAP start failed",
+            Failure::FactoryData => "factory data is corrupt",
+        };
+
+        f.write_str(description)
+    }
+}
@@ -0,0 +1,152 @@
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+use anyhow::Result;
+use hap_core::schema::{self, Defaults, Entries, Outcome};
+use log::{error, info, warn};
+
+use crate::{config, logging, metrics, nvs};
+
+// The version found at boot, 0 until then
+static STORED: AtomicU8 = AtomicU8::new(0);
+static DOWNGRADED: AtomicBool = AtomicBool::new(false);
+static QUARANTINED: AtomicBool = AtomicBool::new(false);
+
+const DEFAULTS: Defaults = Defaults {
+    outlet_channel: config::RELAY_CHANNEL,
+    policy: config::RESTORE_POLICY as u8,
+};
+
+/// The namespace to keep the accessory state in: `app_state`, or a fallback
+/// while that is from a newer firmware, so a downgrade boots with defaults
+/// and the newer state is still there after upgrading again.
+pub fn namespace() -> &'static str {
+    if DOWNGRADED.load(Ordering::Relaxed) {
+        nvs::APP_STATE_FALLBACK
+    } else {
+        nvs::APP_STATE
+    }
+}
+
+pub fn downgraded() -> bool {
+    DOWNGRADED.load(Ordering::Relaxed)
+}
+
+/// Brings the accessory state to this firmware's schema before anything
+/// loads it. State that cannot be migrated is moved aside rather than
+/// erased, state from a newer firmware is left alone.
+pub fn migrate_at_boot() {
+    if let Err(err) = migrate() {
+        warn!(
+            target: logging::DIAG,
+            "Migrating the accessory state failed, using it as it is: {:?}", err
+        );
+    }
+}
+
+fn migrate() -> Result<()> {
+    let store = nvs::Namespace::open(nvs::APP_STATE)?;
+    // Before loading entries of types this firmware may not know; a version
+    // of the wrong type fails the migration below
+    let version = store.get_u8(schema::VERSION_KEY).ok().flatten();
+    if let Some(version) = version.filter(|&version| version > schema::VERSION) {
+        downgrade(version);
+        return Ok(());
+    }
+    // What a downgraded firmware stored is dropped once upgraded again
+    let fallback = nvs::Namespace::open(nvs::APP_STATE_FALLBACK)?;
+    if fallback.used_entries()? > 0 {
+        fallback.erase_all()?;
+        fallback.commit()?;
+        info!(target: logging::DIAG, "Dropped the accessory state of a downgraded firmware");
+    }
+
+    let entries = nvs::load(nvs::APP_STATE)?;
+    match schema::migrate(&entries, &DEFAULTS) {
+        Outcome::Current => STORED.store(schema::VERSION, Ordering::Relaxed),
+        Outcome::Migrated {
+            from,
+            entries: migrated,
+        } => {
+            STORED.store(from, Ordering::Relaxed);
+            write(&store, &entries, &migrated)?;
+            info!(
+                target: logging::DIAG,
+                "Accessory state migrated from schema {} to {}",
+                from,
+                schema::VERSION
+            );
+        }
+        Outcome::Newer(version) => downgrade(version),
+        Outcome::Failed { from, error } => {
+            STORED.store(from, Ordering::Relaxed);
+            let moved = nvs::move_namespace(nvs::APP_STATE, nvs::APP_STATE_QUARANTINE)?;
+            QUARANTINED.store(true, Ordering::Relaxed);
+            error!(
+                target: logging::DIAG,
+                "Accessory state at schema {} is unusable ({}): moved its {} entries to {} and \
+                 starting from defaults",
+                from,
+                error,
+                moved,
+                nvs::APP_STATE_QUARANTINE
+            );
+            if let Outcome::Migrated { entries, .. } = schema::migrate(&Entries::new(), &DEFAULTS) {
+                write(&store, &Entries::new(), &entries)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn downgrade(version: u8) {
+    STORED.store(version, Ordering::Relaxed);
+    DOWNGRADED.store(true, Ordering::Relaxed);
+    error!(
+        target: logging::DIAG,
+        "DOWNGRADE: the accessory state is at schema {}, this firmware knows {}; it stays \
+         untouched and the accessory runs with defaults from {} until upgraded again",
+        version,
+        schema::VERSION,
+        nvs::APP_STATE_FALLBACK
+    );
+}
+
+/// Stores the changed entries and removes the dropped ones, the version
+/// last: a restart in between has the migrations run again.
+fn write(store: &nvs::Namespace, old: &Entries, new: &Entries) -> Result<()> {
+    for (key, value) in new {
+        if key != schema::VERSION_KEY && old.get(key) != Some(value) {
+            store.set(key, value)?;
+        }
+    }
+    for key in old.keys() {
+        if !new.contains_key(key) {
+            store.remove(key)?;
+        }
+    }
+    if let Some(version) = new.get(schema::VERSION_KEY) {
+        store.set(schema::VERSION_KEY, version)?;
+    }
+    store.commit()?;
+
+    Ok(())
+}
+
+pub fn render_json() -> String {
+    format!(
+        "{{\"schema\":{},\"stored\":{},\"downgraded\":{},\"quarantined\":{}}}",
+        schema::VERSION,
+        STORED.load(Ordering::Relaxed),
+        downgraded(),
+        QUARANTINED.load(Ordering::Relaxed)
+    )
+}
+
+pub fn register_metrics() {
+    metrics::register("app_state_schema", || STORED.load(Ordering::Relaxed) as i64);
+    metrics::register("app_state_downgraded", || downgraded() as i64);
+    metrics::register("app_state_quarantined", || {
+        QUARANTINED.load(Ordering::Relaxed) as i64
+    });
+}
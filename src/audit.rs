@@ -0,0 +1,159 @@
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+use anyhow::{bail, Result};
+use hap_core::audit::{self, Entry, Log, Origin, MAX_ENTRY_LEN};
+use hap_core::Value;
+use log::{info, warn};
+use spin::{Mutex, Once};
+
+use crate::{clock, config, console, http, logging, nvs, system};
+
+const _: () = assert!(config::AUDIT_MIRROR_ENTRIES <= audit::CAPACITY);
+// One blob, so a mirror is a single NVS write however many entries are new
+const STORED_LEN: usize = 1 + config::AUDIT_MIRROR_ENTRIES * MAX_ENTRY_LEN;
+
+static LOG: Mutex<Log> = Mutex::new(Log::new());
+static STORE: Once<nvs::Namespace> = Once::new();
+/// `Log::recorded` when the entries were last mirrored
+static MIRRORED: AtomicU32 = AtomicU32::new(0);
+/// When they were last mirrored, milliseconds since boot
+static MIRRORED_MS: AtomicU64 = AtomicU64::new(0);
+
+fn store() -> Result<&'static nvs::Namespace> {
+    STORE.try_call_once(|| nvs::Namespace::open(nvs::AUDIT))
+}
+
+fn now_ms() -> u64 {
+    (unsafe { esp_idf_sys::esp_timer_get_time() } / 1000) as u64
+}
+
+fn load() -> Result<Vec<Entry>> {
+    let mut bytes = vec![0u8; STORED_LEN];
+    let Some(len) = store()?.get_blob("log", &mut bytes)? else {
+        return Ok(Vec::new());
+    };
+
+    Ok(audit::decode(&bytes[..len])?)
+}
+
+fn mirror() {
+    let (bytes, recorded) = {
+        let log = LOG.lock();
+        (log.encode(config::AUDIT_MIRROR_ENTRIES), log.recorded())
+    };
+    let stored = store().and_then(|store| {
+        store.set_blob("log", &bytes)?;
+        store.commit()?;
+        Ok(())
+    });
+
+    match stored {
+        Ok(()) => {
+            MIRRORED.store(recorded, Ordering::Relaxed);
+            MIRRORED_MS.store(now_ms(), Ordering::Relaxed);
+        }
+        Err(err) => warn!(target: logging::DIAG, "Storing the audit trail failed: {:?}", err),
+    }
+}
+
+/// Puts the entries stored before the reboot back in front.
+pub fn init() {
+    match load() {
+        Ok(stored) => {
+            info!(target: logging::DIAG, "Audit trail: {} stored entries", stored.len());
+            LOG.lock().restore(stored);
+        }
+        Err(err) => warn!(target: logging::DIAG, "Loading the audit trail failed: {:?}", err),
+    }
+    MIRRORED_MS.store(now_ms(), Ordering::Relaxed);
+
+    system::on_shutdown(|| {
+        if LOG.lock().recorded() != MIRRORED.load(Ordering::Relaxed) {
+            mirror();
+        }
+    });
+}
+
+/// Records a change of the characteristic `uuid` (NUL terminated, as the
+/// SDK takes it), applied through `origin`.
+pub fn record(uuid: &[u8], old: &Value, new: &Value, origin: Origin) {
+    let uuid = uuid.strip_suffix(b"\0").unwrap_or(uuid);
+    let at = if clock::is_synced() {
+        clock::epoch() as u32
+    } else {
+        0
+    };
+
+    LOG.lock().record(Entry {
+        at,
+        uuid: String::from_utf8_lossy(uuid).into_owned(),
+        old: audit::show(old),
+        new: audit::show(new),
+        origin,
+    });
+}
+
+/// Mirrors the latest entries if there are new ones and the last mirror is
+/// `AUDIT_MIRROR_SECS` ago; from a task that may block on flash, never
+/// from a write handler.
+pub fn mirror_if_due() {
+    if LOG.lock().recorded() == MIRRORED.load(Ordering::Relaxed) {
+        return;
+    }
+    if now_ms().saturating_sub(MIRRORED_MS.load(Ordering::Relaxed))
+        >= config::AUDIT_MIRROR_SECS * 1000
+    {
+        mirror();
+    }
+}
+
+fn time(entry: &Entry) -> String {
+    match entry.at {
+        0 => "before clock sync".into(),
+        at => clock::format_local(at as i64),
+    }
+}
+
+/// The entries, newest first.
+pub fn render_json() -> String {
+    let log = LOG.lock();
+    let entries: Vec<String> = log
+        .entries()
+        .rev()
+        .map(|entry| {
+            format!(
+                "{{\"at\":{},\"uuid\":{},\"old\":{},\"new\":{},\"origin\":\"{}\"}}",
+                entry.at,
+                http::json_string(&entry.uuid),
+                http::json_string(&entry.old),
+                http::json_string(&entry.new),
+                entry.origin.name()
+            )
+        })
+        .collect();
+
+    format!("[{}]", entries.join(","))
+}
+
+pub fn register_commands() {
+    console::register(
+        "audit",
+        "List the latest characteristic changes, newest first ('audit list [count]')",
+        |args| {
+            let count = match args {
+                ["list"] => usize::MAX,
+                ["list", count] => count.parse()?,
+                _ => bail!("usage: audit list [count]"),
+            };
+
+            let log = LOG.lock();
+            if log.is_empty() {
+                println!("No changes recorded");
+            }
+            for entry in log.entries().rev().take(count) {
+                println!("{}  {}", time(entry), entry);
+            }
+            Ok(())
+        },
+    );
+}
@@ -0,0 +1,641 @@
+// The pin map of the supported chips, and the pin handles the drivers use, so
+// the same firmware builds for the ESP32, the ESP32-C3 and the ESP32-S3.
+
+use anyhow::{bail, Result};
+use embedded_hal::digital::v2::{InputPin, OutputPin};
+use esp_idf_sys::c_types::c_void;
+use esp_idf_sys::{esp, EspError};
+
+use crate::config;
+
+#[cfg(not(any(esp32, esp32c3, esp32s3)))]
+compile_error!("board.rs has no pin map for this chip");
+
+#[cfg(esp32)]
+mod chip {
+    pub const GPIO_COUNT: i32 = 40;
+    // SPI flash
+    pub const RESERVED: &[i32] = &[6, 7, 8, 9, 10, 11];
+    // Boot mode and, for GPIO12, the flash voltage
+    pub const STRAPPING: &[i32] = &[0, 2, 12];
+    pub const INPUT_ONLY: &[i32] = &[34, 35, 36, 37, 38, 39];
+    // Pulled by the pad itself while in reset and until configured
+    pub const RESET_PULL_UP: &[i32] = &[0, 5, 15];
+    pub const RESET_PULL_DOWN: &[i32] = &[2, 12];
+
+    pub const RELAY_GPIO: i32 = 5;
+    pub const STATUS_LED_GPIO: i32 = 2;
+    pub const BUTTON_GPIO: i32 = 0;
+    pub const ENCODER_GPIO_A: i32 = 25;
+    pub const ENCODER_GPIO_B: i32 = 26;
+    pub const ENCODER_SWITCH_GPIO: i32 = 27;
+    /// TOUCH0 is GPIO4
+    pub const TOUCH_PAD: u32 = 0;
+    pub const IR_TX_GPIO: i32 = 18;
+    pub const IR_RX_GPIO: i32 = 19;
+    // Any RMT channel transmits or receives
+    pub const IR_TX_CHANNEL: esp_idf_sys::rmt_channel_t = 0;
+    pub const IR_RX_CHANNEL: esp_idf_sys::rmt_channel_t = 2;
+    pub const RELAY_UART_PORT: esp_idf_sys::uart_port_t = 1;
+    pub const RELAY_UART_TX_GPIO: i32 = 33;
+    pub const METER_UART_PORT: esp_idf_sys::uart_port_t = 2;
+    pub const METER_TX_GPIO: i32 = 17;
+    pub const METER_RX_GPIO: i32 = 16;
+    pub const METER_DE_GPIO: i32 = 23;
+    /// ADC1 channel 6 is GPIO34
+    pub const SOIL_GPIO: i32 = 34;
+    pub const SOIL_ADC_CHANNEL: esp_idf_sys::adc1_channel_t = 6;
+    pub const VALVE_GPIO: i32 = 32;
+    pub const ULTRASONIC_TRIG_GPIO: i32 = 13;
+    pub const ULTRASONIC_ECHO_GPIO: i32 = 14;
+    // Shared with the ultrasonic echo
+    pub const RELAY_FEEDBACK_GPIO: i32 = 14;
+    pub const GARAGE_OPENER_GPIO: i32 = 21;
+    pub const HAS_PCNT: bool = true;
+    /// ADC1 channel 0 is GPIO36; the encoder takes it when fitted instead
+    pub const DOOR_POT_GPIO: i32 = 36;
+    pub const DOOR_ADC_CHANNEL: esp_idf_sys::adc1_channel_t = 0;
+    pub const DOOR_ENCODER_GPIO_A: i32 = 36;
+    pub const DOOR_ENCODER_GPIO_B: i32 = 39;
+    pub const DOOR_MOTOR_IN1_GPIO: i32 = 22;
+    pub const DOOR_MOTOR_IN2_GPIO: i32 = 15;
+    // A dual-coil relay's reset coil, shared with the door motor's IN2
+    pub const RELAY_RESET_GPIO: i32 = 15;
+    // Shared with the touch pad
+    pub const DOOR_MOTOR_EN_GPIO: i32 = 4;
+    /// Input only, the edge needs an external pull-up
+    pub const DOOR_EDGE_GPIO: i32 = 35;
+    // Shared with the garage door opener
+    pub const TILT_SERVO_GPIO: i32 = 21;
+    // Shared with the IR transmitter
+    pub const BUZZER_GPIO: i32 = 18;
+    /// The PN532 on I2C; shared with the valve and the UART relay board
+    pub const RFID_I2C_PORT: esp_idf_sys::i2c_port_t = 0;
+    pub const RFID_SDA_GPIO: i32 = 32;
+    pub const RFID_SCL_GPIO: i32 = 33;
+    /// Or on VSPI, shared with the IR, the meter's DE and the door motor
+    pub const RFID_SPI_HOST: esp_idf_sys::spi_host_device_t = 2;
+    pub const RFID_SCK_GPIO: i32 = 18;
+    pub const RFID_MISO_GPIO: i32 = 19;
+    pub const RFID_MOSI_GPIO: i32 = 23;
+    pub const RFID_CS_GPIO: i32 = 22;
+    // Shared with the encoder switch
+    pub const ONEWIRE_GPIO: i32 = 27;
+}
+
+#[cfg(esp32c3)]
+mod chip {
+    pub const GPIO_COUNT: i32 = 22;
+    // SPI flash
+    pub const RESERVED: &[i32] = &[12, 13, 14, 15, 16, 17];
+    pub const STRAPPING: &[i32] = &[2, 8, 9];
+    pub const INPUT_ONLY: &[i32] = &[];
+    // Pulled by the pad itself while in reset and until configured
+    pub const RESET_PULL_UP: &[i32] = &[9];
+    pub const RESET_PULL_DOWN: &[i32] = &[];
+
+    pub const RELAY_GPIO: i32 = 5;
+    pub const STATUS_LED_GPIO: i32 = 2;
+    /// The BOOT button of the dev boards
+    pub const BUTTON_GPIO: i32 = 9;
+    pub const ENCODER_GPIO_A: i32 = 6;
+    pub const ENCODER_GPIO_B: i32 = 7;
+    pub const ENCODER_SWITCH_GPIO: i32 = 4;
+    /// No touch sensor
+    pub const TOUCH_PAD: u32 = 0;
+    pub const IR_TX_GPIO: i32 = 10;
+    pub const IR_RX_GPIO: i32 = 3;
+    // Channels 0-1 only transmit, 2-3 only receive
+    pub const IR_TX_CHANNEL: esp_idf_sys::rmt_channel_t = 0;
+    pub const IR_RX_CHANNEL: esp_idf_sys::rmt_channel_t = 2;
+    // A single spare UART, shared by the relay board and the meter
+    pub const RELAY_UART_PORT: esp_idf_sys::uart_port_t = 1;
+    pub const RELAY_UART_TX_GPIO: i32 = 21;
+    pub const METER_UART_PORT: esp_idf_sys::uart_port_t = 1;
+    pub const METER_TX_GPIO: i32 = 21;
+    pub const METER_RX_GPIO: i32 = 20;
+    pub const METER_DE_GPIO: i32 = 1;
+    /// ADC1 channel 0 is GPIO0
+    pub const SOIL_GPIO: i32 = 0;
+    pub const SOIL_ADC_CHANNEL: esp_idf_sys::adc1_channel_t = 0;
+    pub const VALVE_GPIO: i32 = 18;
+    pub const ULTRASONIC_TRIG_GPIO: i32 = 19;
+    // Shared with the encoder switch, the two exclude each other
+    pub const ULTRASONIC_ECHO_GPIO: i32 = 4;
+    // Shared with the encoder switch and the ultrasonic echo
+    pub const RELAY_FEEDBACK_GPIO: i32 = 4;
+    // Shared with the IR transmitter
+    pub const GARAGE_OPENER_GPIO: i32 = 10;
+    /// No PCNT unit, the door needs the potentiometer
+    pub const HAS_PCNT: bool = false;
+    /// ADC1 channel 0 is GPIO0, shared with the soil probe
+    pub const DOOR_POT_GPIO: i32 = 0;
+    pub const DOOR_ADC_CHANNEL: esp_idf_sys::adc1_channel_t = 0;
+    pub const DOOR_ENCODER_GPIO_A: i32 = -1;
+    pub const DOOR_ENCODER_GPIO_B: i32 = -1;
+    // Shared with the rotary encoder
+    pub const DOOR_MOTOR_IN1_GPIO: i32 = 6;
+    pub const DOOR_MOTOR_IN2_GPIO: i32 = 7;
+    // A dual-coil relay's reset coil, shared with the door motor's IN2 and
+    // the rotary encoder
+    pub const RELAY_RESET_GPIO: i32 = 7;
+    // Shared with the ultrasonic trigger
+    pub const DOOR_MOTOR_EN_GPIO: i32 = 19;
+    // Shared with the IR receiver
+    pub const DOOR_EDGE_GPIO: i32 = 3;
+    // Shared with the meter's DE
+    pub const TILT_SERVO_GPIO: i32 = 1;
+    // Shared with the IR transmitter and the garage door opener
+    pub const BUZZER_GPIO: i32 = 10;
+    /// The PN532 on I2C; shared with the valve and the meter's DE
+    pub const RFID_I2C_PORT: esp_idf_sys::i2c_port_t = 0;
+    pub const RFID_SDA_GPIO: i32 = 18;
+    pub const RFID_SCL_GPIO: i32 = 1;
+    /// Or on SPI2, shared with the rotary encoder, the IR receiver and the valve
+    pub const RFID_SPI_HOST: esp_idf_sys::spi_host_device_t = 1;
+    pub const RFID_SCK_GPIO: i32 = 6;
+    pub const RFID_MISO_GPIO: i32 = 3;
+    pub const RFID_MOSI_GPIO: i32 = 7;
+    pub const RFID_CS_GPIO: i32 = 18;
+    /// Strapping, but the bus's pull-up holds it high as booting needs
+    pub const ONEWIRE_GPIO: i32 = 8;
+}
+
+#[cfg(esp32s3)]
+mod chip {
+    pub const GPIO_COUNT: i32 = 49;
+    // SPI flash; octal PSRAM modules also take 33-37
+    pub const RESERVED: &[i32] = &[26, 27, 28, 29, 30, 31, 32];
+    pub const STRAPPING: &[i32] = &[0, 3, 45, 46];
+    pub const INPUT_ONLY: &[i32] = &[];
+    // Pulled by the pad itself while in reset and until configured
+    pub const RESET_PULL_UP: &[i32] = &[0];
+    pub const RESET_PULL_DOWN: &[i32] = &[45, 46];
+
+    pub const RELAY_GPIO: i32 = 5;
+    pub const STATUS_LED_GPIO: i32 = 2;
+    pub const BUTTON_GPIO: i32 = 0;
+    pub const ENCODER_GPIO_A: i32 = 6;
+    pub const ENCODER_GPIO_B: i32 = 7;
+    pub const ENCODER_SWITCH_GPIO: i32 = 15;
+    /// No touch support in the driver yet, the S3 sensor counts the other way
+    pub const TOUCH_PAD: u32 = 0;
+    pub const IR_TX_GPIO: i32 = 10;
+    pub const IR_RX_GPIO: i32 = 11;
+    // Channels 0-3 only transmit, 4-7 only receive
+    pub const IR_TX_CHANNEL: esp_idf_sys::rmt_channel_t = 0;
+    pub const IR_RX_CHANNEL: esp_idf_sys::rmt_channel_t = 4;
+    pub const RELAY_UART_PORT: esp_idf_sys::uart_port_t = 1;
+    pub const RELAY_UART_TX_GPIO: i32 = 21;
+    pub const METER_UART_PORT: esp_idf_sys::uart_port_t = 2;
+    pub const METER_TX_GPIO: i32 = 17;
+    pub const METER_RX_GPIO: i32 = 18;
+    pub const METER_DE_GPIO: i32 = 16;
+    /// ADC1 channel 3 is GPIO4
+    pub const SOIL_GPIO: i32 = 4;
+    pub const SOIL_ADC_CHANNEL: esp_idf_sys::adc1_channel_t = 3;
+    pub const VALVE_GPIO: i32 = 38;
+    pub const ULTRASONIC_TRIG_GPIO: i32 = 12;
+    pub const ULTRASONIC_ECHO_GPIO: i32 = 13;
+    // Shared with the ultrasonic echo
+    pub const RELAY_FEEDBACK_GPIO: i32 = 13;
+    pub const GARAGE_OPENER_GPIO: i32 = 14;
+    pub const HAS_PCNT: bool = true;
+    /// ADC1 channel 0 is GPIO1
+    pub const DOOR_POT_GPIO: i32 = 1;
+    pub const DOOR_ADC_CHANNEL: esp_idf_sys::adc1_channel_t = 0;
+    pub const DOOR_ENCODER_GPIO_A: i32 = 8;
+    pub const DOOR_ENCODER_GPIO_B: i32 = 9;
+    pub const DOOR_MOTOR_IN1_GPIO: i32 = 39;
+    pub const DOOR_MOTOR_IN2_GPIO: i32 = 40;
+    // A dual-coil relay's reset coil, shared with the door motor's IN2
+    pub const RELAY_RESET_GPIO: i32 = 40;
+    pub const DOOR_MOTOR_EN_GPIO: i32 = 41;
+    pub const DOOR_EDGE_GPIO: i32 = 42;
+    pub const TILT_SERVO_GPIO: i32 = 47;
+    pub const BUZZER_GPIO: i32 = 48;
+    /// The PN532 on I2C; shared with the door encoder
+    pub const RFID_I2C_PORT: esp_idf_sys::i2c_port_t = 0;
+    pub const RFID_SDA_GPIO: i32 = 8;
+    pub const RFID_SCL_GPIO: i32 = 9;
+    /// Or on SPI2, shared with the IR and the meter's UART
+    pub const RFID_SPI_HOST: esp_idf_sys::spi_host_device_t = 1;
+    pub const RFID_SCK_GPIO: i32 = 10;
+    pub const RFID_MISO_GPIO: i32 = 17;
+    pub const RFID_MOSI_GPIO: i32 = 11;
+    pub const RFID_CS_GPIO: i32 = 18;
+    /// Taken by modules with octal PSRAM
+    pub const ONEWIRE_GPIO: i32 = 33;
+}
+
+pub use chip::*;
+
+// Active-low wake input, -1 for timer wake-ups only
+pub const SLEEP_WAKE_GPIO: i32 = -1;
+
+const fn contains(pins: &[i32], gpio: i32) -> bool {
+    let mut i = 0;
+    while i < pins.len() {
+        if pins[i] == gpio {
+            return true;
+        }
+        i += 1;
+    }
+
+    false
+}
+
+const fn usable(gpio: i32) -> bool {
+    gpio >= 0 && gpio < GPIO_COUNT && !contains(RESERVED, gpio)
+}
+
+// Held at a level during reset, these would change the boot mode
+const fn drivable(gpio: i32) -> bool {
+    usable(gpio) && !contains(STRAPPING, gpio) && !contains(INPUT_ONLY, gpio)
+}
+
+const RFID_PINS: [i32; 4] = match config::RFID_BUS {
+    config::RfidBus::I2c => [RFID_SDA_GPIO, RFID_SCL_GPIO, -1, -1],
+    config::RfidBus::Spi => [RFID_SCK_GPIO, RFID_MISO_GPIO, RFID_MOSI_GPIO, RFID_CS_GPIO],
+};
+
+// Whether the RFID reader needs `gpio` while `enabled` needs it too
+const fn rfid_conflict(enabled: bool, gpio: i32) -> bool {
+    config::RFID_ENABLED && enabled && gpio >= 0 && contains(&RFID_PINS, gpio)
+}
+
+const _: () = {
+    assert!(drivable(RELAY_GPIO), "relay GPIO");
+    assert!(
+        !matches!(config::RELAY_MODE, config::RelayMode::DualCoil)
+            || (drivable(RELAY_RESET_GPIO) && RELAY_RESET_GPIO != RELAY_GPIO),
+        "relay reset coil GPIO"
+    );
+    assert!(
+        !(matches!(config::RELAY_MODE, config::RelayMode::DualCoil)
+            && (config::DOOR_ENABLED
+                || (config::ENCODER_ENABLED
+                    && (RELAY_RESET_GPIO == ENCODER_GPIO_A
+                        || RELAY_RESET_GPIO == ENCODER_GPIO_B)))),
+        "the relay's reset coil needs the GPIO of another peripheral"
+    );
+    assert!(
+        !config::RELAY_FEEDBACK_ENABLED || usable(RELAY_FEEDBACK_GPIO),
+        "relay feedback GPIO"
+    );
+    assert!(
+        usable(STATUS_LED_GPIO) && !contains(INPUT_ONLY, STATUS_LED_GPIO),
+        "LED GPIO"
+    );
+    assert!(usable(BUTTON_GPIO), "button GPIO");
+    assert!(
+        !config::ENCODER_ENABLED || usable(ENCODER_GPIO_A),
+        "encoder GPIO"
+    );
+    assert!(
+        !config::ENCODER_ENABLED || usable(ENCODER_GPIO_B),
+        "encoder GPIO"
+    );
+    assert!(
+        !config::ENCODER_ENABLED || usable(ENCODER_SWITCH_GPIO),
+        "encoder GPIO"
+    );
+    assert!(
+        !config::IR_ENABLED || drivable(IR_TX_GPIO),
+        "IR transmitter GPIO"
+    );
+    assert!(
+        !config::IR_ENABLED || usable(IR_RX_GPIO),
+        "IR receiver GPIO"
+    );
+    assert!(
+        !config::RELAY_UART_ENABLED || drivable(RELAY_UART_TX_GPIO),
+        "relay UART GPIO"
+    );
+    assert!(
+        !config::METER_ENABLED || drivable(METER_TX_GPIO),
+        "meter UART GPIO"
+    );
+    assert!(
+        !config::METER_ENABLED || usable(METER_RX_GPIO),
+        "meter UART GPIO"
+    );
+    assert!(
+        !config::METER_ENABLED || drivable(METER_DE_GPIO),
+        "meter DE GPIO"
+    );
+    assert!(
+        !config::IRRIGATION_ENABLED || usable(SOIL_GPIO),
+        "soil probe GPIO"
+    );
+    assert!(
+        !config::IRRIGATION_ENABLED || drivable(VALVE_GPIO),
+        "valve GPIO"
+    );
+    assert!(
+        !config::DISTANCE_ENABLED || drivable(ULTRASONIC_TRIG_GPIO),
+        "ultrasonic trigger GPIO"
+    );
+    assert!(
+        !config::DISTANCE_ENABLED || usable(ULTRASONIC_ECHO_GPIO),
+        "ultrasonic echo GPIO"
+    );
+    assert!(
+        !config::GARAGE_DOOR_ENABLED || drivable(GARAGE_OPENER_GPIO),
+        "garage door opener GPIO"
+    );
+    assert!(
+        !(config::DISTANCE_ENABLED
+            && config::ENCODER_ENABLED
+            && ULTRASONIC_ECHO_GPIO == ENCODER_SWITCH_GPIO),
+        "the ultrasonic sensor and the encoder switch need the same GPIO"
+    );
+    assert!(
+        !(config::GARAGE_DOOR_ENABLED && config::IR_ENABLED && GARAGE_OPENER_GPIO == IR_TX_GPIO),
+        "the garage door opener and the IR transmitter need the same GPIO"
+    );
+    assert!(
+        !(config::DOOR_ENABLED && matches!(config::DOOR_SENSOR, config::DoorSensor::Pot))
+            || usable(DOOR_POT_GPIO),
+        "door potentiometer GPIO"
+    );
+    assert!(
+        !(config::DOOR_ENABLED && matches!(config::DOOR_SENSOR, config::DoorSensor::Encoder))
+            || (HAS_PCNT && usable(DOOR_ENCODER_GPIO_A) && usable(DOOR_ENCODER_GPIO_B)),
+        "door encoder GPIO, or no PCNT unit for the door encoder"
+    );
+    assert!(
+        !config::DOOR_ENABLED
+            || (drivable(DOOR_MOTOR_IN1_GPIO)
+                && drivable(DOOR_MOTOR_IN2_GPIO)
+                && drivable(DOOR_MOTOR_EN_GPIO)),
+        "door motor GPIO"
+    );
+    assert!(
+        !config::DOOR_ENABLED || usable(DOOR_EDGE_GPIO),
+        "door edge GPIO"
+    );
+    assert!(
+        !(config::DOOR_ENABLED && config::TOUCH_ENABLED && DOOR_MOTOR_EN_GPIO == 4),
+        "the door motor and the touch pad need the same GPIO"
+    );
+    assert!(
+        !(config::DOOR_ENABLED
+            && config::IRRIGATION_ENABLED
+            && matches!(config::DOOR_SENSOR, config::DoorSensor::Pot)
+            && DOOR_POT_GPIO == SOIL_GPIO),
+        "the door potentiometer and the soil probe need the same GPIO"
+    );
+    assert!(
+        !(config::DOOR_ENABLED
+            && config::ENCODER_ENABLED
+            && (DOOR_MOTOR_IN1_GPIO == ENCODER_GPIO_A || DOOR_MOTOR_IN2_GPIO == ENCODER_GPIO_B)),
+        "the door motor and the rotary encoder need the same GPIO"
+    );
+    assert!(
+        !(config::DOOR_ENABLED
+            && config::DISTANCE_ENABLED
+            && DOOR_MOTOR_EN_GPIO == ULTRASONIC_TRIG_GPIO),
+        "the door motor and the ultrasonic sensor need the same GPIO"
+    );
+    assert!(
+        !(config::DOOR_ENABLED && config::IR_ENABLED && DOOR_EDGE_GPIO == IR_RX_GPIO),
+        "the door edge and the IR receiver need the same GPIO"
+    );
+    assert!(
+        !(config::DOOR_ENABLED && matches!(config::DOOR_TILT, config::TiltSource::Servo))
+            || drivable(TILT_SERVO_GPIO),
+        "tilt servo GPIO"
+    );
+    assert!(
+        !(config::DOOR_ENABLED
+            && matches!(config::DOOR_TILT, config::TiltSource::Servo)
+            && ((config::GARAGE_DOOR_ENABLED && TILT_SERVO_GPIO == GARAGE_OPENER_GPIO)
+                || (config::METER_ENABLED && TILT_SERVO_GPIO == METER_DE_GPIO))),
+        "the tilt servo needs the GPIO of another peripheral"
+    );
+    assert!(
+        !config::BUZZER_ENABLED || drivable(BUZZER_GPIO),
+        "buzzer GPIO"
+    );
+    assert!(
+        !(config::BUZZER_ENABLED
+            && ((config::IR_ENABLED && BUZZER_GPIO == IR_TX_GPIO)
+                || (config::GARAGE_DOOR_ENABLED && BUZZER_GPIO == GARAGE_OPENER_GPIO))),
+        "the buzzer needs the GPIO of another peripheral"
+    );
+    assert!(
+        !config::RFID_ENABLED
+            || match config::RFID_BUS {
+                config::RfidBus::I2c => drivable(RFID_SDA_GPIO) && drivable(RFID_SCL_GPIO),
+                config::RfidBus::Spi => {
+                    drivable(RFID_SCK_GPIO)
+                        && usable(RFID_MISO_GPIO)
+                        && drivable(RFID_MOSI_GPIO)
+                        && drivable(RFID_CS_GPIO)
+                }
+            },
+        "RFID GPIO"
+    );
+    assert!(
+        !(rfid_conflict(config::IRRIGATION_ENABLED, VALVE_GPIO)
+            || rfid_conflict(config::RELAY_UART_ENABLED, RELAY_UART_TX_GPIO)
+            || rfid_conflict(config::IR_ENABLED, IR_TX_GPIO)
+            || rfid_conflict(config::IR_ENABLED, IR_RX_GPIO)
+            || rfid_conflict(config::BUZZER_ENABLED, BUZZER_GPIO)
+            || rfid_conflict(config::METER_ENABLED, METER_TX_GPIO)
+            || rfid_conflict(config::METER_ENABLED, METER_RX_GPIO)
+            || rfid_conflict(config::METER_ENABLED, METER_DE_GPIO)
+            || rfid_conflict(config::ENCODER_ENABLED, ENCODER_GPIO_A)
+            || rfid_conflict(config::ENCODER_ENABLED, ENCODER_GPIO_B)
+            || rfid_conflict(config::ENCODER_ENABLED, ENCODER_SWITCH_GPIO)
+            || rfid_conflict(config::DISTANCE_ENABLED, ULTRASONIC_TRIG_GPIO)
+            || rfid_conflict(config::DISTANCE_ENABLED, ULTRASONIC_ECHO_GPIO)
+            || rfid_conflict(config::GARAGE_DOOR_ENABLED, GARAGE_OPENER_GPIO)
+            || rfid_conflict(config::DOOR_ENABLED, DOOR_MOTOR_IN1_GPIO)
+            || rfid_conflict(config::DOOR_ENABLED, DOOR_MOTOR_IN2_GPIO)
+            || rfid_conflict(config::DOOR_ENABLED, DOOR_MOTOR_EN_GPIO)
+            || rfid_conflict(config::DOOR_ENABLED, DOOR_EDGE_GPIO)
+            || rfid_conflict(
+                config::DOOR_ENABLED && matches!(config::DOOR_TILT, config::TiltSource::Servo),
+                TILT_SERVO_GPIO
+            )
+            || rfid_conflict(config::IRRIGATION_ENABLED, SOIL_GPIO)
+            || rfid_conflict(config::TEMP_ENABLED, ONEWIRE_GPIO)
+            || rfid_conflict(config::RELAY_FEEDBACK_ENABLED, RELAY_FEEDBACK_GPIO)
+            || rfid_conflict(
+                matches!(config::RELAY_MODE, config::RelayMode::DualCoil),
+                RELAY_RESET_GPIO
+            )
+            || rfid_conflict(
+                config::DOOR_ENABLED && matches!(config::DOOR_SENSOR, config::DoorSensor::Encoder),
+                DOOR_ENCODER_GPIO_A
+            )
+            || rfid_conflict(
+                config::DOOR_ENABLED && matches!(config::DOOR_SENSOR, config::DoorSensor::Encoder),
+                DOOR_ENCODER_GPIO_B
+            )),
+        "the RFID reader needs the GPIO of another peripheral"
+    );
+    assert!(
+        !config::TEMP_ENABLED || (usable(ONEWIRE_GPIO) && !contains(INPUT_ONLY, ONEWIRE_GPIO)),
+        "1-Wire GPIO"
+    );
+    assert!(
+        !(config::TEMP_ENABLED && config::ENCODER_ENABLED && ONEWIRE_GPIO == ENCODER_SWITCH_GPIO),
+        "the 1-Wire bus and the encoder switch need the same GPIO"
+    );
+    assert!(SLEEP_WAKE_GPIO < 0 || usable(SLEEP_WAKE_GPIO), "wake GPIO");
+    assert!(
+        !(config::METER_ENABLED
+            && config::RELAY_UART_ENABLED
+            && METER_UART_PORT == RELAY_UART_PORT),
+        "the energy meter and the UART relay backend need the same UART"
+    );
+};
+
+#[derive(Clone, Copy)]
+pub enum Pull {
+    Floating,
+    Up,
+    Down,
+}
+
+impl Pull {
+    pub fn name(self) -> &'static str {
+        match self {
+            Pull::Floating => "floating",
+            Pull::Up => "pull-up",
+            Pull::Down => "pull-down",
+        }
+    }
+}
+
+/// What the pad does by itself from reset until the firmware configures it,
+/// which no software can change: only an external resistor overrides it.
+pub const fn reset_pull(gpio: i32) -> Pull {
+    if contains(RESET_PULL_UP, gpio) {
+        Pull::Up
+    } else if contains(RESET_PULL_DOWN, gpio) {
+        Pull::Down
+    } else {
+        Pull::Floating
+    }
+}
+
+/// A push-pull output by GPIO number, whatever the chip.
+pub struct AnyOutputPin {
+    gpio: i32,
+}
+
+impl AnyOutputPin {
+    /// With the input enabled as well, so the self-test can read the pad back.
+    pub fn new(gpio: i32) -> Result<Self, EspError> {
+        esp!(unsafe { esp_idf_sys::gpio_reset_pin(gpio) })?;
+        esp!(unsafe {
+            esp_idf_sys::gpio_set_direction(gpio, esp_idf_sys::gpio_mode_t_GPIO_MODE_INPUT_OUTPUT)
+        })?;
+
+        Ok(Self { gpio })
+    }
+
+    /// Like `new`, but at `level` from the moment it drives and pulled
+    /// towards it before: `gpio_reset_pin` would enable the pull-up and the
+    /// output would start low, either of which can pulse a relay.
+    pub fn with_level(gpio: i32, level: bool) -> Result<Self, EspError> {
+        let pull = if level {
+            esp_idf_sys::gpio_pull_mode_t_GPIO_PULLUP_ONLY
+        } else {
+            esp_idf_sys::gpio_pull_mode_t_GPIO_PULLDOWN_ONLY
+        };
+        unsafe {
+            // Still held from before a deep sleep
+            esp!(esp_idf_sys::gpio_hold_dis(gpio))?;
+            esp_idf_sys::esp_rom_gpio_pad_select_gpio(gpio as u32);
+            esp!(esp_idf_sys::gpio_set_pull_mode(gpio, pull))?;
+            esp!(esp_idf_sys::gpio_set_level(gpio, level as u32))?;
+            esp!(esp_idf_sys::gpio_set_direction(
+                gpio,
+                esp_idf_sys::gpio_mode_t_GPIO_MODE_INPUT_OUTPUT
+            ))?;
+        }
+
+        Ok(Self { gpio })
+    }
+}
+
+/// Latches the pad at the level it drives now, through deep sleep and the
+/// wake-up until `AnyOutputPin::with_level` releases it.
+pub fn hold(gpio: i32) -> Result<(), EspError> {
+    esp!(unsafe { esp_idf_sys::gpio_hold_en(gpio) })?;
+    unsafe { esp_idf_sys::gpio_deep_sleep_hold_en() };
+
+    Ok(())
+}
+
+impl OutputPin for AnyOutputPin {
+    type Error = EspError;
+
+    fn set_low(&mut self) -> Result<(), EspError> {
+        esp!(unsafe { esp_idf_sys::gpio_set_level(self.gpio, 0) })
+    }
+
+    fn set_high(&mut self) -> Result<(), EspError> {
+        esp!(unsafe { esp_idf_sys::gpio_set_level(self.gpio, 1) })
+    }
+}
+
+/// An input by GPIO number, whatever the chip.
+pub struct AnyInputPin {
+    gpio: i32,
+}
+
+impl AnyInputPin {
+    pub fn new(gpio: i32, pull: Pull) -> Result<Self, EspError> {
+        let pull = match pull {
+            Pull::Floating => esp_idf_sys::gpio_pull_mode_t_GPIO_FLOATING,
+            Pull::Up => esp_idf_sys::gpio_pull_mode_t_GPIO_PULLUP_ONLY,
+            Pull::Down => esp_idf_sys::gpio_pull_mode_t_GPIO_PULLDOWN_ONLY,
+        };
+
+        esp!(unsafe { esp_idf_sys::gpio_reset_pin(gpio) })?;
+        esp!(unsafe {
+            esp_idf_sys::gpio_set_direction(gpio, esp_idf_sys::gpio_mode_t_GPIO_MODE_INPUT)
+        })?;
+        esp!(unsafe { esp_idf_sys::gpio_set_pull_mode(gpio, pull) })?;
+
+        Ok(Self { gpio })
+    }
+
+    /// Calls `handler` with `arg` from the GPIO ISR service on both edges.
+    pub fn on_edges(
+        &self,
+        handler: unsafe extern "C" fn(*mut c_void),
+        arg: *mut c_void,
+    ) -> Result<()> {
+        let err = unsafe {
+            esp_idf_sys::gpio_set_intr_type(
+                self.gpio,
+                esp_idf_sys::gpio_int_type_t_GPIO_INTR_ANYEDGE,
+            );
+            esp_idf_sys::gpio_isr_handler_add(self.gpio, Some(handler), arg)
+        };
+        if err != esp_idf_sys::ESP_OK {
+            bail!("installing the ISR on GPIO{} failed: {}", self.gpio, err);
+        }
+
+        Ok(())
+    }
+}
+
+impl InputPin for AnyInputPin {
+    type Error = EspError;
+
+    fn is_high(&self) -> Result<bool, EspError> {
+        Ok(unsafe { esp_idf_sys::gpio_get_level(self.gpio) } != 0)
+    }
+
+    fn is_low(&self) -> Result<bool, EspError> {
+        Ok(unsafe { esp_idf_sys::gpio_get_level(self.gpio) } == 0)
+    }
+}
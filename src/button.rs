@@ -0,0 +1,149 @@
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use esp_idf_sys::c_types::c_void;
+use log::{debug, info};
+use spin::Mutex;
+
+pub use crate::board::Pull;
+use crate::board::{self, AnyInputPin};
+use crate::{config, event_bus, logging, tasks, wdt};
+pub use hap_core::classifier::{Classifier, Event, Timing};
+
+const POLL_MS: u64 = 10;
+
+pub struct ButtonConfig {
+    pub name: &'static str,
+    pub gpio: i32,
+    pub active_high: bool,
+    pub pull: Pull,
+    pub timing: Timing,
+}
+
+/// The BOOT button of the dev boards, active low with the internal pull-up.
+pub const BOOT: ButtonConfig = ButtonConfig {
+    name: "boot",
+    gpio: board::BUTTON_GPIO,
+    active_high: false,
+    pull: Pull::Up,
+    timing: Timing {
+        debounce_ms: config::BUTTON_DEBOUNCE_MS,
+        long_press_ms: config::BUTTON_LONG_PRESS_MS,
+        double_click_ms: config::BUTTON_DOUBLE_CLICK_MS,
+    },
+};
+
+type Listener = &'static (dyn Fn(&'static str, Event) + Send + Sync);
+
+static CLASSIFIERS: Mutex<Vec<(&'static ButtonConfig, Classifier)>> = Mutex::new(Vec::new());
+static LISTENERS: Mutex<Vec<Listener>> = Mutex::new(Vec::new());
+
+/// Registers a listener for the events of every button; listeners run on the
+/// button task and should hand longer work off elsewhere.
+pub fn subscribe(listener: impl Fn(&'static str, Event) + Send + Sync + 'static) {
+    LISTENERS.lock().push(Box::leak(Box::new(listener)));
+}
+
+/// Hands an event to every listener; inputs other than GPIO buttons, like
+/// touch pads, report their classified events through here.
+pub fn dispatch(name: &'static str, event: Event) {
+    debug!(target: logging::BUTTON, "Button {}: {:?}", name, event);
+
+    let listeners = LISTENERS.lock().clone();
+    for listener in listeners {
+        listener(name, event);
+    }
+}
+
+fn now_ms() -> u64 {
+    (unsafe { esp_idf_sys::esp_timer_get_time() } / 1000) as u64
+}
+
+fn is_pressed(button: &ButtonConfig) -> bool {
+    let level = unsafe { esp_idf_sys::gpio_get_level(button.gpio) } != 0;
+    level == button.active_high
+}
+
+unsafe extern "C" fn on_edge(arg: *mut c_void) {
+    let gpio = arg as i32;
+
+    // A full queue drops the oldest edge, the task's level sampling recovers
+    event_bus::publish_from_isr(event_bus::Event::Edge {
+        gpio,
+        level: esp_idf_sys::gpio_get_level(gpio) != 0,
+        at_us: esp_idf_sys::esp_timer_get_time(),
+    });
+}
+
+fn on_bus_event(event: &event_bus::Event) {
+    let event_bus::Event::Edge { gpio, level, at_us } = *event else {
+        return;
+    };
+
+    let mut classifiers = CLASSIFIERS.lock();
+    if let Some((button, classifier)) = classifiers.iter_mut().find(|(b, _)| b.gpio == gpio) {
+        classifier.edge(level == button.active_high, (at_us / 1000) as u64);
+    }
+}
+
+fn configure(button: &ButtonConfig) -> Result<()> {
+    let pin = AnyInputPin::new(button.gpio, button.pull)?;
+    pin.on_edges(on_edge, button.gpio as *mut c_void)
+        .with_context(|| format!("button {}", button.name))
+}
+
+/// Installs the per-pin GPIO ISR dispatcher, shared by all input drivers.
+pub fn install_isr_service() -> Result<()> {
+    // An already installed service is fine
+    let err = unsafe { esp_idf_sys::gpio_install_isr_service(0) };
+    if err != esp_idf_sys::ESP_OK && err != esp_idf_sys::ESP_ERR_INVALID_STATE {
+        bail!("installing the GPIO ISR service failed: {}", err);
+    }
+
+    Ok(())
+}
+
+/// Configures the button pins and starts the classifier task. The edges
+/// arrive through the event bus, which has to be running.
+pub fn init(buttons: &'static [ButtonConfig]) -> Result<()> {
+    *CLASSIFIERS.lock() = buttons
+        .iter()
+        .map(|button| (button, Classifier::new(button.timing)))
+        .collect();
+    event_bus::subscribe(on_bus_event);
+
+    install_isr_service()?;
+
+    for button in buttons {
+        configure(button)?;
+        info!(
+            target: logging::BUTTON,
+            "Button {} on GPIO{}, active {}",
+            button.name,
+            button.gpio,
+            if button.active_high { "high" } else { "low" }
+        );
+    }
+
+    tasks::spawn(&tasks::BUTTON, button_handler)
+}
+
+fn button_handler() {
+    let watchdog = wdt::subscribe(tasks::BUTTON.name);
+    let mut events = Vec::new();
+
+    loop {
+        watchdog.sleep(Duration::from_millis(POLL_MS));
+
+        // Catch up on edges lost to a full queue and advance time
+        let now = now_ms();
+        for (button, classifier) in CLASSIFIERS.lock().iter_mut() {
+            classifier.edge(is_pressed(button), now);
+            classifier.poll(now, &mut |event| events.push((button.name, event)));
+        }
+
+        for (name, event) in events.drain(..) {
+            dispatch(name, event);
+        }
+    }
+}
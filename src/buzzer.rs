@@ -0,0 +1,224 @@
+use std::ptr;
+
+use anyhow::{bail, Result};
+use esp_idf_sys::esp;
+use hap_core::chime::{self, Class, Pattern, Player};
+use hap_core::sys::perm;
+use hap_core::{CharSlot, ServiceBuilder, Status, Value, Write};
+use log::{info, warn};
+use spin::{Mutex, Once};
+
+use crate::hap_sys::HAP;
+use crate::{app_state, board, config, console, logging, nvs};
+
+// Custom UUIDs, the SDK keeps the pointers so they have to be 'static
+const SERVICE_UUID: &[u8] = b"0000D7A0-28E5-4C3F-9B6E-5A1D7E3C9000\0";
+const MUTE_UUID: &[u8] = b"0000D7A1-28E5-4C3F-9B6E-5A1D7E3C9000\0";
+
+const TIMER: esp_idf_sys::ledc_timer_t = esp_idf_sys::ledc_timer_t_LEDC_TIMER_2;
+const CHANNEL: esp_idf_sys::ledc_channel_t = esp_idf_sys::ledc_channel_t_LEDC_CHANNEL_2;
+const MODE: esp_idf_sys::ledc_mode_t = esp_idf_sys::ledc_mode_t_LEDC_LOW_SPEED_MODE;
+const BITS: u32 = 10;
+// Square wave, the loudest a piezo gets
+const DUTY: u32 = 1 << (BITS - 1);
+// Any tone to start the timer with, the first note sets its own
+const IDLE_HZ: u32 = 1000;
+
+struct State {
+    player: Player,
+    /// The tone sounding, 0 for none
+    hz: u16,
+}
+
+static STATE: Mutex<State> = Mutex::new(State {
+    player: Player::new(),
+    hz: 0,
+});
+static STORE: Once<nvs::Namespace> = Once::new();
+static STARTED: Once<()> = Once::new();
+
+static MUTE_CHAR: CharSlot = CharSlot::new();
+
+fn store() -> Result<&'static nvs::Namespace> {
+    STORE.try_call_once(|| nvs::Namespace::open(app_state::namespace()))
+}
+
+fn now_ms() -> u64 {
+    (unsafe { esp_idf_sys::esp_timer_get_time() } / 1000) as u64
+}
+
+fn sound(hz: u16) -> Result<(), esp_idf_sys::EspError> {
+    if hz == 0 {
+        esp!(unsafe { esp_idf_sys::ledc_set_duty(MODE, CHANNEL, 0) })?;
+    } else {
+        esp!(unsafe { esp_idf_sys::ledc_set_freq(MODE, TIMER, hz as u32) })?;
+        esp!(unsafe { esp_idf_sys::ledc_set_duty(MODE, CHANNEL, DUTY) })?;
+    }
+    esp!(unsafe { esp_idf_sys::ledc_update_duty(MODE, CHANNEL) })
+}
+
+unsafe extern "C" fn on_tick(_: *mut esp_idf_sys::c_types::c_void) {
+    let mut state = STATE.lock();
+    let hz = state.player.tick(now_ms());
+    if hz != state.hz && sound(hz).is_ok() {
+        state.hz = hz;
+    }
+}
+
+fn init_ledc() -> Result<()> {
+    let mut timer: esp_idf_sys::ledc_timer_config_t = unsafe { std::mem::zeroed() };
+    timer.speed_mode = MODE;
+    timer.timer_num = TIMER;
+    timer.freq_hz = IDLE_HZ;
+    timer.clk_cfg = esp_idf_sys::ledc_clk_cfg_t_LEDC_AUTO_CLK;
+    timer.__bindgen_anon_1.duty_resolution = BITS;
+    esp!(unsafe { esp_idf_sys::ledc_timer_config(&timer) })?;
+
+    let mut channel: esp_idf_sys::ledc_channel_config_t = unsafe { std::mem::zeroed() };
+    channel.gpio_num = board::BUZZER_GPIO;
+    channel.speed_mode = MODE;
+    channel.channel = CHANNEL;
+    channel.timer_sel = TIMER;
+    channel.duty = 0;
+    esp!(unsafe { esp_idf_sys::ledc_channel_config(&channel) })?;
+
+    Ok(())
+}
+
+/// Sets up the buzzer's LEDC channel, silent, and the timer that plays the
+/// patterns, so playing never blocks the caller.
+pub fn init() -> Result<()> {
+    init_ledc()?;
+
+    match store().and_then(|store| Ok(store.get_u8("mute")?)) {
+        Ok(muted) => STATE.lock().player.set_muted(muted == Some(1)),
+        Err(err) => warn!(target: logging::BUZZER, "Loading the mute setting failed: {:?}", err),
+    }
+
+    let args = esp_idf_sys::esp_timer_create_args_t {
+        callback: Some(on_tick),
+        arg: ptr::null_mut(),
+        dispatch_method: esp_idf_sys::esp_timer_dispatch_t_ESP_TIMER_TASK,
+        name: b"buzzer\0".as_ptr() as _,
+        skip_unhandled_events: true,
+    };
+    let mut timer: esp_idf_sys::esp_timer_handle_t = ptr::null_mut();
+    esp!(unsafe { esp_idf_sys::esp_timer_create(&args, &mut timer) })?;
+    esp!(unsafe { esp_idf_sys::esp_timer_start_periodic(timer, config::BUZZER_TICK_MS * 1000) })?;
+    STARTED.call_once(|| ());
+    info!(target: logging::BUZZER, "Buzzer on GPIO{}", board::BUZZER_GPIO);
+
+    Ok(())
+}
+
+/// Plays `pattern` from the next tick unless muted or something more
+/// important plays; a no-op without a buzzer.
+pub fn play(pattern: &Pattern) -> bool {
+    if STARTED.get().is_none() {
+        return false;
+    }
+
+    let started = STATE.lock().player.play(pattern.clone(), now_ms());
+    if started {
+        info!(target: logging::BUZZER, "Playing {}", pattern.name);
+    }
+
+    started
+}
+
+/// Stops what plays if it is of `class`, anything for none; for the
+/// repeating alarms once their cause is gone.
+pub fn stop(class: Option<Class>) {
+    STATE.lock().player.stop(class);
+}
+
+fn set_muted(muted: bool) -> Result<()> {
+    STATE.lock().player.set_muted(muted);
+    let store = store()?;
+    store.set_u8("mute", muted as u8)?;
+    store.commit()?;
+    info!(target: logging::BUZZER, "Buzzer {}", if muted { "muted" } else { "unmuted" });
+    if let Some(hc) = MUTE_CHAR.get() {
+        HAP.update(hc, &Value::Bool(muted));
+    }
+
+    Ok(())
+}
+
+/// Mute: silences everything except the smoke and leak alarms.
+fn on_write(write: &Write) -> Result<(), Status> {
+    if Some(write.hc) != MUTE_CHAR.get() {
+        return Err(Status::ResourceAbsent);
+    }
+    let muted = write.value.as_bool().ok_or(Status::InvalidValue)?;
+    set_muted(muted).map_err(|err| {
+        warn!(target: logging::BUZZER, "Storing the mute setting failed: {:?}", err);
+        Status::CommunicationError
+    })
+}
+
+pub fn service() -> Result<ServiceBuilder> {
+    if STARTED.get().is_none() {
+        bail!("the buzzer is not set up");
+    }
+
+    let muted = STATE.lock().player.is_muted();
+    Ok(ServiceBuilder::custom(SERVICE_UUID)
+        .name("Buzzer")
+        .char(
+            MUTE_UUID,
+            perm::PR | perm::PW | perm::EV,
+            Value::Bool(muted),
+        )
+        .bind(MUTE_UUID, &MUTE_CHAR)
+        .on_write(&on_write))
+}
+
+fn play_command(args: &[&str]) -> Result<()> {
+    let builtin = match args {
+        [name] => chime::builtin_pattern(name).cloned(),
+        _ => None,
+    };
+    let pattern = match builtin {
+        Some(pattern) => pattern,
+        None => Pattern {
+            name: "console".into(),
+            class: Class::Courtesy,
+            notes: chime::parse(&args.join(" "))?.into(),
+            repeat: false,
+        },
+    };
+    if !play(&pattern) {
+        bail!("muted, or something more important is playing");
+    }
+
+    Ok(())
+}
+
+pub fn register_commands() {
+    console::register(
+        "buzzer",
+        "Show the buzzer ('buzzer'), play a pattern ('buzzer play <name> | <hz/ms/gap>...'), stop it ('buzzer stop') or mute it ('buzzer mute on|off')",
+        |args| match args {
+            [] => {
+                let state = STATE.lock();
+                match state.player.playing() {
+                    Some(pattern) => println!("Playing {} ({:?})", pattern.name, pattern.class),
+                    None => println!("Silent"),
+                }
+                println!("Muted: {}", state.player.is_muted());
+                let names: Vec<&str> = chime::BUILTIN.iter().map(|p| &*p.name).collect();
+                println!("Patterns: {}", names.join(", "));
+                Ok(())
+            }
+            ["play", rest @ ..] if !rest.is_empty() => play_command(rest),
+            ["stop"] => {
+                stop(None);
+                Ok(())
+            }
+            ["mute", "on"] => set_muted(true),
+            ["mute", "off"] => set_muted(false),
+            _ => bail!("usage: buzzer [play <name> | play <hz/ms/gap>... | stop | mute on|off]"),
+        },
+    );
+}
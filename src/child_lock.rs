@@ -0,0 +1,112 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use anyhow::{bail, Result};
+use hap_core::audit::Origin;
+use hap_core::sys::{perm, uuid};
+use hap_core::{CharSlot, ServiceBuilder, Status, Value};
+use log::{info, warn};
+use spin::Once;
+
+use crate::hap_sys::HAP;
+use crate::{app_state, audit, console, logging, nvs};
+
+// Custom UUID, the SDK keeps the pointer so it has to be 'static
+const CHILD_LOCK_UUID: &[u8] = b"0000D8A0-28E5-4C3F-9B6E-5A1D7E3C9000\0";
+
+static LOCKED: AtomicBool = AtomicBool::new(false);
+static STORE: Once<nvs::Namespace> = Once::new();
+
+static LOCK_CHAR: CharSlot = CharSlot::new();
+static ON_CHAR: CharSlot = CharSlot::new();
+
+fn store() -> Result<&'static nvs::Namespace> {
+    STORE.try_call_once(|| nvs::Namespace::open(app_state::namespace()))
+}
+
+pub fn is_locked() -> bool {
+    LOCKED.load(Ordering::Relaxed)
+}
+
+/// Loads the lock and refuses the remote writes it covers, before any
+/// handler sees them, so the controller's toggle snaps back. The lock
+/// itself only changes locally.
+pub fn init() {
+    match store().and_then(|store| Ok(store.get_u8("childlock")?)) {
+        Ok(locked) => LOCKED.store(locked == Some(1), Ordering::Relaxed),
+        Err(err) => warn!(target: logging::OUTLET, "Loading the child lock failed: {:?}", err),
+    }
+
+    hap_core::write::add_gate(|write| {
+        if !write.remote {
+            return Ok(());
+        }
+        let hc = Some(write.hc);
+        if hc == LOCK_CHAR.get() || (hc == ON_CHAR.get() && is_locked()) {
+            return Err(Status::InsufficientAuthorization);
+        }
+        Ok(())
+    });
+}
+
+/// Locks or unlocks remote control, from the button or the console. The
+/// lock only changes once it is stored, so a restart brings back the same.
+pub fn set(locked: bool, origin: Origin) -> Result<()> {
+    let was = is_locked();
+    if was == locked {
+        return Ok(());
+    }
+    let store = store()?;
+    store.set_u8("childlock", locked as u8)?;
+    store.commit()?;
+    LOCKED.store(locked, Ordering::Relaxed);
+    audit::record(
+        CHILD_LOCK_UUID,
+        &Value::Bool(was),
+        &Value::Bool(locked),
+        origin,
+    );
+    info!(
+        target: logging::OUTLET,
+        "Child lock {}",
+        if locked { "on, remote control disabled" } else { "off" }
+    );
+    if let Some(hc) = LOCK_CHAR.get() {
+        HAP.update(hc, &Value::Bool(locked));
+    }
+
+    Ok(())
+}
+
+pub fn toggle(origin: Origin) {
+    if let Err(err) = set(!is_locked(), origin) {
+        warn!(target: logging::OUTLET, "Storing the child lock failed: {:?}", err);
+    }
+}
+
+/// Adds the Child Lock to the outlet service and puts its On under it.
+pub fn characteristics(service: ServiceBuilder) -> ServiceBuilder {
+    service
+        .char(
+            CHILD_LOCK_UUID,
+            perm::PR | perm::PW | perm::EV,
+            Value::Bool(is_locked()),
+        )
+        .bind(CHILD_LOCK_UUID, &LOCK_CHAR)
+        .bind(uuid::ON, &ON_CHAR)
+}
+
+pub fn register_commands() {
+    console::register(
+        "childlock",
+        "Show the child lock ('childlock') or set it ('childlock on|off')",
+        |args| match args {
+            [] => {
+                println!("Child lock: {}", if is_locked() { "on" } else { "off" });
+                Ok(())
+            }
+            ["on"] => set(true, Origin::Console),
+            ["off"] => set(false, Origin::Console),
+            _ => bail!("usage: childlock [on|off]"),
+        },
+    );
+}
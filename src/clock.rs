@@ -0,0 +1,86 @@
+use std::ffi::CString;
+
+use anyhow::Result;
+use esp_idf_svc::sntp::EspSntp;
+use hap_core::energy;
+use spin::Mutex;
+
+use crate::config;
+
+// Anything before 2022-01-01 means SNTP has not synced yet
+const MIN_VALID_EPOCH: i64 = 1_640_995_200;
+
+static SNTP: Mutex<Option<EspSntp>> = Mutex::new(None);
+
+pub struct LocalTime {
+    pub hour: u8,
+    pub minute: u8,
+    /// 0 = Sunday
+    pub weekday: u8,
+}
+
+pub fn init() -> Result<()> {
+    let tz = CString::new(config::SNTP_TIMEZONE)?;
+    unsafe {
+        esp_idf_sys::setenv(b"TZ\0".as_ptr() as _, tz.as_ptr(), 1);
+        esp_idf_sys::tzset();
+    }
+
+    *SNTP.lock() = Some(EspSntp::new_default()?);
+
+    Ok(())
+}
+
+pub fn is_synced() -> bool {
+    epoch() >= MIN_VALID_EPOCH
+}
+
+pub fn epoch() -> i64 {
+    unsafe { esp_idf_sys::time(std::ptr::null_mut()) as i64 }
+}
+
+pub fn local_time() -> Option<LocalTime> {
+    if !is_synced() {
+        return None;
+    }
+
+    let now = epoch() as esp_idf_sys::time_t;
+    let mut tm: esp_idf_sys::tm = unsafe { std::mem::zeroed() };
+    unsafe { esp_idf_sys::localtime_r(&now, &mut tm) };
+
+    Some(LocalTime {
+        hour: tm.tm_hour as u8,
+        minute: tm.tm_min as u8,
+        weekday: tm.tm_wday as u8,
+    })
+}
+
+/// The local date as a day number, days since 1970-01-01, so it changes at
+/// local midnight.
+pub fn local_day() -> Option<u32> {
+    if !is_synced() {
+        return None;
+    }
+
+    let now = epoch() as esp_idf_sys::time_t;
+    let mut tm: esp_idf_sys::tm = unsafe { std::mem::zeroed() };
+    unsafe { esp_idf_sys::localtime_r(&now, &mut tm) };
+
+    Some(energy::day_number(tm.tm_year + 1900, tm.tm_yday as u16))
+}
+
+/// `epoch` as local "YYYY-MM-DD HH:MM".
+pub fn format_local(epoch: i64) -> String {
+    let time = epoch as esp_idf_sys::time_t;
+    let mut tm: esp_idf_sys::tm = unsafe { std::mem::zeroed() };
+    unsafe { esp_idf_sys::localtime_r(&time, &mut tm) };
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}",
+        tm.tm_year + 1900,
+        tm.tm_mon + 1,
+        tm.tm_mday,
+        tm.tm_hour,
+        tm.tm_min
+    )
+}
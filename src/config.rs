@@ -0,0 +1,612 @@
+// Values marked as build-time configurable can be overridden through the
+// environment when building, e.g. `ESP_HAP_OUTLET_STACK=16384 cargo build`.
+const fn env_u32(value: Option<&str>, default: u32) -> u32 {
+    let bytes = match value {
+        Some(value) => value.as_bytes(),
+        None => return default,
+    };
+
+    let mut result = 0u32;
+    let mut i = 0;
+    while i < bytes.len() {
+        assert!(bytes[i].is_ascii_digit(), "expected a decimal number");
+        result = result * 10 + (bytes[i] - b'0') as u32;
+        i += 1;
+    }
+
+    result
+}
+
+const fn env_bool(value: Option<&str>, default: bool) -> bool {
+    match value {
+        Some(value) => !matches!(value.as_bytes(), b"0" | b"false" | b"no" | b"off"),
+        None => default,
+    }
+}
+
+// Task stacks (bytes, build-time configurable). The defaults are estimates,
+// not measured on a board yet. `tasks sizes` prints each task's peak and the
+// size it derives from it (the peak plus STACK_HEADROOM, rounded up to
+// 512 B); measure with every feature the task serves enabled, after a
+// pairing, a few HomeKit writes, `selftest` and a Wi-Fi reconnect, before
+// replacing an estimate with a measured size.
+//
+// The outlet task builds the accessory database, retries hap_start and runs
+// the boot smoke test, then idles: pairing (SRP) and the HTTP sessions run
+// on the SDK's own tasks, not on this stack.
+pub const STACK_HEADROOM: u32 = 1024;
+pub const SMART_OUTLET_TASK_STACKSIZE: u32 =
+    env_u32(option_env!("ESP_HAP_OUTLET_STACK"), 12 * 1024);
+pub const HEAP_MONITOR_TASK_STACKSIZE: u32 =
+    env_u32(option_env!("ESP_HAP_HEAP_MONITOR_STACK"), 3 * 1024);
+pub const CONSOLE_TASK_STACKSIZE: u32 = env_u32(option_env!("ESP_HAP_CONSOLE_STACK"), 6 * 1024);
+pub const ENCODER_TASK_STACKSIZE: u32 = env_u32(option_env!("ESP_HAP_ENCODER_STACK"), 3 * 1024);
+pub const IR_TASK_STACKSIZE: u32 = env_u32(option_env!("ESP_HAP_IR_STACK"), 4 * 1024);
+pub const ENERGY_METER_TASK_STACKSIZE: u32 = env_u32(option_env!("ESP_HAP_METER_STACK"), 4 * 1024);
+pub const TOUCH_TASK_STACKSIZE: u32 = env_u32(option_env!("ESP_HAP_TOUCH_STACK"), 3 * 1024);
+pub const SLEEP_TASK_STACKSIZE: u32 = env_u32(option_env!("ESP_HAP_SLEEP_STACK"), 3 * 1024);
+pub const BUTTON_TASK_STACKSIZE: u32 = env_u32(option_env!("ESP_HAP_BUTTON_STACK"), 4 * 1024);
+pub const EVENT_BUS_TASK_STACKSIZE: u32 = env_u32(option_env!("ESP_HAP_EVENT_BUS_STACK"), 4 * 1024);
+pub const ACCESSORY_POLL_TASK_STACKSIZE: u32 = env_u32(option_env!("ESP_HAP_POLL_STACK"), 4 * 1024);
+pub const WIFI_TASK_STACKSIZE: u32 = env_u32(option_env!("ESP_HAP_WIFI_STACK"), 6 * 1024);
+pub const WIFI_MONITOR_TASK_STACKSIZE: u32 =
+    env_u32(option_env!("ESP_HAP_WIFI_MONITOR_STACK"), 3 * 1024);
+pub const SCHEDULE_TASK_STACKSIZE: u32 = env_u32(option_env!("ESP_HAP_SCHEDULE_STACK"), 4 * 1024);
+pub const IRRIGATION_TASK_STACKSIZE: u32 =
+    env_u32(option_env!("ESP_HAP_IRRIGATION_STACK"), 4 * 1024);
+pub const DISTANCE_TASK_STACKSIZE: u32 = env_u32(option_env!("ESP_HAP_DISTANCE_STACK"), 4 * 1024);
+pub const DOOR_TASK_STACKSIZE: u32 = env_u32(option_env!("ESP_HAP_DOOR_STACK"), 4 * 1024);
+pub const RFID_TASK_STACKSIZE: u32 = env_u32(option_env!("ESP_HAP_RFID_STACK"), 4 * 1024);
+pub const TEMP_TASK_STACKSIZE: u32 = env_u32(option_env!("ESP_HAP_TEMP_STACK"), 4 * 1024);
+
+// Task watchdog (build-time configurable, set ESP_HAP_TASK_WDT=0 to disable
+// it while stepping through code with a debugger)
+pub const TASK_WDT_ENABLED: bool = env_bool(option_env!("ESP_HAP_TASK_WDT"), true);
+pub const TASK_WDT_TIMEOUT_SECS: u32 = 10;
+
+// Stack reporting
+pub const STACK_REPORT_DELAY_SECS: u64 = 60;
+pub const STACK_REPORT_INTERVAL_SECS: u64 = 60 * 60;
+
+// Heap diagnostics
+pub const HEAP_SAMPLE_INTERVAL_SECS: u64 = 30;
+pub const HEAP_LARGEST_BLOCK_WARN: usize = 16 * 1024;
+// When enabled, a device whose minimum-ever free heap dropped below the floor
+// restarts itself at HEAP_RESTART_HOUR local time instead of degrading further.
+pub const HEAP_RESTART_ENABLED: bool = false;
+pub const HEAP_CRITICAL_FLOOR: u32 = 24 * 1024;
+pub const HEAP_RESTART_HOUR: u8 = 3;
+
+// Time
+pub const SNTP_TIMEZONE: &str = "CET-1CEST,M3.5.0,M10.5.0/3";
+
+// On-device schedule: once the clock is synced after a boot, or after it
+// stepped, switch to what the latest past entry says (ESP_HAP_SCHEDULE_CATCH_UP=0
+// waits for the next entry instead)
+pub const SCHEDULE_CATCH_UP: bool = env_bool(option_env!("ESP_HAP_SCHEDULE_CATCH_UP"), true);
+
+// Simulation (the `simulation` cargo feature): the relay, the energy meter
+// and the soil probe become software stubs and the buttons are pressed from
+// the console (`sim`), to work on the HAP side with nothing attached.
+pub const SIMULATION: bool = cfg!(feature = "simulation");
+#[cfg(all(feature = "simulation", not(debug_assertions)))]
+compile_error!("the simulation feature stubs out the hardware, release builds refuse it");
+// What the simulated meter sees with the relay on
+pub const SIM_LOAD_WATTS: u32 = env_u32(option_env!("ESP_HAP_SIM_LOAD_WATTS"), 60);
+
+// Relay (the pins of every peripheral are in board.rs)
+pub const RELAY_SAFE_STATE: bool = false;
+pub const RELAY_CHANNEL: u8 = 0;
+// The level that closes the relay (ESP_HAP_RELAY_ACTIVE_HIGH=0 for the
+// modules whose input pulls the optocoupler's LED to ground)
+pub const RELAY_ACTIVE_HIGH: bool = env_bool(option_env!("ESP_HAP_RELAY_ACTIVE_HIGH"), true);
+
+/// How the GPIO relay's coil is driven, see hap_core::actuation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RelayMode {
+    Level,
+    Pulse,
+    DualCoil,
+}
+
+const fn env_relay_mode(value: Option<&str>, default: RelayMode) -> RelayMode {
+    match value {
+        Some(value) => match value.as_bytes() {
+            b"level" => RelayMode::Level,
+            b"pulse" => RelayMode::Pulse,
+            b"dual" => RelayMode::DualCoil,
+            _ => panic!("expected level, pulse or dual"),
+        },
+        None => default,
+    }
+}
+
+// Impulse and latching relays instead of the level-held module (build-time
+// configurable, ESP_HAP_RELAY_MODE=pulse gives an impulse relay a pulse on
+// the relay GPIO per change, =dual pulses the set coil on it to close a
+// dual-coil relay and the reset coil on RELAY_RESET_GPIO to open it). Pulses
+// last RELAY_PULSE_MS at the active level; UART relay boards ignore this. An
+// impulse relay is read back at boot through the feedback input where
+// there is one (ESP_HAP_RELAY_FEEDBACK), else taken to be open.
+//
+// The GPIO backend has a single channel, the relay GPIO with its reset pin,
+// so one mode covers it; the actuators take a mode each, should a board wire
+// up more channels.
+pub const RELAY_MODE: RelayMode =
+    env_relay_mode(option_env!("ESP_HAP_RELAY_MODE"), RelayMode::Level);
+pub const RELAY_PULSE_MS: u32 = env_u32(option_env!("ESP_HAP_RELAY_PULSE_MS"), 50);
+
+/// What the relay does after a boot, the values of the restore policy
+/// characteristic.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RestorePolicy {
+    AlwaysOff = 0,
+    AlwaysOn = 1,
+    LastState = 2,
+}
+
+impl RestorePolicy {
+    pub const VALUES: &'static [u8] = &[0, 1, 2];
+
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(Self::AlwaysOff),
+            1 => Some(Self::AlwaysOn),
+            2 => Some(Self::LastState),
+            _ => None,
+        }
+    }
+}
+
+const fn env_restore(value: Option<&str>, default: RestorePolicy) -> RestorePolicy {
+    match value {
+        Some(value) => match value.as_bytes() {
+            b"off" => RestorePolicy::AlwaysOff,
+            b"on" => RestorePolicy::AlwaysOn,
+            b"last" => RestorePolicy::LastState,
+            _ => panic!("expected off, on or last"),
+        },
+        None => default,
+    }
+}
+
+// Relay state after a boot until changed by the restore policy characteristic
+// (build-time configurable, e.g. ESP_HAP_RESTORE=last). Stored at the first
+// boot, later firmware with another default leaves it. The heartbeat dates
+// power outages to within its interval.
+pub const RESTORE_POLICY: RestorePolicy =
+    env_restore(option_env!("ESP_HAP_RESTORE"), RestorePolicy::AlwaysOff);
+pub const HEARTBEAT_SECS: u32 = 600;
+// Guardrails on the outlet, for loads that must not run for long or at night:
+// at most GUARD_MAX_ON_MINS on within every rolling GUARD_WINDOW_MINS (0 for
+// no limit), then off for GUARD_COOLDOWN_MINS once a limit switched it off.
+// Quiet hours are set with `guard quiet`; while the clock is not synced they
+// keep the outlet off unless ESP_HAP_GUARD_CLOCK_OPEN=1. Limits set from the
+// console are stored and win over these.
+pub const GUARD_MAX_ON_MINS: u32 = env_u32(option_env!("ESP_HAP_GUARD_MAX_ON_MINS"), 0);
+pub const GUARD_WINDOW_MINS: u32 = env_u32(option_env!("ESP_HAP_GUARD_WINDOW_MINS"), 60);
+pub const GUARD_COOLDOWN_MINS: u32 = env_u32(option_env!("ESP_HAP_GUARD_COOLDOWN_MINS"), 0);
+pub const GUARD_CLOCK_FAIL_OPEN: bool = env_bool(option_env!("ESP_HAP_GUARD_CLOCK_OPEN"), false);
+// UART relay boards instead of the GPIO (build-time configurable, set
+// ESP_HAP_RELAY_UART=1 for the CH340-style 4/8-channel boards)
+pub const RELAY_UART_ENABLED: bool = env_bool(option_env!("ESP_HAP_RELAY_UART"), false);
+pub const RELAY_UART_BAUD: u32 = env_u32(option_env!("ESP_HAP_RELAY_UART_BAUD"), 9600);
+pub const RELAY_UART_CHANNELS: u8 = env_u32(option_env!("ESP_HAP_RELAY_UART_CHANNELS"), 4) as u8;
+pub const RELAY_UART_ATTEMPTS: u32 = 3;
+pub const RELAY_UART_FRAME_GAP_MS: u64 = 50;
+// Relay contact feedback for the self-test (build-time configurable, set
+// ESP_HAP_RELAY_FEEDBACK=1 with an optocoupler across the contacts pulling
+// the input low while they are closed)
+pub const RELAY_FEEDBACK_ENABLED: bool = env_bool(option_env!("ESP_HAP_RELAY_FEEDBACK"), false);
+pub const RELAY_FEEDBACK_ACTIVE_HIGH: bool = false;
+// Relay channels beyond the outlet's as Switch services, e.g.
+// ESP_HAP_RELAY_SWITCHES=1,2 on a UART relay board, and rules between the
+// channels: ESP_HAP_RELAY_INTERLOCK=0+1 never closes channels 0 and 1
+// together, closing one opens the other RELAY_BREAK_MS before, and
+// ESP_HAP_RELAY_FOLLOW=3=!0 drives channel 3 as the inverse of channel 0,
+// say for a pilot light (build-time configurable, see hap_core::interlock).
+// Writes against the rules are carried out as they allow; rules that cannot
+// hold stop the start.
+pub const RELAY_SWITCHES: &str = match option_env!("ESP_HAP_RELAY_SWITCHES") {
+    Some(channels) => channels,
+    None => "",
+};
+pub const RELAY_INTERLOCK: &str = match option_env!("ESP_HAP_RELAY_INTERLOCK") {
+    Some(groups) => groups,
+    None => "",
+};
+pub const RELAY_FOLLOW: &str = match option_env!("ESP_HAP_RELAY_FOLLOW") {
+    Some(follows) => follows,
+    None => "",
+};
+pub const RELAY_BREAK_MS: u64 = env_u32(option_env!("ESP_HAP_RELAY_BREAK_MS"), 100) as u64;
+
+// Status LED (build-time configurable, set ESP_HAP_STATUS_LED=0 for
+// installations where any light is unwelcome)
+pub const STATUS_LED_ENABLED: bool = env_bool(option_env!("ESP_HAP_STATUS_LED"), true);
+pub const STATUS_LED_ACTIVE_HIGH: bool = true;
+
+// Buttons
+pub const BUTTON_DEBOUNCE_MS: u64 = 30;
+pub const BUTTON_LONG_PRESS_MS: u64 = 1000;
+pub const BUTTON_DOUBLE_CLICK_MS: u64 = 300;
+pub const FACTORY_RESET_HOLD_MS: u64 = 10 * 1000;
+// Shorter holds restart into the Wi-Fi setup portal, keeping the pairings
+pub const WIFI_SETUP_HOLD_MS: u64 = 5 * 1000;
+// The boot button as a Stateless Programmable Switch, its single, double and
+// long presses as three triggers for scenes (build-time configurable, set
+// ESP_HAP_SCENE_SWITCH=1). Each gesture's local action, set with the
+// `gesture` console command, applies either way; holds from
+// WIFI_SETUP_HOLD_MS on are never gestures.
+pub const SCENE_SWITCH_ENABLED: bool = env_bool(option_env!("ESP_HAP_SCENE_SWITCH"), false);
+
+// Capacitive touch pad as button (build-time configurable, set ESP_HAP_TOUCH=1;
+// ESP32 only, the C3 has no touch sensor)
+pub const TOUCH_ENABLED: bool = env_bool(option_env!("ESP_HAP_TOUCH"), false);
+pub const TOUCH_THRESHOLD_PERCENT: u32 = 70;
+pub const TOUCH_RELEASE_PERCENT: u32 = 85;
+pub const TOUCH_SAMPLES: u8 = 3;
+pub const TOUCH_SAMPLE_MS: u64 = 20;
+
+// Rotary encoder with push switch (build-time configurable, set
+// ESP_HAP_ENCODER=1 on boards that have one). Detented encoders produce 4
+// counts per detent, set ESP_HAP_ENCODER_COUNTS=1 for non-detented ones.
+pub const ENCODER_ENABLED: bool = env_bool(option_env!("ESP_HAP_ENCODER"), false);
+pub const ENCODER_COUNTS_PER_STEP: u32 = env_u32(option_env!("ESP_HAP_ENCODER_COUNTS"), 4);
+
+// IR transmitter and learning receiver (build-time configurable, set
+// ESP_HAP_IR=1 on boards with an IR LED and a TSOP-style receiver)
+pub const IR_ENABLED: bool = env_bool(option_env!("ESP_HAP_IR"), false);
+pub const IR_CARRIER_HZ: u32 = 38_000;
+// NEC repeat codes sent after the frame, like a briefly held remote key
+pub const IR_NEC_REPEATS: u32 = 1;
+pub const IR_LEARN_TIMEOUT_SECS: u64 = 8;
+pub const IR_SWITCH_RESET_MS: u64 = 500;
+
+// Modbus RTU energy meter on RS-485 (build-time configurable, set
+// ESP_HAP_METER=1 with a meter attached). The C3 has a single spare UART, so
+// there the meter and the UART relay backend exclude each other.
+pub const METER_ENABLED: bool = env_bool(option_env!("ESP_HAP_METER"), false);
+pub const METER_BAUD: u32 = env_u32(option_env!("ESP_HAP_METER_BAUD"), 2400);
+pub const METER_UNIT: u8 = env_u32(option_env!("ESP_HAP_METER_UNIT"), 1) as u8;
+pub const METER_TIMEOUT_MS: u64 = 200;
+pub const METER_ATTEMPTS: u32 = 3;
+pub const METER_POLL_SECS: u64 = 10;
+// Energy since the last commit is lost on a power cut, committing more
+// often wears the flash
+pub const ENERGY_COMMIT_SECS: u64 = 15 * 60;
+
+// Write audit trail: the latest entries are mirrored to NVS this often while
+// there are new ones, and at shutdown
+pub const AUDIT_MIRROR_SECS: u64 = 5 * 60;
+pub const AUDIT_MIRROR_ENTRIES: usize = 16;
+
+// Capacitive soil moisture probe and irrigation valve (build-time
+// configurable, set ESP_HAP_IRRIGATION=1 with a probe on the ADC and a valve
+// driver). Calibrate the probe with `soil calibrate dry|wet`. Automatic
+// watering waits the lockout after every opening and after a restart.
+pub const IRRIGATION_ENABLED: bool = env_bool(option_env!("ESP_HAP_IRRIGATION"), false);
+pub const IRRIGATION_LOCKOUT_HOURS: u32 =
+    env_u32(option_env!("ESP_HAP_IRRIGATION_LOCKOUT_HOURS"), 12);
+pub const SOIL_SAMPLE_SECS: u32 = 10;
+// Averaged per reading
+pub const SOIL_SAMPLES: u32 = 16;
+pub const VALVE_ACTIVE_HIGH: bool = true;
+pub const VALVE_DEFAULT_DURATION_SECS: u32 = 5 * 60;
+pub const VALVE_MAX_DURATION_SECS: u32 = 60 * 60;
+
+// Ultrasonic distance sensor, an HC-SR04 or JSN-SR04T with its echo output
+// divided down to 3.3 V, on the ceiling above the door and the parking spot
+// (build-time configurable: ESP_HAP_GARAGE_DOOR=1 exposes a Garage Door
+// Opener that follows the distance and presses the opener's push-button
+// input for commands, ESP_HAP_CAR_PRESENCE=1 an Occupancy Sensor for the car)
+pub const GARAGE_DOOR_ENABLED: bool = env_bool(option_env!("ESP_HAP_GARAGE_DOOR"), false);
+pub const CAR_PRESENCE_ENABLED: bool = env_bool(option_env!("ESP_HAP_CAR_PRESENCE"), false);
+pub const DISTANCE_ENABLED: bool = GARAGE_DOOR_ENABLED || CAR_PRESENCE_ENABLED;
+pub const DISTANCE_INTERVAL_MS: u64 =
+    env_u32(option_env!("ESP_HAP_DISTANCE_INTERVAL_MS"), 1000) as u64;
+// Median of this many pings per measurement
+pub const DISTANCE_PINGS: usize = 5;
+// Echo pulses this long are the sensors' "nothing within range", about 5 m
+pub const DISTANCE_MAX_ECHO_US: u32 = 30_000;
+// For the speed of sound until `distance temp` sets the actual one
+pub const DISTANCE_AIR_TEMP_C: i32 = 20;
+pub const GARAGE_OPEN_WITHIN_CM: u32 = env_u32(option_env!("ESP_HAP_GARAGE_OPEN_CM"), 40);
+pub const GARAGE_CLOSED_BEYOND_CM: u32 = env_u32(option_env!("ESP_HAP_GARAGE_CLOSED_CM"), 100);
+pub const GARAGE_HYSTERESIS_CM: u32 = 10;
+pub const GARAGE_OPENER_PULSE_MS: u64 = 500;
+pub const GARAGE_TRAVEL_SECS: u32 = 30;
+pub const CAR_PRESENT_WITHIN_CM: u32 = env_u32(option_env!("ESP_HAP_CAR_CM"), 180);
+pub const CAR_HYSTERESIS_CM: u32 = 20;
+
+/// Where the door's position comes from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DoorSensor {
+    /// A potentiometer on an ADC channel
+    Pot,
+    /// A quadrature encoder on a PCNT unit
+    Encoder,
+}
+
+const fn env_door_sensor(value: Option<&str>, default: DoorSensor) -> DoorSensor {
+    match value {
+        Some(value) => match value.as_bytes() {
+            b"pot" => DoorSensor::Pot,
+            b"encoder" => DoorSensor::Encoder,
+            _ => panic!("expected pot or encoder"),
+        },
+        None => default,
+    }
+}
+
+/// What the door's motor moves, and so the service it is exposed as.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Covering {
+    Door,
+    /// Venetian blinds, a Window Covering
+    Blinds,
+}
+
+/// What turns the slats of blinds.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TiltSource {
+    None,
+    /// Short runs of the lift motor, timed
+    Motor,
+    /// A hobby servo of its own
+    Servo,
+}
+
+const fn env_covering(value: Option<&str>, default: Covering) -> Covering {
+    match value {
+        Some(value) => match value.as_bytes() {
+            b"door" => Covering::Door,
+            b"blinds" => Covering::Blinds,
+            _ => panic!("expected door or blinds"),
+        },
+        None => default,
+    }
+}
+
+const fn env_tilt(value: Option<&str>, default: TiltSource) -> TiltSource {
+    match value {
+        Some(value) => match value.as_bytes() {
+            b"none" => TiltSource::None,
+            b"motor" => TiltSource::Motor,
+            b"servo" => TiltSource::Servo,
+            _ => panic!("expected none, motor or servo"),
+        },
+        None => default,
+    }
+}
+
+// Motorised door with position feedback on an H-bridge, two direction inputs
+// and a PWM enable (build-time configurable: ESP_HAP_DOOR=1, and
+// ESP_HAP_DOOR_SENSOR=encoder for a quadrature encoder instead of a
+// potentiometer; the C3 has no PCNT unit). Calibrate with `door calibrate
+// closed|open`. The safety edge is active low and stops and backs off the
+// motor from its ISR.
+pub const DOOR_ENABLED: bool = env_bool(option_env!("ESP_HAP_DOOR"), false);
+pub const DOOR_SENSOR: DoorSensor =
+    env_door_sensor(option_env!("ESP_HAP_DOOR_SENSOR"), DoorSensor::Pot);
+pub const DOOR_POLL_MS: u64 = 50;
+// Averaged per potentiometer reading
+pub const DOOR_ADC_SAMPLES: u32 = 8;
+pub const DOOR_TOLERANCE_PERCENT: u8 = 2;
+// No change in position for this long is a stall
+pub const DOOR_STALL_MS: u64 = 1500;
+pub const DOOR_TRAVEL_SECS: u64 = env_u32(option_env!("ESP_HAP_DOOR_TRAVEL_SECS"), 30) as u64;
+pub const DOOR_BACK_OFF_MS: u64 = 300;
+pub const DOOR_PWM_HZ: u32 = 20_000;
+pub const DOOR_DUTY_PERCENT: u32 = env_u32(option_env!("ESP_HAP_DOOR_DUTY"), 80);
+
+// The same motor on venetian blinds, as a Window Covering (build-time
+// configurable: ESP_HAP_COVERING=blinds, and ESP_HAP_TILT=motor|servo for
+// slat tilt). Timed tilt runs the lift motor for a share of the time a full
+// turn of the slats takes, `door tilt-time <ms>` stores the actual one; the
+// lift target follows where a turn leaves it when that is beyond the
+// tolerance. Tilt waits until the lift is done and writes have settled, and
+// is applied again after every lift move.
+pub const DOOR_COVERING: Covering = env_covering(option_env!("ESP_HAP_COVERING"), Covering::Door);
+pub const DOOR_TILT: TiltSource = env_tilt(option_env!("ESP_HAP_TILT"), TiltSource::None);
+pub const TILT_FULL_MS: u32 = 1500;
+pub const TILT_SETTLE_MS: u64 = 500;
+pub const SERVO_MIN_US: u32 = 500;
+pub const SERVO_MAX_US: u32 = 2500;
+
+const _: () = assert!(
+    matches!(DOOR_COVERING, Covering::Blinds) || matches!(DOOR_TILT, TiltSource::None),
+    "slat tilt needs ESP_HAP_COVERING=blinds"
+);
+
+// Piezo buzzer for audible feedback and alarms (build-time configurable, set
+// ESP_HAP_BUZZER=1 on boards with one). Patterns are played from a timer.
+pub const BUZZER_ENABLED: bool = env_bool(option_env!("ESP_HAP_BUZZER"), false);
+pub const BUZZER_TICK_MS: u64 = 10;
+
+/// How the PN532 is wired, as set with its mode switches.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RfidBus {
+    I2c,
+    Spi,
+}
+
+const fn env_rfid_bus(value: Option<&str>, default: RfidBus) -> RfidBus {
+    match value {
+        Some(value) => match value.as_bytes() {
+            b"i2c" => RfidBus::I2c,
+            b"spi" => RfidBus::Spi,
+            _ => panic!("expected i2c or spi"),
+        },
+        None => default,
+    }
+}
+
+// PN532 NFC reader for local unlock (build-time configurable, set
+// ESP_HAP_RFID=1, and ESP_HAP_RFID_BUS=spi unless it is on I2C). A
+// whitelisted ISO14443A tag opens the garage door; tags are enrolled with
+// `rfid add` or by holding the boot button for a gesture's long press and
+// presenting the tag. Unknown tags are rejected, RFID_TAMPER_REJECTS of them
+// within the window set StatusTampered until a whitelisted tag is read.
+pub const RFID_ENABLED: bool = env_bool(option_env!("ESP_HAP_RFID"), false);
+pub const RFID_BUS: RfidBus = env_rfid_bus(option_env!("ESP_HAP_RFID_BUS"), RfidBus::I2c);
+pub const RFID_I2C_HZ: u32 = 100_000;
+pub const RFID_SPI_HZ: u32 = 1_000_000;
+pub const RFID_POLL_MS: u64 = 250;
+// A tag still in the field is not presented again until it was away this long
+pub const RFID_REPEAT_MS: u64 = 2000;
+pub const RFID_ENROLL_SECS: u32 = 30;
+pub const RFID_TAMPER_REJECTS: usize = 3;
+pub const RFID_TAMPER_WINDOW_SECS: u32 = 60;
+
+// DS18B20 probes on a 1-Wire bus with a 4.7 kOhm pull-up, each powered from
+// VDD rather than parasitically (build-time configurable, set ESP_HAP_TEMP=1).
+// The probes found at the first boot are registered, more with `temp add`.
+// The contributing ones make up the room temperature; a probe without a
+// reading for TEMP_STALE_SECS (ESP_HAP_TEMP_STALE_SECS) drops out of it and
+// reports a fault.
+pub const TEMP_ENABLED: bool = env_bool(option_env!("ESP_HAP_TEMP"), false);
+pub const TEMP_SAMPLE_SECS: u32 = 10;
+pub const TEMP_STALE_SECS: u32 = env_u32(option_env!("ESP_HAP_TEMP_STALE_SECS"), 5 * 60);
+const _: () = assert!(
+    TEMP_STALE_SECS > TEMP_SAMPLE_SECS,
+    "probes would go stale between samples"
+);
+
+// Events from ISRs to the dispatcher task; when full the oldest is dropped
+pub const EVENT_BUS_QUEUE_LEN: u32 = env_u32(option_env!("ESP_HAP_EVENT_BUS_LEN"), 32);
+
+// How often the accessory is polled for changes HAP did not cause, such as a
+// UART relay board switched by hand
+pub const ACCESSORY_POLL_MS: u64 = env_u32(option_env!("ESP_HAP_POLL_MS"), 1000) as u64;
+
+// Deep sleep for battery-powered sensors (build-time configurable, set
+// ESP_HAP_DEEP_SLEEP=1). The device sleeps once a controller had the grace
+// period to collect the notifications, or after the maximum awake time.
+pub const SLEEP_ENABLED: bool = env_bool(option_env!("ESP_HAP_DEEP_SLEEP"), false);
+pub const SLEEP_INTERVAL_SECS: u64 = env_u32(option_env!("ESP_HAP_SLEEP_SECS"), 300) as u64;
+pub const SLEEP_GRACE_MS: u64 = 1500;
+pub const SLEEP_MAX_AWAKE_SECS: u64 = 15;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PowerProfile {
+    Perf,
+    Balanced,
+    LowPower,
+}
+
+const fn env_profile(value: Option<&str>, default: PowerProfile) -> PowerProfile {
+    match value {
+        Some(value) => match value.as_bytes() {
+            b"perf" => PowerProfile::Perf,
+            b"balanced" => PowerProfile::Balanced,
+            b"lowpower" => PowerProfile::LowPower,
+            _ => panic!("expected perf, balanced or lowpower"),
+        },
+        None => default,
+    }
+}
+
+// CPU frequency, task core pinning and Wi-Fi power save, see pm::PROFILE
+// (build-time configurable, e.g. ESP_HAP_POWER_PROFILE=lowpower)
+pub const POWER_PROFILE: PowerProfile =
+    env_profile(option_env!("ESP_HAP_POWER_PROFILE"), PowerProfile::Balanced);
+
+// Power management for always-on devices (build-time configurable, set
+// ESP_HAP_PM=1, and ESP_HAP_LIGHT_SLEEP=1 for automatic light sleep). The
+// maximum frequency comes from the power profile.
+pub const PM_ENABLED: bool = env_bool(option_env!("ESP_HAP_PM"), false);
+pub const PM_MIN_FREQ_MHZ: u32 = env_u32(option_env!("ESP_HAP_PM_MIN_MHZ"), 40);
+pub const PM_LIGHT_SLEEP: bool = env_bool(option_env!("ESP_HAP_LIGHT_SLEEP"), false);
+
+// HAP
+pub const HAP_SETUP_CODE: &str = "111-22-333";
+pub const HAP_SETUP_ID: &str = "ES32";
+// Identity of development units, per-unit values come from the factory partition
+pub const SERIAL_NUMBER: &str = "111122334455";
+pub const MODEL: &str = "Esp32";
+pub const HW_REV: &str = "0.1.0";
+pub const HAP_START_ATTEMPTS: u32 = 5;
+pub const HAP_START_RETRY_SECS: u64 = 5;
+// The SDK's HTTP server port and its limit on open HAP connections, as set
+// in sdkconfig.defaults; used to check what mDNS advertises and to warn when
+// the controllers reach the limit
+pub const HAP_PORT: u16 = esp_idf_sys::CONFIG_HAP_HTTP_SERVER_PORT as u16;
+pub const HAP_MAX_SESSIONS: u32 = esp_idf_sys::CONFIG_HAP_HTTP_MAX_OPEN_SOCKETS;
+// Below this much free heap the socket of the session idle the longest is
+// closed, so the next pair-verify finds room (build-time configurable, set
+// ESP_HAP_SESSION_SHED_HEAP in bytes, 0 never sheds); sessions active within
+// SESSION_SHED_MIN_IDLE_SECS, or without a read or write yet, are left alone
+pub const SESSION_SHED_FREE_HEAP: u32 =
+    env_u32(option_env!("ESP_HAP_SESSION_SHED_HEAP"), 28 * 1024);
+pub const SESSION_SHED_MIN_IDLE_SECS: u32 = 30;
+// HAP traffic trace, off until turned on from the console; 'trace on ring'
+// also keeps the last lines in RAM for 'trace show' (build-time configurable,
+// set ESP_HAP_TRACE_RING_LINES, each line takes up to some 200 B)
+pub const TRACE_RING_LINES: usize = env_u32(option_env!("ESP_HAP_TRACE_RING_LINES"), 64) as usize;
+// Runs the smoke test ('selftest') once HAP started, for boards in
+// hardware-in-the-loop CI that wait for its SMOKE line on the console
+// (build-time configurable, set ESP_HAP_SMOKE_TEST)
+pub const SMOKE_TEST_AT_BOOT: bool = env_bool(option_env!("ESP_HAP_SMOKE_TEST"), false);
+// How long the smoke test waits for the HAP server to answer
+pub const SMOKE_HTTP_TIMEOUT_MS: u64 = 3000;
+// Unpaired, the accessory only takes pairings for PAIRING_WINDOW_MINS after
+// the first boot, a pairing reset or a press of the boot button, then stops
+// advertising itself as unpaired; the closed window outlasts restarts
+// (build-time configurable, set ESP_HAP_PAIRING_WINDOW=1, without it an
+// unpaired accessory advertises until paired)
+pub const PAIRING_WINDOW_ENABLED: bool = env_bool(option_env!("ESP_HAP_PAIRING_WINDOW"), false);
+pub const PAIRING_WINDOW_MINS: u32 = env_u32(option_env!("ESP_HAP_PAIRING_WINDOW_MINS"), 15);
+const _: () = assert!(
+    PAIRING_WINDOW_MINS > 0,
+    "a pairing window of 0 minutes never opens"
+);
+
+// Startup failure handling
+pub const WIFI_RETRY_SECS: u64 = 5;
+// A station that has not connected for this long opens the setup portal on
+// its own, for a device whose network went away; the station is tried again
+// once the portal times out (build-time configurable, set
+// ESP_HAP_WIFI_AP_FALLBACK_SECS, 0 only opens it when asked for)
+pub const WIFI_AP_FALLBACK_SECS: u64 =
+    env_u32(option_env!("ESP_HAP_WIFI_AP_FALLBACK_SECS"), 5 * 60) as u64;
+pub const WIFI_RSSI_INTERVAL_SECS: u64 = 30;
+pub const WIFI_RSSI_NOTIFY_DB: i32 = 5;
+// Wi-Fi setup portal (build-time configurable password of its access point,
+// ESP_HAP_SETUP_AP_PASSWORD with at least 8 characters; open without). Once
+// the timeout passes the previous credentials are tried again.
+pub const SETUP_AP_PASSWORD: &str = match option_env!("ESP_HAP_SETUP_AP_PASSWORD") {
+    Some(password) => password,
+    None => "",
+};
+pub const SETUP_TIMEOUT_SECS: u64 = 10 * 60;
+const _: () = assert!(
+    SETUP_AP_PASSWORD.is_empty() || SETUP_AP_PASSWORD.len() >= 8,
+    "a WPA2 password needs at least 8 characters"
+);
+// How long a fatal startup failure is blinked before the device restarts
+pub const FAILURE_RESTART_SECS: u64 = 5 * 60;
+
+// Connections the HTTP API, or the setup portal in its place, takes at once
+pub const HTTP_MAX_OPEN_SOCKETS: usize = 2;
+// Either server holds a listening and a control socket besides its
+// connections, the smoke test's loopback client one more; see the socket
+// budget in sdkconfig.defaults
+const _: () = assert!(
+    HAP_MAX_SESSIONS as usize + 2 + HTTP_MAX_OPEN_SOCKETS + 2 + 1
+        <= esp_idf_sys::CONFIG_LWIP_MAX_SOCKETS as usize,
+    "the HTTP servers need more sockets than CONFIG_LWIP_MAX_SOCKETS"
+);
+// The SDK's default CONFIG_HAP_HTTP_SERVER_PORT is 80 too; whichever binds
+// second fails, and without HAP's server nothing pairs
+const _: () = assert!(
+    HTTP_PORT != HAP_PORT,
+    "the HTTP API needs a port of its own, move HAP with CONFIG_HAP_HTTP_SERVER_PORT"
+);
+// HTTP (build-time configurable token, sent as `Authorization: Bearer <token>`;
+// an empty token leaves the API unauthenticated)
+pub const HTTP_PORT: u16 = 80;
+pub const HTTP_API_TOKEN: &str = match option_env!("ESP_HAP_API_TOKEN") {
+    Some(token) => token,
+    None => "",
+};
@@ -0,0 +1,88 @@
+use std::io::{self, Read};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::Result;
+use log::warn;
+use spin::Mutex;
+
+use crate::{logging, tasks, wdt};
+
+pub type Handler = fn(&[&str]) -> Result<()>;
+
+struct Command {
+    name: &'static str,
+    help: &'static str,
+    handler: Handler,
+}
+
+static COMMANDS: Mutex<Vec<Command>> = Mutex::new(Vec::new());
+
+pub fn register(name: &'static str, help: &'static str, handler: Handler) {
+    COMMANDS.lock().push(Command {
+        name,
+        help,
+        handler,
+    });
+}
+
+fn execute(line: &str) {
+    let args: Vec<&str> = line.split_whitespace().collect();
+    let Some(&name) = args.first() else {
+        return;
+    };
+
+    if name == "help" {
+        for command in COMMANDS.lock().iter() {
+            println!("{:<12} {}", command.name, command.help);
+        }
+        return;
+    }
+
+    let handler = COMMANDS
+        .lock()
+        .iter()
+        .find(|command| command.name == name)
+        .map(|command| command.handler);
+
+    match handler {
+        Some(handler) => {
+            if let Err(err) = handler(&args[1..]) {
+                println!("{}: {}", name, err);
+            }
+        }
+        None => println!("Unknown command '{}', try 'help'", name),
+    }
+}
+
+pub fn console_handler() {
+    let mut stdin = io::stdin();
+    let mut line = String::new();
+    let mut buf = [0u8; 64];
+    let watchdog = wdt::subscribe(tasks::CONSOLE.name);
+
+    loop {
+        watchdog.feed();
+
+        // The UART VFS is non-blocking, so poll instead of blocking in read_line
+        match stdin.read(&mut buf) {
+            Ok(0) => {}
+            Ok(n) => {
+                for &byte in &buf[..n] {
+                    match byte {
+                        b'\r' | b'\n' => {
+                            execute(line.trim());
+                            line.clear();
+                        }
+                        _ => line.push(byte as char),
+                    }
+                }
+                continue;
+            }
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+            Err(err) => warn!(target: logging::DIAG, "Console read failed: {}", err),
+        }
+
+        thread::sleep(Duration::from_millis(50));
+    }
+}
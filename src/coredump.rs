@@ -0,0 +1,120 @@
+use anyhow::{anyhow, Result};
+use esp_idf_sys::{esp, esp_partition_t};
+use log::{info, warn};
+
+use crate::{console, diag_service, logging};
+
+pub const CHUNK_SIZE: usize = 4096;
+
+pub struct CoreDump {
+    partition: *const esp_partition_t,
+    offset: usize,
+    size: usize,
+}
+
+fn partition() -> Option<*const esp_partition_t> {
+    let partition = unsafe {
+        esp_idf_sys::esp_partition_find_first(
+            esp_idf_sys::esp_partition_type_t_ESP_PARTITION_TYPE_DATA,
+            esp_idf_sys::esp_partition_subtype_t_ESP_PARTITION_SUBTYPE_DATA_COREDUMP,
+            std::ptr::null(),
+        )
+    };
+
+    (!partition.is_null()).then_some(partition)
+}
+
+/// Returns the stored core dump, if the partition holds a valid one.
+pub fn find() -> Option<CoreDump> {
+    let partition = partition()?;
+
+    let mut addr = 0usize;
+    let mut size = 0usize;
+    let err = unsafe { esp_idf_sys::esp_core_dump_image_get(&mut addr, &mut size) };
+    if err != esp_idf_sys::ESP_OK {
+        return None;
+    }
+
+    Some(CoreDump {
+        partition,
+        offset: addr - unsafe { (*partition).address } as usize,
+        size,
+    })
+}
+
+pub fn is_present() -> bool {
+    find().is_some()
+}
+
+impl CoreDump {
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Reads the image in `CHUNK_SIZE` pieces so it never has to fit in RAM.
+    pub fn read_chunks(&self, mut sink: impl FnMut(&[u8]) -> Result<()>) -> Result<()> {
+        let mut buf = vec![0u8; CHUNK_SIZE];
+        let mut done = 0;
+
+        while done < self.size {
+            let len = (self.size - done).min(CHUNK_SIZE);
+            esp!(unsafe {
+                esp_idf_sys::esp_partition_read(
+                    self.partition,
+                    self.offset + done,
+                    buf.as_mut_ptr() as _,
+                    len,
+                )
+            })?;
+            sink(&buf[..len])?;
+            done += len;
+        }
+
+        Ok(())
+    }
+}
+
+pub fn erase() -> Result<()> {
+    let partition = partition().ok_or_else(|| anyhow!("no core dump partition"))?;
+
+    esp!(unsafe {
+        esp_idf_sys::esp_partition_erase_range(partition, 0, (*partition).size as usize)
+    })?;
+    info!(target: logging::DIAG, "Core dump erased");
+    diag_service::update_core_dump(false);
+
+    Ok(())
+}
+
+pub fn check_at_boot() {
+    if partition().is_none() {
+        warn!(target: logging::DIAG, "No core dump partition, crashes will only be reported as panic messages");
+        return;
+    }
+
+    if let Some(dump) = find() {
+        warn!(
+            target: logging::DIAG,
+            "Core dump of {} B stored in flash, fetch it from GET /api/coredump",
+            dump.size()
+        );
+    }
+}
+
+pub fn register_commands() {
+    console::register(
+        "coredump",
+        "Show ('coredump') or erase ('coredump erase') the stored core dump",
+        |args| match args {
+            ["erase"] => erase(),
+            [] => {
+                match find() {
+                    Some(dump) => println!("Core dump stored, {} B", dump.size()),
+                    None => println!("No core dump stored"),
+                }
+                Ok(())
+            }
+            _ => anyhow::bail!("usage: coredump [erase]"),
+        },
+    );
+}
@@ -0,0 +1,236 @@
+use std::ffi::CString;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::time::Duration;
+
+use anyhow::bail;
+use log::{info, warn};
+
+use crate::{
+    app_state, clock, config, console, coredump, fault, http, logging, mdns, metrics, restore,
+    system, tasks, wdt,
+};
+
+static FREE_HEAP: AtomicU32 = AtomicU32::new(0);
+static MIN_FREE_HEAP: AtomicU32 = AtomicU32::new(0);
+static LARGEST_FREE_BLOCK: AtomicU32 = AtomicU32::new(0);
+static RESTART_SCHEDULED: AtomicBool = AtomicBool::new(false);
+
+pub fn reset_reason() -> &'static str {
+    match unsafe { esp_idf_sys::esp_reset_reason() } {
+        esp_idf_sys::esp_reset_reason_t_ESP_RST_POWERON => "PowerOn",
+        esp_idf_sys::esp_reset_reason_t_ESP_RST_EXT => "External",
+        esp_idf_sys::esp_reset_reason_t_ESP_RST_SW => "Software",
+        esp_idf_sys::esp_reset_reason_t_ESP_RST_PANIC => "Panic",
+        esp_idf_sys::esp_reset_reason_t_ESP_RST_INT_WDT => "InterruptWDT",
+        esp_idf_sys::esp_reset_reason_t_ESP_RST_TASK_WDT => "TaskWDT",
+        esp_idf_sys::esp_reset_reason_t_ESP_RST_WDT => "WDT",
+        esp_idf_sys::esp_reset_reason_t_ESP_RST_DEEPSLEEP => "DeepSleep",
+        esp_idf_sys::esp_reset_reason_t_ESP_RST_BROWNOUT => "Brownout",
+        esp_idf_sys::esp_reset_reason_t_ESP_RST_SDIO => "SDIO",
+        _ => "Unknown",
+    }
+}
+
+/// Seconds since boot from the monotonic esp_timer, unaffected by SNTP.
+pub fn uptime_secs() -> u32 {
+    (unsafe { esp_idf_sys::esp_timer_get_time() } / 1_000_000) as u32
+}
+
+pub fn is_fault_reset(reason: &str) -> bool {
+    matches!(
+        reason,
+        "Panic" | "InterruptWDT" | "TaskWDT" | "WDT" | "Brownout"
+    )
+}
+
+pub fn render_json() -> String {
+    let last_fault = match fault::last_fault() {
+        Some(fault) => http::json_string(&fault),
+        None => "null".into(),
+    };
+
+    let coredump_size = match coredump::find() {
+        Some(dump) => dump.size().to_string(),
+        None => "null".into(),
+    };
+
+    format!(
+        "{{\"reset_reason\":{},\"last_fault\":{},\"coredump_size\":{},\"outages\":{},\"brownouts\":{},\"last_outage\":{},\"mdns\":{},\"app_state\":{}}}",
+        http::json_string(reset_reason()),
+        last_fault,
+        coredump_size,
+        restore::outages(),
+        restore::brownouts(),
+        http::json_string(&restore::last_outage()),
+        mdns::render_json(),
+        app_state::render_json()
+    )
+}
+
+pub struct HeapStats {
+    pub free: u32,
+    pub min_free: u32,
+    pub largest_block: u32,
+}
+
+pub fn heap_stats() -> HeapStats {
+    HeapStats {
+        free: FREE_HEAP.load(Ordering::Relaxed),
+        min_free: MIN_FREE_HEAP.load(Ordering::Relaxed),
+        largest_block: LARGEST_FREE_BLOCK.load(Ordering::Relaxed),
+    }
+}
+
+/// Reads the heap now, refreshing what `heap_stats` returns.
+pub fn sample_heap() -> HeapStats {
+    let stats = unsafe {
+        HeapStats {
+            free: esp_idf_sys::esp_get_free_heap_size(),
+            min_free: esp_idf_sys::esp_get_minimum_free_heap_size(),
+            largest_block: esp_idf_sys::heap_caps_get_largest_free_block(
+                esp_idf_sys::MALLOC_CAP_DEFAULT,
+            ) as u32,
+        }
+    };
+
+    FREE_HEAP.store(stats.free, Ordering::Relaxed);
+    MIN_FREE_HEAP.store(stats.min_free, Ordering::Relaxed);
+    LARGEST_FREE_BLOCK.store(stats.largest_block, Ordering::Relaxed);
+
+    stats
+}
+
+pub fn register_metrics() {
+    metrics::register("heap_free", || heap_stats().free as i64);
+    metrics::register("heap_min_free", || heap_stats().min_free as i64);
+    metrics::register("heap_largest_block", || heap_stats().largest_block as i64);
+    metrics::register("brownouts", || restore::brownouts() as i64);
+}
+
+pub fn heap_monitor() {
+    let watchdog = wdt::subscribe(tasks::HEAP_MONITOR.name);
+
+    loop {
+        let stats = sample_heap();
+        restore::heartbeat();
+
+        if stats.largest_block < config::HEAP_LARGEST_BLOCK_WARN as u32 {
+            warn!(
+                target: logging::DIAG,
+                "Heap fragmented: largest free block {} B (free {} B, minimum {} B)",
+                stats.largest_block, stats.free, stats.min_free
+            );
+        }
+
+        if config::HEAP_RESTART_ENABLED && stats.min_free < config::HEAP_CRITICAL_FLOOR {
+            if !RESTART_SCHEDULED.swap(true, Ordering::Relaxed) {
+                warn!(
+                    target: logging::DIAG,
+                    "Minimum free heap {} B below critical floor {} B, restart scheduled for {:02}:00",
+                    stats.min_free,
+                    config::HEAP_CRITICAL_FLOOR,
+                    config::HEAP_RESTART_HOUR
+                );
+            }
+
+            // Without a synced clock we cannot tell when 3 AM is, so keep waiting
+            if let Some(now) = clock::local_time() {
+                if now.hour == config::HEAP_RESTART_HOUR {
+                    info!(target: logging::DIAG, "Performing scheduled low-memory restart");
+                    system::restart("minimum free heap below critical floor");
+                }
+            }
+        }
+
+        watchdog.sleep(Duration::from_secs(config::HEAP_SAMPLE_INTERVAL_SECS));
+    }
+}
+
+pub struct TaskStack {
+    pub name: &'static str,
+    pub stack_size: u32,
+    /// Smallest amount of stack that has stayed unused since the task started
+    pub high_water_mark: u32,
+}
+
+pub fn task_stacks() -> Vec<TaskStack> {
+    tasks::spawned()
+        .into_iter()
+        .filter_map(|spec| {
+            let name = CString::new(spec.name).ok()?;
+            let handle = unsafe { esp_idf_sys::xTaskGetHandle(name.as_ptr()) };
+            if handle.is_null() {
+                return None;
+            }
+
+            Some(TaskStack {
+                name: spec.name,
+                stack_size: spec.stack_size,
+                high_water_mark: unsafe { esp_idf_sys::uxTaskGetStackHighWaterMark(handle) } as u32,
+            })
+        })
+        .collect()
+}
+
+pub fn log_task_stacks() {
+    for stack in task_stacks() {
+        let used = stack.stack_size.saturating_sub(stack.high_water_mark);
+        if stack.high_water_mark < config::STACK_HEADROOM {
+            warn!(
+                target: logging::DIAG,
+                "Task {}: {} of {} B stack used, only {} B headroom",
+                stack.name, used, stack.stack_size, stack.high_water_mark
+            );
+        } else {
+            info!(
+                target: logging::DIAG,
+                "Task {}: {} of {} B stack used, {} B headroom",
+                stack.name, used, stack.stack_size, stack.high_water_mark
+            );
+        }
+    }
+}
+
+/// The stack size a task's peak so far asks for, as the defaults in config
+/// are derived.
+pub fn derived_stack_size(stack: &TaskStack) -> u32 {
+    let peak = stack.stack_size.saturating_sub(stack.high_water_mark);
+    (peak + config::STACK_HEADROOM + 511) / 512 * 512
+}
+
+pub fn register_commands() {
+    console::register(
+        "tasks",
+        "Show stack usage of the application tasks ('tasks'), or the sizes their peaks \
+         ask for ('tasks sizes')",
+        |args| {
+            match args {
+                [] => {
+                    for stack in task_stacks() {
+                        println!(
+                            "{:<12} {:>6} / {:>6} B used, {:>6} B free",
+                            stack.name,
+                            stack.stack_size.saturating_sub(stack.high_water_mark),
+                            stack.stack_size,
+                            stack.high_water_mark
+                        );
+                    }
+                }
+                ["sizes"] => {
+                    for stack in task_stacks() {
+                        println!(
+                            "{:<12} peak {:>6} B, size {:>6} B, derived {:>6} B",
+                            stack.name,
+                            stack.stack_size.saturating_sub(stack.high_water_mark),
+                            stack.stack_size,
+                            derived_stack_size(&stack)
+                        );
+                    }
+                }
+                _ => bail!("usage: tasks [sizes]"),
+            }
+
+            Ok(())
+        },
+    );
+}
@@ -0,0 +1,280 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use hap_core::guardrail::State;
+use hap_core::pairing_window;
+use hap_core::{Char, CharSlot, ServiceBuilder, Status, Value};
+use spin::Mutex;
+
+use crate::hap_sys::HAP;
+use crate::wifi::{self, LinkInfo};
+use crate::{
+    config, coredump, diag, fault, guardrail, pairing_window as window, restore, selftest, tasks,
+    wdt,
+};
+
+// Custom UUIDs, the SDK keeps the pointers so they have to be 'static
+const SERVICE_UUID: &[u8] = b"0000D1A0-28E5-4C3F-9B6E-5A1D7E3C9000\0";
+const LAST_FAULT_UUID: &[u8] = b"0000D1A1-28E5-4C3F-9B6E-5A1D7E3C9000\0";
+const CORE_DUMP_UUID: &[u8] = b"0000D1A2-28E5-4C3F-9B6E-5A1D7E3C9000\0";
+const RSSI_UUID: &[u8] = b"0000D1A3-28E5-4C3F-9B6E-5A1D7E3C9000\0";
+const BSSID_UUID: &[u8] = b"0000D1A4-28E5-4C3F-9B6E-5A1D7E3C9000\0";
+const RECONNECTS_UUID: &[u8] = b"0000D1A5-28E5-4C3F-9B6E-5A1D7E3C9000\0";
+const IP_UUID: &[u8] = b"0000D1A6-28E5-4C3F-9B6E-5A1D7E3C9000\0";
+const UPTIME_UUID: &[u8] = b"0000D1A7-28E5-4C3F-9B6E-5A1D7E3C9000\0";
+const FREE_HEAP_UUID: &[u8] = b"0000D1A8-28E5-4C3F-9B6E-5A1D7E3C9000\0";
+const MIN_FREE_HEAP_UUID: &[u8] = b"0000D1A9-28E5-4C3F-9B6E-5A1D7E3C9000\0";
+const RESET_REASON_UUID: &[u8] = b"0000D1AA-28E5-4C3F-9B6E-5A1D7E3C9000\0";
+const OUTAGES_UUID: &[u8] = b"0000D1AB-28E5-4C3F-9B6E-5A1D7E3C9000\0";
+const LAST_OUTAGE_UUID: &[u8] = b"0000D1AC-28E5-4C3F-9B6E-5A1D7E3C9000\0";
+const SELF_TEST_UUID: &[u8] = b"0000D1AD-28E5-4C3F-9B6E-5A1D7E3C9000\0";
+const GUARD_STATE_UUID: &[u8] = b"0000D1AE-28E5-4C3F-9B6E-5A1D7E3C9000\0";
+const GUARD_REMAINING_UUID: &[u8] = b"0000D1AF-28E5-4C3F-9B6E-5A1D7E3C9000\0";
+const PAIRING_WINDOW_UUID: &[u8] = b"0000D1B0-28E5-4C3F-9B6E-5A1D7E3C9000\0";
+const PAIRING_REMAINING_UUID: &[u8] = b"0000D1B1-28E5-4C3F-9B6E-5A1D7E3C9000\0";
+
+static LAST_FAULT_CHAR: CharSlot = CharSlot::new();
+static CORE_DUMP_CHAR: CharSlot = CharSlot::new();
+static RSSI_CHAR: CharSlot = CharSlot::new();
+static BSSID_CHAR: CharSlot = CharSlot::new();
+static RECONNECTS_CHAR: CharSlot = CharSlot::new();
+static IP_CHAR: CharSlot = CharSlot::new();
+static UPTIME_CHAR: CharSlot = CharSlot::new();
+static FREE_HEAP_CHAR: CharSlot = CharSlot::new();
+static MIN_FREE_HEAP_CHAR: CharSlot = CharSlot::new();
+static OUTAGES_CHAR: CharSlot = CharSlot::new();
+static LAST_OUTAGE_CHAR: CharSlot = CharSlot::new();
+static GUARD_STATE_CHAR: CharSlot = CharSlot::new();
+static GUARD_REMAINING_CHAR: CharSlot = CharSlot::new();
+static PAIRING_WINDOW_CHAR: CharSlot = CharSlot::new();
+static PAIRING_REMAINING_CHAR: CharSlot = CharSlot::new();
+
+// What controllers were last notified of
+static REPORTED: Mutex<Option<LinkInfo>> = Mutex::new(None);
+
+const READ_ONLY: u16 =
+    (esp_homekit_sdk_sys::HAP_CHAR_PERM_PR | esp_homekit_sdk_sys::HAP_CHAR_PERM_EV) as u16;
+// Without notifications, for values that change on every read
+const READ_ONLY_POLLED: u16 = esp_homekit_sdk_sys::HAP_CHAR_PERM_PR as u16;
+
+pub fn service() -> ServiceBuilder {
+    ServiceBuilder::custom(SERVICE_UUID)
+        .name("Diagnostics")
+        .char(
+            LAST_FAULT_UUID,
+            READ_ONLY,
+            Value::String(fault::last_fault().unwrap_or_default()),
+        )
+        .bind(LAST_FAULT_UUID, &LAST_FAULT_CHAR)
+        .char(
+            CORE_DUMP_UUID,
+            READ_ONLY,
+            Value::Bool(coredump::is_present()),
+        )
+        .bind(CORE_DUMP_UUID, &CORE_DUMP_CHAR)
+        .char(RSSI_UUID, READ_ONLY, Value::Int(0))
+        .bind(RSSI_UUID, &RSSI_CHAR)
+        .char(BSSID_UUID, READ_ONLY, Value::String(String::new()))
+        .bind(BSSID_UUID, &BSSID_CHAR)
+        .char(RECONNECTS_UUID, READ_ONLY, Value::Uint32(0))
+        .bind(RECONNECTS_UUID, &RECONNECTS_CHAR)
+        .char(IP_UUID, READ_ONLY, Value::String(String::new()))
+        .bind(IP_UUID, &IP_CHAR)
+        .char(UPTIME_UUID, READ_ONLY_POLLED, uptime())
+        .bind(UPTIME_UUID, &UPTIME_CHAR)
+        .char(FREE_HEAP_UUID, READ_ONLY_POLLED, free_heap())
+        .bind(FREE_HEAP_UUID, &FREE_HEAP_CHAR)
+        .char(MIN_FREE_HEAP_UUID, READ_ONLY_POLLED, min_free_heap())
+        .bind(MIN_FREE_HEAP_UUID, &MIN_FREE_HEAP_CHAR)
+        // Fixed until the next reset
+        .char(
+            RESET_REASON_UUID,
+            READ_ONLY,
+            Value::String(diag::reset_reason().into()),
+        )
+        .char(OUTAGES_UUID, READ_ONLY_POLLED, outages())
+        .bind(OUTAGES_UUID, &OUTAGES_CHAR)
+        .char(LAST_OUTAGE_UUID, READ_ONLY_POLLED, last_outage())
+        .bind(LAST_OUTAGE_UUID, &LAST_OUTAGE_CHAR)
+        // The self-test runs once, before HAP starts
+        .char(
+            SELF_TEST_UUID,
+            READ_ONLY,
+            Value::String(selftest::summary()),
+        )
+        // OK, limited or cooling down, and the seconds remaining of it
+        .char(GUARD_STATE_UUID, READ_ONLY, guard_state())
+        .valid_values(GUARD_STATE_UUID, State::VALUES)
+        .bind(GUARD_STATE_UUID, &GUARD_STATE_CHAR)
+        .char(GUARD_REMAINING_UUID, READ_ONLY_POLLED, guard_remaining())
+        .bind(GUARD_REMAINING_UUID, &GUARD_REMAINING_CHAR)
+        // Paired, or whether an unpaired accessory takes pairings, and the
+        // seconds until it stops
+        .char(PAIRING_WINDOW_UUID, READ_ONLY, pairing_window())
+        .valid_values(PAIRING_WINDOW_UUID, pairing_window::State::VALUES)
+        .bind(PAIRING_WINDOW_UUID, &PAIRING_WINDOW_CHAR)
+        .char(
+            PAIRING_REMAINING_UUID,
+            READ_ONLY_POLLED,
+            pairing_remaining(),
+        )
+        .bind(PAIRING_REMAINING_UUID, &PAIRING_REMAINING_CHAR)
+        .on_read(&refresh)
+}
+
+fn rssi(link: &LinkInfo) -> Value {
+    Value::Int(link.rssi as i32)
+}
+
+fn bssid(link: &LinkInfo) -> Value {
+    let bssid = link.bssid.map(|b| {
+        format!(
+            "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+            b[0], b[1], b[2], b[3], b[4], b[5]
+        )
+    });
+    Value::String(bssid.unwrap_or_default())
+}
+
+fn ip(link: &LinkInfo) -> Value {
+    Value::String(link.ip.map(|ip| ip.to_string()).unwrap_or_default())
+}
+
+fn reconnects(link: &LinkInfo) -> Value {
+    Value::Uint32(link.reconnects)
+}
+
+type Field = fn(&LinkInfo) -> Value;
+
+// RSSI first, it is notified on larger changes only
+static LINK_CHARS: [(&CharSlot, Field); 4] = [
+    (&RSSI_CHAR, rssi),
+    (&BSSID_CHAR, bssid),
+    (&RECONNECTS_CHAR, reconnects),
+    (&IP_CHAR, ip),
+];
+
+fn uptime() -> Value {
+    Value::Uint32(diag::uptime_secs())
+}
+
+fn free_heap() -> Value {
+    Value::Uint32(diag::sample_heap().free)
+}
+
+fn min_free_heap() -> Value {
+    Value::Uint32(diag::sample_heap().min_free)
+}
+
+fn outages() -> Value {
+    Value::Uint32(restore::outages())
+}
+
+// The length of the outage is only known once the clock synced
+fn last_outage() -> Value {
+    Value::String(restore::last_outage())
+}
+
+fn guard_state() -> Value {
+    Value::Uint8(guardrail::state().0 as u8)
+}
+
+fn guard_remaining() -> Value {
+    Value::Uint32(guardrail::state().1)
+}
+
+fn pairing_window() -> Value {
+    Value::Uint8(window::state().0 as u8)
+}
+
+fn pairing_remaining() -> Value {
+    Value::Uint32(window::state().1)
+}
+
+// Changing all the time, so only refreshed when read
+static SYSTEM_CHARS: [(&CharSlot, fn() -> Value); 9] = [
+    (&UPTIME_CHAR, uptime),
+    (&FREE_HEAP_CHAR, free_heap),
+    (&MIN_FREE_HEAP_CHAR, min_free_heap),
+    (&OUTAGES_CHAR, outages),
+    (&LAST_OUTAGE_CHAR, last_outage),
+    (&GUARD_STATE_CHAR, guard_state),
+    (&GUARD_REMAINING_CHAR, guard_remaining),
+    (&PAIRING_WINDOW_CHAR, pairing_window),
+    (&PAIRING_REMAINING_CHAR, pairing_remaining),
+];
+
+/// Reads get the current state, not the one last notified.
+fn refresh(hc: Char) -> Result<(), Status> {
+    if let Some((_, field)) = LINK_CHARS.iter().find(|(slot, _)| slot.get() == Some(hc)) {
+        HAP.update(hc, &field(&wifi::link_info()));
+    } else if let Some((_, value)) = SYSTEM_CHARS.iter().find(|(slot, _)| slot.get() == Some(hc)) {
+        HAP.update(hc, &value());
+    }
+
+    Ok(())
+}
+
+fn notify_link() {
+    let link = wifi::link_info();
+    let mut reported = REPORTED.lock();
+    let mut next = link;
+
+    // Small drifts add up, so they are measured from the RSSI last sent
+    let rssi_moved = reported.map_or(true, |previous| {
+        let delta = (link.rssi as i32 - previous.rssi as i32).abs();
+        let moved = delta >= config::WIFI_RSSI_NOTIFY_DB || previous.connected != link.connected;
+        if !moved {
+            next.rssi = previous.rssi;
+        }
+        moved
+    });
+    if let (true, Some(hc)) = (rssi_moved, RSSI_CHAR.get()) {
+        HAP.update(hc, &rssi(&link));
+    }
+
+    for (slot, field) in &LINK_CHARS[1..] {
+        let changed = reported.map_or(true, |previous| field(&previous) != field(&link));
+        if let (true, Some(hc)) = (changed, slot.get()) {
+            HAP.update(hc, &field(&link));
+        }
+    }
+
+    *reported = Some(next);
+}
+
+/// Samples the link every `WIFI_RSSI_INTERVAL_SECS`, notifying controllers
+/// of new values and of RSSI changes of `WIFI_RSSI_NOTIFY_DB` or more.
+pub fn start_link_monitor() -> Result<()> {
+    tasks::spawn(&tasks::WIFI_MONITOR, || {
+        let watchdog = wdt::subscribe(tasks::WIFI_MONITOR.name);
+        loop {
+            notify_link();
+            watchdog.sleep(Duration::from_secs(config::WIFI_RSSI_INTERVAL_SECS));
+        }
+    })
+}
+
+pub fn update_last_fault(value: &str) {
+    if let Some(hc) = LAST_FAULT_CHAR.get() {
+        HAP.update(hc, &Value::String(value.into()));
+    }
+}
+
+pub fn update_core_dump(present: bool) {
+    if let Some(hc) = CORE_DUMP_CHAR.get() {
+        HAP.update(hc, &Value::Bool(present));
+    }
+}
+
+pub fn update_guard_state(state: State) {
+    if let Some(hc) = GUARD_STATE_CHAR.get() {
+        HAP.update(hc, &Value::Uint8(state as u8));
+    }
+}
+
+pub fn update_pairing_window(state: pairing_window::State) {
+    if let Some(hc) = PAIRING_WINDOW_CHAR.get() {
+        HAP.update(hc, &Value::Uint8(state as u8));
+    }
+}
@@ -0,0 +1,416 @@
+use std::ptr;
+use std::sync::atomic::{AtomicI32, AtomicI64, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, Result};
+use embedded_hal::digital::v2::OutputPin;
+use esp_idf_sys::c_types::c_void;
+use hap_core::distance::{self, DoorBands, DoorState, DoorTracker, Ping, Presence};
+use hap_core::sys::{perm, uuid};
+use hap_core::{CharSlot, ServiceBuilder, Status, Value, Write};
+use log::{info, warn};
+use spin::{Mutex, Once};
+
+use crate::board::{self, AnyInputPin, AnyOutputPin, Pull};
+use crate::hap_sys::HAP;
+use crate::{button, config, console, diag, logging, tasks, wdt};
+
+// Apple's Garage Door Opener and Occupancy Sensor
+const GARAGE_DOOR_UUID: &[u8] = b"41\0";
+const CURRENT_DOOR_UUID: &[u8] = b"0E\0";
+const TARGET_DOOR_UUID: &[u8] = b"32\0";
+const OBSTRUCTION_UUID: &[u8] = b"24\0";
+const OCCUPANCY_SENSOR_UUID: &[u8] = b"86\0";
+const OCCUPANCY_UUID: &[u8] = b"71\0";
+
+const TRIGGER_PULSE_US: u32 = 10;
+// The sensors want 60 ms between pings, so late echoes die down
+const PING_GAP: Duration = Duration::from_millis(60);
+const ECHO_TIMEOUT: Duration = Duration::from_millis(60);
+// Waiting in ticks, the ISR timestamps carry the timing
+const ECHO_POLL: Duration = Duration::from_millis(5);
+
+// The task feeds the watchdog once per interval
+const _: () = assert!(
+    config::DISTANCE_INTERVAL_MS < config::TASK_WDT_TIMEOUT_SECS as u64 * 1000 / 2,
+    "distance interval"
+);
+
+const READ_ONLY: u16 = perm::PR | perm::EV;
+
+static RISE_US: AtomicI64 = AtomicI64::new(0);
+static FALL_US: AtomicI64 = AtomicI64::new(0);
+static AIR_TEMP_C: AtomicI32 = AtomicI32::new(config::DISTANCE_AIR_TEMP_C);
+
+struct State {
+    measurement: Option<Ping>,
+    door: DoorTracker,
+    car: Presence,
+    target: DoorState,
+    /// The target of a move the opener was triggered for, and when
+    moving: Option<(DoorState, u32)>,
+    obstructed: bool,
+    reported: Option<Reported>,
+}
+
+/// What was last notified.
+#[derive(Clone, Copy)]
+struct Reported {
+    current: DoorState,
+    target: DoorState,
+    obstructed: bool,
+    present: bool,
+    fault: bool,
+}
+
+static STATE: Mutex<State> = Mutex::new(State {
+    measurement: None,
+    door: DoorTracker::new(DoorBands {
+        open_within: config::GARAGE_OPEN_WITHIN_CM as f32,
+        closed_beyond: config::GARAGE_CLOSED_BEYOND_CM as f32,
+        hysteresis: config::GARAGE_HYSTERESIS_CM as f32,
+    }),
+    car: Presence::new(
+        config::CAR_PRESENT_WITHIN_CM as f32,
+        config::CAR_HYSTERESIS_CM as f32,
+    ),
+    target: DoorState::Closed,
+    moving: None,
+    obstructed: false,
+    reported: None,
+});
+static COMMANDS: Once<Mutex<SyncSender<()>>> = Once::new();
+
+static CURRENT_DOOR_CHAR: CharSlot = CharSlot::new();
+static TARGET_DOOR_CHAR: CharSlot = CharSlot::new();
+static OBSTRUCTION_CHAR: CharSlot = CharSlot::new();
+static OCCUPANCY_CHAR: CharSlot = CharSlot::new();
+static SENSOR_FAULT_CHAR: CharSlot = CharSlot::new();
+
+impl State {
+    /// The door as HomeKit sees it: moving towards the target until the
+    /// distance says it arrived, or until the travel time is up.
+    fn current(&mut self) -> DoorState {
+        let door = self.door.state();
+        match self.moving {
+            Some((target, since)) if door != target => {
+                if diag::uptime_secs() < since + config::GARAGE_TRAVEL_SECS {
+                    return match target {
+                        DoorState::Open => DoorState::Opening,
+                        _ => DoorState::Closing,
+                    };
+                }
+                warn!(target: logging::DISTANCE, "Door did not reach {:?} in time", target);
+                self.obstructed = true;
+            }
+            Some(_) => self.obstructed = false,
+            None => {}
+        }
+        self.moving = None;
+
+        // Moved by the wall button or the remote, the target follows
+        if matches!(door, DoorState::Open | DoorState::Closed) {
+            self.target = door;
+        }
+
+        door
+    }
+
+    fn update(&mut self, measurement: Ping) {
+        if self.measurement != Some(measurement) {
+            match measurement {
+                Ping::Echo(_) => {}
+                Ping::OutOfRange => {
+                    info!(target: logging::DISTANCE, "Nothing within range, holding the state")
+                }
+                Ping::NoEcho => {
+                    warn!(target: logging::DISTANCE, "No echo, is the sensor connected?")
+                }
+            }
+        }
+        self.measurement = Some(measurement);
+
+        let Ping::Echo(cm) = measurement else {
+            return;
+        };
+        let door = self.door.update(cm);
+        // The open door hides the car
+        if !(config::GARAGE_DOOR_ENABLED && door == DoorState::Open) {
+            self.car.update(cm);
+        }
+    }
+
+    fn changes(&mut self) -> Vec<(&'static CharSlot, Value)> {
+        let now = Reported {
+            current: self.current(),
+            target: self.target,
+            obstructed: self.obstructed,
+            present: self.car.is_present(),
+            fault: self.measurement == Some(Ping::NoEcho),
+        };
+        let last = self.reported.replace(now);
+
+        let fields: [(&'static CharSlot, fn(&Reported) -> Value); 5] = [
+            (&CURRENT_DOOR_CHAR, |r| Value::Uint8(r.current as u8)),
+            (&TARGET_DOOR_CHAR, |r| Value::Uint8(r.target as u8)),
+            (&OBSTRUCTION_CHAR, |r| Value::Bool(r.obstructed)),
+            (&OCCUPANCY_CHAR, |r| Value::Uint8(r.present as u8)),
+            (&SENSOR_FAULT_CHAR, |r| Value::Uint8(r.fault as u8)),
+        ];
+        fields
+            .into_iter()
+            .filter(|(_, field)| last.as_ref().map(field) != Some(field(&now)))
+            .map(|(slot, field)| (slot, field(&now)))
+            .collect()
+    }
+}
+
+fn notify(changes: Vec<(&'static CharSlot, Value)>) {
+    for (slot, value) in changes {
+        if let Some(hc) = slot.get() {
+            HAP.update(hc, &value);
+        }
+    }
+}
+
+unsafe extern "C" fn on_echo(_: *mut c_void) {
+    let now = esp_idf_sys::esp_timer_get_time();
+    if esp_idf_sys::gpio_get_level(board::ULTRASONIC_ECHO_GPIO) != 0 {
+        RISE_US.store(now, Ordering::Relaxed);
+    } else {
+        FALL_US.store(now, Ordering::Relaxed);
+    }
+}
+
+/// One ping: the width of the echo pulse, or none within the timeout.
+fn echo(trigger: &mut AnyOutputPin) -> Result<Option<u32>> {
+    RISE_US.store(0, Ordering::Relaxed);
+    FALL_US.store(0, Ordering::Relaxed);
+    trigger.set_high()?;
+    unsafe { esp_idf_sys::ets_delay_us(TRIGGER_PULSE_US) };
+    trigger.set_low()?;
+
+    let sent = Instant::now();
+    while sent.elapsed() < ECHO_TIMEOUT {
+        thread::sleep(ECHO_POLL);
+        let (rise, fall) = (
+            RISE_US.load(Ordering::Relaxed),
+            FALL_US.load(Ordering::Relaxed),
+        );
+        if rise != 0 && fall > rise {
+            return Ok(Some((fall - rise) as u32));
+        }
+    }
+
+    Ok(None)
+}
+
+fn measure(trigger: &mut AnyOutputPin) -> Result<Ping> {
+    let celsius = AIR_TEMP_C.load(Ordering::Relaxed) as f32;
+    let mut pings = Vec::with_capacity(config::DISTANCE_PINGS);
+    for _ in 0..config::DISTANCE_PINGS {
+        let echo = echo(trigger)?;
+        pings.push(distance::ping(echo, celsius, config::DISTANCE_MAX_ECHO_US));
+        thread::sleep(PING_GAP);
+    }
+
+    Ok(distance::median(&pings))
+}
+
+fn press_opener(opener: &mut AnyOutputPin) -> Result<()> {
+    info!(target: logging::DISTANCE, "Pressing the door opener button");
+    opener.set_high()?;
+    thread::sleep(Duration::from_millis(config::GARAGE_OPENER_PULSE_MS));
+    opener.set_low()?;
+
+    Ok(())
+}
+
+fn distance_handler(
+    mut trigger: AnyOutputPin,
+    mut opener: Option<AnyOutputPin>,
+    commands: Receiver<()>,
+) {
+    let watchdog = wdt::subscribe(tasks::DISTANCE.name);
+    let interval = Duration::from_millis(config::DISTANCE_INTERVAL_MS);
+
+    loop {
+        match measure(&mut trigger) {
+            Ok(measurement) => STATE.lock().update(measurement),
+            Err(err) => warn!(target: logging::DISTANCE, "Ping failed: {:?}", err),
+        }
+        let changes = STATE.lock().changes();
+        notify(changes);
+
+        watchdog.feed();
+        match commands.recv_timeout(interval) {
+            Ok(()) => {
+                if let Some(opener) = opener.as_mut() {
+                    if let Err(err) = press_opener(opener) {
+                        warn!(target: logging::DISTANCE, "Triggering the opener failed: {:?}", err);
+                    }
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+    }
+}
+
+/// Sets up the sensor, and the opener output for the garage door, and
+/// starts measuring on a task of its own, so waiting for echoes never holds
+/// up HAP.
+pub fn init() -> Result<()> {
+    let trigger = AnyOutputPin::new(board::ULTRASONIC_TRIG_GPIO)?;
+    let echo = AnyInputPin::new(board::ULTRASONIC_ECHO_GPIO, Pull::Down)?;
+    button::install_isr_service()?;
+    echo.on_edges(on_echo, ptr::null_mut())?;
+
+    let opener = if config::GARAGE_DOOR_ENABLED {
+        Some(AnyOutputPin::new(board::GARAGE_OPENER_GPIO)?)
+    } else {
+        None
+    };
+
+    let (sender, receiver) = mpsc::sync_channel(1);
+    COMMANDS.call_once(|| Mutex::new(sender));
+    info!(
+        target: logging::DISTANCE,
+        "Ultrasonic sensor on GPIO{}/{}, measuring every {} ms",
+        board::ULTRASONIC_TRIG_GPIO,
+        board::ULTRASONIC_ECHO_GPIO,
+        config::DISTANCE_INTERVAL_MS
+    );
+
+    tasks::spawn(&tasks::DISTANCE, move || {
+        distance_handler(trigger, opener, receiver)
+    })
+}
+
+/// Target door state: the opener's button is pressed when the door is not
+/// already there.
+fn on_write(write: &Write) -> Result<(), Status> {
+    if Some(write.hc) != TARGET_DOOR_CHAR.get() {
+        return Err(Status::ResourceAbsent);
+    }
+    let target = match write.value.as_u32() {
+        Some(0) => DoorState::Open,
+        Some(1) => DoorState::Closed,
+        _ => return Err(Status::InvalidValue),
+    };
+
+    command(target)
+}
+
+/// Opens the garage door for a local control, notified as if from HomeKit.
+pub fn open() -> Result<()> {
+    if !config::GARAGE_DOOR_ENABLED {
+        bail!("no garage door");
+    }
+
+    command(DoorState::Open).map_err(|status| anyhow!("the opener is busy ({:?})", status))
+}
+
+fn command(target: DoorState) -> Result<(), Status> {
+    let mut state = STATE.lock();
+    if state.door.state() != target {
+        let sent = COMMANDS
+            .get()
+            .map_or(false, |commands| commands.lock().try_send(()).is_ok());
+        if !sent {
+            return Err(Status::ResourceBusy);
+        }
+        state.moving = Some((target, diag::uptime_secs()));
+    }
+    state.target = target;
+    let changes = state.changes();
+    drop(state);
+
+    notify(changes);
+
+    Ok(())
+}
+
+/// A Garage Door Opener and an Occupancy Sensor for the car, as enabled.
+pub fn services() -> Result<Vec<ServiceBuilder>> {
+    if COMMANDS.get().is_none() {
+        bail!("the sensor is not set up");
+    }
+
+    let mut state = STATE.lock();
+    let current = state.current();
+    let mut services = Vec::new();
+
+    if config::GARAGE_DOOR_ENABLED {
+        services.push(
+            ServiceBuilder::custom(GARAGE_DOOR_UUID)
+                .name("Garage Door")
+                .char(CURRENT_DOOR_UUID, READ_ONLY, Value::Uint8(current as u8))
+                .char(
+                    TARGET_DOOR_UUID,
+                    perm::PR | perm::PW | perm::EV,
+                    Value::Uint8(state.target as u8),
+                )
+                .char(OBSTRUCTION_UUID, READ_ONLY, Value::Bool(state.obstructed))
+                .valid_values(CURRENT_DOOR_UUID, &[0, 1, 2, 3, 4])
+                .valid_values(TARGET_DOOR_UUID, &[0, 1])
+                .bind(CURRENT_DOOR_UUID, &CURRENT_DOOR_CHAR)
+                .bind(TARGET_DOOR_UUID, &TARGET_DOOR_CHAR)
+                .bind(OBSTRUCTION_UUID, &OBSTRUCTION_CHAR)
+                .on_write(&on_write),
+        );
+    }
+    if config::CAR_PRESENCE_ENABLED {
+        services.push(
+            ServiceBuilder::custom(OCCUPANCY_SENSOR_UUID)
+                .name("Car")
+                .status_fault()
+                .char(
+                    OCCUPANCY_UUID,
+                    READ_ONLY,
+                    Value::Uint8(state.car.is_present() as u8),
+                )
+                .bind(OCCUPANCY_UUID, &OCCUPANCY_CHAR)
+                .bind(uuid::STATUS_FAULT, &SENSOR_FAULT_CHAR),
+        );
+    }
+
+    Ok(services)
+}
+
+pub fn register_commands() {
+    console::register(
+        "distance",
+        "Show the last measurement ('distance'), or set the air temperature for the speed of sound ('distance temp <C>')",
+        |args| match args {
+            [] => {
+                let mut state = STATE.lock();
+                match state.measurement {
+                    Some(Ping::Echo(cm)) => println!("{:.1} cm", cm),
+                    Some(Ping::OutOfRange) => println!("Nothing within range"),
+                    Some(Ping::NoEcho) => println!("No echo"),
+                    None => println!("Not measured yet"),
+                }
+                println!(
+                    "Door {:?}{}, car {}, {} °C",
+                    state.current(),
+                    if state.obstructed { " (obstructed)" } else { "" },
+                    if state.car.is_present() { "present" } else { "absent" },
+                    AIR_TEMP_C.load(Ordering::Relaxed)
+                );
+                Ok(())
+            }
+            ["temp", celsius] => {
+                let celsius: i32 = celsius.parse()?;
+                if !(-40..=80).contains(&celsius) {
+                    bail!("{} °C is outside the sensors' range", celsius);
+                }
+                AIR_TEMP_C.store(celsius, Ordering::Relaxed);
+                Ok(())
+            }
+            _ => bail!("usage: distance [temp <C>]"),
+        },
+    );
+}
@@ -0,0 +1,764 @@
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use esp_idf_sys::c_types::c_void;
+use esp_idf_sys::esp;
+use hap_core::deferred::Deferred;
+use hap_core::position::{self, Calibration, Drive, Fault, Mover, PositionState, Tilt};
+use hap_core::sys::perm;
+use hap_core::{Bounds, CharSlot, ServiceBuilder, Status, Value, Write};
+use log::{info, warn};
+use spin::{Mutex, Once};
+
+use crate::board::{self, AnyInputPin, AnyOutputPin, Pull};
+use crate::config::{self, Covering, DoorSensor, TiltSource};
+use crate::hap_sys::HAP;
+use crate::{button, console, logging, nvs, system, tasks, wdt};
+
+// Apple's Door and Window Covering
+const DOOR_UUID: &[u8] = b"81\0";
+const WINDOW_COVERING_UUID: &[u8] = b"8C\0";
+const CURRENT_POSITION_UUID: &[u8] = b"6D\0";
+const TARGET_POSITION_UUID: &[u8] = b"7C\0";
+const POSITION_STATE_UUID: &[u8] = b"72\0";
+const OBSTRUCTION_UUID: &[u8] = b"24\0";
+const CURRENT_TILT_UUID: &[u8] = b"6C\0";
+const TARGET_TILT_UUID: &[u8] = b"7B\0";
+
+const READ_ONLY: u16 = perm::PR | perm::EV;
+
+const NAMESPACE: &str = nvs::DOOR;
+
+const PWM_TIMER: esp_idf_sys::ledc_timer_t = esp_idf_sys::ledc_timer_t_LEDC_TIMER_0;
+const PWM_CHANNEL: esp_idf_sys::ledc_channel_t = esp_idf_sys::ledc_channel_t_LEDC_CHANNEL_0;
+const PWM_MODE: esp_idf_sys::ledc_mode_t = esp_idf_sys::ledc_mode_t_LEDC_LOW_SPEED_MODE;
+const PWM_BITS: u32 = 10;
+
+const SERVO_TIMER: esp_idf_sys::ledc_timer_t = esp_idf_sys::ledc_timer_t_LEDC_TIMER_1;
+const SERVO_CHANNEL: esp_idf_sys::ledc_channel_t = esp_idf_sys::ledc_channel_t_LEDC_CHANNEL_1;
+const SERVO_HZ: u32 = 50;
+const SERVO_BITS: u32 = 14;
+
+const PCNT_UNIT: esp_idf_sys::pcnt_unit_t = esp_idf_sys::pcnt_unit_t_PCNT_UNIT_0;
+// The counter wraps to 0 at either limit; polled far more often than it
+// could travel half of it
+const PCNT_LIMIT: i16 = 30_000;
+// Glitches shorter than this many APB cycles are ignored
+const PCNT_FILTER: u16 = 100;
+
+// The task feeds the watchdog once per poll
+const _: () = assert!(
+    config::DOOR_POLL_MS < config::TASK_WDT_TIMEOUT_SECS as u64 * 1000 / 2,
+    "door poll interval"
+);
+
+/// Set by the edge ISR, which already cut the motor.
+static EDGE_TRIPPED: AtomicBool = AtomicBool::new(false);
+/// The encoder count, extended beyond the 16-bit PCNT counter.
+static ENCODER_COUNT: AtomicI32 = AtomicI32::new(0);
+
+// Written targets, applied by the door task
+static TARGET_POSITION: Deferred<u8> = Deferred::new();
+static TARGET_TILT: Deferred<i8> = Deferred::new();
+
+struct State {
+    mover: Mover,
+    calibration: Calibration,
+    raw: i32,
+    /// Driving the other way after an obstruction, until then
+    back_off: Option<(Drive, u64)>,
+    tilt: Tilt,
+    /// Turning the slats with the lift motor, until then
+    pulse: Option<(Drive, u64)>,
+    /// No tilt before then, so a write of lift and tilt together finishes
+    /// the lift first
+    tilt_after: u64,
+    fault: Option<Fault>,
+    reported: Option<Reported>,
+}
+
+/// What was last notified.
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Reported {
+    position: u8,
+    target: u8,
+    state: PositionState,
+    obstructed: bool,
+    tilt: i8,
+    tilt_target: i8,
+}
+
+static STATE: Mutex<State> = Mutex::new(State {
+    mover: Mover::new(
+        config::DOOR_TOLERANCE_PERCENT,
+        config::DOOR_STALL_MS,
+        config::DOOR_TRAVEL_SECS * 1000,
+    ),
+    calibration: Calibration {
+        closed: 0,
+        open: 4095,
+    },
+    raw: 0,
+    back_off: None,
+    tilt: Tilt::new(config::TILT_FULL_MS as u64),
+    pulse: None,
+    tilt_after: 0,
+    fault: None,
+    reported: None,
+});
+static STORE: Once<nvs::Namespace> = Once::new();
+static EDGE: Once<AnyInputPin> = Once::new();
+
+static CURRENT_POSITION_CHAR: CharSlot = CharSlot::new();
+static TARGET_POSITION_CHAR: CharSlot = CharSlot::new();
+static POSITION_STATE_CHAR: CharSlot = CharSlot::new();
+static OBSTRUCTION_CHAR: CharSlot = CharSlot::new();
+static CURRENT_TILT_CHAR: CharSlot = CharSlot::new();
+static TARGET_TILT_CHAR: CharSlot = CharSlot::new();
+
+type Changes = Vec<(&'static CharSlot, Value)>;
+
+fn store() -> Result<&'static nvs::Namespace> {
+    STORE.try_call_once(|| nvs::Namespace::open(NAMESPACE))
+}
+
+fn save(f: impl FnOnce(&nvs::Namespace) -> Result<()>) {
+    let result = store().and_then(|store| {
+        f(store)?;
+        Ok(store.commit()?)
+    });
+    if let Err(err) = result {
+        warn!(target: logging::DOOR, "Storing the door settings failed: {:?}", err);
+    }
+}
+
+fn notify(changes: Changes) {
+    for (slot, value) in changes {
+        if let Some(hc) = slot.get() {
+            HAP.update(hc, &value);
+        }
+    }
+}
+
+fn now_ms() -> u64 {
+    (unsafe { esp_idf_sys::esp_timer_get_time() } / 1000) as u64
+}
+
+fn edge_active() -> bool {
+    unsafe { esp_idf_sys::gpio_get_level(board::DOOR_EDGE_GPIO) == 0 }
+}
+
+/// Cuts the direction inputs only, which brakes the motor and is all an ISR
+/// may do; the task then sets the PWM to zero.
+fn cut_motor() {
+    unsafe {
+        esp_idf_sys::gpio_set_level(board::DOOR_MOTOR_IN1_GPIO, 0);
+        esp_idf_sys::gpio_set_level(board::DOOR_MOTOR_IN2_GPIO, 0);
+    }
+}
+
+unsafe extern "C" fn on_edge(_: *mut c_void) {
+    if edge_active() {
+        cut_motor();
+        EDGE_TRIPPED.store(true, Ordering::Relaxed);
+    }
+}
+
+fn set_motor(drive: Drive) -> Result<()> {
+    let (in1, in2, duty) = match drive {
+        Drive::Stop => (0, 0, 0),
+        Drive::Open => (1, 0, config::DOOR_DUTY_PERCENT.min(100)),
+        Drive::Close => (0, 1, config::DOOR_DUTY_PERCENT.min(100)),
+    };
+    // The direction settles before the motor is powered
+    cut_motor();
+    esp!(unsafe {
+        esp_idf_sys::ledc_set_duty(PWM_MODE, PWM_CHANNEL, duty * ((1 << PWM_BITS) - 1) / 100)
+    })?;
+    esp!(unsafe { esp_idf_sys::ledc_update_duty(PWM_MODE, PWM_CHANNEL) })?;
+    esp!(unsafe { esp_idf_sys::gpio_set_level(board::DOOR_MOTOR_IN1_GPIO, in1) })?;
+    esp!(unsafe { esp_idf_sys::gpio_set_level(board::DOOR_MOTOR_IN2_GPIO, in2) })?;
+
+    Ok(())
+}
+
+fn set_servo(angle: i8) -> Result<()> {
+    let pulse_us = position::servo_pulse_us(angle, config::SERVO_MIN_US, config::SERVO_MAX_US);
+    let duty = pulse_us * SERVO_HZ * (1 << SERVO_BITS) / 1_000_000;
+    esp!(unsafe { esp_idf_sys::ledc_set_duty(PWM_MODE, SERVO_CHANNEL, duty) })?;
+    esp!(unsafe { esp_idf_sys::ledc_update_duty(PWM_MODE, SERVO_CHANNEL) })?;
+
+    Ok(())
+}
+
+fn read_pot() -> Result<i32> {
+    let mut sum = 0;
+    for _ in 0..config::DOOR_ADC_SAMPLES {
+        let raw = unsafe { esp_idf_sys::adc1_get_raw(board::DOOR_ADC_CHANNEL) };
+        if raw < 0 {
+            bail!("reading ADC1 channel {} failed", board::DOOR_ADC_CHANNEL);
+        }
+        sum += raw;
+    }
+
+    Ok(sum / config::DOOR_ADC_SAMPLES as i32)
+}
+
+/// Adds the PCNT counter's change since the last read to the extended count.
+fn read_encoder(last: &mut i16) -> Result<i32> {
+    let mut counter = 0;
+    esp!(unsafe { esp_idf_sys::pcnt_get_counter_value(PCNT_UNIT, &mut counter) })?;
+    let mut delta = counter as i32 - *last as i32;
+    *last = counter;
+    if delta > PCNT_LIMIT as i32 / 2 {
+        delta -= PCNT_LIMIT as i32;
+    } else if delta < -(PCNT_LIMIT as i32) / 2 {
+        delta += PCNT_LIMIT as i32;
+    }
+
+    Ok(ENCODER_COUNT.fetch_add(delta, Ordering::Relaxed) + delta)
+}
+
+fn read_raw(last: &mut i16) -> Result<i32> {
+    match config::DOOR_SENSOR {
+        DoorSensor::Pot => read_pot(),
+        DoorSensor::Encoder => read_encoder(last),
+    }
+}
+
+impl State {
+    fn set_target(&mut self, target: u8) -> Changes {
+        let now = now_ms();
+        // The lift turns the slats over anyway
+        self.pulse = None;
+        self.mover.set_target(target, now);
+        self.tilt_after = now + config::TILT_SETTLE_MS;
+        self.fault = None;
+        self.changes()
+    }
+
+    fn set_tilt(&mut self, angle: i8) -> Changes {
+        self.tilt.set_target(angle);
+        self.tilt_after = now_ms() + config::TILT_SETTLE_MS;
+        self.changes()
+    }
+
+    fn reported(&self) -> Reported {
+        Reported {
+            position: self.mover.position(),
+            target: self.mover.target(),
+            state: self.mover.drive().state(),
+            obstructed: self.mover.is_obstructed(),
+            tilt: self.tilt.current(),
+            tilt_target: self.tilt.target(),
+        }
+    }
+
+    fn changes(&mut self) -> Changes {
+        let now = self.reported();
+        let last = self.reported.replace(now);
+
+        let fields: [(&'static CharSlot, fn(&Reported) -> Value); 6] = [
+            (&CURRENT_POSITION_CHAR, |r| Value::Uint8(r.position)),
+            (&TARGET_POSITION_CHAR, |r| Value::Uint8(r.target)),
+            (&POSITION_STATE_CHAR, |r| Value::Uint8(r.state as u8)),
+            (&OBSTRUCTION_CHAR, |r| Value::Bool(r.obstructed)),
+            (&CURRENT_TILT_CHAR, |r| Value::Int(r.tilt as i32)),
+            (&TARGET_TILT_CHAR, |r| Value::Int(r.tilt_target as i32)),
+        ];
+        fields
+            .into_iter()
+            .filter(|(_, field)| last.as_ref().map(field) != Some(field(&now)))
+            .map(|(slot, field)| (slot, field(&now)))
+            .collect()
+    }
+
+    /// Only the end of a move is stored, the position changes too often.
+    fn store_position(&self) {
+        let (position, raw, tilt) = (self.mover.position(), self.raw, self.tilt.current());
+        save(|store| {
+            store.set_u8("position", position)?;
+            store.set_u32("raw", raw as u32)?;
+            store.set_u8("tilt", tilt as u8)
+        });
+    }
+
+    /// Takes a reading, and whether the edge tripped since the last one, and
+    /// tells how to drive the motor.
+    fn update(&mut self, raw: i32, tripped: bool, now: u64) -> Drive {
+        let previous = self.mover.drive();
+        let position = self.calibration.percent(raw);
+        self.raw = raw;
+
+        if let Some((drive, until)) = self.pulse {
+            if now < until && !tripped {
+                return drive;
+            }
+            self.pulse = None;
+            self.mover.settle(position);
+            self.store_position();
+        }
+
+        if tripped {
+            let back_off = self.mover.obstruct();
+            warn!(target: logging::DOOR, "Safety edge tripped, stopping");
+            if back_off != Drive::Stop {
+                self.back_off = Some((back_off, now + config::DOOR_BACK_OFF_MS));
+            }
+        }
+
+        let (drive, fault) = self.mover.update(position, now);
+        if let Some(fault) = fault {
+            warn!(
+                target: logging::DOOR,
+                "Motor stopped at {} %: {:?}",
+                self.mover.position(),
+                fault
+            );
+        }
+        if fault.is_some() {
+            self.fault = fault;
+        }
+
+        if previous != Drive::Stop && drive == Drive::Stop {
+            if config::DOOR_TILT != TiltSource::None {
+                self.tilt.lifted(previous);
+            }
+            self.store_position();
+        }
+
+        match self.back_off {
+            Some((back_off, until)) if now < until => return back_off,
+            Some(_) => self.back_off = None,
+            None => {}
+        }
+
+        if drive == Drive::Stop && !self.mover.is_obstructed() && now >= self.tilt_after {
+            if let Some(pulse) = self.turn_slats(now) {
+                return pulse;
+            }
+        }
+
+        drive
+    }
+
+    /// Brings the slats to their target, returning the drive for timed tilt;
+    /// the servo is set by the task.
+    fn turn_slats(&mut self, now: u64) -> Option<Drive> {
+        if config::DOOR_TILT == TiltSource::None {
+            return None;
+        }
+        let (drive, ms) = self.tilt.pulse()?;
+        info!(target: logging::DOOR, "Tilting the slats to {}°", self.tilt.current());
+        if config::DOOR_TILT == TiltSource::Servo {
+            self.store_position();
+            return None;
+        }
+
+        self.pulse = Some((drive, now + ms));
+        Some(drive)
+    }
+}
+
+fn door_handler() {
+    let watchdog = wdt::subscribe(tasks::DOOR.name);
+    let mut last_count = 0;
+    let mut driving = Drive::Stop;
+    let mut servo = None;
+
+    loop {
+        // The ISR cut the motor already
+        let tripped = EDGE_TRIPPED.swap(false, Ordering::Relaxed);
+        if tripped {
+            driving = Drive::Stop;
+        }
+
+        match read_raw(&mut last_count) {
+            Ok(raw) => {
+                let mut state = STATE.lock();
+                if let Some(target) = TARGET_POSITION.take() {
+                    state.set_target(target);
+                }
+                if let Some(angle) = TARGET_TILT.take() {
+                    state.set_tilt(angle);
+                }
+                let drive = state.update(raw, tripped, now_ms());
+                let angle = state.tilt.current();
+                let changes = state.changes();
+                drop(state);
+
+                if config::DOOR_TILT == TiltSource::Servo && servo != Some(angle) {
+                    match set_servo(angle) {
+                        Ok(()) => servo = Some(angle),
+                        Err(err) => {
+                            warn!(target: logging::DOOR, "Setting the servo failed: {:?}", err)
+                        }
+                    }
+                }
+
+                if drive != driving {
+                    match set_motor(drive) {
+                        Ok(()) => driving = drive,
+                        Err(err) => {
+                            warn!(target: logging::DOOR, "Driving the motor failed: {:?}", err)
+                        }
+                    }
+                }
+                notify(changes);
+            }
+            Err(err) => {
+                // No position, no move
+                cut_motor();
+                driving = Drive::Stop;
+                warn!(target: logging::DOOR, "Reading the position failed: {:?}", err);
+            }
+        }
+
+        watchdog.sleep(Duration::from_millis(config::DOOR_POLL_MS));
+    }
+}
+
+fn init_pwm() -> Result<()> {
+    let mut timer: esp_idf_sys::ledc_timer_config_t = unsafe { std::mem::zeroed() };
+    timer.speed_mode = PWM_MODE;
+    timer.timer_num = PWM_TIMER;
+    timer.freq_hz = config::DOOR_PWM_HZ;
+    timer.clk_cfg = esp_idf_sys::ledc_clk_cfg_t_LEDC_AUTO_CLK;
+    timer.__bindgen_anon_1.duty_resolution = PWM_BITS;
+    esp!(unsafe { esp_idf_sys::ledc_timer_config(&timer) })?;
+
+    let mut channel: esp_idf_sys::ledc_channel_config_t = unsafe { std::mem::zeroed() };
+    channel.gpio_num = board::DOOR_MOTOR_EN_GPIO;
+    channel.speed_mode = PWM_MODE;
+    channel.channel = PWM_CHANNEL;
+    channel.timer_sel = PWM_TIMER;
+    channel.duty = 0;
+    esp!(unsafe { esp_idf_sys::ledc_channel_config(&channel) })?;
+
+    Ok(())
+}
+
+fn init_servo() -> Result<()> {
+    let mut timer: esp_idf_sys::ledc_timer_config_t = unsafe { std::mem::zeroed() };
+    timer.speed_mode = PWM_MODE;
+    timer.timer_num = SERVO_TIMER;
+    timer.freq_hz = SERVO_HZ;
+    timer.clk_cfg = esp_idf_sys::ledc_clk_cfg_t_LEDC_AUTO_CLK;
+    timer.__bindgen_anon_1.duty_resolution = SERVO_BITS;
+    esp!(unsafe { esp_idf_sys::ledc_timer_config(&timer) })?;
+
+    let mut channel: esp_idf_sys::ledc_channel_config_t = unsafe { std::mem::zeroed() };
+    channel.gpio_num = board::TILT_SERVO_GPIO;
+    channel.speed_mode = PWM_MODE;
+    channel.channel = SERVO_CHANNEL;
+    channel.timer_sel = SERVO_TIMER;
+    channel.duty = 0;
+    esp!(unsafe { esp_idf_sys::ledc_channel_config(&channel) })?;
+
+    Ok(())
+}
+
+/// Counts both edges of A, up or down by the level of B.
+fn init_encoder() -> Result<()> {
+    let mut pcnt: esp_idf_sys::pcnt_config_t = unsafe { std::mem::zeroed() };
+    pcnt.pulse_gpio_num = board::DOOR_ENCODER_GPIO_A;
+    pcnt.ctrl_gpio_num = board::DOOR_ENCODER_GPIO_B;
+    pcnt.channel = esp_idf_sys::pcnt_channel_t_PCNT_CHANNEL_0;
+    pcnt.unit = PCNT_UNIT;
+    pcnt.pos_mode = esp_idf_sys::pcnt_count_mode_t_PCNT_COUNT_DEC;
+    pcnt.neg_mode = esp_idf_sys::pcnt_count_mode_t_PCNT_COUNT_INC;
+    pcnt.lctrl_mode = esp_idf_sys::pcnt_ctrl_mode_t_PCNT_MODE_REVERSE;
+    pcnt.hctrl_mode = esp_idf_sys::pcnt_ctrl_mode_t_PCNT_MODE_KEEP;
+    pcnt.counter_h_lim = PCNT_LIMIT;
+    pcnt.counter_l_lim = -PCNT_LIMIT;
+    esp!(unsafe { esp_idf_sys::pcnt_unit_config(&pcnt) })?;
+    esp!(unsafe { esp_idf_sys::pcnt_set_filter_value(PCNT_UNIT, PCNT_FILTER) })?;
+    esp!(unsafe { esp_idf_sys::pcnt_filter_enable(PCNT_UNIT) })?;
+    esp!(unsafe { esp_idf_sys::pcnt_counter_pause(PCNT_UNIT) })?;
+    esp!(unsafe { esp_idf_sys::pcnt_counter_clear(PCNT_UNIT) })?;
+    esp!(unsafe { esp_idf_sys::pcnt_counter_resume(PCNT_UNIT) })?;
+
+    Ok(())
+}
+
+fn init_pot() -> Result<()> {
+    esp!(unsafe {
+        esp_idf_sys::adc1_config_width(esp_idf_sys::adc_bits_width_t_ADC_WIDTH_BIT_12)
+    })?;
+    esp!(unsafe {
+        esp_idf_sys::adc1_config_channel_atten(
+            board::DOOR_ADC_CHANNEL,
+            esp_idf_sys::adc_atten_t_ADC_ATTEN_DB_11,
+        )
+    })?;
+
+    Ok(())
+}
+
+/// The stored calibrations, the slats' angle and, for the encoder, which
+/// counts nothing at boot, the count the door stopped at.
+fn load(state: &mut State) -> Result<Option<u8>> {
+    let store = store()?;
+    if let (Some(closed), Some(open)) = (store.get_u32("closed")?, store.get_u32("open")?) {
+        state.calibration = Calibration {
+            closed: closed as i32,
+            open: open as i32,
+        };
+    }
+    if let Some(full_ms) = store.get_u32("tilt_ms")? {
+        state.tilt = Tilt::new(full_ms as u64);
+    }
+    if let Some(tilt) = store.get_u8("tilt")? {
+        state.tilt.hold(tilt as i8);
+    }
+    if config::DOOR_SENSOR == DoorSensor::Encoder {
+        if let Some(raw) = store.get_u32("raw")? {
+            ENCODER_COUNT.store(raw as i32, Ordering::Relaxed);
+        }
+    }
+
+    Ok(store.get_u8("position")?)
+}
+
+/// Sets up the position sensor, the stopped motor and the safety edge, and
+/// starts following the target on a task of its own.
+pub fn init() -> Result<()> {
+    // Driven by number from here on, the ISR included
+    AnyOutputPin::new(board::DOOR_MOTOR_IN1_GPIO)?;
+    AnyOutputPin::new(board::DOOR_MOTOR_IN2_GPIO)?;
+    cut_motor();
+    init_pwm()?;
+    if config::DOOR_TILT == TiltSource::Servo {
+        init_servo()?;
+    }
+    match config::DOOR_SENSOR {
+        DoorSensor::Pot => init_pot()?,
+        DoorSensor::Encoder => init_encoder()?,
+    }
+
+    let mut state = STATE.lock();
+    let stored = match load(&mut state) {
+        Ok(stored) => stored,
+        Err(err) => {
+            warn!(target: logging::DOOR, "Loading the door settings failed: {:?}", err);
+            None
+        }
+    };
+    // The potentiometer knows where the door is, the encoder trusts the store
+    let position = match config::DOOR_SENSOR {
+        DoorSensor::Pot => state.calibration.percent(read_pot()?),
+        DoorSensor::Encoder => stored.unwrap_or(0),
+    };
+    state.raw = ENCODER_COUNT.load(Ordering::Relaxed);
+    state.mover.hold(position);
+    info!(
+        target: logging::DOOR,
+        "Door motor on GPIO{}/{} (PWM GPIO{}), {:?} position sensor, at {} %",
+        board::DOOR_MOTOR_IN1_GPIO,
+        board::DOOR_MOTOR_IN2_GPIO,
+        board::DOOR_MOTOR_EN_GPIO,
+        config::DOOR_SENSOR,
+        position
+    );
+    drop(state);
+
+    let edge = AnyInputPin::new(board::DOOR_EDGE_GPIO, Pull::Up)?;
+    button::install_isr_service()?;
+    edge.on_edges(on_edge, ptr::null_mut())?;
+    EDGE.call_once(|| edge);
+
+    system::on_shutdown(cut_motor);
+    tasks::spawn(&tasks::DOOR, door_handler)
+}
+
+/// Target position: a new target replaces the one being driven to, and
+/// clears an obstruction unless the edge is still pressed. Target tilt:
+/// applied once the lift is done. Both are handed to the door task, which
+/// confirms them with the next notification.
+fn on_write(write: &Write) -> Result<(), Status> {
+    let hc = Some(write.hc);
+    if hc == TARGET_POSITION_CHAR.get() {
+        let target = write.value.as_u32().ok_or(Status::InvalidValue)? as u8;
+        if edge_active() {
+            return Err(Status::ResourceBusy);
+        }
+        TARGET_POSITION.submit(target);
+    } else if hc == TARGET_TILT_CHAR.get() {
+        let angle = write.value.as_f64().ok_or(Status::InvalidValue)?;
+        TARGET_TILT.submit(angle as i8);
+    } else {
+        return Err(Status::ResourceAbsent);
+    }
+
+    Ok(())
+}
+
+/// A Door, or a Window Covering with the slats' tilt for blinds.
+pub fn service() -> Result<ServiceBuilder> {
+    if EDGE.get().is_none() {
+        bail!("the door is not set up");
+    }
+
+    let mut state = STATE.lock();
+    let reported = state.reported();
+    state.reported = Some(reported);
+    let percent = Bounds {
+        min: 0.0,
+        max: 100.0,
+        step: 1.0,
+    };
+
+    let service = match config::DOOR_COVERING {
+        Covering::Door => ServiceBuilder::custom(DOOR_UUID).name("Door"),
+        Covering::Blinds => ServiceBuilder::custom(WINDOW_COVERING_UUID).name("Blinds"),
+    };
+    let service = service
+        .char(
+            CURRENT_POSITION_UUID,
+            READ_ONLY,
+            Value::Uint8(reported.position),
+        )
+        .char(
+            TARGET_POSITION_UUID,
+            perm::PR | perm::PW | perm::EV,
+            Value::Uint8(reported.target),
+        )
+        .char(
+            POSITION_STATE_UUID,
+            READ_ONLY,
+            Value::Uint8(reported.state as u8),
+        )
+        .char(
+            OBSTRUCTION_UUID,
+            READ_ONLY,
+            Value::Bool(reported.obstructed),
+        )
+        .bounds(CURRENT_POSITION_UUID, percent)
+        .bounds(TARGET_POSITION_UUID, percent)
+        .valid_values(POSITION_STATE_UUID, &[0, 1, 2])
+        .bind(CURRENT_POSITION_UUID, &CURRENT_POSITION_CHAR)
+        .bind(TARGET_POSITION_UUID, &TARGET_POSITION_CHAR)
+        .bind(POSITION_STATE_UUID, &POSITION_STATE_CHAR)
+        .bind(OBSTRUCTION_UUID, &OBSTRUCTION_CHAR)
+        .on_write(&on_write);
+    if config::DOOR_TILT == TiltSource::None {
+        return Ok(service);
+    }
+
+    // Without bounds the Home app shows a broken slider
+    let degrees = Bounds {
+        min: -90.0,
+        max: 90.0,
+        step: 1.0,
+    };
+    Ok(service
+        .char(
+            CURRENT_TILT_UUID,
+            READ_ONLY,
+            Value::Int(reported.tilt as i32),
+        )
+        .char(
+            TARGET_TILT_UUID,
+            perm::PR | perm::PW | perm::EV,
+            Value::Int(reported.tilt_target as i32),
+        )
+        .bounds(CURRENT_TILT_UUID, degrees)
+        .bounds(TARGET_TILT_UUID, degrees)
+        .bind(CURRENT_TILT_UUID, &CURRENT_TILT_CHAR)
+        .bind(TARGET_TILT_UUID, &TARGET_TILT_CHAR))
+}
+
+/// Stores the current reading as the closed or the open end; move the door
+/// there by hand or with `door <percent>` first.
+fn calibrate(point: &str) -> Result<()> {
+    let mut state = STATE.lock();
+    let raw = state.raw;
+    match point {
+        "closed" => state.calibration.closed = raw,
+        "open" => state.calibration.open = raw,
+        _ => bail!("calibrate 'closed' or 'open' with the door at that end"),
+    }
+    save(|store| store.set_u32(point, raw as u32));
+    println!("Stored {} = {}", point, raw);
+    if state.calibration.closed == state.calibration.open {
+        println!("Closed and open read the same, calibrate the other end");
+    }
+
+    Ok(())
+}
+
+pub fn register_commands() {
+    console::register(
+        "door",
+        "Show the door ('door'), move it ('door <percent>'), tilt the slats of blinds ('door tilt <degrees>'), or calibrate its sensor ('door calibrate closed|open') or the time a full turn of the slats takes ('door tilt-time <ms>')",
+        |args| match args {
+            [] => {
+                let state = STATE.lock();
+                println!(
+                    "At {} % (raw {}), target {} %, {:?}{}",
+                    state.mover.position(),
+                    state.raw,
+                    state.mover.target(),
+                    state.mover.drive().state(),
+                    if state.mover.is_obstructed() {
+                        " (obstructed)"
+                    } else {
+                        ""
+                    }
+                );
+                println!(
+                    "Calibration: closed {}, open {}",
+                    state.calibration.closed, state.calibration.open
+                );
+                if config::DOOR_TILT != TiltSource::None {
+                    println!(
+                        "Slats at {}°, target {}°",
+                        state.tilt.current(),
+                        state.tilt.target()
+                    );
+                }
+                if let Some(fault) = state.fault {
+                    println!("Last move stopped short: {:?}", fault);
+                }
+                println!("Safety edge {}", if edge_active() { "pressed" } else { "clear" });
+                Ok(())
+            }
+            ["calibrate", point] => calibrate(point),
+            ["tilt", angle] => {
+                let angle: i8 = angle.parse()?;
+                if !(-90..=90).contains(&angle) {
+                    bail!("{}° is not a slat angle", angle);
+                }
+                let changes = STATE.lock().set_tilt(angle);
+                notify(changes);
+                Ok(())
+            }
+            ["tilt-time", ms] => {
+                let full_ms: u32 = ms.parse()?;
+                let mut state = STATE.lock();
+                let current = state.tilt.current();
+                state.tilt = Tilt::new(full_ms as u64);
+                state.tilt.hold(current);
+                save(|store| store.set_u32("tilt_ms", full_ms));
+                Ok(())
+            }
+            [percent] => {
+                let target: u8 = percent.parse()?;
+                if target > 100 {
+                    bail!("{} % is not a position", target);
+                }
+                if edge_active() {
+                    bail!("the safety edge is pressed");
+                }
+                let changes = STATE.lock().set_target(target);
+                notify(changes);
+                Ok(())
+            }
+            _ => bail!(
+                "usage: door [<percent> | tilt <degrees> | calibrate closed|open | tilt-time <ms>]"
+            ),
+        },
+    );
+}
@@ -0,0 +1,128 @@
+use std::sync::atomic::{AtomicI32, AtomicU8, Ordering};
+use std::time::Duration;
+
+use anyhow::Result;
+use esp_idf_sys::c_types::c_void;
+use log::{debug, info};
+use spin::Mutex;
+
+use crate::board::{self, AnyInputPin};
+use crate::button::{self, ButtonConfig, Pull, Timing};
+use crate::{config, event_bus, logging, tasks, wdt};
+
+const POLL_MS: u64 = 20;
+
+// Step multipliers while turning fast, by the time since the previous step
+const ACCELERATION: &[(u64, i32)] = &[(25, 5), (60, 2)];
+
+/// Count change for `previous << 2 | current` of the two-bit (A, B) state;
+/// both pins changing at once is an invalid transition and counts nothing.
+const TRANSITIONS: [i8; 16] = [0, -1, 1, 0, 1, 0, 0, -1, -1, 0, 0, 1, 0, 1, -1, 0];
+
+/// The push switch of the encoder, delivered as a regular button.
+pub const SWITCH: ButtonConfig = ButtonConfig {
+    name: "encoder",
+    gpio: board::ENCODER_SWITCH_GPIO,
+    active_high: false,
+    pull: Pull::Up,
+    timing: Timing {
+        debounce_ms: config::BUTTON_DEBOUNCE_MS,
+        long_press_ms: config::BUTTON_LONG_PRESS_MS,
+        double_click_ms: config::BUTTON_DOUBLE_CLICK_MS,
+    },
+};
+
+type Listener = &'static (dyn Fn(i32) + Send + Sync);
+
+static STATE: AtomicU8 = AtomicU8::new(0);
+static COUNTS: AtomicI32 = AtomicI32::new(0);
+static LISTENERS: Mutex<Vec<Listener>> = Mutex::new(Vec::new());
+
+/// Registers a listener for the signed, accelerated step deltas; listeners run
+/// on the encoder task.
+pub fn subscribe(listener: impl Fn(i32) + Send + Sync + 'static) {
+    LISTENERS.lock().push(Box::leak(Box::new(listener)));
+}
+
+fn read_state() -> u8 {
+    unsafe {
+        let a = esp_idf_sys::gpio_get_level(board::ENCODER_GPIO_A) as u8;
+        let b = esp_idf_sys::gpio_get_level(board::ENCODER_GPIO_B) as u8;
+        (a << 1) | (b & 1)
+    }
+}
+
+unsafe extern "C" fn on_edge(_: *mut c_void) {
+    let current = read_state();
+    let previous = STATE.swap(current, Ordering::Relaxed);
+
+    let delta = TRANSITIONS[((previous << 2) | current) as usize];
+    if delta != 0 {
+        event_bus::publish_from_isr(event_bus::Event::EncoderCounts(delta as i32));
+    }
+}
+
+fn on_bus_event(event: &event_bus::Event) {
+    if let event_bus::Event::EncoderCounts(counts) = *event {
+        COUNTS.fetch_add(counts, Ordering::Relaxed);
+    }
+}
+
+fn configure(gpio: i32) -> Result<()> {
+    AnyInputPin::new(gpio, Pull::Up)?.on_edges(on_edge, std::ptr::null_mut())
+}
+
+/// Configures the quadrature pins and starts the task turning counts into
+/// steps. The C3 has no PCNT unit, so the edges are decoded in the ISR and
+/// the counts travel through the event bus, which has to be running.
+pub fn init() -> Result<()> {
+    event_bus::subscribe(on_bus_event);
+    button::install_isr_service()?;
+
+    STATE.store(read_state(), Ordering::Relaxed);
+    configure(board::ENCODER_GPIO_A)?;
+    configure(board::ENCODER_GPIO_B)?;
+    info!(
+        target: logging::BUTTON,
+        "Rotary encoder on GPIO{}/GPIO{}, {} counts per step",
+        board::ENCODER_GPIO_A,
+        board::ENCODER_GPIO_B,
+        config::ENCODER_COUNTS_PER_STEP
+    );
+
+    tasks::spawn(&tasks::ENCODER, encoder_handler)
+}
+
+fn encoder_handler() {
+    let watchdog = wdt::subscribe(tasks::ENCODER.name);
+    let counts_per_step = config::ENCODER_COUNTS_PER_STEP.max(1) as i32;
+    let mut last_step_ms = 0;
+
+    loop {
+        watchdog.sleep(Duration::from_millis(POLL_MS));
+
+        // Leaves a partial step in the counter for the next round
+        let counts = COUNTS.load(Ordering::Relaxed);
+        let steps = counts / counts_per_step;
+        if steps == 0 {
+            continue;
+        }
+        COUNTS.fetch_sub(steps * counts_per_step, Ordering::Relaxed);
+
+        let now = (unsafe { esp_idf_sys::esp_timer_get_time() } / 1000) as u64;
+        let interval = (now - last_step_ms) / steps.unsigned_abs() as u64;
+        last_step_ms = now;
+
+        let factor = ACCELERATION
+            .iter()
+            .find(|(below_ms, _)| interval < *below_ms)
+            .map_or(1, |(_, factor)| *factor);
+        let delta = steps * factor;
+        debug!(target: logging::BUTTON, "Encoder {:+} ({} steps)", delta, steps);
+
+        let listeners = LISTENERS.lock().clone();
+        for listener in listeners {
+            listener(delta);
+        }
+    }
+}
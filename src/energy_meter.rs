@@ -0,0 +1,199 @@
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::time::Duration;
+
+use anyhow::Result;
+use hap_core::{CharSlot, ServiceBuilder, Value};
+use log::{info, warn};
+
+use crate::event_bus::{self, Event, Reading};
+use crate::modbus::{self, Master};
+use crate::{board, config, energy_stats, factory_config, logging, metrics, sim, tasks, wdt};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Quantity {
+    Voltage,
+    Current,
+    Power,
+    Energy,
+}
+
+const QUANTITIES: [Quantity; 4] = [
+    Quantity::Voltage,
+    Quantity::Current,
+    Quantity::Power,
+    Quantity::Energy,
+];
+
+/// Where a meter keeps a quantity, as an IEEE 754 float in two input registers.
+pub struct Register {
+    pub quantity: Quantity,
+    pub address: u16,
+}
+
+/// Eastron SDM120; other meters only need a table like this one.
+pub const SDM120: &[Register] = &[
+    Register {
+        quantity: Quantity::Voltage,
+        address: 0x0000,
+    },
+    Register {
+        quantity: Quantity::Current,
+        address: 0x0006,
+    },
+    Register {
+        quantity: Quantity::Power,
+        address: 0x000c,
+    },
+    Register {
+        quantity: Quantity::Energy,
+        address: 0x0156,
+    },
+];
+
+// Eve energy characteristics, understood by the Eve and Home+ apps
+const VOLTAGE_UUID: &[u8] = b"E863F10A-079E-48FF-8F27-9C2605A29F52\0";
+const CURRENT_UUID: &[u8] = b"E863F126-079E-48FF-8F27-9C2605A29F52\0";
+const POWER_UUID: &[u8] = b"E863F10D-079E-48FF-8F27-9C2605A29F52\0";
+
+const READ_ONLY: u16 =
+    (esp_homekit_sdk_sys::HAP_CHAR_PERM_PR | esp_homekit_sdk_sys::HAP_CHAR_PERM_EV) as u16;
+
+struct Reading {
+    /// f32 bits, so the metrics and HAP readers never lock
+    value: AtomicU32,
+    hc: CharSlot,
+}
+
+const EMPTY_READING: Reading = Reading {
+    value: AtomicU32::new(0),
+    hc: CharSlot::new(),
+};
+
+static READINGS: [Reading; 4] = [EMPTY_READING; 4];
+static ONLINE: AtomicBool = AtomicBool::new(false);
+
+fn index(quantity: Quantity) -> usize {
+    QUANTITIES.iter().position(|q| *q == quantity).unwrap_or(0)
+}
+
+pub fn reading(quantity: Quantity) -> f32 {
+    f32::from_bits(READINGS[index(quantity)].value.load(Ordering::Relaxed))
+}
+
+pub fn is_online() -> bool {
+    ONLINE.load(Ordering::Relaxed)
+}
+
+fn publish(quantity: Quantity, value: f32) {
+    let value = value * factory_config::get().meter_gain(quantity);
+    let reading = &READINGS[index(quantity)];
+    if reading.value.swap(value.to_bits(), Ordering::Relaxed) == value.to_bits() {
+        return;
+    }
+
+    if let Some(hc) = reading.hc.get() {
+        event_bus::publish(Event::Update(hc, Reading::Float(value)));
+    }
+}
+
+/// Adds the Eve energy characteristics to the outlet service; the total
+/// consumption is the firmware's own, the meter's register survives neither
+/// a meter swap nor a reset of the meter.
+pub fn characteristics(mut service: ServiceBuilder) -> ServiceBuilder {
+    for (quantity, uuid) in [
+        (Quantity::Voltage, VOLTAGE_UUID),
+        (Quantity::Current, CURRENT_UUID),
+        (Quantity::Power, POWER_UUID),
+    ] {
+        service = service
+            .char(uuid, READ_ONLY, Value::Float(reading(quantity)))
+            .bind(uuid, &READINGS[index(quantity)].hc);
+    }
+
+    energy_stats::characteristics(service)
+}
+
+pub fn register_metrics() {
+    // Milli-units, the metrics are integers
+    metrics::register("meter_voltage_mv", || {
+        (reading(Quantity::Voltage) * 1000.0) as i64
+    });
+    metrics::register("meter_current_ma", || {
+        (reading(Quantity::Current) * 1000.0) as i64
+    });
+    metrics::register("meter_power_mw", || {
+        (reading(Quantity::Power) * 1000.0) as i64
+    });
+    metrics::register("meter_energy_wh", || {
+        (reading(Quantity::Energy) * 1000.0) as i64
+    });
+    metrics::register("meter_online", || is_online() as i64);
+}
+
+fn poll(master: &Master, registers: &[Register]) -> Result<()> {
+    for register in registers {
+        let words = master.read_input_registers(config::METER_UNIT, register.address, 2)?;
+        let value = f32::from_bits((words[0] as u32) << 16 | words[1] as u32);
+        publish(register.quantity, value);
+    }
+
+    Ok(())
+}
+
+fn meter_handler(poll: impl Fn() -> Result<()>) {
+    let watchdog = wdt::subscribe(tasks::ENERGY_METER.name);
+
+    loop {
+        // Every request is bounded by its timeout, a dead bus only delays this task
+        match poll() {
+            Ok(()) => {
+                if !ONLINE.swap(true, Ordering::Relaxed) {
+                    info!(target: logging::ENERGY, "Energy meter online");
+                }
+                energy_stats::sample(reading(Quantity::Power));
+            }
+            Err(err) => {
+                if ONLINE.swap(false, Ordering::Relaxed) {
+                    warn!(target: logging::ENERGY, "Energy meter offline: {:?}", err);
+                }
+            }
+        }
+
+        watchdog.sleep(Duration::from_secs(config::METER_POLL_SECS));
+    }
+}
+
+/// Opens the RS-485 bus and starts polling the meter, or the simulated one.
+pub fn init() -> Result<()> {
+    if config::SIMULATION {
+        info!(target: logging::ENERGY, "Simulated meter, {} W load", config::SIM_LOAD_WATTS);
+        return tasks::spawn(&tasks::ENERGY_METER, || {
+            meter_handler(|| {
+                for (quantity, value) in sim::meter_readings() {
+                    publish(quantity, value);
+                }
+                Ok(())
+            })
+        });
+    }
+
+    let master = Master::new(modbus::Config {
+        port: board::METER_UART_PORT,
+        tx_gpio: board::METER_TX_GPIO,
+        rx_gpio: board::METER_RX_GPIO,
+        de_gpio: board::METER_DE_GPIO,
+        baud: config::METER_BAUD,
+        timeout: Duration::from_millis(config::METER_TIMEOUT_MS),
+        attempts: config::METER_ATTEMPTS,
+    })?;
+    info!(
+        target: logging::ENERGY,
+        "Polling Modbus unit {} every {} s",
+        config::METER_UNIT,
+        config::METER_POLL_SECS
+    );
+
+    tasks::spawn(&tasks::ENERGY_METER, move || {
+        meter_handler(|| poll(&master, SDM120))
+    })
+}
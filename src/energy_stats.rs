@@ -0,0 +1,207 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use anyhow::{bail, Result};
+use hap_core::energy::{Accumulator, Totals, DAYS};
+use hap_core::sys::perm;
+use hap_core::{CharSlot, ServiceBuilder, Value};
+use log::{info, warn};
+use spin::{Mutex, Once};
+
+use crate::event_bus::{self, Event, Reading};
+use crate::{clock, config, console, logging, metrics, nvs, system};
+
+// Eve Total Consumption, in kWh, and today's share of it
+const TOTAL_UUID: &[u8] = b"E863F10C-079E-48FF-8F27-9C2605A29F52\0";
+const TODAY_UUID: &[u8] = b"0000D6A2-28E5-4C3F-9B6E-5A1D7E3C9000\0";
+
+// Twice the poll interval, a missed poll still counts
+const MAX_GAP_MS: u64 = 2 * config::METER_POLL_SECS * 1000 + 1000;
+
+static ENERGY: Mutex<Accumulator> = Mutex::new(Accumulator::new(MAX_GAP_MS));
+static STORE: Once<nvs::Namespace> = Once::new();
+/// When the totals were last committed, milliseconds since boot
+static COMMITTED_MS: AtomicU64 = AtomicU64::new(0);
+
+static TOTAL_CHAR: CharSlot = CharSlot::new();
+static TODAY_CHAR: CharSlot = CharSlot::new();
+
+fn store() -> Result<&'static nvs::Namespace> {
+    STORE.try_call_once(|| nvs::Namespace::open(nvs::ENERGY))
+}
+
+fn now_ms() -> u64 {
+    (unsafe { esp_idf_sys::esp_timer_get_time() } / 1000) as u64
+}
+
+fn load() -> Result<Totals> {
+    let store = store()?;
+    let mut days = [0u8; DAYS * 4];
+    let stored = store.get_blob("days", &mut days)?;
+
+    let mut totals = Totals {
+        day: store.get_u32("day")?,
+        lifetime: store.get_u32("life")?.unwrap_or(0),
+        wraps: store.get_u32("wraps")?.unwrap_or(0),
+        ..Totals::default()
+    };
+    // Days from a store of another length are dropped, the lifetime stays
+    if stored == Some(days.len()) {
+        for (total, bytes) in totals.days.iter_mut().zip(days.chunks_exact(4)) {
+            *total = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        }
+    } else {
+        totals.day = None;
+    }
+
+    Ok(totals)
+}
+
+fn save(totals: &Totals) -> Result<()> {
+    let mut days = [0u8; DAYS * 4];
+    for (bytes, total) in days.chunks_exact_mut(4).zip(totals.days) {
+        bytes.copy_from_slice(&total.to_le_bytes());
+    }
+
+    let store = store()?;
+    match totals.day {
+        Some(day) => store.set_u32("day", day)?,
+        None => store.remove("day")?,
+    }
+    store.set_blob("days", &days)?;
+    store.set_u32("life", totals.lifetime)?;
+    store.set_u32("wraps", totals.wraps)?;
+    store.commit()?;
+
+    Ok(())
+}
+
+fn commit() {
+    let totals = ENERGY.lock().totals().clone();
+    match save(&totals) {
+        Ok(()) => COMMITTED_MS.store(now_ms(), Ordering::Relaxed),
+        Err(err) => warn!(target: logging::ENERGY, "Storing the energy totals failed: {:?}", err),
+    }
+}
+
+fn kwh(wh: u64) -> f32 {
+    wh as f32 / 1000.0
+}
+
+fn publish(totals: &Totals) {
+    for (slot, wh) in [
+        (&TOTAL_CHAR, totals.lifetime_wh()),
+        (&TODAY_CHAR, totals.today() as u64),
+    ] {
+        if let Some(hc) = slot.get() {
+            event_bus::publish(Event::Update(hc, Reading::Float(kwh(wh))));
+        }
+    }
+}
+
+/// Continues the totals stored before the reboot; energy taken before the
+/// clock syncs is held back until the day it belongs to is known.
+pub fn init() {
+    match load() {
+        Ok(totals) => {
+            info!(
+                target: logging::ENERGY,
+                "Energy today {} Wh, lifetime {} Wh",
+                totals.today(),
+                totals.lifetime_wh()
+            );
+            ENERGY.lock().restore(totals);
+        }
+        Err(err) => warn!(target: logging::ENERGY, "Loading the energy totals failed: {:?}", err),
+    }
+    COMMITTED_MS.store(now_ms(), Ordering::Relaxed);
+
+    system::on_shutdown(commit);
+}
+
+/// Takes the active power of each meter poll; commits at midnight and at
+/// least every `ENERGY_COMMIT_SECS` while energy accumulates.
+pub fn sample(watts: f32) {
+    let now = now_ms();
+    let (changed, totals) = {
+        let mut energy = ENERGY.lock();
+        let changed = energy.sample(watts, now, clock::local_day());
+        (changed, energy.totals().clone())
+    };
+
+    if changed.energy || changed.day {
+        publish(&totals);
+    }
+    if changed.day {
+        info!(
+            target: logging::ENERGY,
+            "Energy yesterday {} Wh, lifetime {} Wh",
+            totals.days[1],
+            totals.lifetime_wh()
+        );
+        commit();
+    } else if changed.energy
+        && now.saturating_sub(COMMITTED_MS.load(Ordering::Relaxed))
+            >= config::ENERGY_COMMIT_SECS * 1000
+    {
+        commit();
+    }
+}
+
+/// Adds the lifetime total as Eve Total Consumption, and today's.
+pub fn characteristics(service: ServiceBuilder) -> ServiceBuilder {
+    let totals = ENERGY.lock().totals().clone();
+
+    service
+        .char(
+            TOTAL_UUID,
+            perm::PR | perm::EV,
+            Value::Float(kwh(totals.lifetime_wh())),
+        )
+        .bind(TOTAL_UUID, &TOTAL_CHAR)
+        .char(
+            TODAY_UUID,
+            perm::PR | perm::EV,
+            Value::Float(kwh(totals.today() as u64)),
+        )
+        .bind(TODAY_UUID, &TODAY_CHAR)
+}
+
+pub fn register_metrics() {
+    metrics::register("energy_today_wh", || ENERGY.lock().totals().today() as i64);
+    metrics::register("energy_lifetime_wh", || {
+        ENERGY.lock().totals().lifetime_wh() as i64
+    });
+}
+
+pub fn register_commands() {
+    console::register(
+        "energy",
+        "Show the daily and lifetime energy totals ('energy') or store them now ('energy commit')",
+        |args| match args {
+            [] => {
+                let energy = ENERGY.lock();
+                let totals = energy.totals();
+                match totals.day {
+                    Some(_) => {
+                        println!("Today: {} Wh", totals.today());
+                        for (age, wh) in totals.days.iter().enumerate().skip(1) {
+                            println!("{} day(s) ago: {} Wh", age, wh);
+                        }
+                    }
+                    None => println!("No day yet, the clock has not synced"),
+                }
+                println!(
+                    "Pending for the day the clock syncs on: {} Wh",
+                    energy.pending()
+                );
+                println!("Lifetime: {} Wh", totals.lifetime_wh());
+                Ok(())
+            }
+            ["commit"] => {
+                commit();
+                Ok(())
+            }
+            _ => bail!("usage: energy [commit]"),
+        },
+    );
+}
@@ -0,0 +1,185 @@
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, AtomicU32, Ordering};
+
+use anyhow::{bail, Result};
+use esp_idf_sys::c_types::c_void;
+use hap_core::write::format_of;
+use hap_core::{Char, Format, Value};
+use log::{debug, warn};
+use spin::Mutex;
+
+use crate::hap_sys::HAP;
+use crate::{config, logging, maintenance, metrics, status_led, tasks, wdt};
+
+// Receive timeout of the dispatcher, so it feeds its watchdog while idle
+const IDLE_MS: u32 = 1000;
+
+/// A characteristic value small enough to travel through the queue.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Reading {
+    Bool(bool),
+    Float(f32),
+}
+
+/// Copied by value through the FreeRTOS queue, so plain data only.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Event {
+    /// A GPIO edge with the level after it
+    Edge {
+        gpio: i32,
+        level: bool,
+        at_us: i64,
+    },
+    /// Quadrature counts decoded in the encoder ISR
+    EncoderCounts(i32),
+    /// Becomes a characteristic update on the dispatcher task
+    Update(Char, Reading),
+    Led(status_led::Event),
+    /// A maintenance switch was written, armed or confirmed
+    Maintenance {
+        action: maintenance::Action,
+        confirmed: bool,
+    },
+}
+
+type Listener = &'static (dyn Fn(&Event) + Send + Sync);
+
+static QUEUE: AtomicPtr<esp_idf_sys::QueueDefinition> = AtomicPtr::new(ptr::null_mut());
+static DROPPED: AtomicU32 = AtomicU32::new(0);
+static LISTENERS: Mutex<Vec<Listener>> = Mutex::new(Vec::new());
+
+/// Registers a listener for every event, drivers consuming their own ISR
+/// events or bridges forwarding them; listeners run on the dispatcher task.
+pub fn subscribe(listener: impl Fn(&Event) + Send + Sync + 'static) {
+    LISTENERS.lock().push(Box::leak(Box::new(listener)));
+}
+
+/// Events lost to a full queue since boot.
+pub fn dropped() -> u32 {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+/// Queues an event from an ISR. A full queue drops its oldest event.
+///
+/// # Safety
+///
+/// Only to be called from interrupt context.
+pub unsafe fn publish_from_isr(event: Event) {
+    let queue = QUEUE.load(Ordering::Relaxed);
+    if queue.is_null() {
+        return;
+    }
+
+    let mut woken = 0;
+    let item = &event as *const Event as *const c_void;
+    if esp_idf_sys::xQueueGenericSendFromISR(queue, item, &mut woken, 0) != 1 {
+        let mut oldest = event;
+        esp_idf_sys::xQueueReceiveFromISR(
+            queue,
+            &mut oldest as *mut Event as *mut c_void,
+            &mut woken,
+        );
+        esp_idf_sys::xQueueGenericSendFromISR(queue, item, &mut woken, 0);
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Queues an event from a task, with the same overflow policy as ISRs.
+/// Without the bus the event is dispatched right away, on the caller's task.
+pub fn publish(event: Event) {
+    let queue = QUEUE.load(Ordering::Relaxed);
+    if queue.is_null() {
+        dispatch(&event);
+        return;
+    }
+
+    let item = &event as *const Event as *const c_void;
+    unsafe {
+        if esp_idf_sys::xQueueGenericSend(queue, item, 0, 0) != 1 {
+            let mut oldest = event;
+            esp_idf_sys::xQueueReceive(queue, &mut oldest as *mut Event as *mut c_void, 0);
+            esp_idf_sys::xQueueGenericSend(queue, item, 0, 0);
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+fn value(hc: Char, reading: Reading) -> Option<Value> {
+    let value = match (reading, format_of(hc)?) {
+        (Reading::Bool(b), Format::Bool) => Value::Bool(b),
+        (Reading::Float(f), Format::Float) => Value::Float(f),
+        _ => return None,
+    };
+
+    Some(value)
+}
+
+fn dispatch(event: &Event) {
+    match *event {
+        Event::Update(hc, reading) => match value(hc, reading) {
+            Some(value) => {
+                HAP.update(hc, &value);
+            }
+            None => warn!(target: logging::DIAG, "Dropping {:?}, wrong format", event),
+        },
+        Event::Led(led) => status_led::event(led),
+        _ => {}
+    }
+
+    let listeners = LISTENERS.lock().clone();
+    for listener in listeners {
+        listener(event);
+    }
+}
+
+fn dispatcher() {
+    let watchdog = wdt::subscribe(tasks::EVENT_BUS.name);
+    let queue = QUEUE.load(Ordering::Relaxed);
+    let idle_ticks = IDLE_MS * esp_idf_sys::configTICK_RATE_HZ / 1000;
+    let mut reported_drops = 0;
+
+    loop {
+        watchdog.feed();
+
+        let mut event = Event::EncoderCounts(0);
+        let received = unsafe {
+            esp_idf_sys::xQueueReceive(queue, &mut event as *mut Event as *mut c_void, idle_ticks)
+        };
+        if received == 1 {
+            debug!(target: logging::DIAG, "Bus event {:?}", event);
+            dispatch(&event);
+            continue;
+        }
+
+        // Reported once the queue drained, not once per lost event
+        let drops = dropped();
+        if drops != reported_drops {
+            warn!(
+                target: logging::DIAG,
+                "Event bus queue full, {} events dropped since boot",
+                drops
+            );
+            reported_drops = drops;
+        }
+    }
+}
+
+/// Creates the queue and starts the task dispatching its events. Drivers
+/// publishing from ISRs have to be initialized after it.
+pub fn init() -> Result<()> {
+    let queue = unsafe {
+        esp_idf_sys::xQueueGenericCreate(
+            config::EVENT_BUS_QUEUE_LEN,
+            std::mem::size_of::<Event>() as u32,
+            0,
+        )
+    };
+    if queue.is_null() {
+        bail!("creating the event bus queue failed");
+    }
+    QUEUE.store(queue, Ordering::Relaxed);
+
+    metrics::register("event_bus_dropped", || dropped() as i64);
+
+    tasks::spawn(&tasks::EVENT_BUS, dispatcher)
+}
@@ -0,0 +1,227 @@
+use std::fmt;
+
+use anyhow::{anyhow, bail, Context, Result};
+use log::{info, warn};
+use spin::Once;
+
+use crate::energy_meter::Quantity;
+use crate::{config, logging, nvs};
+
+// Written per unit with nvs_partition_gen from a CSV like
+//
+//   key,type,encoding,value
+//   factory,namespace,,
+//   setup_id,data,string,ES32
+//   setup_salt,data,hex2bin,<16 bytes>
+//   setup_verif,data,hex2bin,<384 bytes>
+//   serial,data,string,A1B2C3D4E5F6
+//   meter_gain_v,data,u32,1002300
+//
+// setup_code (string) can replace the salt and verifier on development
+// units; the identity keys are required, everything else is optional.
+pub const PARTITION: &str = "fctry";
+const NAMESPACE: &str = "factory";
+
+const SALT_LEN: usize = 16;
+const VERIFIER_LEN: usize = 384;
+const PRODUCT_DATA_LEN: usize = 8;
+const MAX_STR_LEN: usize = 64;
+
+// Meter gains are in parts per million, 1_000_000 reads unchanged
+const GAIN_KEYS: [(Quantity, &str); 4] = [
+    (Quantity::Voltage, "meter_gain_v"),
+    (Quantity::Current, "meter_gain_i"),
+    (Quantity::Power, "meter_gain_p"),
+    (Quantity::Energy, "meter_gain_e"),
+];
+
+/// How controllers prove they know the setup code.
+pub enum Setup {
+    /// Plain code, the SDK derives the verifier; development units only
+    Code(String),
+    /// SRP salt and verifier generated with the code, which never reaches
+    /// the device
+    Verifier {
+        salt: [u8; SALT_LEN],
+        verifier: Box<[u8; VERIFIER_LEN]>,
+    },
+}
+
+pub struct FactoryConfig {
+    pub setup: Setup,
+    pub setup_id: String,
+    pub serial: String,
+    pub model: String,
+    pub hw_rev: String,
+    /// HAP product data from the Apple MFi portal, logged for production
+    /// records; the SDK in use has no call taking it
+    pub product_data: Option<[u8; PRODUCT_DATA_LEN]>,
+    meter_gains: [f32; 4],
+}
+
+impl FactoryConfig {
+    fn defaults() -> Self {
+        Self {
+            setup: Setup::Code(config::HAP_SETUP_CODE.into()),
+            setup_id: config::HAP_SETUP_ID.into(),
+            serial: config::SERIAL_NUMBER.into(),
+            model: config::MODEL.into(),
+            hw_rev: config::HW_REV.into(),
+            product_data: None,
+            meter_gains: [1.0; 4],
+        }
+    }
+
+    pub fn meter_gain(&self, quantity: Quantity) -> f32 {
+        let index = GAIN_KEYS.iter().position(|(q, _)| *q == quantity);
+        index.map_or(1.0, |index| self.meter_gains[index])
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Source {
+    Factory,
+    Default,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Source::Factory => "factory data",
+            Source::Default => "firmware default",
+        })
+    }
+}
+
+static CONFIG: Once<FactoryConfig> = Once::new();
+
+/// The per-unit configuration; the development defaults until `load` ran.
+pub fn get() -> &'static FactoryConfig {
+    CONFIG.call_once(FactoryConfig::defaults)
+}
+
+fn string(store: &nvs::Namespace, key: &str) -> Result<Option<String>> {
+    let mut buf = [0u8; MAX_STR_LEN + 1];
+    let value = store
+        .get_str(key, &mut buf)
+        .with_context(|| format!("key {}", key))?;
+
+    Ok(value.map(Into::into))
+}
+
+fn blob<const N: usize>(store: &nvs::Namespace, key: &str) -> Result<Option<[u8; N]>> {
+    let mut buf = [0u8; N];
+    match store
+        .get_blob(key, &mut buf)
+        .with_context(|| format!("key {}", key))?
+    {
+        Some(len) if len == N => Ok(Some(buf)),
+        Some(len) => bail!("key {} holds {} bytes, expected {}", key, len, N),
+        None => Ok(None),
+    }
+}
+
+fn setup(store: &nvs::Namespace) -> Result<Setup> {
+    let salt = blob::<SALT_LEN>(store, "setup_salt")?;
+    let verifier = blob::<VERIFIER_LEN>(store, "setup_verif")?;
+    let code = string(store, "setup_code")?;
+
+    match (salt, verifier, code) {
+        (Some(salt), Some(verifier), _) => Ok(Setup::Verifier {
+            salt,
+            verifier: Box::new(verifier),
+        }),
+        (None, None, Some(code)) => Ok(Setup::Code(code)),
+        (None, None, None) => bail!("no setup code or verifier"),
+        _ => bail!("setup salt and verifier have to come together"),
+    }
+}
+
+fn read(store: &nvs::Namespace) -> Result<FactoryConfig> {
+    let defaults = FactoryConfig::defaults();
+    let setup = setup(store)?;
+    let setup_id = string(store, "setup_id")?.ok_or_else(|| anyhow!("no setup id"))?;
+    match &setup {
+        Setup::Code(code) => hap_core::setup::validate(code, &setup_id)?,
+        Setup::Verifier { .. } => hap_core::setup::validate_id(&setup_id)?,
+    }
+
+    let mut sources = Vec::new();
+    let mut field = |name: &'static str, value: Option<String>, default: String| {
+        sources.push((name, value.is_some()));
+        value.unwrap_or(default)
+    };
+    let serial = field("serial", string(store, "serial")?, defaults.serial);
+    let model = field("model", string(store, "model")?, defaults.model);
+    let hw_rev = field("hw_rev", string(store, "hw_rev")?, defaults.hw_rev);
+
+    let mut meter_gains = defaults.meter_gains;
+    for (gain, (_, key)) in meter_gains.iter_mut().zip(GAIN_KEYS) {
+        if let Some(ppm) = store.get_u32(key).with_context(|| format!("key {}", key))? {
+            *gain = ppm as f32 / 1_000_000.0;
+        }
+    }
+
+    for (name, from_factory) in sources {
+        let source = if from_factory {
+            Source::Factory
+        } else {
+            Source::Default
+        };
+        info!(target: logging::DIAG, "Factory config: {} from {}", name, source);
+    }
+
+    Ok(FactoryConfig {
+        setup,
+        setup_id,
+        serial,
+        model,
+        hw_rev,
+        product_data: blob::<PRODUCT_DATA_LEN>(store, "product_data")?,
+        meter_gains,
+    })
+}
+
+/// Reads the factory partition. Without one the development defaults apply;
+/// a partition that is there but unreadable is an error, pairing under a
+/// default identity would ship a unit nobody can support.
+pub fn load() -> Result<&'static FactoryConfig> {
+    if !nvs::init_partition(PARTITION).context("mounting the factory partition")? {
+        warn!(
+            target: logging::DIAG,
+            "No {} partition, using the development identity from the firmware", PARTITION
+        );
+        return Ok(get());
+    }
+
+    // A partition never written reads as empty, like a missing one
+    let store = match nvs::Namespace::open_read_only(PARTITION, NAMESPACE) {
+        Ok(store) => store,
+        Err(err) if nvs::is_not_found(&err) => {
+            warn!(
+                target: logging::DIAG,
+                "Empty {} partition, using the development identity from the firmware", PARTITION
+            );
+            return Ok(get());
+        }
+        Err(err) => return Err(err.context("opening the factory namespace")),
+    };
+    let factory = read(&store).context("factory data is corrupt")?;
+
+    let setup = match factory.setup {
+        Setup::Code(_) => "setup code",
+        Setup::Verifier { .. } => "setup verifier",
+    };
+    info!(
+        target: logging::DIAG,
+        "Factory config: {} and setup id {} from factory data, serial {}",
+        setup,
+        factory.setup_id,
+        factory.serial
+    );
+    if let Some(data) = factory.product_data {
+        info!(target: logging::DIAG, "Factory config: product data {:02x?}", data);
+    }
+
+    Ok(CONFIG.call_once(|| factory))
+}
@@ -0,0 +1,196 @@
+use std::ffi::CStr;
+use std::fmt::{self, Write};
+use std::panic::PanicInfo;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use anyhow::Result;
+use log::{error, info};
+use spin::{Mutex, Once};
+
+use crate::{console, diag, diag_service, logging, nvs};
+
+pub const MAX_FAULT_LEN: usize = 512;
+const BACKTRACE_DEPTH: usize = 8;
+
+const NAMESPACE: &str = nvs::DIAG;
+const KEY: &str = "last_fault";
+
+static STORE: Once<nvs::Namespace> = Once::new();
+static LAST_FAULT: Mutex<Option<String>> = Mutex::new(None);
+
+// The panic path must not allocate, so the record is composed in place
+static PANICKING: AtomicBool = AtomicBool::new(false);
+static mut PANIC_BUF: [u8; MAX_FAULT_LEN] = [0; MAX_FAULT_LEN];
+
+struct FaultWriter<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl Write for FaultWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        // Silently truncate, a partial message is still better than none
+        let n = s.len().min(self.buf.len() - self.len);
+        self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+
+        Ok(())
+    }
+}
+
+pub fn init() -> Result<()> {
+    let store = STORE.try_call_once(|| nvs::Namespace::open(NAMESPACE))?;
+
+    let mut buf = [0u8; MAX_FAULT_LEN];
+    let mut fault = store
+        .get_blob(KEY, &mut buf)?
+        .map(|len| String::from_utf8_lossy(&buf[..len]).into_owned());
+
+    // Resets that never reach the panic hook still deserve a record
+    let reason = diag::reset_reason();
+    if fault.is_none() && diag::is_fault_reset(reason) {
+        let record = format!("Reset by {} without a recorded panic message", reason);
+        store.set_blob(KEY, record.as_bytes())?;
+        store.commit()?;
+        fault = Some(record);
+    }
+
+    match &fault {
+        Some(fault) => {
+            error!(target: logging::DIAG, "**************** LAST FAULT ****************");
+            for line in fault.lines() {
+                error!(target: logging::DIAG, "{}", line);
+            }
+            error!(target: logging::DIAG, "Acknowledge with the 'fault ack' console command");
+            error!(target: logging::DIAG, "********************************************");
+        }
+        None => info!(target: logging::DIAG, "No fault recorded, reset reason {}", reason),
+    }
+    *LAST_FAULT.lock() = fault;
+
+    std::panic::set_hook(Box::new(panic_hook));
+
+    Ok(())
+}
+
+pub fn last_fault() -> Option<String> {
+    LAST_FAULT.lock().clone()
+}
+
+/// Records a non-panic failure so it is still visible after the next reboot.
+pub fn record(message: &str) {
+    let mut end = message.len().min(MAX_FAULT_LEN);
+    while !message.is_char_boundary(end) {
+        end -= 1;
+    }
+    let message = &message[..end];
+
+    if let Some(store) = STORE.get() {
+        let persisted = store
+            .set_blob(KEY, message.as_bytes())
+            .and_then(|_| store.commit().map_err(Into::into));
+        if let Err(err) = persisted {
+            error!(target: logging::DIAG, "Persisting fault failed: {:?}", err);
+        }
+    }
+
+    *LAST_FAULT.lock() = Some(message.into());
+    diag_service::update_last_fault(message);
+}
+
+pub fn acknowledge() -> Result<()> {
+    if let Some(store) = STORE.get() {
+        store.remove(KEY)?;
+        store.commit()?;
+    }
+
+    *LAST_FAULT.lock() = None;
+    diag_service::update_last_fault("");
+    info!(target: logging::DIAG, "Last fault acknowledged");
+
+    Ok(())
+}
+
+fn panic_hook(info: &PanicInfo) {
+    if PANICKING.swap(true, Ordering::SeqCst) {
+        return;
+    }
+
+    let buf = unsafe { &mut *std::ptr::addr_of_mut!(PANIC_BUF) };
+    let mut writer = FaultWriter { buf, len: 0 };
+
+    let task = unsafe { esp_idf_sys::pcTaskGetName(std::ptr::null_mut()) };
+    if !task.is_null() {
+        let task = unsafe { CStr::from_ptr(task) };
+        let _ = writeln!(writer, "task: {}", task.to_str().unwrap_or("?"));
+    }
+    let _ = writeln!(writer, "{}", info);
+    write_backtrace(&mut writer);
+
+    let record = &writer.buf[..writer.len];
+    unsafe {
+        esp_idf_sys::esp_rom_printf(
+            b"%.*s\0".as_ptr() as _,
+            record.len() as i32,
+            record.as_ptr(),
+        );
+    }
+
+    if let Some(store) = STORE.get() {
+        if store.set_blob(KEY, record).is_err() || store.commit().is_err() {
+            unsafe {
+                esp_idf_sys::esp_rom_printf(b"Could not persist the panic record\n\0".as_ptr() as _)
+            };
+        }
+    }
+
+    // A write handler's panic unwinds to the dispatch, which fails the write
+    // and goes on; any other restarts the device, as panic_abort did
+    if hap_core::write::containing_panic() {
+        PANICKING.store(false, Ordering::SeqCst);
+        return;
+    }
+    std::process::abort();
+}
+
+#[cfg(target_arch = "xtensa")]
+fn write_backtrace(writer: &mut FaultWriter) {
+    let mut frame: esp_idf_sys::esp_backtrace_frame_t = unsafe { std::mem::zeroed() };
+    unsafe {
+        esp_idf_sys::esp_backtrace_get_start(&mut frame.pc, &mut frame.sp, &mut frame.next_pc)
+    };
+
+    let _ = write!(writer, "backtrace:");
+    for _ in 0..BACKTRACE_DEPTH {
+        // Strip the window increment bits so the address feeds straight into addr2line
+        let _ = write!(writer, " 0x{:08x}", (frame.pc & 0x3fff_ffff) | 0x4000_0000);
+        if frame.next_pc == 0 || !unsafe { esp_idf_sys::esp_backtrace_get_next_frame(&mut frame) } {
+            break;
+        }
+    }
+    let _ = writeln!(writer);
+}
+
+#[cfg(not(target_arch = "xtensa"))]
+fn write_backtrace(writer: &mut FaultWriter) {
+    // The IDF has no frame walker for RISC-V outside its own panic handler
+    let _ = writeln!(writer, "backtrace: unavailable on this target");
+}
+
+pub fn register_commands() {
+    console::register(
+        "fault",
+        "Show ('fault') or acknowledge ('fault ack') the last fault",
+        |args| match args {
+            ["ack"] => acknowledge(),
+            [] => {
+                match last_fault() {
+                    Some(fault) => println!("{}", fault),
+                    None => println!("No fault recorded"),
+                }
+                Ok(())
+            }
+            _ => anyhow::bail!("usage: fault [ack]"),
+        },
+    );
+}
@@ -0,0 +1,197 @@
+use std::sync::atomic::{AtomicU32, AtomicU8, Ordering};
+
+use anyhow::{bail, Result};
+use hap_core::guardrail::{ClockPolicy, Guardrail, Limits, Refusal, State};
+use log::{info, warn};
+use spin::{Mutex, Once};
+
+use crate::{app_state, clock, config, console, diag, diag_service, logging, metrics, nvs};
+
+const DEFAULTS: Limits = Limits {
+    max_on_secs: config::GUARD_MAX_ON_MINS * 60,
+    window_secs: config::GUARD_WINDOW_MINS * 60,
+    quiet: None,
+    cooldown_secs: config::GUARD_COOLDOWN_MINS * 60,
+    clock: if config::GUARD_CLOCK_FAIL_OPEN {
+        ClockPolicy::FailOpen
+    } else {
+        ClockPolicy::FailClosed
+    },
+};
+const _: () = assert!(
+    DEFAULTS.max_on_secs == 0 || DEFAULTS.max_on_secs < DEFAULTS.window_secs,
+    "the guardrail's on time has to be shorter than its window"
+);
+
+static STORE: Once<nvs::Namespace> = Once::new();
+static GUARD: Mutex<Guardrail> = Mutex::new(Guardrail::new(DEFAULTS));
+static TRIPS: AtomicU32 = AtomicU32::new(0);
+// As last notified
+static REPORTED: AtomicU8 = AtomicU8::new(State::Ok as u8);
+
+fn store() -> Result<&'static nvs::Namespace> {
+    STORE.try_call_once(|| nvs::Namespace::open(app_state::namespace()))
+}
+
+/// The minute of the local day, once the clock synced.
+fn minute() -> Option<u16> {
+    clock::local_time().map(|time| time.hour as u16 * 60 + time.minute as u16)
+}
+
+/// Loads the limits set from the console over the built-in ones.
+pub fn init() {
+    let mut bytes = [0u8; 32];
+    let stored = store().and_then(|store| Ok(store.get_blob("guardrail", &mut bytes)?));
+    match stored {
+        Ok(Some(len)) => match Limits::decode(&bytes[..len]) {
+            Some(limits) => GUARD.lock().set_limits(limits),
+            None => warn!(target: logging::OUTLET, "Ignoring malformed guardrail limits"),
+        },
+        Ok(None) => {}
+        Err(err) => {
+            warn!(target: logging::OUTLET, "Loading the guardrail limits failed: {:?}", err)
+        }
+    }
+}
+
+fn set_limits(limits: Limits) -> Result<()> {
+    if !limits.is_valid() {
+        bail!("the on time has to be shorter than the window, quiet hours have to end");
+    }
+    let store = store()?;
+    store.set_blob("guardrail", &limits.encode())?;
+    store.commit()?;
+    GUARD.lock().set_limits(limits);
+    info!(target: logging::OUTLET, "Guardrail limits now {}", describe(&limits));
+    notify();
+
+    Ok(())
+}
+
+/// Whether the outlet may switch on now.
+pub fn check() -> Result<(), Refusal> {
+    GUARD.lock().check(diag::uptime_secs(), minute())
+}
+
+/// Follows the outlet's state, whatever switched it.
+pub fn switched(on: bool) {
+    GUARD.lock().switched(on, diag::uptime_secs());
+    notify();
+}
+
+/// Checks the outlet while it is on, the limit that tripped if it has to
+/// switch off.
+pub fn trip() -> Option<Refusal> {
+    let refusal = GUARD.lock().tick(diag::uptime_secs(), minute());
+    if let Some(refusal) = refusal {
+        let count = TRIPS.fetch_add(1, Ordering::Relaxed) + 1;
+        warn!(target: logging::OUTLET, "Guardrail tripped ({}), switching off, #{}", refusal, count);
+    }
+
+    refusal
+}
+
+pub fn state() -> (State, u32) {
+    GUARD.lock().state(diag::uptime_secs(), minute())
+}
+
+/// Notifies controllers when the state changed, the seconds remaining are
+/// refreshed on read only.
+pub fn notify() {
+    let (state, _) = state();
+    if REPORTED.swap(state as u8, Ordering::Relaxed) != state as u8 {
+        diag_service::update_guard_state(state);
+    }
+}
+
+fn format_minute(minute: u16) -> String {
+    format!("{:02}:{:02}", minute / 60, minute % 60)
+}
+
+fn parse_minute(text: &str) -> Result<u16> {
+    let Some((hour, minute)) = text.split_once(':') else {
+        bail!("expected HH:MM, got {}", text);
+    };
+    let (hour, minute): (u16, u16) = (hour.parse()?, minute.parse()?);
+    if hour >= 24 || minute >= 60 {
+        bail!("{} is not a time of day", text);
+    }
+
+    Ok(hour * 60 + minute)
+}
+
+fn describe(limits: &Limits) -> String {
+    let duty = match limits.max_on_secs {
+        0 => "no duty limit".into(),
+        secs => format!(
+            "at most {} min on per {} min",
+            secs / 60,
+            limits.window_secs / 60
+        ),
+    };
+    let quiet = match limits.quiet {
+        Some((start, end)) => format!(
+            "quiet {}-{} (clock fails {})",
+            format_minute(start),
+            format_minute(end),
+            limits.clock.name()
+        ),
+        None => "no quiet hours".into(),
+    };
+
+    format!(
+        "{}, {}, {} min cooldown",
+        duty,
+        quiet,
+        limits.cooldown_secs / 60
+    )
+}
+
+pub fn register_metrics() {
+    metrics::register("guardrail_trips", || TRIPS.load(Ordering::Relaxed) as i64);
+    metrics::register("guardrail_state", || state().0 as i64);
+}
+
+pub fn register_commands() {
+    console::register(
+        "guard",
+        "Show the outlet's guardrails ('guard') or set them: 'guard duty <max min> <window min>|off', 'guard quiet <HH:MM> <HH:MM>|off', 'guard cooldown <min>', 'guard clock open|closed', 'guard clear' ends a cooldown",
+        |args| {
+            let mut limits = *GUARD.lock().limits();
+            match args {
+                [] => {
+                    let (state, secs) = state();
+                    println!("{}", describe(&limits));
+                    println!(
+                        "State: {}, {} s remaining, {} trips",
+                        state.name(),
+                        secs,
+                        TRIPS.load(Ordering::Relaxed)
+                    );
+                    return Ok(());
+                }
+                ["clear"] => {
+                    if !GUARD.lock().clear_cooldown(diag::uptime_secs()) {
+                        println!("Not cooling down");
+                    }
+                    notify();
+                    return Ok(());
+                }
+                ["duty", "off"] => limits.max_on_secs = 0,
+                ["duty", max, window] => {
+                    limits.max_on_secs = max.parse::<u32>()? * 60;
+                    limits.window_secs = window.parse::<u32>()? * 60;
+                }
+                ["quiet", "off"] => limits.quiet = None,
+                ["quiet", start, end] => {
+                    limits.quiet = Some((parse_minute(start)?, parse_minute(end)?))
+                }
+                ["cooldown", minutes] => limits.cooldown_secs = minutes.parse::<u32>()? * 60,
+                ["clock", "open"] => limits.clock = ClockPolicy::FailOpen,
+                ["clock", "closed"] => limits.clock = ClockPolicy::FailClosed,
+                _ => bail!("usage: guard [duty <max> <window> | duty off | quiet <HH:MM> <HH:MM> | quiet off | cooldown <min> | clock open|closed | clear]"),
+            }
+            set_limits(limits)
+        },
+    );
+}
@@ -0,0 +1,83 @@
+use hap_core::{chime, trace};
+use hap_core::{Event as HapEvent, HapSys};
+use log::{info, warn};
+use spin::Mutex;
+
+use crate::hap_sys::HAP;
+use crate::status_led::{self, Event};
+use crate::{buzzer, config, logging, pairing_window, pm, sessions, sleep};
+
+// An unpaired accessory has to stay reachable for pair-setup
+static PAIRING_MODE: Mutex<Option<sleep::Inhibitor>> = Mutex::new(None);
+
+fn set_pairing_mode(unpaired: bool) {
+    let mut inhibitor = PAIRING_MODE.lock();
+    if unpaired && inhibitor.is_none() {
+        *inhibitor = Some(sleep::inhibit());
+    } else if !unpaired {
+        *inhibitor = None;
+    }
+}
+
+fn on_hap_event(event: HapEvent) {
+    trace::event(event);
+    match event {
+        HapEvent::PairingStarted => {
+            info!(target: logging::HAP, "Pairing started");
+            status_led::event(Event::PairingStarted);
+            buzzer::play(&chime::PAIRING_STARTED);
+            pm::pairing(true);
+            if config::PAIRING_WINDOW_ENABLED {
+                pairing_window::pairing_started();
+            }
+        }
+        HapEvent::PairingAborted => {
+            warn!(target: logging::HAP, "Pairing aborted");
+            status_led::event(Event::PairingEnded);
+            pm::pairing(false);
+        }
+        HapEvent::ControllerPaired => {
+            info!(target: logging::HAP, "Controller paired");
+            status_led::event(Event::Paired);
+            buzzer::play(&chime::PAIRED);
+            pm::pairing(false);
+            set_pairing_mode(false);
+            if config::PAIRING_WINDOW_ENABLED {
+                pairing_window::controller_paired();
+            }
+        }
+        HapEvent::ControllerUnpaired => {
+            info!(target: logging::HAP, "Controller removed");
+            if HAP.sys().paired_controller_count() == 0 {
+                status_led::event(Event::Unpaired);
+                set_pairing_mode(true);
+                if config::PAIRING_WINDOW_ENABLED {
+                    pairing_window::controller_unpaired();
+                }
+            }
+        }
+        HapEvent::ControllerConnected(controller) => {
+            sleep::controller_connected();
+            sessions::connected(controller);
+        }
+        HapEvent::ControllerDisconnected(controller) => {
+            sessions::disconnected(controller);
+        }
+        _ => {}
+    }
+}
+
+pub fn register() {
+    let err = HAP.sys().on_event(on_hap_event);
+    if err != esp_idf_sys::ESP_OK {
+        warn!(target: logging::HAP, "Registering the HAP event handler failed: {}", err);
+    }
+
+    let paired = HAP.sys().paired_controller_count() > 0;
+    status_led::event(if paired {
+        Event::Paired
+    } else {
+        Event::Unpaired
+    });
+    set_pairing_mode(!paired);
+}
@@ -0,0 +1,381 @@
+use std::ffi::{c_void, CStr, CString};
+use std::mem::{size_of, transmute};
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+
+use esp_homekit_sdk_sys::{
+    hap_acc_t, hap_char_t, hap_data_val_t, hap_tlv8_val_t, hap_val_t, hap_write_data_t,
+};
+use esp_idf_sys::c_types::c_char;
+use hap_core::sys::{
+    perm, AccessoryInfo, RawVal, RawWrite, ReadCb, WriteCb, HAP_FAIL, HAP_SUCCESS,
+};
+use hap_core::{Acc, Char, ControllerId, Event, Format, Hap, HapSys, Serv, Value};
+use log::warn;
+use spin::Mutex;
+
+use crate::logging;
+
+// hap_core mirrors these, the casts below depend on identical layouts
+const _: () = assert!(size_of::<RawVal>() == size_of::<hap_val_t>());
+const _: () = assert!(size_of::<RawWrite>() == size_of::<hap_write_data_t>());
+const _: () = assert!(perm::PR as u32 == esp_homekit_sdk_sys::HAP_CHAR_PERM_PR);
+const _: () = assert!(perm::PW as u32 == esp_homekit_sdk_sys::HAP_CHAR_PERM_PW);
+const _: () = assert!(perm::EV as u32 == esp_homekit_sdk_sys::HAP_CHAR_PERM_EV);
+
+/// The esp-homekit-sdk behind the hap_core layer.
+pub struct EspHap;
+
+pub static HAP: Hap<EspHap> = Hap::new(EspHap);
+
+static EVENT_HANDLERS: Mutex<Vec<fn(Event)>> = Mutex::new(Vec::new());
+
+/// The controller id a connect or disconnect carries as its data.
+unsafe fn controller_of(data: *mut esp_idf_sys::c_types::c_void) -> ControllerId {
+    if data.is_null() {
+        return ControllerId::new("");
+    }
+    ControllerId::new(CStr::from_ptr(data as *const c_char).to_str().unwrap_or(""))
+}
+
+unsafe extern "C" fn on_hap_event(
+    _: *mut esp_idf_sys::c_types::c_void,
+    _: esp_idf_sys::esp_event_base_t,
+    event: i32,
+    data: *mut esp_idf_sys::c_types::c_void,
+) {
+    let event = match event as u32 {
+        esp_homekit_sdk_sys::hap_event_t_HAP_EVENT_PAIRING_STARTED => Event::PairingStarted,
+        esp_homekit_sdk_sys::hap_event_t_HAP_EVENT_PAIRING_ABORTED => Event::PairingAborted,
+        esp_homekit_sdk_sys::hap_event_t_HAP_EVENT_CTRL_PAIRED => Event::ControllerPaired,
+        esp_homekit_sdk_sys::hap_event_t_HAP_EVENT_CTRL_UNPAIRED => Event::ControllerUnpaired,
+        esp_homekit_sdk_sys::hap_event_t_HAP_EVENT_CTRL_CONNECTED => {
+            Event::ControllerConnected(controller_of(data))
+        }
+        esp_homekit_sdk_sys::hap_event_t_HAP_EVENT_CTRL_DISCONNECTED => {
+            Event::ControllerDisconnected(controller_of(data))
+        }
+        _ => Event::Other(event),
+    };
+
+    let handlers = EVENT_HANDLERS.lock().clone();
+    for handler in handlers {
+        handler(event);
+    }
+}
+
+/// The controller behind a read or write, from the request the SDK passes
+/// to the callbacks.
+pub fn request_controller(request: *mut c_void) -> Option<ControllerId> {
+    let id = unsafe { esp_homekit_sdk_sys::hap_req_get_ctrl_id(request as _) };
+    if id.is_null() {
+        return None;
+    }
+    let id = unsafe { CStr::from_ptr(id as *const c_char) };
+
+    id.to_str().ok().map(ControllerId::new)
+}
+
+// The SDK's HTTP server, as its requests tell it
+static HTTPD: AtomicPtr<c_void> = AtomicPtr::new(ptr::null_mut());
+
+/// The socket of a read or write. The request the SDK passes along is the
+/// httpd request, which also tells the server for `close_session`.
+pub fn request_socket(request: *mut c_void) -> Option<i32> {
+    if request.is_null() {
+        return None;
+    }
+    let request = request as *mut esp_idf_sys::httpd_req_t;
+    HTTPD.store(unsafe { (*request).handle }, Ordering::Relaxed);
+    let socket = unsafe { esp_idf_sys::httpd_req_to_sockfd(request) };
+
+    (socket >= 0).then_some(socket)
+}
+
+/// Runs on the server's task, where its sessions may be closed.
+unsafe extern "C" fn close_socket(socket: *mut c_void) {
+    let socket = socket as i32;
+    let err = esp_idf_sys::httpd_sess_trigger_close(HTTPD.load(Ordering::Relaxed), socket);
+    if err != esp_idf_sys::ESP_OK {
+        warn!(target: logging::HAP, "Closing HAP socket {} failed: {}", socket, err);
+    }
+}
+
+fn char_of(hc: *mut hap_char_t) -> Option<Char> {
+    Char::from_ptr(hc)
+}
+
+impl HapSys for EspHap {
+    fn init(&self) -> i32 {
+        unsafe {
+            esp_homekit_sdk_sys::hap_init(esp_homekit_sdk_sys::hap_transport_t_HAP_TRANSPORT_WIFI)
+        }
+    }
+
+    fn start(&self) -> i32 {
+        unsafe { esp_homekit_sdk_sys::hap_start() }
+    }
+
+    fn set_setup(&self, code: &CStr, id: &CStr) {
+        unsafe {
+            esp_homekit_sdk_sys::hap_set_setup_code(code.as_ptr() as _);
+            esp_homekit_sdk_sys::hap_set_setup_id(id.as_ptr() as _);
+        }
+    }
+
+    fn set_setup_info(&self, salt: &[u8; 16], verifier: &[u8; 384], id: &CStr) {
+        let info = esp_homekit_sdk_sys::hap_setup_info_t {
+            salt: *salt,
+            verifier: *verifier,
+        };
+        unsafe {
+            esp_homekit_sdk_sys::hap_set_setup_info(&info);
+            esp_homekit_sdk_sys::hap_set_setup_id(id.as_ptr() as _);
+        }
+    }
+
+    fn paired_controller_count(&self) -> i32 {
+        unsafe { esp_homekit_sdk_sys::hap_get_paired_controller_count() }
+    }
+
+    fn reset_to_factory(&self) -> i32 {
+        unsafe { esp_homekit_sdk_sys::hap_reset_to_factory() }
+    }
+
+    fn reset_network(&self) -> i32 {
+        unsafe { esp_homekit_sdk_sys::hap_reset_network() }
+    }
+
+    fn reset_pairings(&self) -> i32 {
+        unsafe { esp_homekit_sdk_sys::hap_reset_pairings() }
+    }
+
+    fn on_event(&self, handler: fn(Event)) -> i32 {
+        EVENT_HANDLERS.lock().push(handler);
+
+        static REGISTERED: AtomicBool = AtomicBool::new(false);
+        if REGISTERED.swap(true, Ordering::Relaxed) {
+            return esp_idf_sys::ESP_OK;
+        }
+        unsafe {
+            esp_idf_sys::esp_event_handler_register(
+                esp_homekit_sdk_sys::HAP_EVENT as _,
+                esp_idf_sys::ESP_EVENT_ANY_ID,
+                Some(on_hap_event),
+                ptr::null_mut(),
+            )
+        }
+    }
+
+    fn close_session(&self, socket: i32) -> i32 {
+        let server = HTTPD.load(Ordering::Relaxed);
+        if server.is_null() {
+            return HAP_FAIL;
+        }
+        let err = unsafe {
+            esp_idf_sys::httpd_queue_work(
+                server,
+                Some(close_socket),
+                socket as usize as *mut c_void,
+            )
+        };
+
+        if err == esp_idf_sys::ESP_OK {
+            HAP_SUCCESS
+        } else {
+            HAP_FAIL
+        }
+    }
+
+    fn acc_create(&self, info: &AccessoryInfo) -> Option<Acc> {
+        let config = esp_homekit_sdk_sys::hap_acc_cfg_t {
+            name: info.name.as_ptr() as _,
+            model: info.model.as_ptr() as _,
+            manufacturer: info.manufacturer.as_ptr() as _,
+            serial_num: info.serial_num.as_ptr() as _,
+            fw_rev: info.fw_rev.as_ptr() as _,
+            hw_rev: info.hw_rev.as_ptr() as _,
+            pv: info.pv.as_ptr() as _,
+            cid: info.cid as _,
+            // Only the pointer type of the argument differs
+            identify_routine: Some(unsafe {
+                transmute::<_, unsafe extern "C" fn(*mut hap_acc_t) -> i32>(info.identify)
+            }),
+        };
+
+        Acc::from_ptr(unsafe { esp_homekit_sdk_sys::hap_acc_create(&config) })
+    }
+
+    fn acc_add_serv(&self, acc: Acc, serv: Serv) -> i32 {
+        unsafe { esp_homekit_sdk_sys::hap_acc_add_serv(acc.as_ptr(), serv.as_ptr()) }
+    }
+
+    fn add_accessory(&self, acc: Acc) {
+        unsafe { esp_homekit_sdk_sys::hap_add_accessory(acc.as_ptr()) };
+    }
+
+    fn add_bridged_accessory(&self, acc: Acc, aid: i32) -> i32 {
+        unsafe { esp_homekit_sdk_sys::hap_add_bridged_accessory(acc.as_ptr(), aid) }
+    }
+
+    fn unique_aid(&self, id: &CStr) -> i32 {
+        unsafe { esp_homekit_sdk_sys::hap_get_unique_aid(id.as_ptr() as _) }
+    }
+
+    fn serv_create(&self, uuid: &'static [u8]) -> Option<Serv> {
+        Serv::from_ptr(unsafe { esp_homekit_sdk_sys::hap_serv_create(uuid.as_ptr() as _) })
+    }
+
+    fn serv_outlet_create(&self, on: bool, in_use: bool) -> Option<Serv> {
+        Serv::from_ptr(unsafe { esp_homekit_sdk_sys::hap_serv_outlet_create(on, in_use) })
+    }
+
+    fn serv_switch_create(&self, on: bool) -> Option<Serv> {
+        Serv::from_ptr(unsafe { esp_homekit_sdk_sys::hap_serv_switch_create(on) })
+    }
+
+    fn serv_add_char(&self, serv: Serv, hc: Char) -> i32 {
+        unsafe { esp_homekit_sdk_sys::hap_serv_add_char(serv.as_ptr(), hc.as_ptr()) }
+    }
+
+    fn serv_char_by_uuid(&self, serv: Serv, uuid: &'static [u8]) -> Option<Char> {
+        char_of(unsafe {
+            esp_homekit_sdk_sys::hap_serv_get_char_by_uuid(serv.as_ptr(), uuid.as_ptr() as _)
+        })
+    }
+
+    fn serv_set_priv(&self, serv: Serv, priv_data: *mut c_void) {
+        unsafe { esp_homekit_sdk_sys::hap_serv_set_priv(serv.as_ptr(), priv_data as _) };
+    }
+
+    fn serv_set_write_cb(&self, serv: Serv, cb: WriteCb) {
+        // hap_write_data_t and RawWrite are the same layout
+        unsafe { esp_homekit_sdk_sys::hap_serv_set_write_cb(serv.as_ptr(), Some(transmute(cb))) };
+    }
+
+    fn serv_set_read_cb(&self, serv: Serv, cb: ReadCb) {
+        // Only the pointer types of the characteristic and status differ
+        unsafe { esp_homekit_sdk_sys::hap_serv_set_read_cb(serv.as_ptr(), Some(transmute(cb))) };
+    }
+
+    fn serv_mark_primary(&self, serv: Serv) {
+        unsafe { esp_homekit_sdk_sys::hap_serv_mark_primary(serv.as_ptr()) };
+    }
+
+    fn serv_mark_hidden(&self, serv: Serv) {
+        unsafe { esp_homekit_sdk_sys::hap_serv_mark_hidden(serv.as_ptr()) };
+    }
+
+    fn char_create(&self, uuid: &'static [u8], perms: u16, value: &Value) -> Option<Char> {
+        let uuid = uuid.as_ptr() as _;
+        let hc = unsafe {
+            match value {
+                Value::Bool(b) => esp_homekit_sdk_sys::hap_char_bool_create(uuid, perms, *b),
+                Value::Uint8(u) => esp_homekit_sdk_sys::hap_char_uint8_create(uuid, perms, *u),
+                Value::Uint16(u) => esp_homekit_sdk_sys::hap_char_uint16_create(uuid, perms, *u),
+                Value::Uint32(u) => esp_homekit_sdk_sys::hap_char_uint32_create(uuid, perms, *u),
+                Value::Uint64(u) => esp_homekit_sdk_sys::hap_char_uint64_create(uuid, perms, *u),
+                Value::Int(i) => esp_homekit_sdk_sys::hap_char_int_create(uuid, perms, *i),
+                Value::Float(f) => esp_homekit_sdk_sys::hap_char_float_create(uuid, perms, *f),
+                Value::String(s) => {
+                    // Copied by the SDK
+                    let s = CString::new(s.as_str()).unwrap_or_default();
+                    esp_homekit_sdk_sys::hap_char_string_create(uuid, perms, s.as_ptr() as _)
+                }
+                Value::Tlv8(bytes) => {
+                    let mut tlv8 = hap_tlv8_val_t {
+                        buf: bytes.as_ptr() as _,
+                        buflen: bytes.len() as _,
+                    };
+                    esp_homekit_sdk_sys::hap_char_tlv8_create(uuid, perms, &mut tlv8)
+                }
+                Value::Data(bytes) => {
+                    let mut data = hap_data_val_t {
+                        buf: bytes.as_ptr() as _,
+                        buflen: bytes.len() as _,
+                    };
+                    esp_homekit_sdk_sys::hap_char_data_create(uuid, perms, &mut data)
+                }
+            }
+        };
+
+        char_of(hc)
+    }
+
+    fn char_name_create(&self, name: &CStr) -> Option<Char> {
+        char_of(unsafe { esp_homekit_sdk_sys::hap_char_name_create(name.as_ptr() as _) })
+    }
+
+    fn char_status_fault_create(&self, fault: u8) -> Option<Char> {
+        char_of(unsafe { esp_homekit_sdk_sys::hap_char_status_fault_create(fault) })
+    }
+
+    fn char_type_uuid(&self, hc: Char) -> Option<String> {
+        let uuid = unsafe { esp_homekit_sdk_sys::hap_char_get_type_uuid(hc.as_ptr()) };
+        if uuid.is_null() {
+            return None;
+        }
+
+        Some(
+            unsafe { CStr::from_ptr(uuid) }
+                .to_string_lossy()
+                .into_owned(),
+        )
+    }
+
+    fn char_update_val(&self, hc: Char, val: &RawVal) -> i32 {
+        unsafe {
+            esp_homekit_sdk_sys::hap_char_update_val(
+                hc.as_ptr(),
+                val as *const RawVal as *const hap_val_t as *mut _,
+            )
+        }
+    }
+
+    fn char_get_val(&self, hc: Char, format: Format) -> Option<Value> {
+        let val = unsafe { esp_homekit_sdk_sys::hap_char_get_val(hc.as_ptr()) };
+        if val.is_null() {
+            return None;
+        }
+
+        unsafe { Value::decode(format, &*(val as *const RawVal)) }
+    }
+
+    fn update_config_number(&self) {
+        unsafe { esp_homekit_sdk_sys::hap_update_config_number() };
+    }
+
+    fn serv_get_iid(&self, serv: Serv) -> i32 {
+        unsafe { esp_homekit_sdk_sys::hap_serv_get_iid(serv.as_ptr()) as i32 }
+    }
+
+    fn serv_set_iid(&self, serv: Serv, iid: i32) {
+        unsafe { esp_homekit_sdk_sys::hap_serv_set_iid(serv.as_ptr(), iid) };
+    }
+
+    fn char_get_iid(&self, hc: Char) -> i32 {
+        unsafe { esp_homekit_sdk_sys::hap_char_get_iid(hc.as_ptr()) as i32 }
+    }
+
+    fn char_set_iid(&self, hc: Char, iid: i32) {
+        unsafe { esp_homekit_sdk_sys::hap_char_set_iid(hc.as_ptr(), iid) };
+    }
+
+    fn char_int_set_constraints(&self, hc: Char, min: i32, max: i32, step: i32) {
+        unsafe { esp_homekit_sdk_sys::hap_char_int_set_constraints(hc.as_ptr(), min, max, step) };
+    }
+
+    fn char_float_set_constraints(&self, hc: Char, min: f32, max: f32, step: f32) {
+        unsafe { esp_homekit_sdk_sys::hap_char_float_set_constraints(hc.as_ptr(), min, max, step) };
+    }
+
+    fn char_add_valid_vals(&self, hc: Char, values: &'static [u8]) {
+        unsafe {
+            esp_homekit_sdk_sys::hap_char_add_valid_vals(
+                hc.as_ptr(),
+                values.as_ptr(),
+                values.len() as _,
+            )
+        };
+    }
+}
@@ -0,0 +1,140 @@
+use std::fmt::Write;
+
+use anyhow::Result;
+use embedded_svc::http::server::registry::Registry;
+use embedded_svc::http::server::Response;
+use embedded_svc::http::{Headers, SendHeaders, SendStatus};
+use embedded_svc::io::Write as _;
+use esp_idf_svc::http::server::{Configuration, EspHttpServer};
+use log::{info, warn};
+use spin::Mutex;
+
+use crate::{audit, config, coredump, diag, logging, metrics};
+
+static SERVER: Mutex<Option<EspHttpServer>> = Mutex::new(None);
+
+fn authorized(req: &impl Headers) -> bool {
+    if config::HTTP_API_TOKEN.is_empty() {
+        return true;
+    }
+
+    req.header("Authorization")
+        .as_deref()
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map_or(false, |token| token == config::HTTP_API_TOKEN)
+}
+
+fn unauthorized(mut resp: impl Response) -> Result<()> {
+    resp.set_status(401);
+    resp.set_header("WWW-Authenticate", "Bearer");
+    resp.send_str("Unauthorized")?;
+
+    Ok(())
+}
+
+pub fn start() -> Result<()> {
+    if config::HTTP_API_TOKEN.is_empty() {
+        warn!(target: logging::HTTP, "No API token configured, the HTTP API is unauthenticated");
+    }
+
+    let mut server = EspHttpServer::new(&Configuration {
+        http_port: config::HTTP_PORT,
+        max_open_sockets: config::HTTP_MAX_OPEN_SOCKETS,
+        ..Default::default()
+    })?;
+
+    server.handle_get("/api/metrics", |req, mut resp| {
+        if !authorized(&req) {
+            return unauthorized(resp);
+        }
+
+        resp.set_header("Content-Type", "application/json");
+        resp.send_str(&metrics::render_json())?;
+
+        Ok(())
+    })?;
+
+    server.handle_get("/api/diagnostics", |req, mut resp| {
+        if !authorized(&req) {
+            return unauthorized(resp);
+        }
+
+        resp.set_header("Content-Type", "application/json");
+        resp.send_str(&diag::render_json())?;
+
+        Ok(())
+    })?;
+
+    server.handle_get("/api/audit", |req, mut resp| {
+        if !authorized(&req) {
+            return unauthorized(resp);
+        }
+
+        resp.set_header("Content-Type", "application/json");
+        resp.send_str(&audit::render_json())?;
+
+        Ok(())
+    })?;
+
+    server.handle_get("/api/coredump", |req, mut resp| {
+        if !authorized(&req) {
+            return unauthorized(resp);
+        }
+
+        let Some(dump) = coredump::find() else {
+            resp.set_status(404);
+            resp.send_str("No core dump stored")?;
+            return Ok(());
+        };
+
+        resp.set_header("Content-Type", "application/octet-stream");
+        resp.set_header(
+            "Content-Disposition",
+            "attachment; filename=\"coredump.elf\"",
+        );
+        let mut writer = resp.into_writer(req)?;
+        dump.read_chunks(|chunk| {
+            writer.write_all(chunk)?;
+            Ok(())
+        })?;
+
+        Ok(())
+    })?;
+
+    *SERVER.lock() = Some(server);
+    info!(target: logging::HTTP, "HTTP server listening on port {}", config::HTTP_PORT);
+
+    Ok(())
+}
+
+/// Stops the server, for the setup portal to take its port; whether it was
+/// running.
+pub fn stop() -> bool {
+    let stopped = SERVER.lock().take().is_some();
+    if stopped {
+        info!(target: logging::HTTP, "HTTP server stopped");
+    }
+
+    stopped
+}
+
+pub fn json_string(value: &str) -> String {
+    let mut json = String::with_capacity(value.len() + 2);
+    json.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(json, "\\u{:04x}", c as u32);
+            }
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+
+    json
+}
@@ -0,0 +1,41 @@
+use anyhow::Result;
+use hap_core::IidMap;
+use log::{info, warn};
+
+use crate::{logging, nvs};
+
+const NAMESPACE: &str = nvs::IIDS;
+const KEY: &str = "map";
+// About 40 bytes per service or characteristic
+const MAX_LEN: usize = 4096;
+
+/// The stored iids, or an empty map that takes over the SDK's on first boot.
+pub fn load() -> IidMap {
+    let mut buf = vec![0u8; MAX_LEN];
+    let stored = nvs::Namespace::open(NAMESPACE).and_then(|store| store.get_blob(KEY, &mut buf));
+
+    match stored {
+        Ok(Some(len)) => IidMap::decode(&buf[..len]),
+        Ok(None) => {
+            info!(target: logging::HAP, "No stored iids, keeping the ones the SDK assigns");
+            IidMap::new()
+        }
+        Err(err) => {
+            warn!(target: logging::HAP, "Reading the stored iids failed: {:?}", err);
+            IidMap::new()
+        }
+    }
+}
+
+pub fn store(map: &IidMap) -> Result<()> {
+    if !map.is_changed() {
+        return Ok(());
+    }
+
+    let store = nvs::Namespace::open(NAMESPACE)?;
+    store.set_blob(KEY, &map.encode())?;
+    store.commit()?;
+    info!(target: logging::HAP, "Stored the iids of new services and characteristics");
+
+    Ok(())
+}
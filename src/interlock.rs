@@ -0,0 +1,158 @@
+use std::sync::{Mutex, PoisonError};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use hap_core::interlock::{Plan, Rules};
+use log::{info, warn};
+
+use crate::relay::RelayBackend;
+use crate::{config, logging};
+
+type Listener = &'static (dyn Fn(u32) + Send + Sync);
+
+static LISTENERS: spin::Mutex<Vec<Listener>> = spin::Mutex::new(Vec::new());
+
+/// Registers a listener for the channels a rule switched along with the one
+/// written, as a mask; listeners run on the writing task.
+pub fn subscribe(listener: impl Fn(u32) + Send + Sync + 'static) {
+    LISTENERS.lock().push(Box::leak(Box::new(listener)));
+}
+
+/// Tells the listeners of channels switched behind their backs.
+pub fn notify(changed: u32) {
+    let listeners = LISTENERS.lock().clone();
+    for listener in listeners {
+        listener(changed);
+    }
+}
+
+/// A relay backend held to the interlock and follow rules, whatever writes
+/// to it: closing a channel opens the others of its group first, and
+/// followers are switched along with their leaders.
+pub struct InterlockedRelay {
+    inner: &'static dyn RelayBackend,
+    rules: Rules,
+    lock: Mutex<()>,
+}
+
+impl InterlockedRelay {
+    fn state(&self) -> u32 {
+        (0..self.inner.channels())
+            .filter(|&channel| self.inner.get(channel) == Some(true))
+            .fold(0, |state, channel| state | 1 << channel)
+    }
+
+    fn apply(&self, plan: &Plan) -> Result<()> {
+        for &channel in &plan.opens {
+            self.inner.set(channel, false)?;
+        }
+        if plan.break_first && !plan.closes.is_empty() {
+            thread::sleep(Duration::from_millis(config::RELAY_BREAK_MS));
+        }
+        for &channel in &plan.closes {
+            self.inner.set(channel, true)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl RelayBackend for InterlockedRelay {
+    fn channels(&self) -> u8 {
+        self.inner.channels()
+    }
+
+    fn set(&self, channel: u8, on: bool) -> Result<()> {
+        if channel >= self.channels() {
+            bail!("no relay channel {}", channel);
+        }
+
+        let (plan, result) = {
+            let _lock = self.lock.lock().unwrap_or_else(PoisonError::into_inner);
+            let plan = self.rules.plan(self.state(), channel, on);
+            let result = self.apply(&plan);
+            (plan, result)
+        };
+        if (plan.target & 1 << channel != 0) != on {
+            info!(target: logging::OUTLET, "Channel {} follows another, not switching it", channel);
+        }
+
+        let along = plan
+            .opens
+            .iter()
+            .chain(&plan.closes)
+            .filter(|&&other| other != channel)
+            .fold(0, |mask, other| mask | 1 << other);
+        if along != 0 {
+            info!(
+                target: logging::OUTLET,
+                "Switching channel {} {} also opened {:?} and closed {:?}",
+                channel,
+                if on { "on" } else { "off" },
+                plan.opens,
+                plan.closes
+            );
+            notify(along);
+        }
+
+        result
+    }
+
+    fn get(&self, channel: u8) -> Option<bool> {
+        self.inner.get(channel)
+    }
+
+    fn faulted(&self) -> bool {
+        self.inner.faulted()
+    }
+}
+
+/// Puts the configured rules between `relay` and its users, checked against
+/// its channels and the `exposed` ones; without rules the relay is used as
+/// it is. Followers take their leaders' state right away.
+pub fn wrap(relay: &'static dyn RelayBackend, exposed: u32) -> Result<&'static dyn RelayBackend> {
+    let rules = Rules::parse(
+        config::RELAY_INTERLOCK,
+        config::RELAY_FOLLOW,
+        relay.channels(),
+        exposed,
+    )?;
+    if rules.is_empty() {
+        return Ok(relay);
+    }
+    for group in rules.groups() {
+        info!(target: logging::OUTLET, "Interlocked relay channels: {:#b}", group);
+    }
+    for follow in rules.follows() {
+        info!(
+            target: logging::OUTLET,
+            "Relay channel {} follows channel {}{}",
+            follow.channel,
+            follow.leader,
+            if follow.inverted { ", inverted" } else { "" }
+        );
+    }
+
+    let interlocked = InterlockedRelay {
+        inner: relay,
+        rules,
+        lock: Mutex::new(()),
+    };
+    let state = interlocked.state();
+    let settled = interlocked.rules.settle(state);
+    for channel in 0..relay.channels() {
+        if (state ^ settled) & 1 << channel != 0 {
+            if let Err(err) = relay.set(channel, settled & 1 << channel != 0) {
+                warn!(
+                    target: logging::OUTLET,
+                    "Setting follower channel {} failed: {:?}",
+                    channel,
+                    err
+                );
+            }
+        }
+    }
+
+    Ok(Box::leak(Box::new(interlocked)))
+}
@@ -0,0 +1,445 @@
+use std::ptr;
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::PoisonError;
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Result};
+use esp_idf_sys::{esp, rmt_item32_t};
+use hap_core::sys::uuid;
+use hap_core::{CharSlot, ServiceBuilder, Status, Write};
+use log::{info, warn};
+use spin::{Mutex, Once};
+
+use crate::event_bus::{self, Event, Reading};
+use crate::{board, config, console, logging, nvs, pm, tasks};
+
+const NAMESPACE: &str = nvs::IR;
+
+// 80 MHz APB clock divided down to 1 µs ticks
+const CLK_DIV: u8 = 80;
+const RX_IDLE_US: u16 = 12_000;
+const RX_FILTER_TICKS: u8 = 100;
+
+const MAX_RAW_DURATIONS: usize = 256;
+
+// NEC timings in µs; frames start every 108 ms while a key is held
+const NEC_LEADER_MARK: u16 = 9000;
+const NEC_LEADER_SPACE: u16 = 4500;
+const NEC_REPEAT_SPACE: u16 = 2250;
+const NEC_BIT_MARK: u16 = 560;
+const NEC_ZERO_SPACE: u16 = 560;
+const NEC_ONE_SPACE: u16 = 1690;
+const NEC_FRAME_PERIOD_US: u32 = 108_000;
+
+const KIND_NEC: u8 = 1;
+const KIND_RAW: u8 = 2;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Code {
+    /// Address bytes as sent (extended NEC uses both), command without its complement
+    Nec { address: u16, command: u8 },
+    /// Alternating mark and space durations in µs, starting with a mark
+    Raw(Vec<u16>),
+}
+
+impl Code {
+    fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            Code::Nec { address, command } => {
+                let [lo, hi] = address.to_le_bytes();
+                vec![KIND_NEC, lo, hi, *command]
+            }
+            Code::Raw(durations) => {
+                let mut bytes = vec![KIND_RAW];
+                for duration in durations {
+                    bytes.extend_from_slice(&duration.to_le_bytes());
+                }
+                bytes
+            }
+        }
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        match bytes {
+            [KIND_NEC, lo, hi, command] => Ok(Code::Nec {
+                address: u16::from_le_bytes([*lo, *hi]),
+                command: *command,
+            }),
+            [KIND_RAW, durations @ ..] if durations.len() % 2 == 0 => Ok(Code::Raw(
+                durations
+                    .chunks(2)
+                    .map(|pair| u16::from_le_bytes([pair[0], pair[1]]))
+                    .collect(),
+            )),
+            _ => bail!("stored code is malformed"),
+        }
+    }
+
+    /// Recognizes an NEC frame in captured durations, keeping anything else raw.
+    fn decode(durations: Vec<u16>) -> Self {
+        let near = |value: u16, expected: u16| {
+            let tolerance = expected / 4;
+            value.abs_diff(expected) <= tolerance
+        };
+
+        // Leader, 32 bits of mark and space, final mark
+        if durations.len() >= 67
+            && near(durations[0], NEC_LEADER_MARK)
+            && near(durations[1], NEC_LEADER_SPACE)
+        {
+            let mut bits = 0u32;
+            let valid = (0..32).all(|bit| {
+                let mark = durations[2 + bit * 2];
+                let space = durations[3 + bit * 2];
+                if near(space, NEC_ONE_SPACE) {
+                    bits |= 1 << bit;
+                }
+                near(mark, NEC_BIT_MARK)
+                    && (near(space, NEC_ONE_SPACE) || near(space, NEC_ZERO_SPACE))
+            });
+            let command = (bits >> 16) as u8;
+            if valid && command == !(bits >> 24) as u8 {
+                return Code::Nec {
+                    address: bits as u16,
+                    command,
+                };
+            }
+        }
+
+        Code::Raw(durations)
+    }
+
+    fn is_nec_repeat(durations: &[u16]) -> bool {
+        durations.len() <= 3
+            && durations
+                .first()
+                .map_or(false, |mark| mark.abs_diff(NEC_LEADER_MARK) < 2000)
+            && durations
+                .get(1)
+                .map_or(false, |space| space.abs_diff(NEC_REPEAT_SPACE) < 600)
+    }
+}
+
+/// Packs alternating mark/space durations into RMT items, one pair per item.
+fn items(durations: &[u16]) -> Vec<rmt_item32_t> {
+    durations
+        .chunks(2)
+        .map(|pair| {
+            let mark = pair[0].min(0x7fff) as u32;
+            let space = pair.get(1).copied().unwrap_or(0).min(0x7fff) as u32;
+            let mut item: rmt_item32_t = unsafe { std::mem::zeroed() };
+            // duration0 | level0 << 15 | duration1 << 16 | level1 << 31
+            item.__bindgen_anon_1.val = mark | 1 << 15 | space << 16;
+            item
+        })
+        .collect()
+}
+
+fn nec_frame(address: u16, command: u8) -> Vec<u16> {
+    let bits = address as u32 | (command as u32) << 16 | (!command as u32) << 24;
+
+    let mut durations = vec![NEC_LEADER_MARK, NEC_LEADER_SPACE];
+    for bit in 0..32 {
+        durations.push(NEC_BIT_MARK);
+        durations.push(if bits & 1 << bit != 0 {
+            NEC_ONE_SPACE
+        } else {
+            NEC_ZERO_SPACE
+        });
+    }
+    durations.push(NEC_BIT_MARK);
+
+    durations
+}
+
+/// Time left of the 108 ms frame period after `durations` were sent.
+fn frame_gap(durations: &[u16]) -> Duration {
+    let sent: u32 = durations.iter().map(|&d| d as u32).sum();
+    Duration::from_micros(NEC_FRAME_PERIOD_US.saturating_sub(sent) as u64)
+}
+
+static TX_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+// The RMT clock runs off APB, scaling it mid-frame detunes the carrier
+static PM_LOCK: Once<pm::Lock> = Once::new();
+static STORE: Once<nvs::Namespace> = Once::new();
+
+fn write(durations: &[u16]) -> Result<()> {
+    let items = items(durations);
+    esp!(unsafe {
+        esp_idf_sys::rmt_write_items(
+            board::IR_TX_CHANNEL,
+            items.as_ptr(),
+            items.len() as i32,
+            true,
+        )
+    })?;
+
+    Ok(())
+}
+
+/// Sends a code. NEC is one full frame followed by repeat codes in the 108 ms
+/// frame raster, as a held remote key would; many receivers drop repeated
+/// full frames.
+pub fn transmit(code: &Code) -> Result<()> {
+    let _lock = TX_LOCK.lock().unwrap_or_else(PoisonError::into_inner);
+    let _pm = PM_LOCK.get().map(pm::Lock::acquire);
+
+    match code {
+        Code::Nec { address, command } => {
+            let frame = nec_frame(*address, *command);
+            write(&frame)?;
+
+            let repeat = [NEC_LEADER_MARK, NEC_REPEAT_SPACE, NEC_BIT_MARK];
+            let mut previous: &[u16] = &frame;
+            for _ in 0..config::IR_NEC_REPEATS {
+                thread::sleep(frame_gap(previous));
+                write(&repeat)?;
+                previous = &repeat;
+            }
+        }
+        Code::Raw(durations) => write(durations)?,
+    }
+
+    Ok(())
+}
+
+fn store() -> Result<&'static nvs::Namespace> {
+    STORE.try_call_once(|| nvs::Namespace::open(NAMESPACE))
+}
+
+pub fn load(slot: &str) -> Result<Code> {
+    let mut buf = [0u8; 1 + MAX_RAW_DURATIONS * 2];
+    let len = store()?
+        .get_blob(slot, &mut buf)?
+        .ok_or_else(|| anyhow!("no code stored under '{}'", slot))?;
+
+    Code::from_bytes(&buf[..len])
+}
+
+pub fn slots() -> Result<Vec<String>> {
+    Ok(store()?.keys())
+}
+
+fn save(slot: &str, code: &Code) -> Result<()> {
+    let store = store()?;
+    store.set_blob(slot, &code.to_bytes())?;
+    store.commit()?;
+
+    Ok(())
+}
+
+fn delete(slot: &str) -> Result<()> {
+    let store = store()?;
+    store.remove(slot)?;
+    store.commit()?;
+
+    Ok(())
+}
+
+/// Captures one frame from the receiver; the receiver output is active low.
+fn capture(timeout: Duration) -> Result<Vec<u16>> {
+    let _pm = PM_LOCK.get().map(pm::Lock::acquire);
+    let mut ring: esp_idf_sys::RingbufHandle_t = ptr::null_mut();
+    esp!(unsafe { esp_idf_sys::rmt_get_ringbuf_handle(board::IR_RX_CHANNEL, &mut ring) })?;
+    esp!(unsafe { esp_idf_sys::rmt_rx_start(board::IR_RX_CHANNEL, true) })?;
+
+    let mut size = 0;
+    let ticks = timeout.as_millis() as u32 * esp_idf_sys::configTICK_RATE_HZ / 1000;
+    let received = unsafe { esp_idf_sys::xRingbufferReceive(ring, &mut size, ticks) };
+    unsafe { esp_idf_sys::rmt_rx_stop(board::IR_RX_CHANNEL) };
+    if received.is_null() {
+        bail!("nothing received within {} s", timeout.as_secs());
+    }
+
+    let count = size / std::mem::size_of::<rmt_item32_t>();
+    let items = unsafe { std::slice::from_raw_parts(received as *const rmt_item32_t, count) };
+    let mut durations = Vec::with_capacity(count * 2);
+    for item in items {
+        let val = unsafe { item.__bindgen_anon_1.val };
+        for duration in [val & 0x7fff, (val >> 16) & 0x7fff] {
+            if duration != 0 && durations.len() < MAX_RAW_DURATIONS {
+                durations.push(duration as u16);
+            }
+        }
+    }
+    unsafe { esp_idf_sys::vRingbufferReturnItem(ring, received) };
+
+    Ok(durations)
+}
+
+fn learn(slot: &str) -> Result<Code> {
+    if slot.is_empty() || slot.len() > 15 {
+        bail!("slot names have 1 to 15 characters");
+    }
+
+    println!("Press the remote key briefly...");
+    let durations = capture(Duration::from_secs(config::IR_LEARN_TIMEOUT_SECS))?;
+    if Code::is_nec_repeat(&durations) {
+        bail!("caught only a repeat frame, press the key briefly");
+    }
+    if durations.len() < 4 {
+        bail!("caught only {} edges, probably noise", durations.len());
+    }
+
+    let code = Code::decode(durations);
+    save(slot, &code)?;
+    info!(target: logging::IR, "Learned {} as {:?}", slot, code);
+
+    Ok(code)
+}
+
+fn init_rmt() -> Result<()> {
+    let mut tx: esp_idf_sys::rmt_config_t = unsafe { std::mem::zeroed() };
+    tx.rmt_mode = esp_idf_sys::rmt_mode_t_RMT_MODE_TX;
+    tx.channel = board::IR_TX_CHANNEL;
+    tx.gpio_num = board::IR_TX_GPIO;
+    tx.clk_div = CLK_DIV;
+    tx.mem_block_num = 1;
+    unsafe {
+        let tx_config = &mut tx.__bindgen_anon_1.tx_config;
+        tx_config.carrier_en = true;
+        tx_config.carrier_freq_hz = config::IR_CARRIER_HZ;
+        tx_config.carrier_duty_percent = 33;
+        tx_config.carrier_level = esp_idf_sys::rmt_carrier_level_t_RMT_CARRIER_LEVEL_HIGH;
+        tx_config.idle_level = esp_idf_sys::rmt_idle_level_t_RMT_IDLE_LEVEL_LOW;
+        tx_config.idle_output_en = true;
+    }
+    esp!(unsafe { esp_idf_sys::rmt_config(&tx) })?;
+    esp!(unsafe { esp_idf_sys::rmt_driver_install(board::IR_TX_CHANNEL, 0, 0) })?;
+
+    let mut rx: esp_idf_sys::rmt_config_t = unsafe { std::mem::zeroed() };
+    rx.rmt_mode = esp_idf_sys::rmt_mode_t_RMT_MODE_RX;
+    rx.channel = board::IR_RX_CHANNEL;
+    rx.gpio_num = board::IR_RX_GPIO;
+    rx.clk_div = CLK_DIV;
+    rx.mem_block_num = 1;
+    unsafe {
+        let rx_config = &mut rx.__bindgen_anon_1.rx_config;
+        rx_config.idle_threshold = RX_IDLE_US;
+        rx_config.filter_en = true;
+        rx_config.filter_ticks_thresh = RX_FILTER_TICKS;
+    }
+    esp!(unsafe { esp_idf_sys::rmt_config(&rx) })?;
+    esp!(unsafe { esp_idf_sys::rmt_driver_install(board::IR_RX_CHANNEL, 1024, 0) })?;
+
+    Ok(())
+}
+
+static REQUESTS: Once<Mutex<SyncSender<&'static Slot>>> = Once::new();
+
+/// A stored code exposed as a momentary switch.
+struct Slot {
+    name: &'static str,
+    on_char: CharSlot,
+}
+
+impl Slot {
+    fn notify(&self, on: bool) {
+        if let Some(hc) = self.on_char.get() {
+            event_bus::publish(Event::Update(hc, Reading::Bool(on)));
+        }
+    }
+
+    fn write(&'static self, write: &Write) -> Result<(), Status> {
+        let on = write.value.as_bool().ok_or(Status::InvalidValue)?;
+        self.notify(on);
+
+        // Transmitting takes a few hundred ms, too long for the HAP task
+        let queued = on
+            && REQUESTS
+                .get()
+                .map_or(false, |requests| requests.lock().try_send(self).is_ok());
+        if on && !queued {
+            warn!(target: logging::IR, "IR busy, dropping {}", self.name);
+            self.notify(false);
+            return Err(Status::ResourceBusy);
+        }
+
+        Ok(())
+    }
+}
+
+fn ir_handler(requests: Receiver<&'static Slot>) {
+    for slot in requests {
+        match load(slot.name).and_then(|code| transmit(&code)) {
+            Ok(()) => info!(target: logging::IR, "Sent {}", slot.name),
+            Err(err) => warn!(target: logging::IR, "Sending {} failed: {:?}", slot.name, err),
+        }
+
+        thread::sleep(Duration::from_millis(config::IR_SWITCH_RESET_MS));
+        slot.notify(false);
+    }
+}
+
+/// Sets up the transmitter and receiver and the task sending switch presses.
+pub fn init() -> Result<()> {
+    init_rmt()?;
+    if config::PM_ENABLED {
+        let lock = pm::Lock::apb_max(b"ir\0")?;
+        PM_LOCK.call_once(|| lock);
+    }
+
+    let (sender, receiver) = mpsc::sync_channel(4);
+    REQUESTS.call_once(|| Mutex::new(sender));
+    tasks::spawn(&tasks::IR, move || ir_handler(receiver))?;
+
+    info!(
+        target: logging::IR,
+        "IR transmitter on GPIO{} ({} Hz), receiver on GPIO{}",
+        board::IR_TX_GPIO,
+        config::IR_CARRIER_HZ,
+        board::IR_RX_GPIO
+    );
+
+    Ok(())
+}
+
+/// Describes a Switch service per stored slot. Slots learned later show up
+/// after a restart, the accessory database is fixed once HAP started.
+pub fn services() -> Result<Vec<ServiceBuilder>> {
+    let mut services = Vec::new();
+
+    for name in slots()? {
+        let slot: &'static Slot = Box::leak(Box::new(Slot {
+            name: Box::leak(name.into_boxed_str()),
+            on_char: CharSlot::new(),
+        }));
+
+        services.push(
+            ServiceBuilder::switch()
+                .name(slot.name)
+                .bind(uuid::ON, &slot.on_char)
+                .on_write(Box::leak(Box::new(move |write: &Write| slot.write(write)))),
+        );
+    }
+    info!(target: logging::IR, "Exposing {} IR codes as switches", services.len());
+
+    Ok(services)
+}
+
+pub fn register_commands() {
+    console::register(
+        "ir",
+        "Manage IR codes ('ir list', 'ir learn <slot>', 'ir send <slot>', 'ir delete <slot>')",
+        |args| match args {
+            [] | ["list"] => {
+                for slot in slots()? {
+                    match load(&slot) {
+                        Ok(code) => println!("{:<16} {:?}", slot, code),
+                        Err(err) => println!("{:<16} {}", slot, err),
+                    }
+                }
+                Ok(())
+            }
+            ["learn", slot] => {
+                let code = learn(slot)?;
+                println!("Stored {:?}, restart to expose it in HomeKit", code);
+                Ok(())
+            }
+            ["send", slot] => transmit(&load(slot)?),
+            ["delete", slot] => delete(slot),
+            _ => bail!("usage: ir [list | learn <slot> | send <slot> | delete <slot>]"),
+        },
+    );
+}
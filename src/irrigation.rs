@@ -0,0 +1,470 @@
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use embedded_hal::digital::v2::OutputPin;
+use esp_idf_sys::esp;
+use hap_core::soil::{self, Automation, Calibration};
+use hap_core::sys::{perm, uuid};
+use hap_core::{Bounds, Char, CharSlot, ServiceBuilder, Status, Value, Write};
+use log::{info, warn};
+use spin::{Mutex, Once};
+
+use crate::board::{self, AnyOutputPin};
+use crate::hap_sys::HAP;
+use crate::{config, console, diag, logging, nvs, sim, system, tasks, wdt};
+
+// Apple's Valve and Humidity Sensor
+const VALVE_UUID: &[u8] = b"D0\0";
+const ACTIVE_UUID: &[u8] = b"B0\0";
+const IN_USE_UUID: &[u8] = b"D2\0";
+const VALVE_TYPE_UUID: &[u8] = b"D5\0";
+const SET_DURATION_UUID: &[u8] = b"D3\0";
+const REMAINING_UUID: &[u8] = b"D4\0";
+const HUMIDITY_SENSOR_UUID: &[u8] = b"82\0";
+const HUMIDITY_UUID: &[u8] = b"10\0";
+
+// Custom UUIDs, the SDK keeps the pointers so they have to be 'static
+const AUTO_UUID: &[u8] = b"0000D5A1-28E5-4C3F-9B6E-5A1D7E3C9000\0";
+const THRESHOLD_UUID: &[u8] = b"0000D5A2-28E5-4C3F-9B6E-5A1D7E3C9000\0";
+
+const VALVE_TYPE_IRRIGATION: u8 = 1;
+
+const READ_WRITE: u16 = perm::PR | perm::PW | perm::EV;
+const READ_ONLY: u16 = perm::PR | perm::EV;
+
+const NAMESPACE: &str = nvs::SOIL;
+
+struct State {
+    valve: Option<AnyOutputPin>,
+    calibration: Calibration,
+    automation: Automation,
+    duration: u32,
+    /// Uptime at which the open valve closes
+    closes_at: Option<u32>,
+    raw: u16,
+    moisture: f32,
+    fault: bool,
+}
+
+static STATE: Mutex<State> = Mutex::new(State {
+    valve: None,
+    calibration: Calibration::DEFAULT,
+    automation: Automation::new(config::IRRIGATION_LOCKOUT_HOURS * 60 * 60),
+    duration: config::VALVE_DEFAULT_DURATION_SECS,
+    closes_at: None,
+    raw: 0,
+    moisture: 0.0,
+    fault: false,
+});
+static STORE: Once<nvs::Namespace> = Once::new();
+
+static ACTIVE_CHAR: CharSlot = CharSlot::new();
+static IN_USE_CHAR: CharSlot = CharSlot::new();
+static SET_DURATION_CHAR: CharSlot = CharSlot::new();
+static REMAINING_CHAR: CharSlot = CharSlot::new();
+static AUTO_CHAR: CharSlot = CharSlot::new();
+static THRESHOLD_CHAR: CharSlot = CharSlot::new();
+static HUMIDITY_CHAR: CharSlot = CharSlot::new();
+static PROBE_FAULT_CHAR: CharSlot = CharSlot::new();
+
+type Changes = Vec<(&'static CharSlot, Value)>;
+
+fn store() -> Result<&'static nvs::Namespace> {
+    STORE.try_call_once(|| nvs::Namespace::open(NAMESPACE))
+}
+
+fn save(f: impl FnOnce(&nvs::Namespace) -> Result<()>) {
+    let result = store().and_then(|store| {
+        f(store)?;
+        Ok(store.commit()?)
+    });
+    if let Err(err) = result {
+        warn!(target: logging::IRRIGATION, "Storing the irrigation settings failed: {:?}", err);
+    }
+}
+
+// Unlocked, so a slow SDK never holds up the valve
+fn notify(changes: Changes) {
+    for (slot, value) in changes {
+        if let Some(hc) = slot.get() {
+            HAP.update(hc, &value);
+        }
+    }
+}
+
+impl State {
+    fn is_open(&self) -> bool {
+        self.closes_at.is_some()
+    }
+
+    fn remaining(&self, now: u32) -> u32 {
+        self.closes_at.map_or(0, |at| at.saturating_sub(now))
+    }
+
+    fn set_valve(&mut self, open: bool, reason: &str) -> Changes {
+        if let Some(valve) = self.valve.as_mut() {
+            let high = open == config::VALVE_ACTIVE_HIGH;
+            let result = if high {
+                valve.set_high()
+            } else {
+                valve.set_low()
+            };
+            if let Err(err) = result {
+                warn!(target: logging::IRRIGATION, "Driving the valve failed: {:?}", err);
+                return Vec::new();
+            }
+        }
+
+        let now = diag::uptime_secs();
+        if open {
+            // No duration would leave the valve open for good
+            let duration = match self.duration {
+                0 => config::VALVE_MAX_DURATION_SECS,
+                duration => duration,
+            };
+            self.closes_at = Some(now + duration);
+            self.automation.opened(now);
+        } else {
+            self.closes_at = None;
+        }
+        info!(
+            target: logging::IRRIGATION,
+            "Valve {} ({})",
+            if open { "opened" } else { "closed" },
+            reason
+        );
+
+        vec![
+            (&ACTIVE_CHAR, Value::Uint8(open as u8)),
+            (&IN_USE_CHAR, Value::Uint8(open as u8)),
+            (&REMAINING_CHAR, Value::Uint32(self.remaining(now))),
+        ]
+    }
+
+    fn disable_automation(&mut self) -> Changes {
+        if !self.automation.enabled {
+            return Vec::new();
+        }
+
+        self.automation.enabled = false;
+        save(|store| store.set_u8("auto", 0));
+        vec![(&AUTO_CHAR, Value::Bool(false))]
+    }
+
+    /// Takes a new probe reading and waters if it is due.
+    fn sample(&mut self, raw: u16) -> Changes {
+        let mut changes = Vec::new();
+        let fault = soil::at_rail(raw);
+        self.raw = raw;
+
+        if fault != self.fault {
+            self.fault = fault;
+            if fault {
+                warn!(
+                    target: logging::IRRIGATION,
+                    "Soil probe reads {}, disconnected? Automatic watering is off",
+                    raw
+                );
+                changes.extend(self.disable_automation());
+            } else {
+                info!(target: logging::IRRIGATION, "Soil probe back");
+            }
+            changes.push((&PROBE_FAULT_CHAR, Value::Uint8(fault as u8)));
+        }
+        if fault {
+            return changes;
+        }
+
+        let moisture = self.calibration.percent(raw).round();
+        if moisture != self.moisture {
+            self.moisture = moisture;
+            changes.push((&HUMIDITY_CHAR, Value::Float(moisture)));
+        }
+
+        let now = diag::uptime_secs();
+        if self.automation.due(now, moisture, self.is_open()) {
+            changes.extend(self.set_valve(true, "soil drier than the threshold"));
+        }
+
+        changes
+    }
+
+    fn tick(&mut self) -> Changes {
+        match self.closes_at {
+            Some(at) if diag::uptime_secs() >= at => self.set_valve(false, "duration elapsed"),
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// Controllers' writes always win: opening or closing the valve by hand ends
+/// whatever the automation started, and any opening restarts the lockout.
+fn on_write(write: &Write) -> Result<(), Status> {
+    let hc = Some(write.hc);
+    let mut state = STATE.lock();
+    let mut changes = Vec::new();
+
+    if hc == ACTIVE_CHAR.get() {
+        let open = write.value.as_u32().ok_or(Status::InvalidValue)? != 0;
+        changes = state.set_valve(open, "HomeKit");
+        if changes.is_empty() {
+            return Err(Status::CommunicationError);
+        }
+    } else if hc == SET_DURATION_CHAR.get() {
+        let duration = write.value.as_u32().ok_or(Status::InvalidValue)?;
+        state.duration = duration;
+        save(|store| store.set_u32("duration", duration));
+    } else if hc == AUTO_CHAR.get() {
+        let enabled = write.value.as_bool().ok_or(Status::InvalidValue)?;
+        if enabled && state.fault {
+            return Err(Status::CommunicationError);
+        }
+        state.automation.enabled = enabled;
+        save(|store| store.set_u8("auto", enabled as u8));
+    } else if hc == THRESHOLD_CHAR.get() {
+        let threshold = write.value.as_u32().ok_or(Status::InvalidValue)? as u8;
+        state.automation.threshold = threshold;
+        save(|store| store.set_u8("threshold", threshold));
+    } else {
+        return Err(Status::ResourceAbsent);
+    }
+    drop(state);
+
+    HAP.update(write.hc, &write.value);
+    notify(changes);
+
+    Ok(())
+}
+
+fn refresh(hc: Char) -> Result<(), Status> {
+    if Some(hc) == REMAINING_CHAR.get() {
+        let remaining = STATE.lock().remaining(diag::uptime_secs());
+        HAP.update(hc, &Value::Uint32(remaining));
+    }
+
+    Ok(())
+}
+
+fn read_raw() -> Result<u16> {
+    if config::SIMULATION {
+        return Ok(sim::soil_raw());
+    }
+
+    let mut sum = 0;
+    for _ in 0..config::SOIL_SAMPLES {
+        let raw = unsafe { esp_idf_sys::adc1_get_raw(board::SOIL_ADC_CHANNEL) };
+        if raw < 0 {
+            bail!("reading ADC1 channel {} failed", board::SOIL_ADC_CHANNEL);
+        }
+        sum += raw as u32;
+    }
+
+    Ok((sum / config::SOIL_SAMPLES) as u16)
+}
+
+fn irrigation_handler() {
+    let watchdog = wdt::subscribe(tasks::IRRIGATION.name);
+    let mut next_sample = 0;
+
+    loop {
+        let now = diag::uptime_secs();
+        let mut changes = STATE.lock().tick();
+        if now >= next_sample {
+            next_sample = now + config::SOIL_SAMPLE_SECS;
+            match read_raw() {
+                Ok(raw) => changes.extend(STATE.lock().sample(raw)),
+                Err(err) => warn!(target: logging::IRRIGATION, "{:?}", err),
+            }
+        }
+        notify(changes);
+
+        watchdog.sleep(Duration::from_secs(1));
+    }
+}
+
+fn load(state: &mut State) -> Result<()> {
+    let store = store()?;
+    if let (Some(dry), Some(wet)) = (store.get_u32("dry")?, store.get_u32("wet")?) {
+        state.calibration = Calibration {
+            dry: dry as u16,
+            wet: wet as u16,
+        };
+    }
+    if let Some(threshold) = store.get_u8("threshold")? {
+        state.automation.threshold = threshold;
+    }
+    if let Some(enabled) = store.get_u8("auto")? {
+        state.automation.enabled = enabled != 0;
+    }
+    if let Some(duration) = store.get_u32("duration")? {
+        state.duration = duration.min(config::VALVE_MAX_DURATION_SECS);
+    }
+
+    Ok(())
+}
+
+/// Sets up the probe and the closed valve and starts watching the soil.
+pub fn init() -> Result<()> {
+    esp!(unsafe {
+        esp_idf_sys::adc1_config_width(esp_idf_sys::adc_bits_width_t_ADC_WIDTH_BIT_12)
+    })?;
+    esp!(unsafe {
+        esp_idf_sys::adc1_config_channel_atten(
+            board::SOIL_ADC_CHANNEL,
+            esp_idf_sys::adc_atten_t_ADC_ATTEN_DB_11,
+        )
+    })?;
+
+    let mut state = STATE.lock();
+    if let Err(err) = load(&mut state) {
+        warn!(target: logging::IRRIGATION, "Loading the irrigation settings failed: {:?}", err);
+    }
+    state.valve = Some(AnyOutputPin::new(board::VALVE_GPIO)?);
+    state.set_valve(false, "boot");
+    // A restart counts as a watering, so a valve whose inrush resets the
+    // board cannot make the automation water in a loop
+    state.automation.opened(diag::uptime_secs());
+    info!(
+        target: logging::IRRIGATION,
+        "Soil probe on GPIO{}, valve on GPIO{}, automatic watering {} below {} %",
+        board::SOIL_GPIO,
+        board::VALVE_GPIO,
+        if state.automation.enabled { "on" } else { "off" },
+        state.automation.threshold
+    );
+    drop(state);
+
+    system::on_shutdown(|| {
+        STATE.lock().set_valve(false, "shutdown");
+    });
+    tasks::spawn(&tasks::IRRIGATION, irrigation_handler)
+}
+
+/// A Valve with the automation's settings, and a Humidity Sensor for the soil.
+pub fn services() -> Result<Vec<ServiceBuilder>> {
+    let state = STATE.lock();
+    if state.valve.is_none() {
+        bail!("the valve is not set up");
+    }
+    let now = diag::uptime_secs();
+    let open = state.is_open() as u8;
+    let percent = Bounds {
+        min: 0.0,
+        max: 100.0,
+        step: 1.0,
+    };
+
+    let valve = ServiceBuilder::custom(VALVE_UUID)
+        .name("Irrigation")
+        .char(ACTIVE_UUID, READ_WRITE, Value::Uint8(open))
+        .char(IN_USE_UUID, READ_ONLY, Value::Uint8(open))
+        .char(
+            VALVE_TYPE_UUID,
+            READ_ONLY,
+            Value::Uint8(VALVE_TYPE_IRRIGATION),
+        )
+        .char(SET_DURATION_UUID, READ_WRITE, Value::Uint32(state.duration))
+        .char(
+            REMAINING_UUID,
+            READ_ONLY,
+            Value::Uint32(state.remaining(now)),
+        )
+        .char(AUTO_UUID, READ_WRITE, Value::Bool(state.automation.enabled))
+        .char(
+            THRESHOLD_UUID,
+            READ_WRITE,
+            Value::Uint8(state.automation.threshold),
+        )
+        .valid_values(ACTIVE_UUID, &[0, 1])
+        .bounds(
+            SET_DURATION_UUID,
+            Bounds {
+                min: 0.0,
+                max: config::VALVE_MAX_DURATION_SECS as f64,
+                step: 1.0,
+            },
+        )
+        .bounds(THRESHOLD_UUID, percent)
+        .bind(ACTIVE_UUID, &ACTIVE_CHAR)
+        .bind(IN_USE_UUID, &IN_USE_CHAR)
+        .bind(SET_DURATION_UUID, &SET_DURATION_CHAR)
+        .bind(REMAINING_UUID, &REMAINING_CHAR)
+        .bind(AUTO_UUID, &AUTO_CHAR)
+        .bind(THRESHOLD_UUID, &THRESHOLD_CHAR)
+        .on_write(&on_write)
+        .on_read(&refresh);
+
+    let sensor = ServiceBuilder::custom(HUMIDITY_SENSOR_UUID)
+        .name("Soil Moisture")
+        .status_fault()
+        .char(HUMIDITY_UUID, READ_ONLY, Value::Float(state.moisture))
+        .bounds(HUMIDITY_UUID, percent)
+        .bind(HUMIDITY_UUID, &HUMIDITY_CHAR)
+        .bind(uuid::STATUS_FAULT, &PROBE_FAULT_CHAR);
+
+    Ok(vec![valve, sensor])
+}
+
+fn calibrate(point: &str) -> Result<()> {
+    let raw = read_raw()?;
+    if soil::at_rail(raw) {
+        bail!("the probe reads {}, check its wiring", raw);
+    }
+
+    let mut state = STATE.lock();
+    match point {
+        "dry" => state.calibration.dry = raw,
+        "wet" => state.calibration.wet = raw,
+        _ => bail!("calibrate 'dry' in air or 'wet' in water"),
+    }
+    save(|store| store.set_u32(point, raw as u32));
+    println!("Stored {} = {}", point, raw);
+    if state.calibration.dry == state.calibration.wet {
+        println!("Dry and wet read the same, calibrate the other point");
+    }
+
+    Ok(())
+}
+
+pub fn register_commands() {
+    console::register(
+        "soil",
+        "Show the soil probe and valve ('soil'), or calibrate the probe ('soil calibrate dry|wet')",
+        |args| match args {
+            [] => {
+                let state = STATE.lock();
+                let now = diag::uptime_secs();
+                println!(
+                    "Probe: raw {}, {} %{}",
+                    state.raw,
+                    state.moisture,
+                    if state.fault { " (fault)" } else { "" }
+                );
+                println!(
+                    "Calibration: dry {}, wet {}",
+                    state.calibration.dry, state.calibration.wet
+                );
+                match state.closes_at {
+                    Some(_) => println!("Valve open, {} s left", state.remaining(now)),
+                    None => println!("Valve closed"),
+                }
+                print!(
+                    "Automatic watering {} below {} %",
+                    if state.automation.enabled {
+                        "on"
+                    } else {
+                        "off"
+                    },
+                    state.automation.threshold
+                );
+                match state.automation.locked_until(now) {
+                    Some(until) => println!(", locked out for {} min", (until - now) / 60),
+                    None => println!(),
+                }
+                Ok(())
+            }
+            ["calibrate", point] => calibrate(point),
+            _ => bail!("usage: soil [calibrate dry|wet]"),
+        },
+    );
+}
@@ -0,0 +1,271 @@
+use std::ffi::CString;
+
+use anyhow::{anyhow, bail, Result};
+use esp_idf_svc::log::EspLogger;
+use log::{error, info, warn, LevelFilter};
+use spin::{Mutex, Once};
+
+use crate::{console, nvs};
+
+// Log targets of our own modules, used as `info!(target: logging::WIFI, ...)`
+pub const WIFI: &str = "app::wifi";
+pub const HAP: &str = "app::hap";
+pub const OUTLET: &str = "app::outlet";
+pub const DIAG: &str = "app::diag";
+pub const HTTP: &str = "app::http";
+pub const BUTTON: &str = "app::button";
+pub const IR: &str = "app::ir";
+pub const ENERGY: &str = "app::energy";
+pub const POWER: &str = "app::power";
+pub const IRRIGATION: &str = "app::irrigation";
+pub const DISTANCE: &str = "app::distance";
+pub const DOOR: &str = "app::door";
+pub const BUZZER: &str = "app::buzzer";
+pub const RFID: &str = "app::rfid";
+pub const TEMP: &str = "app::temp";
+
+struct Tag {
+    name: &'static str,
+    target: &'static str,
+    /// IDF components that follow the level of this tag
+    idf_tags: &'static [&'static str],
+}
+
+const TAGS: &[Tag] = &[
+    Tag {
+        name: "wifi",
+        target: WIFI,
+        idf_tags: &["wifi", "wifi_init", "esp_netif_handlers", "phy_init"],
+    },
+    Tag {
+        name: "hap",
+        target: HAP,
+        idf_tags: &["HAP", "mdns"],
+    },
+    Tag {
+        name: "outlet",
+        target: OUTLET,
+        idf_tags: &["gpio"],
+    },
+    Tag {
+        name: "diag",
+        target: DIAG,
+        idf_tags: &[],
+    },
+    Tag {
+        name: "http",
+        target: HTTP,
+        idf_tags: &["httpd", "httpd_txrx", "httpd_uri", "httpd_parse"],
+    },
+    Tag {
+        name: "button",
+        target: BUTTON,
+        idf_tags: &[],
+    },
+    Tag {
+        name: "ir",
+        target: IR,
+        idf_tags: &["rmt"],
+    },
+    Tag {
+        name: "energy",
+        target: ENERGY,
+        idf_tags: &["uart"],
+    },
+    Tag {
+        name: "power",
+        target: POWER,
+        idf_tags: &["sleep"],
+    },
+    Tag {
+        name: "irrigation",
+        target: IRRIGATION,
+        idf_tags: &["adc"],
+    },
+    Tag {
+        name: "distance",
+        target: DISTANCE,
+        idf_tags: &[],
+    },
+    Tag {
+        name: "door",
+        target: DOOR,
+        idf_tags: &["ledc", "pcnt"],
+    },
+    Tag {
+        name: "buzzer",
+        target: BUZZER,
+        idf_tags: &[],
+    },
+    Tag {
+        name: "rfid",
+        target: RFID,
+        idf_tags: &["i2c", "spi_master"],
+    },
+    Tag {
+        name: "temp",
+        target: TEMP,
+        idf_tags: &[],
+    },
+];
+
+const APP_DEFAULT: LevelFilter = LevelFilter::Info;
+const IDF_DEFAULT: LevelFilter = LevelFilter::Warn;
+
+const NAMESPACE: &str = nvs::LOG;
+
+static LOGGER: EspLogger = EspLogger;
+static STORE: Once<nvs::Namespace> = Once::new();
+static LEVELS: Mutex<Vec<(&'static str, LevelFilter)>> = Mutex::new(Vec::new());
+
+fn parse_level(level: &str) -> Option<LevelFilter> {
+    match level {
+        "off" | "none" => Some(LevelFilter::Off),
+        "error" => Some(LevelFilter::Error),
+        "warn" => Some(LevelFilter::Warn),
+        "info" => Some(LevelFilter::Info),
+        "debug" => Some(LevelFilter::Debug),
+        "verbose" | "trace" => Some(LevelFilter::Trace),
+        _ => None,
+    }
+}
+
+fn level_from_u8(level: u8) -> Option<LevelFilter> {
+    [
+        LevelFilter::Off,
+        LevelFilter::Error,
+        LevelFilter::Warn,
+        LevelFilter::Info,
+        LevelFilter::Debug,
+        LevelFilter::Trace,
+    ]
+    .get(level as usize)
+    .copied()
+}
+
+fn idf_level(level: LevelFilter) -> esp_idf_sys::esp_log_level_t {
+    match level {
+        LevelFilter::Off => esp_idf_sys::esp_log_level_t_ESP_LOG_NONE,
+        LevelFilter::Error => esp_idf_sys::esp_log_level_t_ESP_LOG_ERROR,
+        LevelFilter::Warn => esp_idf_sys::esp_log_level_t_ESP_LOG_WARN,
+        LevelFilter::Info => esp_idf_sys::esp_log_level_t_ESP_LOG_INFO,
+        LevelFilter::Debug => esp_idf_sys::esp_log_level_t_ESP_LOG_DEBUG,
+        LevelFilter::Trace => esp_idf_sys::esp_log_level_t_ESP_LOG_VERBOSE,
+    }
+}
+
+fn set_idf_level(tag: &str, level: LevelFilter) {
+    if let Ok(tag) = CString::new(tag) {
+        unsafe { esp_idf_sys::esp_log_level_set(tag.as_ptr(), idf_level(level)) };
+    }
+}
+
+fn apply(tag: &Tag, level: LevelFilter) {
+    // esp_log_write filters per tag, so our targets go through the same table
+    set_idf_level(tag.target, level);
+
+    // The IDF components never get chattier than warn unless asked to
+    let idf = if level == APP_DEFAULT {
+        IDF_DEFAULT
+    } else {
+        level
+    };
+    for idf_tag in tag.idf_tags {
+        set_idf_level(idf_tag, idf);
+    }
+
+    let mut levels = LEVELS.lock();
+    match levels.iter_mut().find(|(name, _)| *name == tag.name) {
+        Some(entry) => entry.1 = level,
+        None => levels.push((tag.name, level)),
+    }
+}
+
+pub trait LogErr {
+    /// Logs the error, if any, under `target` before it is propagated.
+    fn log_err(self, target: &str, context: &str) -> Self;
+}
+
+impl<T> LogErr for Result<T> {
+    fn log_err(self, target: &str, context: &str) -> Self {
+        if let Err(err) = &self {
+            error!(target: target, "{}: {:?}", context, err);
+        }
+
+        self
+    }
+}
+
+/// Installs the logger with the default levels, before anything else logs.
+pub fn init() -> Result<()> {
+    log::set_logger(&LOGGER).map_err(|err| anyhow!("failed to install logger: {}", err))?;
+    log::set_max_level(LevelFilter::Trace);
+
+    for tag in TAGS {
+        apply(tag, APP_DEFAULT);
+    }
+
+    Ok(())
+}
+
+/// Applies the levels persisted with `log set`, once NVS is available.
+pub fn load_levels() {
+    let store = STORE.try_call_once(|| nvs::Namespace::open(NAMESPACE));
+    if let Err(err) = &store {
+        warn!(target: DIAG, "Persisted log levels unavailable: {}", err);
+    }
+
+    for tag in TAGS {
+        let stored = store
+            .as_ref()
+            .ok()
+            .and_then(|store| store.get_u8(tag.name).ok().flatten())
+            .and_then(level_from_u8);
+
+        if let Some(level) = stored {
+            apply(tag, level);
+        }
+    }
+}
+
+fn set(name: &str, level: LevelFilter) -> Result<()> {
+    let tag = TAGS
+        .iter()
+        .find(|tag| tag.name == name)
+        .ok_or_else(|| anyhow!("unknown tag '{}'", name))?;
+
+    apply(tag, level);
+
+    if let Some(store) = STORE.get() {
+        store.set_u8(tag.name, level as u8)?;
+        store.commit()?;
+    }
+    info!(target: DIAG, "Log level of {} set to {}", tag.name, level);
+
+    Ok(())
+}
+
+pub fn register_commands() {
+    console::register(
+        "log",
+        "List ('log') or change ('log set <tag> <level>') log levels",
+        |args| match args {
+            [] => {
+                for (name, level) in LEVELS.lock().iter() {
+                    println!("{:<8} {}", name, level);
+                }
+                Ok(())
+            }
+            ["set", name, level] => {
+                let level = parse_level(level).ok_or_else(|| {
+                    anyhow!(
+                        "unknown level '{}', use off/error/warn/info/debug/verbose",
+                        level
+                    )
+                })?;
+                set(name, level)
+            }
+            _ => bail!("usage: log [set <tag> <level>]"),
+        },
+    );
+}
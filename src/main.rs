@@ -1,187 +1,609 @@
 use std::env;
-use std::ffi::CString;
-use std::sync::Arc;
-use anyhow::{bail, Result};
-use esp_homekit_sdk_sys::{accessory, hap, service, task};
-use esp_idf_hal::peripherals::Peripherals;
-use esp_idf_svc::httpd::Configuration;
+use std::ffi::{c_void, CString};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
 use esp_idf_svc::netif::EspNetifStack;
 use esp_idf_svc::nvs::EspDefaultNvs;
-use esp_idf_svc::ping::EspPing;
 use esp_idf_svc::sysloop::EspSysLoopStack;
-use esp_idf_svc::wifi::EspWifi;
-
 use esp_idf_sys as _;
-use spin::Mutex;
-
-const SSID: &str = "ssid";
-const PASS: &str = "password";
-
-const SMART_OUTLET_TASK_NAME: &str = "hap_outlet";
-const SMART_OUTLET_TASK_STACKSIZE: u32 = 40000;
-const SMART_OUTLET_TASK_PRIORITY: UBaseType_t = 1;
-
-static WIFI: Mutex<Option<Box<EspWifi>>> = Mutex::new(None);
-static GPIO: CriticalSectionSpinLockMutex<
-    Option<esp_idf_hal::gpio::Gpio8<esp_idf_hal::gpio::Output>>,
-> = CriticalSectionSpinLockMutex::new(None);
+use hap_core::audit::Origin;
+use hap_core::sys::{AccessoryInfo, HAP_SUCCESS};
+use hap_core::write::Incident;
+use hap_core::{AccessoryBuilder, HapSys, Status};
+use log::{error, info, warn};
+use logging::LogErr;
+use once_cell::sync::OnceCell;
+
+use app::{Accessory, AppContext, Failure};
+use board::AnyOutputPin;
+use factory_config::Setup;
+use hap_sys::HAP;
+use outlet::Outlet;
+use relay::{GpioRelay, RelayBackend, UartRelay};
+
+mod app;
+mod app_state;
+mod audit;
+mod board;
+mod button;
+mod buzzer;
+mod child_lock;
+mod clock;
+mod config;
+mod console;
+mod coredump;
+mod diag;
+mod diag_service;
+mod distance;
+mod door;
+mod encoder;
+mod energy_meter;
+mod energy_stats;
+mod event_bus;
+mod factory_config;
+mod fault;
+mod guardrail;
+mod hap_events;
+mod hap_sys;
+mod http;
+mod iids;
+mod interlock;
+mod ir;
+mod irrigation;
+mod logging;
+mod maintenance;
+mod mdns;
+mod metrics;
+mod modbus;
+mod nvs;
+mod onewire;
+mod outlet;
+mod pairing_window;
+mod pm;
+mod pn532;
+mod provisioning;
+mod relay;
+mod restore;
+mod rfid;
+mod scene_switch;
+mod schedule;
+mod selftest;
+mod sessions;
+mod sim;
+mod sleep;
+mod status_led;
+mod switches;
+mod system;
+mod tasks;
+mod temperature;
+mod touch;
+mod trace;
+mod wdt;
+mod wifi;
+
+static APP: OnceCell<AppContext> = OnceCell::new();
 
 fn main() -> Result<()> {
     esp_idf_sys::link_patches();
-
-    let wifi = wifi()?;
-    {
-        let lock = WIFI.lock();
-        *lock = Some(wifi);
+    logging::init()?;
+    info!(target: logging::DIAG, "Booting, reset reason {}", diag::reset_reason());
+
+    status_led::init();
+    wdt::init();
+    nvs::init().log_err(logging::DIAG, "NVS initialization failed")?;
+    app_state::migrate_at_boot();
+    nvs::check_at_boot();
+    logging::load_levels();
+    fault::init().log_err(logging::DIAG, "Loading the last fault failed")?;
+    if let Err(err) = restore::init() {
+        warn!(target: logging::DIAG, "Power-failure restore unavailable: {:?}", err);
+    }
+    coredump::check_at_boot();
+    if let Err(err) = factory_config::load() {
+        fail(err.context(Failure::FactoryData), tasks::MAIN.name);
     }
 
-    task::Task::create(
-        smart_outlet_handler,
-        SMART_OUTLET_TASK_NAME,
-        SMART_OUTLET_TASK_STACKSIZE,
-        SMART_OUTLET_TASK_PRIORITY,
-    );
-
-    Ok(())
-}
-
-fn smart_outlet_handler(cv: *mut esp_homekit_sdk_sys::c_types::c_void) {
-    env::set_var("RUST_BACKTRACE", "1");
+    if let Err(err) = event_bus::init() {
+        warn!(target: logging::DIAG, "Event bus unavailable: {:?}", err);
+    }
 
-    let peripherals = Peripherals::take().unwrap();
-    let pins = peripherals.pins;
-    let mut switch = pins.gpio5.into_output().unwrap(); // Blue
-    switch.set_low();
-
-    use esp32_hal::gpio::Mutex;
-    (&GPIO).lock(|val| *val = Some(switch));
-
-    let hap_config = hap::Config {
-        name: CString::new("Smart-Outlet").unwrap(),
-        model: CString::new("Esp32").unwrap(),
-        manufacturer: CString::new("Espressif").unwrap(),
-        serial_num: CString::new("111122334455").unwrap(),
-        fw_rev: CString::new("1.0.0").unwrap(),
-        hw_rev: CString::new("0.1.0").unwrap(),
-        pv: CString::new("1.1.0").unwrap(),
-        cid: accessory::Category::OUTLET,
+    // Before Wi-Fi, so a device stuck in a bad configuration can still be reset
+    button::subscribe(|_, event| {
+        if let button::Event::LongPress(held) = event {
+            let held = held.as_millis() as u64;
+            if held >= config::FACTORY_RESET_HOLD_MS {
+                system::factory_reset();
+            } else if held >= config::WIFI_SETUP_HOLD_MS {
+                provisioning::request();
+            }
+        }
+    });
+    let buttons: &'static [button::ButtonConfig] = if config::ENCODER_ENABLED {
+        &[button::BOOT, encoder::SWITCH]
+    } else {
+        &[button::BOOT]
     };
+    if let Err(err) = button::init(buttons) {
+        warn!(target: logging::BUTTON, "Buttons unavailable: {:?}", err);
+    }
+    if config::TOUCH_ENABLED {
+        if let Err(err) = touch::init(&[touch::PAD]) {
+            warn!(target: logging::BUTTON, "Touch pad unavailable: {:?}", err);
+        }
+    }
+    if config::ENCODER_ENABLED {
+        if let Err(err) = encoder::init() {
+            warn!(target: logging::BUTTON, "Rotary encoder unavailable: {:?}", err);
+        }
+    }
 
-    hap::init();
-
-    let mut accessory = accessory::create(&hap_config);
-    let mut service = service::create();
-
-    service::add_name(service, "My Smart Outlet");
-
-    let outlet_in_use = service::get_service_by_uuid(service);
+    let netif = Arc::new(EspNetifStack::new()?);
+    let sysloop = Arc::new(EspSysLoopStack::new()?);
+    let default_nvs = Arc::new(EspDefaultNvs::new()?);
 
-    service::set_write_cb(service, Some(outlet_write));
+    let wifi = wifi::create(netif.clone(), sysloop.clone(), default_nvs.clone())
+        .unwrap_or_else(|err| fail(err.context(Failure::Wifi), tasks::MAIN.name));
 
-    hap::add_service_to_accessory(accessory, service);
+    let app = APP.get_or_init(|| AppContext {
+        netif,
+        sysloop,
+        nvs: default_nvs,
+        wifi: Mutex::new(wifi),
+        accessory: OnceCell::new(),
+    });
 
-    hap::add_accessory(accessory);
+    if let Err(err) = pm::init() {
+        warn!(target: logging::POWER, "Power management unavailable: {:?}", err);
+    }
+    clock::init().log_err(logging::DIAG, "SNTP initialization failed")?;
+    http::start().log_err(logging::HTTP, "Starting the HTTP server failed")?;
+    // Alongside the rest, a network gone missing must not hold up the local
+    // controls and HAP; HAP starts once the station has an address. After
+    // the API server, whose port the setup portal takes over
+    tasks::spawn(&tasks::WIFI, move || wifi::bring_up(&app.wifi))?;
+
+    diag::register_metrics();
+    sessions::register_metrics();
+    wifi::register_metrics();
+    nvs::register_metrics();
+    app_state::register_metrics();
+    selftest::register_metrics();
+    diag::register_commands();
+    sessions::register_commands();
+    trace::register_commands();
+    nvs::register_commands();
+    mdns::register_commands();
+    schedule::register_commands();
+    scene_switch::register_commands();
+    fault::register_commands();
+    coredump::register_commands();
+    logging::register_commands();
+    pm::register_commands();
+    selftest::register_commands();
+    provisioning::register_commands();
+    if config::SIMULATION {
+        sim::register_commands();
+    }
+    audit::register_commands();
+    child_lock::init();
+    child_lock::register_commands();
+    guardrail::init();
+    guardrail::register_metrics();
+    guardrail::register_commands();
+    if config::PAIRING_WINDOW_ENABLED {
+        pairing_window::register_commands();
+    }
+    if config::METER_ENABLED {
+        energy_stats::init();
+        energy_stats::register_metrics();
+        energy_stats::register_commands();
+        match energy_meter::init() {
+            Ok(()) => energy_meter::register_metrics(),
+            Err(err) => warn!(target: logging::ENERGY, "Energy meter unavailable: {:?}", err),
+        }
+    }
+    if config::IR_ENABLED {
+        match ir::init() {
+            Ok(()) => ir::register_commands(),
+            Err(err) => warn!(target: logging::IR, "IR unavailable: {:?}", err),
+        }
+    }
+    if config::DISTANCE_ENABLED {
+        match distance::init() {
+            Ok(()) => distance::register_commands(),
+            Err(err) => warn!(target: logging::DISTANCE, "Distance sensor unavailable: {:?}", err),
+        }
+    }
+    if config::BUZZER_ENABLED {
+        match buzzer::init() {
+            Ok(()) => buzzer::register_commands(),
+            Err(err) => warn!(target: logging::BUZZER, "Buzzer unavailable: {:?}", err),
+        }
+    }
+    if config::RFID_ENABLED {
+        match rfid::init() {
+            Ok(()) => {
+                rfid::register_metrics();
+                rfid::register_commands();
+            }
+            Err(err) => warn!(target: logging::RFID, "RFID reader unavailable: {:?}", err),
+        }
+    }
+    if config::DOOR_ENABLED {
+        match door::init() {
+            Ok(()) => door::register_commands(),
+            Err(err) => warn!(target: logging::DOOR, "Door unavailable: {:?}", err),
+        }
+    }
+    if config::IRRIGATION_ENABLED {
+        match irrigation::init() {
+            Ok(()) => irrigation::register_commands(),
+            Err(err) => warn!(target: logging::IRRIGATION, "Irrigation unavailable: {:?}", err),
+        }
+    }
+    if config::TEMP_ENABLED {
+        match temperature::init() {
+            Ok(()) => {
+                temperature::register_metrics();
+                temperature::register_commands();
+            }
+            Err(err) => warn!(target: logging::TEMP, "Temperature probes unavailable: {:?}", err),
+        }
+    }
+    tasks::spawn(&tasks::HEAP_MONITOR, diag::heap_monitor)?;
+    tasks::spawn(&tasks::CONSOLE, console::console_handler)?;
 
-    let setup_code = CString::new("111-22-333").unwrap();
-    let setup_id = CString::new("ES32").unwrap();
+    tasks::spawn(&tasks::SMART_OUTLET, move || smart_outlet_handler(app))?;
+    if config::SLEEP_ENABLED {
+        sleep::init().log_err(logging::POWER, "Starting the sleep manager failed")?;
+    }
 
-    hap::secret(setup_code, setup_id);
+    Ok(())
+}
 
-    hap::start();
+fn smart_outlet_handler(app: &'static AppContext) {
+    env::set_var("RUST_BACKTRACE", "1");
+    info!(
+        target: logging::HAP,
+        "Outlet task started on core {}",
+        unsafe { esp_idf_sys::xPortGetCoreID() }
+    );
 
-    loop {}
+    if let Err(err) = run_hap(app) {
+        fail(err, tasks::SMART_OUTLET.name);
+    }
+    pm::log_ready();
+    pm::log_latency();
+
+    let watchdog = wdt::subscribe(tasks::SMART_OUTLET.name);
+    watchdog.sleep(Duration::from_secs(config::STACK_REPORT_DELAY_SECS));
+    loop {
+        diag::log_task_stacks();
+        watchdog.sleep(Duration::from_secs(config::STACK_REPORT_INTERVAL_SECS));
+    }
 }
 
-unsafe extern "C" fn outlet_write(
-    write_data: *mut esp_homekit_sdk_sys::hap_write_data_t,
-    count: i32,
-    serv_priv: *mut esp_homekit_sdk_sys::c_types::c_void,
-    write_priv: *mut esp_homekit_sdk_sys::c_types::c_void,
-) -> i32 {
-    use esp32_hal::gpio::Mutex;
-
-    let mut gpio = &GPIO;
-
-    if (*write_data).val.b == true {
-        gpio.lock(|gpio| {
-            let gpio = gpio.as_mut().unwrap();
-            gpio.set_high();
-        })
+fn run_hap(app: &'static AppContext) -> Result<()> {
+    let factory = factory_config::get();
+    match &factory.setup {
+        Setup::Code(code) => hap_core::setup::validate(code, &factory.setup_id),
+        Setup::Verifier { .. } => hap_core::setup::validate_id(&factory.setup_id),
+    }
+    .context(Failure::Config)?;
+
+    let relay: &'static dyn RelayBackend = if config::SIMULATION {
+        sim::relay()
+    } else if config::RELAY_UART_ENABLED {
+        Box::leak(Box::new(
+            UartRelay::new(
+                board::RELAY_UART_PORT,
+                board::RELAY_UART_TX_GPIO,
+                config::RELAY_UART_BAUD,
+                config::RELAY_UART_CHANNELS,
+            )
+            .context(Failure::Gpio)?,
+        ))
     } else {
-        gpio.lock(|gpio| {
-            let gpio = gpio.as_mut().unwrap();
-            gpio.set_low();
-        })
+        let off = !config::RELAY_ACTIVE_HIGH;
+        let relay = AnyOutputPin::with_level(board::RELAY_GPIO, off).context(Failure::Gpio)?;
+        relay::log_gpio(board::RELAY_GPIO);
+        let reset: Option<relay::Pin> = if config::RELAY_MODE == config::RelayMode::DualCoil {
+            let reset =
+                AnyOutputPin::with_level(board::RELAY_RESET_GPIO, off).context(Failure::Gpio)?;
+            relay::log_gpio(board::RELAY_RESET_GPIO);
+            Some(Box::new(reset))
+        } else {
+            None
+        };
+        // The one channel the boards wire up to GPIOs
+        let channel = relay::gpio_channel(relay::mode(), Box::new(relay), reset)
+            .context(Failure::Config)?;
+        relay::sense_at_boot(&channel);
+        Box::leak(Box::new(GpioRelay::new(vec![channel])))
+    };
+    // Every writer goes through the rules, HomeKit, local control and restore
+    let channels = switches::channels(relay).context(Failure::Config)?;
+    let exposed = channels
+        .iter()
+        .fold(1 << config::RELAY_CHANNEL, |mask, channel| mask | 1 << channel);
+    let relay = interlock::wrap(relay, exposed).context(Failure::Config)?;
+    let outlet = Outlet::new(relay, config::RELAY_CHANNEL);
+    switches::init(relay, &channels);
+    interlock::subscribe(move |changed| {
+        if changed & 1 << config::RELAY_CHANNEL != 0 {
+            outlet.poll();
+        }
+    });
+    // Its shutdown hook after the outlet's, so the safe state is stored too
+    audit::init();
+    selftest::run(relay, config::RELAY_CHANNEL);
+    selftest::attach(relay, outlet);
+    // The boot button's gestures, the rest toggles
+    scene_switch::init(outlet);
+    button::subscribe(move |name, event| {
+        if name == button::BOOT.name && event == button::Event::TripleClick {
+            child_lock::toggle(Origin::Local);
+            return;
+        }
+        let toggle = match (name, event) {
+            (name, button::Event::Click) if name == touch::PAD.name => true,
+            (name, button::Event::Pressed) if name == encoder::SWITCH.name => true,
+            _ => false,
+        };
+        if toggle {
+            let on = outlet.with(|outlet| {
+                outlet.toggle(Origin::Local);
+                outlet.is_on()
+            });
+            info!(target: logging::OUTLET, "Toggled locally, now {}", on);
+        }
+    });
+    // A binary accessory has no level to adjust: turning right switches on
+    encoder::subscribe(move |delta| {
+        let switched = outlet.with(|outlet| {
+            let switch = (delta > 0) != outlet.is_on();
+            if switch {
+                outlet.set(delta > 0, Origin::Local);
+            }
+            switch.then(|| outlet.is_on())
+        });
+        if let Some(on) = switched {
+            info!(target: logging::OUTLET, "Switched locally, now {}", on);
+        }
+    });
+
+    let name = CString::new("Smart-Outlet")?;
+    let model = CString::new(factory.model.as_str())?;
+    let manufacturer = CString::new("Espressif")?;
+    let serial_num = CString::new(factory.serial.as_str())?;
+    let fw_rev = CString::new("1.0.0")?;
+    let hw_rev = CString::new(factory.hw_rev.as_str())?;
+    let pv = CString::new("1.1.0")?;
+    let hap_config = AccessoryInfo {
+        name: &name,
+        model: &model,
+        manufacturer: &manufacturer,
+        serial_num: &serial_num,
+        fw_rev: &fw_rev,
+        hw_rev: &hw_rev,
+        pv: &pv,
+        cid: esp_homekit_sdk_sys::hap_cid_t_HAP_CID_OUTLET,
+        identify,
+    };
+
+    let err = HAP.sys().init();
+    if err != HAP_SUCCESS {
+        return Err(anyhow!("hap_init returned {}", err).context(Failure::HapInit));
+    }
+    info!(target: logging::HAP, "HAP initialized, building accessory database");
+    hap_events::register();
+    sessions::init();
+    trace::init();
+
+    // A write racing a restart would switch relays already in their safe state
+    hap_core::write::add_gate(|_| {
+        if system::is_shutting_down() {
+            return Err(Status::ResourceBusy);
+        }
+        Ok(())
+    });
+    hap_core::write::add_monitor(|hc, incident| match incident {
+        Incident::Panicked(message) => error!(
+            target: logging::HAP,
+            "Write handler of {:?} panicked: {}", hc, message
+        ),
+        Incident::Slow(elapsed) => warn!(
+            target: logging::HAP,
+            "Write handler of {:?} took {} ms", hc, elapsed.as_millis()
+        ),
+    });
+
+    maintenance::init();
+    schedule::init();
+    let mut accessory = AccessoryBuilder::new(hap_config)
+        .service(diag_service::service())
+        .service(maintenance::service())
+        .service(schedule::service());
+    for service in switches::services() {
+        accessory = accessory.service(service);
+    }
+    if config::IR_ENABLED {
+        match ir::services() {
+            Ok(services) => {
+                for service in services {
+                    accessory = accessory.service(service);
+                }
+            }
+            Err(err) => warn!(target: logging::IR, "IR switches unavailable: {:?}", err),
+        }
+    }
+    if config::DISTANCE_ENABLED {
+        match distance::services() {
+            Ok(services) => {
+                for service in services {
+                    accessory = accessory.service(service);
+                }
+            }
+            Err(err) => {
+                warn!(target: logging::DISTANCE, "Distance services unavailable: {:?}", err)
+            }
+        }
+    }
+    if config::BUZZER_ENABLED {
+        match buzzer::service() {
+            Ok(service) => accessory = accessory.service(service),
+            Err(err) => warn!(target: logging::BUZZER, "Buzzer service unavailable: {:?}", err),
+        }
+    }
+    if config::RFID_ENABLED {
+        match rfid::service() {
+            Ok(service) => accessory = accessory.service(service),
+            Err(err) => warn!(target: logging::RFID, "RFID service unavailable: {:?}", err),
+        }
+    }
+    if config::DOOR_ENABLED {
+        match door::service() {
+            Ok(service) => accessory = accessory.service(service),
+            Err(err) => warn!(target: logging::DOOR, "Door service unavailable: {:?}", err),
+        }
+    }
+    if config::SCENE_SWITCH_ENABLED {
+        accessory = accessory.service(scene_switch::service());
+    }
+    if config::IRRIGATION_ENABLED {
+        match irrigation::services() {
+            Ok(services) => {
+                for service in services {
+                    accessory = accessory.service(service);
+                }
+            }
+            Err(err) => {
+                warn!(target: logging::IRRIGATION, "Irrigation services unavailable: {:?}", err)
+            }
+        }
+    }
+    if config::TEMP_ENABLED {
+        match temperature::services() {
+            Ok(services) => {
+                for service in services {
+                    accessory = accessory.service(service);
+                }
+            }
+            Err(err) => warn!(target: logging::TEMP, "Temperature services unavailable: {:?}", err),
+        }
     }
 
-    hap::HAP_SUCCESS_
-}
+    // The outlet's service comes first and is the primary one
+    let mut iid_map = iids::load();
+    let registered = outlet
+        .register(accessory.bridged(false).iids(&mut iid_map))
+        .context(Failure::HapInit)?;
+    if let Err(err) = iids::store(&iid_map) {
+        warn!(target: logging::HAP, "Storing the iids failed: {:?}", err);
+    }
+    if let Err(err) = diag_service::start_link_monitor() {
+        warn!(target: logging::WIFI, "Wi-Fi link monitor unavailable: {:?}", err);
+    }
+    if let Err(err) = schedule::start(outlet) {
+        warn!(target: logging::OUTLET, "Schedule unavailable: {:?}", err);
+    }
+    if let Err(err) = outlet::start_polling(outlet) {
+        warn!(target: logging::OUTLET, "Outlet polling unavailable: {:?}", err);
+    }
+    let _ = app.accessory.set(Accessory {
+        accessory: registered.acc,
+        outlet_service: registered.services[0].serv,
+        diag_service: registered.services[1].serv,
+        outlet,
+    });
+
+    let setup_id = CString::new(factory.setup_id.as_str())?;
+    match &factory.setup {
+        Setup::Code(code) => HAP
+            .sys()
+            .set_setup(&CString::new(code.as_str())?, &setup_id),
+        Setup::Verifier { salt, verifier } => HAP.sys().set_setup_info(salt, verifier, &setup_id),
+    }
 
-fn wifi() -> Result<Box<EspWifi>> {
-    let mut wifi = Box::new(EspWifi::new(
-        Arc::new(EspNetifStack::new()?),
-        Arc::new(EspSysLoopStack::new()?),
-        Arc::new(EspDefaultNvs::new()?),
-    )?);
+    // Built and locally controllable meanwhile; mDNS needs the network
+    info!(target: logging::HAP, "Accessory built, HAP starts once Wi-Fi is up");
+    wifi::wait_for_ip();
+
+    // The first start can fail transiently while mDNS is still coming up
+    let mut attempt = 1;
+    loop {
+        let err = HAP.start();
+        if err == HAP_SUCCESS {
+            status_led::event(status_led::Event::ErrorCleared);
+            break;
+        }
 
-    info!("Wifi created, about to scan");
+        if attempt == config::HAP_START_ATTEMPTS {
+            return Err(
+                anyhow!("hap_start returned {} after {} attempts", err, attempt)
+                    .context(Failure::HapStart),
+            );
+        }
+        warn!(
+            target: logging::HAP,
+            "hap_start returned {} (attempt {}/{}), retrying in {} s",
+            err,
+            attempt,
+            config::HAP_START_ATTEMPTS,
+            config::HAP_START_RETRY_SECS
+        );
+        status_led::event(status_led::Event::Error(Failure::HapStart.blink_count()));
+        thread::sleep(Duration::from_secs(config::HAP_START_RETRY_SECS));
+        attempt += 1;
+    }
+    info!(
+        target: logging::HAP,
+        "HAP started, setup id {}",
+        factory.setup_id
+    );
+    if config::PAIRING_WINDOW_ENABLED {
+        pairing_window::start();
+    }
+    mdns::check_at_start(name.to_str()?);
+    if config::SMOKE_TEST_AT_BOOT {
+        selftest::smoke_at_boot();
+    }
 
-    let ap_infos = wifi.scan()?;
+    Ok(())
+}
 
-    let ours = ap_infos.into_iter().find(|a| a.ssid == SSID);
+unsafe extern "C" fn identify(_: *mut c_void) -> i32 {
+    info!(target: logging::HAP, "Identify requested");
+    event_bus::publish(event_bus::Event::Led(status_led::Event::Identify));
 
-    let channel = if let Some(ours) = ours {
-        info!(
-            "Found configured access point {} on channel {}",
-            SSID, ours.channel
-        );
-        Some(ours.channel)
-    } else {
-        info!(
-            "Configured access point {} not found during scanning, will go with unknown channel",
-            SSID
-        );
-        None
-    };
+    HAP_SUCCESS
+}
 
-    wifi.set_configuration(&Configuration::Mixed(
-        ClientConfiguration {
-            ssid: SSID.into(),
-            password: PASS.into(),
-            channel,
-            ..Default::default()
-        },
-        AccessPointConfiguration {
-            ssid: "aptest".into(),
-            channel: channel.unwrap_or(1),
-            ..Default::default()
-        },
-    ))?;
-
-    info!("Wifi configuration set, about to get status");
-
-    let status = wifi.get_status();
-
-    if let Status(
-        ClientStatus::Started(ClientConnectionStatus::Connected(ClientIpStatus::Done(ip_settings))),
-        ApStatus::Started(ApIpStatus::Done),
-    ) = status
-    {
-        info!("Wifi connected, about to do some pings");
-
-        let ping_summary =
-            EspPing::default().ping(ip_settings.subnet.gateway, &Default::default())?;
-        if ping_summary.transmitted != ping_summary.received {
-            bail!(
-                "Pinging gateway {} resulted in timeouts",
-                ip_settings.subnet.gateway
-            );
+/// Reports a fatal startup failure: relay to its safe state, a persisted fault
+/// record and the blink code of the failure class until the device restarts.
+fn fail(err: anyhow::Error, task: &'static str) -> ! {
+    let failure = err
+        .downcast_ref::<Failure>()
+        .copied()
+        .unwrap_or(Failure::HapInit);
+    error!(target: logging::HAP, "{:?}", err);
+
+    system::enter_safe_state();
+    fault::record(&format!("{:?}", err));
+
+    let watchdog = wdt::subscribe(task);
+    status_led::event(status_led::Event::Fatal(failure.blink_count()));
+    if failure == Failure::FactoryData {
+        // A restart reads the same data, and no identity beats a wrong one
+        error!(target: logging::DIAG, "Halted, the factory partition has to be reflashed");
+        loop {
+            watchdog.sleep(Duration::from_secs(config::FAILURE_RESTART_SECS));
         }
-
-        info!("Pinging done");
-    } else {
-        bail!("Unexpected Wifi status: {:?}", status);
     }
+    watchdog.sleep(Duration::from_secs(config::FAILURE_RESTART_SECS));
 
-    Ok(wifi)
-}
\ No newline at end of file
+    system::restart(&failure.to_string());
+}
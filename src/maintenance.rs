@@ -0,0 +1,123 @@
+use std::thread;
+use std::time::Duration;
+
+use hap_core::sys::perm;
+use hap_core::{CharSlot, ServiceBuilder, Status, Value, Write};
+use log::{info, warn};
+use spin::Mutex;
+
+use crate::event_bus::{self, Event};
+use crate::{logging, pairing_window, status_led, system};
+
+// Custom UUIDs, the SDK keeps the pointers so they have to be 'static
+const SERVICE_UUID: &[u8] = b"0000D3A0-28E5-4C3F-9B6E-5A1D7E3C9000\0";
+const REBOOT_UUID: &[u8] = b"0000D3A1-28E5-4C3F-9B6E-5A1D7E3C9000\0";
+const RESET_NETWORK_UUID: &[u8] = b"0000D3A2-28E5-4C3F-9B6E-5A1D7E3C9000\0";
+const RESET_PAIRINGS_UUID: &[u8] = b"0000D3A3-28E5-4C3F-9B6E-5A1D7E3C9000\0";
+
+const CONFIRM_MS: u64 = 5000;
+// Lets the controller receive the response to the confirming write
+const GRACE_MS: u64 = 500;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    Reboot,
+    ResetNetwork,
+    ResetPairings,
+}
+
+static REBOOT_CHAR: CharSlot = CharSlot::new();
+static RESET_NETWORK_CHAR: CharSlot = CharSlot::new();
+static RESET_PAIRINGS_CHAR: CharSlot = CharSlot::new();
+
+// The action armed by a first write and when
+static ARMED: Mutex<Option<(Action, u64)>> = Mutex::new(None);
+
+fn now_ms() -> u64 {
+    (unsafe { esp_idf_sys::esp_timer_get_time() } / 1000) as u64
+}
+
+fn action_of(write: &Write) -> Option<Action> {
+    let hc = Some(write.hc);
+    if hc == REBOOT_CHAR.get() {
+        Some(Action::Reboot)
+    } else if hc == RESET_NETWORK_CHAR.get() {
+        Some(Action::ResetNetwork)
+    } else if hc == RESET_PAIRINGS_CHAR.get() {
+        Some(Action::ResetPairings)
+    } else {
+        None
+    }
+}
+
+/// Writing true arms an action, writing true again within the confirmation
+/// window runs it; false disarms.
+fn on_write(write: &Write) -> Result<(), Status> {
+    let action = action_of(write).ok_or(Status::ResourceAbsent)?;
+    let mut armed = ARMED.lock();
+    if write.value.as_bool() != Some(true) {
+        *armed = None;
+        return Ok(());
+    }
+
+    let now = now_ms();
+    let confirmed = matches!(*armed, Some((a, at)) if a == action && now - at <= CONFIRM_MS);
+    *armed = (!confirmed).then_some((action, now));
+    drop(armed);
+
+    // Run from the dispatcher, the HAP task still has to answer the write
+    event_bus::publish(Event::Maintenance { action, confirmed });
+
+    Ok(())
+}
+
+fn on_bus_event(event: &Event) {
+    let Event::Maintenance { action, confirmed } = *event else {
+        return;
+    };
+
+    if !confirmed {
+        info!(
+            target: logging::DIAG,
+            "Maintenance {:?} armed, write again within {} s to confirm",
+            action,
+            CONFIRM_MS / 1000
+        );
+        status_led::event(status_led::Event::Identify);
+        return;
+    }
+
+    warn!(target: logging::DIAG, "Maintenance {:?} confirmed", action);
+    thread::sleep(Duration::from_millis(GRACE_MS));
+    match action {
+        Action::Reboot => system::restart("maintenance switch"),
+        Action::ResetNetwork => system::reset_network(),
+        Action::ResetPairings => {
+            pairing_window::reopen_at_restart();
+            system::reset_pairings()
+        }
+    }
+}
+
+/// Starts acting on confirmed writes; before the service is registered.
+pub fn init() {
+    event_bus::subscribe(on_bus_event);
+}
+
+/// A hidden service of three write-only switches, for support to walk a
+/// user through a reboot or reset from any app that lists every service.
+pub fn service() -> ServiceBuilder {
+    let switch = |service: ServiceBuilder, uuid, slot| {
+        service
+            .char(uuid, perm::PW, Value::Bool(false))
+            .bind(uuid, slot)
+    };
+
+    let service = ServiceBuilder::custom(SERVICE_UUID)
+        .name("Maintenance")
+        .hidden()
+        .on_write(&on_write);
+    let service = switch(service, REBOOT_UUID, &REBOOT_CHAR);
+    let service = switch(service, RESET_NETWORK_UUID, &RESET_NETWORK_CHAR);
+    switch(service, RESET_PAIRINGS_UUID, &RESET_PAIRINGS_CHAR)
+}
@@ -0,0 +1,170 @@
+use std::ffi::CStr;
+use std::{ptr, slice};
+
+use anyhow::{anyhow, bail, Result};
+use esp_idf_sys::esp;
+use hap_core::MdnsInfo;
+use log::{info, warn};
+use spin::{Mutex, Once};
+
+use crate::{config, console, http, logging, pairing_window};
+
+const QUERY_TIMEOUT_MS: u32 = 3000;
+const MAX_RESULTS: usize = 16;
+
+static INSTANCE: Once<String> = Once::new();
+static INFO: Mutex<Option<MdnsInfo>> = Mutex::new(None);
+
+unsafe fn string(s: *const esp_idf_sys::c_types::c_char) -> String {
+    if s.is_null() {
+        String::new()
+    } else {
+        CStr::from_ptr(s).to_string_lossy().into_owned()
+    }
+}
+
+unsafe fn record(result: &esp_idf_sys::mdns_result_t) -> MdnsInfo {
+    let mut txt = Vec::with_capacity(result.txt_count);
+    for i in 0..result.txt_count {
+        let item = &*result.txt.add(i);
+        // Values are binary in principle, HAP's are all ASCII
+        let value = if item.value.is_null() || result.txt_value_len.is_null() {
+            String::new()
+        } else {
+            let len = *result.txt_value_len.add(i) as usize;
+            String::from_utf8_lossy(slice::from_raw_parts(item.value as *const u8, len)).into()
+        };
+        txt.push((string(item.key), value));
+    }
+
+    MdnsInfo {
+        instance: string(result.instance_name),
+        hostname: string(result.hostname),
+        port: result.port,
+        txt,
+    }
+}
+
+/// Browses `_hap._tcp` for the record of `instance`, the way a controller
+/// finds the accessory.
+fn query(instance: &str) -> Result<MdnsInfo> {
+    let exists = unsafe {
+        esp_idf_sys::mdns_service_exists(c"_hap".as_ptr(), c"_tcp".as_ptr(), ptr::null())
+    };
+    if !exists {
+        bail!("no _hap._tcp service registered with mDNS");
+    }
+
+    let mut results: *mut esp_idf_sys::mdns_result_t = ptr::null_mut();
+    esp!(unsafe {
+        esp_idf_sys::mdns_query_ptr(
+            c"_hap".as_ptr(),
+            c"_tcp".as_ptr(),
+            QUERY_TIMEOUT_MS,
+            MAX_RESULTS,
+            &mut results,
+        )
+    })?;
+
+    let mut found = None;
+    let mut others = Vec::new();
+    let mut it = results;
+    while !it.is_null() {
+        let result = unsafe { &*it };
+        let record = unsafe { record(result) };
+        // One answer per interface and IP version
+        if record.instance == instance {
+            found.get_or_insert(record);
+        } else if !others.contains(&record.instance) {
+            others.push(record.instance);
+        }
+        it = result.next;
+    }
+    unsafe { esp_idf_sys::mdns_query_results_free(results) };
+
+    found.ok_or_else(|| {
+        anyhow!(
+            "no answer for '{}', other accessories seen: {:?}",
+            instance,
+            others
+        )
+    })
+}
+
+/// The accessory's name, once HAP started.
+pub fn instance() -> Option<&'static str> {
+    INSTANCE.get().map(String::as_str)
+}
+
+/// What the accessory advertised when last checked.
+pub fn info() -> Option<MdnsInfo> {
+    INFO.lock().clone()
+}
+
+/// Reads back the advertised record and warns where it disagrees with the
+/// accessory, e.g. an sf that makes paired controllers try pair setup.
+pub fn check() -> Result<MdnsInfo> {
+    let instance = INSTANCE
+        .get()
+        .ok_or_else(|| anyhow!("HAP has not started"))?;
+    let info = query(instance)?;
+    info!(target: logging::HAP, "mDNS: {}", info);
+
+    for mismatch in info.check(config::HAP_PORT, pairing_window::discoverable()) {
+        warn!(target: logging::HAP, "mDNS: {}", mismatch);
+    }
+    if info.port == config::HTTP_PORT {
+        warn!(
+            target: logging::HAP,
+            "mDNS: HAP is advertised on the port of the HTTP API"
+        );
+    }
+    *INFO.lock() = Some(info.clone());
+
+    Ok(info)
+}
+
+/// Checks the record once HAP started, the SDK registers it in `hap_start`.
+pub fn check_at_start(instance: &str) {
+    INSTANCE.call_once(|| instance.into());
+    if let Err(err) = check() {
+        warn!(target: logging::HAP, "mDNS self-check failed: {:?}", err);
+    }
+}
+
+pub fn render_json() -> String {
+    let Some(info) = info() else {
+        return "null".into();
+    };
+
+    let txt: Vec<_> = info
+        .txt
+        .iter()
+        .map(|(key, value)| format!("{}:{}", http::json_string(key), http::json_string(value)))
+        .collect();
+    format!(
+        "{{\"instance\":{},\"hostname\":{},\"port\":{},\"txt\":{{{}}}}}",
+        http::json_string(&info.instance),
+        http::json_string(&info.hostname),
+        info.port,
+        txt.join(",")
+    )
+}
+
+pub fn register_commands() {
+    console::register(
+        "mdns",
+        "Query the advertised _hap._tcp record and check it",
+        |_| {
+            let info = check()?;
+            println!(
+                "{} '{}' on port {}",
+                info.hostname, info.instance, info.port
+            );
+            for (key, value) in &info.txt {
+                println!("  {:<3} {}", key, value);
+            }
+            Ok(())
+        },
+    );
+}
@@ -0,0 +1,27 @@
+use std::fmt::Write;
+
+use spin::Mutex;
+
+pub type Source = fn() -> i64;
+
+static SOURCES: Mutex<Vec<(&'static str, Source)>> = Mutex::new(Vec::new());
+
+pub fn register(name: &'static str, source: Source) {
+    SOURCES.lock().push((name, source));
+}
+
+pub fn render_json() -> String {
+    // Copy the table out so no source is evaluated while holding the lock
+    let sources = SOURCES.lock().clone();
+
+    let mut json = String::from("{");
+    for (i, (name, source)) in sources.iter().enumerate() {
+        if i > 0 {
+            json.push(',');
+        }
+        let _ = write!(json, "\"{}\":{}", name, source());
+    }
+    json.push('}');
+
+    json
+}
@@ -0,0 +1,193 @@
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use esp_idf_sys::esp;
+use log::debug;
+
+use crate::{config, logging, pm};
+
+const READ_INPUT_REGISTERS: u8 = 0x04;
+const EXCEPTION: u8 = 0x80;
+
+// Registers per request the frame buffer is sized for
+const MAX_REGISTERS: usize = 32;
+
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0xffffu16;
+    for byte in data {
+        crc ^= *byte as u16;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xa001
+            } else {
+                crc >> 1
+            };
+        }
+    }
+
+    crc
+}
+
+pub struct Config {
+    pub port: esp_idf_sys::uart_port_t,
+    pub tx_gpio: i32,
+    pub rx_gpio: i32,
+    /// Driver-enable / receiver-enable pin of the RS-485 transceiver
+    pub de_gpio: i32,
+    pub baud: u32,
+    pub timeout: Duration,
+    pub attempts: u32,
+}
+
+/// A Modbus RTU master on a half-duplex RS-485 bus.
+pub struct Master {
+    config: Config,
+    /// Keeps the baud rate while a frame is on the bus
+    pm_lock: Option<pm::Lock>,
+}
+
+impl Master {
+    pub fn new(config: Config) -> Result<Self> {
+        let mut uart_config: esp_idf_sys::uart_config_t = unsafe { std::mem::zeroed() };
+        uart_config.baud_rate = config.baud as i32;
+        uart_config.data_bits = esp_idf_sys::uart_word_length_t_UART_DATA_8_BITS;
+        uart_config.parity = esp_idf_sys::uart_parity_t_UART_PARITY_DISABLE;
+        uart_config.stop_bits = esp_idf_sys::uart_stop_bits_t_UART_STOP_BITS_1;
+        uart_config.flow_ctrl = esp_idf_sys::uart_hw_flowcontrol_t_UART_HW_FLOWCTRL_DISABLE;
+
+        esp!(unsafe { esp_idf_sys::uart_param_config(config.port, &uart_config) })?;
+        esp!(unsafe {
+            esp_idf_sys::uart_set_pin(config.port, config.tx_gpio, config.rx_gpio, -1, -1)
+        })?;
+        esp!(unsafe {
+            esp_idf_sys::uart_driver_install(config.port, 256, 0, 0, std::ptr::null_mut(), 0)
+        })?;
+
+        unsafe {
+            esp_idf_sys::gpio_reset_pin(config.de_gpio);
+            esp_idf_sys::gpio_set_direction(
+                config.de_gpio,
+                esp_idf_sys::gpio_mode_t_GPIO_MODE_OUTPUT,
+            );
+            esp_idf_sys::gpio_set_level(config.de_gpio, 0);
+        }
+
+        let pm_lock = if config::PM_ENABLED {
+            Some(pm::Lock::apb_max(b"modbus\0")?)
+        } else {
+            None
+        };
+
+        Ok(Self { config, pm_lock })
+    }
+
+    /// Reads `count` input registers (function 0x04), retrying timeouts and
+    /// corrupted replies.
+    pub fn read_input_registers(&self, unit: u8, start: u16, count: u16) -> Result<Vec<u16>> {
+        if count as usize > MAX_REGISTERS {
+            bail!("at most {} registers per request", MAX_REGISTERS);
+        }
+
+        let mut attempt = 1;
+        loop {
+            match self.transact(unit, start, count) {
+                Ok(registers) => return Ok(registers),
+                Err(err) if attempt < self.config.attempts => {
+                    debug!(
+                        target: logging::ENERGY,
+                        "Modbus read of unit {} register {:#06x} failed (attempt {}): {}",
+                        unit,
+                        start,
+                        attempt,
+                        err
+                    );
+                    attempt += 1;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    fn transact(&self, unit: u8, start: u16, count: u16) -> Result<Vec<u16>> {
+        let port = self.config.port;
+        let _pm = self.pm_lock.as_ref().map(pm::Lock::acquire);
+        let [start_hi, start_lo] = start.to_be_bytes();
+        let [count_hi, count_lo] = count.to_be_bytes();
+
+        let mut request = [
+            unit,
+            READ_INPUT_REGISTERS,
+            start_hi,
+            start_lo,
+            count_hi,
+            count_lo,
+            0,
+            0,
+        ];
+        let [crc_lo, crc_hi] = crc16(&request[..6]).to_le_bytes();
+        request[6] = crc_lo;
+        request[7] = crc_hi;
+
+        // Stale bytes of an earlier, timed out reply would corrupt this one
+        unsafe { esp_idf_sys::uart_flush_input(port) };
+
+        unsafe { esp_idf_sys::gpio_set_level(self.config.de_gpio, 1) };
+        let written =
+            unsafe { esp_idf_sys::uart_write_bytes(port, request.as_ptr() as _, request.len()) };
+        let ticks = 100 * esp_idf_sys::configTICK_RATE_HZ / 1000;
+        let sent = unsafe { esp_idf_sys::uart_wait_tx_done(port, ticks) };
+        unsafe { esp_idf_sys::gpio_set_level(self.config.de_gpio, 0) };
+        if written != request.len() as i32 {
+            bail!("wrote {} of {} bytes", written, request.len());
+        }
+        esp!(sent)?;
+
+        // Address, function, byte count, data, CRC; exceptions are 5 bytes
+        let expected = 5 + count as usize * 2;
+        let mut reply = [0u8; 5 + MAX_REGISTERS * 2];
+        let mut len = 0;
+        let ticks = self.config.timeout.as_millis() as u32 * esp_idf_sys::configTICK_RATE_HZ / 1000;
+        while len < expected {
+            let read = unsafe {
+                esp_idf_sys::uart_read_bytes(
+                    port,
+                    reply[len..].as_mut_ptr() as _,
+                    (expected - len) as u32,
+                    ticks.max(1),
+                )
+            };
+            if read <= 0 {
+                bail!("timeout after {} of {} bytes", len, expected);
+            }
+            len += read as usize;
+
+            if len >= 5 && reply[1] == READ_INPUT_REGISTERS | EXCEPTION {
+                break;
+            }
+        }
+
+        let frame = &reply[..len];
+        let crc = u16::from_le_bytes([frame[len - 2], frame[len - 1]]);
+        if crc != crc16(&frame[..len - 2]) {
+            bail!("CRC mismatch");
+        }
+        if frame[0] != unit {
+            bail!("reply from unit {} instead of {}", frame[0], unit);
+        }
+        if frame[1] == READ_INPUT_REGISTERS | EXCEPTION {
+            bail!("exception code {}", frame[2]);
+        }
+        if frame[2] as usize != count as usize * 2 {
+            bail!("reply carries {} bytes instead of {}", frame[2], count * 2);
+        }
+
+        // Modbus wants 3.5 characters of silence between frames
+        thread::sleep(Duration::from_millis(5));
+
+        Ok(frame[3..len - 2]
+            .chunks(2)
+            .map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
+            .collect())
+    }
+}
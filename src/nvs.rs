@@ -0,0 +1,838 @@
+use std::ffi::{CStr, CString};
+use std::ptr;
+
+use anyhow::{anyhow, bail, Context, Result};
+use esp_idf_sys::{esp, nvs_handle_t, nvs_type_t, EspError};
+use hap_core::schema::{Entries, Stored};
+use log::{info, warn};
+
+use crate::{console, factory_config, logging, metrics};
+
+// NVS limits keys and namespaces to 15 characters; the extra byte is the terminator
+const MAX_KEY_LEN: usize = 15;
+
+pub const DEFAULT_PARTITION: &str = "nvs";
+/// The SDK's pairings and accessory keys, apart from our data so a namespace
+/// filling up the default partition cannot cost the pairings. Has to match
+/// CONFIG_HAP_PLATFORM_DEF_NVS_PARTITION in sdkconfig.defaults.
+pub const HAP_PARTITION: &str = "hap_nvs";
+
+pub const APP_STATE: &str = "app_state";
+pub const APP_STATE_QUARANTINE: &str = "app_state_q";
+pub const APP_STATE_FALLBACK: &str = "app_state_fb";
+pub const WIFI: &str = "wifi";
+pub const SCHED: &str = "sched";
+pub const DIAG: &str = "diag";
+pub const LOG: &str = "log";
+pub const IR: &str = "ir";
+pub const SOIL: &str = "soil";
+pub const DOOR: &str = "door";
+pub const ENERGY: &str = "energy";
+pub const AUDIT: &str = "audit";
+pub const TEMP: &str = "temp";
+pub const IIDS: &str = "hap_iids";
+
+struct Layout {
+    name: &'static str,
+    contents: &'static str,
+    /// Whether `nvs erase` may clear it
+    erasable: bool,
+}
+
+/// Every namespace the firmware keeps in the default partition. The Wi-Fi
+/// driver stores its own there as well; the HAP keystore and the factory
+/// data each have a partition of their own.
+const LAYOUT: &[Layout] = &[
+    Layout {
+        name: APP_STATE,
+        contents: "accessory state restored at boot",
+        erasable: true,
+    },
+    Layout {
+        name: APP_STATE_QUARANTINE,
+        contents: "accessory state that failed to migrate, kept for debugging",
+        erasable: true,
+    },
+    Layout {
+        name: APP_STATE_FALLBACK,
+        contents: "accessory state while app_state is from a newer firmware",
+        erasable: true,
+    },
+    Layout {
+        name: WIFI,
+        contents: "network settings beyond the compiled-in ones",
+        erasable: true,
+    },
+    Layout {
+        name: SCHED,
+        contents: "schedules",
+        erasable: true,
+    },
+    Layout {
+        name: DIAG,
+        contents: "last fault",
+        erasable: true,
+    },
+    Layout {
+        name: LOG,
+        contents: "log levels",
+        erasable: true,
+    },
+    Layout {
+        name: IR,
+        contents: "learned IR codes",
+        erasable: true,
+    },
+    Layout {
+        name: SOIL,
+        contents: "soil probe calibration and irrigation settings",
+        erasable: true,
+    },
+    Layout {
+        name: DOOR,
+        contents: "door position sensor calibration and last position",
+        erasable: true,
+    },
+    Layout {
+        name: ENERGY,
+        contents: "daily and lifetime energy totals",
+        erasable: true,
+    },
+    Layout {
+        name: AUDIT,
+        contents: "latest entries of the write audit trail",
+        erasable: true,
+    },
+    Layout {
+        name: TEMP,
+        contents: "temperature probes, their offsets and weights",
+        erasable: true,
+    },
+    // New iids without new pairings would make controllers lose their
+    // automations, they are only cleared by a factory reset
+    Layout {
+        name: IIDS,
+        contents: "HAP instance ids",
+        erasable: false,
+    },
+];
+
+// Namespaces of ESP-IDF itself in the default partition
+const DRIVER_NAMESPACES: [&str; 2] = ["nvs.net80211", "phy"];
+
+// NVS reclaims a page only by moving its live entries to a free one; close
+// to full every write forces page erases and eventually fails
+const LOW_FREE_PERCENT: usize = 10;
+
+fn layout(name: &str) -> Option<&'static Layout> {
+    LAYOUT.iter().find(|layout| layout.name == name)
+}
+
+fn c_name(name: &str) -> Result<[u8; MAX_KEY_LEN + 1]> {
+    if name.len() > MAX_KEY_LEN {
+        bail!(
+            "NVS name '{}' is longer than {} characters",
+            name,
+            MAX_KEY_LEN
+        );
+    }
+
+    let mut buf = [0u8; MAX_KEY_LEN + 1];
+    buf[..name.len()].copy_from_slice(name.as_bytes());
+
+    Ok(buf)
+}
+
+fn needs_reformat(err: i32) -> bool {
+    err == esp_idf_sys::ESP_ERR_NVS_NO_FREE_PAGES as i32
+        || err == esp_idf_sys::ESP_ERR_NVS_NEW_VERSION_FOUND as i32
+}
+
+/// Mounts the default and the HAP partition, reformatting either when it is
+/// full or from a newer NVS version, as the SDK would.
+pub fn init() -> Result<()> {
+    let err = unsafe { esp_idf_sys::nvs_flash_init() };
+    if needs_reformat(err) {
+        esp!(unsafe { esp_idf_sys::nvs_flash_erase() })?;
+        esp!(unsafe { esp_idf_sys::nvs_flash_init() })?;
+    } else {
+        esp!(err)?;
+    }
+
+    let mounted = match init_partition(HAP_PARTITION) {
+        Err(err)
+            if err
+                .downcast_ref::<EspError>()
+                .map_or(false, |err| needs_reformat(err.code())) =>
+        {
+            warn!(target: logging::DIAG, "Reformatting {}: {:?}", HAP_PARTITION, err);
+            erase_partition(HAP_PARTITION)?;
+            init_partition(HAP_PARTITION)?
+        }
+        mounted => mounted?,
+    };
+    if !mounted {
+        bail!(
+            "no {} partition, flash the partition table from partitions.csv",
+            HAP_PARTITION
+        );
+    }
+
+    if let Err(err) = migrate_hap_keystore() {
+        warn!(target: logging::DIAG, "Moving the HAP keystore failed: {:?}", err);
+    }
+
+    Ok(())
+}
+
+/// Moves what the SDK stored in the default partition before it had its
+/// own, so devices keep their pairings across the update. Runs only while
+/// the HAP partition is still empty.
+fn migrate_hap_keystore() -> Result<()> {
+    if stats(HAP_PARTITION)?.used_entries > 0 {
+        return Ok(());
+    }
+
+    let entries: Vec<_> = entries(DEFAULT_PARTITION)?
+        .into_iter()
+        .filter(|entry| entry.namespace.starts_with("hap_") && layout(&entry.namespace).is_none())
+        .collect();
+    if entries.is_empty() {
+        return Ok(());
+    }
+
+    let mut namespaces: Vec<&str> = Vec::new();
+    for entry in &entries {
+        if !namespaces.contains(&entry.namespace.as_str()) {
+            namespaces.push(&entry.namespace);
+        }
+    }
+
+    for name in &namespaces {
+        let from = Namespace::open(name)?;
+        let to = Namespace::open_in(
+            HAP_PARTITION,
+            name,
+            esp_idf_sys::nvs_open_mode_t_NVS_READWRITE,
+        )?;
+        for entry in entries.iter().filter(|entry| entry.namespace == *name) {
+            copy_entry(&from, &to, &entry.key, entry.kind)
+                .with_context(|| format!("copying {}/{}", name, entry.key))?;
+        }
+        to.commit()?;
+    }
+    // Only once everything arrived; a restart before leaves a stale copy,
+    // which the boot check reports
+    for name in &namespaces {
+        let from = Namespace::open(name)?;
+        from.erase_all()?;
+        from.commit()?;
+    }
+
+    info!(
+        target: logging::DIAG,
+        "Moved {} HAP keys in {} namespaces to {}",
+        entries.len(),
+        namespaces.len(),
+        HAP_PARTITION
+    );
+
+    Ok(())
+}
+
+fn copy_entry(from: &Namespace, to: &Namespace, key: &str, kind: nvs_type_t) -> Result<()> {
+    let key = c_name(key)?;
+    let key = key.as_ptr() as *const _;
+
+    macro_rules! copy_int {
+        ($get:ident, $set:ident, $ty:ty) => {{
+            let mut value: $ty = 0;
+            esp!(unsafe { esp_idf_sys::$get(from.handle, key, &mut value) })?;
+            esp!(unsafe { esp_idf_sys::$set(to.handle, key, value) })?;
+        }};
+    }
+
+    match kind {
+        esp_idf_sys::nvs_type_t_NVS_TYPE_U8 => copy_int!(nvs_get_u8, nvs_set_u8, u8),
+        esp_idf_sys::nvs_type_t_NVS_TYPE_I8 => copy_int!(nvs_get_i8, nvs_set_i8, i8),
+        esp_idf_sys::nvs_type_t_NVS_TYPE_U16 => copy_int!(nvs_get_u16, nvs_set_u16, u16),
+        esp_idf_sys::nvs_type_t_NVS_TYPE_I16 => copy_int!(nvs_get_i16, nvs_set_i16, i16),
+        esp_idf_sys::nvs_type_t_NVS_TYPE_U32 => copy_int!(nvs_get_u32, nvs_set_u32, u32),
+        esp_idf_sys::nvs_type_t_NVS_TYPE_I32 => copy_int!(nvs_get_i32, nvs_set_i32, i32),
+        esp_idf_sys::nvs_type_t_NVS_TYPE_U64 => copy_int!(nvs_get_u64, nvs_set_u64, u64),
+        esp_idf_sys::nvs_type_t_NVS_TYPE_I64 => copy_int!(nvs_get_i64, nvs_set_i64, i64),
+        esp_idf_sys::nvs_type_t_NVS_TYPE_STR => {
+            let mut len = 0;
+            esp!(unsafe { esp_idf_sys::nvs_get_str(from.handle, key, ptr::null_mut(), &mut len) })?;
+            let mut buf = vec![0u8; len];
+            esp!(unsafe {
+                esp_idf_sys::nvs_get_str(from.handle, key, buf.as_mut_ptr() as _, &mut len)
+            })?;
+            esp!(unsafe { esp_idf_sys::nvs_set_str(to.handle, key, buf.as_ptr() as _) })?;
+        }
+        esp_idf_sys::nvs_type_t_NVS_TYPE_BLOB => {
+            let mut len = 0;
+            esp!(unsafe {
+                esp_idf_sys::nvs_get_blob(from.handle, key, ptr::null_mut(), &mut len)
+            })?;
+            let mut buf = vec![0u8; len];
+            esp!(unsafe {
+                esp_idf_sys::nvs_get_blob(from.handle, key, buf.as_mut_ptr() as _, &mut len)
+            })?;
+            esp!(unsafe { esp_idf_sys::nvs_set_blob(to.handle, key, buf.as_ptr() as _, len) })?;
+        }
+        kind => bail!("unknown entry type {}", kind),
+    }
+
+    Ok(())
+}
+
+/// Moves every entry of a namespace to another, replacing what that held;
+/// returns how many moved.
+pub fn move_namespace(from: &str, to: &str) -> Result<usize> {
+    let source = Namespace::open(from)?;
+    let target = Namespace::open(to)?;
+    target.erase_all()?;
+
+    let entries: Vec<_> = entries(DEFAULT_PARTITION)?
+        .into_iter()
+        .filter(|entry| entry.namespace == from)
+        .collect();
+    for entry in &entries {
+        copy_entry(&source, &target, &entry.key, entry.kind)
+            .with_context(|| format!("copying {}/{}", from, entry.key))?;
+    }
+    target.commit()?;
+    // Only once everything arrived, as with the HAP keystore
+    source.erase_all()?;
+    source.commit()?;
+
+    Ok(entries.len())
+}
+
+/// Reads a whole namespace of the default partition, for the schema
+/// migrations; the integer types other than u8 and u32 are not read.
+pub fn load(name: &str) -> Result<Entries> {
+    let store = Namespace::open(name)?;
+    let mut loaded = Entries::new();
+
+    for entry in entries(DEFAULT_PARTITION)? {
+        if entry.namespace != name {
+            continue;
+        }
+        let value = match entry.kind {
+            esp_idf_sys::nvs_type_t_NVS_TYPE_U8 => store.get_u8(&entry.key)?.map(Stored::U8),
+            esp_idf_sys::nvs_type_t_NVS_TYPE_U32 => store.get_u32(&entry.key)?.map(Stored::U32),
+            esp_idf_sys::nvs_type_t_NVS_TYPE_STR => {
+                let mut buf = vec![0u8; store.len_of(&entry.key, entry.kind)?];
+                store
+                    .get_str(&entry.key, &mut buf)?
+                    .map(|value| Stored::Str(value.into()))
+            }
+            esp_idf_sys::nvs_type_t_NVS_TYPE_BLOB => {
+                let mut buf = vec![0u8; store.len_of(&entry.key, entry.kind)?];
+                store.get_blob(&entry.key, &mut buf)?.map(|len| {
+                    buf.truncate(len);
+                    Stored::Blob(buf)
+                })
+            }
+            kind => bail!("{}/{} has entry type {}", name, entry.key, kind),
+        };
+        if let Some(value) = value {
+            loaded.insert(entry.key, value);
+        }
+    }
+
+    Ok(loaded)
+}
+
+struct Entry {
+    namespace: String,
+    key: String,
+    kind: nvs_type_t,
+}
+
+fn entries(partition: &str) -> Result<Vec<Entry>> {
+    let partition = c_name(partition)?;
+    let mut entries = Vec::new();
+
+    let mut it = unsafe {
+        esp_idf_sys::nvs_entry_find(
+            partition.as_ptr() as _,
+            ptr::null(),
+            esp_idf_sys::nvs_type_t_NVS_TYPE_ANY,
+        )
+    };
+    // nvs_entry_next releases the iterator once it returns null
+    while !it.is_null() {
+        let mut info: esp_idf_sys::nvs_entry_info_t = unsafe { std::mem::zeroed() };
+        unsafe { esp_idf_sys::nvs_entry_info(it, &mut info) };
+        let namespace = unsafe { CStr::from_ptr(info.namespace_name.as_ptr()) };
+        let key = unsafe { CStr::from_ptr(info.key.as_ptr()) };
+        entries.push(Entry {
+            namespace: namespace.to_string_lossy().into_owned(),
+            key: key.to_string_lossy().into_owned(),
+            kind: info.type_,
+        });
+
+        it = unsafe { esp_idf_sys::nvs_entry_next(it) };
+    }
+
+    Ok(entries)
+}
+
+fn stats(partition: &str) -> Result<esp_idf_sys::nvs_stats_t> {
+    let partition = c_name(partition)?;
+    let mut stats: esp_idf_sys::nvs_stats_t = unsafe { std::mem::zeroed() };
+    esp!(unsafe { esp_idf_sys::nvs_get_stats(partition.as_ptr() as _, &mut stats) })?;
+
+    Ok(stats)
+}
+
+fn is_low(stats: &esp_idf_sys::nvs_stats_t) -> bool {
+    stats.free_entries * 100 < stats.total_entries * LOW_FREE_PERCENT
+}
+
+/// The namespaces of a partition with the entries each uses, long strings
+/// and blobs taking several.
+fn usage(partition: &str) -> Result<Vec<(String, usize)>> {
+    let mut names: Vec<String> = Vec::new();
+    for entry in entries(partition)? {
+        if !names.contains(&entry.namespace) {
+            names.push(entry.namespace);
+        }
+    }
+
+    names
+        .into_iter()
+        .map(|name| {
+            let store =
+                Namespace::open_in(partition, &name, esp_idf_sys::nvs_open_mode_t_NVS_READONLY)?;
+            let used = store.used_entries()?;
+            Ok((name, used))
+        })
+        .collect()
+}
+
+/// Logs how full the partitions are and what each namespace uses, warning
+/// about a partition close to full and namespaces missing from the layout.
+pub fn check_at_boot() {
+    for partition in [DEFAULT_PARTITION, HAP_PARTITION] {
+        let stats = match stats(partition) {
+            Ok(stats) => stats,
+            Err(err) => {
+                warn!(target: logging::DIAG, "No statistics of {}: {:?}", partition, err);
+                continue;
+            }
+        };
+        info!(
+            target: logging::DIAG,
+            "NVS {}: {} of {} entries used, {} namespaces",
+            partition,
+            stats.used_entries,
+            stats.total_entries,
+            stats.namespace_count
+        );
+        if is_low(&stats) {
+            warn!(
+                target: logging::DIAG,
+                "NVS {} has only {} free entries left",
+                partition,
+                stats.free_entries
+            );
+        }
+
+        let usage = match usage(partition) {
+            Ok(usage) => usage,
+            Err(err) => {
+                warn!(target: logging::DIAG, "Listing {} failed: {:?}", partition, err);
+                continue;
+            }
+        };
+        for (name, used) in usage {
+            let known = partition != DEFAULT_PARTITION
+                || layout(&name).is_some()
+                || DRIVER_NAMESPACES.contains(&name.as_str());
+            if known {
+                info!(target: logging::DIAG, "NVS {}/{}: {} entries", partition, name, used);
+            } else {
+                warn!(
+                    target: logging::DIAG,
+                    "NVS {}/{}: {} entries in a namespace outside the layout", partition, name, used
+                );
+            }
+        }
+    }
+}
+
+/// Clears every namespace of the layout, for a factory reset; the SDK only
+/// erases its own partition.
+pub fn erase_app_data() {
+    for layout in LAYOUT {
+        let erased = Namespace::open(layout.name).and_then(|store| {
+            store.erase_all()?;
+            Ok(store.commit()?)
+        });
+        if let Err(err) = erased {
+            warn!(target: logging::DIAG, "Erasing NVS {} failed: {:?}", layout.name, err);
+        }
+    }
+}
+
+pub fn register_metrics() {
+    metrics::register("nvs_free_entries", || {
+        stats(DEFAULT_PARTITION).map_or(-1, |stats| stats.free_entries as i64)
+    });
+    metrics::register("hap_nvs_free_entries", || {
+        stats(HAP_PARTITION).map_or(-1, |stats| stats.free_entries as i64)
+    });
+}
+
+fn print_stats() -> Result<()> {
+    for partition in [DEFAULT_PARTITION, HAP_PARTITION, factory_config::PARTITION] {
+        let stats = match stats(partition) {
+            Ok(stats) => stats,
+            Err(_) => {
+                println!("{:<8} not mounted", partition);
+                continue;
+            }
+        };
+        println!(
+            "{:<8} {}/{} entries used, {} free{}",
+            partition,
+            stats.used_entries,
+            stats.total_entries,
+            stats.free_entries,
+            if is_low(&stats) { ", low" } else { "" }
+        );
+
+        for (name, used) in usage(partition)? {
+            let contents = match layout(&name) {
+                Some(layout) if partition == DEFAULT_PARTITION => layout.contents,
+                _ => "",
+            };
+            println!("  {:<15} {:>4}  {}", name, used, contents);
+        }
+    }
+
+    Ok(())
+}
+
+fn erasable(name: &str) -> Result<&'static Layout> {
+    let layout = layout(name)
+        .ok_or_else(|| anyhow!("'{}' is not one of our namespaces, see 'nvs stats'", name))?;
+    if !layout.erasable {
+        bail!(
+            "{} ({}) is only cleared by a factory reset",
+            name,
+            layout.contents
+        );
+    }
+
+    Ok(layout)
+}
+
+pub fn register_commands() {
+    console::register(
+        "nvs",
+        "Show NVS usage ('nvs stats') or clear one of our namespaces ('nvs erase <namespace>')",
+        |args| match args {
+            ["stats"] => print_stats(),
+            ["erase", name] => {
+                let layout = erasable(name)?;
+                let used = Namespace::open(name)?.used_entries()?;
+                println!(
+                    "This clears {} ({}, {} entries); confirm with 'nvs erase {} confirm'",
+                    name, layout.contents, used, name
+                );
+                Ok(())
+            }
+            ["erase", name, "confirm"] => {
+                erasable(name)?;
+                let store = Namespace::open(name)?;
+                store.erase_all()?;
+                store.commit()?;
+                warn!(target: logging::DIAG, "NVS {} erased from the console", name);
+                println!("Erased {}, restart to drop what is still loaded", name);
+                Ok(())
+            }
+            _ => bail!("usage: nvs stats | nvs erase <namespace> [confirm]"),
+        },
+    );
+}
+
+/// Whether an NVS error only says the key or namespace does not exist.
+pub fn is_not_found(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<EspError>().map_or(false, |err| {
+        err.code() == esp_idf_sys::ESP_ERR_NVS_NOT_FOUND as i32
+    })
+}
+
+/// Mounts an extra NVS partition, e.g. factory data. `Ok(false)` if the
+/// partition table has none of that name.
+pub fn init_partition(label: &str) -> Result<bool> {
+    let c_label = c_name(label)?;
+    let partition = unsafe {
+        esp_idf_sys::esp_partition_find_first(
+            esp_idf_sys::esp_partition_type_t_ESP_PARTITION_TYPE_DATA,
+            esp_idf_sys::esp_partition_subtype_t_ESP_PARTITION_SUBTYPE_DATA_NVS,
+            c_label.as_ptr() as _,
+        )
+    };
+    if partition.is_null() {
+        return Ok(false);
+    }
+
+    esp!(unsafe { esp_idf_sys::nvs_flash_init_partition(c_label.as_ptr() as _) })?;
+
+    Ok(true)
+}
+
+/// Erases a whole partition, unmounting it first if it is mounted.
+pub fn erase_partition(label: &str) -> Result<()> {
+    let label = c_name(label)?;
+    esp!(unsafe { esp_idf_sys::nvs_flash_erase_partition(label.as_ptr() as _) })?;
+
+    Ok(())
+}
+
+/// An open namespace of an NVS partition, writable in the default one.
+///
+/// Keys are converted on the stack so reads and writes never allocate, which
+/// keeps the panic hook allowed to use this type.
+pub struct Namespace {
+    handle: nvs_handle_t,
+    partition: [u8; MAX_KEY_LEN + 1],
+    name: [u8; MAX_KEY_LEN + 1],
+}
+
+unsafe impl Send for Namespace {}
+unsafe impl Sync for Namespace {}
+
+impl Namespace {
+    pub fn open(name: &str) -> Result<Self> {
+        Self::open_in(
+            DEFAULT_PARTITION,
+            name,
+            esp_idf_sys::nvs_open_mode_t_NVS_READWRITE,
+        )
+    }
+
+    /// Opens a namespace of a partition mounted with `init_partition`.
+    pub fn open_read_only(partition: &str, name: &str) -> Result<Self> {
+        Self::open_in(partition, name, esp_idf_sys::nvs_open_mode_t_NVS_READONLY)
+    }
+
+    fn open_in(partition: &str, name: &str, mode: esp_idf_sys::nvs_open_mode_t) -> Result<Self> {
+        let partition = c_name(partition)?;
+        let name = c_name(name)?;
+        let mut handle: nvs_handle_t = 0;
+        esp!(unsafe {
+            esp_idf_sys::nvs_open_from_partition(
+                partition.as_ptr() as _,
+                name.as_ptr() as _,
+                mode,
+                &mut handle,
+            )
+        })?;
+
+        Ok(Self {
+            handle,
+            partition,
+            name,
+        })
+    }
+
+    /// Reads a blob into `buf`, returning its length or `None` if it is not stored.
+    pub fn get_blob(&self, key: &str, buf: &mut [u8]) -> Result<Option<usize>> {
+        let key = c_name(key)?;
+        let mut len = buf.len();
+        let err = unsafe {
+            esp_idf_sys::nvs_get_blob(
+                self.handle,
+                key.as_ptr() as _,
+                buf.as_mut_ptr() as _,
+                &mut len,
+            )
+        };
+
+        if err == esp_idf_sys::ESP_ERR_NVS_NOT_FOUND as i32 {
+            return Ok(None);
+        }
+        esp!(err)?;
+
+        Ok(Some(len))
+    }
+
+    /// Reads a string into `buf`, which needs room for the terminator.
+    pub fn get_str<'a>(&self, key: &str, buf: &'a mut [u8]) -> Result<Option<&'a str>> {
+        let key = c_name(key)?;
+        let mut len = buf.len();
+        let err = unsafe {
+            esp_idf_sys::nvs_get_str(
+                self.handle,
+                key.as_ptr() as _,
+                buf.as_mut_ptr() as _,
+                &mut len,
+            )
+        };
+
+        if err == esp_idf_sys::ESP_ERR_NVS_NOT_FOUND as i32 {
+            return Ok(None);
+        }
+        esp!(err)?;
+
+        // The length includes the terminator
+        let value = std::str::from_utf8(&buf[..len.saturating_sub(1)])?;
+
+        Ok(Some(value))
+    }
+
+    pub fn set_blob(&self, key: &str, data: &[u8]) -> Result<()> {
+        let key = c_name(key)?;
+        esp!(unsafe {
+            esp_idf_sys::nvs_set_blob(
+                self.handle,
+                key.as_ptr() as _,
+                data.as_ptr() as _,
+                data.len(),
+            )
+        })?;
+
+        Ok(())
+    }
+
+    pub fn set_str(&self, key: &str, value: &str) -> Result<()> {
+        let key = c_name(key)?;
+        let value = CString::new(value)?;
+        esp!(unsafe {
+            esp_idf_sys::nvs_set_str(self.handle, key.as_ptr() as _, value.as_ptr())
+        })?;
+
+        Ok(())
+    }
+
+    pub fn get_u32(&self, key: &str) -> Result<Option<u32>> {
+        let key = c_name(key)?;
+        let mut value = 0u32;
+        let err = unsafe { esp_idf_sys::nvs_get_u32(self.handle, key.as_ptr() as _, &mut value) };
+
+        if err == esp_idf_sys::ESP_ERR_NVS_NOT_FOUND as i32 {
+            return Ok(None);
+        }
+        esp!(err)?;
+
+        Ok(Some(value))
+    }
+
+    pub fn set_u32(&self, key: &str, value: u32) -> Result<()> {
+        let key = c_name(key)?;
+        esp!(unsafe { esp_idf_sys::nvs_set_u32(self.handle, key.as_ptr() as _, value) })?;
+
+        Ok(())
+    }
+
+    pub fn get_u8(&self, key: &str) -> Result<Option<u8>> {
+        let key = c_name(key)?;
+        let mut value = 0u8;
+        let err = unsafe { esp_idf_sys::nvs_get_u8(self.handle, key.as_ptr() as _, &mut value) };
+
+        if err == esp_idf_sys::ESP_ERR_NVS_NOT_FOUND as i32 {
+            return Ok(None);
+        }
+        esp!(err)?;
+
+        Ok(Some(value))
+    }
+
+    pub fn set_u8(&self, key: &str, value: u8) -> Result<()> {
+        let key = c_name(key)?;
+        esp!(unsafe { esp_idf_sys::nvs_set_u8(self.handle, key.as_ptr() as _, value) })?;
+
+        Ok(())
+    }
+
+    pub fn set(&self, key: &str, value: &Stored) -> Result<()> {
+        match value {
+            Stored::U8(value) => self.set_u8(key, *value),
+            Stored::U32(value) => self.set_u32(key, *value),
+            Stored::Blob(data) => self.set_blob(key, data),
+            Stored::Str(value) => self.set_str(key, value),
+        }
+    }
+
+    /// The length of a string, with its terminator, or of a blob.
+    fn len_of(&self, key: &str, kind: nvs_type_t) -> Result<usize> {
+        let key = c_name(key)?;
+        let mut len = 0;
+        if kind == esp_idf_sys::nvs_type_t_NVS_TYPE_STR {
+            esp!(unsafe {
+                esp_idf_sys::nvs_get_str(self.handle, key.as_ptr() as _, ptr::null_mut(), &mut len)
+            })?;
+        } else {
+            esp!(unsafe {
+                esp_idf_sys::nvs_get_blob(self.handle, key.as_ptr() as _, ptr::null_mut(), &mut len)
+            })?;
+        }
+
+        Ok(len)
+    }
+
+    pub fn remove(&self, key: &str) -> Result<()> {
+        let key = c_name(key)?;
+        let err = unsafe { esp_idf_sys::nvs_erase_key(self.handle, key.as_ptr() as _) };
+
+        if err != esp_idf_sys::ESP_ERR_NVS_NOT_FOUND as i32 {
+            esp!(err)?;
+        }
+
+        Ok(())
+    }
+
+    /// Lists the keys stored in the namespace; allocates, unlike the accessors.
+    pub fn keys(&self) -> Vec<String> {
+        let mut keys = Vec::new();
+
+        let mut it = unsafe {
+            esp_idf_sys::nvs_entry_find(
+                self.partition.as_ptr() as _,
+                self.name.as_ptr() as _,
+                esp_idf_sys::nvs_type_t_NVS_TYPE_ANY,
+            )
+        };
+        // nvs_entry_next releases the iterator once it returns null
+        while !it.is_null() {
+            let mut info: esp_idf_sys::nvs_entry_info_t = unsafe { std::mem::zeroed() };
+            unsafe { esp_idf_sys::nvs_entry_info(it, &mut info) };
+            let key = unsafe { CStr::from_ptr(info.key.as_ptr()) };
+            keys.push(key.to_string_lossy().into_owned());
+
+            it = unsafe { esp_idf_sys::nvs_entry_next(it) };
+        }
+
+        keys
+    }
+
+    pub fn used_entries(&self) -> Result<usize> {
+        let mut used = 0;
+        esp!(unsafe { esp_idf_sys::nvs_get_used_entry_count(self.handle, &mut used) })?;
+
+        Ok(used)
+    }
+
+    pub fn erase_all(&self) -> Result<()> {
+        esp!(unsafe { esp_idf_sys::nvs_erase_all(self.handle) })?;
+
+        Ok(())
+    }
+
+    pub fn commit(&self) -> Result<(), EspError> {
+        esp!(unsafe { esp_idf_sys::nvs_commit(self.handle) })
+    }
+}
+
+impl Drop for Namespace {
+    fn drop(&mut self) {
+        unsafe { esp_idf_sys::nvs_close(self.handle) };
+    }
+}
@@ -0,0 +1,119 @@
+use anyhow::Result;
+use esp_idf_hal::interrupt;
+use esp_idf_sys::esp;
+use hap_core::onewire::{self, Bus, BusError, Rom};
+
+fn delay_us(us: u32) {
+    unsafe { esp_idf_sys::ets_delay_us(us) };
+}
+
+/// A 1-Wire master bit-banging an open-drain GPIO, released to the bus's
+/// pull-up. Every time slot runs with interrupts off, 70 µs at most; the
+/// reset pulse is long enough to take them.
+pub struct OneWire {
+    gpio: i32,
+}
+
+impl OneWire {
+    pub fn new(gpio: i32) -> Result<Self> {
+        esp!(unsafe { esp_idf_sys::gpio_reset_pin(gpio) })?;
+        esp!(unsafe { esp_idf_sys::gpio_set_level(gpio, 1) })?;
+        esp!(unsafe {
+            esp_idf_sys::gpio_set_direction(
+                gpio,
+                esp_idf_sys::gpio_mode_t_GPIO_MODE_INPUT_OUTPUT_OD,
+            )
+        })?;
+        // Too weak for the bus, but keeps it defined without the probes
+        esp!(unsafe {
+            esp_idf_sys::gpio_set_pull_mode(gpio, esp_idf_sys::gpio_pull_mode_t_GPIO_PULLUP_ONLY)
+        })?;
+
+        Ok(Self { gpio })
+    }
+
+    fn drive_low(&self) {
+        unsafe { esp_idf_sys::gpio_set_level(self.gpio, 0) };
+    }
+
+    fn release(&self) {
+        unsafe { esp_idf_sys::gpio_set_level(self.gpio, 1) };
+    }
+
+    fn sample(&self) -> bool {
+        unsafe { esp_idf_sys::gpio_get_level(self.gpio) != 0 }
+    }
+
+    /// Starts a conversion on every probe at once; the readings are ready
+    /// after `onewire::CONVERSION_MS`.
+    pub fn convert_all(&mut self) -> Result<(), BusError> {
+        if !self.reset() {
+            return Err(BusError::NoDevice);
+        }
+        self.write_byte(onewire::SKIP_ROM);
+        self.write_byte(onewire::CONVERT_T);
+
+        Ok(())
+    }
+
+    /// The last conversion of the probe `rom`.
+    pub fn read_celsius(&mut self, rom: &Rom) -> Result<f32, BusError> {
+        if !self.reset() {
+            return Err(BusError::NoDevice);
+        }
+        self.write_byte(onewire::MATCH_ROM);
+        for byte in rom.0 {
+            self.write_byte(byte);
+        }
+        self.write_byte(onewire::READ_SCRATCHPAD);
+        let mut scratchpad = [0; 9];
+        for byte in &mut scratchpad {
+            *byte = self.read_byte();
+        }
+
+        onewire::celsius(&scratchpad)
+    }
+}
+
+impl Bus for OneWire {
+    fn reset(&mut self) -> bool {
+        self.drive_low();
+        delay_us(480);
+        let present = interrupt::free(|| {
+            self.release();
+            delay_us(70);
+            !self.sample()
+        });
+        delay_us(410);
+
+        present
+    }
+
+    fn write_bit(&mut self, bit: bool) {
+        interrupt::free(|| {
+            self.drive_low();
+            if bit {
+                delay_us(6);
+                self.release();
+                delay_us(64);
+            } else {
+                delay_us(60);
+                self.release();
+                delay_us(10);
+            }
+        });
+    }
+
+    fn read_bit(&mut self) -> bool {
+        let bit = interrupt::free(|| {
+            self.drive_low();
+            delay_us(6);
+            self.release();
+            delay_us(9);
+            self.sample()
+        });
+        delay_us(55);
+
+        bit
+    }
+}
@@ -0,0 +1,258 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use hap_core::audit::Origin;
+use hap_core::selftest::Subsystem;
+use hap_core::sys::{perm, uuid};
+use hap_core::{Accessory, Char, Runner, ServiceBuilder, ServiceHandle, Status, Value};
+use log::{info, warn};
+
+use crate::config::RestorePolicy as Policy;
+use crate::hap_sys::{EspHap, HAP};
+use crate::relay::RelayBackend;
+use crate::{
+    audit, child_lock, config, energy_meter, guardrail, logging, pairing_window, restore, selftest,
+    sessions, system, tasks, wdt,
+};
+
+// Custom UUID, the SDK keeps the pointer so it has to be 'static
+const RESTORE_POLICY_UUID: &[u8] = b"0000D6A1-28E5-4C3F-9B6E-5A1D7E3C9000\0";
+
+pub type OutletRunner = Runner<EspHap, Outlet>;
+
+pub struct Outlet {
+    relay: &'static dyn RelayBackend,
+    channel: u8,
+    on: bool,
+    /// On and fault as last notified
+    reported: Option<(bool, bool)>,
+    on_char: Option<Char>,
+    fault_char: Option<Char>,
+}
+
+impl Outlet {
+    /// Takes one channel of the relay backend and hands out the runner owning
+    /// the outlet for the lifetime of the firmware, switched as the restore
+    /// policy says.
+    pub fn new(relay: &'static dyn RelayBackend, channel: u8) -> &'static OutletRunner {
+        let mut outlet = Self {
+            relay,
+            channel,
+            on: false,
+            reported: None,
+            on_char: None,
+            fault_char: None,
+        };
+        if restore::initial_state() {
+            outlet.set(true, Origin::System);
+        }
+        info!(
+            target: logging::OUTLET,
+            "Outlet on relay channel {}, initially {} ({:?})",
+            channel,
+            if outlet.on { "on" } else { "off" },
+            restore::policy()
+        );
+
+        let runner = Runner::new(&HAP, outlet);
+        system::on_shutdown(move || {
+            runner.with(|outlet| outlet.set(config::RELAY_SAFE_STATE, Origin::System))
+        });
+
+        runner
+    }
+
+    /// Drives the relay; the runner reports the new state to HomeKit, for HAP
+    /// writes and local control alike, so the two never diverge. `origin`
+    /// goes into the audit trail. Switching on is subject to the guardrails.
+    pub fn set(&mut self, on: bool, origin: Origin) {
+        if on && !self.on {
+            if let Err(refusal) = guardrail::check() {
+                warn!(
+                    target: logging::OUTLET,
+                    "Not switching on ({}): {}",
+                    origin.name(),
+                    refusal
+                );
+                return;
+            }
+        }
+
+        let was = self.on;
+        let result = self.relay.set(self.channel, on);
+        if let Err(err) = &result {
+            warn!(
+                target: logging::OUTLET,
+                "Switching relay channel {} {} failed: {:?}",
+                self.channel,
+                on,
+                err
+            );
+        }
+
+        if let Some(on) = self.relay.get(self.channel) {
+            self.on = on;
+        }
+        guardrail::switched(self.on);
+        restore::record(self.on);
+        audit::record(uuid::ON, &Value::Bool(was), &Value::Bool(self.on), origin);
+    }
+
+    /// Drives the relay for the smoke test, which switches straight back:
+    /// no guardrail, nothing persisted or audited.
+    pub fn switch_for_test(&mut self, on: bool) {
+        if let Err(err) = self.relay.set(self.channel, on) {
+            warn!(
+                target: logging::OUTLET,
+                "Switching relay channel {} {} for the smoke test failed: {:?}",
+                self.channel,
+                on,
+                err
+            );
+        }
+        if let Some(on) = self.relay.get(self.channel) {
+            self.on = on;
+        }
+    }
+
+    pub fn toggle(&mut self, origin: Origin) {
+        self.set(!self.on, origin);
+    }
+
+    pub fn is_on(&self) -> bool {
+        self.on
+    }
+
+    pub fn on_char(&self) -> Option<Char> {
+        self.on_char
+    }
+
+    /// Switches off once a guardrail trips, whatever switched the outlet on.
+    fn enforce(&mut self) {
+        if self.on && guardrail::trip().is_some() {
+            self.set(false, Origin::System);
+        }
+    }
+}
+
+impl Accessory for Outlet {
+    fn services(&self) -> Vec<ServiceBuilder> {
+        let mut service = ServiceBuilder::outlet()
+            .name("My Smart Outlet")
+            .status_fault()
+            .char(
+                RESTORE_POLICY_UUID,
+                perm::PR | perm::PW | perm::EV,
+                Value::Uint8(restore::policy() as u8),
+            )
+            .valid_values(RESTORE_POLICY_UUID, Policy::VALUES);
+        service = child_lock::characteristics(service);
+        if config::METER_ENABLED {
+            service = energy_meter::characteristics(service);
+        }
+
+        vec![service]
+    }
+
+    fn attach(&mut self, services: &[ServiceHandle]) {
+        self.on_char = services[0].char(uuid::ON);
+        self.fault_char = services[0].char(uuid::STATUS_FAULT);
+    }
+
+    fn handle_write(&mut self, uuid: &str, value: &Value) -> Result<(), Status> {
+        // On and the restore policy are the writable characteristics, the
+        // write path refuses others
+        info!(target: logging::OUTLET, "Write of char {} = {:?}", uuid, value);
+        if uuid.as_bytes() == &RESTORE_POLICY_UUID[..RESTORE_POLICY_UUID.len() - 1] {
+            let policy = value
+                .as_u32()
+                .and_then(|value| u8::try_from(value).ok())
+                .and_then(Policy::from_u8)
+                .ok_or(Status::InvalidValue)?;
+            let was = restore::policy();
+            restore::set_policy(policy).map_err(|err| {
+                warn!(target: logging::OUTLET, "Storing the restore policy failed: {:?}", err);
+                Status::CommunicationError
+            })?;
+            audit::record(
+                RESTORE_POLICY_UUID,
+                &Value::Uint8(was as u8),
+                &Value::Uint8(policy as u8),
+                Origin::HomeKit,
+            );
+            return Ok(());
+        }
+
+        let on = value.as_bool().ok_or(Status::InvalidValue)?;
+        if on && !self.on {
+            if let Err(refusal) = guardrail::check() {
+                // The controller shows On until told otherwise
+                warn!(target: logging::OUTLET, "On refused: {}", refusal);
+                if let Some(hc) = self.on_char {
+                    HAP.update(hc, &Value::Bool(false));
+                }
+                return Err(Status::InvalidValue);
+            }
+        }
+        self.set(on, Origin::HomeKit);
+        if self.on != on {
+            return Err(Status::CommunicationError);
+        }
+
+        Ok(())
+    }
+
+    fn poll(&mut self) -> Vec<(Char, Value)> {
+        if self.on_char.is_none() {
+            return Vec::new();
+        }
+
+        // A UART relay board can switch on its own, and the interlock and
+        // the follow rules switch the relay behind the outlet's back, so the
+        // relay is asked and a change kept for the restore policy
+        if let Some(on) = self.relay.get(self.channel).filter(|&on| on != self.on) {
+            self.on = on;
+            restore::record(on);
+        }
+        guardrail::switched(self.on);
+        let state = (
+            self.on,
+            self.relay.faulted() || selftest::failed(Subsystem::Relay),
+        );
+        let reported = self.reported.replace(state);
+
+        let mut changes = Vec::new();
+        if let Some(hc) = self
+            .on_char
+            .filter(|_| reported.map(|r| r.0) != Some(state.0))
+        {
+            changes.push((hc, Value::Bool(state.0)));
+        }
+        if let Some(hc) = self
+            .fault_char
+            .filter(|_| reported.map(|r| r.1) != Some(state.1))
+        {
+            changes.push((hc, Value::Uint8(state.1 as u8)));
+        }
+
+        changes
+    }
+}
+
+/// Polls the outlet for changes HomeKit did not make, and holds it to the
+/// guardrails.
+pub fn start_polling(runner: &'static OutletRunner) -> Result<()> {
+    tasks::spawn(&tasks::ACCESSORY_POLL, move || {
+        let watchdog = wdt::subscribe(tasks::ACCESSORY_POLL.name);
+        loop {
+            runner.with(Outlet::enforce);
+            guardrail::notify();
+            sessions::poll();
+            if config::PAIRING_WINDOW_ENABLED {
+                pairing_window::poll();
+            }
+            audit::mirror_if_due();
+            watchdog.sleep(Duration::from_millis(config::ACCESSORY_POLL_MS));
+        }
+    })
+}
@@ -0,0 +1,216 @@
+use std::ffi::CString;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+use anyhow::{bail, Result};
+use hap_core::mdns::SF_UNPAIRED;
+use hap_core::pairing_window::{PairingWindow, State};
+use hap_core::HapSys;
+use log::{info, warn};
+use spin::{Mutex, Once};
+
+use crate::button::{self, Event};
+use crate::hap_sys::HAP;
+use crate::{app_state, config, console, diag, diag_service, logging, nvs, status_led, system};
+
+static STORE: Once<nvs::Namespace> = Once::new();
+static WINDOW: Mutex<PairingWindow> =
+    Mutex::new(PairingWindow::new(config::PAIRING_WINDOW_MINS * 60));
+// Whether the pair setup in progress started within the window
+static SETUP_IN_WINDOW: AtomicBool = AtomicBool::new(false);
+// As last notified
+static REPORTED: AtomicU8 = AtomicU8::new(State::Paired as u8);
+
+fn store() -> Result<&'static nvs::Namespace> {
+    STORE.try_call_once(|| nvs::Namespace::open(app_state::namespace()))
+}
+
+fn paired() -> bool {
+    HAP.sys().paired_controller_count() > 0
+}
+
+/// Remembers a closed window across restarts.
+fn persist(closed: bool) {
+    let stored = store().and_then(|store| {
+        store.set_u8("pair_closed", closed as u8)?;
+        Ok(store.commit()?)
+    });
+    if let Err(err) = stored {
+        warn!(target: logging::HAP, "Storing the pairing window failed: {:?}", err);
+    }
+}
+
+/// Sets the unpaired bit of the advertised status flags; the SDK sets them
+/// itself whenever the pairings change.
+fn advertise(unpaired: bool) {
+    let sf = CString::new(if unpaired { SF_UNPAIRED } else { 0 }.to_string()).unwrap();
+    let err = unsafe {
+        esp_idf_sys::mdns_service_txt_item_set(
+            c"_hap".as_ptr(),
+            c"_tcp".as_ptr(),
+            c"sf".as_ptr(),
+            sf.as_ptr(),
+        )
+    };
+    if err != esp_idf_sys::ESP_OK {
+        warn!(target: logging::HAP, "Setting the advertised sf failed: {}", err);
+    }
+}
+
+/// Paired, or whether the window is open; an unpaired accessory without the
+/// window always takes pairings.
+pub fn state() -> (State, u32) {
+    let paired = paired();
+    if !config::PAIRING_WINDOW_ENABLED {
+        let state = if paired { State::Paired } else { State::Open };
+        return (state, 0);
+    }
+    let now = diag::uptime_secs();
+    let window = WINDOW.lock();
+
+    (window.state(now, paired), window.remaining(now))
+}
+
+/// Whether the accessory takes pair setup, what its mDNS record should say.
+pub fn discoverable() -> bool {
+    state().0 == State::Open
+}
+
+fn notify() {
+    let (state, _) = state();
+    if REPORTED.swap(state as u8, Ordering::Relaxed) != state as u8 {
+        diag_service::update_pairing_window(state);
+    }
+}
+
+/// Opens the window of an unpaired accessory for its full length.
+pub fn open() -> Result<()> {
+    if paired() {
+        bail!("a controller is paired, add others from the Home app");
+    }
+    WINDOW.lock().open(diag::uptime_secs());
+    persist(false);
+    advertise(true);
+    status_led::event(status_led::Event::Unpaired);
+    info!(
+        target: logging::HAP,
+        "Pairing window open for {} min",
+        config::PAIRING_WINDOW_MINS
+    );
+    notify();
+
+    Ok(())
+}
+
+/// Stops advertising the unpaired accessory until the window opens again.
+pub fn close() {
+    WINDOW.lock().close();
+    persist(true);
+    advertise(false);
+    status_led::event(status_led::Event::PairingWindowClosed);
+    info!(
+        target: logging::HAP,
+        "Pairing window closed, press the button to open it again"
+    );
+    notify();
+}
+
+/// Closes the window once it ran out; from the poll task.
+pub fn poll() {
+    let expired = WINDOW.lock().expire(diag::uptime_secs());
+    if expired && !paired() {
+        close();
+    }
+}
+
+/// Any press of the boot button opens the window of an unpaired accessory.
+fn on_button(name: &'static str, event: Event) {
+    if name == button::BOOT.name && event == Event::Pressed && !paired() {
+        let _ = open();
+    }
+}
+
+/// Opens the window of an unpaired accessory after the first boot or a
+/// pairing reset, or keeps the closed one closed; once HAP started and
+/// registered its mDNS record.
+pub fn start() {
+    button::subscribe(on_button);
+    if paired() {
+        notify();
+        return;
+    }
+
+    let closed = store().and_then(|store| Ok(store.get_u8("pair_closed")?));
+    match closed {
+        Ok(Some(1)) => close(),
+        Ok(_) => {
+            let _ = open();
+        }
+        Err(err) => {
+            warn!(target: logging::HAP, "Loading the pairing window failed: {:?}", err);
+            close();
+        }
+    }
+}
+
+/// The next start opens the window again, for a pairing reset.
+pub fn reopen_at_restart() {
+    persist(false);
+}
+
+pub fn pairing_started() {
+    let open = state().0 == State::Open;
+    SETUP_IN_WINDOW.store(open, Ordering::Relaxed);
+    if !open {
+        warn!(target: logging::HAP, "Pair setup while the pairing window is closed");
+    }
+}
+
+/// The SDK has no way to refuse a pair setup, so a first pairing that started
+/// outside the window is removed again; the restart that takes keeps the
+/// window closed.
+pub fn controller_paired() {
+    let in_window = SETUP_IN_WINDOW.swap(false, Ordering::Relaxed);
+    let first = HAP.sys().paired_controller_count() == 1;
+    if first && !in_window && !WINDOW.lock().is_open(diag::uptime_secs()) {
+        warn!(
+            target: logging::HAP,
+            "Controller paired outside the pairing window, removing it"
+        );
+        system::reset_pairings();
+    }
+    WINDOW.lock().close();
+    notify();
+}
+
+/// The last controller was removed, which opens the window.
+pub fn controller_unpaired() {
+    if !paired() {
+        let _ = open();
+    }
+}
+
+pub fn register_commands() {
+    console::register(
+        "pairing",
+        "Show the pairing window ('pairing'), open it ('pairing open') or close it ('pairing close')",
+        |args| match args {
+            [] => {
+                let (state, secs) = state();
+                match state {
+                    State::Open => println!("Open, {} s remaining", secs),
+                    state => println!("{}", state.name()),
+                }
+                Ok(())
+            }
+            ["open"] => open(),
+            ["close"] => {
+                if paired() {
+                    bail!("a controller is paired, the window does not apply");
+                }
+                close();
+                Ok(())
+            }
+            _ => bail!("usage: pairing [open|close]"),
+        },
+    );
+}
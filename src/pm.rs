@@ -0,0 +1,302 @@
+use std::net::Ipv4Addr;
+use std::ptr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Result};
+use embedded_svc::ping::{Configuration, Ping};
+use embedded_svc::wifi::{ClientConnectionStatus, ClientIpStatus, ClientStatus, Status, Wifi};
+use esp_idf_svc::ping::EspPing;
+use esp_idf_sys::esp;
+use log::{info, warn};
+use spin::{Mutex, Once};
+
+use crate::config::{self, PowerProfile};
+use crate::{console, logging};
+
+#[cfg(esp32)]
+type PmConfig = esp_idf_sys::esp_pm_config_esp32_t;
+#[cfg(esp32s2)]
+type PmConfig = esp_idf_sys::esp_pm_config_esp32s2_t;
+#[cfg(esp32s3)]
+type PmConfig = esp_idf_sys::esp_pm_config_esp32s3_t;
+#[cfg(esp32c3)]
+type PmConfig = esp_idf_sys::esp_pm_config_esp32c3_t;
+
+// Only the C3 tops out at 160 MHz
+#[cfg(esp32c3)]
+const TOP_MHZ: u32 = 160;
+#[cfg(not(esp32c3))]
+const TOP_MHZ: u32 = 240;
+
+// Core 0 runs the Wi-Fi and lwIP tasks
+const DUAL_CORE: bool = cfg!(any(esp32, esp32s3));
+
+pub struct Profile {
+    pub name: &'static str,
+    pub cpu_mhz: u32,
+    /// Held during pair-setup, the SRP-3072 math takes several seconds at 80 MHz
+    pub pairing_mhz: u32,
+    /// Core the tasks from `tasks::spawn` are pinned to, `None` lets them float
+    pub app_core: Option<i32>,
+    pub wifi_ps: esp_idf_sys::wifi_ps_type_t,
+}
+
+pub const PROFILE: Profile = match config::POWER_PROFILE {
+    PowerProfile::Perf => Profile {
+        name: "perf",
+        cpu_mhz: TOP_MHZ,
+        pairing_mhz: TOP_MHZ,
+        app_core: None,
+        wifi_ps: esp_idf_sys::wifi_ps_type_t_WIFI_PS_NONE,
+    },
+    PowerProfile::Balanced => Profile {
+        name: "balanced",
+        cpu_mhz: 160,
+        pairing_mhz: 160,
+        app_core: if DUAL_CORE { Some(1) } else { None },
+        wifi_ps: esp_idf_sys::wifi_ps_type_t_WIFI_PS_MIN_MODEM,
+    },
+    // Everything on core 0 leaves core 1 idling in WFI; a truly single-core
+    // build needs CONFIG_FREERTOS_UNICORE in sdkconfig.defaults
+    PowerProfile::LowPower => Profile {
+        name: "lowpower",
+        cpu_mhz: 80,
+        pairing_mhz: 160,
+        app_core: if DUAL_CORE { Some(0) } else { None },
+        wifi_ps: esp_idf_sys::wifi_ps_type_t_WIFI_PS_MAX_MODEM,
+    },
+};
+
+static PAIRING_LOCK: Once<Lock> = Once::new();
+static PAIRING_GUARD: Mutex<Option<Guard<'static>>> = Mutex::new(None);
+static PAIRING_STARTED_MS: AtomicU64 = AtomicU64::new(0);
+
+fn now_ms() -> u64 {
+    (unsafe { esp_idf_sys::esp_timer_get_time() } / 1000) as u64
+}
+
+/// A power management lock for peripherals that glitch when the clocks are
+/// scaled or gated, like a carrier being generated or a UART receiving.
+pub struct Lock {
+    handle: esp_idf_sys::esp_pm_lock_handle_t,
+}
+
+unsafe impl Send for Lock {}
+unsafe impl Sync for Lock {}
+
+impl Lock {
+    /// `name` must be NUL-terminated, the lock keeps the pointer.
+    pub fn new(name: &'static [u8], kind: esp_idf_sys::esp_pm_lock_type_t) -> Result<Self> {
+        let mut handle = ptr::null_mut();
+        esp!(unsafe { esp_idf_sys::esp_pm_lock_create(kind, 0, name.as_ptr() as _, &mut handle) })?;
+
+        Ok(Self { handle })
+    }
+
+    /// Keeps the APB clock, and with it the UART baud rate, at its maximum;
+    /// this also keeps the device out of light sleep.
+    /// Runs the CPU at the maximum frequency of the configuration.
+    pub fn cpu_max(name: &'static [u8]) -> Result<Self> {
+        Self::new(name, esp_idf_sys::esp_pm_lock_type_t_ESP_PM_CPU_FREQ_MAX)
+    }
+
+    pub fn apb_max(name: &'static [u8]) -> Result<Self> {
+        Self::new(name, esp_idf_sys::esp_pm_lock_type_t_ESP_PM_APB_FREQ_MAX)
+    }
+
+    pub fn acquire(&self) -> Guard<'_> {
+        // Only fails without CONFIG_PM_ENABLE, when there is nothing to hold off
+        unsafe { esp_idf_sys::esp_pm_lock_acquire(self.handle) };
+        Guard { lock: self }
+    }
+}
+
+pub struct Guard<'a> {
+    lock: &'a Lock,
+}
+
+impl Drop for Guard<'_> {
+    fn drop(&mut self) {
+        unsafe { esp_idf_sys::esp_pm_lock_release(self.lock.handle) };
+    }
+}
+
+fn min_freq_mhz() -> u32 {
+    if config::PM_ENABLED {
+        config::PM_MIN_FREQ_MHZ
+    } else {
+        PROFILE.cpu_mhz
+    }
+}
+
+fn light_sleep() -> bool {
+    config::PM_ENABLED && config::PM_LIGHT_SLEEP
+}
+
+/// Applies the power profile: CPU frequency, the pair-setup boost and Wi-Fi
+/// power save, plus frequency scaling and light sleep with ESP_HAP_PM.
+pub fn init() -> Result<()> {
+    let pm_config = PmConfig {
+        max_freq_mhz: PROFILE.pairing_mhz as i32,
+        min_freq_mhz: min_freq_mhz() as i32,
+        light_sleep_enable: light_sleep(),
+    };
+    esp!(unsafe { esp_idf_sys::esp_pm_configure(&pm_config as *const PmConfig as *const _) })
+        .map_err(|err| anyhow!("esp_pm_configure failed (CONFIG_PM_ENABLE set?): {}", err))?;
+
+    // Without a lock the CPU idles at the minimum, the profile frequency
+    let lock = Lock::cpu_max(b"pairing\0")?;
+    PAIRING_LOCK.call_once(|| lock);
+
+    // Light sleep needs the radio to doze between DTIM beacons
+    let wifi_ps = if light_sleep() && PROFILE.wifi_ps == esp_idf_sys::wifi_ps_type_t_WIFI_PS_NONE {
+        warn!(target: logging::POWER, "Light sleep needs Wi-Fi modem sleep, enabling it");
+        esp_idf_sys::wifi_ps_type_t_WIFI_PS_MIN_MODEM
+    } else {
+        PROFILE.wifi_ps
+    };
+    let err = unsafe { esp_idf_sys::esp_wifi_set_ps(wifi_ps) };
+    if err != esp_idf_sys::ESP_OK {
+        warn!(target: logging::POWER, "Setting the Wi-Fi power save mode failed: {}", err);
+    }
+
+    info!(
+        target: logging::POWER,
+        "Power profile {}: {}-{} MHz, tasks on {}, Wi-Fi power save {}, light sleep {}",
+        PROFILE.name,
+        min_freq_mhz(),
+        PROFILE.pairing_mhz,
+        core_name(PROFILE.app_core),
+        wifi_ps,
+        if light_sleep() { "on" } else { "off" }
+    );
+
+    Ok(())
+}
+
+fn core_name(core: Option<i32>) -> String {
+    match core {
+        Some(core) => format!("core {}", core),
+        None => "any core".into(),
+    }
+}
+
+/// Raises the CPU to the pairing frequency while a pair-setup runs, and logs
+/// how long it took so a slow profile shows before controllers time out.
+pub fn pairing(active: bool) {
+    let Some(lock) = PAIRING_LOCK.get() else {
+        return;
+    };
+
+    let mut guard = PAIRING_GUARD.lock();
+    if active {
+        if guard.is_none() {
+            PAIRING_STARTED_MS.store(now_ms(), Ordering::Relaxed);
+            *guard = Some(lock.acquire());
+        }
+    } else if guard.take().is_some() {
+        info!(
+            target: logging::POWER,
+            "Pair-setup took {} ms at {} MHz",
+            now_ms().saturating_sub(PAIRING_STARTED_MS.load(Ordering::Relaxed)),
+            PROFILE.pairing_mhz
+        );
+    }
+}
+
+/// Boot-to-ready time, so the log shows what the profile costs.
+pub fn log_ready() {
+    info!(
+        target: logging::POWER,
+        "HAP ready {} ms after boot with power profile {} ({} MHz)",
+        now_ms(),
+        PROFILE.name,
+        PROFILE.cpu_mhz
+    );
+}
+
+pub struct Latency {
+    pub received: u32,
+    pub transmitted: u32,
+    pub average: Duration,
+}
+
+/// Round trip to the gateway, probing how quickly the device answers while
+/// it dozes between beacons; repeat it to judge the power management cost.
+pub fn probe_latency(gateway: Ipv4Addr) -> Result<Latency> {
+    let ping_config = Configuration {
+        count: 5,
+        interval: Duration::from_millis(700),
+        ..Default::default()
+    };
+    let summary = EspPing::default().ping(gateway, &ping_config)?;
+    if summary.received == 0 {
+        bail!("no ping replies from {}", gateway);
+    }
+
+    Ok(Latency {
+        received: summary.received,
+        transmitted: summary.transmitted,
+        average: summary.time / summary.received,
+    })
+}
+
+fn gateway() -> Option<Ipv4Addr> {
+    let app = crate::APP.get()?;
+    match app.wifi.lock().ok()?.get_status() {
+        Status(
+            ClientStatus::Started(ClientConnectionStatus::Connected(ClientIpStatus::Done(ip))),
+            _,
+        ) => Some(ip.subnet.gateway),
+        _ => None,
+    }
+}
+
+/// Logs the gateway round trip once, so the trade-off shows up in every boot log.
+pub fn log_latency() {
+    let Some(gateway) = gateway() else {
+        return;
+    };
+
+    match probe_latency(gateway) {
+        Ok(latency) => info!(
+            target: logging::POWER,
+            "Gateway round trip {} ms average ({}/{} replies, light sleep {})",
+            latency.average.as_millis(),
+            latency.received,
+            latency.transmitted,
+            if light_sleep() { "on" } else { "off" }
+        ),
+        Err(err) => warn!(target: logging::POWER, "Latency probe failed: {:?}", err),
+    }
+}
+
+pub fn register_commands() {
+    console::register(
+        "pm",
+        "Show the power management configuration and probe the gateway latency",
+        |_| {
+            println!(
+                "profile {}, {}-{} MHz, tasks on {}, scaling {}, light sleep {}",
+                PROFILE.name,
+                min_freq_mhz(),
+                PROFILE.pairing_mhz,
+                core_name(PROFILE.app_core),
+                config::PM_ENABLED,
+                light_sleep()
+            );
+
+            let gateway = gateway().ok_or_else(|| anyhow!("Wi-Fi not connected"))?;
+            let latency = probe_latency(gateway)?;
+            println!(
+                "gateway {} round trip {} ms ({}/{} replies)",
+                gateway,
+                latency.average.as_millis(),
+                latency.received,
+                latency.transmitted
+            );
+            Ok(())
+        },
+    );
+}
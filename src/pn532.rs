@@ -0,0 +1,222 @@
+use std::ptr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Result};
+use esp_idf_sys::{esp, EspError};
+use hap_core::pn532::{self, Firmware, Target};
+
+const I2C_ADDRESS: u8 = 0x24;
+const I2C_TIMEOUT_MS: u32 = 50;
+// Set in the status byte while the chip has a frame to read
+const READY: u8 = 0x01;
+// The first byte of every SPI transfer says what it is
+const SPI_WRITE: u8 = 0x01;
+const SPI_STATUS: u8 = 0x02;
+const SPI_READ: u8 = 0x03;
+
+const ACK_TIMEOUT: Duration = Duration::from_millis(50);
+const READY_POLL: Duration = Duration::from_millis(5);
+// More than any answer to the commands sent here; the rest reads as padding
+const RESPONSE_LEN: usize = 32;
+// 16 activation retries take about 150 ms without a tag
+const POLL_TIMEOUT: Duration = Duration::from_millis(500);
+const COMMAND_TIMEOUT: Duration = Duration::from_millis(100);
+// The IC code of the firmware version
+const PN532_IC: u8 = 0x32;
+
+enum Bus {
+    I2c(esp_idf_sys::i2c_port_t),
+    Spi(esp_idf_sys::spi_device_handle_t),
+}
+
+/// A PN532 on I2C or SPI, polled rather than through its IRQ line.
+pub struct Pn532 {
+    bus: Bus,
+}
+
+// The SPI device handle is only used by the task owning the reader
+unsafe impl Send for Pn532 {}
+
+fn ticks(ms: u32) -> u32 {
+    ms * esp_idf_sys::configTICK_RATE_HZ / 1000
+}
+
+fn transfer(
+    device: esp_idf_sys::spi_device_handle_t,
+    tx: &[u8],
+    rx: Option<&mut [u8]>,
+) -> Result<(), EspError> {
+    let mut transaction: esp_idf_sys::spi_transaction_t = unsafe { std::mem::zeroed() };
+    transaction.length = tx.len() * 8;
+    transaction.__bindgen_anon_1.tx_buffer = tx.as_ptr() as _;
+    if let Some(rx) = rx {
+        transaction.__bindgen_anon_2.rx_buffer = rx.as_mut_ptr() as _;
+    }
+
+    esp!(unsafe { esp_idf_sys::spi_device_polling_transmit(device, &mut transaction) })
+}
+
+impl Pn532 {
+    pub fn i2c(port: esp_idf_sys::i2c_port_t, sda: i32, scl: i32, hz: u32) -> Result<Self> {
+        let mut config: esp_idf_sys::i2c_config_t = unsafe { std::mem::zeroed() };
+        config.mode = esp_idf_sys::i2c_mode_t_I2C_MODE_MASTER;
+        config.sda_io_num = sda;
+        config.scl_io_num = scl;
+        // The breakout boards have their own, these only help long wires
+        config.sda_pullup_en = true;
+        config.scl_pullup_en = true;
+        config.__bindgen_anon_1.master.clk_speed = hz;
+        esp!(unsafe { esp_idf_sys::i2c_param_config(port, &config) })?;
+        esp!(unsafe { esp_idf_sys::i2c_driver_install(port, config.mode, 0, 0, 0) })?;
+
+        Ok(Self {
+            bus: Bus::I2c(port),
+        })
+    }
+
+    /// The PN532 shifts LSB first, in SPI mode 0.
+    pub fn spi(
+        host: esp_idf_sys::spi_host_device_t,
+        sck: i32,
+        miso: i32,
+        mosi: i32,
+        cs: i32,
+        hz: u32,
+    ) -> Result<Self> {
+        let mut bus: esp_idf_sys::spi_bus_config_t = unsafe { std::mem::zeroed() };
+        bus.__bindgen_anon_1.mosi_io_num = mosi;
+        bus.__bindgen_anon_2.miso_io_num = miso;
+        bus.sclk_io_num = sck;
+        bus.__bindgen_anon_3.quadwp_io_num = -1;
+        bus.__bindgen_anon_4.quadhd_io_num = -1;
+        esp!(unsafe {
+            esp_idf_sys::spi_bus_initialize(
+                host,
+                &bus,
+                esp_idf_sys::spi_common_dma_t_SPI_DMA_DISABLED,
+            )
+        })?;
+
+        let mut config: esp_idf_sys::spi_device_interface_config_t = unsafe { std::mem::zeroed() };
+        config.clock_speed_hz = hz as i32;
+        config.mode = 0;
+        config.spics_io_num = cs;
+        config.queue_size = 1;
+        config.flags = esp_idf_sys::SPI_DEVICE_BIT_LSBFIRST;
+        let mut device: esp_idf_sys::spi_device_handle_t = ptr::null_mut();
+        esp!(unsafe { esp_idf_sys::spi_bus_add_device(host, &config, &mut device) })?;
+
+        Ok(Self {
+            bus: Bus::Spi(device),
+        })
+    }
+
+    fn write(&mut self, frame: &[u8]) -> Result<(), EspError> {
+        match self.bus {
+            Bus::I2c(port) => esp!(unsafe {
+                esp_idf_sys::i2c_master_write_to_device(
+                    port,
+                    I2C_ADDRESS,
+                    frame.as_ptr(),
+                    frame.len(),
+                    ticks(I2C_TIMEOUT_MS),
+                )
+            }),
+            Bus::Spi(device) => {
+                let mut tx = vec![SPI_WRITE];
+                tx.extend_from_slice(frame);
+                transfer(device, &tx, None)
+            }
+        }
+    }
+
+    /// `len` bytes if the chip has a frame ready, none yet otherwise. On
+    /// I2C every read starts with the status byte.
+    fn read(&mut self, len: usize) -> Result<Option<Vec<u8>>, EspError> {
+        match self.bus {
+            Bus::I2c(port) => {
+                let mut buf = vec![0; len + 1];
+                esp!(unsafe {
+                    esp_idf_sys::i2c_master_read_from_device(
+                        port,
+                        I2C_ADDRESS,
+                        buf.as_mut_ptr(),
+                        buf.len(),
+                        ticks(I2C_TIMEOUT_MS),
+                    )
+                })?;
+                Ok((buf[0] & READY != 0).then(|| buf.split_off(1)))
+            }
+            Bus::Spi(device) => {
+                let mut status = [0; 2];
+                transfer(device, &[SPI_STATUS, 0], Some(&mut status))?;
+                if status[1] & READY == 0 {
+                    return Ok(None);
+                }
+                let mut tx = vec![0; len + 1];
+                tx[0] = SPI_READ;
+                let mut rx = vec![0; len + 1];
+                transfer(device, &tx, Some(&mut rx))?;
+                Ok(Some(rx.split_off(1)))
+            }
+        }
+    }
+
+    fn read_ready(&mut self, len: usize, timeout: Duration) -> Result<Vec<u8>> {
+        let started = Instant::now();
+        loop {
+            if let Some(bytes) = self.read(len)? {
+                return Ok(bytes);
+            }
+            if started.elapsed() >= timeout {
+                bail!("no answer from the PN532 within {} ms", timeout.as_millis());
+            }
+            thread::sleep(READY_POLL);
+        }
+    }
+
+    /// Sends `command` and returns the data of the chip's answer.
+    pub fn command(&mut self, command: u8, params: &[u8], timeout: Duration) -> Result<Vec<u8>> {
+        self.write(&pn532::encode(command, params))?;
+        let ack = self.read_ready(pn532::ACK.len(), ACK_TIMEOUT)?;
+        if !pn532::is_ack(&ack) {
+            bail!("the PN532 did not acknowledge command {:#04x}", command);
+        }
+        let frame = self.read_ready(RESPONSE_LEN, timeout)?;
+
+        Ok(pn532::decode(&frame, command)?.to_vec())
+    }
+
+    /// Checks that a PN532 answers, and sets it up to read tags.
+    pub fn configure(&mut self) -> Result<Firmware> {
+        let data = self.command(pn532::GET_FIRMWARE_VERSION, &[], COMMAND_TIMEOUT)?;
+        let firmware = pn532::parse_firmware(&data)?;
+        if firmware.ic != PN532_IC {
+            bail!("not a PN532 but IC {:#04x}", firmware.ic);
+        }
+        self.command(
+            pn532::SAM_CONFIGURATION,
+            &pn532::SAM_NORMAL,
+            COMMAND_TIMEOUT,
+        )?;
+        self.command(
+            pn532::RF_CONFIGURATION,
+            &pn532::PASSIVE_RETRIES,
+            COMMAND_TIMEOUT,
+        )?;
+
+        Ok(firmware)
+    }
+
+    /// The ISO14443A tag in the field, if any.
+    pub fn poll(&mut self) -> Result<Option<Target>> {
+        let data = self.command(
+            pn532::IN_LIST_PASSIVE_TARGET,
+            &pn532::LIST_ONE_14443A,
+            POLL_TIMEOUT,
+        )?;
+
+        Ok(pn532::parse_target(&data)?)
+    }
+}
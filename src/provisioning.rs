@@ -0,0 +1,199 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Result};
+use embedded_svc::http::server::registry::Registry;
+use embedded_svc::http::server::{Request, Response};
+use embedded_svc::http::{SendHeaders, SendStatus};
+use embedded_svc::io::Read;
+use embedded_svc::wifi::{AccessPointConfiguration, AuthMethod, Configuration, Wifi};
+use esp_idf_svc::http::server::{self, EspHttpServer};
+use esp_idf_svc::wifi::EspWifi;
+use hap_core::credentials::{self, Credentials};
+use log::{info, warn};
+use spin::Mutex;
+
+use crate::status_led::{self, Event};
+use crate::{config, console, factory_config, http, logging, nvs, system, wifi};
+
+const PAGE: &str =
+    "<!DOCTYPE html><html><head><meta name=\"viewport\" content=\"width=device-width\">\
+<title>Wi-Fi setup</title></head><body><h1>Wi-Fi setup</h1>\
+<form method=\"post\" action=\"/\">\
+<p><label>Network <input name=\"ssid\" maxlength=\"32\" required></label></p>\
+<p><label>Password <input name=\"password\" type=\"password\" maxlength=\"63\"></label></p>\
+<p><button>Save</button></p></form></body></html>";
+const MAX_FORM_LEN: usize = 512;
+const POLL_MS: u64 = 200;
+
+/// Credentials posted to the portal, taken by the waiting Wi-Fi task
+static SUBMITTED: Mutex<Option<Credentials>> = Mutex::new(None);
+
+/// Restarts into the setup portal. The pairings and the stored credentials
+/// stay untouched until new ones are accepted.
+pub fn request() -> ! {
+    let requested = nvs::Namespace::open(nvs::WIFI).and_then(|store| {
+        store.set_u8("reconf", 1)?;
+        store.commit()?;
+        Ok(())
+    });
+    if let Err(err) = requested {
+        warn!(target: logging::WIFI, "Storing the reconfiguration request failed: {:?}", err);
+    }
+
+    system::restart("Wi-Fi reconfiguration requested");
+}
+
+/// Whether the setup portal was requested before the restart; cleared, so a
+/// crash in the portal does not bring it back on every boot.
+pub fn take_request() -> bool {
+    let taken = nvs::Namespace::open(nvs::WIFI).and_then(|store| {
+        let requested = store.get_u8("reconf")? == Some(1);
+        if requested {
+            store.remove("reconf")?;
+            store.commit()?;
+        }
+        Ok(requested)
+    });
+
+    taken.unwrap_or_else(|err| {
+        warn!(target: logging::WIFI, "Reading the reconfiguration request failed: {:?}", err);
+        false
+    })
+}
+
+fn read_form(req: &mut impl Request) -> Result<String> {
+    let mut body = Vec::new();
+    let mut chunk = [0u8; 128];
+    let mut reader = req.reader();
+    loop {
+        let len = reader.read(&mut chunk)?;
+        if len == 0 {
+            break;
+        }
+        if body.len() + len > MAX_FORM_LEN {
+            bail!("the form is larger than {} bytes", MAX_FORM_LEN);
+        }
+        body.extend_from_slice(&chunk[..len]);
+    }
+
+    Ok(String::from_utf8(body)?)
+}
+
+fn start_portal() -> Result<EspHttpServer> {
+    let mut server = EspHttpServer::new(&server::Configuration {
+        http_port: config::HTTP_PORT,
+        max_open_sockets: config::HTTP_MAX_OPEN_SOCKETS,
+        ..Default::default()
+    })?;
+
+    server.handle_get("/", |_req, mut resp| {
+        resp.set_header("Content-Type", "text/html");
+        resp.send_str(PAGE)?;
+
+        Ok(())
+    })?;
+
+    server.handle_post("/", |mut req, mut resp| {
+        let parsed =
+            read_form(&mut req).and_then(|form| credentials::parse_form(&form).map_err(Into::into));
+        match parsed {
+            Ok(credentials) => {
+                info!(target: logging::WIFI, "Setup portal received {:?}", credentials);
+                *SUBMITTED.lock() = Some(credentials);
+                resp.send_str("Saved, connecting. This network goes away now.")?;
+            }
+            Err(err) => {
+                resp.set_status(400);
+                resp.send_str(&format!("{}", err))?;
+            }
+        }
+
+        Ok(())
+    })?;
+
+    Ok(server)
+}
+
+/// The setup network's name, with the setup id from the label so
+/// neighbouring devices can be told apart.
+fn ap_ssid() -> String {
+    format!("Smart-Outlet-{}", factory_config::get().setup_id)
+}
+
+fn restart_api() {
+    if let Err(err) = http::start() {
+        warn!(target: logging::HTTP, "Restarting the HTTP server failed: {:?}", err);
+    }
+}
+
+/// Serves the setup portal on an access point of our own until new
+/// credentials are posted, which replace the stored ones, or the timeout
+/// passes; `None` then, and the old credentials are still in place.
+pub fn run(wifi: &mut EspWifi) -> Result<Option<Credentials>> {
+    let auth_method = if config::SETUP_AP_PASSWORD.is_empty() {
+        AuthMethod::None
+    } else {
+        AuthMethod::WPA2Personal
+    };
+    wifi.set_configuration(&Configuration::AccessPoint(AccessPointConfiguration {
+        ssid: ap_ssid().as_str().into(),
+        password: config::SETUP_AP_PASSWORD.into(),
+        auth_method,
+        ..Default::default()
+    }))?;
+    // The portal serves on the API's port, which steps aside meanwhile
+    let api = http::stop();
+    let portal = start_portal();
+    if portal.is_err() && api {
+        restart_api();
+    }
+    let portal = portal?;
+    status_led::event(Event::Reconfiguring);
+    info!(
+        target: logging::WIFI,
+        "Setup portal on network {} for {} min",
+        ap_ssid(),
+        config::SETUP_TIMEOUT_SECS / 60
+    );
+
+    *SUBMITTED.lock() = None;
+    let deadline = Instant::now() + Duration::from_secs(config::SETUP_TIMEOUT_SECS);
+    let credentials = loop {
+        if let Some(credentials) = SUBMITTED.lock().take() {
+            break Some(credentials);
+        }
+        if Instant::now() >= deadline {
+            break None;
+        }
+        thread::sleep(Duration::from_millis(POLL_MS));
+    };
+
+    // Time for the response to reach the browser
+    thread::sleep(Duration::from_secs(1));
+    drop(portal);
+    if api {
+        restart_api();
+    }
+
+    match &credentials {
+        Some(credentials) => wifi::store_credentials(credentials)?,
+        None => warn!(
+            target: logging::WIFI,
+            "Setup portal timed out, keeping the previous credentials"
+        ),
+    }
+
+    Ok(credentials)
+}
+
+pub fn register_commands() {
+    console::register(
+        "wifi",
+        "Restart into the Wi-Fi setup portal, keeping the pairings ('wifi reconfigure')",
+        |args| match args {
+            ["reconfigure"] => request(),
+            _ => bail!("usage: wifi reconfigure"),
+        },
+    );
+}
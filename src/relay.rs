@@ -0,0 +1,397 @@
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Mutex, PoisonError};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use embedded_hal::digital::v2::OutputPin;
+use esp_idf_sys::{esp, EspError};
+use hap_core::actuation::{Actuator, Coil, Coils, Mode};
+use log::{info, warn};
+
+use crate::board::{self, Pull};
+use crate::config::RelayMode;
+use crate::{config, logging, pm};
+
+pub type Pin = Box<dyn OutputPin<Error = EspError> + Send>;
+
+/// A set of relay channels, numbered from 0.
+pub trait RelayBackend: Send + Sync {
+    fn channels(&self) -> u8;
+
+    fn set(&self, channel: u8, on: bool) -> Result<()>;
+
+    fn get(&self, channel: u8) -> Option<bool>;
+
+    /// Whether the transport failed on its last attempt, reported as StatusFault
+    fn faulted(&self) -> bool {
+        false
+    }
+}
+
+/// The pins of a GPIO relay channel, driven at `RELAY_ACTIVE_HIGH`.
+pub struct GpioCoils {
+    set: Pin,
+    reset: Option<Pin>,
+}
+
+impl Coils for GpioCoils {
+    type Error = anyhow::Error;
+
+    fn drive(&mut self, coil: Coil, active: bool) -> Result<()> {
+        let pin = match (coil, &mut self.reset) {
+            (Coil::Set, _) => &mut self.set,
+            (Coil::Reset, Some(reset)) => reset,
+            (Coil::Reset, None) => bail!("no reset coil"),
+        };
+        if active == config::RELAY_ACTIVE_HIGH {
+            pin.set_high()?;
+        } else {
+            pin.set_low()?;
+        }
+
+        Ok(())
+    }
+
+    fn wait(&mut self, ms: u32) {
+        thread::sleep(Duration::from_millis(ms as u64));
+    }
+}
+
+/// The actuation of the relay pin, from the configured mode.
+pub fn mode() -> Mode {
+    match config::RELAY_MODE {
+        RelayMode::Level => Mode::Level,
+        RelayMode::Pulse => Mode::Pulse {
+            width_ms: config::RELAY_PULSE_MS,
+        },
+        RelayMode::DualCoil => Mode::DualCoil {
+            width_ms: config::RELAY_PULSE_MS,
+        },
+    }
+}
+
+/// A channel of a GPIO relay: the set pin, and the reset pin of a dual-coil
+/// relay.
+pub fn gpio_channel(mode: Mode, set: Pin, reset: Option<Pin>) -> Result<Actuator<GpioCoils>> {
+    if matches!(mode, Mode::DualCoil { .. }) && reset.is_none() {
+        bail!("a dual-coil relay needs a reset pin");
+    }
+
+    Ok(Actuator::new(mode, GpioCoils { set, reset }))
+}
+
+/// Whether the contacts are closed, from the optocoupler across them.
+pub fn feedback_closed() -> Result<bool> {
+    board::AnyInputPin::new(board::RELAY_FEEDBACK_GPIO, Pull::Up)?;
+    let level = unsafe { esp_idf_sys::gpio_get_level(board::RELAY_FEEDBACK_GPIO) } != 0;
+
+    Ok(level == config::RELAY_FEEDBACK_ACTIVE_HIGH)
+}
+
+/// An impulse relay's pin says nothing about its contacts, and one latched
+/// on before the boot would be switched the wrong way by the first command
+/// while taken to be open; so the feedback input tells where there is one.
+pub fn sense_at_boot(channel: &Actuator<GpioCoils>) {
+    if !matches!(channel.mode(), Mode::Pulse { .. }) {
+        return;
+    }
+    if !config::RELAY_FEEDBACK_ENABLED {
+        warn!(
+            target: logging::OUTLET,
+            "Impulse relay taken to be open, nothing reads it back; one latched on switches the wrong way once (set ESP_HAP_RELAY_FEEDBACK=1 with a feedback input)"
+        );
+        return;
+    }
+
+    match feedback_closed() {
+        Ok(closed) => {
+            info!(
+                target: logging::OUTLET,
+                "Impulse relay reads {} at boot",
+                if closed { "closed" } else { "open" }
+            );
+            channel.sense(closed);
+        }
+        Err(err) => warn!(
+            target: logging::OUTLET,
+            "Reading the relay feedback failed, impulse relay taken to be open: {:?}", err
+        ),
+    }
+}
+
+/// Relays driven directly by GPIOs, each channel in its own actuation mode.
+pub struct GpioRelay {
+    channels: Vec<Actuator<GpioCoils>>,
+}
+
+impl GpioRelay {
+    /// Opens every channel; pulses a dual-coil relay's reset coil, as its
+    /// state is unknown until then.
+    pub fn new(channels: Vec<Actuator<GpioCoils>>) -> Self {
+        let relay = Self { channels };
+        for (channel, actuator) in relay.channels.iter().enumerate() {
+            info!(target: logging::OUTLET, "Relay channel {}: {:?}", channel, actuator.mode());
+            if let Err(err) = actuator.set(false) {
+                warn!(
+                    target: logging::OUTLET,
+                    "Opening relay channel {} failed: {:?}",
+                    channel,
+                    err
+                );
+            }
+        }
+
+        relay
+    }
+}
+
+/// Logs how a relay pin is wired up, and warns where the relay can close
+/// without the firmware: the pad's own pull during reset is towards the on
+/// level, or the pad reads other than the off level just driven.
+pub fn log_gpio(gpio: i32) {
+    let off = !config::RELAY_ACTIVE_HIGH;
+    let reset_pull = board::reset_pull(gpio);
+    let mut strength = 0;
+    unsafe { esp_idf_sys::gpio_get_drive_capability(gpio, &mut strength) };
+    let level = unsafe { esp_idf_sys::gpio_get_level(gpio) } != 0;
+    info!(
+        target: logging::OUTLET,
+        "Relay on GPIO{}: active {}, off drives {} with a {} meanwhile, {} in reset, drive strength {}, reads {}",
+        gpio,
+        if config::RELAY_ACTIVE_HIGH { "high" } else { "low" },
+        off as u8,
+        if off { Pull::Up } else { Pull::Down }.name(),
+        reset_pull.name(),
+        strength,
+        level as u8
+    );
+
+    let pulled_on = match reset_pull {
+        Pull::Up => config::RELAY_ACTIVE_HIGH,
+        Pull::Down => !config::RELAY_ACTIVE_HIGH,
+        Pull::Floating => false,
+    };
+    if pulled_on {
+        warn!(
+            target: logging::OUTLET,
+            "GPIO{} is pulled towards on during reset, the relay may click at every restart; fit an external resistor to {}",
+            gpio,
+            if off { "3V3" } else { "ground" }
+        );
+    }
+    if level != off {
+        warn!(
+            target: logging::OUTLET,
+            "GPIO{} reads {} driven {}, check ESP_HAP_RELAY_ACTIVE_HIGH and the wiring",
+            gpio,
+            level as u8,
+            off as u8
+        );
+    }
+}
+
+impl RelayBackend for GpioRelay {
+    fn channels(&self) -> u8 {
+        self.channels.len() as u8
+    }
+
+    /// Waits for a pulse under way on the channel; the state the tasks see
+    /// changes once the pulse is over.
+    fn set(&self, channel: u8, on: bool) -> Result<()> {
+        let Some(actuator) = self.channels.get(channel as usize) else {
+            bail!("no relay channel {}", channel);
+        };
+
+        actuator.set(on)
+    }
+
+    fn get(&self, channel: u8) -> Option<bool> {
+        let actuator = self.channels.get(channel as usize)?;
+        Some(actuator.state() == Some(true))
+    }
+}
+
+/// The CH340-style boards with a `A0 <channel> <state> <sum>` byte protocol.
+///
+/// The boards never answer, so the state is a shadow of what was sent last.
+pub struct UartRelay {
+    port: esp_idf_sys::uart_port_t,
+    channels: u8,
+    shadow: AtomicU32,
+    faulted: AtomicBool,
+    lock: Mutex<()>,
+    pm_lock: Option<pm::Lock>,
+}
+
+impl UartRelay {
+    pub fn new(
+        port: esp_idf_sys::uart_port_t,
+        tx_gpio: i32,
+        baud: u32,
+        channels: u8,
+    ) -> Result<Self> {
+        let mut uart_config: esp_idf_sys::uart_config_t = unsafe { std::mem::zeroed() };
+        uart_config.baud_rate = baud as i32;
+        uart_config.data_bits = esp_idf_sys::uart_word_length_t_UART_DATA_8_BITS;
+        uart_config.parity = esp_idf_sys::uart_parity_t_UART_PARITY_DISABLE;
+        uart_config.stop_bits = esp_idf_sys::uart_stop_bits_t_UART_STOP_BITS_1;
+        uart_config.flow_ctrl = esp_idf_sys::uart_hw_flowcontrol_t_UART_HW_FLOWCTRL_DISABLE;
+
+        // TX only; the driver needs an RX buffer regardless
+        esp!(unsafe { esp_idf_sys::uart_param_config(port, &uart_config) })?;
+        esp!(unsafe { esp_idf_sys::uart_set_pin(port, tx_gpio, -1, -1, -1) })?;
+        esp!(unsafe {
+            esp_idf_sys::uart_driver_install(port, 256, 0, 0, std::ptr::null_mut(), 0)
+        })?;
+        info!(
+            target: logging::OUTLET,
+            "UART relay board on UART{} (GPIO{}, {} baud), {} channels",
+            port,
+            tx_gpio,
+            baud,
+            channels
+        );
+
+        let relay = Self {
+            port,
+            channels,
+            shadow: AtomicU32::new(0),
+            faulted: AtomicBool::new(false),
+            lock: Mutex::new(()),
+            pm_lock: if config::PM_ENABLED {
+                Some(pm::Lock::apb_max(b"relay_uart\0")?)
+            } else {
+                None
+            },
+        };
+        for channel in 0..channels {
+            let _ = relay.set(channel, false);
+        }
+
+        Ok(relay)
+    }
+
+    fn frame(channel: u8, on: bool) -> [u8; 4] {
+        // The boards number their channels from 1
+        let header = 0xa0u8;
+        let channel = channel + 1;
+        let state = on as u8;
+        let sum = header.wrapping_add(channel).wrapping_add(state);
+
+        [header, channel, state, sum]
+    }
+
+    fn send(&self, frame: &[u8]) -> Result<()> {
+        let _pm = self.pm_lock.as_ref().map(pm::Lock::acquire);
+        let written =
+            unsafe { esp_idf_sys::uart_write_bytes(self.port, frame.as_ptr() as _, frame.len()) };
+        if written != frame.len() as i32 {
+            bail!("wrote {} of {} bytes", written, frame.len());
+        }
+
+        let ticks = 100 * esp_idf_sys::configTICK_RATE_HZ / 1000;
+        esp!(unsafe { esp_idf_sys::uart_wait_tx_done(self.port, ticks) })?;
+
+        Ok(())
+    }
+}
+
+impl RelayBackend for UartRelay {
+    fn channels(&self) -> u8 {
+        self.channels
+    }
+
+    fn set(&self, channel: u8, on: bool) -> Result<()> {
+        if channel >= self.channels {
+            bail!("no relay channel {}", channel);
+        }
+
+        let _lock = self.lock.lock().unwrap_or_else(PoisonError::into_inner);
+        let frame = Self::frame(channel, on);
+
+        let mut attempt = 1;
+        loop {
+            match self.send(&frame) {
+                Ok(()) => break,
+                Err(err) if attempt < config::RELAY_UART_ATTEMPTS => {
+                    warn!(
+                        target: logging::OUTLET,
+                        "UART relay write failed (attempt {}/{}): {:?}",
+                        attempt,
+                        config::RELAY_UART_ATTEMPTS,
+                        err
+                    );
+                    thread::sleep(Duration::from_millis(20));
+                    attempt += 1;
+                }
+                Err(err) => {
+                    self.faulted.store(true, Ordering::Relaxed);
+                    return Err(err);
+                }
+            }
+        }
+
+        // The boards drop frames sent back to back
+        thread::sleep(Duration::from_millis(config::RELAY_UART_FRAME_GAP_MS));
+
+        self.faulted.store(false, Ordering::Relaxed);
+        if on {
+            self.shadow.fetch_or(1 << channel, Ordering::Relaxed);
+        } else {
+            self.shadow.fetch_and(!(1 << channel), Ordering::Relaxed);
+        }
+
+        Ok(())
+    }
+
+    fn get(&self, channel: u8) -> Option<bool> {
+        (channel < self.channels).then(|| self.shadow.load(Ordering::Relaxed) & 1 << channel != 0)
+    }
+
+    fn faulted(&self) -> bool {
+        self.faulted.load(Ordering::Relaxed)
+    }
+}
+
+/// Relays that only log and keep their state, for the simulation.
+pub struct SimRelay {
+    channels: u8,
+    state: AtomicU32,
+}
+
+impl SimRelay {
+    pub fn new(channels: u8) -> Self {
+        info!(target: logging::OUTLET, "Simulated relay, {} channels", channels);
+
+        Self {
+            channels,
+            state: AtomicU32::new(0),
+        }
+    }
+}
+
+impl RelayBackend for SimRelay {
+    fn channels(&self) -> u8 {
+        self.channels
+    }
+
+    fn set(&self, channel: u8, on: bool) -> Result<()> {
+        if channel >= self.channels {
+            bail!("no relay channel {}", channel);
+        }
+
+        info!(target: logging::OUTLET, "Simulated relay channel {} {}", channel, if on { "on" } else { "off" });
+        if on {
+            self.state.fetch_or(1 << channel, Ordering::Relaxed);
+        } else {
+            self.state.fetch_and(!(1 << channel), Ordering::Relaxed);
+        }
+
+        Ok(())
+    }
+
+    fn get(&self, channel: u8) -> Option<bool> {
+        (channel < self.channels).then(|| self.state.load(Ordering::Relaxed) & 1 << channel != 0)
+    }
+}
@@ -0,0 +1,236 @@
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU8, Ordering};
+
+use anyhow::Result;
+use hap_core::interlock::MAX_CHANNELS;
+use hap_core::schema;
+use log::{info, warn};
+use spin::{Mutex, Once};
+
+use crate::config::RestorePolicy as Policy;
+use crate::{app_state, clock, config, diag, logging, nvs, system};
+
+/// The last outage: the heartbeat before it, and how long it lasted once the
+/// clock synced after it.
+#[derive(Clone, Copy, Debug, Default)]
+struct Outage {
+    since: Option<u32>,
+    secs: Option<u32>,
+}
+
+static STORE: Once<nvs::Namespace> = Once::new();
+static POLICY: AtomicU8 = AtomicU8::new(config::RESTORE_POLICY as u8);
+static LAST_ON: AtomicBool = AtomicBool::new(false);
+static OUTAGES: AtomicU32 = AtomicU32::new(0);
+static BROWNOUTS: AtomicU32 = AtomicU32::new(0);
+// This boot follows a brownout: the supply sagged and may do so again as
+// soon as the relay coil draws, so the relay stays off whatever the policy
+static AFTER_BROWNOUT: AtomicBool = AtomicBool::new(false);
+static LAST_OUTAGE: Mutex<Outage> = Mutex::new(Outage {
+    since: None,
+    secs: None,
+});
+// An outage at this boot whose length is not known yet
+static MEASURING: AtomicBool = AtomicBool::new(false);
+// Uptime of the last heartbeat written
+static LAST_BEAT: Mutex<Option<u32>> = Mutex::new(None);
+
+fn store() -> Result<&'static nvs::Namespace> {
+    STORE.try_call_once(|| nvs::Namespace::open(app_state::namespace()))
+}
+
+/// The last state of every relay channel, off where none is stored.
+fn relays(store: &nvs::Namespace) -> Result<Vec<u8>> {
+    let mut relays = [0u8; MAX_CHANNELS as usize];
+    let len = store
+        .get_blob(schema::RELAYS_KEY, &mut relays)?
+        .unwrap_or(0);
+
+    Ok(relays[..len].to_vec())
+}
+
+fn format_outage(outage: &Outage) -> String {
+    match (outage.since, outage.secs) {
+        (None, _) => "time unknown".into(),
+        (Some(since), None) => format!("after {}", clock::format_local(since as i64)),
+        (Some(since), Some(secs)) => format!(
+            "{}, about {} min",
+            clock::format_local(since as i64),
+            (secs + 30) / 60
+        ),
+    }
+}
+
+/// Loads the policy and last state and tells an outage from a restart: the
+/// dirty marker stays set unless the firmware shut down cleanly, and a
+/// power-on or brownout reset with it set means the power went away.
+pub fn init() -> Result<()> {
+    let store = store()?;
+    if let Some(policy) = store.get_u8("policy")?.and_then(Policy::from_u8) {
+        POLICY.store(policy as u8, Ordering::Relaxed);
+    }
+    let last_on = schema::relay_state(&relays(store)?, config::RELAY_CHANNEL);
+    LAST_ON.store(last_on, Ordering::Relaxed);
+    OUTAGES.store(store.get_u32("outages")?.unwrap_or(0), Ordering::Relaxed);
+    BROWNOUTS.store(store.get_u32("brownouts")?.unwrap_or(0), Ordering::Relaxed);
+    *LAST_OUTAGE.lock() = Outage {
+        since: store.get_u32("outage_at")?,
+        secs: store.get_u32("outage_secs")?,
+    };
+
+    let dirty = store.get_u8("dirty")? == Some(1);
+    let reason = diag::reset_reason();
+    if reason == "Brownout" {
+        AFTER_BROWNOUT.store(true, Ordering::Relaxed);
+        let count = BROWNOUTS.fetch_add(1, Ordering::Relaxed) + 1;
+        store.set_u32("brownouts", count)?;
+        warn!(
+            target: logging::DIAG,
+            "Brownout reset #{}, the relay stays off this boot instead of {:?}",
+            count,
+            policy()
+        );
+    }
+    if dirty && matches!(reason, "PowerOn" | "Brownout") {
+        let count = OUTAGES.fetch_add(1, Ordering::Relaxed) + 1;
+        let outage = Outage {
+            since: store.get_u32("beat")?,
+            secs: None,
+        };
+        store.set_u32("outages", count)?;
+        match outage.since {
+            Some(since) => store.set_u32("outage_at", since)?,
+            None => store.remove("outage_at")?,
+        }
+        store.remove("outage_secs")?;
+        warn!(
+            target: logging::DIAG,
+            "Power outage #{} ({}), relay {} per {:?}",
+            count,
+            format_outage(&outage),
+            if initial_state() { "on" } else { "off" },
+            policy()
+        );
+        *LAST_OUTAGE.lock() = outage;
+        MEASURING.store(true, Ordering::Relaxed);
+    } else if dirty {
+        info!(target: logging::DIAG, "Unclean restart ({}), not an outage", reason);
+    }
+
+    store.set_u8("dirty", 1)?;
+    store.commit()?;
+    system::on_shutdown(mark_clean);
+
+    Ok(())
+}
+
+fn mark_clean() {
+    let result = store().and_then(|store| {
+        store.set_u8("dirty", 0)?;
+        Ok(store.commit()?)
+    });
+    if let Err(err) = result {
+        warn!(target: logging::DIAG, "Clearing the dirty shutdown marker failed: {:?}", err);
+    }
+}
+
+pub fn policy() -> Policy {
+    Policy::from_u8(POLICY.load(Ordering::Relaxed)).unwrap_or(config::RESTORE_POLICY)
+}
+
+/// Stores the policy right away, a power cut right after the write still
+/// boots with it.
+pub fn set_policy(policy: Policy) -> Result<()> {
+    let store = store()?;
+    store.set_u8("policy", policy as u8)?;
+    store.commit()?;
+    POLICY.store(policy as u8, Ordering::Relaxed);
+    info!(target: logging::OUTLET, "Restore policy now {:?}", policy);
+
+    Ok(())
+}
+
+/// What the relay starts in; off once after a brownout.
+pub fn initial_state() -> bool {
+    if AFTER_BROWNOUT.load(Ordering::Relaxed) {
+        return false;
+    }
+
+    match policy() {
+        Policy::AlwaysOff => false,
+        Policy::AlwaysOn => true,
+        Policy::LastState => LAST_ON.load(Ordering::Relaxed),
+    }
+}
+
+/// Remembers the relay state for LastState; the safe state driven on the
+/// way to a restart is not the user's.
+pub fn record(on: bool) {
+    if system::is_shutting_down() || LAST_ON.swap(on, Ordering::Relaxed) == on {
+        return;
+    }
+
+    // Other channels' states are kept as stored
+    let result = store().and_then(|store| {
+        let mut relays = relays(store)?;
+        schema::set_relay_state(&mut relays, config::RELAY_CHANNEL, on);
+        store.set_blob(schema::RELAYS_KEY, &relays)?;
+        Ok(store.commit()?)
+    });
+    if let Err(err) = result {
+        warn!(target: logging::OUTLET, "Storing the relay state failed: {:?}", err);
+    }
+}
+
+/// Stores the time every HEARTBEAT_SECS, the last one before an outage is
+/// when it began; sizes up the outage once the clock synced after it.
+pub fn heartbeat() {
+    if !clock::is_synced() {
+        return;
+    }
+    let now = clock::epoch() as u32;
+
+    if MEASURING.swap(false, Ordering::Relaxed) {
+        let mut outage = LAST_OUTAGE.lock();
+        if let Some(since) = outage.since {
+            // Up to a heartbeat too long, plus the time until SNTP synced
+            let secs = now.saturating_sub(since);
+            outage.secs = Some(secs);
+            info!(target: logging::DIAG, "Power was out {}", format_outage(&outage));
+            if let Err(err) = store().and_then(|store| store.set_u32("outage_secs", secs)) {
+                warn!(target: logging::DIAG, "Storing the outage length failed: {:?}", err);
+            }
+        }
+    }
+
+    let uptime = diag::uptime_secs();
+    let mut last = LAST_BEAT.lock();
+    if last.is_some_and(|at| uptime - at < config::HEARTBEAT_SECS) {
+        return;
+    }
+    *last = Some(uptime);
+
+    let result = store().and_then(|store| {
+        store.set_u32("beat", now)?;
+        Ok(store.commit()?)
+    });
+    if let Err(err) = result {
+        warn!(target: logging::DIAG, "Storing the heartbeat failed: {:?}", err);
+    }
+}
+
+pub fn outages() -> u32 {
+    OUTAGES.load(Ordering::Relaxed)
+}
+
+pub fn brownouts() -> u32 {
+    BROWNOUTS.load(Ordering::Relaxed)
+}
+
+/// The last outage for diagnostics, empty if there was none.
+pub fn last_outage() -> String {
+    if outages() == 0 {
+        return String::new();
+    }
+
+    format_outage(&LAST_OUTAGE.lock())
+}
@@ -0,0 +1,348 @@
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Result};
+use hap_core::chime;
+use hap_core::gesture::Gesture;
+use hap_core::rfid::{Tamper, Uid, Whitelist, MAX_TAGS};
+use hap_core::sys::{perm, uuid};
+use hap_core::{CharSlot, ServiceBuilder, Value};
+use log::{info, warn};
+use spin::{Mutex, Once};
+
+use crate::button::{self, Event};
+use crate::config::{self, RfidBus};
+use crate::hap_sys::HAP;
+use crate::pn532::Pn532;
+use crate::{app_state, board, buzzer, console, diag, distance, logging, metrics, nvs, tasks, wdt};
+
+// Custom UUIDs, the SDK keeps the pointers so they have to be 'static
+const SERVICE_UUID: &[u8] = b"0000D9A0-28E5-4C3F-9B6E-5A1D7E3C9000\0";
+const REJECTED_UUID: &[u8] = b"0000D9A1-28E5-4C3F-9B6E-5A1D7E3C9000\0";
+
+const READ_ONLY: u16 = perm::PR | perm::EV;
+// Longer holds restart into the setup portal or reset the device
+const MAX_GESTURE_HOLD: Duration = Duration::from_millis(config::WIFI_SETUP_HOLD_MS);
+// A reader that stopped answering is set up again after this many failures
+const MAX_FAILED_POLLS: u32 = 10;
+
+// A whitelisted tag presses the garage door opener's button
+const _: () = assert!(
+    !config::RFID_ENABLED || config::GARAGE_DOOR_ENABLED,
+    "RFID unlock opens the garage door, set ESP_HAP_GARAGE_DOOR=1"
+);
+// The task feeds the watchdog once per poll
+const _: () = assert!(
+    config::RFID_POLL_MS < config::TASK_WDT_TIMEOUT_SECS as u64 * 1000 / 2,
+    "RFID poll interval"
+);
+
+static STORE: Once<nvs::Namespace> = Once::new();
+static STARTED: Once<()> = Once::new();
+static WHITELIST: Mutex<Whitelist> = Mutex::new(Whitelist::new());
+static TAMPER: Mutex<Tamper> = Mutex::new(Tamper::new(
+    config::RFID_TAMPER_REJECTS,
+    config::RFID_TAMPER_WINDOW_SECS,
+));
+static REJECTED: AtomicU32 = AtomicU32::new(0);
+/// Uptime until which the next unknown tag is enrolled, 0 when not enrolling
+static ENROLL_UNTIL: AtomicU32 = AtomicU32::new(0);
+
+static TAMPERED_CHAR: CharSlot = CharSlot::new();
+static REJECTED_CHAR: CharSlot = CharSlot::new();
+
+fn store() -> Result<&'static nvs::Namespace> {
+    STORE.try_call_once(|| nvs::Namespace::open(app_state::namespace()))
+}
+
+fn load() -> Result<Option<Whitelist>> {
+    let mut bytes = vec![0u8; 1 + MAX_TAGS * 11];
+    let Some(len) = store()?.get_blob("rfid_tags", &mut bytes)? else {
+        return Ok(None);
+    };
+
+    Ok(Some(Whitelist::decode(&bytes[..len])?))
+}
+
+fn save(whitelist: &Whitelist) -> Result<()> {
+    let store = store()?;
+    store.set_blob("rfid_tags", &whitelist.encode())?;
+    store.commit()?;
+
+    Ok(())
+}
+
+fn add(uid: Uid) -> Result<bool> {
+    let mut whitelist = WHITELIST.lock();
+    let added = whitelist.add(uid)?;
+    if added {
+        save(&whitelist)?;
+        info!(target: logging::RFID, "Tag {} enrolled", uid);
+    }
+
+    Ok(added)
+}
+
+fn remove(uid: &Uid) -> Result<bool> {
+    let mut whitelist = WHITELIST.lock();
+    let removed = whitelist.remove(uid);
+    if removed {
+        save(&whitelist)?;
+        info!(target: logging::RFID, "Tag {} removed", uid);
+    }
+
+    Ok(removed)
+}
+
+/// Enrolls the next unknown tag presented within RFID_ENROLL_SECS.
+pub fn enroll() {
+    ENROLL_UNTIL.store(
+        diag::uptime_secs() + config::RFID_ENROLL_SECS,
+        Ordering::Relaxed,
+    );
+    buzzer::play(&chime::PAIRING_STARTED);
+    info!(
+        target: logging::RFID,
+        "Present the tag to enroll within {} s",
+        config::RFID_ENROLL_SECS
+    );
+}
+
+fn enrolling() -> bool {
+    let until = ENROLL_UNTIL.load(Ordering::Relaxed);
+    if until == 0 {
+        return false;
+    }
+    if diag::uptime_secs() >= until {
+        ENROLL_UNTIL.store(0, Ordering::Relaxed);
+        info!(target: logging::RFID, "Enrollment timed out");
+        return false;
+    }
+
+    true
+}
+
+fn set_tampered(tampered: bool) {
+    if let Some(hc) = TAMPERED_CHAR.get() {
+        HAP.update(hc, &Value::Uint8(tampered as u8));
+    }
+}
+
+fn accept(uid: Uid) {
+    info!(target: logging::RFID, "Tag {} accepted", uid);
+    buzzer::play(&chime::TAG_ACCEPTED);
+    if TAMPER.lock().clear() {
+        info!(target: logging::RFID, "Tamper flag cleared");
+        set_tampered(false);
+    }
+    if let Err(err) = distance::open() {
+        warn!(target: logging::RFID, "Unlocking failed: {:?}", err);
+    }
+}
+
+fn reject(uid: Uid) {
+    let count = REJECTED.fetch_add(1, Ordering::Relaxed) + 1;
+    warn!(target: logging::RFID, "Unknown tag {} rejected, {} so far", uid, count);
+    buzzer::play(&chime::TAG_REJECTED);
+    if let Some(hc) = REJECTED_CHAR.get() {
+        HAP.update(hc, &Value::Uint32(count));
+    }
+    if TAMPER.lock().reject(diag::uptime_secs()) {
+        warn!(
+            target: logging::RFID,
+            "{} unknown tags within {} s, tampering reported",
+            config::RFID_TAMPER_REJECTS,
+            config::RFID_TAMPER_WINDOW_SECS
+        );
+        set_tampered(true);
+    }
+}
+
+/// One presentation of a tag: enrolled while enrolling, accepted or
+/// rejected otherwise.
+fn on_tag(uid: Uid) {
+    if enrolling() {
+        ENROLL_UNTIL.store(0, Ordering::Relaxed);
+        match add(uid) {
+            Ok(true) => {
+                buzzer::play(&chime::PAIRED);
+            }
+            Ok(false) => info!(target: logging::RFID, "Tag {} is whitelisted already", uid),
+            Err(err) => warn!(target: logging::RFID, "Enrolling {} failed: {:?}", uid, err),
+        }
+        return;
+    }
+
+    if WHITELIST.lock().contains(&uid) {
+        accept(uid);
+    } else {
+        reject(uid);
+    }
+}
+
+fn on_button(name: &'static str, event: Event) {
+    if name == button::BOOT.name && Gesture::of(event, MAX_GESTURE_HOLD) == Some(Gesture::Long) {
+        enroll();
+    }
+}
+
+fn open_reader() -> Result<Pn532> {
+    match config::RFID_BUS {
+        RfidBus::I2c => Pn532::i2c(
+            board::RFID_I2C_PORT,
+            board::RFID_SDA_GPIO,
+            board::RFID_SCL_GPIO,
+            config::RFID_I2C_HZ,
+        ),
+        RfidBus::Spi => Pn532::spi(
+            board::RFID_SPI_HOST,
+            board::RFID_SCK_GPIO,
+            board::RFID_MISO_GPIO,
+            board::RFID_MOSI_GPIO,
+            board::RFID_CS_GPIO,
+            config::RFID_SPI_HZ,
+        ),
+    }
+}
+
+fn rfid_handler(mut reader: Pn532) {
+    let watchdog = wdt::subscribe(tasks::RFID.name);
+    let interval = Duration::from_millis(config::RFID_POLL_MS);
+    let repeat = Duration::from_millis(config::RFID_REPEAT_MS);
+    // The tag last in the field and when it was last seen, so one held
+    // against the reader counts once
+    let mut last: Option<(Uid, Instant)> = None;
+    let mut failed = 0;
+
+    loop {
+        match reader.poll() {
+            Ok(Some(target)) => {
+                failed = 0;
+                let again =
+                    last.is_some_and(|(uid, seen)| uid == target.uid && seen.elapsed() < repeat);
+                last = Some((target.uid, Instant::now()));
+                if !again {
+                    on_tag(target.uid);
+                }
+            }
+            Ok(None) => failed = 0,
+            Err(err) => {
+                failed += 1;
+                if failed == MAX_FAILED_POLLS {
+                    warn!(target: logging::RFID, "Reader not answering: {:?}", err);
+                    if let Err(err) = reader.configure() {
+                        warn!(target: logging::RFID, "Setting up the reader again failed: {:?}", err);
+                    }
+                    failed = 0;
+                }
+            }
+        }
+
+        watchdog.feed();
+        thread::sleep(interval);
+    }
+}
+
+/// Loads the whitelist, sets up the PN532 and polls it for tags on a task
+/// of its own.
+pub fn init() -> Result<()> {
+    match load() {
+        Ok(Some(whitelist)) => *WHITELIST.lock() = whitelist,
+        Ok(None) => {}
+        Err(err) => warn!(target: logging::RFID, "Loading the tag whitelist failed: {:?}", err),
+    }
+
+    let mut reader = open_reader()?;
+    let firmware = reader.configure()?;
+    info!(
+        target: logging::RFID,
+        "PN532 firmware {}.{} on {:?}, {} tags whitelisted",
+        firmware.version,
+        firmware.revision,
+        config::RFID_BUS,
+        WHITELIST.lock().tags().len()
+    );
+
+    button::subscribe(on_button);
+    STARTED.call_once(|| ());
+    tasks::spawn(&tasks::RFID, move || rfid_handler(reader))
+}
+
+/// A custom service for the reader, with StatusTampered and the number of
+/// rejected tags.
+pub fn service() -> Result<ServiceBuilder> {
+    if STARTED.get().is_none() {
+        bail!("the reader is not set up");
+    }
+
+    let tampered = TAMPER.lock().is_tampered();
+    Ok(ServiceBuilder::custom(SERVICE_UUID)
+        .name("RFID Reader")
+        .char(
+            uuid::STATUS_TAMPERED,
+            READ_ONLY,
+            Value::Uint8(tampered as u8),
+        )
+        .char(
+            REJECTED_UUID,
+            READ_ONLY,
+            Value::Uint32(REJECTED.load(Ordering::Relaxed)),
+        )
+        .bind(uuid::STATUS_TAMPERED, &TAMPERED_CHAR)
+        .bind(REJECTED_UUID, &REJECTED_CHAR))
+}
+
+pub fn register_metrics() {
+    metrics::register("rfid_rejected", || REJECTED.load(Ordering::Relaxed) as i64);
+}
+
+pub fn register_commands() {
+    console::register(
+        "rfid",
+        "Show the reader ('rfid'), list, add or delete whitelisted tags ('rfid list', 'rfid add <uid>', 'rfid del <uid>'), enroll the next tag presented ('rfid enroll') or clear the tamper flag ('rfid clear')",
+        |args| match args {
+            [] => {
+                println!(
+                    "{} tags whitelisted, {} rejected{}",
+                    WHITELIST.lock().tags().len(),
+                    REJECTED.load(Ordering::Relaxed),
+                    if TAMPER.lock().is_tampered() { ", tampered" } else { "" }
+                );
+                if enrolling() {
+                    println!("Enrolling");
+                }
+                Ok(())
+            }
+            ["list"] => {
+                for uid in WHITELIST.lock().tags() {
+                    println!("{}", uid);
+                }
+                Ok(())
+            }
+            ["add", uid] => {
+                if !add(uid.parse()?)? {
+                    println!("Already whitelisted");
+                }
+                Ok(())
+            }
+            ["del", uid] => {
+                if !remove(&uid.parse()?)? {
+                    bail!("{} is not whitelisted", uid);
+                }
+                Ok(())
+            }
+            ["enroll"] => {
+                enroll();
+                Ok(())
+            }
+            ["clear"] => {
+                if TAMPER.lock().clear() {
+                    set_tampered(false);
+                }
+                Ok(())
+            }
+            _ => bail!("usage: rfid [list | add <uid> | del <uid> | enroll | clear]"),
+        },
+    );
+}
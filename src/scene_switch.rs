@@ -0,0 +1,130 @@
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Result};
+use hap_core::audit::Origin;
+use hap_core::gesture::{Action, Gesture, Mapping};
+use hap_core::sys::perm;
+use hap_core::{CharSlot, ServiceBuilder, Value};
+use log::{info, warn};
+use spin::{Mutex, Once};
+
+use crate::button::{self, Event};
+use crate::hap_sys::HAP;
+use crate::outlet::OutletRunner;
+use crate::{app_state, config, console, logging, nvs};
+
+// Apple's Stateless Programmable Switch and its Programmable Switch Event
+const SWITCH_UUID: &[u8] = b"89\0";
+const EVENT_UUID: &[u8] = b"73\0";
+
+// Longer holds restart into the setup portal or reset the device
+const MAX_GESTURE_HOLD: Duration = Duration::from_millis(config::WIFI_SETUP_HOLD_MS);
+
+static MAPPING: Mutex<Mapping> = Mutex::new(Mapping::new());
+static STORE: Once<nvs::Namespace> = Once::new();
+static OUTLET: Once<&'static OutletRunner> = Once::new();
+
+static EVENT_CHAR: CharSlot = CharSlot::new();
+
+fn store() -> Result<&'static nvs::Namespace> {
+    STORE.try_call_once(|| nvs::Namespace::open(app_state::namespace()))
+}
+
+fn load() -> Result<Option<Mapping>> {
+    let mut bytes = [0u8; 3];
+    let Some(len) = store()?.get_blob("gestures", &mut bytes)? else {
+        return Ok(None);
+    };
+
+    Ok(Mapping::decode(&bytes[..len]))
+}
+
+fn set_action(gesture: Gesture, action: Action) -> Result<()> {
+    let mapping = {
+        let mut mapping = MAPPING.lock();
+        mapping.set(gesture, action);
+        *mapping
+    };
+    let store = store()?;
+    store.set_blob("gestures", &mapping.encode())?;
+    store.commit()?;
+    info!(target: logging::BUTTON, "Gesture {} now {}", gesture.name(), action.name());
+
+    Ok(())
+}
+
+/// Applies the gesture's action to the outlet, then reports the gesture, so
+/// the local part never waits for a home hub.
+fn on_button(name: &'static str, event: Event) {
+    if name != button::BOOT.name {
+        return;
+    }
+    let Some(gesture) = Gesture::of(event, MAX_GESTURE_HOLD) else {
+        return;
+    };
+    // The hold enrolls RFID tags instead, see rfid::enroll
+    if config::RFID_ENABLED && gesture == Gesture::Long {
+        return;
+    }
+
+    let action = MAPPING.lock().action(gesture);
+    if let Some(outlet) = OUTLET.get() {
+        let switched = outlet.with(|outlet| {
+            let on = action.apply(outlet.is_on())?;
+            outlet.set(on, Origin::Local);
+            Some(outlet.is_on())
+        });
+        if let Some(on) = switched {
+            info!(target: logging::OUTLET, "{} press: switched locally, now {}", gesture.name(), on);
+        }
+    }
+
+    if let Some(hc) = EVENT_CHAR.get() {
+        HAP.update(hc, &Value::Uint8(gesture as u8));
+    }
+}
+
+/// Loads the gesture table and takes over the boot button's gestures.
+pub fn init(outlet: &'static OutletRunner) {
+    match load() {
+        Ok(Some(mapping)) => *MAPPING.lock() = mapping,
+        Ok(None) => {}
+        Err(err) => warn!(target: logging::BUTTON, "Loading the gesture table failed: {:?}", err),
+    }
+    OUTLET.call_once(|| outlet);
+
+    button::subscribe(on_button);
+}
+
+/// The boot button, single, double and long press as a scene trigger each.
+pub fn service() -> ServiceBuilder {
+    ServiceBuilder::custom(SWITCH_UUID)
+        .name("Button")
+        .char(EVENT_UUID, perm::PR | perm::EV, Value::Uint8(0))
+        .valid_values(EVENT_UUID, Gesture::VALUES)
+        .bind(EVENT_UUID, &EVENT_CHAR)
+}
+
+pub fn register_commands() {
+    console::register(
+        "gesture",
+        "Show what the boot button's gestures do locally ('gesture') or set it ('gesture single|double|long none|toggle|on|off')",
+        |args| match args {
+            [] => {
+                let mapping = *MAPPING.lock();
+                for gesture in Gesture::ALL {
+                    println!("{}: {}", gesture.name(), mapping.action(gesture).name());
+                }
+                Ok(())
+            }
+            [gesture, action] => {
+                let gesture =
+                    Gesture::from_name(gesture).ok_or_else(|| anyhow!("no gesture {}", gesture))?;
+                let action =
+                    Action::from_name(action).ok_or_else(|| anyhow!("no action {}", action))?;
+                set_action(gesture, action)
+            }
+            _ => bail!("usage: gesture [single|double|long none|toggle|on|off]"),
+        },
+    );
+}
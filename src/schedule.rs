@@ -0,0 +1,248 @@
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Result};
+use hap_core::audit::Origin;
+use hap_core::schedule::{self, Entry, ScheduleError, WEEK_MINUTES};
+use hap_core::sys::perm;
+use hap_core::{CharSlot, Schedule, ServiceBuilder, Status, Value, Write};
+use log::{info, warn};
+use spin::{Mutex, Once};
+
+use crate::hap_sys::HAP;
+use crate::outlet::OutletRunner;
+use crate::{clock, config, console, logging, nvs, tasks, wdt};
+
+// Custom UUIDs, the SDK keeps the pointers so they have to be 'static
+const SERVICE_UUID: &[u8] = b"0000D4A0-28E5-4C3F-9B6E-5A1D7E3C9000\0";
+const TABLE_UUID: &[u8] = b"0000D4A1-28E5-4C3F-9B6E-5A1D7E3C9000\0";
+
+const KEY: &str = "table";
+// Version and revision items, then six bytes per entry
+const MAX_LEN: usize = 9 + schedule::MAX_ENTRIES * 6;
+
+// Longer gaps are clock steps rather than a late wake-up; covers the hour
+// skipped when daylight saving time starts
+const REPLAY_LIMIT_MINUTES: u32 = 90;
+
+static SCHEDULE: Mutex<Schedule> = Mutex::new(Schedule::new());
+static STORE: Once<nvs::Namespace> = Once::new();
+static TABLE_CHAR: CharSlot = CharSlot::new();
+static OUTLET: Once<&'static OutletRunner> = Once::new();
+
+fn store() -> Result<&'static nvs::Namespace> {
+    STORE.try_call_once(|| nvs::Namespace::open(nvs::SCHED))
+}
+
+/// Loads the stored table; a damaged one is dropped rather than followed.
+pub fn init() {
+    let mut buf = [0u8; MAX_LEN];
+    let loaded = store().and_then(|store| Ok(store.get_blob(KEY, &mut buf)?));
+    let schedule = match loaded {
+        Ok(Some(len)) => Schedule::decode(&buf[..len]).map_err(|err| anyhow!(err)),
+        Ok(None) => Ok(Schedule::new()),
+        Err(err) => Err(err),
+    };
+
+    match schedule {
+        Ok(schedule) => {
+            info!(
+                target: logging::OUTLET,
+                "Schedule: {} entries, revision {}",
+                schedule.entries().len(),
+                schedule.revision()
+            );
+            *SCHEDULE.lock() = schedule;
+        }
+        Err(err) => warn!(target: logging::OUTLET, "Stored schedule unusable: {:?}", err),
+    }
+}
+
+/// Applies `f` to a copy of the table and keeps the result once it is stored.
+fn change<T>(f: impl FnOnce(&mut Schedule) -> Result<T, ScheduleError>) -> Result<T> {
+    let mut schedule = SCHEDULE.lock();
+    let mut changed = schedule.clone();
+    let result = f(&mut changed)?;
+
+    let encoded = changed.encode();
+    let store = store()?;
+    store.set_blob(KEY, &encoded)?;
+    store.commit()?;
+    *schedule = changed;
+    drop(schedule);
+
+    if let Some(hc) = TABLE_CHAR.get() {
+        HAP.update(hc, &Value::Tlv8(encoded));
+    }
+
+    Ok(result)
+}
+
+fn on_write(write: &Write) -> Result<(), Status> {
+    let Value::Tlv8(bytes) = &write.value else {
+        return Err(Status::InvalidValue);
+    };
+
+    match change(|schedule| schedule.apply_write(bytes)) {
+        Ok(()) => {
+            info!(target: logging::OUTLET, "Schedule replaced by a controller");
+            Ok(())
+        }
+        Err(err) if err.is::<ScheduleError>() => {
+            warn!(target: logging::OUTLET, "Schedule write refused: {}", err);
+            Err(Status::InvalidValue)
+        }
+        Err(err) => {
+            warn!(target: logging::OUTLET, "Storing the schedule failed: {:?}", err);
+            Err(Status::NoResource)
+        }
+    }
+}
+
+/// The table as a TLV8 characteristic: reads return it with its revision,
+/// a write replaces it, see `hap_core::schedule` for the format.
+pub fn service() -> ServiceBuilder {
+    ServiceBuilder::custom(SERVICE_UUID)
+        .name("Schedule")
+        .char(
+            TABLE_UUID,
+            perm::PR | perm::PW | perm::EV,
+            Value::Tlv8(SCHEDULE.lock().encode()),
+        )
+        .bind(TABLE_UUID, &TABLE_CHAR)
+        .on_write(&on_write)
+}
+
+fn switch(on: bool, reason: &str) {
+    let Some(outlet) = OUTLET.get() else {
+        return;
+    };
+
+    info!(target: logging::OUTLET, "Schedule: switching {} ({})", if on { "on" } else { "off" }, reason);
+    outlet.with(|outlet| outlet.set(on, Origin::Schedule));
+}
+
+/// One evaluation, with the minute of the week evaluated last.
+fn evaluate(last: &mut Option<u32>) {
+    let Some(now) = clock::local_time() else {
+        // Without a synced clock every entry would fire at a bogus time
+        if last.take().is_some() {
+            warn!(target: logging::OUTLET, "Schedule suspended, the clock is not synced");
+        }
+        return;
+    };
+    let at = schedule::minute_of_week(now.weekday, now.hour, now.minute);
+    let schedule = SCHEDULE.lock().clone();
+
+    let Some(previous) = last.replace(at) else {
+        if config::SCHEDULE_CATCH_UP {
+            if let Some(on) = schedule.latest(at, WEEK_MINUTES) {
+                switch(on, "catching up");
+            }
+        }
+        return;
+    };
+
+    let elapsed = (at + WEEK_MINUTES - previous) % WEEK_MINUTES;
+    if elapsed == 0 {
+        return;
+    }
+    if elapsed <= REPLAY_LIMIT_MINUTES {
+        if let Some(on) = schedule.latest(at, elapsed) {
+            switch(on, "entry due");
+        }
+    } else {
+        warn!(target: logging::OUTLET, "Schedule: clock stepped by {} min", elapsed);
+        if config::SCHEDULE_CATCH_UP {
+            if let Some(on) = schedule.latest(at, WEEK_MINUTES) {
+                switch(on, "catching up");
+            }
+        }
+    }
+}
+
+/// Follows the table from now on, switching `outlet` like local control so
+/// HomeKit is notified.
+pub fn start(outlet: &'static OutletRunner) -> Result<()> {
+    OUTLET.call_once(|| outlet);
+
+    tasks::spawn(&tasks::SCHEDULE, || {
+        let watchdog = wdt::subscribe(tasks::SCHEDULE.name);
+        let mut last = None;
+        loop {
+            evaluate(&mut last);
+            // Early in the next minute
+            let secs = 60 - clock::epoch().rem_euclid(60) as u64 + 1;
+            watchdog.sleep(Duration::from_secs(secs));
+        }
+    })
+}
+
+fn parse_days(days: &str) -> Result<u8> {
+    const NAMES: [&str; 7] = ["su", "mo", "tu", "we", "th", "fr", "sa"];
+    match days {
+        "daily" => return Ok(0x7f),
+        "weekdays" => return Ok(0b0111110),
+        "weekend" => return Ok(0b1000001),
+        _ => {}
+    }
+
+    days.split(',').try_fold(0, |mask, day| {
+        let index = NAMES
+            .iter()
+            .position(|name| name.eq_ignore_ascii_case(day))
+            .ok_or_else(|| anyhow!("unknown day '{}', use su,mo,..,sa", day))?;
+        Ok(mask | 1 << index)
+    })
+}
+
+fn parse_entry(days: &str, time: &str, action: &str) -> Result<Entry> {
+    let (hour, minute) = time
+        .split_once(':')
+        .and_then(|(h, m)| Some((h.parse().ok()?, m.parse().ok()?)))
+        .ok_or_else(|| anyhow!("time '{}' is not HH:MM", time))?;
+    let on = match action {
+        "on" => true,
+        "off" => false,
+        _ => bail!("action '{}' is neither on nor off", action),
+    };
+
+    Ok(Entry {
+        days: parse_days(days)?,
+        hour,
+        minute,
+        on,
+    })
+}
+
+pub fn register_commands() {
+    console::register(
+        "sched",
+        "List ('sched list'), add ('sched add <days> <HH:MM> <on|off>') or delete ('sched del <n>') schedule entries",
+        |args| match args {
+            ["list"] => {
+                let schedule = SCHEDULE.lock().clone();
+                println!("Revision {}", schedule.revision());
+                for (i, entry) in schedule.entries().iter().enumerate() {
+                    println!("{:>2}  {}", i, entry);
+                }
+                if !clock::is_synced() {
+                    println!("Suspended until the clock is synced");
+                }
+                Ok(())
+            }
+            ["add", days, time, action] => {
+                let entry = parse_entry(days, time, action)?;
+                change(|schedule| schedule.add(entry))?;
+                println!("Added {}", entry);
+                Ok(())
+            }
+            ["del", index] => {
+                let index = index.parse()?;
+                let entry = change(|schedule| schedule.remove(index))?;
+                println!("Deleted {}", entry);
+                Ok(())
+            }
+            _ => bail!("usage: sched list | sched add <days> <HH:MM> <on|off> | sched del <n>"),
+        },
+    );
+}
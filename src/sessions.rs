@@ -0,0 +1,165 @@
+use std::ffi::c_void;
+
+use hap_core::sessions::{self as tracker, Session, Sessions};
+use hap_core::sys::HAP_SUCCESS;
+use hap_core::ControllerId;
+use log::{info, warn};
+use spin::Mutex;
+
+use crate::hap_sys::{self, HAP};
+use crate::{config, console, diag, logging, metrics};
+
+static SESSIONS: Mutex<Sessions> = Mutex::new(Sessions::new());
+
+fn on_request(request: *mut c_void) {
+    if let Some(controller) = hap_sys::request_controller(request) {
+        let socket = hap_sys::request_socket(request);
+        SESSIONS
+            .lock()
+            .active(controller, socket, diag::uptime_secs());
+    }
+}
+
+/// Closes the socket of the session idle the longest once the heap runs
+/// below the floor, rather than have the next pair-verify fail.
+fn shed_if_low(free: u32) {
+    if config::SESSION_SHED_FREE_HEAP == 0 {
+        return;
+    }
+    let now = diag::uptime_secs();
+    let shed = SESSIONS.lock().shed(
+        HAP.sys(),
+        free,
+        config::SESSION_SHED_FREE_HEAP,
+        config::SESSION_SHED_MIN_IDLE_SECS,
+        now,
+    );
+    let Some((session, err)) = shed else {
+        return;
+    };
+
+    if err == HAP_SUCCESS {
+        warn!(
+            target: logging::HAP,
+            "{} B heap free, below {} B: closing a session of {}, idle for {} s",
+            free,
+            config::SESSION_SHED_FREE_HEAP,
+            session.controller,
+            now.saturating_sub(session.last_active)
+        );
+    } else {
+        warn!(
+            target: logging::HAP,
+            "Closing a session of {} failed: {}",
+            session.controller,
+            err
+        );
+    }
+}
+
+pub fn connected(controller: ControllerId) {
+    let free = diag::sample_heap().free;
+    let (open, session) = {
+        let mut sessions = SESSIONS.lock();
+        sessions.connected(controller, diag::uptime_secs(), free);
+        (
+            sessions.sessions().len(),
+            sessions.sessions().last().copied(),
+        )
+    };
+    info!(
+        target: logging::HAP,
+        "Controller {} connected, {} sessions open, took {} B, {} B heap free",
+        controller,
+        open,
+        session.map_or(0, |session| session.heap_bytes),
+        free
+    );
+    if open as u32 >= config::HAP_MAX_SESSIONS {
+        warn!(
+            target: logging::HAP,
+            "{} sessions open, the SDK takes no more than {}",
+            open,
+            config::HAP_MAX_SESSIONS
+        );
+    }
+
+    shed_if_low(free);
+}
+
+pub fn disconnected(controller: ControllerId) {
+    let free = diag::sample_heap().free;
+    let session = SESSIONS.lock().disconnected(controller, free);
+    match session {
+        Some(session) => info!(
+            target: logging::HAP,
+            "Controller {} disconnected after {} s, {} B heap free",
+            controller,
+            diag::uptime_secs().saturating_sub(session.opened),
+            free
+        ),
+        None => info!(target: logging::HAP, "Controller {} disconnected", controller),
+    }
+}
+
+/// Samples the heap for the next session to be measured against, and sheds
+/// one if it runs low between connects.
+pub fn poll() {
+    let free = diag::sample_heap().free;
+    SESSIONS.lock().sample(free);
+    shed_if_low(free);
+}
+
+pub fn init() {
+    tracker::on_request(on_request);
+}
+
+pub fn register_metrics() {
+    metrics::register("hap_sessions_open", || SESSIONS.lock().stats().open as i64);
+    metrics::register("hap_sessions_opened", || {
+        SESSIONS.lock().stats().opened as i64
+    });
+    metrics::register("hap_sessions_shed", || SESSIONS.lock().stats().shed as i64);
+    metrics::register("hap_sessions_max", || config::HAP_MAX_SESSIONS as i64);
+    metrics::register("hap_session_heap_bytes", || {
+        SESSIONS.lock().stats().heap_per_session as i64
+    });
+    metrics::register("hap_session_heap_returned_bytes", || {
+        SESSIONS.lock().stats().heap_returned as i64
+    });
+}
+
+pub fn register_commands() {
+    console::register(
+        "sessions",
+        "Show the open HAP sessions, the idlest first",
+        |_| {
+            let now = diag::uptime_secs();
+            let mut sessions: Vec<Session> = SESSIONS.lock().sessions().to_vec();
+            sessions.sort_by_key(|session| session.last_active);
+            for session in &sessions {
+                println!(
+                    "{:<40} socket {:>3}, open {:>6} s, idle {:>6} s, {:>6} B",
+                    session.controller.as_str(),
+                    session
+                        .socket
+                        .map_or("-".into(), |socket| socket.to_string()),
+                    now.saturating_sub(session.opened),
+                    now.saturating_sub(session.last_active),
+                    session.heap_bytes
+                );
+            }
+            let stats = SESSIONS.lock().stats();
+            println!(
+                "{} open of {}, {} opened, {} shed, {} B taken and {} B returned per session",
+                stats.open,
+                config::HAP_MAX_SESSIONS,
+                stats.opened,
+                stats.shed,
+                stats.heap_per_session,
+                stats.heap_returned
+            );
+            Ok(())
+        },
+    );
+}
@@ -0,0 +1,169 @@
+use std::f32::consts::TAU;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Result};
+use hap_core::soil;
+use log::info;
+use spin::{Mutex, Once};
+
+use crate::button::{self, Event};
+use crate::energy_meter::Quantity;
+use crate::relay::{RelayBackend, SimRelay};
+use crate::{config, console, diag, encoder, logging, touch};
+
+// No stubs for these, they would drive a motor against nothing or time out
+// on every measurement
+const _: () = assert!(
+    !config::SIMULATION
+        || !(config::DOOR_ENABLED || config::DISTANCE_ENABLED || config::TEMP_ENABLED),
+    "the simulation has no door, distance sensor or temperature probes"
+);
+
+// A soil that dries out and gets watered over ten minutes
+const SOIL_PERIOD_SECS: f32 = 600.0;
+// The grid wanders a little around its nominal voltage
+const MAINS_VOLTS: f32 = 230.0;
+const MAINS_WANDER_VOLTS: f32 = 2.0;
+const STANDBY_WATTS: f32 = 0.4;
+// The share of the way to the new load covered per meter poll
+const POWER_RAMP: f32 = 0.5;
+
+struct Meter {
+    watts: f32,
+    kwh: f32,
+}
+
+static RELAY: Once<&'static SimRelay> = Once::new();
+static METER: Mutex<Meter> = Mutex::new(Meter {
+    watts: 0.0,
+    kwh: 0.0,
+});
+/// A soil reading pinned from the console, -1 follows the wave
+static SOIL_RAW: AtomicI32 = AtomicI32::new(-1);
+
+/// The simulated relay, the same one for every caller.
+pub fn relay() -> &'static SimRelay {
+    RELAY.call_once(|| {
+        Box::leak(Box::new(SimRelay::new(
+            config::RELAY_UART_CHANNELS.max(config::RELAY_CHANNEL + 1),
+        )))
+    })
+}
+
+fn relay_on() -> bool {
+    RELAY
+        .get()
+        .and_then(|relay| relay.get(config::RELAY_CHANNEL))
+        .unwrap_or(false)
+}
+
+/// One poll of the simulated meter: the power ramps towards the load the
+/// relay switches, the energy register follows.
+pub fn meter_readings() -> [(Quantity, f32); 4] {
+    let t = diag::uptime_secs() as f32;
+    let volts = MAINS_VOLTS + MAINS_WANDER_VOLTS * (t * TAU / 60.0).sin();
+    let load = if relay_on() {
+        config::SIM_LOAD_WATTS as f32
+    } else {
+        STANDBY_WATTS
+    };
+
+    let mut meter = METER.lock();
+    meter.watts += (load - meter.watts) * POWER_RAMP;
+    meter.kwh += meter.watts * config::METER_POLL_SECS as f32 / 3_600_000.0;
+
+    [
+        (Quantity::Voltage, volts),
+        (Quantity::Current, meter.watts / volts),
+        (Quantity::Power, meter.watts),
+        (Quantity::Energy, meter.kwh),
+    ]
+}
+
+/// The raw soil probe reading, a slow sine over most of the ADC range.
+pub fn soil_raw() -> u16 {
+    if let Ok(raw) = u16::try_from(SOIL_RAW.load(Ordering::Relaxed)) {
+        return raw;
+    }
+
+    let phase = diag::uptime_secs() as f32 * TAU / SOIL_PERIOD_SECS;
+    let mid = soil::ADC_MAX as f32 / 2.0;
+    (mid + mid * 0.8 * phase.sin()) as u16
+}
+
+fn button_name(name: &str) -> Result<&'static str> {
+    [button::BOOT.name, touch::PAD.name, encoder::SWITCH.name]
+        .into_iter()
+        .find(|known| *known == name)
+        .ok_or_else(|| anyhow!("no button {}", name))
+}
+
+/// Dispatches what the classifier would report for the gesture, releases
+/// and all.
+fn press(name: &'static str, gesture: &[&str]) -> Result<()> {
+    let clicks = |count: usize| -> Vec<Event> {
+        let mut events = [Event::Pressed, Event::Released].repeat(count);
+        events.push(match count {
+            1 => Event::Click,
+            2 => Event::DoubleClick,
+            _ => Event::TripleClick,
+        });
+        events
+    };
+    let events = match gesture {
+        [] | ["click"] => clicks(1),
+        ["double"] => clicks(2),
+        ["triple"] => clicks(3),
+        ["long", ms] => vec![
+            Event::Pressed,
+            Event::Released,
+            Event::LongPress(Duration::from_millis(ms.parse()?)),
+        ],
+        _ => bail!("usage: sim press <button> [click|double|triple|long <ms>]"),
+    };
+
+    let gesture = if gesture.is_empty() {
+        "click".into()
+    } else {
+        gesture.join(" ")
+    };
+    info!(target: logging::BUTTON, "Simulated {} on {}", gesture, name);
+    for event in events {
+        button::dispatch(name, event);
+    }
+
+    Ok(())
+}
+
+pub fn register_commands() {
+    info!(target: logging::DIAG, "Simulation build, no hardware is driven");
+
+    console::register(
+        "sim",
+        "Show the simulated hardware ('sim'), press a button ('sim press <button> [click|double|triple|long <ms>]') or pin the soil probe ('sim soil <raw>|wave')",
+        |args| match args {
+            [] => {
+                println!("Relay: {}", if relay_on() { "on" } else { "off" });
+                let meter = METER.lock();
+                println!("Meter: {:.1} W, {:.3} kWh", meter.watts, meter.kwh);
+                println!("Soil probe: {} raw", soil_raw());
+                Ok(())
+            }
+            ["press", name, gesture @ ..] => press(button_name(name)?, gesture),
+            ["soil", "wave"] => {
+                SOIL_RAW.store(-1, Ordering::Relaxed);
+                Ok(())
+            }
+            ["soil", raw] => {
+                let raw: u16 = raw.parse()?;
+                if raw > soil::ADC_MAX {
+                    bail!("the ADC reads at most {}", soil::ADC_MAX);
+                }
+                SOIL_RAW.store(raw as i32, Ordering::Relaxed);
+                Ok(())
+            }
+            _ => bail!("usage: sim [press <button> [click|double|triple|long <ms>] | soil <raw>|wave]"),
+        },
+    );
+}
@@ -0,0 +1,209 @@
+use std::net::Ipv4Addr;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::time::Duration;
+
+use anyhow::Result;
+use log::{info, warn};
+
+use crate::{board, config, logging, system, tasks, touch, wdt};
+
+const RTC_MAGIC: u32 = 0x5ee9_1a7e;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WakeCause {
+    PowerOn,
+    Timer,
+    Gpio,
+    Touch,
+    Other,
+}
+
+/// What a fast reconnect needs to skip the scan and DHCP.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct Link {
+    pub channel: u8,
+    pub bssid: [u8; 6],
+    pub ip: Ipv4Addr,
+    pub gateway: Ipv4Addr,
+    pub mask: u8,
+    pub dns: Option<Ipv4Addr>,
+}
+
+#[repr(C)]
+struct RtcState {
+    magic: u32,
+    link: Option<Link>,
+    wakes: u32,
+}
+
+// Initialized on power-on, kept across deep sleep
+#[link_section = ".rtc.data"]
+static mut RTC: RtcState = RtcState {
+    magic: 0,
+    link: None,
+    wakes: 0,
+};
+
+static INHIBITORS: AtomicU32 = AtomicU32::new(0);
+static LAST_ACTIVITY_MS: AtomicU64 = AtomicU64::new(0);
+static CONTROLLER_SEEN: AtomicBool = AtomicBool::new(false);
+
+fn now_ms() -> u64 {
+    (unsafe { esp_idf_sys::esp_timer_get_time() } / 1000) as u64
+}
+
+pub fn wake_cause() -> WakeCause {
+    match unsafe { esp_idf_sys::esp_sleep_get_wakeup_cause() } {
+        esp_idf_sys::esp_sleep_source_t_ESP_SLEEP_WAKEUP_UNDEFINED => WakeCause::PowerOn,
+        esp_idf_sys::esp_sleep_source_t_ESP_SLEEP_WAKEUP_TIMER => WakeCause::Timer,
+        esp_idf_sys::esp_sleep_source_t_ESP_SLEEP_WAKEUP_EXT0
+        | esp_idf_sys::esp_sleep_source_t_ESP_SLEEP_WAKEUP_EXT1
+        | esp_idf_sys::esp_sleep_source_t_ESP_SLEEP_WAKEUP_GPIO => WakeCause::Gpio,
+        esp_idf_sys::esp_sleep_source_t_ESP_SLEEP_WAKEUP_TOUCHPAD => WakeCause::Touch,
+        _ => WakeCause::Other,
+    }
+}
+
+/// The link of the previous wake cycle; never after power-on, when the RTC
+/// memory content is meaningless.
+pub fn cached_link() -> Option<Link> {
+    if !config::SLEEP_ENABLED || wake_cause() == WakeCause::PowerOn {
+        return None;
+    }
+
+    unsafe {
+        if RTC.magic == RTC_MAGIC {
+            RTC.link
+        } else {
+            None
+        }
+    }
+}
+
+pub fn store_link(link: Option<Link>) {
+    unsafe {
+        RTC.link = link;
+        RTC.magic = RTC_MAGIC;
+    }
+}
+
+/// Keeps the device awake while held, for pairing mode or an update.
+pub struct Inhibitor(());
+
+pub fn inhibit() -> Inhibitor {
+    INHIBITORS.fetch_add(1, Ordering::Relaxed);
+    Inhibitor(())
+}
+
+impl Drop for Inhibitor {
+    fn drop(&mut self) {
+        INHIBITORS.fetch_sub(1, Ordering::Relaxed);
+        activity();
+    }
+}
+
+/// Restarts the idle grace period, e.g. after publishing a reading.
+pub fn activity() {
+    LAST_ACTIVITY_MS.store(now_ms(), Ordering::Relaxed);
+}
+
+/// A controller session is up, so notifications reach it from now on.
+pub fn controller_connected() {
+    CONTROLLER_SEEN.store(true, Ordering::Relaxed);
+    activity();
+}
+
+fn enter() -> ! {
+    info!(
+        target: logging::POWER,
+        "Deep sleep for {} s after {} ms awake",
+        config::SLEEP_INTERVAL_SECS,
+        now_ms()
+    );
+    system::enter_safe_state();
+    // The pad would float while the chip sleeps and until the relay is set
+    // up again after the wake-up
+    if !config::RELAY_UART_ENABLED && !config::SIMULATION {
+        if let Err(err) = board::hold(board::RELAY_GPIO) {
+            warn!(target: logging::POWER, "Holding the relay pin failed: {:?}", err);
+        }
+        if config::RELAY_MODE == config::RelayMode::DualCoil {
+            if let Err(err) = board::hold(board::RELAY_RESET_GPIO) {
+                warn!(target: logging::POWER, "Holding the reset coil pin failed: {:?}", err);
+            }
+        }
+    }
+
+    unsafe {
+        RTC.wakes = RTC.wakes.wrapping_add(1);
+        esp_idf_sys::esp_sleep_enable_timer_wakeup(config::SLEEP_INTERVAL_SECS * 1_000_000);
+    }
+    if board::SLEEP_WAKE_GPIO >= 0 {
+        enable_gpio_wakeup(board::SLEEP_WAKE_GPIO);
+    }
+    if config::TOUCH_ENABLED {
+        if let Err(err) = touch::enable_wakeup() {
+            warn!(target: logging::POWER, "Touch wake-up unavailable: {:?}", err);
+        }
+    }
+
+    unsafe { esp_idf_sys::esp_deep_sleep_start() }
+}
+
+#[cfg(esp32c3)]
+fn enable_gpio_wakeup(gpio: i32) {
+    unsafe {
+        esp_idf_sys::esp_deep_sleep_enable_gpio_wakeup(
+            1 << gpio,
+            esp_idf_sys::esp_deepsleep_gpio_wake_up_mode_t_ESP_GPIO_WAKEUP_GPIO_LOW,
+        )
+    };
+}
+
+#[cfg(not(esp32c3))]
+fn enable_gpio_wakeup(gpio: i32) {
+    unsafe { esp_idf_sys::esp_sleep_enable_ext0_wakeup(gpio, 0) };
+}
+
+fn sleep_handler() {
+    let watchdog = wdt::subscribe(tasks::SLEEP.name);
+    let grace = config::SLEEP_GRACE_MS;
+    let max_awake = config::SLEEP_MAX_AWAKE_SECS * 1000;
+
+    loop {
+        watchdog.sleep(Duration::from_millis(100));
+
+        if INHIBITORS.load(Ordering::Relaxed) > 0 {
+            continue;
+        }
+
+        // Wait for a controller to pick up the notifications, but not forever
+        let now = now_ms();
+        let idle = now.saturating_sub(LAST_ACTIVITY_MS.load(Ordering::Relaxed));
+        let delivered = CONTROLLER_SEEN.load(Ordering::Relaxed) && idle >= grace;
+        if delivered || now >= max_awake {
+            enter();
+        }
+    }
+}
+
+/// Starts the sleep manager, which puts the device into deep sleep once it
+/// has been idle for the grace period and nothing inhibits sleep.
+pub fn init() -> Result<()> {
+    let cause = wake_cause();
+    let wakes = unsafe {
+        if cause == WakeCause::PowerOn || RTC.magic != RTC_MAGIC {
+            RTC = RtcState {
+                magic: RTC_MAGIC,
+                link: None,
+                wakes: 0,
+            };
+        }
+        RTC.wakes
+    };
+    info!(target: logging::POWER, "Woke up by {:?}, wake cycle {}", cause, wakes);
+
+    activity();
+    tasks::spawn(&tasks::SLEEP, sleep_handler)
+}
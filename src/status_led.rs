@@ -0,0 +1,223 @@
+use std::ptr;
+
+use log::warn;
+use spin::Mutex;
+
+use crate::{board, config, logging};
+
+const TICK_MS: u64 = 50;
+
+// One character per tick, '#' = on; patterns repeat until the state changes
+const SLOW_BLINK: &str = "####################....................";
+const FAST_BLINK: &str = "##..";
+const DOUBLE_PULSE: &str = "###...###...................................";
+const TRIPLE_PULSE: &str = "##..##..##..............";
+const STROBE: &str = "#.";
+const BLIP: &str = "#.......................................";
+const SOLID: &str = "#";
+const SOS: &str = "###...###...###.........#########...#########...#########.........###...###...###.....................";
+
+const IDENTIFY_MS: u64 = 3000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Event {
+    WaitingForProvisioning,
+    /// The setup portal is up, the pairings are kept
+    Reconfiguring,
+    WifiConnecting,
+    WifiConnected,
+    WifiLost,
+    Unpaired,
+    PairingStarted,
+    PairingEnded,
+    /// Unpaired, but the pairing window ran out
+    PairingWindowClosed,
+    Paired,
+    Identify,
+    /// Recoverable failure of the given blink-code class, shown until cleared
+    Error(u32),
+    ErrorCleared,
+    Fatal(u32),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Wifi {
+    Unprovisioned,
+    Reconfiguring,
+    Connecting,
+    Connected,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Pairing {
+    Unknown,
+    Unpaired,
+    WindowClosed,
+    InProgress,
+    Paired,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Pattern {
+    Static(&'static str),
+    /// SOS followed by the blink code (when fatal) or just the blink code
+    Code {
+        count: u32,
+        fatal: bool,
+    },
+}
+
+struct State {
+    wifi: Wifi,
+    pairing: Pairing,
+    error: Option<(u32, bool)>,
+    identify_until_ms: u64,
+    pattern: Pattern,
+    tick: usize,
+}
+
+static STATE: Mutex<State> = Mutex::new(State {
+    wifi: Wifi::Connecting,
+    pairing: Pairing::Unknown,
+    error: None,
+    identify_until_ms: 0,
+    pattern: Pattern::Static(FAST_BLINK),
+    tick: 0,
+});
+
+fn now_ms() -> u64 {
+    (unsafe { esp_idf_sys::esp_timer_get_time() } / 1000) as u64
+}
+
+fn set(on: bool) {
+    let level = (on == config::STATUS_LED_ACTIVE_HIGH) as u32;
+    unsafe { esp_idf_sys::gpio_set_level(board::STATUS_LED_GPIO, level) };
+}
+
+impl State {
+    fn pattern(&self, now_ms: u64) -> Pattern {
+        // Priority: identify, errors, then the connectivity and pairing state
+        if now_ms < self.identify_until_ms {
+            return Pattern::Static(STROBE);
+        }
+
+        if let Some((count, fatal)) = self.error {
+            return Pattern::Code { count, fatal };
+        }
+
+        match (self.wifi, self.pairing) {
+            (Wifi::Unprovisioned, _) => Pattern::Static(SLOW_BLINK),
+            (Wifi::Reconfiguring, _) => Pattern::Static(TRIPLE_PULSE),
+            (Wifi::Connecting, _) => Pattern::Static(FAST_BLINK),
+            // HAP not started yet or a pair-setup running
+            (Wifi::Connected, Pairing::Unknown | Pairing::InProgress) => {
+                Pattern::Static(FAST_BLINK)
+            }
+            (Wifi::Connected, Pairing::Unpaired) => Pattern::Static(DOUBLE_PULSE),
+            (Wifi::Connected, Pairing::WindowClosed) => Pattern::Static(BLIP),
+            (Wifi::Connected, Pairing::Paired) => Pattern::Static(SOLID),
+        }
+    }
+
+    fn level(&self) -> bool {
+        match self.pattern {
+            Pattern::Static(ticks) => ticks.as_bytes()[self.tick % ticks.len()] == b'#',
+            Pattern::Code { count, fatal } => {
+                // Each blink is 4 ticks on and 6 off, followed by a 1.5 s pause
+                let code_len = count as usize * 10 + 30;
+                let prefix = if fatal { SOS.len() } else { 0 };
+                let tick = self.tick % (prefix + code_len);
+
+                if tick < prefix {
+                    SOS.as_bytes()[tick] == b'#'
+                } else {
+                    let tick = tick - prefix;
+                    tick < count as usize * 10 && tick % 10 < 4
+                }
+            }
+        }
+    }
+}
+
+unsafe extern "C" fn on_tick(_: *mut esp_idf_sys::c_types::c_void) {
+    let mut state = STATE.lock();
+
+    let pattern = state.pattern(now_ms());
+    if pattern != state.pattern {
+        state.pattern = pattern;
+        state.tick = 0;
+    }
+
+    set(state.level());
+    state.tick = state.tick.wrapping_add(1);
+}
+
+/// Configures the LED pin directly, so it still works when the peripherals
+/// could not be taken, and starts the pattern timer.
+pub fn init() {
+    if !config::STATUS_LED_ENABLED {
+        return;
+    }
+
+    unsafe {
+        esp_idf_sys::gpio_reset_pin(board::STATUS_LED_GPIO);
+        esp_idf_sys::gpio_set_direction(
+            board::STATUS_LED_GPIO,
+            esp_idf_sys::gpio_mode_t_GPIO_MODE_OUTPUT,
+        );
+    }
+    set(false);
+
+    let args = esp_idf_sys::esp_timer_create_args_t {
+        callback: Some(on_tick),
+        arg: ptr::null_mut(),
+        dispatch_method: esp_idf_sys::esp_timer_dispatch_t_ESP_TIMER_TASK,
+        name: b"status_led\0".as_ptr() as _,
+        skip_unhandled_events: true,
+    };
+    let mut timer: esp_idf_sys::esp_timer_handle_t = ptr::null_mut();
+
+    let err = unsafe {
+        let err = esp_idf_sys::esp_timer_create(&args, &mut timer);
+        if err == esp_idf_sys::ESP_OK {
+            esp_idf_sys::esp_timer_start_periodic(timer, TICK_MS * 1000)
+        } else {
+            err
+        }
+    };
+    if err != esp_idf_sys::ESP_OK {
+        warn!(target: logging::DIAG, "Starting the status LED timer failed: {}", err);
+    }
+}
+
+/// Updates the LED state; cheap and non-blocking, so it is safe to call from
+/// event handlers.
+pub fn event(event: Event) {
+    if !config::STATUS_LED_ENABLED {
+        return;
+    }
+
+    let mut state = STATE.lock();
+    match event {
+        Event::WaitingForProvisioning => state.wifi = Wifi::Unprovisioned,
+        Event::Reconfiguring => state.wifi = Wifi::Reconfiguring,
+        Event::WifiConnecting | Event::WifiLost => state.wifi = Wifi::Connecting,
+        Event::WifiConnected => state.wifi = Wifi::Connected,
+        Event::Unpaired | Event::PairingEnded => state.pairing = Pairing::Unpaired,
+        Event::PairingWindowClosed => state.pairing = Pairing::WindowClosed,
+        Event::PairingStarted => state.pairing = Pairing::InProgress,
+        Event::Paired => state.pairing = Pairing::Paired,
+        Event::Identify => state.identify_until_ms = now_ms() + IDENTIFY_MS,
+        Event::Error(count) => {
+            if state.error.map_or(true, |(_, fatal)| !fatal) {
+                state.error = Some((count, false));
+            }
+        }
+        Event::ErrorCleared => {
+            if state.error.map_or(false, |(_, fatal)| !fatal) {
+                state.error = None;
+            }
+        }
+        Event::Fatal(count) => state.error = Some((count, true)),
+    }
+}
@@ -0,0 +1,122 @@
+use anyhow::{bail, Result};
+use hap_core::interlock;
+use hap_core::sys::uuid;
+use hap_core::{CharSlot, ServiceBuilder, Status, Value, Write};
+use log::{info, warn};
+use spin::Once;
+
+use crate::hap_sys::HAP;
+use crate::relay::RelayBackend;
+use crate::{config, logging, system};
+
+static RELAY: Once<&'static dyn RelayBackend> = Once::new();
+static SWITCHES: Once<Vec<&'static Switch>> = Once::new();
+
+/// A relay channel beyond the outlet's, as a Switch service.
+struct Switch {
+    channel: u8,
+    on_char: CharSlot,
+}
+
+impl Switch {
+    fn is_on(&self) -> bool {
+        RELAY
+            .get()
+            .and_then(|relay| relay.get(self.channel))
+            .unwrap_or(false)
+    }
+
+    fn notify(&self) {
+        if let Some(hc) = self.on_char.get() {
+            HAP.update(hc, &Value::Bool(self.is_on()));
+        }
+    }
+
+    /// Accepted even where a rule has the relay end up otherwise; the
+    /// controller is told what it did.
+    fn write(&'static self, write: &Write) -> Result<(), Status> {
+        let on = write.value.as_bool().ok_or(Status::InvalidValue)?;
+        let relay = RELAY.get().ok_or(Status::ResourceAbsent)?;
+        if let Err(err) = relay.set(self.channel, on) {
+            warn!(
+                target: logging::OUTLET,
+                "Switching relay channel {} {} failed: {:?}",
+                self.channel,
+                on,
+                err
+            );
+            return Err(Status::CommunicationError);
+        }
+        self.notify();
+
+        Ok(())
+    }
+}
+
+/// The channels to expose as switches, checked against the relay's and the
+/// outlet's.
+pub fn channels(relay: &dyn RelayBackend) -> Result<Vec<u8>> {
+    let channels = interlock::parse_channels(config::RELAY_SWITCHES, relay.channels())?;
+    for (i, &channel) in channels.iter().enumerate() {
+        if channel == config::RELAY_CHANNEL {
+            bail!("relay channel {} is the outlet's", channel);
+        }
+        if channels[..i].contains(&channel) {
+            bail!("relay channel {} is listed twice", channel);
+        }
+    }
+
+    Ok(channels)
+}
+
+/// Takes the channels, off at a restart like the outlet, and follows the
+/// changes the interlock makes to them.
+pub fn init(relay: &'static dyn RelayBackend, channels: &[u8]) {
+    RELAY.call_once(|| relay);
+    let switches = SWITCHES.call_once(|| {
+        channels
+            .iter()
+            .map(|&channel| {
+                &*Box::leak(Box::new(Switch {
+                    channel,
+                    on_char: CharSlot::new(),
+                }))
+            })
+            .collect()
+    });
+    if switches.is_empty() {
+        return;
+    }
+    info!(target: logging::OUTLET, "Relay channels {:?} as switches", channels);
+
+    crate::interlock::subscribe(|changed| {
+        for switch in SWITCHES.get().into_iter().flatten() {
+            if changed & 1 << switch.channel != 0 {
+                switch.notify();
+            }
+        }
+    });
+    system::on_shutdown(|| {
+        for switch in SWITCHES.get().into_iter().flatten() {
+            if let Some(relay) = RELAY.get() {
+                let _ = relay.set(switch.channel, false);
+            }
+        }
+    });
+}
+
+pub fn services() -> Vec<ServiceBuilder> {
+    SWITCHES
+        .get()
+        .into_iter()
+        .flatten()
+        .map(|&switch| {
+            ServiceBuilder::switch()
+                .name(&format!("Relay {}", switch.channel + 1))
+                .bind(uuid::ON, &switch.on_char)
+                .on_write(Box::leak(Box::new(move |write: &Write| {
+                    switch.write(write)
+                })))
+        })
+        .collect()
+}
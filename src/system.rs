@@ -0,0 +1,111 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use hap_core::sys::HAP_SUCCESS;
+use hap_core::HapSys;
+use log::warn;
+use spin::Mutex;
+
+use crate::hap_sys::HAP;
+use crate::{logging, nvs};
+
+type Hook = &'static (dyn Fn() + Send + Sync);
+
+static SHUTDOWN_HOOKS: Mutex<Vec<Hook>> = Mutex::new(Vec::new());
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+
+/// Registers a hook that brings a subsystem into its safe state before a restart.
+pub fn on_shutdown(hook: impl Fn() + Send + Sync + 'static) {
+    SHUTDOWN_HOOKS.lock().push(Box::leak(Box::new(hook)));
+}
+
+/// Drives every subsystem that registered a hook into its safe state.
+pub fn enter_safe_state() {
+    SHUTTING_DOWN.store(true, Ordering::Relaxed);
+
+    let hooks = SHUTDOWN_HOOKS.lock().clone();
+    for hook in hooks {
+        hook();
+    }
+}
+
+/// Set once the subsystems head for their safe state, controller writes are
+/// refused from then on.
+pub fn is_shutting_down() -> bool {
+    SHUTTING_DOWN.load(Ordering::Relaxed)
+}
+
+/// Erases the pairings and HAP data and restarts into an unpaired accessory.
+pub fn factory_reset() -> ! {
+    warn!(target: logging::DIAG, "Factory reset requested");
+
+    enter_safe_state();
+
+    // The SDK only erases its own partition
+    nvs::erase_app_data();
+
+    // The SDK erases its data and restarts from its own task; it refuses when
+    // HAP was never initialized, the flash is wiped directly then
+    let err = HAP.sys().reset_to_factory();
+    if err == HAP_SUCCESS {
+        thread::sleep(Duration::from_secs(5));
+    }
+    warn!(target: logging::DIAG, "HAP factory reset did not restart ({}), erasing NVS", err);
+    let _ = nvs::erase_partition(nvs::HAP_PARTITION);
+    unsafe {
+        esp_idf_sys::nvs_flash_erase();
+        esp_idf_sys::esp_restart()
+    }
+}
+
+/// Erases the Wi-Fi configuration stored by the driver and the setup portal
+/// and restarts, which falls back to the compiled-in credentials.
+pub fn reset_network() -> ! {
+    warn!(target: logging::DIAG, "Network reset requested");
+
+    enter_safe_state();
+
+    let erased = nvs::Namespace::open(nvs::WIFI).and_then(|store| {
+        store.erase_all()?;
+        store.commit()?;
+        Ok(())
+    });
+    if let Err(err) = erased {
+        warn!(target: logging::DIAG, "Erasing the stored Wi-Fi credentials failed: {:?}", err);
+    }
+
+    let err = HAP.sys().reset_network();
+    if err == HAP_SUCCESS {
+        thread::sleep(Duration::from_secs(5));
+    }
+    warn!(target: logging::DIAG, "HAP network reset did not restart ({}), restoring Wi-Fi", err);
+    unsafe {
+        esp_idf_sys::esp_wifi_restore();
+        esp_idf_sys::esp_restart()
+    }
+}
+
+/// Removes every controller pairing and restarts into an unpaired
+/// accessory, keeping the network configuration.
+pub fn reset_pairings() -> ! {
+    warn!(target: logging::DIAG, "Pairing reset requested");
+
+    enter_safe_state();
+
+    let err = HAP.sys().reset_pairings();
+    if err == HAP_SUCCESS {
+        thread::sleep(Duration::from_secs(5));
+    }
+    warn!(target: logging::DIAG, "HAP pairing reset did not restart ({})", err);
+    unsafe { esp_idf_sys::esp_restart() }
+}
+
+/// Restarts the device after driving every subsystem into its safe state.
+pub fn restart(reason: &str) -> ! {
+    warn!(target: logging::DIAG, "Restarting: {}", reason);
+
+    enter_safe_state();
+
+    unsafe { esp_idf_sys::esp_restart() }
+}
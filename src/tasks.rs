@@ -0,0 +1,202 @@
+use std::ffi::{c_void, CString};
+
+use anyhow::{bail, Result};
+use esp_idf_sys::UBaseType_t;
+use spin::Mutex;
+
+use crate::{config, pm};
+
+type Entry = Box<dyn FnOnce() + Send + 'static>;
+
+pub struct TaskSpec {
+    pub name: &'static str,
+    pub stack_size: u32,
+    pub priority: UBaseType_t,
+}
+
+/// The IDF's own task, running `main` and holding a startup failure.
+pub const MAIN: TaskSpec = TaskSpec {
+    name: "main",
+    stack_size: esp_idf_sys::CONFIG_ESP_MAIN_TASK_STACK_SIZE,
+    priority: 1,
+};
+
+pub const SMART_OUTLET: TaskSpec = TaskSpec {
+    name: "hap_outlet",
+    stack_size: config::SMART_OUTLET_TASK_STACKSIZE,
+    priority: 1,
+};
+
+pub const HEAP_MONITOR: TaskSpec = TaskSpec {
+    name: "diag_heap",
+    stack_size: config::HEAP_MONITOR_TASK_STACKSIZE,
+    priority: 1,
+};
+
+pub const CONSOLE: TaskSpec = TaskSpec {
+    name: "console",
+    stack_size: config::CONSOLE_TASK_STACKSIZE,
+    priority: 1,
+};
+
+pub const BUTTON: TaskSpec = TaskSpec {
+    name: "button",
+    stack_size: config::BUTTON_TASK_STACKSIZE,
+    priority: 2,
+};
+
+pub const ENCODER: TaskSpec = TaskSpec {
+    name: "encoder",
+    stack_size: config::ENCODER_TASK_STACKSIZE,
+    priority: 2,
+};
+
+pub const IR: TaskSpec = TaskSpec {
+    name: "ir",
+    stack_size: config::IR_TASK_STACKSIZE,
+    priority: 1,
+};
+
+pub const ENERGY_METER: TaskSpec = TaskSpec {
+    name: "meter",
+    stack_size: config::ENERGY_METER_TASK_STACKSIZE,
+    priority: 1,
+};
+
+pub const TOUCH: TaskSpec = TaskSpec {
+    name: "touch",
+    stack_size: config::TOUCH_TASK_STACKSIZE,
+    priority: 2,
+};
+
+pub const SLEEP: TaskSpec = TaskSpec {
+    name: "sleep",
+    stack_size: config::SLEEP_TASK_STACKSIZE,
+    priority: 1,
+};
+
+pub const EVENT_BUS: TaskSpec = TaskSpec {
+    name: "event_bus",
+    stack_size: config::EVENT_BUS_TASK_STACKSIZE,
+    priority: 2,
+};
+
+pub const ACCESSORY_POLL: TaskSpec = TaskSpec {
+    name: "hap_poll",
+    stack_size: config::ACCESSORY_POLL_TASK_STACKSIZE,
+    priority: 1,
+};
+
+pub const WIFI: TaskSpec = TaskSpec {
+    name: "wifi",
+    stack_size: config::WIFI_TASK_STACKSIZE,
+    priority: 1,
+};
+
+pub const WIFI_MONITOR: TaskSpec = TaskSpec {
+    name: "wifi_mon",
+    stack_size: config::WIFI_MONITOR_TASK_STACKSIZE,
+    priority: 1,
+};
+
+pub const IRRIGATION: TaskSpec = TaskSpec {
+    name: "irrigation",
+    stack_size: config::IRRIGATION_TASK_STACKSIZE,
+    priority: 1,
+};
+
+pub const DISTANCE: TaskSpec = TaskSpec {
+    name: "distance",
+    stack_size: config::DISTANCE_TASK_STACKSIZE,
+    priority: 1,
+};
+
+pub const DOOR: TaskSpec = TaskSpec {
+    name: "door",
+    stack_size: config::DOOR_TASK_STACKSIZE,
+    priority: 2,
+};
+
+pub const SCHEDULE: TaskSpec = TaskSpec {
+    name: "sched",
+    stack_size: config::SCHEDULE_TASK_STACKSIZE,
+    priority: 1,
+};
+
+pub const RFID: TaskSpec = TaskSpec {
+    name: "rfid",
+    stack_size: config::RFID_TASK_STACKSIZE,
+    priority: 1,
+};
+
+pub const TEMPERATURE: TaskSpec = TaskSpec {
+    name: "temp",
+    stack_size: config::TEMP_TASK_STACKSIZE,
+    priority: 1,
+};
+
+/// Every task of the firmware; the watchdog keeps a slot for each.
+pub const ALL: [&TaskSpec; 20] = [
+    &MAIN,
+    &SMART_OUTLET,
+    &HEAP_MONITOR,
+    &CONSOLE,
+    &BUTTON,
+    &ENCODER,
+    &IR,
+    &ENERGY_METER,
+    &TOUCH,
+    &SLEEP,
+    &EVENT_BUS,
+    &ACCESSORY_POLL,
+    &WIFI,
+    &WIFI_MONITOR,
+    &IRRIGATION,
+    &DISTANCE,
+    &DOOR,
+    &SCHEDULE,
+    &RFID,
+    &TEMPERATURE,
+];
+
+static SPAWNED: Mutex<Vec<&'static TaskSpec>> = Mutex::new(Vec::new());
+
+extern "C" fn trampoline(arg: *mut c_void) {
+    let entry = unsafe { Box::from_raw(arg as *mut Entry) };
+    entry();
+
+    unsafe { esp_idf_sys::vTaskDelete(std::ptr::null_mut()) };
+}
+
+/// Starts `entry` as a FreeRTOS task, which lets it capture the state it needs.
+pub fn spawn<F>(spec: &'static TaskSpec, entry: F) -> Result<()>
+where
+    F: FnOnce() + Send + 'static,
+{
+    let name = CString::new(spec.name)?;
+    let entry: *mut Entry = Box::into_raw(Box::new(Box::new(entry)));
+
+    let created = unsafe {
+        esp_idf_sys::xTaskCreatePinnedToCore(
+            Some(trampoline),
+            name.as_ptr(),
+            spec.stack_size,
+            entry as *mut c_void,
+            spec.priority,
+            std::ptr::null_mut(),
+            pm::PROFILE.app_core.unwrap_or(i32::MAX), // tskNO_AFFINITY
+        )
+    };
+    if created != 1 {
+        drop(unsafe { Box::from_raw(entry) });
+        bail!("creating task {} failed", spec.name);
+    }
+
+    SPAWNED.lock().push(spec);
+
+    Ok(())
+}
+
+pub fn spawned() -> Vec<&'static TaskSpec> {
+    SPAWNED.lock().clone()
+}
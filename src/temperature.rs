@@ -0,0 +1,351 @@
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use hap_core::fusion::{Aggregation, Fusion, Probe, MAX_PROBES};
+use hap_core::onewire::{self, Rom, FAMILY_DS18B20};
+use hap_core::sys::{perm, uuid};
+use hap_core::{Bounds, CharSlot, ServiceBuilder, Value};
+use log::{info, warn};
+use spin::{Mutex, Once};
+
+use crate::hap_sys::HAP;
+use crate::onewire::OneWire;
+use crate::{board, config, console, diag, logging, metrics, nvs, tasks, wdt};
+
+// Apple's Temperature Sensor
+const TEMPERATURE_SENSOR_UUID: &[u8] = b"8A\0";
+const CURRENT_TEMPERATURE_UUID: &[u8] = b"11\0";
+
+const READ_ONLY: u16 = perm::PR | perm::EV;
+// The DS18B20's range
+const CELSIUS: Bounds = Bounds {
+    min: -55.0,
+    max: 125.0,
+    step: 0.1,
+};
+
+static STORE: Once<nvs::Namespace> = Once::new();
+static BUS: Mutex<Option<OneWire>> = Mutex::new(None);
+static FUSION: Mutex<Fusion> = Mutex::new(Fusion::new(config::TEMP_STALE_SECS));
+/// What controllers were last notified of: the aggregate and its fault
+static REPORTED: Mutex<Option<(f32, bool)>> = Mutex::new(None);
+static EXPOSED: Mutex<Vec<&'static Exposed>> = Mutex::new(Vec::new());
+
+static ROOM_CHAR: CharSlot = CharSlot::new();
+static ROOM_FAULT_CHAR: CharSlot = CharSlot::new();
+
+/// A probe's Temperature Sensor service.
+struct Exposed {
+    rom: Rom,
+    temperature_char: CharSlot,
+    fault_char: CharSlot,
+    /// As last notified
+    reported: Mutex<Option<(f32, bool)>>,
+}
+
+fn store() -> Result<&'static nvs::Namespace> {
+    STORE.try_call_once(|| nvs::Namespace::open(nvs::TEMP))
+}
+
+fn load() -> Result<Option<Fusion>> {
+    let mut bytes = vec![0u8; 2 + MAX_PROBES * 12];
+    let Some(len) = store()?.get_blob("probes", &mut bytes)? else {
+        return Ok(None);
+    };
+
+    Ok(Some(Fusion::decode(
+        &bytes[..len],
+        config::TEMP_STALE_SECS,
+    )?))
+}
+
+fn save(fusion: &Fusion) -> Result<()> {
+    let store = store()?;
+    store.set_blob("probes", &fusion.encode())?;
+    store.commit()?;
+
+    Ok(())
+}
+
+fn scan() -> Result<Vec<Rom>> {
+    let mut bus = BUS.lock();
+    let Some(bus) = bus.as_mut() else {
+        bail!("the 1-Wire bus is not set up");
+    };
+    let roms = onewire::search(bus)?;
+
+    Ok(roms
+        .into_iter()
+        .filter(|rom| rom.family() == FAMILY_DS18B20)
+        .collect())
+}
+
+// Rounded to what the characteristic shows, so noise is not notified
+fn round(celsius: f32) -> f32 {
+    (celsius * 10.0).round() / 10.0
+}
+
+fn update(slot: &CharSlot, value: Value) {
+    if let Some(hc) = slot.get() {
+        HAP.update(hc, &value);
+    }
+}
+
+fn notify(now: u32) {
+    let mut fusion = FUSION.lock();
+    for exposed in EXPOSED.lock().iter() {
+        let reading = fusion.reading(&exposed.rom, now);
+        let state = (round(reading.unwrap_or_default()), reading.is_none());
+        let previous = exposed.reported.lock().replace(state);
+        if previous.map(|p| p.1) != Some(state.1) {
+            update(&exposed.fault_char, Value::Uint8(state.1 as u8));
+            if state.1 {
+                warn!(target: logging::TEMP, "Probe {} stopped reading", exposed.rom);
+            }
+        }
+        if reading.is_some() && previous.map(|p| p.0) != Some(state.0) {
+            update(&exposed.temperature_char, Value::Float(state.0));
+        }
+    }
+
+    let output = fusion.aggregate(now);
+    drop(fusion);
+    let state = (round(output.celsius.unwrap_or_default()), output.is_fault());
+    let previous = REPORTED.lock().replace(state);
+    if previous.map(|p| p.1) != Some(state.1) {
+        update(&ROOM_FAULT_CHAR, Value::Uint8(state.1 as u8));
+        if state.1 {
+            warn!(target: logging::TEMP, "No probe contributes, holding {:.1} °C", state.0);
+        } else {
+            info!(target: logging::TEMP, "{} probes contribute", output.contributors);
+        }
+    }
+    if previous.map(|p| p.0) != Some(state.0) {
+        update(&ROOM_CHAR, Value::Float(state.0));
+    }
+}
+
+/// One round: starts a conversion on all probes, then reads each.
+fn sample(watchdog: &wdt::Watchdog) {
+    if let Err(err) = BUS.lock().as_mut().unwrap().convert_all() {
+        warn!(target: logging::TEMP, "Starting a conversion failed: {}", err);
+        return;
+    }
+    watchdog.sleep(Duration::from_millis(onewire::CONVERSION_MS));
+
+    let roms: Vec<Rom> = FUSION
+        .lock()
+        .probes()
+        .iter()
+        .map(|probe| probe.rom)
+        .collect();
+    for rom in roms {
+        let reading = BUS.lock().as_mut().unwrap().read_celsius(&rom);
+        match reading {
+            Ok(celsius) => {
+                FUSION.lock().record(&rom, celsius, diag::uptime_secs());
+            }
+            Err(err) => warn!(target: logging::TEMP, "Reading probe {} failed: {}", rom, err),
+        }
+    }
+}
+
+fn temperature_handler() {
+    let watchdog = wdt::subscribe(tasks::TEMPERATURE.name);
+    loop {
+        sample(&watchdog);
+        notify(diag::uptime_secs());
+        watchdog.sleep(Duration::from_secs(config::TEMP_SAMPLE_SECS as u64));
+    }
+}
+
+/// Loads the probes, registering those on the bus at the first boot, and
+/// samples them on a task of their own.
+pub fn init() -> Result<()> {
+    *BUS.lock() = Some(OneWire::new(board::ONEWIRE_GPIO)?);
+
+    match load() {
+        Ok(Some(fusion)) => *FUSION.lock() = fusion,
+        Ok(None) => {
+            let mut fusion = FUSION.lock();
+            for rom in scan()? {
+                fusion.add(Probe::new(rom))?;
+                info!(target: logging::TEMP, "Registered probe {}", rom);
+            }
+            save(&fusion)?;
+        }
+        Err(err) => warn!(target: logging::TEMP, "Loading the probes failed: {:?}", err),
+    }
+    info!(
+        target: logging::TEMP,
+        "1-Wire bus on GPIO{}, {} probes, {} aggregate",
+        board::ONEWIRE_GPIO,
+        FUSION.lock().probes().len(),
+        FUSION.lock().aggregation.name()
+    );
+
+    tasks::spawn(&tasks::TEMPERATURE, temperature_handler)
+}
+
+/// A Temperature Sensor for the aggregate and one per probe, each with
+/// StatusFault. Probes registered later show up after a restart, the
+/// accessory database is fixed once HAP started.
+pub fn services() -> Result<Vec<ServiceBuilder>> {
+    if BUS.lock().is_none() {
+        bail!("the 1-Wire bus is not set up");
+    }
+
+    let mut services = vec![ServiceBuilder::custom(TEMPERATURE_SENSOR_UUID)
+        .name("Room Temperature")
+        .status_fault()
+        .char(CURRENT_TEMPERATURE_UUID, READ_ONLY, Value::Float(0.0))
+        .bounds(CURRENT_TEMPERATURE_UUID, CELSIUS)
+        .bind(CURRENT_TEMPERATURE_UUID, &ROOM_CHAR)
+        .bind(uuid::STATUS_FAULT, &ROOM_FAULT_CHAR)];
+
+    let mut exposed = EXPOSED.lock();
+    for (i, probe) in FUSION.lock().probes().iter().enumerate() {
+        let probe: &'static Exposed = Box::leak(Box::new(Exposed {
+            rom: probe.rom,
+            temperature_char: CharSlot::new(),
+            fault_char: CharSlot::new(),
+            reported: Mutex::new(None),
+        }));
+        exposed.push(probe);
+
+        services.push(
+            ServiceBuilder::custom(TEMPERATURE_SENSOR_UUID)
+                .name(&format!("Temperature Probe {}", i + 1))
+                .status_fault()
+                .char(CURRENT_TEMPERATURE_UUID, READ_ONLY, Value::Float(0.0))
+                .bounds(CURRENT_TEMPERATURE_UUID, CELSIUS)
+                .bind(CURRENT_TEMPERATURE_UUID, &probe.temperature_char)
+                .bind(uuid::STATUS_FAULT, &probe.fault_char),
+        );
+    }
+
+    Ok(services)
+}
+
+pub fn register_metrics() {
+    metrics::register("temp_contributors", || {
+        FUSION.lock().aggregate(diag::uptime_secs()).contributors as i64
+    });
+    metrics::register("temp_centi_celsius", || {
+        let output = FUSION.lock().aggregate(diag::uptime_secs());
+        (output.celsius.unwrap_or_default() * 100.0).round() as i64
+    });
+}
+
+/// Changes the probes and stores them; the reported values follow with the
+/// next sample.
+fn change(f: impl FnOnce(&mut Fusion) -> Result<()>) -> Result<()> {
+    let mut fusion = FUSION.lock();
+    f(&mut fusion)?;
+    save(&fusion)
+}
+
+fn show() {
+    let now = diag::uptime_secs();
+    let mut fusion = FUSION.lock();
+    for (i, probe) in fusion.probes().iter().enumerate() {
+        let reading = match fusion.reading(&probe.rom, now) {
+            Some(celsius) => format!("{:.2} °C", celsius),
+            None => "stale".into(),
+        };
+        println!(
+            "{}. {}  {:+.2} °C offset, weight {}, {}: {}",
+            i + 1,
+            probe.rom,
+            probe.offset,
+            probe.weight,
+            if probe.contributes {
+                "contributes"
+            } else {
+                "reported only"
+            },
+            reading
+        );
+    }
+
+    let aggregation = fusion.aggregation;
+    let output = fusion.aggregate(now);
+    match output.celsius {
+        Some(celsius) => println!(
+            "Room {:.2} °C ({} of {} probes, {}{})",
+            celsius,
+            output.contributors,
+            fusion.probes().len(),
+            aggregation.name(),
+            if output.is_fault() { ", held" } else { "" }
+        ),
+        None => println!("Room temperature unknown yet"),
+    }
+}
+
+pub fn register_commands() {
+    console::register(
+        "temp",
+        "Show the temperature probes ('temp'), find those on the bus ('temp scan'), register or remove one ('temp add|del <rom>'), set its offset ('temp offset <rom> <°C>'), weight ('temp weight <rom> <n>') or whether it contributes ('temp use <rom> on|off'), or the aggregation ('temp mode mean|min|weighted')",
+        |args| match args {
+            [] => {
+                show();
+                Ok(())
+            }
+            ["scan"] => {
+                let probes = FUSION.lock().probes().to_vec();
+                for rom in scan()? {
+                    let registered = probes.iter().any(|probe| probe.rom == rom);
+                    println!("{}{}", rom, if registered { " (registered)" } else { "" });
+                }
+                Ok(())
+            }
+            ["add", rom] => {
+                let rom: Rom = rom.parse()?;
+                change(|fusion| {
+                    if !fusion.add(Probe::new(rom))? {
+                        bail!("{} is registered already", rom);
+                    }
+                    Ok(())
+                })?;
+                println!("Registered, its service shows up after a restart");
+                Ok(())
+            }
+            ["del", rom] => {
+                let rom: Rom = rom.parse()?;
+                change(|fusion| {
+                    if !fusion.remove(&rom) {
+                        bail!("{} is not registered", rom);
+                    }
+                    Ok(())
+                })
+            }
+            ["offset", rom, offset] => {
+                let (rom, offset): (Rom, f32) = (rom.parse()?, offset.parse()?);
+                if offset.abs() > 10.0 {
+                    bail!("offsets are at most ±10 °C");
+                }
+                change(|fusion| Ok(fusion.update(&rom, |probe| probe.offset = offset)?))
+            }
+            ["weight", rom, weight] => {
+                let (rom, weight): (Rom, u8) = (rom.parse()?, weight.parse()?);
+                change(|fusion| Ok(fusion.update(&rom, |probe| probe.weight = weight)?))
+            }
+            ["use", rom, on @ ("on" | "off")] => {
+                let rom: Rom = rom.parse()?;
+                let contributes = *on == "on";
+                change(|fusion| Ok(fusion.update(&rom, |probe| probe.contributes = contributes)?))
+            }
+            ["mode", mode] => {
+                let Some(aggregation) = Aggregation::from_name(mode) else {
+                    bail!("expected mean, min or weighted");
+                };
+                change(|fusion| {
+                    fusion.aggregation = aggregation;
+                    Ok(())
+                })
+            }
+            _ => bail!("usage: temp [scan | add <rom> | del <rom> | offset <rom> <°C> | weight <rom> <n> | use <rom> on|off | mode mean|min|weighted]"),
+        },
+    );
+}
@@ -0,0 +1,215 @@
+// Only the ESP32 has the touch sensor the detector is fed from
+#![cfg_attr(not(esp32), allow(dead_code))]
+
+use anyhow::Result;
+
+use crate::button::{Classifier, Timing};
+use crate::{board, config};
+
+pub struct TouchConfig {
+    pub name: &'static str,
+    pub pad: u32,
+    /// Touched below this share of the baseline (the ESP32 reading drops on touch)
+    pub threshold_percent: u32,
+    /// Released again above this share, the gap is the hysteresis
+    pub release_percent: u32,
+    /// Consecutive samples across a threshold before the state flips
+    pub samples: u8,
+    pub timing: Timing,
+}
+
+/// TOUCH0 (GPIO4 on the ESP32).
+pub const PAD: TouchConfig = TouchConfig {
+    name: "touch",
+    pad: board::TOUCH_PAD,
+    threshold_percent: config::TOUCH_THRESHOLD_PERCENT,
+    release_percent: config::TOUCH_RELEASE_PERCENT,
+    samples: config::TOUCH_SAMPLES,
+    timing: Timing {
+        // The consecutive-sample filter already debounces
+        debounce_ms: 0,
+        long_press_ms: config::BUTTON_LONG_PRESS_MS,
+        double_click_ms: config::BUTTON_DOUBLE_CLICK_MS,
+    },
+};
+
+const CALIBRATION_SAMPLES: u32 = 32;
+// Share of each untouched sample folded into the baseline, as 1/n
+const DRIFT_WEIGHT: u32 = 64;
+
+/// Touch state of one pad from its raw readings. Pure state like the button
+/// classifier, so recorded sample sequences replay the same way.
+pub struct Detector {
+    threshold_percent: u32,
+    release_percent: u32,
+    samples: u8,
+    /// Scaled by DRIFT_WEIGHT to keep the fraction of the running average
+    baseline: u32,
+    touched: bool,
+    streak: u8,
+}
+
+impl Detector {
+    pub fn new(config: &TouchConfig, baseline: u16) -> Self {
+        Self {
+            threshold_percent: config.threshold_percent,
+            release_percent: config.release_percent,
+            samples: config.samples.max(1),
+            baseline: baseline as u32 * DRIFT_WEIGHT,
+            touched: false,
+            streak: 0,
+        }
+    }
+
+    pub fn baseline(&self) -> u16 {
+        (self.baseline / DRIFT_WEIGHT) as u16
+    }
+
+    pub fn threshold(&self) -> u16 {
+        (self.baseline() as u32 * self.threshold_percent / 100) as u16
+    }
+
+    /// Feeds one reading and returns whether the pad counts as touched.
+    pub fn sample(&mut self, value: u16) -> bool {
+        let baseline = self.baseline() as u32;
+        let crossing = if self.touched {
+            value as u32 > baseline * self.release_percent / 100
+        } else {
+            (value as u32) < baseline * self.threshold_percent / 100
+        };
+
+        if crossing {
+            self.streak += 1;
+            if self.streak >= self.samples {
+                self.touched = !self.touched;
+                self.streak = 0;
+            }
+        } else {
+            self.streak = 0;
+        }
+
+        // Temperature and humidity move the baseline; a finger must not
+        if !self.touched && !crossing {
+            self.baseline = self.baseline - self.baseline / DRIFT_WEIGHT + value as u32;
+        }
+
+        self.touched
+    }
+}
+
+#[cfg(esp32)]
+mod pad {
+    use std::time::Duration;
+
+    use anyhow::Result;
+    use esp_idf_sys::esp;
+    use log::info;
+
+    use super::{Classifier, Detector, TouchConfig, CALIBRATION_SAMPLES};
+    use crate::{button, config, logging, tasks, wdt};
+
+    const FILTER_PERIOD_MS: u32 = 10;
+
+    fn read(pad: u32) -> Result<u16> {
+        let mut value = 0;
+        esp!(unsafe { esp_idf_sys::touch_pad_read_filtered(pad, &mut value) })?;
+
+        Ok(value)
+    }
+
+    fn calibrate(pad: &TouchConfig) -> Result<Detector> {
+        // Nobody touches the pad during boot, so this is the untouched level
+        let mut sum = 0u32;
+        for _ in 0..CALIBRATION_SAMPLES {
+            std::thread::sleep(Duration::from_millis(FILTER_PERIOD_MS as u64));
+            sum += read(pad.pad)? as u32;
+        }
+        let detector = Detector::new(pad, (sum / CALIBRATION_SAMPLES) as u16);
+
+        // The hardware threshold only matters as deep-sleep wake source
+        esp!(unsafe { esp_idf_sys::touch_pad_set_thresh(pad.pad, detector.threshold()) })?;
+        info!(
+            target: logging::BUTTON,
+            "Touch pad {} calibrated, baseline {}, threshold {}",
+            pad.name,
+            detector.baseline(),
+            detector.threshold()
+        );
+
+        Ok(detector)
+    }
+
+    pub fn init(pads: &'static [TouchConfig]) -> Result<()> {
+        esp!(unsafe { esp_idf_sys::touch_pad_init() })?;
+        esp!(unsafe {
+            esp_idf_sys::touch_pad_set_fsm_mode(esp_idf_sys::touch_fsm_mode_t_TOUCH_FSM_MODE_TIMER)
+        })?;
+        for pad in pads {
+            esp!(unsafe { esp_idf_sys::touch_pad_config(pad.pad, 0) })?;
+        }
+        esp!(unsafe { esp_idf_sys::touch_pad_filter_start(FILTER_PERIOD_MS) })?;
+
+        let detectors = pads.iter().map(calibrate).collect::<Result<Vec<_>>>()?;
+
+        tasks::spawn(&tasks::TOUCH, move || touch_handler(pads, detectors))
+    }
+
+    fn touch_handler(pads: &'static [TouchConfig], mut detectors: Vec<Detector>) {
+        let watchdog = wdt::subscribe(tasks::TOUCH.name);
+        let mut classifiers: Vec<Classifier> =
+            pads.iter().map(|pad| Classifier::new(pad.timing)).collect();
+        let mut events = Vec::new();
+
+        loop {
+            watchdog.sleep(Duration::from_millis(config::TOUCH_SAMPLE_MS));
+
+            let now = (unsafe { esp_idf_sys::esp_timer_get_time() } / 1000) as u64;
+            for ((pad, detector), classifier) in pads
+                .iter()
+                .zip(detectors.iter_mut())
+                .zip(classifiers.iter_mut())
+            {
+                if let Ok(value) = read(pad.pad) {
+                    classifier.edge(detector.sample(value), now);
+                }
+                classifier.poll(now, &mut |event| events.push((pad.name, event)));
+            }
+
+            for (name, event) in events.drain(..) {
+                button::dispatch(name, event);
+            }
+        }
+    }
+
+    pub fn enable_wakeup() -> Result<()> {
+        esp!(unsafe { esp_idf_sys::esp_sleep_enable_touchpad_wakeup() })?;
+
+        Ok(())
+    }
+}
+
+#[cfg(not(esp32))]
+mod pad {
+    use anyhow::{bail, Result};
+
+    use super::TouchConfig;
+
+    pub fn init(_: &'static [TouchConfig]) -> Result<()> {
+        bail!("touch pads are only supported on the ESP32")
+    }
+
+    pub fn enable_wakeup() -> Result<()> {
+        bail!("touch pads are only supported on the ESP32")
+    }
+}
+
+/// Calibrates the pads and starts sampling them; their events arrive through
+/// the button listeners under the pad names.
+pub fn init(pads: &'static [TouchConfig]) -> Result<()> {
+    pad::init(pads)
+}
+
+/// Arms the pads as deep-sleep wake source, with the calibrated thresholds.
+pub fn enable_wakeup() -> Result<()> {
+    pad::enable_wakeup()
+}
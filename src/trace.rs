@@ -0,0 +1,69 @@
+use anyhow::bail;
+use hap_core::trace::{self, Record, Ring};
+use hap_core::HapSys;
+use log::info;
+use spin::Mutex;
+
+use crate::hap_sys::{self, HAP};
+use crate::{config, console, logging};
+
+static RING: Mutex<Ring> = Mutex::new(Ring::new());
+
+fn uptime_ms() -> u64 {
+    (unsafe { esp_idf_sys::esp_timer_get_time() } / 1000) as u64
+}
+
+fn sink(record: &Record) {
+    let uuid = record.hc().and_then(|hc| HAP.sys().char_type_uuid(hc));
+    let line = format!("{} ms {}", uptime_ms(), record.render(uuid.as_deref()));
+    info!(target: logging::HAP, "trace {}", line);
+
+    let mut ring = RING.lock();
+    if ring.capacity() > 0 {
+        ring.push(line);
+    }
+}
+
+pub fn init() {
+    trace::set_sink(sink);
+    trace::on_controller(hap_sys::request_controller);
+}
+
+pub fn register_commands() {
+    console::register(
+        "trace",
+        "Trace the HAP traffic to the log ('trace on'), also into RAM ('trace on ring'), \
+         stop ('trace off'), show ('trace show') or drop ('trace clear') the kept lines",
+        |args| {
+            match args {
+                [] => println!(
+                    "{}, keeping {} lines",
+                    if trace::enabled() { "On" } else { "Off" },
+                    RING.lock().capacity()
+                ),
+                ["on"] => {
+                    RING.lock().set_capacity(0);
+                    trace::set_enabled(true);
+                }
+                ["on", "ring"] => {
+                    RING.lock().set_capacity(config::TRACE_RING_LINES);
+                    trace::set_enabled(true);
+                }
+                // The kept lines stay for 'trace show'
+                ["off"] => trace::set_enabled(false),
+                ["show"] => {
+                    let ring = RING.lock();
+                    for line in ring.lines() {
+                        println!("{}", line);
+                    }
+                    if ring.dropped() > 0 {
+                        println!("({} older lines dropped)", ring.dropped());
+                    }
+                }
+                ["clear"] => RING.lock().clear(),
+                _ => bail!("usage: trace [on [ring]|off|show|clear]"),
+            }
+            Ok(())
+        },
+    );
+}
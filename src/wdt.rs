@@ -0,0 +1,149 @@
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use log::{info, warn};
+
+use crate::{config, logging, tasks};
+
+const MAX_WATCHED_TASKS: usize = tasks::ALL.len();
+
+struct Slot {
+    claimed: AtomicBool,
+    active: AtomicBool,
+    name: spin::Once<&'static str>,
+    last_feed_ms: AtomicU32,
+}
+
+const EMPTY_SLOT: Slot = Slot {
+    claimed: AtomicBool::new(false),
+    active: AtomicBool::new(false),
+    name: spin::Once::new(),
+    last_feed_ms: AtomicU32::new(0),
+};
+
+// Fixed table so the watchdog ISR can walk it without locking or allocating
+static SLOTS: [Slot; MAX_WATCHED_TASKS] = [EMPTY_SLOT; MAX_WATCHED_TASKS];
+
+/// Subscription of the calling task to the task watchdog.
+pub struct Watchdog {
+    slot: Option<&'static Slot>,
+}
+
+fn now_ms() -> u32 {
+    (unsafe { esp_idf_sys::esp_timer_get_time() } / 1000) as u32
+}
+
+pub fn init() {
+    if !config::TASK_WDT_ENABLED {
+        warn!(target: logging::DIAG, "Task watchdog disabled at build time");
+        return;
+    }
+
+    // Reconfigures the watchdog the IDF already started during boot
+    let err = unsafe { esp_idf_sys::esp_task_wdt_init(config::TASK_WDT_TIMEOUT_SECS, true) };
+    if err != esp_idf_sys::ESP_OK {
+        warn!(target: logging::DIAG, "Configuring the task watchdog failed: {}", err);
+    } else {
+        info!(
+            target: logging::DIAG,
+            "Task watchdog armed with a {} s timeout",
+            config::TASK_WDT_TIMEOUT_SECS
+        );
+    }
+}
+
+/// Subscribes the calling task, which must call `feed` at least once per timeout.
+pub fn subscribe(name: &'static str) -> Watchdog {
+    if !config::TASK_WDT_ENABLED {
+        return Watchdog { slot: None };
+    }
+
+    // Slots are never released, a task subscribing again reuses its own
+    let slot = SLOTS
+        .iter()
+        .find(|slot| slot.name.get() == Some(&name) && !slot.active.load(Ordering::Acquire))
+        .or_else(|| {
+            SLOTS.iter().find(|slot| {
+                slot.claimed
+                    .compare_exchange(false, true, Ordering::AcqRel, Ordering::Relaxed)
+                    .is_ok()
+            })
+        });
+    // One slot per task in tasks::ALL, a task missing there is a bug
+    let Some(slot) = slot else {
+        panic!(
+            "no watchdog slot left for task {}, add it to tasks::ALL",
+            name
+        );
+    };
+
+    slot.name.call_once(|| name);
+    slot.last_feed_ms.store(now_ms(), Ordering::Relaxed);
+    slot.active.store(true, Ordering::Release);
+
+    let err = unsafe { esp_idf_sys::esp_task_wdt_add(std::ptr::null_mut()) };
+    if err != esp_idf_sys::ESP_OK {
+        warn!(target: logging::DIAG, "Subscribing {} to the task watchdog failed: {}", name, err);
+    }
+
+    Watchdog { slot: Some(slot) }
+}
+
+impl Watchdog {
+    pub fn feed(&self) {
+        if let Some(slot) = self.slot {
+            slot.last_feed_ms.store(now_ms(), Ordering::Relaxed);
+            unsafe { esp_idf_sys::esp_task_wdt_reset() };
+        }
+    }
+
+    /// Sleeps for `duration` while keeping the watchdog fed.
+    pub fn sleep(&self, duration: Duration) {
+        let step = Duration::from_secs(1);
+        let mut remaining = duration;
+
+        while remaining > Duration::ZERO {
+            let chunk = remaining.min(step);
+            thread::sleep(chunk);
+            remaining -= chunk;
+            self.feed();
+        }
+    }
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        if let Some(slot) = self.slot {
+            unsafe { esp_idf_sys::esp_task_wdt_delete(std::ptr::null_mut()) };
+            slot.active.store(false, Ordering::Release);
+        }
+    }
+}
+
+/// Called by the IDF from the watchdog ISR right before it panics.
+#[no_mangle]
+extern "C" fn esp_task_wdt_isr_user_handler() {
+    let now = now_ms();
+
+    for slot in SLOTS.iter() {
+        if !slot.active.load(Ordering::Acquire) {
+            continue;
+        }
+        let Some(name) = slot.name.get() else {
+            continue;
+        };
+
+        let starved_ms = now.wrapping_sub(slot.last_feed_ms.load(Ordering::Relaxed));
+        if starved_ms >= config::TASK_WDT_TIMEOUT_SECS * 1000 {
+            unsafe {
+                esp_idf_sys::esp_rom_printf(
+                    b"Task watchdog: %.*s starved for %u ms\n\0".as_ptr() as _,
+                    name.len() as i32,
+                    name.as_ptr(),
+                    starved_ms,
+                );
+            }
+        }
+    }
+}
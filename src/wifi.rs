@@ -0,0 +1,404 @@
+use std::net::Ipv4Addr;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Result};
+use embedded_svc::ipv4;
+use embedded_svc::ping::Ping;
+use embedded_svc::wifi::{
+    AccessPointConfiguration, ApIpStatus, ApStatus, ClientConfiguration, ClientConnectionStatus,
+    ClientIpStatus, ClientStatus, Configuration, Status, Wifi,
+};
+use esp_idf_svc::netif::EspNetifStack;
+use esp_idf_svc::nvs::EspDefaultNvs;
+use esp_idf_svc::ping::EspPing;
+use esp_idf_svc::sysloop::EspSysLoopStack;
+use esp_idf_svc::wifi::EspWifi;
+use hap_core::credentials::Credentials;
+use log::{error, info, warn};
+
+use crate::app::Failure;
+use crate::{config, logging, metrics, nvs, provisioning, sleep};
+
+use crate::status_led::{self, Event};
+
+// Compiled-in credentials, until the setup portal stores others
+const SSID: &str = "ssid";
+const PASS: &str = "password";
+
+// Kept by the event handler; the IP is in network byte order
+static CONNECTED: AtomicBool = AtomicBool::new(false);
+static EVER_CONNECTED: AtomicBool = AtomicBool::new(false);
+static IP: AtomicU32 = AtomicU32::new(0);
+static RECONNECTS: AtomicU32 = AtomicU32::new(0);
+// Of the check after the first connect
+static GATEWAY_PINGS_LOST: AtomicU32 = AtomicU32::new(0);
+static GOT_IP: Mutex<bool> = Mutex::new(false);
+static GOT_IP_CHANGED: Condvar = Condvar::new();
+
+/// The station link as it is now; zeroed while disconnected, so nobody
+/// reports the last access point as if it were still there.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LinkInfo {
+    pub connected: bool,
+    /// dBm, 0 while disconnected
+    pub rssi: i8,
+    pub bssid: Option<[u8; 6]>,
+    pub ip: Option<Ipv4Addr>,
+    /// Connections regained since boot
+    pub reconnects: u32,
+}
+
+pub fn link_info() -> LinkInfo {
+    let reconnects = RECONNECTS.load(Ordering::Relaxed);
+    if !CONNECTED.load(Ordering::Relaxed) {
+        return LinkInfo {
+            reconnects,
+            ..Default::default()
+        };
+    }
+
+    let mut ap: esp_idf_sys::wifi_ap_record_t = unsafe { std::mem::zeroed() };
+    let associated =
+        unsafe { esp_idf_sys::esp_wifi_sta_get_ap_info(&mut ap) } == esp_idf_sys::ESP_OK;
+    LinkInfo {
+        connected: true,
+        rssi: if associated { ap.rssi } else { 0 },
+        bssid: associated.then(|| ap.bssid),
+        ip: Some(Ipv4Addr::from(IP.load(Ordering::Relaxed).to_le_bytes())),
+        reconnects,
+    }
+}
+
+pub fn register_metrics() {
+    metrics::register("wifi_connected", || link_info().connected as i64);
+    metrics::register("wifi_rssi", || link_info().rssi as i64);
+    metrics::register("wifi_reconnects", || link_info().reconnects as i64);
+    metrics::register("wifi_gateway_pings_lost", || {
+        GATEWAY_PINGS_LOST.load(Ordering::Relaxed) as i64
+    });
+}
+
+/// The credentials the setup portal stored, or the compiled-in ones.
+fn credentials() -> Credentials {
+    let stored = nvs::Namespace::open(nvs::WIFI).and_then(|store| {
+        let mut ssid = [0u8; 33];
+        let mut password = [0u8; 64];
+        let Some(ssid) = store.get_str("ssid", &mut ssid)? else {
+            return Ok(None);
+        };
+        let password = store.get_str("pass", &mut password)?.unwrap_or("");
+        Ok(Some(Credentials {
+            ssid: ssid.into(),
+            password: password.into(),
+        }))
+    });
+
+    let compiled = || Credentials {
+        ssid: SSID.into(),
+        password: PASS.into(),
+    };
+    match stored {
+        Ok(Some(credentials)) => credentials,
+        Ok(None) => compiled(),
+        Err(err) => {
+            warn!(target: logging::WIFI, "Reading the stored credentials failed: {:?}", err);
+            compiled()
+        }
+    }
+}
+
+/// Replaces the stored credentials; the HAP keystore is a partition of its
+/// own and never touched.
+pub fn store_credentials(credentials: &Credentials) -> Result<()> {
+    let store = nvs::Namespace::open(nvs::WIFI)?;
+    store.set_str("ssid", &credentials.ssid)?;
+    store.set_str("pass", &credentials.password)?;
+    store.commit()?;
+    info!(target: logging::WIFI, "Stored {:?}", credentials);
+
+    Ok(())
+}
+
+/// Creates the driver without connecting, so HAP and the accessory can be
+/// built while `bring_up` runs.
+pub fn create(
+    netif: Arc<EspNetifStack>,
+    sysloop: Arc<EspSysLoopStack>,
+    nvs: Arc<EspDefaultNvs>,
+) -> Result<Box<EspWifi>> {
+    let wifi = Box::new(EspWifi::new(netif, sysloop, nvs)?);
+    watch_events();
+
+    Ok(wifi)
+}
+
+/// Blocks until the station got its first address; HAP starts from there,
+/// its mDNS needs the network.
+pub fn wait_for_ip() {
+    let mut got_ip = GOT_IP.lock().unwrap_or_else(PoisonError::into_inner);
+    while !*got_ip {
+        got_ip = GOT_IP_CHANGED
+            .wait(got_ip)
+            .unwrap_or_else(PoisonError::into_inner);
+    }
+}
+
+/// Connects the station, retrying until it works. The setup portal opens
+/// when it was asked for, without credentials, or once the station failed
+/// for WIFI_AP_FALLBACK_SECS; after the portal the station is tried again.
+pub fn bring_up(wifi: &Mutex<Box<EspWifi>>) {
+    let mut failing_since = Instant::now();
+    let mut attempt = 1;
+    loop {
+        let fallback = config::WIFI_AP_FALLBACK_SECS > 0
+            && failing_since.elapsed() >= Duration::from_secs(config::WIFI_AP_FALLBACK_SECS);
+        let mut credentials = credentials();
+        if fallback || provisioning::take_request() || credentials.ssid.is_empty() {
+            if fallback {
+                warn!(
+                    target: logging::WIFI,
+                    "No connection to {} for {} s, opening the setup portal",
+                    credentials.ssid,
+                    config::WIFI_AP_FALLBACK_SECS
+                );
+            }
+            let mut wifi = wifi.lock().unwrap_or_else(PoisonError::into_inner);
+            match provisioning::run(&mut wifi) {
+                Ok(Some(accepted)) => {
+                    credentials = accepted;
+                    // Anything cached belongs to the old network
+                    sleep::store_link(None);
+                }
+                Ok(None) => {}
+                Err(err) => warn!(target: logging::WIFI, "Setup portal failed: {:?}", err),
+            }
+            failing_since = Instant::now();
+        }
+        if credentials.ssid.is_empty() {
+            status_led::event(Event::WaitingForProvisioning);
+            error!(target: logging::WIFI, "No Wi-Fi credentials configured");
+            thread::sleep(Duration::from_secs(config::WIFI_RETRY_SECS));
+            continue;
+        }
+
+        let connected = connect(
+            &mut wifi.lock().unwrap_or_else(PoisonError::into_inner),
+            &credentials,
+        );
+        match connected {
+            Ok(gateway) => {
+                status_led::event(Event::ErrorCleared);
+                // A diagnostic only, the link is up whatever the gateway says
+                if let Some(gateway) = gateway {
+                    if let Err(err) = ping_gateway(gateway) {
+                        warn!(target: logging::WIFI, "Gateway check failed: {:?}", err);
+                    }
+                }
+                return;
+            }
+            Err(err) => {
+                warn!(
+                    target: logging::WIFI,
+                    "Wi-Fi bring-up failed (attempt {}), retrying in {} s: {:?}",
+                    attempt,
+                    config::WIFI_RETRY_SECS,
+                    err
+                );
+                status_led::event(Event::Error(Failure::Wifi.blink_count()));
+                thread::sleep(Duration::from_secs(config::WIFI_RETRY_SECS));
+                attempt += 1;
+            }
+        }
+    }
+}
+
+/// Joins the network; returns the gateway to check, none after a fast
+/// reconnect from the sleep cache.
+fn connect(wifi: &mut EspWifi, credentials: &Credentials) -> Result<Option<Ipv4Addr>> {
+    status_led::event(Event::WifiConnecting);
+
+    // After deep sleep the cached link skips the scan and DHCP
+    let cached = sleep::cached_link();
+    if cached.is_some() {
+        // Dropped for good unless the connection works out
+        sleep::store_link(None);
+    }
+
+    let channel = if let Some(link) = cached {
+        info!(
+            target: logging::WIFI,
+            "Fast reconnect to {:02x?} on channel {} as {}", link.bssid, link.channel, link.ip
+        );
+        Some(link.channel)
+    } else {
+        info!(target: logging::WIFI, "Wifi created, about to scan");
+        scan_channel(wifi, &credentials.ssid)?
+    };
+
+    wifi.set_configuration(&Configuration::Mixed(
+        ClientConfiguration {
+            ssid: credentials.ssid.as_str().into(),
+            password: credentials.password.as_str().into(),
+            channel,
+            bssid: cached.map(|link| link.bssid),
+            ip_conf: cached.map(|link| {
+                ipv4::ClientConfiguration::Fixed(ipv4::ClientSettings {
+                    ip: link.ip,
+                    subnet: ipv4::Subnet {
+                        gateway: link.gateway,
+                        mask: ipv4::Mask(link.mask),
+                    },
+                    dns: link.dns,
+                    secondary_dns: None,
+                })
+            }),
+            ..Default::default()
+        },
+        AccessPointConfiguration {
+            ssid: "aptest".into(),
+            channel: channel.unwrap_or(1),
+            ..Default::default()
+        },
+    ))?;
+
+    info!(target: logging::WIFI, "Wifi configuration set, about to get status");
+
+    let status = wifi.get_status();
+
+    if let Status(
+        ClientStatus::Started(ClientConnectionStatus::Connected(ClientIpStatus::Done(ip_settings))),
+        ApStatus::Started(ApIpStatus::Done),
+    ) = status
+    {
+        info!(
+            target: logging::WIFI,
+            "Wifi connected with IP {}, gateway {}", ip_settings.ip, ip_settings.subnet.gateway
+        );
+
+        status_led::event(Event::WifiConnected);
+
+        if config::SLEEP_ENABLED {
+            sleep::store_link(current_link(&ip_settings));
+        }
+
+        // The link was fine before the sleep, pinging would cost seconds of battery
+        Ok(cached.is_none().then_some(ip_settings.subnet.gateway))
+    } else {
+        error!(target: logging::WIFI, "Unexpected Wifi status: {:?}", status);
+        bail!("Unexpected Wifi status: {:?}", status);
+    }
+}
+
+fn scan_channel(wifi: &mut EspWifi, ssid: &str) -> Result<Option<u8>> {
+    let ap_infos = wifi.scan()?;
+
+    let ours = ap_infos.into_iter().find(|a| a.ssid == ssid);
+
+    let channel = if let Some(ours) = ours {
+        info!(
+            target: logging::WIFI,
+            "Found configured access point {} on channel {}", ssid, ours.channel
+        );
+        Some(ours.channel)
+    } else {
+        warn!(
+            target: logging::WIFI,
+            "Configured access point {} not found during scanning, will go with unknown channel",
+            ssid
+        );
+        None
+    };
+
+    Ok(channel)
+}
+
+fn ping_gateway(gateway: Ipv4Addr) -> Result<()> {
+    info!(target: logging::WIFI, "About to ping gateway {}", gateway);
+
+    let ping_summary = EspPing::default().ping(gateway, &Default::default())?;
+    GATEWAY_PINGS_LOST.store(
+        ping_summary.transmitted - ping_summary.received,
+        Ordering::Relaxed,
+    );
+    if ping_summary.transmitted != ping_summary.received {
+        warn!(
+            target: logging::WIFI,
+            "Gateway {} answered {} of {} pings",
+            gateway,
+            ping_summary.received,
+            ping_summary.transmitted
+        );
+        bail!("Pinging gateway {} resulted in timeouts", gateway);
+    }
+    info!(target: logging::WIFI, "Pinging done");
+
+    Ok(())
+}
+
+fn current_link(ip_settings: &ipv4::ClientSettings) -> Option<sleep::Link> {
+    let mut ap: esp_idf_sys::wifi_ap_record_t = unsafe { std::mem::zeroed() };
+    if unsafe { esp_idf_sys::esp_wifi_sta_get_ap_info(&mut ap) } != esp_idf_sys::ESP_OK {
+        return None;
+    }
+
+    Some(sleep::Link {
+        channel: ap.primary,
+        bssid: ap.bssid,
+        ip: ip_settings.ip,
+        gateway: ip_settings.subnet.gateway,
+        mask: ip_settings.subnet.mask.0,
+        dns: ip_settings.dns,
+    })
+}
+
+unsafe extern "C" fn on_wifi_event(
+    _: *mut esp_idf_sys::c_types::c_void,
+    base: esp_idf_sys::esp_event_base_t,
+    event: i32,
+    data: *mut esp_idf_sys::c_types::c_void,
+) {
+    if base == esp_idf_sys::WIFI_EVENT
+        && event as u32 == esp_idf_sys::wifi_event_t_WIFI_EVENT_STA_DISCONNECTED
+    {
+        CONNECTED.store(false, Ordering::Relaxed);
+        status_led::event(Event::WifiLost);
+    } else if base == esp_idf_sys::IP_EVENT
+        && event as u32 == esp_idf_sys::ip_event_t_IP_EVENT_STA_GOT_IP
+    {
+        let got_ip = &*(data as *const esp_idf_sys::ip_event_got_ip_t);
+        IP.store(got_ip.ip_info.ip.addr, Ordering::Relaxed);
+        CONNECTED.store(true, Ordering::Relaxed);
+        if EVER_CONNECTED.swap(true, Ordering::Relaxed) {
+            RECONNECTS.fetch_add(1, Ordering::Relaxed);
+        } else {
+            *GOT_IP.lock().unwrap_or_else(PoisonError::into_inner) = true;
+            GOT_IP_CHANGED.notify_all();
+        }
+        status_led::event(Event::WifiConnected);
+    }
+}
+
+fn watch_events() {
+    static REGISTERED: AtomicBool = AtomicBool::new(false);
+    if REGISTERED.swap(true, Ordering::Relaxed) {
+        return;
+    }
+
+    for base in unsafe { [esp_idf_sys::WIFI_EVENT, esp_idf_sys::IP_EVENT] } {
+        let err = unsafe {
+            esp_idf_sys::esp_event_handler_register(
+                base,
+                esp_idf_sys::ESP_EVENT_ANY_ID,
+                Some(on_wifi_event),
+                ptr::null_mut(),
+            )
+        };
+        if err != esp_idf_sys::ESP_OK {
+            warn!(target: logging::WIFI, "Registering the Wi-Fi event handler failed: {}", err);
+        }
+    }
+}